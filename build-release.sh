@@ -2,6 +2,6 @@
 set -ex
 
 cargo +nightly build --release --target wasm32-unknown-unknown
-wasm-bindgen target/wasm32-unknown-unknown/release/gba.wasm --out-dir www
+wasm-bindgen target/wasm32-unknown-unknown/release/gba.wasm --target no-modules --out-dir www
 
 cd www && npm run start
\ No newline at end of file
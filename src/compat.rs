@@ -0,0 +1,183 @@
+//! Fingerprints for problems players report, so that reports of the same
+//! problem can be matched up without reading through crash reports. A
+//! fingerprint is made from the game code, what went wrong (where the
+//! emulator panicked, or the kind of error it stopped with) and the
+//! diagnostics raised along the way (games stuck polling missing hardware,
+//! runaway DMA, invalid opcodes skipped in permissive mode). Addresses and
+//! register values are left out, since they vary between runs that hit the
+//! same problem. The hash is the CRC32 of the fingerprint's key, so it's the
+//! same for every build that produces the same key (panic sites include the
+//! line number, so those change whenever the file does).
+//!
+//! KNOWN_ISSUES maps fingerprints to notes for the player. An issue can be
+//! specific to one game, or apply to every game with the same problem, in
+//! which case its key starts with * instead of a game code.
+
+use std::fmt;
+use cpu::CPUWrapper;
+use error::Error;
+use util::crc32;
+
+/// What made the emulator stop
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cause {
+    /// panicked at the given source location, formatted as file:line
+    Panic(String),
+    /// stopped with an error from step
+    Error(Error),
+}
+
+impl Cause {
+    fn describe(&self) -> String {
+        match *self {
+            Cause::Panic(ref site) => format!("panic:{}", site),
+            Cause::Error(Error::Unsupported { feature, .. }) =>
+                format!("error:Unsupported({})", feature),
+            Cause::Error(ref err) => format!("error:{}", err.kind()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// from the cartridge header, or ???? without a ROM
+    pub game_code: String,
+    /// everything else, which is the same for every game with the same
+    /// problem
+    pub problem: String,
+}
+
+impl Fingerprint {
+    pub fn key(&self) -> String {
+        format!("{} {}", self.game_code, self.problem)
+    }
+
+    pub fn hash(&self) -> u32 {
+        crc32(self.key().as_bytes())
+    }
+
+    /// The hash of the key with the game code replaced by *
+    pub fn generic_hash(&self) -> u32 {
+        crc32(format!("* {}", self.problem).as_bytes())
+    }
+
+    /// Return the note for this problem in this game, or for this problem in
+    /// any game
+    pub fn note(&self) -> Option<&'static str> {
+        let (hash, generic) = (self.hash(), self.generic_hash());
+        KNOWN_ISSUES.iter().find(|issue| issue.fingerprint == hash)
+            .or_else(|| KNOWN_ISSUES.iter().find(|issue| issue.fingerprint == generic))
+            .map(|issue| issue.note)
+    }
+}
+
+/// The hash, formatted as it's shown to players
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X}", self.hash())
+    }
+}
+
+pub struct KnownIssue {
+    /// the CRC32 of key
+    pub fingerprint: u32,
+    /// kept next to the hash so that the table can be checked and read
+    pub key: &'static str,
+    pub note: &'static str,
+}
+
+pub const KNOWN_ISSUES: &[KnownIssue] = &[
+    KnownIssue {
+        fingerprint: 0x323287A1,
+        key: "* running stuck:Serial",
+        note: "The game is waiting for a JOY bus transfer (e.g. to a GameCube), \
+               which isn't emulated",
+    },
+    KnownIssue {
+        fingerprint: 0x627652EF,
+        key: "* error:RomNotLoaded",
+        note: "The game was started without a ROM. Load a ROM and try again",
+    },
+];
+
+impl CPUWrapper {
+    /// Fingerprint the emulator's current state, with the cause of the
+    /// failure if it's stopped. Without one, the fingerprint only covers the
+    /// diagnostics, e.g. for a game that hangs without crashing
+    pub fn fingerprint(&self, cause: Option<&Cause>) -> Fingerprint {
+        let mem = &self.cpu.mem;
+        let mut parts = vec![match cause {
+            Some(cause) => cause.describe(),
+            None => "running".to_string(),
+        }];
+        for subsystem in mem.poll_watch.stuck_subsystems() {
+            parts.push(format!("stuck:{:?}", subsystem));
+        }
+        for channel in mem.dma.runaway_channels() {
+            parts.push(format!("runaway-dma:{}", channel));
+        }
+        if self.invalid_opcodes.skipped > 0 {
+            parts.push("skipped-opcodes".to_string());
+        }
+        Fingerprint {
+            game_code: mem.game_code().unwrap_or_else(|| "????".to_string()),
+            problem: parts.join(" "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::invalid_opcode::OpcodePolicy;
+    use cpu::unsupported::Feature;
+    use mem::io::unsupported::POLL_LIMIT;
+
+    #[test]
+    fn known_issues() {
+        for issue in KNOWN_ISSUES {
+            assert_eq!(issue.fingerprint, crc32(issue.key.as_bytes()), "{}", issue.key);
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut gba = CPUWrapper::new();
+        let mut rom = vec![0; 0xC0];
+        rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+        gba.cpu.mem.raw.rom = Some(rom);
+
+        let unsupported = Cause::Error(Error::Unsupported {
+            pc: 0x8000000,
+            feature: Feature::Exception("FIQ"),
+        });
+        let fingerprint = gba.fingerprint(Some(&unsupported));
+        assert_eq!(fingerprint.key(),
+            "AXVE error:Unsupported(the FIQ exception isn't supported)");
+        assert_eq!(fingerprint.to_string(), format!("{:08X}", fingerprint.hash()));
+        assert_eq!(fingerprint.note(), None);
+
+        // the address isn't part of it
+        let elsewhere = Cause::Error(Error::Unsupported {
+            pc: 0x8000100,
+            feature: Feature::Exception("FIQ"),
+        });
+        assert_eq!(gba.fingerprint(Some(&elsewhere)), fingerprint);
+
+        // waiting for a JOY bus transfer
+        for _ in 0..POLL_LIMIT {
+            gba.cpu.mem.get_halfword(0x4000158);
+        }
+        gba.cpu.mem.poll_watch.take_reports();
+        let stuck = gba.fingerprint(None);
+        assert_eq!(stuck.key(), "AXVE running stuck:Serial");
+        assert_eq!(stuck.note(), Some(KNOWN_ISSUES[0].note));
+
+        let panic = gba.fingerprint(Some(&Cause::Panic("src/cpu/mod.rs:611".to_string())));
+        assert_eq!(panic.problem, "panic:src/cpu/mod.rs:611 stuck:Serial");
+
+        gba.invalid_opcodes.policy = OpcodePolicy::Permissive;
+        gba.invalid_opcodes.check(0x8000000, 0xEC000000);
+        assert_eq!(gba.fingerprint(None).problem, "running stuck:Serial skipped-opcodes");
+    }
+}
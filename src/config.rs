@@ -0,0 +1,413 @@
+//! Settings that the frontend saves between sessions (e.g. in localStorage)
+//! without needing to know what they contain. They're serialized as text, with
+//! a version header followed by one setting per line:
+//!
+//!     gba-settings 1
+//!     bind a KeyX
+//!     speed 1
+//!     overclock 1
+//!     volume 100
+//!     reset_combo off
+//!     overlay frame_count
+//!     game AXVE max_frame_skip 0
+//!
+//! Unknown settings are skipped when importing, so settings saved by a newer
+//! version can still be read by an older one. The version only needs to be
+//! bumped if the meaning of an existing setting changes.
+
+use std::fmt;
+use std::fmt::Write;
+use cpu::CPUWrapper;
+use cpu::bios::NUM_SWIS;
+use cpu::overclock::MAX_MULTIPLIER;
+use cpu::pacing::MAX_SPEED;
+use cpu::soft_reset::ComboAction;
+use mem::backup::MAX_BANKS;
+use mem::overlay::{OverlayKind, OVERLAY_KINDS};
+
+pub const SETTINGS_VERSION: u32 = 1;
+const HEADER: &str = "gba-settings";
+
+/// The GBA buttons, in the order of their bits in KEYINPUT
+pub const BUTTONS: [&str; 10] = [
+    "a", "b", "select", "start", "right", "left", "up", "down", "r", "l",
+];
+
+/// The KeyboardEvent.code bound to each button unless the user changes it
+const DEFAULT_BINDINGS: [&str; 10] = [
+    "KeyX", "KeyZ", "Backspace", "Enter",
+    "ArrowRight", "ArrowLeft", "ArrowUp", "ArrowDown", "KeyS", "KeyA",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SettingsError {
+    /// the data doesn't start with the settings header
+    MissingHeader,
+    /// the settings were saved by a newer, incompatible version
+    UnsupportedVersion(u32),
+    /// a known setting has a value that can't be parsed, on the given line
+    /// (counting from 1)
+    InvalidValue(usize),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SettingsError::MissingHeader => write!(f, "not a settings file"),
+            SettingsError::UnsupportedVersion(version) =>
+                write!(f, "unsupported settings version {}", version),
+            SettingsError::InvalidValue(line) =>
+                write!(f, "invalid setting on line {}", line),
+        }
+    }
+}
+
+/// Settings that only apply to one game. None leaves the global setting as is
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameOverrides {
+    /// the 4 character game code from the cartridge header
+    pub game_code: String,
+    pub max_frame_skip: Option<u32>,
+    pub affine_snapshot: Option<bool>,
+    /// SWIs that should always be emulated, even with a BIOS loaded
+    pub force_hle: Vec<u8>,
+    /// the number of 32KB SRAM banks in the cartridge (see mem::backup)
+    pub sram_banks: Option<u8>,
+}
+
+impl GameOverrides {
+    pub fn new(game_code: &str) -> GameOverrides {
+        GameOverrides {
+            game_code: game_code.to_string(),
+            max_frame_skip: None,
+            affine_snapshot: None,
+            force_hle: Vec::new(),
+            sram_banks: None,
+        }
+    }
+}
+
+/// Return true if the emulation can run at the given speed: it's positive
+/// and finite, and at most MAX_SPEED
+pub fn is_valid_speed(speed: f64) -> bool {
+    speed.is_finite() && speed > 0.0 && speed <= MAX_SPEED
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// the KeyboardEvent.code bound to each button in BUTTONS, or None for
+    /// the default
+    pub bindings: [Option<String>; 10],
+    /// emulation speed relative to the GBA, e.g. 2.0 to fast forward
+    pub speed: f64,
+    /// how many times faster than normal the emulated CPU runs, to remove
+    /// slowdown in games. The frame rate stays the same
+    pub overclock: f64,
+    /// audio volume from 0 to 100
+    pub volume: u8,
+    /// what holding A+B+Select+Start does, see cpu::soft_reset
+    pub reset_combo: ComboAction,
+    /// the overlays drawn over the screen, see mem::overlay
+    pub overlays: Vec<OverlayKind>,
+    pub games: Vec<GameOverrides>,
+}
+
+impl Settings {
+    pub const fn new() -> Settings {
+        Settings {
+            bindings: [None, None, None, None, None, None, None, None, None, None],
+            speed: 1.0,
+            overclock: 1.0,
+            volume: 100,
+            reset_combo: ComboAction::Off,
+            overlays: Vec::new(),
+            games: Vec::new(),
+        }
+    }
+
+    /// Return the key bound to the given button, or None if the button
+    /// doesn't exist
+    pub fn binding(&self, button: &str) -> Option<&str> {
+        let idx = BUTTONS.iter().position(|name| *name == button)?;
+        Some(match self.bindings[idx] {
+            Some(ref key) => key.as_str(),
+            None => DEFAULT_BINDINGS[idx],
+        })
+    }
+
+    /// Bind the given button to a key, and return false if the button
+    /// doesn't exist
+    pub fn set_binding(&mut self, button: &str, key: &str) -> bool {
+        match BUTTONS.iter().position(|name| *name == button) {
+            Some(idx) => {
+                self.bindings[idx] = Some(key.to_string());
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn game(&self, game_code: &str) -> Option<&GameOverrides> {
+        self.games.iter().find(|game| game.game_code == game_code)
+    }
+
+    /// Return the overrides for the given game, adding them if there aren't
+    /// any yet
+    pub fn game_mut(&mut self, game_code: &str) -> &mut GameOverrides {
+        match self.games.iter().position(|game| game.game_code == game_code) {
+            Some(idx) => &mut self.games[idx],
+            None => {
+                self.games.push(GameOverrides::new(game_code));
+                self.games.last_mut().unwrap()
+            },
+        }
+    }
+
+    /// Apply the settings to the emulator, including the overrides for the
+    /// loaded game, if any
+    pub fn apply(&self, gba: &mut CPUWrapper) {
+        gba.pacing.speed = self.speed;
+        gba.overclock.set_multiplier(self.overclock);
+        gba.reset_combo.action = self.reset_combo;
+        for kind in OVERLAY_KINDS.iter() {
+            gba.cpu.mem.framebuffer.post.overlay.set_enabled(*kind, self.overlays.contains(kind));
+        }
+        let game = match gba.cpu.mem.game_code() {
+            Some(code) => match self.game(&code) {
+                Some(game) => game,
+                None => return,
+            },
+            None => return,
+        };
+        if let Some(max_skip) = game.max_frame_skip {
+            gba.pacing.set_max_skip(max_skip);
+        }
+        if let Some(enabled) = game.affine_snapshot {
+            gba.cpu.mem.sprites.affine_snapshot = enabled;
+        }
+        for num in game.force_hle.iter() {
+            gba.cpu.bios.force_hle[*num as usize] = true;
+        }
+        if let Some(banks) = game.sram_banks {
+            gba.cpu.mem.raw.sram.set_banks(banks);
+        }
+    }
+
+    /// Remember the emulator's current per game settings for the loaded game.
+    /// Returns false if there is no game loaded
+    pub fn save_game(&mut self, gba: &CPUWrapper) -> bool {
+        let code = match gba.cpu.mem.game_code() {
+            Some(code) => code,
+            None => return false,
+        };
+        let game = self.game_mut(&code);
+        game.max_frame_skip = Some(gba.pacing.max_skip);
+        game.affine_snapshot = Some(gba.cpu.mem.sprites.affine_snapshot);
+        game.force_hle = (0..NUM_SWIS)
+            .filter(|num| gba.cpu.bios.force_hle[*num])
+            .map(|num| num as u8)
+            .collect();
+        game.sram_banks = Some(gba.cpu.mem.raw.sram.banks());
+        true
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = String::new();
+        let _ = writeln!(out, "{} {}", HEADER, SETTINGS_VERSION);
+        // buttons left on the default binding follow any change to the default
+        for (button, key) in BUTTONS.iter().zip(self.bindings.iter()) {
+            if let Some(ref key) = *key {
+                let _ = writeln!(out, "bind {} {}", button, key);
+            }
+        }
+        let _ = writeln!(out, "speed {}", self.speed);
+        let _ = writeln!(out, "overclock {}", self.overclock);
+        let _ = writeln!(out, "volume {}", self.volume);
+        let _ = writeln!(out, "reset_combo {}", self.reset_combo.name());
+        for kind in self.overlays.iter() {
+            let _ = writeln!(out, "overlay {}", kind.name());
+        }
+        for game in self.games.iter() {
+            let code = &game.game_code;
+            if let Some(max_skip) = game.max_frame_skip {
+                let _ = writeln!(out, "game {} max_frame_skip {}", code, max_skip);
+            }
+            if let Some(enabled) = game.affine_snapshot {
+                let _ = writeln!(out, "game {} affine_snapshot {}", code, enabled);
+            }
+            for num in game.force_hle.iter() {
+                let _ = writeln!(out, "game {} force_hle {}", code, num);
+            }
+            if let Some(banks) = game.sram_banks {
+                let _ = writeln!(out, "game {} sram_banks {}", code, banks);
+            }
+        }
+        out.into_bytes()
+    }
+
+    /// Parse settings produced by serialize(). Settings that are missing keep
+    /// their default values
+    pub fn parse(data: &[u8]) -> Result<Settings, SettingsError> {
+        let text = String::from_utf8_lossy(data);
+        let mut lines = text.lines();
+        let mut header = lines.next().unwrap_or("").split_whitespace();
+        if header.next() != Some(HEADER) {
+            return Err(SettingsError::MissingHeader);
+        }
+        let version = header.next()
+            .and_then(|version| version.parse().ok())
+            .ok_or(SettingsError::MissingHeader)?;
+        if version > SETTINGS_VERSION {
+            return Err(SettingsError::UnsupportedVersion(version));
+        }
+
+        let mut settings = Settings::new();
+        for (i, line) in lines.enumerate() {
+            let invalid = SettingsError::InvalidValue(i + 2);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["bind", button, key] => { settings.set_binding(button, key); },
+                ["speed", speed] => {
+                    settings.speed = match speed.parse::<f64>() {
+                        Ok(speed) if is_valid_speed(speed) => speed,
+                        _ => return Err(invalid),
+                    };
+                },
+                ["overclock", multiplier] => {
+                    settings.overclock = match multiplier.parse::<f64>() {
+                        Ok(multiplier) if multiplier >= 1.0 && multiplier <= MAX_MULTIPLIER =>
+                            multiplier,
+                        _ => return Err(invalid),
+                    };
+                },
+                ["volume", volume] => {
+                    let volume: u32 = volume.parse().map_err(|_| invalid)?;
+                    settings.volume = volume.min(100) as u8;
+                },
+                ["reset_combo", action] => {
+                    settings.reset_combo = ComboAction::from_name(action).ok_or(invalid)?;
+                },
+                ["overlay", kind] => {
+                    // overlays added by a newer version are skipped
+                    if let Some(kind) = OverlayKind::from_name(kind) {
+                        if !settings.overlays.contains(&kind) {
+                            settings.overlays.push(kind);
+                        }
+                    }
+                },
+                ["game", code, "max_frame_skip", max_skip] => {
+                    settings.game_mut(code).max_frame_skip =
+                        Some(max_skip.parse().map_err(|_| invalid)?);
+                },
+                ["game", code, "affine_snapshot", enabled] => {
+                    settings.game_mut(code).affine_snapshot =
+                        Some(enabled.parse().map_err(|_| invalid)?);
+                },
+                ["game", code, "force_hle", num] => {
+                    let num: u8 = num.parse().map_err(|_| invalid.clone())?;
+                    if num as usize >= NUM_SWIS {
+                        return Err(invalid);
+                    }
+                    settings.game_mut(code).force_hle.push(num);
+                },
+                ["game", code, "sram_banks", banks] => {
+                    let banks: u8 = banks.parse().map_err(|_| invalid.clone())?;
+                    if banks > MAX_BANKS {
+                        return Err(invalid);
+                    }
+                    settings.game_mut(code).sram_banks = Some(banks);
+                },
+                _ => (),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut settings = Settings::new();
+        assert_eq!(settings.binding("a"), Some("KeyX"));
+        assert!(settings.set_binding("a", "KeyK"));
+        assert!(!settings.set_binding("turbo", "KeyT"));
+        settings.speed = 1.5;
+        settings.overclock = 2.0;
+        settings.volume = 40;
+        settings.reset_combo = ComboAction::Auto;
+        settings.overlays = vec![OverlayKind::Speed, OverlayKind::Input];
+        settings.game_mut("AXVE").max_frame_skip = Some(0);
+        settings.game_mut("AXVE").force_hle = vec![0x0B, 0x0C];
+        settings.game_mut("AXVE").sram_banks = Some(4);
+
+        let data = settings.serialize();
+        let text = String::from_utf8(data.clone()).unwrap();
+        assert!(text.starts_with("gba-settings 1\nbind a KeyK\nspeed 1.5\noverclock 2\nvolume 40\n\
+            reset_combo auto\noverlay speed\noverlay input\n"));
+        assert!(text.contains("game AXVE max_frame_skip 0\n"));
+        assert!(text.contains("game AXVE sram_banks 4\n"));
+        assert_eq!(Settings::parse(&data), Ok(settings));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Settings::parse(b""), Err(SettingsError::MissingHeader));
+        assert_eq!(Settings::parse(b"gba-settings 2\n"),
+            Err(SettingsError::UnsupportedVersion(2)));
+        assert_eq!(Settings::parse(b"gba-settings 1\nvolume 100\nspeed fast\n"),
+            Err(SettingsError::InvalidValue(3)));
+        for speed in ["0", "-1", "inf", "NaN", "17"].iter() {
+            let text = format!("gba-settings 1\nspeed {}\n", speed);
+            assert_eq!(Settings::parse(text.as_bytes()), Err(SettingsError::InvalidValue(2)));
+        }
+        assert_eq!(Settings::parse(b"gba-settings 1\noverclock 0.5\n"),
+            Err(SettingsError::InvalidValue(2)));
+        assert_eq!(Settings::parse(b"gba-settings 1\ngame AXVE sram_banks 9\n"),
+            Err(SettingsError::InvalidValue(2)));
+        assert_eq!(Settings::parse(b"gba-settings 1\nreset_combo sometimes\n"),
+            Err(SettingsError::InvalidValue(2)));
+
+        // settings from newer versions are skipped
+        let settings = Settings::parse(b"gba-settings 1\nshader crt\nvolume 300\n").unwrap();
+        assert_eq!(settings.volume, 100);
+    }
+
+    #[test]
+    fn apply() {
+        let mut rom = vec![0; 0xC0];
+        rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.load_rom(rom);
+
+        let mut settings = Settings::new();
+        settings.speed = 2.0;
+        settings.overclock = 1.5;
+        settings.reset_combo = ComboAction::Always;
+        settings.overlays.push(OverlayKind::FrameCount);
+        settings.game_mut("AXVE").affine_snapshot = Some(true);
+        settings.game_mut("AXVE").force_hle.push(0x0B);
+        settings.game_mut("AXVE").sram_banks = Some(2);
+        settings.game_mut("BPEE").max_frame_skip = Some(0);
+        settings.apply(&mut gba);
+        assert_eq!(gba.pacing.speed, 2.0);
+        assert_eq!(gba.overclock.multiplier(), 1.5);
+        assert_eq!(gba.reset_combo.action, ComboAction::Always);
+        assert!(gba.cpu.mem.framebuffer.post.overlay.is_enabled(OverlayKind::FrameCount));
+        assert!(!gba.cpu.mem.framebuffer.post.overlay.is_enabled(OverlayKind::Input));
+        assert_eq!(gba.pacing.max_skip, 3);
+        assert!(gba.cpu.mem.sprites.affine_snapshot);
+        assert!(gba.cpu.bios.force_hle[0x0B]);
+        assert_eq!(gba.cpu.mem.raw.sram.banks(), 2);
+
+        gba.pacing.set_max_skip(1);
+        let mut saved = Settings::new();
+        assert!(saved.save_game(&gba));
+        let game = saved.game("AXVE").unwrap();
+        assert_eq!(game.max_frame_skip, Some(1));
+        assert_eq!(game.force_hle, vec![0x0B]);
+        assert_eq!(game.sram_banks, Some(2));
+    }
+}
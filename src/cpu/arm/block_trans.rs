@@ -1,5 +1,6 @@
 use ::cpu::CPU;
 use ::cpu::status_reg::{CPUMode, InstructionSet};
+use ::cpu::unsupported::Feature;
 use ::util;
 
 /// Load or store any subset of the currently visible registers
@@ -37,31 +38,37 @@ impl BlockDataTransfer {
     }
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
-        let mut cycles = cpu.mem.access_time(cpu.r[15], true);
-
-        if self.rn == 15 {
-            panic!("can't use R15 as base in any LDM or STM instruction");
-        }
-        if self.force && cpu.cpsr.mode == CPUMode::USR {
-            panic!("can't set S bit in a non privileged mode");
+        // the prefetch is sequential for LDM and nonsequential for STM
+        let mut cycles = cpu.mem.access_time(cpu.r[15], !self.load);
+
+        let unpredictable = if self.rn == 15 {
+            Some("R15 as the base of an LDM or STM")
+        } else if self.force && cpu.cpsr.mode == CPUMode::USR {
+            Some("the S bit of an LDM or STM in USR mode")
+        } else {
+            None
+        };
+        if let Some(what) = unpredictable {
+            cpu.unsupported.report(Feature::Unpredictable(what));
+            return 0;
         }
 
         let is_pc_in_list = self.register_list >= (1 << 15); // is bit 15 set?
         let original_mode = cpu.cpsr.mode;
-        let mut force_user_bank = false;
-        if self.force {
-            if is_pc_in_list && self.load {
-                cpu.restore_cpsr();
-            } else {
-                force_user_bank = true;
-                // temporarily switch to USR mode so that get/set reg refers
-                // to the user bank registers
-                cpu.cpsr.mode = CPUMode::USR;
-            }
-        }
-        
+        // with the PC, an LDM with the S bit returns from an exception by
+        // restoring the CPSR once the registers (and the base register of
+        // the current mode) have been written
+        let restore_cpsr = self.force && is_pc_in_list && self.load;
+        let force_user_bank = self.force && !restore_cpsr;
         if force_user_bank && self.write_back {
-            panic!("write back should not be used when forcing user bank");
+            cpu.unsupported.report(
+                Feature::Unpredictable("write back from an LDM or STM of the USR registers"));
+            return 0;
+        }
+        if force_user_bank {
+            // temporarily switch to USR mode so that get/set reg refers to
+            // the user bank registers
+            cpu.switch_mode(CPUMode::USR);
         }
         if is_pc_in_list && self.load {
             cpu.should_flush = true;
@@ -123,7 +130,10 @@ impl BlockDataTransfer {
             cpu.set_reg(self.rn, addr);
         }
         if force_user_bank {
-            cpu.cpsr.mode = original_mode;
+            cpu.switch_mode(original_mode);
+        }
+        if restore_cpsr {
+            cpu.restore_cpsr();
         }
         let pc = cpu.r[15];
         if is_pc_in_list && (pc & 1) == 1 {
@@ -131,9 +141,17 @@ impl BlockDataTransfer {
             cpu.set_reg(15, pc & !1);
         }
 
-        // this is 2N + (n - 1)S + 1I, which isn't completely accurate but
-        // close enough
-        cycles + 1
+        // LDM is nS + 1N + 1I, plus 1N + 1S to refill the pipeline when the
+        // PC is loaded. STM is (n - 1)S + 2N
+        if self.load {
+            cycles += cpu.mem.internal_cycles(1);
+            if is_pc_in_list {
+                let pc = cpu.r[15];
+                cycles += cpu.mem.access_time(pc, true) +
+                    cpu.mem.access_time(pc + 4, false);
+            }
+        }
+        cycles
     }
 }
 
@@ -348,4 +366,34 @@ mod test {
         ins.run(&mut cpu);
         assert_eq!(cpu.mem.get_word(0x03000004), 0x03000000);
     }
+    #[test]
+    fn return_from_exception() {
+        // ldmfd sp!, {r0, pc}^ at the end of an IRQ handler
+        let mut cpu = CPU::new();
+        cpu.r[13] = 0x3007F00;
+        cpu.cpsr.mode = CPUMode::IRQ;
+        cpu.spsr_irq = ::cpu::status_reg::PSR::new_direct_boot();
+        cpu.set_reg(13, 0x3000100);
+        cpu.mem.set_word(0x3000100, 0x55);
+        cpu.mem.set_word(0x3000104, 0x3000200);
+
+        let ins = BlockDataTransfer {
+            pre_index: false,
+            offset_up: true,
+            force: true,
+            write_back: true,
+            load: true,
+            rn: 13,
+            register_list: 1 | 1 << 15
+        };
+        ins.run(&mut cpu);
+        assert_eq!(cpu.cpsr.mode, CPUMode::SYS);
+        assert_eq!(cpu.cpsr.irq, false);
+        assert_eq!(cpu.get_reg(0), 0x55);
+        assert_eq!(cpu.get_reg(15), 0x3000200);
+        // the IRQ mode stack pointer is written back, not the one of the mode
+        // being returned to
+        assert_eq!(cpu.r_irq[0], 0x3000108);
+        assert_eq!(cpu.get_reg(13), 0x3007F00);
+    }
 }
\ No newline at end of file
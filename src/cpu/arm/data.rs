@@ -2,6 +2,7 @@ use num::FromPrimitive;
 use super::RegOrImm;
 use ::cpu::CPU;
 use ::cpu::status_reg::InstructionSet;
+use ::cpu::unsupported::Feature;
 use ::util;
 
 enum_from_primitive! {
@@ -85,6 +86,11 @@ impl DataProc {
                 // the shift amount, the PC will be 12 bytes ahead instead of 8
                 let mut rm_val = cpu.get_reg(reg as usize);
                 let reg_shift = util::get_bit(shift, 0);
+                if reg_shift && util::get_nibble(shift, 4) == 15 {
+                    cpu.unsupported.report(
+                        Feature::Unpredictable("R15 as the shift amount of a data processing instruction"));
+                    return 0;
+                }
                 if self.rn == 15 && reg_shift {
                     op1 += 4;
                 }
@@ -231,15 +237,13 @@ pub fn apply_shift(cpu: &CPU, shift: u32, val: u32) -> (u32, bool) {
 }
 
 /// Parse the shift bits (4 - 11) and return whether the shift amount was an
-/// immediate, and the actual shift amount
+/// immediate, and the actual shift amount. Using R15 as the shift amount is
+/// unpredictable, and reported by the instructions before they get here
 fn get_shift_amount(cpu: &CPU, shift: u32) -> (bool, u32) {
     match (util::get_bit(shift, 3), util::get_bit(shift, 0)) {
         // shift by register amount
         (false, true) => {
             let rs = util::get_nibble(shift, 4);
-            if rs == 15 {
-                panic!("cannot use R15 as shift amount");
-            }
             (false, cpu.get_reg(rs as usize) & 0xFF)
         },
         // shift by immediate amount
@@ -259,7 +263,7 @@ fn add(op1: u32, op2: u32, carry: u32) -> (u32, bool, Option<bool>) {
 }
 
 /// Return the difference, carry, and overflow of the two operands
-fn sub(op1: u32, op2: u32, carry: u32) -> (u32, bool, Option<bool>) {
+pub fn sub(op1: u32, op2: u32, carry: u32) -> (u32, bool, Option<bool>) {
     add(op1, !op2, carry)
 }
 
@@ -281,6 +285,18 @@ mod test {
         });
     }
 
+    #[test]
+    fn r15_shift_amount() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(1, 0x1234);
+        // mov r0, r1, lsl r15
+        let ins = DataProc::parse_instruction(0xE1A00F11);
+        ins.run(&mut cpu);
+        assert_eq!(cpu.unsupported.take(), Some(
+            Feature::Unpredictable("R15 as the shift amount of a data processing instruction")));
+        assert_eq!(cpu.get_reg(0), 0);
+    }
+
     #[test]
     fn parse_reg() {
         let ins = DataProc::parse_instruction(
@@ -338,13 +354,6 @@ mod test {
         assert_eq!(get_shift_amount(&cpu, 0b0001_0111), (false, 0));
     }
 
-    #[test]
-    #[should_panic]
-    fn shift_amt_reg_15() {
-        let cpu = CPU::new();
-        get_shift_amount(&cpu, 0b1111_0_00_1);
-    }
-
     #[test]
     fn shift_lsl() {
         let mut cpu = CPU::new();
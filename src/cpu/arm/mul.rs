@@ -1,4 +1,5 @@
 use ::cpu::CPU;
+use ::cpu::unsupported::Feature;
 use ::util;
 
 /// The multiply and multiply-accumulate instructions perform integer multiplication
@@ -32,11 +33,13 @@ impl Multiply {
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
         if self.rd == 15 || self.rm == 15 || self.rn == 15 {
-            panic!("Can't use R15 as operand or dest in mul");
-        }
-        if self.rd == self.rm {
-            panic!("Rd and Rm can't be the same in mul");
+            cpu.unsupported.report(
+                Feature::Unpredictable("R15 as an operand or destination of a MUL or MLA"));
+            return 0;
         }
+        // Rd and Rm being the same is unpredictable on ARM, but the ARM7TDMI
+        // just multiplies the values read before Rd is written. THUMB can't
+        // avoid it, since MUL Rd, Rs multiplies Rd by Rs
         // since we only care about the bottom 32 bits, this will be the same
         // for both signed and unsigned integers
         let multiplier = cpu.get_reg(self.rs);
@@ -47,22 +50,47 @@ impl Multiply {
         cpu.set_reg(self.rd, result as u32);
         if self.set_flags {
             cpu.cpsr.neg = ((result >> 31) & 1) == 1;
-            cpu.cpsr.zero = result == 0;
+            cpu.cpsr.zero = (result as u32) == 0;
+            cpu.cpsr.carry = MUL_CARRY;
         }
 
+        // the bottom 32 bits of the product don't depend on the signedness,
+        // but the multiplier terminates early as if it were signed
         cpu.mem.access_time(cpu.r[15], false) +
-            mul_cycle_time(multiplier) +
-            if self.accumulate { 1 } else { 0 }
+            cpu.mem.internal_cycles(
+                mul_cycle_time(multiplier, true) + if self.accumulate { 1 } else { 0 })
     }
 }
 
-pub fn mul_cycle_time(multiplier: u32) -> u32 {
-    let second_byte = (multiplier >> 8) as u8;
-    let third_byte = (multiplier >> 16) as u8;
-    let fourth_byte = (multiplier >> 24) as u8;
-    1 + if second_byte == 0 || second_byte == 0xFF { 0 } else { 1 } +
-        if third_byte == 0 || third_byte == 0xFF { 0 } else { 1 } +
-        if fourth_byte == 0 || fourth_byte == 0xFF { 0 } else { 1 }
+/// The value of the carry flag after a multiply that sets the flags. The
+/// ARM7TDMI leaves whatever its multiplier array last produced in the carry
+/// (ARM only documents it as meaningless), which no game should depend on.
+/// It's modeled as always cleared, for both the normal and long multiplies,
+/// so that runs are deterministic. The overflow flag is left unchanged
+pub const MUL_CARRY: bool = false;
+
+/// Return the number of internal cycles (m) the multiplier takes for the given
+/// value of Rs. The multiplier works through Rs 8 bits at a time and stops
+/// early once the remaining bits are all 0s, or for a signed multiply, all 0s
+/// or all 1s (i.e. just the sign extension of the bits already done):
+///   - 1 if bits 8-31 are all 0 (or all 1 if signed)
+///   - 2 if bits 16-31 are
+///   - 3 if bits 24-31 are
+///   - 4 otherwise
+pub fn mul_cycle_time(multiplier: u32, signed: bool) -> u32 {
+    let done_after = |bits: u32| {
+        let rest = multiplier >> bits;
+        rest == 0 || (signed && rest == !0 >> bits)
+    };
+    if done_after(8) {
+        1
+    } else if done_after(16) {
+        2
+    } else if done_after(24) {
+        3
+    } else {
+        4
+    }
 }
 
 #[cfg(test)]
@@ -80,4 +108,50 @@ mod test {
         assert_eq!(mul.rs, 15);
         assert_eq!(mul.rm, 2);
     }
+
+    #[test]
+    fn cycle_time() {
+        let cases: [(u32, u32, u32); 9] = [
+            // (multiplier, signed m, unsigned m)
+            (0x00000000, 1, 1),
+            (0x000000FF, 1, 1),
+            (0xFFFFFF80, 1, 4),
+            (0x0000FF00, 2, 2),
+            (0xFFFF8000, 2, 4),
+            (0x00FF0000, 3, 3),
+            (0xFF800000, 3, 4),
+            (0x80000000, 4, 4),
+            // a byte of all 1s in the middle doesn't end the multiply early
+            (0x00FF1200, 3, 3),
+        ];
+        for &(multiplier, signed, unsigned) in cases.iter() {
+            assert_eq!(mul_cycle_time(multiplier, true), signed, "{:08X}", multiplier);
+            assert_eq!(mul_cycle_time(multiplier, false), unsigned, "{:08X}", multiplier);
+        }
+    }
+
+    #[test]
+    fn run() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(2, 3);
+        cpu.set_reg(3, 0xFFFFFFFE);
+        cpu.set_reg(4, 1);
+        cpu.cpsr.carry = true;
+        cpu.cpsr.overflow = true;
+
+        // muls r1, r2, r3: 1S + 1I, since -2 only needs 8 bits when signed
+        let mul = Multiply::parse_instruction(0xE0110392);
+        assert_eq!(mul.run(&mut cpu), 1 + 1);
+        assert_eq!(cpu.get_reg(1), 0xFFFFFFFA);
+        assert!(cpu.cpsr.neg);
+        assert_eq!(cpu.cpsr.carry, MUL_CARRY);
+        assert!(cpu.cpsr.overflow);
+
+        // mla r1, r3, r2, r4: 1S + 2I. the flags are untouched
+        cpu.cpsr.carry = true;
+        let mla = Multiply::parse_instruction(0xE0214293);
+        assert_eq!(mla.run(&mut cpu), 1 + 2);
+        assert_eq!(cpu.get_reg(1), 0xFFFFFFFB);
+        assert!(cpu.cpsr.carry);
+    }
 }
@@ -1,5 +1,6 @@
 use ::cpu::CPU;
-use ::cpu::arm::mul::mul_cycle_time;
+use ::cpu::unsupported::Feature;
+use ::cpu::arm::mul::{MUL_CARRY, mul_cycle_time};
 use ::util;
 
 /// The multiply and multiply-accumulate instructions perform integer multiplication
@@ -38,10 +39,14 @@ impl MultiplyLong {
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
         if self.rm == 15 || self.rs == 15 || self.rdhi == 15 || self.rdlo == 15 {
-            panic!("Can't use R15 as operand or dest in mul");
+            cpu.unsupported.report(
+                Feature::Unpredictable("R15 as an operand or destination of a long multiply"));
+            return 0;
         }
         if self.rdhi == self.rdlo ||  self.rdhi == self.rm || self.rdlo == self.rm {
-            panic!("RdHi, RdLo, and Rm must all specify different registers");
+            cpu.unsupported.report(
+                Feature::Unpredictable("RdHi, RdLo and Rm of a long multiply aren't all different"));
+            return 0;
         }
 
         let summand =
@@ -74,12 +79,16 @@ impl MultiplyLong {
         if self.set_flags {
             cpu.cpsr.neg = ((top >> 31) & 1) == 1;
             cpu.cpsr.zero = result == 0;
+            cpu.cpsr.carry = MUL_CARRY;
         }
 
-        // TODO: this isn't quite accurate for signed mull, see docs
+        // a long multiply takes one more cycle than MUL to produce the high
+        // word, and accumulating takes another. only the signed version can
+        // terminate early on a multiplier of all 1s
         cpu.mem.access_time(cpu.r[15], false) +
-            mul_cycle_time(multiplier) +
-            if self.accumulate { 1 } else { 0 }
+            cpu.mem.internal_cycles(
+                mul_cycle_time(multiplier, self.is_signed) + 1 +
+                if self.accumulate { 1 } else { 0 })
     }
 }
 
@@ -99,4 +108,30 @@ mod test {
         assert_eq!(mul.rs, 3);
         assert_eq!(mul.rm, 8);
     }
+
+    #[test]
+    fn timing() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(3, 0xFFFFFFFF);
+        cpu.set_reg(4, 5);
+        cpu.cpsr.carry = true;
+
+        // umulls r1, r2, r4, r3: all 1s isn't a short multiplier unsigned,
+        // so 1S + (4 + 1)I
+        let umull = MultiplyLong::parse_instruction(0xE0921394);
+        assert_eq!(umull.run(&mut cpu), 1 + 5);
+        assert_eq!((cpu.get_reg(2), cpu.get_reg(1)), (4, 0xFFFFFFFB));
+        assert_eq!(cpu.cpsr.carry, MUL_CARRY);
+
+        // smulls r1, r2, r4, r3: -1 is done after 8 bits, so 1S + (1 + 1)I
+        let smull = MultiplyLong::parse_instruction(0xE0D21394);
+        assert_eq!(smull.run(&mut cpu), 1 + 2);
+        assert_eq!((cpu.get_reg(2), cpu.get_reg(1)), (0xFFFFFFFF, 0xFFFFFFFB));
+        assert!(cpu.cpsr.neg);
+
+        // smlal r1, r2, r4, r3: 1S + (1 + 2)I
+        let smlal = MultiplyLong::parse_instruction(0xE0E21394);
+        assert_eq!(smlal.run(&mut cpu), 1 + 3);
+        assert_eq!((cpu.get_reg(2), cpu.get_reg(1)), (0xFFFFFFFF, 0xFFFFFFF6));
+    }
 }
\ No newline at end of file
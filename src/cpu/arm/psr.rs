@@ -1,6 +1,7 @@
 use super::RegOrImm;
 use ::cpu::CPU;
 use ::cpu::status_reg::CPUMode;
+use ::cpu::unsupported::Feature;
 use ::util;
 
 #[derive(Clone, Copy, Debug)]
@@ -14,7 +15,10 @@ pub enum StateRegType {
 #[derive(Clone, Debug)]
 pub enum TransferType {
     Read { stype: StateRegType, dest: usize },
-    Write { stype: StateRegType, source: RegOrImm, flag_only: bool }
+    /// flag_only and write_flags come from the field mask: only the control
+    /// bits (mode, THUMB and interrupt disable) or flags may be written, e.g.
+    /// with MSR CPSR_c or MSR CPSR_f
+    Write { stype: StateRegType, source: RegOrImm, flag_only: bool, write_flags: bool }
 }
 
 /// These instructions are TEQ/TST/CMN/CMP Data Processing operations but
@@ -57,6 +61,7 @@ impl PSRTransfer {
                         RegOrImm::Reg { reg: util::get_byte(ins, 0), shift: 0 }
                     },
                     flag_only: !util::get_bit(ins, 16),
+                    write_flags: util::get_bit(ins, 19),
                 }
             } else {
                 TransferType::Read { stype, dest: util::get_nibble(ins, 12) as usize }
@@ -68,7 +73,9 @@ impl PSRTransfer {
         match self.trans {
             TransferType::Read { ref stype, dest } => {
                 if dest == 15 {
-                    panic!("can't read/write PSR with R15");
+                    cpu.unsupported.report(
+                        Feature::Unpredictable("R15 as the register of an MRS or MSR"));
+                    return 0;
                 }
                 let val = match stype {
                     StateRegType::Current => cpu.cpsr.to_u32(),
@@ -76,22 +83,32 @@ impl PSRTransfer {
                 };
                 cpu.set_reg(dest, val);
             },
-            TransferType::Write { ref stype, ref source, flag_only } => {
+            TransferType::Write { ref stype, ref source, mut flag_only, write_flags } => {
                 let mut val = match source {
-                    RegOrImm::Imm { rotate, ref value } => value.rotate_right(*rotate),
+                    RegOrImm::Imm { rotate, ref value } => value.rotate_right(*rotate * 2),
                     RegOrImm::Reg { shift: _, reg } => {
                         if *reg == 15 {
-                            panic!("can't read/write PSR with R15");
+                            cpu.unsupported.report(
+                                Feature::Unpredictable("R15 as the register of an MRS or MSR"));
+                            return 0;
                         }
                         cpu.get_reg(*reg as usize)
                     }
                 };
+                let old = match stype {
+                    StateRegType::Current => cpu.cpsr.to_u32(),
+                    StateRegType::Saved => cpu.get_spsr().to_u32(),
+                };
+                if !write_flags {
+                    val = (val & 0x0FFFFFFF) | (old & 0xF0000000);
+                }
                 match stype {
                     StateRegType::Current => {
-                        cpu.set_cpsr(val, flag_only);
-                        if let CPUMode::INVALID = cpu.cpsr.mode {
-                            panic!("setting CPSR to an invalid mode")
+                        // the control bits can't be changed from USR mode
+                        if cpu.cpsr.mode == CPUMode::USR {
+                            flag_only = true;
                         }
+                        cpu.set_cpsr(val, flag_only)
                     },
                     StateRegType::Saved => cpu.set_spsr(val, flag_only)
                 }
@@ -104,7 +121,8 @@ impl PSRTransfer {
 #[cfg(test)]
 mod test {
     use super::*;
-    use ::cpu::status_reg::{InstructionSet, CPUMode};
+    use ::cpu::status_reg::{InstructionSet, CPUMode, PSR};
+    use ::error::Error;
 
     #[test]
     fn parse_read() {
@@ -125,6 +143,7 @@ mod test {
                 stype: StateRegType::Saved,
                 source: RegOrImm::Reg { shift: 0, reg: 8 },
                 flag_only: true,
+                write_flags: true,
             } => true,
             _ => false
         });
@@ -136,6 +155,7 @@ mod test {
                 stype: StateRegType::Saved,
                 source: RegOrImm::Reg { shift: 0, reg: 8 },
                 flag_only: false,
+                write_flags: true,
             } => true,
             _ => false
         });
@@ -150,6 +170,7 @@ mod test {
                 stype: StateRegType::Current,
                 source: RegOrImm::Imm { rotate: 10, value: 128 },
                 flag_only: true,
+                write_flags: true,
             } => true,
             _ => false
         });
@@ -178,7 +199,8 @@ mod test {
             trans: TransferType::Write {
                 stype: StateRegType::Saved,
                 source: RegOrImm::Reg { shift: 0, reg: 14 },
-                flag_only: false
+                flag_only: false,
+                write_flags: true
             }
         };
         ins.run(&mut cpu);
@@ -194,17 +216,19 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn write_cpsr_invalid() {
         let mut cpu = CPU::new();
+        cpu.set_reg(14, 0);
         let ins = PSRTransfer {
             trans: TransferType::Write {
                 stype: StateRegType::Current,
                 source: RegOrImm::Reg { shift: 0, reg: 14 },
-                flag_only: false
+                flag_only: false,
+                write_flags: true
             }
         };
         ins.run(&mut cpu);
+        assert_eq!(cpu.check_mode(), Err(Error::InvalidPsrMode { bits: 0 }));
     }
 
     #[test]
@@ -214,7 +238,8 @@ mod test {
             trans: TransferType::Write {
                 stype: StateRegType::Current,
                 source: RegOrImm::Imm { rotate: 0, value: 0xFFFFFFFF },
-                flag_only: true
+                flag_only: true,
+                write_flags: true
             }
         };
         ins.run(&mut cpu);
@@ -229,12 +254,84 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
+    fn write_control_only() {
+        // msr cpsr_c, #0x1F
+        let ins = PSRTransfer::parse_instruction(0xE321F01F);
+        let mut cpu = CPU::new();
+        cpu.cpsr.carry = true;
+        ins.run(&mut cpu);
+        assert_eq!(cpu.cpsr.mode, CPUMode::SYS);
+        assert_eq!(cpu.cpsr.irq, false);
+        assert_eq!(cpu.cpsr.carry, true);
+
+        // msr cpsr_fc, #0xF0000000 from USR mode only changes the flags
+        let ins = PSRTransfer::parse_instruction(0xE329F20F);
+        cpu.cpsr.mode = CPUMode::USR;
+        ins.run(&mut cpu);
+        assert_eq!(cpu.cpsr.mode, CPUMode::USR);
+        assert_eq!(cpu.cpsr.neg, true);
+    }
+
+    #[test]
+    fn msr_mode_switches() {
+        // the stacks being set up by init code that runs without the BIOS
+        let msr = |cpu: &mut CPU, mode: u32| {
+            // msr cpsr_c, #mode
+            PSRTransfer::parse_instruction(0xE321F000 | mode).run(cpu);
+        };
+        let mut cpu = CPU::new();
+        cpu.cpsr.carry = true;
+        cpu.set_reg(13, 0x3007FE0);
+        msr(&mut cpu, 0xD2);
+        assert_eq!(cpu.cpsr.mode, CPUMode::IRQ);
+        cpu.set_reg(13, 0x3007FA0);
+        msr(&mut cpu, 0xD1);
+        assert_eq!(cpu.cpsr.mode, CPUMode::FIQ);
+        cpu.set_reg(8, 8);
+        cpu.set_reg(13, 0x3007F80);
+        msr(&mut cpu, 0x1F);
+        assert_eq!(cpu.cpsr.mode, CPUMode::SYS);
+        cpu.set_reg(13, 0x3007F00);
+
+        assert_eq!(cpu.get_reg(8), 0);
+        assert_eq!(cpu.r_svc[0], 0x3007FE0);
+        assert_eq!(cpu.r_irq[0], 0x3007FA0);
+        assert_eq!((cpu.r_fiq[0], cpu.r_fiq[5]), (8, 0x3007F80));
+        assert_eq!(cpu.r[13], 0x3007F00);
+        assert!(cpu.cpsr.carry);
+        // unlike an exception, MSR doesn't save the CPSR to the new mode's SPSR
+        assert_eq!(cpu.spsr_irq.to_u32(), PSR::new().to_u32());
+        assert_eq!(cpu.spsr_fiq.to_u32(), PSR::new().to_u32());
+
+        // SYS mode has no SPSR: reading it gives the CPSR and writing it does
+        // nothing
+        PSRTransfer::parse_instruction(0xE14F0000).run(&mut cpu); // mrs r0, spsr
+        assert_eq!(cpu.get_reg(0), cpu.cpsr.to_u32());
+        cpu.set_reg(0, 0x10);
+        PSRTransfer::parse_instruction(0xE169F000).run(&mut cpu); // msr spsr_fc, r0
+        assert_eq!(cpu.cpsr.mode, CPUMode::SYS);
+
+        // from FIQ straight to USR, after which the mode is locked
+        msr(&mut cpu, 0xD1);
+        assert_eq!(cpu.get_reg(8), 8);
+        msr(&mut cpu, 0x10);
+        assert_eq!(cpu.cpsr.mode, CPUMode::USR);
+        assert_eq!(cpu.get_reg(8), 0);
+        assert_eq!(cpu.get_reg(13), 0x3007F00);
+        msr(&mut cpu, 0x1F);
+        assert_eq!(cpu.cpsr.mode, CPUMode::USR);
+    }
+
+    #[test]
     fn use_r15() {
         let ins = PSRTransfer {
             trans: TransferType::Read { stype: StateRegType::Saved, dest: 15 }
         };
 
-        ins.run(&mut CPU::new());
+        let mut cpu = CPU::new();
+        ins.run(&mut cpu);
+        assert_eq!(cpu.unsupported.take(),
+            Some(Feature::Unpredictable("R15 as the register of an MRS or MSR")));
+        assert_eq!(cpu.r[15], 0);
     }
 }
@@ -1,5 +1,6 @@
 use super::RegOrImm;
 use ::cpu::{CPU, TransferParams, TransferSize};
+use ::cpu::unsupported::Feature;
 use ::util;
 
 /// Load or store a half words of data from memory and also load sign-extended
@@ -57,7 +58,8 @@ impl SignedDataTransfer {
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
         if !self.load && self.signed {
-            panic!("should not store when signed operations have been selected");
+            cpu.unsupported.report(Feature::Unpredictable("a signed store"));
+            return 0;
         }
 
         // all the same, except you can load as signed (which means that when
@@ -1,5 +1,6 @@
 use super::RegOrImm;
 use ::cpu::{CPU, TransferParams, TransferSize};
+use ::cpu::unsupported::Feature;
 use ::util;
 
 /// Load or store a single byte/word to/from memory. The memory address is
@@ -15,8 +16,12 @@ pub struct SingleDataTransfer {
     pub offset_up: bool,
     /// if true, transfer byte, else transfer word
     pub byte: bool,
-    /// if true, write address back to base reg, else do nothing
+    /// if true, write address back to base reg, else do nothing. only used
+    /// when pre indexing, since post indexing always writes back
     pub write_back: bool,
+    /// if true, this is a LDRT/STRT which performs the access as if in user
+    /// mode. this is encoded by setting W when post indexing
+    pub force_user: bool,
     /// if true, load from memory, else write to memory
     pub load: bool,
     /// base register
@@ -34,11 +39,14 @@ impl SingleDataTransfer {
     /// I  | P  | U  | B  | W  | L  |    Rn    |    Rd    |  offset
     pub fn parse_instruction(ins: u32) -> SingleDataTransfer {
         let is_imm = !util::get_bit(ins, 25);
+        let pre_index = util::get_bit(ins, 24);
+        let w = util::get_bit(ins, 21);
         SingleDataTransfer {
-            pre_index: util::get_bit(ins, 24),
+            pre_index,
             offset_up: util::get_bit(ins, 23),
             byte: util::get_bit(ins, 22),
-            write_back: util::get_bit(ins, 21),
+            write_back: pre_index && w,
+            force_user: !pre_index && w,
             load: util::get_bit(ins, 20),
             rn: util::get_nibble(ins, 16) as usize,
             rd: util::get_nibble(ins, 12) as usize,
@@ -54,18 +62,32 @@ impl SingleDataTransfer {
     }
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
-        if self.rn == 15 && self.write_back {
-            panic!("cannot write back when R15 is the base register");
+        if self.rn == 15 && (self.write_back || !self.pre_index) {
+            cpu.unsupported.report(
+                Feature::Unpredictable("write back to R15 as the base of an LDR or STR"));
+            return 0;
         }
-        if let RegOrImm::Reg { shift: _, reg: rm } = self.offset {
+        if let RegOrImm::Reg { shift, reg: rm } = self.offset {
+            if util::get_bit(shift, 0) {
+                cpu.unsupported.report(
+                    Feature::Unpredictable("a register specified shift of the offset of an LDR or STR"));
+                return 0;
+            }
             if rm == 15 {
-                panic!("R15 cannot be used as the register offset");
+                cpu.unsupported.report(
+                    Feature::Unpredictable("R15 as the offset of an LDR or STR"));
+                return 0;
             }
             if rm as usize == self.rn {
-                panic!("Rm should not equal Rn");
+                cpu.unsupported.report(
+                    Feature::Unpredictable("the same register as the base and offset of an LDR or STR"));
+                return 0;
             }
         }
 
+        // the GBA has no MMU so there is no difference between a user mode
+        // and a privileged access to memory: LDRT/STRT behave exactly like a
+        // post indexed LDR/STR
         cpu.transfer_reg(TransferParams {
             pre_index: self.pre_index,
             offset_up: self.offset_up,
@@ -93,6 +115,7 @@ mod test {
         assert!(!ins.offset_up);
         assert!(ins.byte);
         assert!(!ins.write_back);
+        assert!(!ins.force_user);
         assert!(ins.load);
         assert_eq!(ins.rn, 1);
         assert_eq!(ins.rd, 2);
@@ -109,7 +132,8 @@ mod test {
         assert!(!ins.pre_index);
         assert!(ins.offset_up);
         assert!(!ins.byte);
-        assert!(ins.write_back);
+        assert!(!ins.write_back);
+        assert!(ins.force_user);
         assert!(!ins.load);
         assert_eq!(ins.rn, 14);
         assert_eq!(ins.rd, 1);
@@ -118,4 +142,37 @@ mod test {
             _ => false,
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn post_index_user() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(0, 0x3000000);
+        cpu.set_reg(1, 0xABC);
+        // strt r1, [r0], #4
+        let ins = SingleDataTransfer::parse_instruction(0xE4A01004);
+        assert!(ins.force_user);
+        ins.run(&mut cpu);
+        assert_eq!(cpu.mem.get_word(0x3000000), 0xABC);
+        assert_eq!(cpu.get_reg(0), 0x3000004);
+
+        // ldrt r2, [r0], #-4
+        let ins = SingleDataTransfer::parse_instruction(0xE4302004);
+        assert!(ins.force_user);
+        assert!(!ins.offset_up);
+        ins.run(&mut cpu);
+        assert_eq!(cpu.get_reg(2), 0);
+        assert_eq!(cpu.get_reg(0), 0x3000000);
+    }
+
+    #[test]
+    fn register_shift() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(0, 0x3000000);
+        // ldr r2, [r0, r1, lsl r3]
+        let ins = SingleDataTransfer::parse_instruction(0xE7902311);
+        ins.run(&mut cpu);
+        assert_eq!(cpu.unsupported.take(), Some(
+            Feature::Unpredictable("a register specified shift of the offset of an LDR or STR")));
+        assert_eq!(cpu.get_reg(0), 0x3000000);
+    }
+}
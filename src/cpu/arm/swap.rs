@@ -1,4 +1,5 @@
 use ::cpu::CPU;
+use ::cpu::unsupported::Feature;
 use ::util;
 
 /// Swap a byte or word between a register and external memory "atomically"
@@ -28,7 +29,8 @@ impl SingleDataSwap {
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
         if self.rn == 15 || self.rd == 15 || self.rm == 15 {
-            panic!("can't use R15 as an operand");
+            cpu.unsupported.report(Feature::Unpredictable("R15 as an operand of a SWP"));
+            return 0;
         }
     
         let addr = cpu.get_reg(self.rn);
@@ -47,9 +49,11 @@ impl SingleDataSwap {
 
         cpu.set_reg(self.rd, memval);
 
-        1 + cpu.mem.access_time(cpu.r[15], true) +
-            cpu.mem.access_time(cpu.r[15] + 4, false) +
-            cpu.mem.access_time(addr, true)
+        // 1S + 2N + 1I: the prefetch, then a read and a write to addr
+        cpu.mem.access_time(cpu.r[15], false) +
+            cpu.mem.access_time(addr, true) +
+            cpu.mem.access_time(addr, true) +
+            cpu.mem.internal_cycles(1)
     }
 }
 
@@ -1,7 +1,10 @@
 use ::cpu::{CPU, InterruptType};
+use ::cpu::bios::SwiPath;
+use ::cpu::status_reg::InstructionSet;
 
 /// Cause a software interrupt trap to be taken, which switches to Supervisor mode,
-/// changes the PC to a fixed value (0x08), and saves the CPSR
+/// changes the PC to a fixed value (0x08), and saves the CPSR. The BIOS call
+/// may instead be emulated directly, see cpu::bios
 #[derive(Clone,  Debug)]
 pub struct SWInterrupt { pub comment: u32 }
 
@@ -11,7 +14,25 @@ impl SWInterrupt {
     }
 
     pub fn run(&self, cpu: &mut CPU) -> u32 {
-        cpu.handle_interrupt(InterruptType::SWI);
-        cpu.mem.access_time(cpu.r[15], true) + cpu.mem.access_time(cpu.r[15] + 4, false)
+        // the BIOS reads the SWI number from the upper 8 bits of the comment
+        // field in ARM mode
+        let num = match cpu.cpsr.isa {
+            InstructionSet::ARM => (self.comment >> 16) as u8,
+            InstructionSet::THUMB => self.comment as u8,
+        };
+        let pc = cpu.r[15].wrapping_sub(2 * cpu.instruction_size());
+        match cpu.run_swi(num, pc) {
+            SwiPath::Bios => {
+                // 2S + 1N, like a branch to the vector
+                let old_pc = cpu.r[15];
+                cpu.handle_interrupt(InterruptType::SWI);
+                cpu.should_flush = true;
+                cpu.mem.access_time(old_pc, false) +
+                    cpu.mem.access_time(cpu.r[15], true) +
+                    cpu.mem.access_time(cpu.r[15] + 4, false)
+            },
+            // plus however long the emulated call took
+            _ => cpu.mem.access_time(cpu.r[15], false) + cpu.bios.take_cycles(),
+        }
     }
 }
@@ -0,0 +1,882 @@
+//! High level emulation (HLE) of BIOS calls. When a SWI is executed we can
+//! either jump into the BIOS like the hardware does, or perform the operation
+//! directly. Jumping into the BIOS is the most accurate, but requires a
+//! complete BIOS dump; HLE is used whenever there is no BIOS loaded, and can be
+//! forced for individual calls to work around incomplete or patched dumps.
+//!
+//! Interrupts also normally go through the BIOS: its handler at 0x18 saves
+//! registers and calls the game's handler, whose address the game stores at
+//! 0x03007FFC. How this is done is chosen by IrqDispatch.
+//!
+//! The BIOS waits for interrupts in IntrWait and VBlankIntrWait by halting
+//! the CPU. Halting isn't emulated, so when the interrupt hasn't happened yet
+//! the emulated call returns to the SWI instead, and runs again after the
+//! next instruction boundary. Interrupts are taken in between, and their
+//! handler returns to the SWI too.
+
+use std;
+use std::fmt::Write;
+use ::cpu::CPU;
+use mem::io::addrs::{IME, SOUNDBIAS};
+
+/// SWI numbers go from 0x00 to 0x2A
+pub const NUM_SWIS: usize = 0x2B;
+/// number of SWIs kept in the log
+pub const SWI_LOG_LEN: usize = 64;
+
+pub const SOFT_RESET: u8 = 0x00;
+pub const INTR_WAIT: u8 = 0x04;
+pub const VBLANK_INTR_WAIT: u8 = 0x05;
+pub const DIV: u8 = 0x06;
+pub const DIV_ARM: u8 = 0x07;
+pub const SQRT: u8 = 0x08;
+pub const ARC_TAN: u8 = 0x09;
+pub const ARC_TAN2: u8 = 0x0A;
+pub const CPU_SET: u8 = 0x0B;
+pub const CPU_FAST_SET: u8 = 0x0C;
+pub const BG_AFFINE_SET: u8 = 0x0E;
+pub const OBJ_AFFINE_SET: u8 = 0x0F;
+pub const LZ77_UNCOMP_WRAM: u8 = 0x11;
+pub const LZ77_UNCOMP_VRAM: u8 = 0x12;
+pub const SOUND_BIAS: u8 = 0x19;
+pub const MIDI_KEY_2_FREQ: u8 = 0x1F;
+
+/// The first quarter of the BIOS's sine table, which has 256 entries for a
+/// full turn in 1.1.14 fixed point. The BIOS truncates rather than rounds
+const SINE_QUARTER: [i32; 65] = [
+    0x0000, 0x0192, 0x0323, 0x04B5, 0x0645, 0x07D5, 0x0964, 0x0AF1, 0x0C7C,
+    0x0E05, 0x0F8C, 0x1111, 0x1294, 0x1413, 0x158F, 0x1708, 0x187D, 0x19EF,
+    0x1B5D, 0x1CC6, 0x1E2B, 0x1F8B, 0x20E7, 0x223D, 0x238E, 0x24DA, 0x261F,
+    0x275F, 0x2899, 0x29CD, 0x2AFA, 0x2C21, 0x2D41, 0x2E5A, 0x2F6B, 0x3076,
+    0x3179, 0x3274, 0x3367, 0x3453, 0x3536, 0x3612, 0x36E5, 0x37AF, 0x3871,
+    0x392A, 0x39DA, 0x3A82, 0x3B20, 0x3BB6, 0x3C42, 0x3CC5, 0x3D3E, 0x3DAE,
+    0x3E14, 0x3E71, 0x3EC5, 0x3F0E, 0x3F4E, 0x3F84, 0x3FB1, 0x3FD3, 0x3FEC,
+    0x3FFB, 0x4000,
+];
+
+/// the cycles taken by the BIOS's copy loops for each iteration, on top of
+/// the memory accesses: fetching the load, store, compare and branch (the
+/// BIOS has no waitstates), refilling the pipeline after the branch, and the
+/// internal cycle of the load
+const COPY_LOOP_CYCLES: u32 = 7;
+
+/// where the game stores the address of its interrupt handler
+pub const IRQ_HANDLER_PTR: u32 = 0x3007FFC;
+/// where the game's interrupt handler sets the bits of the interrupts it
+/// handled, for IntrWait to check
+pub const IRQ_FLAGS: u32 = 0x3007FF8;
+/// the IRQ vector, where the BIOS's interrupt handler starts
+pub const IRQ_VECTOR: u32 = 0x18;
+/// the BIOS's interrupt handler, used when it's emulated. this is what the
+/// real BIOS does, moved to start at the vector
+const HLE_IRQ_HANDLER: [u32; 6] = [
+    0xE92D500F, // stmfd sp!, {r0-r3, r12, lr}
+    0xE3A00301, // mov r0, #0x4000000
+    0xE28FE000, // add lr, pc, #0
+    0xE510F004, // ldr pc, [r0, #-4]
+    0xE8BD500F, // ldmfd sp!, {r0-r3, r12, lr}
+    0xE25EF004, // subs pc, lr, #4
+];
+
+/// How the CPU gets to the game's interrupt handler when an IRQ is taken
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqDispatch {
+    /// jump to the IRQ vector in the loaded BIOS, like the hardware does.
+    /// Without a BIOS there's no handler there, so Hle is used instead
+    Bios,
+    /// jump to the IRQ vector, but run a built in copy of the BIOS's handler
+    /// there, so that the game's handler is called through 0x03007FFC even
+    /// without a BIOS
+    Hle,
+    /// skip the BIOS and jump straight to the address at 0x03007FFC. the
+    /// handler is entered in IRQ mode with nothing saved, so it must return
+    /// like an exception handler itself (e.g. with SUBS PC, LR, #4)
+    Vector,
+}
+
+impl IrqDispatch {
+    pub fn from_name(name: &str) -> Option<IrqDispatch> {
+        match name {
+            "bios" => Some(IrqDispatch::Bios),
+            "hle" => Some(IrqDispatch::Hle),
+            "vector" => Some(IrqDispatch::Vector),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SwiPath {
+    /// jumped to the SWI vector in the loaded BIOS
+    Bios,
+    /// emulated directly
+    Hle,
+    /// there's no BIOS loaded and no HLE implementation for this call, so it
+    /// was treated as a no-op
+    Skipped,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SwiLogEntry {
+    /// the SWI number
+    pub num: u8,
+    /// address of the SWI instruction
+    pub pc: u32,
+    pub path: SwiPath,
+}
+
+pub struct Bios {
+    /// SWIs which should always use HLE, even if a BIOS is loaded
+    pub force_hle: [bool; NUM_SWIS],
+    /// the most recent SWIs, oldest first
+    pub log: Vec<SwiLogEntry>,
+    pub irq_dispatch: IrqDispatch,
+    /// the cycles taken by the last emulated call
+    cycles: u32,
+    /// the address of the IntrWait being waited in, which doesn't discard
+    /// the old flags again when it's retried
+    pub waiting: Option<u32>,
+    /// where an emulated SoftReset restarts the game, which CPUWrapper::step
+    /// jumps to once the SWI has finished
+    reset_entry: Option<u32>,
+}
+
+impl Bios {
+    pub const fn new() -> Bios {
+        Bios {
+            force_hle: [false; NUM_SWIS],
+            log: Vec::new(),
+            irq_dispatch: IrqDispatch::Bios,
+            cycles: 0,
+            waiting: None,
+            reset_entry: None,
+        }
+    }
+
+    /// Return the log as one line per SWI
+    pub fn format_log(&self) -> String {
+        let mut out = String::new();
+        for entry in self.log.iter() {
+            let _ = writeln!(out, "{:#010X}: SWI {:#04X} ({:?})",
+                entry.pc, entry.num, entry.path);
+        }
+        out
+    }
+
+    /// Return the cycles taken by the last emulated call, and reset them
+    pub fn take_cycles(&mut self) -> u32 {
+        std::mem::replace(&mut self.cycles, 0)
+    }
+
+    /// Return where an emulated SoftReset restarts the game, if one has just
+    /// run
+    pub fn take_reset_entry(&mut self) -> Option<u32> {
+        self.reset_entry.take()
+    }
+
+    fn record(&mut self, entry: SwiLogEntry) {
+        if self.log.len() == SWI_LOG_LEN {
+            self.log.remove(0);
+        }
+        self.log.push(entry);
+    }
+}
+
+/// Return true if there is an HLE implementation of the given SWI
+pub fn has_hle(num: u8) -> bool {
+    match num {
+        SOFT_RESET | INTR_WAIT | VBLANK_INTR_WAIT | DIV | DIV_ARM | SQRT |
+            ARC_TAN | ARC_TAN2 | CPU_SET | CPU_FAST_SET | BG_AFFINE_SET |
+            OBJ_AFFINE_SET | LZ77_UNCOMP_WRAM | LZ77_UNCOMP_VRAM | SOUND_BIAS |
+            MIDI_KEY_2_FREQ => true,
+        _ => false,
+    }
+}
+
+impl CPU {
+    /// Return how IRQs get to the game's handler, which is the chosen
+    /// IrqDispatch unless that's the BIOS's handler and there's no BIOS
+    pub fn irq_dispatch(&self) -> IrqDispatch {
+        match self.bios.irq_dispatch {
+            IrqDispatch::Bios if !self.mem.bios_loaded => IrqDispatch::Hle,
+            dispatch => dispatch,
+        }
+    }
+
+    /// Return the instruction at the given address if it's part of the
+    /// emulated interrupt handler, which replaces whatever is in the BIOS
+    pub fn hle_fetch(&self, addr: u32) -> Option<u32> {
+        if self.irq_dispatch() != IrqDispatch::Hle || addr < IRQ_VECTOR {
+            return None;
+        }
+        HLE_IRQ_HANDLER.get(((addr - IRQ_VECTOR) / 4) as usize).cloned()
+    }
+
+    /// Decide how to handle the given SWI, record the decision in the log, and
+    /// return it. The caller is responsible for jumping into the BIOS if the
+    /// returned path is SwiPath::Bios
+    pub fn run_swi(&mut self, num: u8, pc: u32) -> SwiPath {
+        let forced = (num as usize) < NUM_SWIS && self.bios.force_hle[num as usize];
+        let path = if self.mem.bios_loaded && !(forced && has_hle(num)) {
+            SwiPath::Bios
+        } else if has_hle(num) {
+            self.run_hle(num, pc);
+            SwiPath::Hle
+        } else {
+            SwiPath::Skipped
+        };
+        self.bios.record(SwiLogEntry { num, pc, path });
+        path
+    }
+
+    fn run_hle(&mut self, num: u8, pc: u32) {
+        match num {
+            SOFT_RESET => self.bios.reset_entry = Some(self.soft_reset_state()),
+            INTR_WAIT => self.hle_intr_wait(self.r[0] != 0, self.r[1] as u16, pc),
+            VBLANK_INTR_WAIT => {
+                self.r[0] = 1;
+                self.r[1] = 1;
+                self.hle_intr_wait(true, 1, pc);
+            },
+            DIV => self.hle_div(self.r[0], self.r[1]),
+            DIV_ARM => self.hle_div(self.r[1], self.r[0]),
+            SQRT => self.r[0] = sqrt(self.r[0]),
+            ARC_TAN => {
+                let (angle, a, b) = arc_tan(self.r[0] as i32);
+                self.r[0] = angle as i32 as u32;
+                self.r[1] = a as u32;
+                self.r[3] = b as u32;
+            },
+            ARC_TAN2 => self.hle_arc_tan2(),
+            CPU_SET => self.hle_cpu_set(),
+            CPU_FAST_SET => self.hle_cpu_fast_set(),
+            BG_AFFINE_SET => self.hle_bg_affine_set(),
+            OBJ_AFFINE_SET => self.hle_obj_affine_set(),
+            LZ77_UNCOMP_WRAM => {
+                let data = self.lz77_uncomp(self.r[0]);
+                for (i, byte) in data.iter().enumerate() {
+                    self.mem.set_byte(self.r[1] + i as u32, *byte);
+                }
+            },
+            LZ77_UNCOMP_VRAM => {
+                // VRAM can't be written a byte at a time, so the BIOS writes
+                // halfwords, padding an odd length with a zero
+                let data = self.lz77_uncomp(self.r[0]);
+                for (i, pair) in data.chunks(2).enumerate() {
+                    let val = pair[0] as u32 | (*pair.get(1).unwrap_or(&0) as u32) << 8;
+                    self.mem.set_halfword(self.r[1] + i as u32 * 2, val);
+                }
+            },
+            SOUND_BIAS => self.hle_sound_bias(),
+            MIDI_KEY_2_FREQ => {
+                let freq = self.mem.get_word(self.r[0].wrapping_add(4));
+                self.r[0] = midi_key_to_freq(freq, self.r[1], self.r[2]);
+            },
+            _ => panic!("should not get here"),
+        }
+    }
+
+    /// Turn on IME and wait for any of the interrupts in mask to be flagged
+    /// at 0x3007FF8 by the game's handler, then clear their flags. If
+    /// discard is set, flags set before the call don't count. While none
+    /// of them are flagged the call is retried from the SWI at pc
+    fn hle_intr_wait(&mut self, discard: bool, mask: u16, pc: u32) {
+        let retry = self.bios.waiting == Some(pc);
+        let flags = self.mem.get_halfword(IRQ_FLAGS);
+        let flags = if discard && !retry { flags & !mask } else { flags };
+        self.mem.set_halfword(IME, 1);
+        if flags & mask != 0 {
+            self.mem.set_halfword(IRQ_FLAGS, (flags & !mask) as u32);
+            self.bios.waiting = None;
+        } else {
+            self.mem.set_halfword(IRQ_FLAGS, flags as u32);
+            self.bios.waiting = Some(pc);
+            self.r[15] = pc;
+            self.should_flush = true;
+        }
+    }
+
+    /// Return the data compressed at src with the BIOS's LZ77 format: a
+    /// header word with the uncompressed length in bits 8-31, then groups of
+    /// 8 blocks each led by a byte of flags, first block in the top bit. A
+    /// block is either a byte to copy as is (flag clear), or a halfword
+    /// (flag set) that repeats 3-18 bytes (the top 4 bits + 3) starting 1-4096
+    /// bytes back (the other 12 bits + 1, big endian)
+    fn lz77_uncomp(&self, src: u32) -> Vec<u8> {
+        let len = (self.mem.get_word(src) >> 8) as usize;
+        let mut out = Vec::with_capacity(len);
+        let mut src = src + 4;
+        while out.len() < len {
+            let flags = self.mem.get_byte(src);
+            src += 1;
+            for bit in (0..8).rev() {
+                if out.len() >= len {
+                    break;
+                }
+                if flags & (1 << bit) == 0 {
+                    out.push(self.mem.get_byte(src));
+                    src += 1;
+                    continue;
+                }
+                let (hi, lo) = (self.mem.get_byte(src) as usize, self.mem.get_byte(src + 1) as usize);
+                src += 2;
+                let count = (hi >> 4) + 3;
+                let disp = ((hi & 0xF) << 8 | lo) + 1;
+                for _ in 0..count.min(len - out.len()) {
+                    // data from before the start of the output reads as 0
+                    let byte = out.len().checked_sub(disp).map_or(0, |i| out[i]);
+                    out.push(byte);
+                }
+            }
+        }
+        out
+    }
+
+    /// r0 = num / denom, r1 = num % denom, r3 = abs(num / denom). the real
+    /// BIOS hangs when dividing by zero, so we return the same values as
+    /// most other emulators instead
+    fn hle_div(&mut self, num: u32, denom: u32) {
+        let (num, denom) = (num as i32, denom as i32);
+        if denom == 0 {
+            self.r[0] = if num < 0 { -1i32 as u32 } else { 1 };
+            self.r[1] = num as u32;
+            self.r[3] = 1;
+            return;
+        }
+        let quot = num.wrapping_div(denom);
+        self.r[0] = quot as u32;
+        self.r[1] = num.wrapping_rem(denom) as u32;
+        self.r[3] = quot.wrapping_abs() as u32;
+    }
+
+    /// r0 = the angle of the vector (r0, r1), from 0 to 0xFFFF for a full
+    /// turn. Each octant is worked out with ArcTan, like the BIOS does, which
+    /// also leaves its r1
+    fn hle_arc_tan2(&mut self) {
+        let (x, y) = (self.r[0] as i32, self.r[1] as i32);
+        let angle = if y == 0 {
+            if x >= 0 { 0 } else { 0x8000 }
+        } else if x == 0 {
+            if y >= 0 { 0x4000 } else { 0xC000 }
+        } else {
+            // the angle of y / x, or of x / y for the octants where y is larger
+            let mut tan = |num: i32, denom: i32| {
+                let (angle, a, _) = arc_tan(num.wrapping_shl(14).wrapping_div(denom));
+                self.r[1] = a as u32;
+                angle as i32
+            };
+            if y >= 0 {
+                if x >= 0 && x >= y {
+                    tan(y, x)
+                } else if x < 0 && x.wrapping_neg() >= y {
+                    tan(y, x) + 0x8000
+                } else {
+                    0x4000 - tan(x, y)
+                }
+            } else {
+                if x <= 0 && x.wrapping_neg() > y.wrapping_neg() {
+                    tan(y, x) + 0x8000
+                } else if x > 0 && x >= y.wrapping_neg() {
+                    tan(y, x) + 0x10000
+                } else {
+                    0xC000 - tan(x, y)
+                }
+            }
+        };
+        self.r[0] = angle as u16 as u32;
+    }
+
+    /// Fill in the matrix and reference point of an affine background for
+    /// each of r2 entries from r0 to r1. Each 20 byte entry has the point in
+    /// the bitmap to put at the center (two 19.8 fixed point words), the
+    /// center on screen (two halfwords), the x and y scale (two 8.8
+    /// halfwords), and the angle in the top byte of a halfword. Each entry is
+    /// written as BG2PA-BG2PD and BG2X-BG2Y are laid out, so 16 bytes
+    fn hle_bg_affine_set(&mut self) {
+        let (mut src, mut dest) = (self.r[0], self.r[1]);
+        for _ in 0..self.r[2] {
+            let origin_x = self.mem.get_word(src) as i32;
+            let origin_y = self.mem.get_word(src + 4) as i32;
+            let center_x = self.mem.get_halfword(src + 8) as i16 as i32;
+            let center_y = self.mem.get_halfword(src + 10) as i16 as i32;
+            let scale_x = self.mem.get_halfword(src + 12) as i16 as i32;
+            let scale_y = self.mem.get_halfword(src + 14) as i16 as i32;
+            let theta = (self.mem.get_halfword(src + 16) >> 8) as u8;
+            let [pa, pb, pc, pd] = affine_matrix(scale_x, scale_y, theta);
+            let ref_x = origin_x.wrapping_sub(pa * center_x + pb * center_y);
+            let ref_y = origin_y.wrapping_sub(pc * center_x + pd * center_y);
+            for (i, param) in [pa, pb, pc, pd].iter().enumerate() {
+                self.mem.set_halfword(dest + i as u32 * 2, *param as u16 as u32);
+            }
+            self.mem.set_word(dest + 8, ref_x as u32);
+            self.mem.set_word(dest + 12, ref_y as u32);
+            src += 20;
+            dest += 16;
+        }
+    }
+
+    /// Fill in the matrix of an affine sprite for each of r2 entries from r0
+    /// to r1. Each 8 byte entry has the x and y scale (two 8.8 halfwords) and
+    /// the angle in the top byte of a halfword. The 4 halfwords of each matrix
+    /// are written r3 bytes apart, which is 2 for a plain array or 8 to write
+    /// straight into OAM
+    fn hle_obj_affine_set(&mut self) {
+        let (mut src, mut dest, stride) = (self.r[0], self.r[1], self.r[3]);
+        for _ in 0..self.r[2] {
+            let scale_x = self.mem.get_halfword(src) as i16 as i32;
+            let scale_y = self.mem.get_halfword(src + 2) as i16 as i32;
+            let theta = (self.mem.get_halfword(src + 4) >> 8) as u8;
+            for param in affine_matrix(scale_x, scale_y, theta).iter() {
+                self.mem.set_halfword(dest, *param as u16 as u32);
+                dest += stride;
+            }
+            src += 8;
+        }
+    }
+
+    /// Move the bias level in SOUNDBIAS to 0 if r0 is 0, or to 0x200
+    /// otherwise, keeping the amplitude resolution bits. The BIOS gets there
+    /// a step at a time with a delay in between, to avoid a click, but only
+    /// the final level is heard here since samples aren't mixed until the
+    /// call returns
+    fn hle_sound_bias(&mut self) {
+        let level = if self.r[0] == 0 { 0 } else { 0x200 };
+        let reg = self.mem.get_halfword(SOUNDBIAS) as u32;
+        self.mem.set_halfword(SOUNDBIAS, (reg & !0x3FF) | level);
+    }
+
+    /// Copy or fill memory from r0 to r1. r2 contains the number of units to
+    /// copy in bits 0-20, fills with the first unit of the source if bit 24 is
+    /// set, and uses 32 bit units if bit 26 is set (otherwise 16 bit)
+    fn hle_cpu_set(&mut self) {
+        let (src, dest, cnt) = (self.r[0], self.r[1], self.r[2]);
+        let count = cnt & 0x1FFFFF;
+        let fill = (cnt >> 24) & 1 == 1;
+        if (cnt >> 26) & 1 == 1 {
+            self.hle_copy_words(src & !3, dest & !3, count, fill, 1);
+        } else {
+            let (src, dest) = (src & !1, dest & !1);
+            for i in 0..count {
+                let offset = if fill { 0 } else { i * 2 };
+                let val = self.mem.get_halfword(src + offset);
+                self.mem.set_halfword(dest + i * 2, val as u32);
+            }
+            self.bios.cycles = self.copy_cycles(src, dest, count, 1);
+        }
+    }
+
+    /// Like CpuSet but always uses 32 bit units, and the count is rounded up
+    /// to a multiple of 8 words
+    fn hle_cpu_fast_set(&mut self) {
+        let (src, dest, cnt) = (self.r[0], self.r[1], self.r[2]);
+        let count = ((cnt & 0x1FFFFF) + 7) & !7;
+        let fill = (cnt >> 24) & 1 == 1;
+        self.hle_copy_words(src & !3, dest & !3, count, fill, 8);
+    }
+
+    /// Copy words in one go if possible, since this is how most games load
+    /// their graphics, and charge the cycles of the BIOS's loop, which moves
+    /// block words per iteration
+    fn hle_copy_words(&mut self, src: u32, dest: u32, count: u32, fill: bool, block: u32) {
+        if !self.mem.copy_words(src, dest, count, fill) {
+            for i in 0..count {
+                let offset = if fill { 0 } else { i * 4 };
+                let val = self.mem.get_word(src + offset);
+                self.mem.set_word(dest + i * 4, val);
+            }
+        }
+        self.bios.cycles = self.copy_cycles(src, dest, count, block);
+    }
+
+    /// The cycles taken by the BIOS to copy count units, block at a time.
+    /// Each block is read and written as 1N + (block - 1)S. The waitstates
+    /// only depend on the region, so one block is timed and multiplied out
+    fn copy_cycles(&self, src: u32, dest: u32, count: u32, block: u32) -> u32 {
+        let blocks = (count + block - 1) / block;
+        if blocks == 0 {
+            return 0;
+        }
+        let mut cycles = COPY_LOOP_CYCLES;
+        for &addr in [src, dest].iter() {
+            cycles += self.mem.access_time(addr, true);
+            if block > 1 {
+                cycles += (block - 1) * self.mem.access_time(addr, false);
+            }
+        }
+        blocks * cycles
+    }
+}
+
+/// The entry of the BIOS's sine table for the given angle, out of 256 for a
+/// full turn
+fn sine(angle: u8) -> i32 {
+    let i = (angle & 0x7F) as usize;
+    let val = if i <= 64 { SINE_QUARTER[i] } else { SINE_QUARTER[128 - i] };
+    if angle >= 0x80 { -val } else { val }
+}
+
+/// The 8.8 fixed point matrix (PA, PB, PC, PD) that rotates by theta (out of
+/// 256 for a full turn) and then scales by the given 8.8 factors, as the
+/// affine helpers work it out from the sine table
+fn affine_matrix(scale_x: i32, scale_y: i32, theta: u8) -> [i32; 4] {
+    let (sin, cos) = (sine(theta), sine(theta.wrapping_add(0x40)));
+    [
+        (cos * scale_x) >> 14,
+        (-sin * scale_x) >> 14,
+        (sin * scale_y) >> 14,
+        (cos * scale_y) >> 14,
+    ]
+}
+
+/// The square root of val, rounded down
+fn sqrt(val: u32) -> u32 {
+    // a double has enough precision for the estimate to be at most 1 off
+    let mut root = (val as f64).sqrt() as u32;
+    while root as u64 * root as u64 > val as u64 {
+        root -= 1;
+    }
+    while (root as u64 + 1) * (root as u64 + 1) <= val as u64 {
+        root += 1;
+    }
+    root
+}
+
+/// 2^31 * 2^(n / 12) for each semitone n of an octave, as in the BIOS
+const FREQ_TABLE: [u32; 12] = [
+    2147483648, 2275179671, 2410468894, 2553802834, 2705659852, 2866546760,
+    3037000500, 3217589947, 3408917802, 3611622603, 3826380858, 4053909305,
+];
+
+/// The BIOS's 2^32 / 2 ^ ((180 - key) / 12) for a MIDI key up to 179. Its
+/// scale table holds the semitone and the octaves below 180 for each key,
+/// which are worked out here instead
+fn key_scale(key: u32) -> u32 {
+    FREQ_TABLE[(key % 12) as usize] >> (14 - key / 12)
+}
+
+/// The sample rate to play a sample recorded at the given rate (in the freq
+/// field of the MP2k engine's WaveData, so 1024 times the rate) at the given
+/// MIDI key, plus a fine adjustment in 256ths of a semitone. Like the BIOS,
+/// this interpolates between the scales of the key and the next one, in
+/// 32 bit fixed point, and keys past 178 play as 178 plus 255/256
+fn midi_key_to_freq(freq: u32, key: u32, fine: u32) -> u32 {
+    let high_mul = |a: u32, b: u32| ((a as u64 * b as u64) >> 32) as u32;
+    let (key, fine) = if key > 178 { (178, 255 << 24) } else { (key, fine << 24) };
+    let (low, high) = (key_scale(key), key_scale(key + 1));
+    high_mul(freq, low + high_mul(high - low, fine))
+}
+
+/// The BIOS's arctangent of a 1.1.14 fixed point value, which evaluates a
+/// polynomial with 16.14 fixed point steps. Returns the angle (from -0x2000
+/// to 0x2000 for -45 to 45 degrees) along with the values the BIOS leaves in
+/// r1 and r3
+fn arc_tan(tan: i32) -> (i16, i32, i32) {
+    let a = -(tan.wrapping_mul(tan) >> 14);
+    let mut b = ((0xA9 * a) >> 14) + 0x390;
+    for &coeff in [0x91C, 0xFB6, 0x16AA, 0x2081, 0x3651, 0xA2F9].iter() {
+        b = (b.wrapping_mul(a) >> 14) + coeff;
+    }
+    ((tan.wrapping_mul(b) >> 16) as i16, a, b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::CPUWrapper;
+
+    #[test]
+    fn div() {
+        let mut cpu = CPU::new();
+        cpu.r[0] = -7i32 as u32;
+        cpu.r[1] = 2;
+        assert_eq!(cpu.run_swi(DIV, 0), SwiPath::Hle);
+        assert_eq!(cpu.r[0], -3i32 as u32);
+        assert_eq!(cpu.r[1], -1i32 as u32);
+        assert_eq!(cpu.r[3], 3);
+
+        cpu.r[0] = 2;
+        cpu.r[1] = 9;
+        cpu.run_swi(DIV_ARM, 0);
+        assert_eq!(cpu.r[0], 4);
+        assert_eq!(cpu.r[1], 1);
+    }
+
+    #[test]
+    fn math() {
+        let mut cpu = CPU::new();
+        for &(val, root) in [(0, 0), (15, 3), (16, 4), (0x10000, 0x100),
+                             (0xFFFFFFFF, 0xFFFF)].iter() {
+            cpu.r[0] = val;
+            cpu.run_swi(SQRT, 0);
+            assert_eq!(cpu.r[0], root);
+        }
+
+        // tan 1, -1 and 0.5
+        for &(tan, angle) in [(0x4000, 0x2000), (-0x4000, -0x2000), (0x2000, 0x12E4)].iter() {
+            cpu.r[0] = tan as u32;
+            cpu.run_swi(ARC_TAN, 0);
+            assert_eq!(cpu.r[0], angle as u32);
+        }
+        assert_eq!((cpu.r[1], cpu.r[3]), (-0x1000i32 as u32, 0x9720));
+
+        // each quadrant and both axes
+        for &(x, y, angle) in [(0x100, 0x100, 0x2000), (-0x100, 0x100, 0x6000),
+                               (0x100, -0x100, 0xE000), (0x1000, 0x800, 0x12E4),
+                               (-3, -4, 0xA5C9), (0, 5, 0x4000), (-5, 0, 0x8000)].iter() {
+            cpu.r[0] = x as u32;
+            cpu.r[1] = y as u32;
+            cpu.run_swi(ARC_TAN2, 0);
+            assert_eq!(cpu.r[0], angle, "({}, {})", x, y);
+        }
+
+        // the most negative values can't be negated, but don't panic
+        for &(x, y) in [(i32::MIN, 1), (i32::MIN, -1), (-1, i32::MIN),
+                        (i32::MIN, i32::MIN)].iter() {
+            cpu.r[0] = x as u32;
+            cpu.r[1] = y as u32;
+            cpu.run_swi(ARC_TAN2, 0);
+        }
+    }
+
+    #[test]
+    fn sound() {
+        let mut cpu = CPU::new();
+        cpu.mem.set_halfword(0x4000088, 0xC123);
+        cpu.r[0] = 1;
+        cpu.run_swi(SOUND_BIAS, 0);
+        assert_eq!(cpu.mem.get_halfword(0x4000088), 0xC200);
+        assert_eq!(cpu.mem.apu.bias, 0x200);
+        cpu.r[0] = 0;
+        cpu.run_swi(SOUND_BIAS, 0);
+        assert_eq!(cpu.mem.get_halfword(0x4000088), 0xC000);
+
+        // a sample recorded at 13379Hz played at middle C (60), which is the
+        // key the engine treats as the recorded pitch, an octave up, a
+        // semitone and a half down, and at the highest keys
+        cpu.mem.set_word(0x3000004, 13379 << 10);
+        for &(key, fine, freq) in [(60, 0, 13379), (72, 0, 26758), (58, 128, 12273),
+                                   (0, 0, 418), (178, 0, 12205397), (180, 0, 12928333)].iter() {
+            cpu.r[0] = 0x3000000;
+            cpu.r[1] = key;
+            cpu.r[2] = fine;
+            assert_eq!(cpu.run_swi(MIDI_KEY_2_FREQ, 0), SwiPath::Hle);
+            assert_eq!(cpu.r[0], freq, "key {} fine {}", key, fine);
+        }
+    }
+
+    #[test]
+    fn affine_set() {
+        let mut cpu = CPU::new();
+        // 2x horizontally at 45 degrees, and the identity
+        for (i, hw) in [0x200, 0x100, 0x2000, 0, 0x100, 0x100, 0, 0].iter().enumerate() {
+            cpu.mem.set_halfword(0x3000000 + i as u32 * 2, *hw);
+        }
+        cpu.r[0] = 0x3000000;
+        cpu.r[1] = 0x7000006;
+        cpu.r[2] = 2;
+        cpu.r[3] = 8;
+        cpu.run_swi(OBJ_AFFINE_SET, 0);
+        let params = |cpu: &CPU, start: u32| -> Vec<u16> {
+            (0..4).map(|i| cpu.mem.get_halfword(start + i * 8)).collect()
+        };
+        assert_eq!(params(&cpu, 0x7000006), vec![362, -363i16 as u16, 181, 181]);
+        assert_eq!(params(&cpu, 0x7000026), vec![0x100, 0, 0, 0x100]);
+        assert_eq!(cpu.mem.sprites.affine_params[0].dmx, -363.0 / 256.0);
+
+        // turn 90 degrees about (64, 32) in the bitmap, centered on the screen
+        cpu.mem.set_word(0x3000100, 64 << 8);
+        cpu.mem.set_word(0x3000104, 32 << 8);
+        for (i, hw) in [120, 80, 0x100, 0x100, 0x4000].iter().enumerate() {
+            cpu.mem.set_halfword(0x3000108 + i as u32 * 2, *hw);
+        }
+        cpu.r[0] = 0x3000100;
+        cpu.r[1] = 0x4000020;
+        cpu.r[2] = 1;
+        cpu.run_swi(BG_AFFINE_SET, 0);
+        assert_eq!((0..4).map(|i| cpu.mem.get_halfword(0x4000020 + i * 2)).collect::<Vec<_>>(),
+            vec![0, -0x100i16 as u16, 0x100, 0]);
+        assert_eq!(cpu.mem.get_word(0x4000028), 0x9000);
+        assert_eq!(cpu.mem.get_word(0x400002C), -0x5800i32 as u32);
+        assert_eq!(cpu.mem.graphics.bg_affine[0].ref_x, 144.0);
+    }
+
+    #[test]
+    fn cpu_set() {
+        let mut cpu = CPU::new();
+        for i in 0..4 {
+            cpu.mem.set_word(0x3000000 + i * 4, i + 1);
+        }
+
+        cpu.r[0] = 0x3000000;
+        cpu.r[1] = 0x3000100;
+        cpu.r[2] = 3;
+        cpu.run_swi(CPU_SET, 0);
+        assert_eq!(cpu.mem.get_word(0x3000100), 1);
+        assert_eq!(cpu.mem.get_halfword(0x3000104), 2);
+        assert_eq!(cpu.mem.get_halfword(0x3000106), 0);
+
+        cpu.r[1] = 0x3000200;
+        cpu.r[2] = (1 << 26) | (1 << 24) | 2;
+        cpu.run_swi(CPU_SET, 0);
+        assert_eq!(cpu.mem.get_word(0x3000200), 1);
+        assert_eq!(cpu.mem.get_word(0x3000204), 1);
+        assert_eq!(cpu.mem.get_word(0x3000208), 0);
+
+        cpu.r[1] = 0x3000300;
+        cpu.r[2] = 1;
+        cpu.run_swi(CPU_FAST_SET, 0);
+        assert_eq!(cpu.mem.get_word(0x300030C), 4);
+        assert_eq!(cpu.mem.get_word(0x300031C), 0);
+        // one 8 word block in IWRAM, with no waitstates
+        assert_eq!(cpu.bios.take_cycles(), 7 + 8 + 8);
+    }
+
+    #[test]
+    fn lz77() {
+        let mut cpu = CPU::new();
+        // "ABC", then 6 bytes from 3 back, then "X"
+        let data = [0x10, 0x0A, 0, 0, 0x10, 0x41, 0x42, 0x43, 0x30, 0x02, 0x58];
+        for (i, byte) in data.iter().enumerate() {
+            cpu.mem.set_byte(0x2000000 + i as u32, *byte);
+        }
+        cpu.r[0] = 0x2000000;
+        cpu.r[1] = 0x3000000;
+        assert_eq!(cpu.run_swi(LZ77_UNCOMP_WRAM, 0), SwiPath::Hle);
+        let out: Vec<u8> = (0..11).map(|i| cpu.mem.get_byte(0x3000000 + i)).collect();
+        assert_eq!(&out[..], b"ABCABCABCX\0");
+
+        // 9 bytes are written as 5 halfwords
+        cpu.mem.set_byte(0x2000001, 9);
+        cpu.r[1] = 0x6000000;
+        cpu.run_swi(LZ77_UNCOMP_VRAM, 0);
+        assert_eq!(cpu.mem.get_halfword(0x6000006), 0x4241);
+        assert_eq!(cpu.mem.get_halfword(0x6000008), 0x0043);
+    }
+
+    #[test]
+    fn intr_wait() {
+        let mut cpu = CPU::new();
+        // an interrupt that's already been flagged counts for IntrWait with
+        // r0 clear
+        cpu.mem.set_halfword(IRQ_FLAGS, 3);
+        cpu.r[0] = 0;
+        cpu.r[1] = 1;
+        cpu.r[15] = 0x3000104;
+        cpu.run_swi(INTR_WAIT, 0x3000100);
+        assert_eq!(cpu.mem.get_halfword(IRQ_FLAGS), 2);
+        assert_eq!(cpu.mem.get_halfword(IME), 1);
+        assert_eq!((cpu.r[15], cpu.should_flush), (0x3000104, false));
+
+        // but not for VBlankIntrWait, which waits by retrying the SWI
+        cpu.mem.set_halfword(IRQ_FLAGS, 1);
+        cpu.run_swi(VBLANK_INTR_WAIT, 0x3000100);
+        assert_eq!(cpu.mem.get_halfword(IRQ_FLAGS), 0);
+        assert_eq!((cpu.r[15], cpu.should_flush), (0x3000100, true));
+
+        // the retry doesn't discard the flag set by the handler since
+        cpu.should_flush = false;
+        cpu.r[15] = 0x3000104;
+        cpu.mem.set_halfword(IRQ_FLAGS, 5);
+        cpu.run_swi(VBLANK_INTR_WAIT, 0x3000100);
+        assert_eq!(cpu.mem.get_halfword(IRQ_FLAGS), 4);
+        assert_eq!((cpu.r[15], cpu.should_flush), (0x3000104, false));
+        assert_eq!((cpu.r[0], cpu.r[1]), (1, 1));
+    }
+
+    #[test]
+    fn vblank_intr_wait() {
+        // with no BIOS, the default dispatch still gets the VBlank IRQ to
+        // the game's handler, which sets the flag the wait is looking for
+        let mut gba = CPUWrapper::new();
+        let main: [u32; 3] = [
+            0xEF050000, // swi 0x50000
+            0xE2844001, // add r4, r4, #1
+            0xEAFFFFFC, // b 0x3000000
+        ];
+        let handler: [u32; 11] = [
+            0xE3A03301, // mov r3, #0x4000000
+            0xE2833C02, // add r3, r3, #0x200
+            0xE3A02001, // mov r2, #1
+            0xE1C320B2, // strh r2, [r3, #2]
+            0xE3A00403, // mov r0, #0x3000000
+            0xE2800C7F, // add r0, r0, #0x7F00
+            0xE28000F8, // add r0, r0, #0xF8
+            0xE1D020B0, // ldrh r2, [r0]
+            0xE3822001, // orr r2, r2, #1
+            0xE1C020B0, // strh r2, [r0]
+            0xE12FFF1E, // bx lr
+        ];
+        for (i, ins) in main.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000000 + i as u32 * 4, *ins);
+        }
+        for (i, ins) in handler.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000100 + i as u32 * 4, *ins);
+        }
+        gba.cpu.mem.set_word(0x3007FFC, 0x3000100);
+        gba.direct_boot_at(0x3000000);
+        gba.cpu.mem.set_halfword(0x4000004, 0x8); // VBlank IRQ
+        gba.cpu.mem.set_halfword(0x4000200, 0x1); // IE = VBlank
+
+        assert_eq!(gba.cpu.bios.irq_dispatch, IrqDispatch::Bios);
+        for _ in 0..3 {
+            gba.frame().unwrap();
+        }
+        assert_eq!(gba.cpu.r[4], 3);
+
+        // a state saved part way through the wait carries on waiting, rather
+        // than discarding the flag when the call is retried
+        while gba.cpu.bios.waiting.is_none() || !gba.cpu.mem.int.triggered.vblank {
+            gba.step().unwrap();
+        }
+        let mut restored = CPUWrapper::new();
+        restored.load_state(&gba.save_state()).unwrap();
+        for _ in 0..3 {
+            gba.frame().unwrap();
+            restored.frame().unwrap();
+        }
+        assert_eq!(restored.cpu.r[4], gba.cpu.r[4]);
+    }
+
+    #[test]
+    fn soft_reset() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_word(0x3000000, 0xEF000000); // swi 0
+        let mut rom = vec![0x05, 0x40, 0xA0, 0xE3]; // mov r4, #5
+        rom.resize(0x10, 0);
+        gba.cpu.mem.load_rom(rom);
+        gba.direct_boot_at(0x3000000);
+        gba.cpu.r[4] = 1;
+        while gba.cpu.r[15] != 0x8000000 {
+            gba.step().unwrap();
+        }
+        assert_eq!((gba.cpu.r[4], gba.pipeline_depth()), (0, 0));
+        assert!(gba.last_instruction.is_none());
+        for _ in 0..3 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.cpu.r[4], 5);
+    }
+
+    #[test]
+    fn policy() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.run_swi(0x03, 0x100), SwiPath::Skipped);
+
+        cpu.mem.bios_loaded = true;
+        assert_eq!(cpu.run_swi(DIV, 0x104), SwiPath::Bios);
+        cpu.bios.force_hle[DIV as usize] = true;
+        cpu.bios.force_hle[0x03] = true;
+        assert_eq!(cpu.run_swi(DIV, 0x108), SwiPath::Hle);
+        // can't force HLE for calls that haven't been implemented
+        assert_eq!(cpu.run_swi(0x03, 0x10C), SwiPath::Bios);
+
+        assert_eq!(cpu.bios.log.len(), 4);
+        assert_eq!(cpu.bios.log[2], SwiLogEntry { num: DIV, pc: 0x108, path: SwiPath::Hle });
+        assert!(cpu.bios.format_log().contains("0x00000104: SWI 0x06 (Bios)"));
+
+        for _ in 0..SWI_LOG_LEN {
+            cpu.run_swi(DIV, 0);
+        }
+        assert_eq!(cpu.bios.log.len(), SWI_LOG_LEN);
+    }
+}
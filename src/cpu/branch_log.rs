@@ -0,0 +1,123 @@
+//! Records the branches the CPU has taken recently, for finding your way
+//! around a game without a full trace: the edges that are taken the most are
+//! usually the main loop and the functions it calls every frame, and the
+//! BIOS's jump out of its interrupt handler shows where the game's own IRQ
+//! handler is. Any instruction that writes the PC counts as a branch, so
+//! returns (e.g. BX LR or POP {PC}) show up too. Repeats of an edge that's
+//! already in the log are counted instead of taking up another entry, so a
+//! tight loop doesn't push everything else out. Logging is off by default.
+
+/// number of distinct edges kept in the log
+pub const BRANCH_LOG_LEN: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BranchEdge {
+    /// address of the branch instruction
+    pub from: u32,
+    /// address that was branched to
+    pub to: u32,
+    /// number of times the branch was taken while it was in the log
+    pub count: u32,
+}
+
+pub struct BranchLog {
+    pub enabled: bool,
+    /// least recently taken first
+    edges: Vec<BranchEdge>,
+}
+
+impl BranchLog {
+    pub const fn new() -> BranchLog {
+        BranchLog {
+            enabled: false,
+            edges: Vec::new(),
+        }
+    }
+
+    /// Count a branch from the given address to the other. The edge becomes
+    /// the most recent one, and if the log is full the least recently taken
+    /// edge is dropped to make room for it
+    pub fn record(&mut self, from: u32, to: u32) {
+        let count = match self.edges.iter().position(|e| e.from == from && e.to == to) {
+            Some(i) => self.edges.remove(i).count.saturating_add(1),
+            None => {
+                if self.edges.len() == BRANCH_LOG_LEN {
+                    self.edges.remove(0);
+                }
+                1
+            },
+        };
+        self.edges.push(BranchEdge { from, to, count });
+    }
+
+    /// Return the edges in the log, least recently taken first
+    pub fn edges(&self) -> &[BranchEdge] {
+        &self.edges
+    }
+
+    /// Return the edges in the log, most often taken first
+    pub fn hottest(&self) -> Vec<BranchEdge> {
+        let mut edges = self.edges.clone();
+        edges.sort_by(|a, b| b.count.cmp(&a.count));
+        edges
+    }
+
+    pub fn clear(&mut self) {
+        self.edges.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::CPUWrapper;
+
+    #[test]
+    fn aggregate() {
+        let mut log = BranchLog::new();
+        log.record(0x100, 0x200);
+        log.record(0x300, 0x400);
+        log.record(0x100, 0x200);
+        assert_eq!(log.edges(), &[
+            BranchEdge { from: 0x300, to: 0x400, count: 1 },
+            BranchEdge { from: 0x100, to: 0x200, count: 2 },
+        ]);
+
+        // the least recently taken edge is dropped when the log is full
+        for i in 0..(BRANCH_LOG_LEN as u32 - 1) {
+            log.record(0x1000 + i * 4, 0);
+        }
+        assert_eq!(log.edges().len(), BRANCH_LOG_LEN);
+        assert_eq!(log.edges()[0], BranchEdge { from: 0x100, to: 0x200, count: 2 });
+        assert_eq!(log.hottest()[0], BranchEdge { from: 0x100, to: 0x200, count: 2 });
+
+        log.clear();
+        assert!(log.edges().is_empty());
+    }
+
+    #[test]
+    fn records_taken_branches() {
+        let mut gba = CPUWrapper::new();
+        let code: [u16; 4] = [
+            0x3001, // add r0, #1
+            0x2803, // cmp r0, #3
+            0xD1FC, // bne .-4
+            0xE7FE, // b .
+        ];
+        for (i, ins) in code.iter().enumerate() {
+            gba.cpu.mem.set_halfword(0x3000000 + i as u32 * 2, *ins as u32);
+        }
+        gba.direct_boot_at(0x3000001);
+        gba.branch_log.enabled = true;
+        for _ in 0..30 {
+            gba.step().unwrap();
+        }
+        // the BNE is taken twice before it falls through, and the last B is
+        // taken over and over
+        let edges = gba.branch_log.edges();
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[0], BranchEdge { from: 0x3000004, to: 0x3000000, count: 2 });
+        assert_eq!((edges[1].from, edges[1].to), (0x3000006, 0x3000006));
+        assert!(edges[1].count > 2);
+    }
+}
@@ -0,0 +1,412 @@
+//! Games spend much of their loading time copying memory around, e.g. tiles
+//! into VRAM or code into IWRAM, and most of them (and the BIOS's CpuFastSet)
+//! do it with a loop like
+//!     loop: LDMIA r0!, {r2-r9}
+//!           STMIA r1!, {r2-r9}
+//!           SUBS  r10, r10, #1    (or CMP r1, r10)
+//!           BNE   loop
+//! Running every iteration through the pipeline is slow, so once an
+//! iteration of a loop like this has run normally, the iterations after it
+//! are run in one go: the words are copied as a single slice, the pointers
+//! and counter are moved on, and each iteration is charged the cycles the
+//! normal one took. This keeps the result exact:
+//!   - only iterations that would finish before the next LCD event or serial
+//!     transfer are skipped, so DMA, interrupts and rendering see the same
+//!     memory at the same time as they would have
+//!   - the iteration that was timed must not have had anything else (DMA or
+//!     an interrupt) run part way through, or have crossed an LCD event,
+//!     since then its cycles don't match the rest
+//!   - the last iteration always runs normally, which leaves the loaded
+//!     registers and the flags as they should be
+
+use ::cpu::CPU;
+use ::cpu::arm::RegOrImm;
+use ::cpu::arm::block_trans::BlockDataTransfer;
+use ::cpu::arm::branch::Branch;
+use ::cpu::arm::data::{self, DataProc, Op};
+use ::cpu::pipeline::{decode_arm, decode_thumb, Instruction, satisfies_cond};
+use ::cpu::status_reg::InstructionSet;
+use ::util;
+
+/// the condition field for instructions that always run
+const AL: u32 = 0xE;
+
+/// The check at the end of each iteration of a copy loop
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoopTest {
+    /// SUBS reg, reg, #step, counting down the iterations left
+    Count { reg: usize, step: u32 },
+    /// CMP ptr, end, comparing the source or destination pointer with where
+    /// the copy stops
+    End { ptr: usize, end: usize },
+}
+
+/// A loop of an LDMIA, an STMIA of the same registers, a LoopTest, and a
+/// conditional branch back to the LDMIA
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CopyLoop {
+    /// the registers holding the source and destination pointers
+    pub src: usize,
+    pub dest: usize,
+    /// the number of words copied by each iteration
+    pub words: u32,
+    pub test: LoopTest,
+    /// the condition for going round again
+    pub cond: u32,
+}
+
+impl CopyLoop {
+    /// Return the copy loop starting at the given address, in the current
+    /// instruction set, if there is one
+    pub fn find(cpu: &CPU, head: u32) -> Option<CopyLoop> {
+        let size = cpu.instruction_size();
+        if cpu.hle_fetch(head).is_some() || !cpu.mem.is_mapped(head + 4 * size - 1) {
+            return None;
+        }
+        // THUMB instructions are unconditional apart from the branch, whose
+        // condition is part of the decoded instruction
+        let decode = |i: u32| -> Option<(u32, Instruction)> {
+            let addr = head + i * size;
+            match cpu.cpsr.isa {
+                InstructionSet::ARM => {
+                    let raw = cpu.mem.fetch_word(addr);
+                    decode_arm(raw).map(|ins| (util::get_nibble(raw, 28), ins))
+                },
+                InstructionSet::THUMB =>
+                    decode_thumb(cpu.mem.fetch_halfword(addr)).map(|ins| (AL, ins)),
+            }
+        };
+
+        let (load, store) = match (decode(0)?, decode(1)?) {
+            ((AL, Instruction::BlockTransfer(load)), (AL, Instruction::BlockTransfer(store))) =>
+                (load, store),
+            _ => return None,
+        };
+        let list = load.register_list;
+        if !is_copy_half(&load, true) || !is_copy_half(&store, false) ||
+            store.register_list != list || load.rn == store.rn {
+            return None;
+        }
+        let (src, dest) = (load.rn, store.rn);
+        // registers that the loop needs to keep can't be loaded over
+        let free = |reg: usize| reg != 15 && reg != src && reg != dest && list & (1 << reg) == 0;
+
+        let test = match decode(2)? {
+            (AL, Instruction::DataProc(DataProc {
+                opcode: Op::SUB, set_flags: true, rn, rd, op2: RegOrImm::Imm { rotate, value }
+            })) if rn == rd && free(rd) =>
+                LoopTest::Count { reg: rd, step: value.rotate_right(rotate * 2) },
+            (AL, Instruction::DataProc(DataProc {
+                opcode: Op::CMP, rn, op2: RegOrImm::Reg { shift: 0, reg }, ..
+            })) if (rn == src || rn == dest) && free(reg as usize) =>
+                LoopTest::End { ptr: rn, end: reg as usize },
+            _ => return None,
+        };
+
+        let branch_addr = head + 3 * size;
+        let (cond, offset) = match decode(3)? {
+            (cond, Instruction::Branch(Branch { offset, link: false })) => (cond, offset),
+            (_, Instruction::CondBranch(branch)) => (branch.cond as u32, branch.offset as i32),
+            _ => return None,
+        };
+        // a loop that always goes round again isn't copying anything useful
+        let target = (branch_addr + 2 * size).wrapping_add(offset as u32);
+        if cond >= AL || target != head {
+            return None;
+        }
+
+        Some(CopyLoop { src, dest, words: list.count_ones(), test, cond })
+    }
+
+    /// Return how many iterations in a row, starting with the one about to
+    /// run, would end by going round again, up to max
+    pub fn iterations(&self, cpu: &CPU, max: u32) -> u32 {
+        let mut flags = cpu.cpsr;
+        let stride = self.words * 4;
+        for i in 0..max {
+            let (op1, op2) = match self.test {
+                LoopTest::Count { reg, step } =>
+                    (cpu.get_reg(reg).wrapping_sub(step.wrapping_mul(i)), step),
+                LoopTest::End { ptr, end } =>
+                    (cpu.get_reg(ptr).wrapping_add(stride * (i + 1)), cpu.get_reg(end)),
+            };
+            let (result, carry, overflow) = data::sub(op1, op2, 1);
+            flags.zero = result == 0;
+            flags.neg = util::get_bit(result, 31);
+            flags.carry = carry;
+            flags.overflow = overflow.unwrap();
+            if !satisfies_cond(&flags, self.cond) {
+                return i;
+            }
+        }
+        max
+    }
+
+    /// Run count iterations of the loop starting at head at once, leaving the
+    /// pointers and counter as they would be after them. The registers in
+    /// the list and the flags are left alone, since the next iteration sets
+    /// them. Returns false without changing anything if the copy can't be
+    /// done in one go
+    pub fn skip(&self, cpu: &mut CPU, head: u32, count: u32) -> bool {
+        let (src, dest) = (cpu.get_reg(self.src), cpu.get_reg(self.dest));
+        let len = count * self.words * 4;
+        // the loop overwriting itself would leave stale instructions in the
+        // pipeline
+        let code_len = 4 * cpu.instruction_size();
+        if (dest as u64) < (head + code_len) as u64 && (head as u64) < dest as u64 + len as u64 {
+            return false;
+        }
+        if !cpu.mem.copy_words(src, dest, count * self.words, false) {
+            return false;
+        }
+        cpu.set_reg(self.src, src.wrapping_add(len));
+        cpu.set_reg(self.dest, dest.wrapping_add(len));
+        if let LoopTest::Count { reg, step } = self.test {
+            let left = cpu.get_reg(reg);
+            cpu.set_reg(reg, left.wrapping_sub(step.wrapping_mul(count)));
+        }
+        true
+    }
+}
+
+/// Return true if the given instruction can be the load (or store) of a copy
+/// loop: incrementing after each word, writing back, and not touching the PC
+/// or the user bank
+fn is_copy_half(ins: &BlockDataTransfer, load: bool) -> bool {
+    ins.load == load && !ins.pre_index && ins.offset_up && ins.write_back && !ins.force &&
+        ins.rn != 15 && ins.register_list != 0 && ins.register_list & (1 << 15) == 0 &&
+        ins.register_list & (1 << ins.rn) == 0
+}
+
+/// Watches copy loops go round, timing each iteration
+pub struct FastCopy {
+    /// turn off to run every iteration of copy loops normally, e.g. when
+    /// stepping through one in a debugger
+    pub enabled: bool,
+    /// the iteration being timed
+    current: Option<Iteration>,
+}
+
+struct Iteration {
+    /// the address of the loop's LDMIA
+    head: u32,
+    /// the instructions run so far, and the cycles they took
+    steps: u32,
+    cycles: u32,
+    /// set if DMA, an interrupt or an LCD event happened part way through
+    interrupted: bool,
+}
+
+/// the number of instructions in an iteration of a copy loop
+const LOOP_LEN: u32 = 4;
+
+impl FastCopy {
+    pub const fn new() -> FastCopy {
+        FastCopy {
+            enabled: true,
+            current: None,
+        }
+    }
+
+    /// Return the cycles taken by the iteration that just finished, if it
+    /// was a whole iteration of the loop at head that can be used to time
+    /// the ones after it
+    pub fn last_iteration(&self, head: u32) -> Option<u32> {
+        match self.current {
+            Some(ref it) if it.head == head && it.steps == LOOP_LEN && !it.interrupted =>
+                Some(it.cycles),
+            _ => None,
+        }
+    }
+
+    /// Start timing an iteration of the loop at head
+    pub fn start_iteration(&mut self, head: u32) {
+        self.current = Some(Iteration { head, steps: 0, cycles: 0, interrupted: false });
+    }
+
+    /// Count an instruction towards the iteration being timed. Steps that
+    /// only refill the pipeline aren't counted, their cycles are part of the
+    /// branch's
+    pub fn record_step(&mut self, cycles: u32) {
+        let done = match self.current {
+            Some(ref mut it) => {
+                it.steps += 1;
+                it.cycles += cycles;
+                it.steps > LOOP_LEN
+            },
+            None => false,
+        };
+        if done {
+            self.current = None;
+        }
+    }
+
+    /// Note that something other than the loop happened, e.g. DMA
+    pub fn interrupt(&mut self) {
+        if let Some(ref mut it) = self.current {
+            it.interrupted = true;
+        }
+    }
+
+    /// Stop timing, e.g. after the CPU state has been replaced
+    pub fn forget(&mut self) {
+        self.current = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::cpu::CPUWrapper;
+
+    const ARM_LOOP: [u32; 5] = [
+        0xE8B0003C, // loop: ldmia r0!, {r2-r5}
+        0xE8A1003C, //       stmia r1!, {r2-r5}
+        0xE2566001, //       subs r6, r6, #1
+        0x1AFFFFFB, //       bne loop
+        0xEAFFFFFE, //       b .
+    ];
+
+    const THUMB_LOOP: [u16; 5] = [
+        0xC83C, // loop: ldmia r0!, {r2-r5}
+        0xC13C, //       stmia r1!, {r2-r5}
+        0x42B9, //       cmp r1, r7
+        0xDBFB, //       blt loop
+        0xE7FE, //       b .
+    ];
+
+    /// Set up a copy of 4 * iterations words from EWRAM to VRAM with one of
+    /// the loops above at 0x3000000
+    fn copy_program(isa: InstructionSet, iterations: u32) -> CPUWrapper {
+        let mut gba = CPUWrapper::new();
+        match isa {
+            InstructionSet::ARM => for (i, &ins) in ARM_LOOP.iter().enumerate() {
+                gba.cpu.mem.set_word(0x3000000 + 4 * i as u32, ins);
+            },
+            InstructionSet::THUMB => for (i, &ins) in THUMB_LOOP.iter().enumerate() {
+                gba.cpu.mem.set_halfword(0x3000000 + 2 * i as u32, ins as u32);
+            },
+        }
+        for i in 0..(4 * iterations) {
+            gba.cpu.mem.set_word(0x2000000 + 4 * i, i.wrapping_mul(0x01010101));
+        }
+        gba.cpu.cpsr.isa = isa;
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.r[0] = 0x2000000;
+        gba.cpu.r[1] = 0x6000000;
+        gba.cpu.r[6] = iterations;
+        gba.cpu.r[7] = 0x6000000 + 16 * iterations;
+        gba
+    }
+
+    /// Run until the CPU reaches the end of the program, returning the number
+    /// of steps taken
+    fn run_to_end(gba: &mut CPUWrapper) -> u32 {
+        let end = 0x3000000 + 4 * gba.cpu.instruction_size();
+        let mut steps = 0;
+        while gba.cpu.r[15] != end + 2 * gba.cpu.instruction_size() {
+            gba.step().unwrap();
+            steps += 1;
+            assert!(steps < 100000);
+        }
+        steps
+    }
+
+    #[test]
+    fn find() {
+        for &isa in [InstructionSet::ARM, InstructionSet::THUMB].iter() {
+            let gba = copy_program(isa, 1);
+            let found = CopyLoop::find(&gba.cpu, 0x3000000).unwrap();
+            assert_eq!((found.src, found.dest, found.words), (0, 1, 4));
+            assert_eq!(found.test, match isa {
+                InstructionSet::ARM => LoopTest::Count { reg: 6, step: 1 },
+                InstructionSet::THUMB => LoopTest::End { ptr: 1, end: 7 },
+            });
+            assert!(CopyLoop::find(&gba.cpu, 0x3000000 + gba.cpu.instruction_size()).is_none());
+        }
+
+        // the counter can't be one of the copied registers
+        let mut gba = copy_program(InstructionSet::ARM, 1);
+        gba.cpu.mem.set_word(0x3000008, 0xE2533001); // subs r3, r3, #1
+        assert!(CopyLoop::find(&gba.cpu, 0x3000000).is_none());
+        // or always go round again
+        let mut gba = copy_program(InstructionSet::ARM, 1);
+        gba.cpu.mem.set_word(0x300000C, 0xEAFFFFFB); // b loop
+        assert!(CopyLoop::find(&gba.cpu, 0x3000000).is_none());
+    }
+
+    #[test]
+    fn iterations() {
+        let gba = copy_program(InstructionSet::ARM, 10);
+        let found = CopyLoop::find(&gba.cpu, 0x3000000).unwrap();
+        // the 10th iteration falls through
+        assert_eq!(found.iterations(&gba.cpu, 100), 9);
+        assert_eq!(found.iterations(&gba.cpu, 5), 5);
+
+        let gba = copy_program(InstructionSet::THUMB, 10);
+        let found = CopyLoop::find(&gba.cpu, 0x3000000).unwrap();
+        assert_eq!(found.iterations(&gba.cpu, 100), 9);
+    }
+
+    /// Skipping iterations must leave everything exactly as running each of
+    /// them would, including the timing. The copies here are long enough to
+    /// span several LCD events
+    #[test]
+    fn matches_normal_execution() {
+        for &isa in [InstructionSet::ARM, InstructionSet::THUMB].iter() {
+            let mut fast = copy_program(isa, 400);
+            let mut slow = copy_program(isa, 400);
+            slow.fast_copy.enabled = false;
+            let fast_steps = run_to_end(&mut fast);
+            let slow_steps = run_to_end(&mut slow);
+            assert!(fast_steps * 4 < slow_steps);
+
+            assert_eq!(fast.cpu.r, slow.cpu.r);
+            assert_eq!(fast.cpu.cpsr.to_u32(), slow.cpu.cpsr.to_u32());
+            assert_eq!(fast.total_cycles, slow.total_cycles);
+            assert_eq!(fast.cycles, slow.cycles);
+            assert_eq!(&fast.cpu.mem.raw.vram[..6400], &slow.cpu.mem.raw.vram[..6400]);
+            assert_eq!(fast.cpu.mem.get_word(0x6000000 + 4 * 1599), 1599u32.wrapping_mul(0x01010101));
+        }
+    }
+
+    /// A watchpoint in the destination stops the copy at the write that
+    /// hits it, rather than being skipped over
+    #[test]
+    fn watchpoints() {
+        let mut gba = copy_program(InstructionSet::ARM, 400);
+        let watched = 0x6000000 + 16 * 300;
+        gba.cpu.mem.watch.add(watched, 4, false, true);
+        while !gba.cpu.mem.watch.is_hit() {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.cpu.mem.watch.take_hit().unwrap().addr, watched);
+        assert_eq!(gba.cpu.mem.get_word(watched + 16), 0);
+    }
+
+    #[test]
+    fn timing() {
+        let mut copy = FastCopy::new();
+        copy.start_iteration(0x3000000);
+        for _ in 0..LOOP_LEN {
+            copy.record_step(10);
+        }
+        assert_eq!(copy.last_iteration(0x3000000), Some(40));
+        assert_eq!(copy.last_iteration(0x3000004), None);
+
+        // a longer loop
+        copy.start_iteration(0x3000000);
+        for _ in 0..(LOOP_LEN + 1) {
+            copy.record_step(10);
+        }
+        assert_eq!(copy.last_iteration(0x3000000), None);
+
+        copy.start_iteration(0x3000000);
+        copy.record_step(10);
+        copy.interrupt();
+        for _ in 1..LOOP_LEN {
+            copy.record_step(10);
+        }
+        assert_eq!(copy.last_iteration(0x3000000), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Instructions that can't be decoded. They're only a problem once they're
+//! executed, since data after the end of a function can end up in the
+//! pipeline without ever running, so decode just marks them as undefined and
+//! it's up to execute to decide what happens when one of them is reached.
+//! On the hardware this raises the undefined instruction exception, which
+//! games never set up a handler for, so hitting one means the game has gone
+//! off the rails (or the decoder is missing something).
+//!
+//! In strict mode, which is the default, execute stops with an
+//! Error::InvalidOpcode. In permissive mode the instruction runs as a NOP
+//! instead, and the error is kept in a log as a diagnostic. This can get a
+//! misbehaving game further along while working out what's wrong with it.
+
+use std::fmt::Write;
+use error::Error;
+
+/// number of skipped instructions kept in the log
+pub const SKIPPED_LOG_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpcodePolicy {
+    /// stop at the instruction, and return an error from execute
+    Strict,
+    /// run the instruction as a NOP, and log it
+    Permissive,
+}
+
+impl OpcodePolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OpcodePolicy::Strict => "strict",
+            OpcodePolicy::Permissive => "permissive",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OpcodePolicy> {
+        match name {
+            "strict" => Some(OpcodePolicy::Strict),
+            "permissive" => Some(OpcodePolicy::Permissive),
+            _ => None,
+        }
+    }
+}
+
+pub struct InvalidOpcodes {
+    pub policy: OpcodePolicy,
+    /// the most recently skipped instructions, oldest first, as the errors
+    /// they would have stopped with in strict mode
+    log: Vec<Error>,
+    /// the number of instructions skipped since the log was last cleared,
+    /// including those that have dropped out of it
+    pub skipped: u32,
+}
+
+impl InvalidOpcodes {
+    pub const fn new() -> InvalidOpcodes {
+        InvalidOpcodes {
+            policy: OpcodePolicy::Strict,
+            log: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Decide what to do about the undecodable instruction at pc. Returns
+    /// the error to stop with, or None if the instruction should be skipped
+    pub fn check(&mut self, pc: u32, raw: u32) -> Option<Error> {
+        let err = Error::InvalidOpcode { pc, raw };
+        if self.policy == OpcodePolicy::Strict {
+            return Some(err);
+        }
+        if self.log.len() == SKIPPED_LOG_LEN {
+            self.log.remove(0);
+        }
+        self.log.push(err);
+        self.skipped = self.skipped.saturating_add(1);
+        None
+    }
+
+    /// Return the skipped instructions in the log, oldest first
+    pub fn log(&self) -> &[Error] {
+        &self.log
+    }
+
+    /// Return the log as one line per skipped instruction
+    pub fn format_log(&self) -> String {
+        let mut out = String::new();
+        for err in self.log.iter() {
+            let _ = writeln!(out, "skipped {}", err);
+        }
+        out
+    }
+
+    pub fn clear(&mut self) {
+        self.log.clear();
+        self.skipped = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::CPUWrapper;
+
+    /// A GBA running ARM code from IWRAM that starts with an undefined
+    /// instruction, followed by an add
+    fn undefined_gba() -> CPUWrapper {
+        let mut gba = CPUWrapper::new();
+        // a coprocessor transfer, add r0, r0, #1, b .
+        for (i, ins) in [0xEC000000, 0xE2800001, 0xEAFFFFFE].iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000000 + i as u32 * 4, *ins);
+        }
+        gba.direct_boot_at(0x3000000);
+        gba
+    }
+
+    #[test]
+    fn strict() {
+        let mut gba = undefined_gba();
+        let err = Error::InvalidOpcode { pc: 0x3000000, raw: 0xEC000000 };
+        while gba.cpu.r[15] < 0x3000008 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.step(), Err(err));
+        assert_eq!(gba.cpu.r[0], 0);
+        assert!(gba.invalid_opcodes.log().is_empty());
+    }
+
+    #[test]
+    fn permissive() {
+        let mut gba = undefined_gba();
+        gba.invalid_opcodes.policy = OpcodePolicy::Permissive;
+        for _ in 0..10 {
+            gba.step().unwrap();
+        }
+        // the add after it still runs
+        assert_eq!(gba.cpu.r[0], 1);
+        assert_eq!(gba.invalid_opcodes.log(),
+            &[Error::InvalidOpcode { pc: 0x3000000, raw: 0xEC000000 }]);
+        assert_eq!(gba.invalid_opcodes.format_log(),
+            "skipped invalid opcode 0xEC000000 at 0x03000000\n");
+
+        for i in 0..SKIPPED_LOG_LEN as u32 {
+            gba.invalid_opcodes.check(i * 4, 0);
+        }
+        assert_eq!(gba.invalid_opcodes.log().len(), SKIPPED_LOG_LEN);
+        assert_eq!(gba.invalid_opcodes.skipped, SKIPPED_LOG_LEN as u32 + 1);
+        gba.invalid_opcodes.clear();
+        assert_eq!(gba.invalid_opcodes.skipped, 0);
+    }
+
+    #[test]
+    fn names() {
+        for policy in [OpcodePolicy::Strict, OpcodePolicy::Permissive].iter() {
+            assert_eq!(OpcodePolicy::from_name(policy.name()), Some(*policy));
+        }
+        assert_eq!(OpcodePolicy::from_name("lenient"), None);
+    }
+}
@@ -1,7 +1,17 @@
 pub mod arm;
+pub mod bios;
+pub mod branch_log;
+pub mod fast_copy;
+pub mod invalid_opcode;
+pub mod opcode_stats;
+pub mod overclock;
+pub mod pacing;
 pub mod pipeline;
+pub mod soft_reset;
 pub mod thumb;
 pub mod status_reg;
+pub mod trace;
+pub mod unsupported;
 
 use self::arm::RegOrImm;
 use self::arm::data::apply_shift;
@@ -13,6 +23,9 @@ use self::pipeline::{
     PipelineInstruction,
     satisfies_cond
 };
+use dev::elf::Symbols;
+use error::{AccessKind, Error, Result};
+use script::FrameScript;
 use mem;
 use util;
 use std;
@@ -37,8 +50,31 @@ pub struct CPUWrapper {
     // index into the circular buffer
     idx: usize,
     pub last_instruction: Option<Instruction>,
+    /// the most recently executed instructions, for crash reports
+    pub trace: trace::Trace,
     /// number of run cycles so far mod refresh rate
     pub cycles: u32,
+    /// number of cycles run since power on
+    pub total_cycles: u64,
+    /// decides which frames get drawn when the host is running slowly
+    pub pacing: pacing::Pacing,
+    /// names for addresses in the running program, if it was loaded from an
+    /// ELF file
+    pub symbols: Symbols,
+    /// skips through memory copy loops
+    pub fast_copy: fast_copy::FastCopy,
+    /// lets the CPU run faster than the rest of the hardware
+    pub overclock: overclock::Overclock,
+    /// how often each kind of instruction runs, for profiling the interpreter
+    pub opcode_stats: opcode_stats::OpcodeStats,
+    /// the branches taken recently, for finding hot loops
+    pub branch_log: branch_log::BranchLog,
+    /// run after each frame, see script
+    pub frame_script: Option<FrameScript>,
+    /// what holding A+B+Select+Start does, see soft_reset
+    pub reset_combo: soft_reset::ResetCombo,
+    /// whether undecodable instructions stop the CPU or are skipped
+    pub invalid_opcodes: invalid_opcode::InvalidOpcodes,
 }
 
 impl CPUWrapper {
@@ -55,13 +91,26 @@ impl CPUWrapper {
             ],
             idx: 0,
             last_instruction: None,
+            trace: trace::Trace::new(),
             cycles: 0,
+            total_cycles: 0,
+            pacing: pacing::Pacing::new(),
+            symbols: Symbols::new(),
+            fast_copy: fast_copy::FastCopy::new(),
+            overclock: overclock::Overclock::new(),
+            opcode_stats: opcode_stats::OpcodeStats::new(),
+            branch_log: branch_log::BranchLog::new(),
+            frame_script: None,
+            reset_combo: soft_reset::ResetCombo::new(),
+            invalid_opcodes: invalid_opcode::InvalidOpcodes::new(),
         }
     }
 
     /// Initialize CPU assuming direct boot without the BIOS (i.e. with values
     /// set as if the BIOS has already run): the PC is set to the start of ROM,
-    /// and the CPU is in SYS mode with FIQ bit set
+    /// and the CPU is in SYS mode with FIQ bit set. The IO registers are still
+    /// zero, since they can only be set up after construction by
+    /// direct_boot_at
     pub const fn new_direct_boot() -> CPUWrapper {
         CPUWrapper {
             cpu: CPU::new_direct_boot(),
@@ -72,50 +121,242 @@ impl CPUWrapper {
             ],
             idx: 0,
             last_instruction: None,
+            trace: trace::Trace::new(),
             cycles: 0,
+            total_cycles: 0,
+            pacing: pacing::Pacing::new(),
+            symbols: Symbols::new(),
+            fast_copy: fast_copy::FastCopy::new(),
+            overclock: overclock::Overclock::new(),
+            opcode_stats: opcode_stats::OpcodeStats::new(),
+            branch_log: branch_log::BranchLog::new(),
+            frame_script: None,
+            reset_combo: soft_reset::ResetCombo::new(),
+            invalid_opcodes: invalid_opcode::InvalidOpcodes::new(),
         }
     }
 
-    /// Run until the next frame refresh cycle starts
-    pub fn frame(&mut self) {
+    /// Reset the CPU to the same state as new_direct_boot(), but starting at
+    /// the given address. Memory is left as is, apart from the IO registers
+    /// that the BIOS would have set (see mem::io::boot). The lowest bit of the
+    /// address selects THUMB mode, like for a BX
+    pub fn direct_boot_at(&mut self, entry: u32) {
+        let cpu = &mut self.cpu;
+        cpu.r = [0; 16];
+        cpu.r_fiq = [0; 7];
+        cpu.r_irq = [0x3007FA0, 0];
+        cpu.r_und = [0; 2];
+        cpu.r_abt = [0; 2];
+        cpu.r_svc = [0x3007FA0, 0];
+        cpu.cpsr = PSR::new_direct_boot();
+        cpu.spsr_svc = PSR::new();
+        cpu.spsr_abt = PSR::new();
+        cpu.spsr_und = PSR::new();
+        cpu.spsr_irq = PSR::new();
+        cpu.spsr_fiq = PSR::new();
+        cpu.mem.apply_post_boot_io();
+        self.jump_to_entry(entry);
+        self.cycles = 0;
+    }
+
+    /// Start running from the given address with an empty pipeline, leaving
+    /// behind anything that was in progress. The lowest bit of the address
+    /// selects THUMB mode
+    fn jump_to_entry(&mut self, entry: u32) {
+        if entry & 1 == 1 {
+            self.cpu.cpsr.isa = InstructionSet::THUMB;
+        }
+        self.cpu.r[15] = entry & !1;
+        self.cpu.should_flush = false;
+        self.flush_pipeline();
+        self.last_instruction = None;
+        self.cpu.unsupported.take();
+        self.fast_copy.forget();
+    }
+
+    /// Run until the next frame refresh cycle starts, or a watchpoint is
+    /// hit. Does nothing while paused
+    pub fn frame(&mut self) -> Result<()> {
+        if self.pacing.paused {
+            return Ok(());
+        }
         loop {
-            if self.step() {
-                break;
+            if self.step()? || self.cpu.mem.watch.is_hit() {
+                return Ok(());
             }
         }
     }
 
+    /// Return the number of cycles to run to keep up with the host, given the
+    /// time in milliseconds since it last asked: 0 if no frame is due, and
+    /// otherwise up to the end of the last frame that is due. This counts
+    /// from the current position, so it stays right when the last frame was
+    /// left unfinished (e.g. while waiting on a link partner)
+    pub fn cycles_until_next_frame(&mut self, host_time_delta: f64) -> u32 {
+        match self.pacing.frames_due_after(host_time_delta) {
+            0 => 0,
+            frames => (REFRESH - self.cycles) + (frames - 1) * REFRESH,
+        }
+    }
+
+    /// Show the frame being drawn as if the lines that haven't been drawn yet
+    /// were drawn with the current state, for when the debugger pauses mid
+    /// frame. No cycles are run. Returns the first line that was rendered
+    /// speculatively, or 160 if the frame has already been drawn
+    #[cfg(feature = "debugger")]
+    pub fn render_to_current_line(&mut self) -> u32 {
+        let row = self.cycles / SCANLINE;
+        let first = if !self.pacing.rendering {
+            0
+        } else if self.cycles % SCANLINE >= HDRAW {
+            row + 1
+        } else {
+            row
+        };
+        if first < 160 {
+            self.cpu.mem.render_speculative(first);
+        }
+        first.min(160)
+    }
+
     /// Run a single fetch/decode/execute cycle in the instruction pipeline,
     /// and check for DMA/interrupts. Returns true if a new refresh cycle
-    /// has started
-    pub fn step(&mut self) -> bool {
+    /// has started, in which case the frame script is run
+    pub fn step(&mut self) -> Result<bool> {
         // reset should_flush at the start of the next instruction, so the
         // debugger knows to do a pipeline refill automatically
         self.cpu.should_flush = false;
-        self.fetch();
+        self.fetch()?;
         self.decode();
-        let cycles = self.execute();
+        // nothing runs while the pipeline refills after a branch
+        let executing = self.pipeline_full();
+        let skipped = self.skip_copy_iterations();
+        // the instruction's address, from before it can change the
+        // instruction set
+        let pc = self.cpu.r[15].wrapping_sub(2 * self.cpu.instruction_size());
+        let cycles = self.execute()?;
+        if executing {
+            self.fast_copy.record_step(cycles);
+        }
+        self.cpu.check_mode()?;
 
-        if self.cpu.should_flush {
+        if let Some(entry) = self.cpu.bios.take_reset_entry() {
+            // an emulated SoftReset restarts the game like any other reset
+            self.jump_to_entry(entry);
+        } else if self.cpu.should_flush {
             self.flush_pipeline();
+            if self.branch_log.enabled {
+                self.branch_log.record(pc, self.cpu.r[15]);
+            }
         } else {
             self.idx = (self.idx + 1) % 3;
             self.cpu.incr_pc();
         }
+        let new_frame = self.end_instruction(skipped + cycles)?;
+        if new_frame {
+            self.run_frame_script();
+            self.check_reset_combo();
+        }
+        Ok(new_frame)
+    }
 
-        // TODO: add delay to DMA transfers
+    /// If the instruction about to run starts another iteration of a copy
+    /// loop, run as many of the loop's iterations at once as can be done
+    /// exactly, and return the cycles they took. See fast_copy
+    fn skip_copy_iterations(&mut self) -> u32 {
+        if !self.fast_copy.enabled {
+            return 0;
+        }
+        match self.pipeline[(self.idx + 1) % 3] {
+            PipelineInstruction::Decoded(_, Instruction::BlockTransfer(ref ins)) if ins.load => (),
+            _ => return 0,
+        }
+        let head = self.cpu.r[15].wrapping_sub(2 * self.cpu.instruction_size());
+        let copy = match fast_copy::CopyLoop::find(&self.cpu, head) {
+            Some(copy) => copy,
+            None => return 0,
+        };
+        let last = self.fast_copy.last_iteration(head);
+        let mut skipped = 0;
+        if let Some(iteration) = last {
+            // nothing else may happen while the iterations are skipped. the
+            // budget is in hardware cycles, which is never more than the CPU
+            // cycles they take when overclocked
+            let serial = &self.cpu.mem.serial;
+            let mut budget = cycles_to_lcd_event(self.cycles);
+            if serial.busy && !serial.transfer_done {
+                budget = budget.min(serial.cycles_left);
+            }
+            if let Some(cycles) = self.cpu.mem.timers.cycles_to_overflow() {
+                budget = budget.min(cycles);
+            }
+            let count = copy.iterations(&self.cpu, budget.saturating_sub(1) / iteration);
+            if count > 0 && copy.skip(&mut self.cpu, head, count) {
+                skipped = count * iteration;
+            }
+        }
+        self.fast_copy.start_iteration(head);
+        skipped
+    }
+
+    /// Catch everything that runs alongside the CPU up with the instruction
+    /// that just finished: DMA, interrupts, the serial port, the timers,
+    /// sound and the LCD. This is the only place they're updated, so they
+    /// only ever act between instructions. The hardware relies on the same
+    /// thing for LDM/STM, which can't be interrupted: an IRQ or DMA that
+    /// becomes due part way through a block transfer waits until the last
+    /// register has been transferred, and a DMA sees every word the
+    /// instruction wrote. Anything that schedules events has to keep to this.
+    /// The given cycles are the CPU's, which are fewer for the rest of the
+    /// hardware when overclocked
+    fn end_instruction(&mut self, cycles: u32) -> Result<bool> {
         self.cpu.mem.check_dma(mem::io::dma::TimingMode::Now);
-        self.cpu.check_interrupts();
-        self.update_lcd(cycles)
+        // the CPU is halted while DMA runs, including transfers started by
+        // the LCD during the last step. DMA isn't sped up by overclocking
+        let dma_cycles = self.cpu.mem.dma.take_cycles();
+        let cycles = self.overclock.hardware_cycles(cycles) + dma_cycles;
+        if dma_cycles > 0 || cycles >= cycles_to_lcd_event(self.cycles) {
+            self.fast_copy.interrupt();
+        }
+        if let Some(err) = self.cpu.mem.take_violation() {
+            return Err(err);
+        }
+        self.cpu.mem.tick_serial(cycles);
+        self.cpu.mem.tick_timers(cycles);
+        self.cpu.mem.tick_sound(cycles);
+        self.total_cycles += cycles as u64;
+        self.cpu.mem.mmio_log.now = self.total_cycles;
+        let new_frame = self.update_lcd(cycles);
+        // interrupts raised while the instruction ran are taken as soon as it
+        // finishes. wait for the pipeline to refill after a branch before
+        // taking one, so that the next instruction (which the handler returns
+        // to) is the one that's been decoded
+        if self.pipeline_full() && self.cpu.check_interrupts() {
+            self.fast_copy.interrupt();
+            self.flush_pipeline();
+        }
+        Ok(new_frame)
     }
 
-    pub fn fetch(&mut self) {
-        let pc = self.cpu.get_reg(15);
+    pub fn fetch(&mut self) -> Result<()> {
+        // the lowest bits of the PC are ignored when fetching, so that e.g. a
+        // BX to an odd THUMB address still fetches whole instructions
+        let pc = self.cpu.get_reg(15) & !(self.cpu.instruction_size() - 1);
+        if !self.cpu.mem.is_mapped(pc) {
+            return Err(if self.cpu.mem.is_rom(pc) && self.cpu.mem.raw.rom.is_none() {
+                Error::RomNotLoaded
+            } else {
+                Error::UnmappedAccess { addr: pc, kind: AccessKind::Fetch }
+            });
+        }
         self.pipeline[self.idx] = if self.cpu.cpsr.isa == InstructionSet::THUMB {
-            PipelineInstruction::RawTHUMB(self.cpu.mem.get_halfword(pc))
+            PipelineInstruction::RawTHUMB(self.cpu.mem.fetch_halfword(pc))
+        } else if let Some(ins) = self.cpu.hle_fetch(pc) {
+            PipelineInstruction::RawARM(ins)
         } else {
-            PipelineInstruction::RawARM(self.cpu.mem.get_word(pc))
-        }
+            PipelineInstruction::RawARM(self.cpu.mem.fetch_word(pc))
+        };
+        Ok(())
     }
 
     /// decode the next instruction and save the condition (if any) in the Decoded
@@ -123,32 +364,70 @@ impl CPUWrapper {
     pub fn decode(&mut self) {
         // index of the second element from the end
         let idx = ((self.idx + 2) % 3) as usize;
+        self.decode_slot(idx);
+    }
+
+    fn decode_slot(&mut self, idx: usize) {
         match self.pipeline[idx] {
             PipelineInstruction::RawARM(n) => {
                 let cond = util::get_nibble(n, 28);
-                self.pipeline[idx] = PipelineInstruction::Decoded(
-                    Some(cond), decode_arm(n).unwrap());
+                // data after the end of a function can end up in the pipeline
+                // without ever getting executed, so it's only an error to
+                // execute an undefined instruction
+                self.pipeline[idx] = match decode_arm(n) {
+                    Some(ins) => PipelineInstruction::Decoded(Some(cond), ins),
+                    None => PipelineInstruction::Undefined(n),
+                };
             },
             PipelineInstruction::RawTHUMB(n) => {
-                self.pipeline[idx] =
-                    PipelineInstruction::Decoded(None, decode_thumb(n))
+                self.pipeline[idx] = match decode_thumb(n) {
+                    Some(ins) => PipelineInstruction::Decoded(None, ins),
+                    None => PipelineInstruction::Undefined(n as u32),
+                };
             },
             _ => ()
         }
     }
 
     /// Execute the next instruction in the pipeline if it exists and return
-    /// the number of cycles it took
-    // TODO: return correct number of cycles
-    pub fn execute(&mut self) -> u32 {
+    /// the number of cycles it took. An instruction that fails its condition
+    /// still takes 1S for the next prefetch
+    pub fn execute(&mut self) -> Result<u32> {
         // index of the third element from the end
         let idx = ((self.idx + 1) % 3) as usize;
+        if let PipelineInstruction::Undefined(raw) = self.pipeline[idx] {
+            // only ARM instructions have a condition
+            if self.cpu.cpsr.isa == InstructionSet::ARM &&
+                !satisfies_cond(&self.cpu.cpsr, util::get_nibble(raw, 28)) {
+                return Ok(self.cpu.mem.access_time(self.cpu.r[15], false));
+            }
+            let pc = self.cpu.r[15].wrapping_sub(2 * self.cpu.instruction_size());
+            return match self.invalid_opcodes.check(pc, raw) {
+                Some(err) => Err(err),
+                // skipped like an instruction that fails its condition
+                None => Ok(self.cpu.mem.access_time(self.cpu.r[15], false)),
+            };
+        }
         if let PipelineInstruction::Decoded(cond, ref ins) = self.pipeline[idx] {
+            let size = self.cpu.instruction_size();
+            self.trace.push(trace::TraceEntry {
+                pc: self.cpu.r[15].wrapping_sub(2 * size),
+                isa: self.cpu.cpsr.isa,
+            });
             if cond.is_some() && !satisfies_cond(&self.cpu.cpsr, cond.unwrap()) {
-                return 1;
+                return Ok(self.cpu.mem.access_time(self.cpu.r[15], false));
             }
             self.last_instruction = Some(ins.clone());
-            return match ins {
+            if self.opcode_stats.enabled {
+                match self.cpu.cpsr.isa {
+                    InstructionSet::ARM => self.opcode_stats.record_arm(ins),
+                    InstructionSet::THUMB => {
+                        let pc = self.cpu.r[15].wrapping_sub(2 * size);
+                        self.opcode_stats.record_thumb(self.cpu.mem.fetch_halfword(pc));
+                    },
+                }
+            }
+            let cycles = match ins {
                 Instruction::DataProc(ins) => ins.run(&mut self.cpu),
                 Instruction::PSRTransfer(ins) => ins.run(&mut self.cpu),
                 Instruction::Multiply(ins) => ins.run(&mut self.cpu),
@@ -163,47 +442,135 @@ impl CPUWrapper {
                 Instruction::CondBranch(ins) => ins.run(&mut self.cpu),
                 Instruction::LongBranch(ins) => ins.run(&mut self.cpu),
             };
+            if let Some(feature) = self.cpu.unsupported.take() {
+                let pc = self.cpu.r[15].wrapping_sub(2 * size);
+                return Err(Error::Unsupported { pc, feature });
+            }
+            return Ok(cycles);
         }
-        return 0;
+        return Ok(0);
     }
 
+    /// Empty the pipeline after a write to the PC, so that the next fetch is
+    /// from the new PC (aligned to the size of an instruction in the current
+    /// instruction set)
     pub fn flush_pipeline(&mut self) {
         for i in 0..3 {
             self.pipeline[i] = PipelineInstruction::Empty;
         }
         self.idx = 0;
+        self.cpu.r[15] &= !(self.cpu.instruction_size() - 1);
     }
 
-    pub fn update_lcd(&mut self, cycles: u32) -> bool {
-        let before = self.cycles;
-        for _ in 0..cycles {
-            self.cycles = (self.cycles + 1) % REFRESH;
-
-            let row = self.cycles / SCANLINE;
-            let col = self.cycles % SCANLINE;
-            if row < 160 {
-                match col {
-                    0 => { self.cpu.mem.on_hdraw_hook(); },
-                    HDRAW => { self.cpu.mem.on_hblank_hook(); },
-                    _ => (),
-                }
+    /// Return the number of instructions waiting in the pipeline between
+    /// steps: 0 right after a flush, 1 once the next instruction has been
+    /// fetched, and 2 once the one after that has been too
+    pub fn pipeline_depth(&self) -> u32 {
+        let mut depth = 0;
+        for offset in 1..3 {
+            match self.pipeline[(self.idx + offset) % 3] {
+                PipelineInstruction::Empty => (),
+                _ => depth += 1,
             }
-            if col == 0 {
-                self.cpu.mem.on_vcount_hook(std::cmp::min(row as u8, 160));
+        }
+        depth
+    }
+
+    /// Refill the pipeline to the given depth from the instructions before the
+    /// PC, which must not have changed since they were first fetched. Used to
+    /// restore a pipeline that was saved with pipeline_depth()
+    pub fn restore_pipeline(&mut self, depth: u32) {
+        self.flush_pipeline();
+        let size = self.cpu.instruction_size();
+        for i in 0..depth.min(2) {
+            let addr = self.cpu.r[15].wrapping_sub(size * (i + 1));
+            let slot = 2 - i as usize;
+            self.pipeline[slot] = if self.cpu.cpsr.isa == InstructionSet::THUMB {
+                PipelineInstruction::RawTHUMB(self.cpu.mem.get_halfword(addr))
+            } else {
+                PipelineInstruction::RawARM(self.cpu.mem.get_word(addr))
+            };
+            // the instruction about to be executed has already been decoded
+            if slot == 1 {
+                self.decode_slot(slot);
             }
-            match self.cycles {
-                0 => { self.cpu.mem.on_vdraw_hook(); }
-                VDRAW => { self.cpu.mem.on_vblank_hook(); },
+        }
+    }
+
+    /// Return true if the next step will execute an instruction
+    fn pipeline_full(&self) -> bool {
+        match self.pipeline[(self.idx + 1) % 3] {
+            PipelineInstruction::Empty => false,
+            _ => true,
+        }
+    }
+
+    /// Advance the LCD by the given number of cycles, jumping from one event
+    /// to the next (see cycles_to_lcd_event) instead of going a cycle at a
+    /// time. Returns true if a new refresh cycle started
+    pub fn update_lcd(&mut self, cycles: u32) -> bool {
+        let mut left = cycles;
+        let mut new_frame = false;
+        loop {
+            let until_event = cycles_to_lcd_event(self.cycles);
+            if until_event > left {
+                // the next event is at the latest the start of the next
+                // frame, so this can't wrap around
+                self.cycles += left;
+                return new_frame;
+            }
+            left -= until_event;
+            self.cycles = (self.cycles + until_event) % REFRESH;
+            new_frame |= self.cycles == 0;
+            self.lcd_event();
+        }
+    }
+
+    /// Run the hooks for the position in the frame that the LCD just reached,
+    /// which must be the start of a line or of HBlank
+    fn lcd_event(&mut self) {
+        let row = self.cycles / SCANLINE;
+        let col = self.cycles % SCANLINE;
+        if row < 160 {
+            match col {
+                0 => { self.cpu.mem.on_hdraw_hook(); },
+                HDRAW => {
+                    if self.pacing.rendering {
+                        self.cpu.mem.render_scanline(row);
+                    }
+                    self.cpu.mem.on_hblank_hook();
+                },
                 _ => (),
             }
-            if self.cycles % 4 == 0 {
-                self.cpu.mem.update_pixel(row, col);
+        }
+        if col == 0 {
+            self.cpu.mem.on_vcount_hook(row as u8);
+        }
+        match self.cycles {
+            0 => {
+                self.pacing.start_frame();
+                self.cpu.mem.on_vdraw_hook();
             }
+            VDRAW => {
+                if self.pacing.rendering {
+                    self.update_overlay();
+                    self.cpu.mem.finish_frame();
+                }
+                self.cpu.mem.on_vblank_hook();
+            },
+            _ => (),
         }
-        before > self.cycles // if we wrapped around
     }
 }
 
+/// Return the number of cycles from the given position in the frame until
+/// update_lcd next does something: the start of a line, or of HBlank on a
+/// visible line
+pub fn cycles_to_lcd_event(cycles: u32) -> u32 {
+    let (row, col) = (cycles / SCANLINE, cycles % SCANLINE);
+    if row < 160 && col < HDRAW { HDRAW - col } else { SCANLINE - col }
+}
+
 pub struct CPU {
     /// r0-r12 are general purpose registers,
     /// r13 is usually the stack pointer (to the top element of the stack, not
@@ -234,6 +601,10 @@ pub struct CPU {
     pub should_flush: bool,
 
     pub mem: mem::Memory,
+    /// settings and log for BIOS calls
+    pub bios: bios::Bios,
+    /// what to do when the program needs something that isn't emulated
+    pub unsupported: unsupported::Unsupported,
 }
 
 impl CPU {
@@ -256,6 +627,8 @@ impl CPU {
             should_flush: false,
 
             mem: mem::Memory::new(),
+            bios: bios::Bios::new(),
+            unsupported: unsupported::Unsupported::new(),
         }
     }
 
@@ -278,6 +651,8 @@ impl CPU {
             should_flush: false,
 
             mem: mem::Memory::new(),
+            bios: bios::Bios::new(),
+            unsupported: unsupported::Unsupported::new(),
         }
     }
 
@@ -356,6 +731,7 @@ impl CPU {
         }
 
         // transfer
+        let data_addr = addr;
         if params.load {
             let val = match params.size {
                 TransferSize::Byte => {
@@ -375,6 +751,9 @@ impl CPU {
                 TransferSize::Word => self.mem.get_word(addr),
             };
             self.set_reg(params.data_reg, val);
+            if params.data_reg == 15 {
+                self.should_flush = true;
+            }
         } else {
             let mut val = self.get_reg(params.data_reg);
             if params.data_reg == 15 {
@@ -401,16 +780,20 @@ impl CPU {
             self.set_reg(params.base_reg, addr);
         }
 
-        if params.load && params.base_reg == 15 {
-            1 + self.mem.access_time(old_pc, true) +
-                self.mem.access_time(old_pc + 4, false) +
-                self.mem.access_time(self.r[15], true) +
-                self.mem.access_time(self.r[15] + 4, false)
-        } else if params.load {
-            1 + self.mem.access_time(old_pc, true) +
-                self.mem.access_time(old_pc + 4, false)
+        // LDR is 1S + 1N + 1I (prefetch, data read, writing the register),
+        // plus 1N + 1S to refill the pipeline when loading the PC. STR is 2N
+        // (prefetch, data write)
+        if params.load {
+            let mut cycles = self.mem.access_time(old_pc, false) +
+                self.mem.access_time(data_addr, true) +
+                self.mem.internal_cycles(1);
+            if params.data_reg == 15 {
+                cycles += self.mem.access_time(self.r[15], true) +
+                    self.mem.access_time(self.r[15] + 4, false);
+            }
+            cycles
         } else {
-            self.mem.access_time(old_pc, true) + self.mem.access_time(addr, true)
+            self.mem.access_time(old_pc, true) + self.mem.access_time(data_addr, true)
         }
     }
 
@@ -419,105 +802,158 @@ impl CPU {
         if self.cpsr.isa == InstructionSet::THUMB { 2 } else { 4 }
     }
 
-    /// restore CPSR to the SPSR for the current mode
+    /// Switch to the given mode. Every change to the mode bits of the CPSR
+    /// goes through here: MSR, exception entry and return, and LDM/STM with
+    /// the S bit. The banked registers and the SPSR are looked up from the
+    /// current mode on each access, so nothing needs to be copied between
+    /// banks, but nothing else may change the mode directly or they can end
+    /// up being read from a different bank than they were written to. This
+    /// never saves the CPSR; only exception entry (enter_exception) does that
+    fn switch_mode(&mut self, mode: CPUMode) {
+        self.cpsr.mode = mode;
+    }
+
+    /// Restore the CPSR from the SPSR for the current mode, when returning
+    /// from an exception. USR and SYS modes have no SPSR, so nothing changes
     fn restore_cpsr(&mut self) {
-        self.cpsr = self.get_spsr();
+        let spsr = self.get_spsr();
+        self.set_psr(spsr);
+    }
+
+    /// Replace the whole CPSR, including the mode
+    fn set_psr(&mut self, psr: PSR) {
+        self.cpsr = PSR { mode: self.cpsr.mode, ..psr };
+        self.switch_mode(psr.mode);
     }
 
-    /// Set the CPSR
+    /// Set the CPSR, as MSR does. Changing the mode doesn't save the CPSR to
+    /// the new mode's SPSR, unlike an exception
     fn set_cpsr(&mut self, val: u32, flags_only: bool) {
-        self.cpsr.from_u32(val, flags_only);
+        let mut cpsr = self.cpsr;
+        cpsr.from_u32(val, flags_only);
+        self.set_psr(cpsr);
     }
 
-    /// Return the SPSR for the current mode
+    /// Return the SPSR for the current mode. USR and SYS modes have none, and
+    /// read the CPSR instead
     fn get_spsr(&self) -> PSR {
         match self.cpsr.mode {
-            // TODO: just return CPSR for USR mode?
-            CPUMode::USR => panic!("USR mode has no SPSR"),
             CPUMode::FIQ => self.spsr_fiq,
             CPUMode::IRQ => self.spsr_irq,
             CPUMode::SVC => self.spsr_svc,
             CPUMode::ABT => self.spsr_abt,
             CPUMode::UND => self.spsr_und,
-            CPUMode::SYS => self.cpsr,
+            CPUMode::USR | CPUMode::SYS => self.cpsr,
             CPUMode::INVALID => panic!("invalid mode"),
         }
     }
 
-    /// Set the SPSR for the current mode
+    /// Set the SPSR for the current mode. Writes in USR and SYS modes, which
+    /// have no SPSR, are ignored
     fn set_spsr(&mut self, val: u32, flags_only: bool) {
         match self.cpsr.mode {
-            CPUMode::USR => panic!("USR mode has no SPSR"),
             CPUMode::FIQ => self.spsr_fiq.from_u32(val, flags_only),
             CPUMode::IRQ => self.spsr_irq.from_u32(val, flags_only),
             CPUMode::SVC => self.spsr_svc.from_u32(val, flags_only),
             CPUMode::ABT => self.spsr_abt.from_u32(val, flags_only),
             CPUMode::UND => self.spsr_und.from_u32(val, flags_only),
-            CPUMode::SYS => (),
+            CPUMode::USR | CPUMode::SYS => (),
             CPUMode::INVALID => panic!("invalid mode"),
         }
     }
 
-    /// Change CPU modes, saving or restoring the CPSR in the process
-    fn change_mode(&mut self, new_mode: CPUMode) {
-        match new_mode {
-            CPUMode::USR => self.restore_cpsr(),
+    /// Enter the mode an exception is handled in, saving the CPSR to that
+    /// mode's SPSR so that the handler can return to it
+    fn enter_exception(&mut self, mode: CPUMode) {
+        match mode {
             CPUMode::FIQ => self.spsr_fiq = self.cpsr,
             CPUMode::IRQ => self.spsr_irq = self.cpsr,
             CPUMode::SVC => self.spsr_svc = self.cpsr,
             CPUMode::ABT => self.spsr_abt = self.cpsr,
             CPUMode::UND => self.spsr_und = self.cpsr,
-            CPUMode::SYS => unimplemented!(),
-            CPUMode::INVALID => panic!("invalid mode"),
+            CPUMode::USR | CPUMode::SYS | CPUMode::INVALID =>
+                panic!("no exception is taken in {:?} mode", mode),
         };
-        self.cpsr.mode = new_mode;
+        self.switch_mode(mode);
     }
 
     fn set_isa(&mut self, thumb: bool) {
         self.cpsr.isa = if thumb { InstructionSet::THUMB } else { InstructionSet::ARM };
     }
 
-    pub fn check_interrupts(&mut self) {
-        if self.cpsr.irq && self.mem.int.pending_interrupts() {
+    /// Return an error if the CPSR has been set to an invalid mode, since we
+    /// can't tell which registers should be used afterwards
+    pub fn check_mode(&self) -> Result<()> {
+        if self.cpsr.mode == CPUMode::INVALID {
+            return Err(Error::InvalidPsrMode { bits: self.cpsr.to_u32() & 0x1F });
+        }
+        Ok(())
+    }
+
+    /// Take an IRQ if one is pending and IRQs aren't disabled in the CPSR.
+    /// Should only be called between instructions, and returns true if the
+    /// pipeline needs to be flushed
+    pub fn check_interrupts(&mut self) -> bool {
+        if !self.cpsr.irq && self.mem.int.pending_interrupts() {
             self.handle_interrupt(InterruptType::IRQ);
+            self.should_flush = true;
+            return true;
         }
+        false
     }
 
     /// Emulate a hardware interrupt being triggered
     ///   - CPU is switched to IRQ mode
     ///   - saves the CPSR in SPSR_irq and sets bit 7 (disable IRQ) in the CPSR
-    ///   - saves the address of the next instruction in LR_irq, compensating for
-    ///     THUMB/ARM instruction size
+    ///   - saves the return address in LR_irq, compensating for THUMB/ARM
+    ///     instruction size. A SWI returns with MOVS PC, LR, and an IRQ with
+    ///     SUBS PC, LR, #4
     ///   - branches to the appropriate hardware interrupt vector entry in the BIOS
-    /// The following is done by the BIOS, so should be emulated here if the
-    /// real BIOS is not loaded
+    /// The following is done by the BIOS, which is emulated or skipped
+    /// depending on the IrqDispatch setting
     ///   - r0-r3, r12, LR are pushed onto the stack
     ///   - place address for the next instruction (in the BIOS) in LR
     ///   - branches to the address at 0x0300_7FFC
     fn handle_interrupt(&mut self, type_: InterruptType) {
-        self.change_mode(type_.get_cpu_mode());
-        if let InterruptType::IRQ = type_ {
-            self.cpsr.irq = false;
-        }
+        let (mode, handler_addr) = match (type_.get_cpu_mode(), type_.get_handler_addr()) {
+            (Some(mode), Some(addr)) => (mode, addr),
+            _ => {
+                self.unsupported.report(unsupported::Feature::Exception(type_.name()));
+                return;
+            },
+        };
+        self.enter_exception(mode);
+        self.cpsr.irq = true;
 
-        let next_ins_addr = self.get_reg(15) - self.instruction_size();
-        // TODO: is this correct? GBE does it differently
-        self.set_reg(14, next_ins_addr);
+        // a SWI is taken while it's executing, so the PC is 2 instructions
+        // ahead of it. an IRQ is taken after the PC has been incremented, so
+        // the PC is 2 instructions ahead of the next one
+        let size = self.instruction_size();
+        let return_addr = match type_ {
+            InterruptType::IRQ => self.get_reg(15) - 2 * size + 4,
+            _ => self.get_reg(15) - size,
+        };
+        self.set_reg(14, return_addr);
 
         self.cpsr.isa = InstructionSet::ARM;
-        self.set_reg(15, type_.get_handler_addr());
+        if type_ == InterruptType::IRQ && self.irq_dispatch() == bios::IrqDispatch::Vector {
+            let handler = self.mem.get_word(bios::IRQ_HANDLER_PTR);
+            if handler & 1 == 1 {
+                self.cpsr.isa = InstructionSet::THUMB;
+            }
+            self.set_reg(15, handler & !1);
+        } else {
+            self.set_reg(15, handler_addr);
+        }
     }
 
     // TODO: this should probably be a function
     fn get_offset(&self, offset: &RegOrImm) -> u32 {
         match *offset {
             RegOrImm::Imm { rotate: _, value: n } => n,
-            RegOrImm::Reg { shift: s, reg: r } => {
-                if util::get_bit(s, 3) && util::get_bit(s, 0) {
-                    panic!("cannot use register value as shift amount for LDR/STR");
-                }
-                apply_shift(self, s, r).0
-            }
+            // register specified shifts are reported as unpredictable by
+            // the instructions before they get here
+            RegOrImm::Reg { shift: s, reg: r } => apply_shift(self, s, r).0
         } 
     }
 }
@@ -540,6 +976,7 @@ pub enum TransferSize {
     Word,
 }
 
+#[derive(PartialEq)]
 pub enum InterruptType {
     Reset,
     Undefined,
@@ -551,21 +988,35 @@ pub enum InterruptType {
 }
 
 impl InterruptType {
-    /// The address that the CPU jumps to for this specific interrupt type
-    pub fn get_handler_addr(&self) -> u32 {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            InterruptType::Reset => "Reset",
+            InterruptType::Undefined => "Undefined",
+            InterruptType::SWI => "SWI",
+            InterruptType::PrefetchAbort => "Prefetch Abort",
+            InterruptType::DataAbort => "Data Abort",
+            InterruptType::IRQ => "IRQ",
+            InterruptType::FIQ => "FIQ",
+        }
+    }
+
+    /// The address that the CPU jumps to for this specific interrupt type, if
+    /// it's supported
+    pub fn get_handler_addr(&self) -> Option<u32> {
         match *self {
-            InterruptType::SWI => 0x8,
-            InterruptType::IRQ => 0x18,
-            _ => unimplemented!()
+            InterruptType::SWI => Some(0x8),
+            InterruptType::IRQ => Some(0x18),
+            _ => None,
         }
     }
 
-    /// The mode that the CPU enters for this specific interrupt type
-    pub fn get_cpu_mode(&self) -> CPUMode {
+    /// The mode that the CPU enters for this specific interrupt type, if it's
+    /// supported
+    pub fn get_cpu_mode(&self) -> Option<CPUMode> {
         match *self {
-            InterruptType::SWI => CPUMode::SVC,
-            InterruptType::IRQ => CPUMode::IRQ,
-            _ => unimplemented!()
+            InterruptType::SWI => Some(CPUMode::SVC),
+            InterruptType::IRQ => Some(CPUMode::IRQ),
+            _ => None,
         }
     }
 }
@@ -573,6 +1024,7 @@ impl InterruptType {
 #[cfg(test)]
 mod test {
     use ::cpu::*;
+    use ::cpu::bios::IrqDispatch;
 
     #[test]
     fn transfer_load() {
@@ -650,4 +1102,453 @@ mod test {
         assert_eq!(cpu.get_reg(14), 0xFFFFA10B);
         assert_eq!(cpu.get_reg(0), 80);
     }
+
+    #[test]
+    fn step_errors() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_word(0x3000000, 0xEC000000);
+        gba.cpu.r[15] = 0x3000000;
+        assert_eq!(gba.step(), Ok(false));
+        assert_eq!(gba.step(), Ok(false));
+        assert_eq!(gba.step(), Err(Error::InvalidOpcode { pc: 0x3000000, raw: 0xEC000000 }));
+
+        // THUMB instructions have no condition, so an undefined one is always
+        // an error
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_halfword(0x3000000, 0xDE01);
+        gba.cpu.cpsr.isa = InstructionSet::THUMB;
+        gba.cpu.r[15] = 0x3000000;
+        assert_eq!(gba.step(), Ok(false));
+        assert_eq!(gba.step(), Ok(false));
+        assert_eq!(gba.step(), Err(Error::InvalidOpcode { pc: 0x3000000, raw: 0xDE01 }));
+
+        let mut gba = CPUWrapper::new();
+        gba.cpu.r[15] = 0x1000000;
+        assert_eq!(gba.step(),
+            Err(Error::UnmappedAccess { addr: 0x1000000, kind: AccessKind::Fetch }));
+        gba.cpu.r[15] = 0x8000000;
+        assert_eq!(gba.step(), Err(Error::RomNotLoaded));
+    }
+
+    #[test]
+    fn bx_to_thumb() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.r[0] = 0x3000101;
+        gba.cpu.mem.set_word(0x3000000, 0xE12FFF10); // bx r0
+        gba.cpu.mem.set_halfword(0x3000100, 0x2005); // mov r0, #5
+        for _ in 0..3 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.cpu.cpsr.isa, InstructionSet::THUMB);
+        assert_eq!(gba.cpu.r[15], 0x3000100);
+
+        gba.step().unwrap();
+        match gba.pipeline[0] {
+            PipelineInstruction::RawTHUMB(0x2005) => (),
+            _ => panic!("fetched the wrong instruction"),
+        }
+        gba.step().unwrap();
+        gba.step().unwrap();
+        assert_eq!(gba.cpu.r[0], 5);
+    }
+
+    #[test]
+    fn ldm_unaligned_pc() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.r[0] = 0x3000200;
+        gba.cpu.mem.set_word(0x3000000, 0xE8908000); // ldmia r0, {pc}
+        gba.cpu.mem.set_word(0x3000200, 0x3000102);
+        gba.cpu.mem.set_word(0x3000100, 0xE3A01007); // mov r1, #7
+        for _ in 0..6 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.cpu.cpsr.isa, InstructionSet::ARM);
+        assert_eq!(gba.cpu.r[1], 7);
+    }
+
+    #[test]
+    fn block_transfer_is_atomic() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.mem.set_word(0x3000000, 0xE8807FFE); // stmia r0, {r1-r14}
+        gba.cpu.r[0] = 0x2000000;
+        for i in 1..15 {
+            gba.cpu.set_reg(i, 0x11111111 * i as u32);
+        }
+        // DMA 3 copies what the STM writes to IWRAM at the next HBlank, which
+        // also raises an IRQ
+        gba.cpu.mem.set_word(0x40000D4, 0x2000000);
+        gba.cpu.mem.set_word(0x40000D8, 0x3000400);
+        gba.cpu.mem.set_word(0x40000DC, 0xA400_000E);
+        gba.cpu.mem.set_halfword(0x4000004, 0x10);
+        gba.cpu.mem.set_halfword(0x4000200, 0b10);
+        gba.cpu.mem.set_halfword(0x4000208, 1);
+        gba.cpu.cpsr.irq = false;
+        gba.step().unwrap();
+        gba.step().unwrap();
+
+        // HBlank starts a few cycles into the STM, but the DMA still copies
+        // every register it stores, and the IRQ is taken once it's done
+        gba.cycles = HDRAW - 5;
+        gba.step().unwrap();
+        assert!(gba.cycles > HDRAW);
+        for i in 0..14 {
+            assert_eq!(gba.cpu.mem.get_word(0x2000000 + 4 * i), 0x11111111 * (i + 1));
+            assert_eq!(gba.cpu.mem.get_word(0x3000400 + 4 * i), 0x11111111 * (i + 1));
+        }
+        assert_eq!(gba.cpu.cpsr.mode, CPUMode::IRQ);
+        // returning to the instruction after the STM
+        assert_eq!(gba.cpu.get_reg(14), 0x3000008);
+    }
+
+    /// Execute a single instruction from ROM at 0x8000100 and return the
+    /// number of cycles it took
+    fn instruction_cycles(raw: u32, isa: InstructionSet) -> u32 {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.bios_loaded = true;
+        gba.cpu.cpsr.isa = isa;
+        gba.cpu.r[15] = 0x8000100 + 2 * gba.cpu.instruction_size();
+        gba.cpu.set_reg(0, 0x3000000);
+        gba.cpu.set_reg(1, 0x8000200);
+        gba.cpu.set_reg(14, 0x8000200);
+        gba.cpu.mem.set_word(0x3000000, 0x8000200);
+        let ins = match isa {
+            InstructionSet::ARM => decode_arm(raw).unwrap(),
+            InstructionSet::THUMB => decode_thumb(raw as u16).unwrap(),
+        };
+        let idx = ((gba.idx + 1) % 3) as usize;
+        gba.pipeline[idx] = PipelineInstruction::Decoded(None, ins);
+        gba.execute().unwrap()
+    }
+
+    #[test]
+    fn instruction_timings() {
+        // with the default WAITCNT, ROM takes 5 cycles for a nonsequential (N)
+        // access and 3 for a sequential (S) one. data is in IWRAM, which
+        // always takes 1 cycle
+        let arm: [(u32, u32); 14] = [
+            (0xE3A02001, 3), // mov r2, #1: 1S
+            (0x03A02001, 3), // moveq r2, #1 (not taken): 1S
+            (0xE10F2000, 3), // mrs r2, cpsr: 1S
+            (0xE0020493, 4), // mul r2, r3, r4 (r4 = 0): 1S + 1I
+            (0xE5902000, 5), // ldr r2, [r0]: 1S + 1N + 1I
+            (0xE1D020B0, 5), // ldrh r2, [r0]: 1S + 1N + 1I
+            (0xE590F000, 13), // ldr pc, [r0]: 2S + 2N + 1I
+            (0xE5802000, 6), // str r2, [r0]: 2N
+            (0xE1002093, 6), // swp r2, r3, [r0]: 1S + 2N + 1I
+            (0xE890003C, 8), // ldmia r0, {r2-r5}: 4S + 1N + 1I
+            (0xE880003C, 9), // stmia r0, {r2-r5}: 3S + 2N
+            (0xEA000000, 11), // b: 2S + 1N
+            (0xE12FFF11, 11), // bx r1: 2S + 1N
+            (0xEF000000, 5), // swi 0 (to the BIOS): 2S + 1N
+        ];
+        for &(raw, cycles) in arm.iter() {
+            assert_eq!(instruction_cycles(raw, InstructionSet::ARM), cycles,
+                "wrong timing for {:08X}", raw);
+        }
+
+        let thumb: [(u32, u32); 3] = [
+            (0xD000, 3), // beq (not taken): 1S
+            (0xF000, 3), // first half of bl: 1S
+            (0xF800, 11), // second half of bl: 2S + 1N
+        ];
+        for &(raw, cycles) in thumb.iter() {
+            assert_eq!(instruction_cycles(raw, InstructionSet::THUMB), cycles,
+                "wrong timing for {:04X}", raw);
+        }
+    }
+
+    /// Write the BIOS IRQ handler, which calls the handler at 0x3007FFC
+    fn load_irq_bios(gba: &mut CPUWrapper) {
+        let bios: [u32; 6] = [
+            0xE92D500F, // stmfd sp!, {r0-r3, r12, lr}
+            0xE3A00301, // mov r0, #0x4000000
+            0xE28FE000, // add lr, pc, #0
+            0xE510F004, // ldr pc, [r0, #-4]
+            0xE8BD500F, // ldmfd sp!, {r0-r3, r12, lr}
+            0xE25EF004, // subs pc, lr, #4
+        ];
+        for (i, ins) in bios.iter().enumerate() {
+            for j in 0..4 {
+                gba.cpu.mem.raw.sysrom[0x18 + i * 4 + j] = (ins >> (j * 8)) as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn irq_handler() {
+        let mut gba = CPUWrapper::new_direct_boot();
+        load_irq_bios(&mut gba);
+        // acknowledges the interrupts in IF with a word write to IE/IF, sets
+        // them in the BIOS flags at 0x3007FF8, and counts them at 0x3000200
+        let handler: [u32; 20] = [
+            0xE3A03301, // mov r3, #0x4000000
+            0xE2833C02, // add r3, r3, #0x200
+            0xE5932000, // ldr r2, [r3]
+            0xE0021822, // and r1, r2, r2, lsr #16
+            0xE1A00802, // mov r0, r2, lsl #16
+            0xE1A00820, // mov r0, r0, lsr #16
+            0xE1800801, // orr r0, r0, r1, lsl #16
+            0xE5830000, // str r0, [r3]
+            0xE3A00403, // mov r0, #0x3000000
+            0xE2800C7F, // add r0, r0, #0x7F00
+            0xE28000F8, // add r0, r0, #0xF8
+            0xE1D020B0, // ldrh r2, [r0]
+            0xE1822001, // orr r2, r2, r1
+            0xE1C020B0, // strh r2, [r0]
+            0xE3A00403, // mov r0, #0x3000000
+            0xE2800C02, // add r0, r0, #0x200
+            0xE5902000, // ldr r2, [r0]
+            0xE2822001, // add r2, r2, #1
+            0xE5802000, // str r2, [r0]
+            0xE12FFF1E, // bx lr
+        ];
+        for (i, ins) in handler.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000100 + i as u32 * 4, *ins);
+        }
+        gba.cpu.mem.set_word(0x3007FFC, 0x3000100);
+        gba.cpu.mem.set_word(0x3000000, 0xE3A04001); // mov r4, #1
+        gba.cpu.mem.set_word(0x3000004, 0xEAFFFFFE); // b 0x3000004
+        gba.cpu.r[15] = 0x3000000;
+
+        gba.cpu.mem.set_halfword(0x4000004, 0x8); // VBlank IRQ
+        gba.cpu.mem.set_halfword(0x4000200, 0x1); // IE = VBlank
+        gba.cpu.mem.set_halfword(0x4000208, 0x1); // IME
+        for _ in 0..3 {
+            gba.frame().unwrap();
+        }
+
+        // the handler ran once per VBlank
+        assert_eq!(gba.cpu.mem.get_word(0x3000200), 3);
+        assert_eq!(gba.cpu.mem.get_halfword(0x3007FF8), 1);
+        assert_eq!(gba.cpu.mem.get_halfword(0x4000202), 0);
+        assert_eq!(gba.cpu.mem.get_halfword(0x4000200), 1);
+        // and returned to the main loop each time
+        assert_eq!(gba.cpu.cpsr.mode, CPUMode::SYS);
+        assert_eq!(gba.cpu.cpsr.irq, false);
+        assert_eq!(gba.cpu.r[4], 1);
+        assert_eq!(gba.cpu.r_irq[0], 0x3007FA0);
+        let pc = gba.cpu.r[15];
+        assert!(pc >= 0x3000004 && pc <= 0x300000C);
+    }
+
+    /// Run three frames with a VBlank handler that acknowledges the interrupt,
+    /// counts it at 0x3000200, and then returns with the given instruction
+    fn count_vblanks(gba: &mut CPUWrapper, ret: u32) -> u32 {
+        let handler: [u32; 10] = [
+            0xE3A03301, // mov r3, #0x4000000
+            0xE2833C02, // add r3, r3, #0x200
+            0xE3A02001, // mov r2, #1
+            0xE1C320B2, // strh r2, [r3, #2]
+            0xE3A00403, // mov r0, #0x3000000
+            0xE2800C02, // add r0, r0, #0x200
+            0xE5902000, // ldr r2, [r0]
+            0xE2822001, // add r2, r2, #1
+            0xE5802000, // str r2, [r0]
+            ret,
+        ];
+        for (i, ins) in handler.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000100 + i as u32 * 4, *ins);
+        }
+        gba.cpu.mem.set_word(0x3007FFC, 0x3000100);
+        gba.cpu.mem.set_word(0x3000000, 0xEAFFFFFE); // b 0x3000000
+        gba.cpu.r[15] = 0x3000000;
+
+        gba.cpu.mem.set_halfword(0x4000004, 0x8); // VBlank IRQ
+        gba.cpu.mem.set_halfword(0x4000200, 0x1); // IE = VBlank
+        gba.cpu.mem.set_halfword(0x4000208, 0x1); // IME
+        for _ in 0..3 {
+            gba.frame().unwrap();
+        }
+        assert_eq!(gba.cpu.cpsr.mode, CPUMode::SYS);
+        assert_eq!(gba.cpu.cpsr.irq, false);
+        gba.cpu.mem.get_word(0x3000200)
+    }
+
+    #[test]
+    #[cfg(all(feature = "render", feature = "debugger"))]
+    fn render_to_current_line() {
+        let mut gba = CPUWrapper::new();
+        gba.cycles = 5 * SCANLINE + 10;
+        assert_eq!(gba.render_to_current_line(), 5);
+        gba.cycles = 5 * SCANLINE + HDRAW;
+        assert_eq!(gba.render_to_current_line(), 6);
+        assert_eq!(gba.cpu.mem.framebuffer.speculative_from, Some(6));
+        gba.cycles = VDRAW + 10;
+        assert_eq!(gba.render_to_current_line(), 160);
+    }
+
+    #[test]
+    fn update_lcd_across_events() {
+        let mut gba = CPUWrapper::new();
+        // a whole frame in one go still stops at every event on the way
+        gba.cpu.mem.set_halfword(0x4000004, 0x8); // VBlank IRQ
+        assert!(!gba.update_lcd(VDRAW + 10));
+        assert_eq!(gba.cpu.mem.get_halfword(0x4000006), 160);
+        assert!(gba.cpu.mem.int.triggered.vblank);
+
+        // a new frame is reported whether it starts part way through the
+        // cycles or right at the end of them
+        assert!(gba.update_lcd(VBLANK));
+        assert_eq!(gba.cycles, 10);
+        assert!(!gba.update_lcd(REFRESH - 20));
+        assert!(gba.update_lcd(10));
+        assert_eq!(gba.cycles, 0);
+        assert!(!gba.update_lcd(0));
+    }
+
+    #[test]
+    fn vcount_match_mid_frame() {
+        let mut gba = CPUWrapper::new();
+        let raised = |gba: &mut CPUWrapper, cycles: u32| {
+            gba.update_lcd(cycles);
+            let raised = gba.cpu.mem.int.triggered.vcount;
+            gba.cpu.mem.set_halfword(0x4000202, 0b100);
+            raised
+        };
+        gba.cpu.mem.set_halfword(0x4000004, 0x6420);
+        assert!(!raised(&mut gba, 50 * SCANLINE + 10));
+        // moved onto the current line part way through it
+        gba.cpu.mem.set_halfword(0x4000004, 0x3220);
+        assert!(raised(&mut gba, 0));
+        assert!(!raised(&mut gba, SCANLINE - 10));
+
+        // then once a frame, on the new line
+        let mut lines = Vec::new();
+        for _ in 0..2 * 228 {
+            if raised(&mut gba, SCANLINE) {
+                lines.push(gba.cycles / SCANLINE);
+            }
+        }
+        assert_eq!(lines, vec![50, 50]);
+    }
+
+    #[test]
+    fn irq_dispatch() {
+        // the emulated BIOS handler calls the game's handler without a BIOS
+        let mut gba = CPUWrapper::new_direct_boot();
+        gba.cpu.bios.irq_dispatch = IrqDispatch::Hle;
+        assert_eq!(count_vblanks(&mut gba, 0xE12FFF1E), 3); // bx lr
+        assert_eq!(gba.cpu.r_irq[0], 0x3007FA0);
+
+        // the BIOS is only replaced when it's emulated
+        let mut gba = CPUWrapper::new_direct_boot();
+        gba.cpu.bios.irq_dispatch = IrqDispatch::Hle;
+        assert_eq!(gba.cpu.hle_fetch(0x18), Some(0xE92D500F));
+        assert_eq!(gba.cpu.hle_fetch(0x30), None);
+        gba.cpu.bios.irq_dispatch = IrqDispatch::Bios;
+        gba.cpu.mem.bios_loaded = true;
+        assert_eq!(gba.cpu.hle_fetch(0x18), None);
+        // but it's always emulated without a BIOS
+        gba.cpu.mem.bios_loaded = false;
+        assert_eq!(gba.cpu.hle_fetch(0x18), Some(0xE92D500F));
+
+        // going straight to the handler, it has to return from the exception
+        let mut gba = CPUWrapper::new_direct_boot();
+        gba.cpu.bios.irq_dispatch = IrqDispatch::Vector;
+        assert_eq!(count_vblanks(&mut gba, 0xE25EF004), 3); // subs pc, lr, #4
+    }
+
+    #[test]
+    fn nested_irq() {
+        let mut gba = CPUWrapper::new_direct_boot();
+        load_irq_bios(&mut gba);
+        // the HBlank handler saves SPSR_irq and LR_irq, then re-enables
+        // interrupts (only VBlank) in SYS mode while it spins for long enough
+        // that the VBlank after line 159 interrupts it. Counts of VBlanks,
+        // HBlanks and nested VBlanks are kept at 0x3000200-0x3000208
+        let handler: [u32; 48] = [
+            0xE3A03301, // mov r3, #0x4000000
+            0xE2833C02, // add r3, r3, #0x200
+            0xE5932000, // ldr r2, [r3]
+            0xE0021822, // and r1, r2, r2, lsr #16
+            0xE1C310B2, // strh r1, [r3, #2]
+            0xE3110001, // tst r1, #1
+            0x1A00001E, // bne vblank
+            0xE14F0000, // mrs r0, spsr
+            0xE92D4005, // stmfd sp!, {r0, r2, lr}
+            0xE3A00001, // mov r0, #1
+            0xE1C300B0, // strh r0, [r3] (IE = VBlank)
+            0xE1C300B8, // strh r0, [r3, #8] (IME)
+            0xE10F0000, // mrs r0, cpsr
+            0xE3C000DF, // bic r0, r0, #0xDF
+            0xE380001F, // orr r0, r0, #0x1F
+            0xE129F000, // msr cpsr_fc, r0 (SYS mode, IRQs enabled)
+            0xE92D4000, // stmfd sp!, {lr}
+            0xE3A02403, // mov r2, #0x3000000
+            0xE2822C02, // add r2, r2, #0x200
+            0xE3A01001, // mov r1, #1
+            0xE582100C, // str r1, [r2, #12] (in HBlank handler)
+            0xE3A00064, // mov r0, #100
+            0xE2500001, // subs r0, r0, #1
+            0x1AFFFFFD, // bne -1
+            0xE3A01000, // mov r1, #0
+            0xE582100C, // str r1, [r2, #12]
+            0xE5921004, // ldr r1, [r2, #4]
+            0xE2811001, // add r1, r1, #1
+            0xE5821004, // str r1, [r2, #4]
+            0xE8BD4000, // ldmfd sp!, {lr}
+            0xE10F0000, // mrs r0, cpsr
+            0xE3C000DF, // bic r0, r0, #0xDF
+            0xE3800092, // orr r0, r0, #0x92
+            0xE129F000, // msr cpsr_fc, r0 (IRQ mode, IRQs disabled)
+            0xE8BD4005, // ldmfd sp!, {r0, r2, lr}
+            0xE169F000, // msr spsr_fc, r0
+            0xE1C320B0, // strh r2, [r3] (restore IE)
+            0xE12FFF1E, // bx lr
+            // vblank:
+            0xE3A02403, // mov r2, #0x3000000
+            0xE2822C02, // add r2, r2, #0x200
+            0xE5921000, // ldr r1, [r2]
+            0xE2811001, // add r1, r1, #1
+            0xE5821000, // str r1, [r2]
+            0xE592100C, // ldr r1, [r2, #12]
+            0xE5920008, // ldr r0, [r2, #8]
+            0xE0800001, // add r0, r0, r1
+            0xE5820008, // str r0, [r2, #8]
+            0xE12FFF1E, // bx lr
+        ];
+        for (i, ins) in handler.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000100 + i as u32 * 4, *ins);
+        }
+        gba.cpu.mem.set_word(0x3007FFC, 0x3000100);
+        // counts the number of times the flags were wrong after an interrupt
+        let main: [u32; 5] = [
+            0xE3A04001, // mov r4, #1
+            0xE3540001, // cmp r4, #1
+            0x02877001, // addeq r7, r7, #1
+            0x12888001, // addne r8, r8, #1
+            0xEAFFFFFB, // b 0x3000004
+        ];
+        for (i, ins) in main.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000000 + i as u32 * 4, *ins);
+        }
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.r[13] = 0x3007F00;
+        gba.cpu.r[14] = 0x1234;
+
+        gba.cpu.mem.set_halfword(0x4000004, 0x18); // VBlank and HBlank IRQs
+        gba.cpu.mem.set_halfword(0x4000200, 0x3);
+        gba.cpu.mem.set_halfword(0x4000208, 0x1);
+        for _ in 0..4 {
+            gba.frame().unwrap();
+        }
+
+        // every VBlank arrived while the HBlank handler for line 159 was
+        // running, and no HBlanks were lost
+        assert_eq!(gba.cpu.mem.get_word(0x3000200), 4);
+        assert_eq!(gba.cpu.mem.get_word(0x3000204), 4 * 160);
+        assert_eq!(gba.cpu.mem.get_word(0x3000208), 4);
+        // the main loop never saw corrupted registers or flags
+        assert_eq!(gba.cpu.cpsr.mode, CPUMode::SYS);
+        assert_eq!(gba.cpu.cpsr.irq, false);
+        assert_eq!(gba.cpu.r[8], 0);
+        assert!(gba.cpu.r[7] > 1000);
+        assert_eq!(gba.cpu.r[13], 0x3007F00);
+        assert_eq!(gba.cpu.r[14], 0x1234);
+        assert_eq!(gba.cpu.r_irq[0], 0x3007FA0);
+        assert_eq!(gba.cpu.mem.get_halfword(0x4000200), 0x3);
+    }
 }
@@ -0,0 +1,151 @@
+//! Counts how many times each kind of instruction runs, for profiling the
+//! interpreter itself: the handlers that run the most are the ones most worth
+//! giving a fast path. ARM instructions are counted by their Instruction
+//! variant, and THUMB instructions by their format, since several formats
+//! decode to the same variant (e.g. all of the THUMB loads and stores become
+//! single data transfers). Instructions skipped because their condition
+//! failed aren't counted. Counting is off by default, since it's only useful
+//! to emulator developers.
+
+use std::cmp::Reverse;
+use cpu::pipeline::{Instruction, thumb_format};
+use cpu::thumb;
+
+const ARM_KINDS: [&str; 11] = [
+    "ARM data processing",
+    "ARM PSR transfer",
+    "ARM multiply",
+    "ARM multiply long",
+    "ARM single data swap",
+    "ARM single data transfer",
+    "ARM halfword/signed data transfer",
+    "ARM block data transfer",
+    "ARM branch",
+    "ARM branch and exchange",
+    "ARM software interrupt",
+];
+
+/// Decodes a THUMB instruction of one format
+type ThumbDecoder = fn(u16) -> Instruction;
+
+/// The THUMB formats, numbered as in GBATEK, and the function each one is
+/// decoded with
+const THUMB_FORMATS: [(&str, ThumbDecoder); 19] = [
+    ("THUMB 1: move shifted register", thumb::move_),
+    ("THUMB 2: add/subtract", thumb::add_sub),
+    ("THUMB 3: move/compare/add/subtract immediate", thumb::data_imm),
+    ("THUMB 4: ALU operations", thumb::alu_op),
+    ("THUMB 5: hi register operations/branch exchange", thumb::hi_reg_bex),
+    ("THUMB 6: PC-relative load", thumb::pc_rel_load),
+    ("THUMB 7: load/store with register offset", thumb::reg_offset_trans),
+    ("THUMB 8: load/store sign-extended byte/halfword", thumb::signed_trans),
+    ("THUMB 9: load/store with immediate offset", thumb::imm_offset_trans),
+    ("THUMB 10: load/store halfword", thumb::hw_trans),
+    ("THUMB 11: SP-relative load/store", thumb::sp_rel_trans),
+    ("THUMB 12: load address", thumb::load_addr),
+    ("THUMB 13: add offset to stack pointer", thumb::incr_sp),
+    ("THUMB 14: push/pop registers", thumb::push_pop),
+    ("THUMB 15: multiple load/store", thumb::block_trans),
+    ("THUMB 16: conditional branch", thumb::cond_branch),
+    ("THUMB 17: software interrupt", thumb::swi),
+    ("THUMB 18: unconditional branch", thumb::branch),
+    ("THUMB 19: long branch with link", thumb::long_branch),
+];
+
+pub struct OpcodeStats {
+    pub enabled: bool,
+    /// executions of each of ARM_KINDS
+    arm: [u64; 11],
+    /// executions of each of THUMB_FORMATS
+    thumb: [u64; 19],
+}
+
+impl OpcodeStats {
+    pub const fn new() -> OpcodeStats {
+        OpcodeStats {
+            enabled: false,
+            arm: [0; 11],
+            thumb: [0; 19],
+        }
+    }
+
+    pub fn record_arm(&mut self, ins: &Instruction) {
+        let kind = match *ins {
+            Instruction::DataProc(_) => 0,
+            Instruction::PSRTransfer(_) => 1,
+            Instruction::Multiply(_) => 2,
+            Instruction::MultiplyLong(_) => 3,
+            Instruction::SwapTransfer(_) => 4,
+            Instruction::SingleTransfer(_) => 5,
+            Instruction::SignedTransfer(_) => 6,
+            Instruction::BlockTransfer(_) => 7,
+            Instruction::Branch(_) => 8,
+            Instruction::BranchEx(_) => 9,
+            Instruction::SWInterrupt(_) => 10,
+            // these only come from THUMB instructions
+            Instruction::CondBranch(_) | Instruction::LongBranch(_) => return,
+        };
+        self.arm[kind] += 1;
+    }
+
+    /// Count the given raw THUMB instruction
+    pub fn record_thumb(&mut self, raw: u16) {
+        let format = match thumb_format(raw) {
+            Some(format) => format as usize,
+            None => return,
+        };
+        if let Some(i) = THUMB_FORMATS.iter().position(|&(_, f)| f as usize == format) {
+            self.thumb[i] += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.arm = [0; 11];
+        self.thumb = [0; 19];
+    }
+
+    /// Return the name and count of each kind of instruction that has run
+    /// since the last reset, most frequent first
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        let arm = ARM_KINDS.iter().cloned().zip(self.arm.iter().cloned());
+        let thumb = THUMB_FORMATS.iter().map(|&(name, _)| name).zip(self.thumb.iter().cloned());
+        let mut counts: Vec<_> = arm.chain(thumb).filter(|&(_, count)| count > 0).collect();
+        counts.sort_by_key(|&(_, count)| Reverse(count));
+        counts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cpu::CPUWrapper;
+
+    #[test]
+    fn counts() {
+        let mut gba = CPUWrapper::new();
+        let code: [u16; 4] = [
+            0x3001, // add r0, #1
+            0x6008, // str r0, [r1]
+            0x6008, // str r0, [r1]
+            0xE7FB, // b .-6
+        ];
+        for (i, ins) in code.iter().enumerate() {
+            gba.cpu.mem.set_halfword(0x3000000 + i as u32 * 2, *ins as u32);
+        }
+        gba.direct_boot_at(0x3000001);
+        gba.cpu.set_reg(1, 0x2000000);
+        gba.opcode_stats.enabled = true;
+        // 10 times around the loop, plus the steps that refill the pipeline
+        // after each branch
+        for _ in 0..60 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.opcode_stats.counts(), vec![
+            ("THUMB 9: load/store with immediate offset", 20),
+            ("THUMB 3: move/compare/add/subtract immediate", 10),
+            ("THUMB 18: unconditional branch", 10),
+        ]);
+
+        gba.opcode_stats.reset();
+        assert!(gba.opcode_stats.counts().is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+//! Overclocking lets the CPU run more cycles per scanline than the hardware
+//! does, which removes the slowdown in games that can't finish their work
+//! within a frame. Only the CPU speeds up: the LCD, DMA and serial port still
+//! run on the normal clock, so the game still runs at 60 frames per second.
+//! This is done by converting the cycles each instruction takes into cycles of
+//! the rest of the hardware before they're run.
+
+/// the fastest the CPU can be set to run, relative to the normal speed
+pub const MAX_MULTIPLIER: f64 = 4.0;
+
+/// the multiplier is kept as a fraction of this, so that the conversion
+/// doesn't need any floating point
+const ONE: u32 = 256;
+
+pub struct Overclock {
+    /// how many times faster the CPU runs, in 1/ONEs
+    ratio: u32,
+    /// the part of a hardware cycle (in 1/ONEs) that the CPU has run but that
+    /// hasn't been passed on yet
+    remainder: u32,
+}
+
+impl Overclock {
+    pub const fn new() -> Overclock {
+        Overclock {
+            ratio: ONE,
+            remainder: 0,
+        }
+    }
+
+    /// Set how many times faster than normal the CPU runs, from 1 to
+    /// MAX_MULTIPLIER. NaN runs at normal speed
+    pub fn set_multiplier(&mut self, multiplier: f64) {
+        let multiplier = if multiplier.is_nan() {
+            1.0
+        } else {
+            multiplier.clamp(1.0, MAX_MULTIPLIER)
+        };
+        self.ratio = (multiplier * ONE as f64).round() as u32;
+        self.remainder = 0;
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.ratio as f64 / ONE as f64
+    }
+
+    /// Convert the given number of CPU cycles into cycles of the rest of the
+    /// hardware. Leftover fractions of a cycle are carried over to the next
+    /// call, so none are lost. The math is done in 64 bits, since a long
+    /// enough stretch of cycles (e.g. while halted) overflows 32 in 1/ONEs
+    pub fn hardware_cycles(&mut self, cycles: u32) -> u32 {
+        if self.ratio == ONE {
+            return cycles;
+        }
+        let total = cycles as u64 * ONE as u64 + self.remainder as u64;
+        self.remainder = (total % self.ratio as u64) as u32;
+        (total / self.ratio as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hardware_cycles() {
+        let mut overclock = Overclock::new();
+        assert_eq!(overclock.hardware_cycles(7), 7);
+
+        overclock.set_multiplier(1.5);
+        let total: u32 = (0..300).map(|_| overclock.hardware_cycles(3)).sum();
+        assert_eq!(total, 600);
+
+        // without overflowing for any number of cycles
+        overclock.set_multiplier(2.0);
+        assert_eq!(overclock.hardware_cycles(u32::MAX), u32::MAX / 2);
+        assert_eq!(overclock.hardware_cycles(1), 1);
+
+        overclock.set_multiplier(10.0);
+        assert_eq!(overclock.multiplier(), MAX_MULTIPLIER);
+        overclock.set_multiplier(0.5);
+        assert_eq!(overclock.multiplier(), 1.0);
+        overclock.set_multiplier(f64::NAN);
+        assert_eq!(overclock.multiplier(), 1.0);
+    }
+}
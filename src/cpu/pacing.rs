@@ -0,0 +1,471 @@
+//! Automatic frame skipping for hosts that can't run the emulator at full
+//! speed. Skipped frames are still fully emulated (so game logic and timing
+//! are unaffected), the framebuffer just isn't redrawn for them. The host is
+//! expected to report how long each frame took to compute, since there is no
+//! clock available from inside the emulator.
+//!
+//! The host can also ask how many frames it should run based on the wall
+//! clock (frames_due). This is capped, so that when the frame loop stops for
+//! a while (e.g. the browser tab is in the background) the emulator doesn't
+//! fast forward to catch up once it starts again. Hosts that tick in time
+//! with the display (e.g. requestAnimationFrame at 60Hz or 120Hz) are close
+//! to, but not exactly at, a multiple of the GBA's refresh rate, so the time
+//! left over is carried between ticks and the occasional tick runs no frames
+//! (or two). This keeps the long term speed exact, which means the audio
+//! doesn't need to change pitch to keep up.
+//!
+//! Since frames are run against the wall clock and audio is played against
+//! the audio device's clock, the two slowly drift apart over a long session.
+//! AvSync measures the drift from the number of samples the host has played
+//! and the number of frames emulated, and turns it into a slight resampling
+//! ratio for the audio that pulls them back together. It is only compiled in
+//! with the audio feature.
+
+use ::cpu::REFRESH;
+
+/// the GBA CPU runs at 2^24 Hz
+pub const CLOCK_HZ: f64 = 16777216.0;
+/// the number of frames the GBA draws per second (about 59.7275)
+pub const REFRESH_HZ: f64 = CLOCK_HZ / REFRESH as f64;
+/// the amount of time the host has to compute a frame to keep up with the GBA
+/// (about 16.74ms)
+pub const FRAME_BUDGET_MS: f64 = REFRESH as f64 * 1000.0 / CLOCK_HZ;
+/// weight given to the newest frame time in the moving average
+const AVERAGE_WEIGHT: f64 = 0.1;
+/// once skipping, only skip fewer frames when the average frame time drops
+/// below this fraction of the budget, so that we don't flip back and forth
+/// between two skip levels
+const RECOVER_RATIO: f64 = 0.75;
+/// the most frames that will be run at once to catch up after the host falls
+/// behind, at normal speed
+pub const MAX_CATCH_UP: u32 = 2;
+/// the fastest the emulation can be set to run, relative to the GBA. Much
+/// faster and a frame's budget is shorter than the time it takes to run it
+pub const MAX_SPEED: f64 = 16.0;
+/// when the host ticks more often than the GBA refreshes, a tick only runs
+/// two frames if the emulator is this many host ticks behind, rather than
+/// just a little behind because of jitter in the host's timing
+const JITTER_TICKS: f64 = 1.5;
+/// ticks longer than this are treated as the host stalling, and aren't
+/// counted towards its usual interval
+const MAX_TICK_MS: f64 = 100.0;
+/// the most the audio is resampled by to correct drift. A 0.5% change in
+/// pitch is too small to hear
+#[cfg(feature = "audio")]
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+/// the change in the resampling ratio for each millisecond of drift, so that
+/// the full adjustment is reached at 25ms
+#[cfg(feature = "audio")]
+const DRIFT_GAIN: f64 = MAX_RATE_ADJUST / 25.0;
+
+pub struct Pacing {
+    /// the most number of frames in a row that will be skipped. 0 disables
+    /// frame skipping
+    pub max_skip: u32,
+    /// the number of frames currently being skipped after each rendered frame
+    pub skip: u32,
+    /// exponential moving average of the time taken to compute each frame, in
+    /// milliseconds
+    pub avg_frame_ms: f64,
+    /// whether the frame currently being emulated will be drawn
+    pub rendering: bool,
+    /// number of frames skipped since the last rendered frame
+    skipped_in_row: u32,
+    pub frames_rendered: u64,
+    pub frames_skipped: u64,
+    /// emulation speed relative to the GBA, e.g. 2.0 to fast forward. Only
+    /// affects the number of frames due
+    pub speed: f64,
+    /// while paused, frame() doesn't run and no frames are due
+    pub paused: bool,
+    /// wall clock time of the last call to frames_due
+    last_tick_ms: Option<f64>,
+    /// time since the last call to frames_due that hasn't been used up by a
+    /// whole frame yet
+    owed_ms: f64,
+    /// moving average of the time between the host's ticks, or 0 before the
+    /// first one
+    pub host_interval_ms: f64,
+    #[cfg(feature = "audio")]
+    pub av_sync: AvSync,
+}
+
+impl Pacing {
+    pub const fn new() -> Pacing {
+        Pacing {
+            max_skip: 3,
+            skip: 0,
+            avg_frame_ms: 0.0,
+            rendering: true,
+            skipped_in_row: 0,
+            frames_rendered: 0,
+            frames_skipped: 0,
+            speed: 1.0,
+            paused: false,
+            last_tick_ms: None,
+            owed_ms: 0.0,
+            host_interval_ms: 0.0,
+            #[cfg(feature = "audio")]
+            av_sync: AvSync::new(),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.last_tick_ms = None;
+    }
+
+    /// Resume after a pause, starting the wall clock over so that the time
+    /// spent paused isn't made up for
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.last_tick_ms = None;
+        self.owed_ms = 0.0;
+        #[cfg(feature = "audio")]
+        self.av_sync.reset();
+    }
+
+    /// Return the number of frames the host should run, given the current
+    /// wall clock time in milliseconds
+    pub fn frames_due(&mut self, now_ms: f64) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        let last = match self.last_tick_ms {
+            Some(last) => last,
+            None => {
+                self.last_tick_ms = Some(now_ms);
+                return 1;
+            },
+        };
+        self.last_tick_ms = Some(now_ms);
+        self.frames_due_after(now_ms - last)
+    }
+
+    /// Return the number of frames the host should run, given the time in
+    /// milliseconds since it last asked. A tick that lands just before a
+    /// frame boundary runs no frames, and without care jitter would then make
+    /// the next one run two, which shows as a stutter. So while the host
+    /// ticks faster than the GBA refreshes, the second frame waits for the
+    /// tick after unless the emulator has fallen a whole tick behind
+    pub fn frames_due_after(&mut self, delta_ms: f64) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        let delta_ms = delta_ms.max(0.0);
+        if delta_ms <= MAX_TICK_MS {
+            self.host_interval_ms = if self.host_interval_ms == 0.0 {
+                delta_ms
+            } else {
+                self.host_interval_ms + AVERAGE_WEIGHT * (delta_ms - self.host_interval_ms)
+            };
+        }
+        self.owed_ms += delta_ms;
+        let budget = FRAME_BUDGET_MS / self.speed;
+        let max_frames = (MAX_CATCH_UP as f64 * self.speed.max(1.0)).ceil();
+        let mut frames = (self.owed_ms / budget).floor();
+        if frames > max_frames {
+            self.owed_ms = 0.0;
+            return max_frames as u32;
+        }
+        let interval = self.host_interval_ms;
+        if frames > 1.0 && interval > 0.0 && interval < budget &&
+            self.owed_ms < budget + JITTER_TICKS * interval {
+            frames = 1.0;
+        }
+        self.owed_ms -= frames * budget;
+        frames as u32
+    }
+
+    pub fn set_max_skip(&mut self, max_skip: u32) {
+        self.max_skip = max_skip;
+        if self.skip > max_skip {
+            self.skip = max_skip;
+        }
+    }
+
+    /// Update the moving average with the time taken to compute the last
+    /// frame, and adjust the number of frames to skip
+    pub fn record_frame_time(&mut self, ms: f64) {
+        self.avg_frame_ms = if self.frames_rendered + self.frames_skipped == 0 {
+            ms
+        } else {
+            self.avg_frame_ms + AVERAGE_WEIGHT * (ms - self.avg_frame_ms)
+        };
+
+        // only change the skip level at the end of a skip cycle, after a
+        // rendered frame has contributed to the average
+        if self.skipped_in_row != 0 {
+            return;
+        }
+        if self.avg_frame_ms > FRAME_BUDGET_MS && self.skip < self.max_skip {
+            self.skip += 1;
+        } else if self.avg_frame_ms < FRAME_BUDGET_MS * RECOVER_RATIO && self.skip > 0 {
+            self.skip -= 1;
+        }
+    }
+
+    /// Called at the start of each frame to decide whether it will be drawn
+    pub fn start_frame(&mut self) {
+        #[cfg(feature = "audio")]
+        self.av_sync.record_frame();
+        self.rendering = self.skipped_in_row >= self.skip;
+        if self.rendering {
+            self.skipped_in_row = 0;
+            self.frames_rendered += 1;
+        } else {
+            self.skipped_in_row += 1;
+            self.frames_skipped += 1;
+        }
+    }
+
+    /// Estimate the number of frames per second that the host is emulating
+    pub fn fps(&self) -> f64 {
+        let max_fps = 1000.0 / FRAME_BUDGET_MS;
+        if self.avg_frame_ms <= 0.0 {
+            max_fps
+        } else {
+            (1000.0 / self.avg_frame_ms).min(max_fps)
+        }
+    }
+
+    /// Estimate the emulation speed as a percentage of the GBA's: the speed
+    /// asked for, unless the host can't keep up with it
+    pub fn speed_percent(&self) -> f64 {
+        let max_speed = if self.avg_frame_ms <= 0.0 {
+            self.speed
+        } else {
+            FRAME_BUDGET_MS / self.avg_frame_ms
+        };
+        self.speed.min(max_speed) * 100.0
+    }
+
+    /// Return a short summary for display, e.g. "FPS: 60 (skipping 1/2)".
+    /// Once the host reports audio, the A/V drift is added
+    pub fn summary(&self) -> String {
+        let summary = if self.skip == 0 {
+            format!("FPS: {:.0}", self.fps())
+        } else {
+            format!("FPS: {:.0} (skipping {}/{})", self.fps(), self.skip, self.skip + 1)
+        };
+        summary + &self.drift_summary()
+    }
+
+    #[cfg(feature = "audio")]
+    fn drift_summary(&self) -> String {
+        if self.av_sync.is_active() {
+            format!(", A/V drift: {:+.1}ms", self.av_sync.drift_ms)
+        } else {
+            String::new()
+        }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn drift_summary(&self) -> String {
+        String::new()
+    }
+}
+
+#[cfg(feature = "audio")]
+pub struct AvSync {
+    /// the rate the host plays audio at
+    pub sample_rate: f64,
+    /// samples played by the host since the last reset
+    samples: u64,
+    /// frames emulated since the last reset
+    frames: u64,
+    /// moving average of how far the audio is ahead of the video, in
+    /// milliseconds. Positive means the host has played more audio than the
+    /// frames emulated so far would produce
+    pub drift_ms: f64,
+    /// the ratio to resample the emulator's audio by, e.g. 1.001 to produce
+    /// 0.1% more samples. Always within MAX_RATE_ADJUST of 1
+    pub resample_ratio: f64,
+}
+
+#[cfg(feature = "audio")]
+impl AvSync {
+    pub const fn new() -> AvSync {
+        AvSync {
+            sample_rate: 48000.0,
+            samples: 0,
+            frames: 0,
+            drift_ms: 0.0,
+            resample_ratio: 1.0,
+        }
+    }
+
+    /// Start measuring again. Frames run before the host's audio output
+    /// starts would count as drift, so this should be called when it starts
+    /// (and is called by resume())
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.frames = 0;
+        self.drift_ms = 0.0;
+        self.resample_ratio = 1.0;
+    }
+
+    /// Return true once the host has reported any audio
+    pub fn is_active(&self) -> bool {
+        self.samples > 0
+    }
+
+    /// Called by the host with the number of samples its audio output has
+    /// played since the last call
+    pub fn record_samples(&mut self, count: u32) {
+        self.samples += count as u64;
+    }
+
+    /// Called at the start of each emulated frame to update the drift and the
+    /// resampling ratio, comparing the audio played so far to the frames that
+    /// have finished. The host reports audio in chunks, so the drift is
+    /// averaged over many frames
+    fn record_frame(&mut self) {
+        if self.is_active() {
+            let audio_ms = self.samples as f64 * 1000.0 / self.sample_rate;
+            let video_ms = self.frames as f64 * FRAME_BUDGET_MS;
+            self.drift_ms += AVERAGE_WEIGHT * (audio_ms - video_ms - self.drift_ms);
+            let adjust = (self.drift_ms * DRIFT_GAIN).max(-MAX_RATE_ADJUST).min(MAX_RATE_ADJUST);
+            self.resample_ratio = 1.0 + adjust;
+        }
+        self.frames += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skip_when_slow() {
+        let mut pacing = Pacing::new();
+        pacing.set_max_skip(2);
+        for _ in 0..50 {
+            pacing.start_frame();
+            pacing.record_frame_time(40.0);
+        }
+        assert_eq!(pacing.skip, 2);
+        assert!(pacing.frames_skipped > 0);
+        assert_eq!(pacing.summary(), "FPS: 25 (skipping 2/3)");
+        assert_eq!(pacing.speed_percent().round(), 42.0);
+
+        // every third frame is drawn
+        let rendered: Vec<bool> = (0..6).map(|_| {
+            pacing.start_frame();
+            pacing.rendering
+        }).collect();
+        let count = rendered.iter().filter(|&&r| r).count();
+        assert_eq!(count, 2);
+
+        // recover once the host speeds up again
+        for _ in 0..200 {
+            pacing.start_frame();
+            pacing.record_frame_time(5.0);
+        }
+        assert_eq!(pacing.skip, 0);
+        assert!(pacing.rendering);
+        assert_eq!(pacing.summary(), "FPS: 60");
+        assert_eq!(pacing.speed_percent(), 100.0);
+    }
+
+    #[test]
+    fn frames_due() {
+        let mut pacing = Pacing::new();
+        assert_eq!(pacing.frames_due(1000.0), 1);
+        assert_eq!(pacing.frames_due(1010.0), 0);
+        assert_eq!(pacing.frames_due(1020.0), 1);
+        assert_eq!(pacing.frames_due(1020.0 + 2.0 * FRAME_BUDGET_MS), 2);
+
+        // falling far behind doesn't cause a burst of frames
+        assert_eq!(pacing.frames_due(5000.0), MAX_CATCH_UP);
+        assert_eq!(pacing.frames_due(5001.0), 0);
+
+        pacing.pause();
+        assert_eq!(pacing.frames_due(6000.0), 0);
+        pacing.resume();
+        assert_eq!(pacing.frames_due(60000.0), 1);
+        assert_eq!(pacing.frames_due(60001.0), 0);
+
+        pacing.speed = 2.0;
+        assert_eq!(pacing.frames_due(60001.0 + FRAME_BUDGET_MS), 2);
+    }
+
+    #[test]
+    fn host_vsync() {
+        // a 60Hz display with up to 0.5ms of jitter on each tick
+        let jitter = [0.0, 0.5, -0.5, 0.3, -0.2, 0.4, -0.4, 0.1];
+        let mut pacing = Pacing::new();
+        let mut run = 0;
+        let mut last = 0;
+        for i in 0..6000 {
+            let delta = 1000.0 / 60.0 + jitter[i % 8] - jitter[(i + 7) % 8];
+            let frames = pacing.frames_due_after(delta);
+            // a tick with no frames is never followed by one with two
+            assert!(frames <= 1 || last != 0, "stutter at tick {}", i);
+            run += frames;
+            last = frames;
+        }
+        // 100 seconds of display time is exactly 100 seconds of GBA frames
+        assert!((run as f64 - 100.0 * REFRESH_HZ).abs() < 1.0);
+        assert!((pacing.host_interval_ms - 1000.0 / 60.0).abs() < 1.0);
+
+        // at 120Hz, every other tick runs a frame
+        let mut pacing = Pacing::new();
+        let run: u32 = (0..1200).map(|_| pacing.frames_due_after(1000.0 / 120.0)).sum();
+        assert!((run as f64 - 10.0 * REFRESH_HZ).abs() < 1.0);
+
+        // a missed tick is still made up for
+        let mut pacing = Pacing::new();
+        assert_eq!(pacing.frames_due_after(1000.0 / 60.0), 0);
+        assert_eq!(pacing.frames_due_after(1000.0 / 60.0), 1);
+        assert_eq!(pacing.frames_due_after(1000.0 / 30.0), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn av_sync() {
+        let mut pacing = Pacing::new();
+        pacing.av_sync.sample_rate = 32768.0;
+        let per_frame = 32768.0 * FRAME_BUDGET_MS / 1000.0;
+        // the audio keeps up with the frames
+        let mut played = 0;
+        for i in 1..601 {
+            pacing.start_frame();
+            let total = (i as f64 * per_frame).round() as u32;
+            pacing.av_sync.record_samples(total - played);
+            played = total;
+        }
+        assert!(pacing.av_sync.drift_ms.abs() < 0.5);
+        assert!((pacing.av_sync.resample_ratio - 1.0).abs() < 0.0001);
+        assert!(pacing.summary().starts_with("FPS: 60, A/V drift: "));
+
+        // the audio device runs 1% fast, so the audio gets ahead and more
+        // samples are needed, up to the limit
+        let start = played;
+        for i in 1..601 {
+            pacing.start_frame();
+            let total = start + (i as f64 * per_frame * 1.01).round() as u32;
+            pacing.av_sync.record_samples(total - played);
+            played = total;
+        }
+        assert!(pacing.av_sync.drift_ms > 25.0);
+        assert_eq!(pacing.av_sync.resample_ratio, 1.0 + MAX_RATE_ADJUST);
+
+        pacing.pause();
+        pacing.resume();
+        assert_eq!(pacing.av_sync.resample_ratio, 1.0);
+        assert_eq!(pacing.summary(), "FPS: 60");
+    }
+
+    #[test]
+    fn disabled() {
+        let mut pacing = Pacing::new();
+        pacing.set_max_skip(0);
+        for _ in 0..20 {
+            pacing.start_frame();
+            pacing.record_frame_time(100.0);
+            assert!(pacing.rendering);
+        }
+        assert_eq!(pacing.frames_skipped, 0);
+    }
+}
@@ -27,7 +27,6 @@ use ::cpu::arm::{
 };
 use ::cpu::thumb;
 use ::cpu::status_reg::PSR;
-use util;
 use num::FromPrimitive;
 
 /// An instruction in a specific stage of the ARM7's three stage pipeline
@@ -40,6 +39,9 @@ pub enum PipelineInstruction {
     RawARM(u32),
     /// A fetched THUMB instruction
     RawTHUMB(u16),
+    /// A fetched ARM or THUMB instruction that doesn't correspond to any
+    /// instruction
+    Undefined(u32),
     // TODO: change the Option<u32> to an Option<CondField> instead since we
     // don't need the rest of the bits
     /// A decoded instruction, containing both the original raw instruction
@@ -47,62 +49,146 @@ pub enum PipelineInstruction {
     Decoded(Option<u32>, Instruction)
 }
 
-/// Decode a raw ARM instruction
-// NOTE: this will incorrectly parse some undefined instructions, but we assume
-// that games will never run those
-pub fn decode_arm(ins: u32) -> Option<Instruction> {
-    let op0 = util::get_nibble(ins, 24);
-    let op1 = util::get_nibble(ins, 20);
-    let op2 = util::get_nibble(ins, 4);
+/// What an ARM instruction is, as far as can be told from bits 20-27 and 4-7,
+/// which is enough to know how to parse the rest of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArmClass {
+    DataProc,
+    PSRTransfer,
+    Multiply,
+    MultiplyLong,
+    SwapTransfer,
+    SignedTransfer,
+    SingleTransfer,
+    BlockTransfer,
+    Branch,
+    SWInterrupt,
+    /// a BX if bits 8-19 are all set, otherwise an MSR
+    BranchExOrPSR,
+    Undefined,
+}
+
+/// Return the index into ARM_CLASSES for an instruction: bits 20-27 followed
+/// by bits 4-7
+fn arm_class_key(ins: u32) -> usize {
+    (((ins >> 16) & 0xFF0) | ((ins >> 4) & 0xF)) as usize
+}
+
+/// The class of every ARM instruction, so that decoding takes a single lookup
+/// instead of working through the checks in classify_arm
+const ARM_CLASSES: [ArmClass; 4096] = arm_classes();
+
+const fn arm_classes() -> [ArmClass; 4096] {
+    let mut classes = [ArmClass::Undefined; 4096];
+    let mut key = 0;
+    while key < 4096 {
+        classes[key] = classify_arm(key as u32);
+        key += 1;
+    }
+    classes
+}
+
+/// Return the class of an instruction from its key (see arm_class_key), made
+/// up of its op0 (bits 24-27), op1 (bits 20-23) and op2 (bits 4-7) nibbles
+// NOTE: this will incorrectly classify some undefined instructions, but we
+// assume that games will never run those
+const fn classify_arm(key: u32) -> ArmClass {
+    let (op0, op1, op2) = (key >> 8, (key >> 4) & 0xF, key & 0xF);
     if op0 == 0 && op1 < 4 && op2 == 0b1001 {
-        Some(Multiply(mul::Multiply::parse_instruction(ins)))
+        ArmClass::Multiply
     } else if op0 == 0 && op1 > 7 && op2 == 0b1001 {
-        Some(MultiplyLong(mul_long::MultiplyLong::parse_instruction(ins)))
+        ArmClass::MultiplyLong
     } else if op0 == 1 && op2 == 9 {
-        Some(SwapTransfer(swap::SingleDataSwap::parse_instruction(ins)))
-    } else if (ins & 0x0FFFFFF0) == 0x012FFF10 {
-        Some(BranchEx(branch_ex::BranchAndExchange::parse_instruction(ins)))
+        ArmClass::SwapTransfer
+    } else if op0 == 1 && op1 == 2 && op2 == 1 {
+        ArmClass::BranchExOrPSR
     } else if op0 < 2 && (op2 == 9 || op2 == 11 || op2 == 13 || op2 == 15) {
         // if bits 4 and 7 are 1, this must be a signed/hw transfer
-        Some(SignedTransfer(signed_trans::SignedDataTransfer::parse_instruction(ins)))
+        ArmClass::SignedTransfer
     } else if op0 < 4 {
-        let data = data::DataProc::parse_instruction(ins);
-        let op = data.opcode as u8;
         // PSR instructions are Data Processing operations with TST, TEQ, CMP,
         // or CMN, without the S flag set
-        if !data.set_flags && op >= 8 && op <= 11 {
-            Some(PSRTransfer(psr::PSRTransfer::parse_instruction(ins)))
+        let opcode = (key >> 5) & 0xF;
+        let set_flags = op1 & 1 == 1;
+        if !set_flags && opcode >= 8 && opcode <= 11 {
+            ArmClass::PSRTransfer
         } else {
-            Some(DataProc(data))
+            ArmClass::DataProc
         }
-    } else if op0 >= 4 && op0 < 8 {
-        Some(SingleTransfer(single_trans::SingleDataTransfer::parse_instruction(ins)))
+    } else if op0 < 8 {
+        ArmClass::SingleTransfer
     } else if op0 == 8 || op0 == 9 {
-        Some(BlockTransfer(block_trans::BlockDataTransfer::parse_instruction(ins)))
+        ArmClass::BlockTransfer
     } else if op0 == 10 || op0 == 11 {
-        Some(Branch(branch::Branch::parse_instruction(ins)))
+        ArmClass::Branch
     } else if op0 == 15 {
-        Some(SWInterrupt(swi::SWInterrupt::parse_instruction(ins)))
+        ArmClass::SWInterrupt
     } else {
-        None
+        ArmClass::Undefined
     }
 }
 
-/// Decode a raw thumb instruction
-pub fn decode_thumb(ins: u16) -> Instruction {
-    // this intermediate function exists to be able to test that the correct
-    // THUMB format is identified
-    _decode_thumb(ins)(ins)
+/// Decode a raw ARM instruction
+pub fn decode_arm(ins: u32) -> Option<Instruction> {
+    Some(match ARM_CLASSES[arm_class_key(ins)] {
+        ArmClass::DataProc => DataProc(data::DataProc::parse_instruction(ins)),
+        ArmClass::PSRTransfer => PSRTransfer(psr::PSRTransfer::parse_instruction(ins)),
+        ArmClass::Multiply => Multiply(mul::Multiply::parse_instruction(ins)),
+        ArmClass::MultiplyLong => MultiplyLong(mul_long::MultiplyLong::parse_instruction(ins)),
+        ArmClass::SwapTransfer => SwapTransfer(swap::SingleDataSwap::parse_instruction(ins)),
+        ArmClass::SignedTransfer =>
+            SignedTransfer(signed_trans::SignedDataTransfer::parse_instruction(ins)),
+        ArmClass::SingleTransfer =>
+            SingleTransfer(single_trans::SingleDataTransfer::parse_instruction(ins)),
+        ArmClass::BlockTransfer =>
+            BlockTransfer(block_trans::BlockDataTransfer::parse_instruction(ins)),
+        ArmClass::Branch => Branch(branch::Branch::parse_instruction(ins)),
+        ArmClass::SWInterrupt => SWInterrupt(swi::SWInterrupt::parse_instruction(ins)),
+        ArmClass::BranchExOrPSR if (ins & 0x0FFFFFF0) == 0x012FFF10 =>
+            BranchEx(branch_ex::BranchAndExchange::parse_instruction(ins)),
+        ArmClass::BranchExOrPSR => PSRTransfer(psr::PSRTransfer::parse_instruction(ins)),
+        ArmClass::Undefined => return None,
+    })
+}
+
+/// Decode a THUMB instruction, or return None if it's undefined on the
+/// ARM7TDMI
+pub fn decode_thumb(ins: u16) -> Option<Instruction> {
+    thumb_format(ins).map(|format| format(ins))
+}
+
+/// Decodes a THUMB instruction of one format
+pub type ThumbFormat = fn(u16) -> Instruction;
+
+/// Return the function that decodes the given THUMB instruction's format, which
+/// identifies the format (e.g. for testing the decoder, or for profiling)
+pub fn thumb_format(ins: u16) -> Option<ThumbFormat> {
+    THUMB_FORMATS[(ins >> 8) as usize]
 }
 
-// NOTE: this doesn't check for invalid instructions - it only looks at the minimum
-// number of bits necessary to decide between valid THUMB formats
-fn _decode_thumb(ins: u16) -> (fn(u16) -> Instruction) {
+/// The format of every THUMB instruction, indexed by its top 8 bits, which are
+/// all that classify_thumb looks at
+const THUMB_FORMATS: [Option<ThumbFormat>; 256] = thumb_formats();
+
+const fn thumb_formats() -> [Option<ThumbFormat>; 256] {
+    let mut formats: [Option<ThumbFormat>; 256] = [None; 256];
+    let mut i = 0;
+    while i < 256 {
+        formats[i] = classify_thumb((i as u16) << 8);
+        i += 1;
+    }
+    formats
+}
+
+// NOTE: this only looks at the bits needed to tell the THUMB formats apart
+// (and the encodings that don't belong to any of them), not at whether the
+// fields of a format are valid
+const fn classify_thumb(ins: u16) -> Option<ThumbFormat> {
     // use binary on left to make it easier to compare to the reference doc
-    match (ins >> 12) & 0xF {
+    let format: ThumbFormat = match (ins >> 12) & 0xF {
         0b0000 => thumb::move_,
         0b0001 =>
-            if util::get_bit_hw(ins, 11)
+            if (ins >> 11) & 1 == 1
                 { thumb::add_sub } else
                 { thumb::move_ },
         0b0010 |
@@ -116,7 +202,7 @@ fn _decode_thumb(ins: u16) -> (fn(u16) -> Instruction) {
             }
         },
         0b0101 => {
-            if util::get_bit_hw(ins, 9)
+            if (ins >> 9) & 1 == 1
                 { thumb::signed_trans } else
                 { thumb::reg_offset_trans }
         },
@@ -125,21 +211,26 @@ fn _decode_thumb(ins: u16) -> (fn(u16) -> Instruction) {
         0b1000 => thumb::hw_trans,
         0b1001 => thumb::sp_rel_trans,
         0b1010 => thumb::load_addr,
-        0b1011 => {
-            if util::get_bit_hw(ins, 10)
-                { thumb::push_pop } else
-                { thumb::incr_sp }
+        0b1011 => match (ins >> 8) & 0xF {
+            0b0000 => thumb::incr_sp,
+            0b0100 | 0b0101 |
+            0b1100 | 0b1101 => thumb::push_pop,
+            // breakpoints and the other ARMv5+ additions
+            _ => return None,
         },
         0b1100 => thumb::block_trans,
-        0b1101 => {
-            if (ins >> 8) & 0xF == 0xF
-                { thumb::swi } else
-                { thumb::cond_branch }
+        0b1101 => match (ins >> 8) & 0xF {
+            0b1110 => return None,
+            0b1111 => thumb::swi,
+            _ => thumb::cond_branch,
         },
-        0b1110 => thumb::branch,
-        0b1111 => thumb::long_branch,
-        _ => panic!("should not get here")
-    }
+        0b1110 =>
+            if (ins >> 11) & 1 == 1
+                { return None } else
+                { thumb::branch },
+        _ => thumb::long_branch,
+    };
+    Some(format)
 }
 
 /// The possible instructions of the ARM instruction set
@@ -172,8 +263,8 @@ pub fn satisfies_cond(cpsr: &PSR, cond: u32) -> bool {
         CondField::PL => !cpsr.neg,
         CondField::VS => cpsr.overflow,
         CondField::VC => !cpsr.overflow,
-        CondField::HI => cpsr.carry && !cpsr.overflow,
-        CondField::LS => !cpsr.carry || cpsr.overflow,
+        CondField::HI => cpsr.carry && !cpsr.zero,
+        CondField::LS => !cpsr.carry || cpsr.zero,
         CondField::GE => cpsr.neg == cpsr.overflow,
         CondField::LT => cpsr.neg != cpsr.overflow,
         CondField::GT => !cpsr.zero && (cpsr.neg == cpsr.overflow),
@@ -210,6 +301,7 @@ pub enum CondField {
 mod test {
     mod decode_arm {
         use super::super::*;
+        use util;
 
         macro_rules! has_type {
             ($instr:expr, $instr_type: pat) => (
@@ -365,15 +457,92 @@ mod test {
         fn sw_interrupt() {
             has_type!(0xFF_123ABC, Instruction::SWInterrupt(_));
         }
+
+        /// The decoder from before the lookup table, which works through the
+        /// classes one at a time
+        fn reference_decode(ins: u32) -> Option<Instruction> {
+            let op0 = util::get_nibble(ins, 24);
+            let op1 = util::get_nibble(ins, 20);
+            let op2 = util::get_nibble(ins, 4);
+            if op0 == 0 && op1 < 4 && op2 == 0b1001 {
+                Some(Multiply(mul::Multiply::parse_instruction(ins)))
+            } else if op0 == 0 && op1 > 7 && op2 == 0b1001 {
+                Some(MultiplyLong(mul_long::MultiplyLong::parse_instruction(ins)))
+            } else if op0 == 1 && op2 == 9 {
+                Some(SwapTransfer(swap::SingleDataSwap::parse_instruction(ins)))
+            } else if (ins & 0x0FFFFFF0) == 0x012FFF10 {
+                Some(BranchEx(branch_ex::BranchAndExchange::parse_instruction(ins)))
+            } else if op0 < 2 && (op2 == 9 || op2 == 11 || op2 == 13 || op2 == 15) {
+                Some(SignedTransfer(signed_trans::SignedDataTransfer::parse_instruction(ins)))
+            } else if op0 < 4 {
+                let data = data::DataProc::parse_instruction(ins);
+                let op = data.opcode as u8;
+                if !data.set_flags && op >= 8 && op <= 11 {
+                    Some(PSRTransfer(psr::PSRTransfer::parse_instruction(ins)))
+                } else {
+                    Some(DataProc(data))
+                }
+            } else if op0 >= 4 && op0 < 8 {
+                Some(SingleTransfer(single_trans::SingleDataTransfer::parse_instruction(ins)))
+            } else if op0 == 8 || op0 == 9 {
+                Some(BlockTransfer(block_trans::BlockDataTransfer::parse_instruction(ins)))
+            } else if op0 == 10 || op0 == 11 {
+                Some(Branch(branch::Branch::parse_instruction(ins)))
+            } else if op0 == 15 {
+                Some(SWInterrupt(swi::SWInterrupt::parse_instruction(ins)))
+            } else {
+                None
+            }
+        }
+
+        fn assert_matches_reference(ins: u32) {
+            assert_eq!(format!("{:?}", decode_arm(ins)), format!("{:?}", reference_decode(ins)),
+                "decoded {:08X} differently", ins);
+        }
+
+        #[test]
+        fn matches_reference() {
+            // every key, with the bits that aren't part of it filled in a few
+            // different ways
+            let fillers = [0, 0xF00FFF0F, 0xA00A5A05, 0x50054A0A, 0xE00FFF00];
+            for key in 0..4096 {
+                let ins = (key & 0xFF0) << 16 | (key & 0xF) << 4;
+                for filler in fillers.iter() {
+                    assert_matches_reference(ins | filler);
+                }
+            }
+            // BX with each register, and MSRs that are one bit off it
+            for rn in 0..16 {
+                assert_matches_reference(0xE12FFF10 | rn);
+                assert_matches_reference(0xE12FFF10 ^ (0x100 << rn.min(11)));
+            }
+            // and a pseudo random sample
+            let mut x: u32 = 0x2545F491;
+            for _ in 0..100000 {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                assert_matches_reference(x);
+            }
+        }
     }
 
     mod decode_thumb {
         use super::super::*;
         use ::cpu::thumb::*;
+        use util;
+
+        type Format = fn(u16) -> Instruction;
+
+        /// Return the address of the format function an instruction is
+        /// decoded with, for comparing formats
+        fn format_of(ins: u16) -> Option<usize> {
+            thumb_format(ins).map(|format| format as usize)
+        }
 
         macro_rules! has_format {
             ($instr:expr, $thumb_format: ident) => (
-                assert!(_decode_thumb($instr) == $thumb_format))
+                assert_eq!(format_of($instr), Some($thumb_format as usize)))
         }
 
         #[test]
@@ -396,10 +565,142 @@ mod test {
             has_format!(0xB00A, incr_sp);
             has_format!(0xBD00, push_pop);
             has_format!(0xCEEA, block_trans);
-            has_format!(0xDE01, cond_branch);
+            has_format!(0xDD01, cond_branch);
             has_format!(0xDF01, swi);
             has_format!(0xE590, branch);
             has_format!(0xF3C7, long_branch);
+            assert_eq!(format_of(0xDE01), None);
+        }
+
+        /// The THUMB instruction formats, transcribed from the table in GBATEK
+        /// (THUMB Binary Opcode Format). Bits marked x can be anything, and the
+        /// first pattern an instruction matches is its format. Encodings that
+        /// are undefined on the ARM7TDMI have no format
+        const FORMATS: &[(&str, Option<Format>)] = &[
+            ("00011xxxxxxxxxxx", Some(add_sub)),
+            ("000xxxxxxxxxxxxx", Some(move_)),
+            ("001xxxxxxxxxxxxx", Some(data_imm)),
+            ("010000xxxxxxxxxx", Some(alu_op)),
+            ("010001xxxxxxxxxx", Some(hi_reg_bex)),
+            ("01001xxxxxxxxxxx", Some(pc_rel_load)),
+            ("0101xx0xxxxxxxxx", Some(reg_offset_trans)),
+            ("0101xx1xxxxxxxxx", Some(signed_trans)),
+            ("011xxxxxxxxxxxxx", Some(imm_offset_trans)),
+            ("1000xxxxxxxxxxxx", Some(hw_trans)),
+            ("1001xxxxxxxxxxxx", Some(sp_rel_trans)),
+            ("1010xxxxxxxxxxxx", Some(load_addr)),
+            ("10110000xxxxxxxx", Some(incr_sp)),
+            ("1011x10xxxxxxxxx", Some(push_pop)),
+            ("1011xxxxxxxxxxxx", None),
+            ("1100xxxxxxxxxxxx", Some(block_trans)),
+            ("11011110xxxxxxxx", None),
+            ("11011111xxxxxxxx", Some(swi)),
+            ("1101xxxxxxxxxxxx", Some(cond_branch)),
+            ("11100xxxxxxxxxxx", Some(branch)),
+            ("11101xxxxxxxxxxx", None),
+            ("1111xxxxxxxxxxxx", Some(long_branch)),
+        ];
+
+        fn matches(pattern: &str, ins: u16) -> bool {
+            pattern.chars().enumerate().all(|(i, bit)| {
+                let set = (ins >> (15 - i)) & 1 == 1;
+                match bit {
+                    '0' => !set,
+                    '1' => set,
+                    _ => true,
+                }
+            })
+        }
+
+        /// The format lookup from before the table, which works through the
+        /// bits of each instruction
+        fn reference_format(ins: u16) -> Option<Format> {
+            let format: Format = match (ins >> 12) & 0xF {
+                0b0000 => move_,
+                0b0001 => if util::get_bit_hw(ins, 11) { add_sub } else { move_ },
+                0b0010 | 0b0011 => data_imm,
+                0b0100 => match (ins >> 10) & 0b11 {
+                    0 => alu_op,
+                    1 => hi_reg_bex,
+                    _ => pc_rel_load,
+                },
+                0b0101 => if util::get_bit_hw(ins, 9) { signed_trans } else { reg_offset_trans },
+                0b0110 | 0b0111 => imm_offset_trans,
+                0b1000 => hw_trans,
+                0b1001 => sp_rel_trans,
+                0b1010 => load_addr,
+                0b1011 => match (ins >> 8) & 0xF {
+                    0b0000 => incr_sp,
+                    0b0100 | 0b0101 | 0b1100 | 0b1101 => push_pop,
+                    _ => return None,
+                },
+                0b1100 => block_trans,
+                0b1101 => match (ins >> 8) & 0xF {
+                    0b1110 => return None,
+                    0b1111 => swi,
+                    _ => cond_branch,
+                },
+                0b1110 => if util::get_bit_hw(ins, 11) { return None } else { branch },
+                _ => long_branch,
+            };
+            Some(format)
+        }
+
+        #[test]
+        fn matches_reference() {
+            for ins in 0..=0xFFFF {
+                assert_eq!(format_of(ins), reference_format(ins).map(|format| format as usize),
+                    "wrong format for {:016b}", ins);
+            }
+        }
+
+        #[test]
+        fn every_opcode() {
+            for ins in 0..=0xFFFF {
+                let expected = FORMATS.iter()
+                    .find(|&&(pattern, _)| matches(pattern, ins))
+                    .map(|&(_, format)| format.map(|format| format as usize))
+                    .unwrap();
+                assert_eq!(format_of(ins), expected, "wrong format for {:016b}", ins);
+            }
+        }
+    }
+    mod satisfies_cond {
+        use super::super::*;
+
+        /// For each condition, a bit for each combination of flags (NZCV as
+        /// a 4 bit index) that satisfies it
+        const TRUTH_TABLE: [(&str, u16); 15] = [
+            ("EQ", 0xF0F0),
+            ("NE", 0x0F0F),
+            ("CS", 0xCCCC),
+            ("CC", 0x3333),
+            ("MI", 0xFF00),
+            ("PL", 0x00FF),
+            ("VS", 0xAAAA),
+            ("VC", 0x5555),
+            ("HI", 0x0C0C),
+            ("LS", 0xF3F3),
+            ("GE", 0xAA55),
+            ("LT", 0x55AA),
+            ("GT", 0x0A05),
+            ("LE", 0xF5FA),
+            ("AL", 0xFFFF),
+        ];
+
+        #[test]
+        fn truth_table() {
+            for (cond, &(name, expected)) in TRUTH_TABLE.iter().enumerate() {
+                for flags in 0..16 {
+                    let mut cpsr = PSR::new();
+                    cpsr.neg = flags & 8 != 0;
+                    cpsr.zero = flags & 4 != 0;
+                    cpsr.carry = flags & 2 != 0;
+                    cpsr.overflow = flags & 1 != 0;
+                    assert_eq!(satisfies_cond(&cpsr, cond as u32), (expected >> flags) & 1 == 1,
+                        "{} with NZCV={:04b}", name, flags);
+                }
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,225 @@
+//! The soft reset combo: holding A+B+Select+Start restarts most games. Games
+//! implement it themselves, either by polling KEYINPUT or with a keypad
+//! interrupt set up to fire for the combo, and then call the BIOS's SoftReset.
+//! The keypad interrupt isn't emulated, so games that use it never see the
+//! combo, and some players expect the combo to work in every game anyway.
+//!
+//! The emulator can watch for the combo itself, checking the buttons once
+//! a frame. When the combo is pressed it either does what SoftReset does
+//! (see CPUWrapper::soft_reset), or passes it on to the game by raising the
+//! keypad interrupt, if the game has set one up for these buttons. The combo
+//! has to be released before it can reset again.
+
+use cpu::{CPU, CPUWrapper};
+use cpu::status_reg::PSR;
+use mem::io::addrs::{KEYCNT, KEYINPUT};
+
+/// A, B, Select and Start, as bits of KEYINPUT
+pub const COMBO: u16 = 0xF;
+/// set by the game before calling SoftReset to restart from EWRAM instead of
+/// the cartridge
+const RESET_FLAG: u32 = 0x3007FFA;
+/// SoftReset clears the top 0x200 bytes of IWRAM, which hold the BIOS's
+/// stacks and the interrupt handler pointer
+const CLEARED_START: u32 = 0x3007E00;
+const CLEARED_END: u32 = 0x3008000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComboAction {
+    /// the combo is just buttons, for the game to handle or not
+    Off,
+    /// pass the combo on to the game if it has a keypad interrupt for it,
+    /// and otherwise reset
+    Auto,
+    /// always reset, even if the game would handle the combo itself
+    Always,
+}
+
+impl ComboAction {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ComboAction::Off => "off",
+            ComboAction::Auto => "auto",
+            ComboAction::Always => "always",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ComboAction> {
+        match name {
+            "off" => Some(ComboAction::Off),
+            "auto" => Some(ComboAction::Auto),
+            "always" => Some(ComboAction::Always),
+            _ => None,
+        }
+    }
+}
+
+pub struct ResetCombo {
+    pub action: ComboAction,
+    /// whether the combo was held when the buttons were last checked
+    held: bool,
+    /// the number of times the combo has reset the game
+    pub resets: u32,
+}
+
+impl ResetCombo {
+    pub const fn new() -> ResetCombo {
+        ResetCombo {
+            action: ComboAction::Off,
+            held: false,
+            resets: 0,
+        }
+    }
+}
+
+impl CPU {
+    /// Do what the BIOS's SoftReset does: clear the top of IWRAM, reset the
+    /// stacks and the other registers, and switch to ARM and SYS mode. The
+    /// rest of memory and the IO registers are left alone. Games turn
+    /// interrupts off before calling SoftReset, since the handler pointer is
+    /// about to be cleared, so IME is cleared here too. Returns the address
+    /// to restart the game from: EWRAM if the byte at 0x3007FFA is set and
+    /// otherwise the cartridge
+    pub fn soft_reset_state(&mut self) -> u32 {
+        let entry = if self.mem.get_byte(RESET_FLAG) != 0 { 0x2000000 } else { 0x8000000 };
+        self.mem.set_halfword(0x4000208, 0);
+        for addr in (CLEARED_START..CLEARED_END).step_by(4) {
+            self.mem.set_word(addr, 0);
+        }
+        self.r = [0; 16];
+        self.r[13] = 0x3007F00;
+        self.r_fiq = [0; 7];
+        self.r_irq = [0x3007FA0, 0];
+        self.r_und = [0; 2];
+        self.r_abt = [0; 2];
+        self.r_svc = [0x3007FE0, 0];
+        self.cpsr = PSR::new_direct_boot();
+        self.spsr_svc = PSR::new();
+        self.spsr_irq = PSR::new();
+        entry
+    }
+}
+
+impl CPUWrapper {
+    /// Reset as SoftReset does (see CPU::soft_reset_state) and restart the
+    /// game
+    pub fn soft_reset(&mut self) {
+        let entry = self.cpu.soft_reset_state();
+        self.jump_to_entry(entry);
+    }
+
+    /// Check for the combo being pressed, and reset or raise the keypad
+    /// interrupt depending on the ComboAction. Called at the start of each
+    /// frame, after the frame script has set the buttons
+    pub fn check_reset_combo(&mut self) {
+        if self.reset_combo.action == ComboAction::Off {
+            return;
+        }
+        let pressed = !self.cpu.mem.raw.get_halfword(KEYINPUT) & COMBO == COMBO;
+        let newly_pressed = pressed && !self.reset_combo.held;
+        self.reset_combo.held = pressed;
+        if !newly_pressed {
+            return;
+        }
+        if self.reset_combo.action == ComboAction::Auto && self.game_handles_combo() {
+            self.cpu.mem.int.triggered.keypad = true;
+        } else {
+            self.reset_combo.resets += 1;
+            self.soft_reset();
+        }
+    }
+
+    /// Return true if the game has a keypad interrupt that fires when only
+    /// the combo is held: enabled in KEYCNT and IE, and needing all of
+    /// (rather than any of) the buttons in its mask, which are the combo's
+    fn game_handles_combo(&self) -> bool {
+        let keycnt = self.cpu.mem.raw.get_halfword(KEYCNT);
+        let irq = keycnt & 0x4000 != 0;
+        let all_of = keycnt & 0x8000 != 0;
+        irq && all_of && keycnt & 0x3FF == COMBO && self.cpu.mem.int.enabled.keypad
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::REFRESH;
+    use script::ScriptHandle;
+
+    /// A game at 0x8000000 that loops forever, with the combo held from the
+    /// first frame on
+    fn holding_combo() -> CPUWrapper {
+        let mut gba = CPUWrapper::new();
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&[0xFE, 0xFF, 0xFF, 0xEA]); // b 0x8000000
+        gba.cpu.mem.load_rom(rom);
+        gba.direct_boot_at(0x8000000);
+        gba.set_frame_script(Some(Box::new(|handle: &mut ScriptHandle| {
+            handle.set_buttons(COMBO);
+        })));
+        gba
+    }
+
+    fn run_frame(gba: &mut CPUWrapper) {
+        let start = gba.total_cycles;
+        while gba.total_cycles - start < REFRESH as u64 {
+            gba.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut gba = holding_combo();
+        gba.reset_combo.action = ComboAction::Always;
+        gba.cpu.mem.set_word(0x3007FFC, 0x3001234);
+        gba.cpu.mem.set_word(0x3000000, 0x11111111);
+        gba.cpu.mem.set_halfword(0x4000208, 1);
+        gba.cpu.r[4] = 4;
+        run_frame(&mut gba);
+        assert_eq!(gba.reset_combo.resets, 1);
+        assert_eq!(gba.cpu.mem.get_word(0x3007FFC), 0);
+        assert_eq!(gba.cpu.mem.get_word(0x3000000), 0x11111111);
+        assert!(!gba.cpu.mem.int.master_enabled);
+        assert_eq!(gba.cpu.r[4], 0);
+        assert_eq!(gba.cpu.r[13], 0x3007F00);
+
+        // not again until the combo is released
+        run_frame(&mut gba);
+        assert_eq!(gba.reset_combo.resets, 1);
+        gba.set_frame_script(None);
+        gba.check_reset_combo();
+        gba.cpu.mem.raw.io[0x130] = 0xFF;
+        gba.check_reset_combo();
+        assert!(!gba.reset_combo.held);
+
+        // restarting from EWRAM
+        gba.cpu.mem.set_byte(RESET_FLAG, 1);
+        gba.soft_reset();
+        assert_eq!(gba.cpu.r[15], 0x2000000);
+        assert_eq!(gba.cpu.mem.get_byte(RESET_FLAG), 0);
+    }
+
+    #[test]
+    fn game_handler() {
+        let mut gba = holding_combo();
+        gba.reset_combo.action = ComboAction::Auto;
+        // a keypad interrupt for all of A+B+Select+Start
+        gba.cpu.mem.set_halfword(0x4000132, 0xC00F);
+        gba.cpu.mem.set_halfword(0x4000200, 0x1000);
+        run_frame(&mut gba);
+        assert_eq!(gba.reset_combo.resets, 0);
+        assert!(gba.cpu.mem.int.triggered.keypad);
+
+        // any of the buttons isn't the combo
+        let mut gba = holding_combo();
+        gba.reset_combo.action = ComboAction::Auto;
+        gba.cpu.mem.set_halfword(0x4000132, 0x400F);
+        gba.cpu.mem.set_halfword(0x4000200, 0x1000);
+        run_frame(&mut gba);
+        assert_eq!(gba.reset_combo.resets, 1);
+
+        let mut gba = holding_combo();
+        run_frame(&mut gba);
+        assert_eq!(gba.reset_combo.resets, 0);
+    }
+}
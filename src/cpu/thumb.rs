@@ -198,6 +198,7 @@ pub fn pc_rel_load(raw: u16) -> Instruction {
         offset_up: true,
         byte: false,
         write_back: false,
+        force_user: false,
         load: true,
         rn: 15,
         rd,
@@ -214,6 +215,7 @@ pub fn reg_offset_trans(raw: u16) -> Instruction {
         offset_up: true,
         byte: util::get_bit_hw(raw, 10),
         write_back: false,
+        force_user: false,
         load: util::get_bit_hw(raw, 11),
         rn: (raw as usize >> 3) & 0b111,
         rd: raw as usize & 0b111,
@@ -258,6 +260,7 @@ pub fn imm_offset_trans(raw: u16) -> Instruction {
         offset_up: true,
         byte,
         write_back: false,
+        force_user: false,
         load: util::get_bit_hw(raw, 11),
         rn: (raw as usize >> 3) & 0b111,
         rd: raw as usize & 0b111,
@@ -295,6 +298,7 @@ pub fn sp_rel_trans(raw: u16) -> Instruction {
         offset_up: true,
         byte: false,
         write_back: false,
+        force_user: false,
         load: util::get_bit_hw(raw, 11),
         rn: 13,
         rd: (raw as usize >> 8) & 0b111,
@@ -389,7 +393,7 @@ pub fn cond_branch(raw: u16) -> Instruction {
 // TODO: this extra instruction probably isn't necessary if decode_thumb returns
 // an (Option<Cond>, Instruction) that gets passed to Decoded()
 #[derive(Clone, Debug)]
-pub struct CondBranch { pub cond: u16, offset: i16 }
+pub struct CondBranch { pub cond: u16, pub offset: i16 }
 
 // for ARM instructions the condition is checked while decoding but for THUMB
 // instructions they are checked during execution, since only one THUMB
@@ -403,7 +407,7 @@ impl CondBranch {
                 cpu.mem.access_time(cpu.r[15], true) +
                 cpu.mem.access_time(cpu.r[15] + 4, false)
         } else {
-            1
+            cpu.mem.access_time(cpu.r[15], false)
         }
     }
 }
@@ -455,7 +459,8 @@ impl LongBranch {
             }
             let upper = cpu.get_reg(15) as i64 + (offset as i32) as i64;
             cpu.set_reg(14, upper as u32);
-            0 // incur the cycle cost when the second half is run
+            // the whole BL is 3S + 1N: 1S here and the rest in the second half
+            cpu.mem.access_time(cpu.r[15], false)
         } else {
             let next_ins = (cpu.get_reg(15) - 2) | 1;
             let pc = cpu.get_reg(14).wrapping_add((self.offset as u32) << 1);
@@ -553,6 +558,87 @@ mod test {
         };
     }
 
+    /// The result and carry of shifting val by a register amount, following
+    /// the ARM shifter rules: only the bottom byte of the register is used, a
+    /// shift of 0 leaves the value and carry as they are, and shifts of 32 or
+    /// more shift out every bit
+    fn reference_shift(op: u16, val: u32, amount: u32, carry: bool) -> (u32, bool) {
+        let amount = amount & 0xFF;
+        let bit = |n: u32| (val >> n) & 1 == 1;
+        if amount == 0 {
+            return (val, carry);
+        }
+        match op {
+            0b0010 => match amount { // LSL
+                1...31 => (val << amount, bit(32 - amount)),
+                32 => (0, bit(0)),
+                _ => (0, false),
+            },
+            0b0011 => match amount { // LSR
+                1...31 => (val >> amount, bit(amount - 1)),
+                32 => (0, bit(31)),
+                _ => (0, false),
+            },
+            0b0100 => match amount { // ASR
+                1...31 => (((val as i32) >> amount) as u32, bit(amount - 1)),
+                _ => (if bit(31) { 0xFFFFFFFF } else { 0 }, bit(31)),
+            },
+            _ => match amount % 32 { // ROR
+                0 => (val, bit(31)),
+                n => (val.rotate_right(n), bit(n - 1)),
+            },
+        }
+    }
+
+    #[test]
+    fn shift_by_register() {
+        let values = [0, 1, 0x80000000, 0x80000001, 0xFFFFFFFF, 0x12345678, 0xEDCBA987];
+        let amounts = (0..300).chain([0x100, 0x11F, 0x120, 0xFFFFFF21].iter().cloned());
+        for amount in amounts {
+            for &val in values.iter() {
+                for &op in [0b0010, 0b0011, 0b0100, 0b0111].iter() {
+                    for &carry in [false, true].iter() {
+                        let mut cpu = CPU::new();
+                        cpu.set_reg(1, val);
+                        cpu.set_reg(2, amount);
+                        cpu.cpsr.carry = carry;
+                        cpu.cpsr.overflow = true;
+                        // op r1, r2
+                        match alu_op(0x4000 | (op << 6) | (2 << 3) | 1) {
+                            Instruction::DataProc(ins) => { ins.run(&mut cpu); },
+                            _ => panic!(),
+                        }
+                        let (result, carry_out) = reference_shift(op, val, amount, carry);
+                        let case = format!("op {:04b} of {:08X} by {}", op, val, amount);
+                        assert_eq!(cpu.get_reg(1), result, "{}", case);
+                        assert_eq!(cpu.cpsr.carry, carry_out, "{}", case);
+                        assert_eq!(cpu.cpsr.neg, result >> 31 == 1, "{}", case);
+                        assert_eq!(cpu.cpsr.zero, result == 0, "{}", case);
+                        assert!(cpu.cpsr.overflow, "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mul_flags() {
+        let mut cpu = CPU::new();
+        cpu.set_reg(0, 0x10000);
+        cpu.cpsr.carry = true;
+        cpu.cpsr.overflow = true;
+        // mul r0, r0: the product overflows to 0
+        match alu_op(0b010000_1101_000_000) {
+            Instruction::Multiply(ins) => { ins.run(&mut cpu); },
+            _ => panic!(),
+        }
+        assert_eq!(cpu.get_reg(0), 0);
+        assert!(cpu.cpsr.zero);
+        assert!(!cpu.cpsr.neg);
+        assert!(!cpu.cpsr.carry);
+        assert!(cpu.cpsr.overflow);
+    }
+
     #[test]
     fn test_hi_reg_bex() {
         match hi_reg_bex(0b010001_11_00_001_110) {
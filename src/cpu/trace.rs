@@ -0,0 +1,128 @@
+//! A small ring buffer of the most recently executed instructions. This is
+//! cheap enough to always keep around, and is used to build a crash report
+//! when the emulator panics so that users can file useful bug reports.
+
+use ::cpu::CPUWrapper;
+use ::cpu::status_reg::InstructionSet;
+use ::mem::io::addrs::INT_END;
+use std::fmt::Write;
+
+/// number of instructions kept in the trace
+pub const TRACE_LEN: usize = 32;
+
+/// Only the address and instruction set are recorded for each instruction; the
+/// raw opcode is read back from memory when the trace is displayed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u32,
+    pub isa: InstructionSet,
+}
+
+pub struct Trace {
+    entries: [Option<TraceEntry>; TRACE_LEN],
+    /// index of the next entry to write to
+    idx: usize,
+}
+
+impl Trace {
+    pub const fn new() -> Trace {
+        Trace {
+            entries: [None; TRACE_LEN],
+            idx: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        self.entries[self.idx] = Some(entry);
+        self.idx = (self.idx + 1) % TRACE_LEN;
+    }
+
+    /// Return the recorded entries from oldest to newest
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        (0..TRACE_LEN)
+            .filter_map(|i| self.entries[(self.idx + i) % TRACE_LEN])
+            .collect()
+    }
+}
+
+impl CPUWrapper {
+    /// Build a human readable description of the emulator state, used when an
+    /// internal error occurs. This should avoid anything that could panic
+    /// itself, since it is called after the state may have been corrupted
+    pub fn crash_report(&self, msg: &str) -> String {
+        let cpu = &self.cpu;
+        let mut report = String::new();
+        let _ = writeln!(report, "emulator crashed: {}", msg);
+        let _ = match self.symbols.describe(cpu.r[15]) {
+            Some(name) => writeln!(report, "PC: {:#010X} ({})", cpu.r[15], name),
+            None => writeln!(report, "PC: {:#010X}", cpu.r[15]),
+        };
+        let _ = writeln!(report, "CPSR: {:#010X} ({:?})", cpu.cpsr.to_u32(), cpu.cpsr.mode);
+        let _ = writeln!(report, "last instruction: {:?}", self.last_instruction);
+
+        let _ = writeln!(report, "registers:");
+        for (i, val) in cpu.r.iter().enumerate() {
+            let _ = writeln!(report, "  r{}: {:#010X}", i, val);
+        }
+
+        let _ = writeln!(report, "trace (oldest first):");
+        for entry in self.trace.entries() {
+            let _ = match entry.isa {
+                InstructionSet::ARM => writeln!(report, "  {:#010X}: {:08X}",
+                    entry.pc, cpu.mem.fetch_word(entry.pc)),
+                InstructionSet::THUMB => writeln!(report, "  {:#010X}: {:04X}",
+                    entry.pc, cpu.mem.fetch_halfword(entry.pc)),
+            };
+        }
+
+        // only dump the registers up to the end of the interrupt registers,
+        // skipping rows that are all zero to keep the report short
+        let _ = writeln!(report, "IO:");
+        let io_len = (INT_END + 1 - 0x4000000) as usize;
+        for (i, row) in cpu.mem.raw.io[..io_len].chunks(16).enumerate() {
+            if row.iter().all(|b| *b == 0) {
+                continue;
+            }
+            let _ = write!(report, "  {:#010X}:", 0x4000000 + i * 16);
+            for b in row {
+                let _ = write!(report, " {:02X}", b);
+            }
+            let _ = writeln!(report);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring() {
+        let mut trace = Trace::new();
+        assert_eq!(trace.entries(), vec![]);
+
+        for i in 0..(TRACE_LEN as u32 + 3) {
+            trace.push(TraceEntry { pc: i * 4, isa: InstructionSet::ARM });
+        }
+        let entries = trace.entries();
+        assert_eq!(entries.len(), TRACE_LEN);
+        assert_eq!(entries[0].pc, 12);
+        assert_eq!(entries[TRACE_LEN - 1].pc, (TRACE_LEN as u32 + 2) * 4);
+    }
+
+    #[test]
+    fn report() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.r[15] = 0x3000008;
+        gba.cpu.mem.set_word(0x3000000, 0xE3A00001);
+        gba.cpu.mem.set_byte(0x4000208, 1);
+        gba.trace.push(TraceEntry { pc: 0x3000000, isa: InstructionSet::ARM });
+
+        let report = gba.crash_report("oops");
+        assert!(report.contains("emulator crashed: oops"));
+        assert!(report.contains("PC: 0x03000008"));
+        assert!(report.contains("0x03000000: E3A00001"));
+        assert!(report.contains("0x04000200: 00 00 00 00 00 00 00 00 01"));
+    }
+}
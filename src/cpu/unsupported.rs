@@ -0,0 +1,120 @@
+//! Things a program can make the CPU do that aren't emulated: taking an
+//! exception other than a SWI or an IRQ, and instructions whose behaviour the
+//! ARM7TDMI manual leaves unpredictable (e.g. an LDM with R15 as its base).
+//! Games don't rely on any of them, so hitting one usually means the game
+//! has gone off the rails or is doing something deliberately unusual.
+//!
+//! What happens then is up to the Policy. By default the instruction is
+//! abandoned before it changes anything, and execute returns an
+//! Error::Unsupported naming the feature, so that the frontend can tell the
+//! player what the game needed instead of showing a crash report. When
+//! working on the emulator it's more useful to panic on the spot, with the
+//! handler still on the stack.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// an exception that's never raised on the GBA, named
+    Exception(&'static str),
+    /// an instruction encoding that the manual leaves unpredictable, with a
+    /// description of what made it so
+    Unpredictable(&'static str),
+}
+
+impl Feature {
+    /// Return the name of the variant, like Error::kind
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Feature::Exception(_) => "Exception",
+            Feature::Unpredictable(_) => "Unpredictable",
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Feature::Exception(name) => write!(f, "the {} exception isn't supported", name),
+            Feature::Unpredictable(what) => write!(f, "unpredictable instruction: {}", what),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// stop at the instruction, and return an error from execute
+    Halt,
+    /// panic as soon as the feature is reported
+    Panic,
+}
+
+pub struct Unsupported {
+    pub policy: Policy,
+    /// the first feature reported by the instruction that's executing
+    pending: Option<Feature>,
+}
+
+impl Unsupported {
+    pub const fn new() -> Unsupported {
+        Unsupported {
+            policy: Policy::Halt,
+            pending: None,
+        }
+    }
+
+    /// Called by an instruction that needs the given feature, which should
+    /// then return without doing anything else
+    pub fn report(&mut self, feature: Feature) {
+        if self.policy == Policy::Panic {
+            panic!("{}", feature);
+        }
+        if self.pending.is_none() {
+            self.pending = Some(feature);
+        }
+    }
+
+    /// Remove and return the feature reported since the last call, if any
+    pub fn take(&mut self) -> Option<Feature> {
+        self.pending.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::CPUWrapper;
+    use error::Error;
+
+    #[test]
+    fn halt() {
+        let mut gba = CPUWrapper::new();
+        // ldmia pc, {r0}
+        gba.cpu.mem.set_word(0x3000000, 0xE89F0001);
+        gba.direct_boot_at(0x3000000);
+        gba.cpu.r[0] = 7;
+        let err = Error::Unsupported {
+            pc: 0x3000000,
+            feature: Feature::Unpredictable("R15 as the base of an LDM or STM"),
+        };
+        while gba.cpu.r[15] < 0x3000008 {
+            gba.step().unwrap();
+        }
+        assert_eq!(gba.step(), Err(err.clone()));
+        assert_eq!(gba.cpu.r[0], 7);
+        // the emulator stays stopped at the instruction
+        assert_eq!(gba.step(), Err(err));
+    }
+
+    #[test]
+    #[should_panic(expected = "unpredictable instruction")]
+    fn panic() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.unsupported.policy = Policy::Panic;
+        gba.cpu.mem.set_word(0x3000000, 0xE89F0001);
+        gba.direct_boot_at(0x3000000);
+        for _ in 0..3 {
+            let _ = gba.step();
+        }
+    }
+}
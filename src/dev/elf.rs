@@ -0,0 +1,373 @@
+//! A loader for the ELF files produced by GBA toolchains (e.g. devkitARM),
+//! so that homebrew can be run without converting it to a ROM first. Only
+//! what's needed to run a program is read: the loadable segments, the entry
+//! point and the symbol table.
+
+use std::fmt;
+use mem::addrs::*;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const EM_ARM: u16 = 40;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// the (start, end) of each region of the address space a segment can be
+/// loaded into
+const REGIONS: [(u32, u32); 11] = [
+    (SYSROM_START, SYSROM_END),
+    (EWRAM_START, EWRAM_END),
+    (IWRAM_START, IWRAM_END),
+    (IO_START, IO_END),
+    (PAL_START, PAL_END),
+    (VRAM_START, VRAM_END),
+    (OAM_START, OAM_END),
+    (ROM_START, ROM_END),
+    (ROM_MIRROR1_START, ROM_MIRROR1_END),
+    (ROM_MIRROR2_START, ROM_MIRROR2_END),
+    (SRAM_START, SRAM_END),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// the data doesn't start with the ELF magic number
+    NotElf,
+    /// a valid ELF file that can't run on the GBA (e.g. 64 bit or big endian)
+    Unsupported(&'static str),
+    /// a header or segment points past the end of the file
+    Truncated,
+    /// a segment would be loaded to an address with no memory behind it
+    BadAddress(u32),
+    /// the segment at the given address runs past the end of the memory
+    /// region it's loaded into
+    TooLarge(u32),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported(reason) => write!(f, "unsupported ELF file: {}", reason),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::BadAddress(addr) =>
+                write!(f, "ELF segment at unmapped address {:#010X}", addr),
+            ElfError::TooLarge(addr) =>
+                write!(f, "ELF segment at {:#010X} is too large for its memory region", addr),
+        }
+    }
+}
+
+/// Data to be copied to memory before the program starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// the load (physical) address. Sections that run from RAM are loaded to
+    /// ROM and copied over by the program's startup code
+    pub addr: u32,
+    /// the contents, including any zero filled part at the end
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// for THUMB functions this has the lowest bit cleared, unlike in the
+    /// ELF file
+    pub addr: u32,
+    /// the size in bytes, or 0 if unknown (e.g. for labels in assembly)
+    pub size: u32,
+}
+
+/// Function and variable names from a program's symbol table, used to make
+/// addresses readable
+pub struct Symbols {
+    /// sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub const fn new() -> Symbols {
+        Symbols { symbols: Vec::new() }
+    }
+
+    pub fn from_vec(mut symbols: Vec<Symbol>) -> Symbols {
+        symbols.sort_by_key(|sym| sym.addr);
+        Symbols { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Return the symbol containing the given address, and the offset of the
+    /// address into it. Symbols with an unknown size are assumed to extend
+    /// up to the next symbol
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |sym| sym.addr) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let sym = &self.symbols[idx];
+        let offset = addr - sym.addr;
+        if sym.size == 0 || offset < sym.size {
+            Some((sym, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Describe an address as a symbol and offset, e.g. "main+0x1A"
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(sym, offset)| if offset == 0 {
+            sym.name.clone()
+        } else {
+            format!("{}+{:#X}", sym.name, offset)
+        })
+    }
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    Ok(read_u16(data, offset)? as u32 | (read_u16(data, offset + 2)? as u32) << 16)
+}
+
+/// Return len bytes of the file from offset. Offsets can be anything up to
+/// 4GB, which overflows usize on wasm
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    data.get(offset..end).ok_or(ElfError::Truncated)
+}
+
+/// Return the given entry in a table of headers
+fn header(data: &[u8], table: usize, i: usize, entry_size: usize) -> Result<&[u8], ElfError> {
+    let offset = i.checked_mul(entry_size)
+        .and_then(|offset| offset.checked_add(table))
+        .ok_or(ElfError::Truncated)?;
+    slice(data, offset, entry_size)
+}
+
+/// Make sure a segment of the given size fits in the memory region at addr
+fn check_segment(addr: u32, size: u32) -> Result<(), ElfError> {
+    let &(_, end) = REGIONS.iter()
+        .find(|&&(start, end)| start <= addr && addr <= end)
+        .ok_or(ElfError::BadAddress(addr))?;
+    if size - 1 > end - addr {
+        return Err(ElfError::TooLarge(addr));
+    }
+    Ok(())
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Elf, ElfError> {
+        if data.get(0..4) != Some(b"\x7FELF") {
+            return Err(ElfError::NotElf);
+        }
+        if data.get(4) != Some(&1) {
+            return Err(ElfError::Unsupported("not 32 bit"));
+        }
+        if data.get(5) != Some(&1) {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if read_u16(data, 18)? != EM_ARM {
+            return Err(ElfError::Unsupported("not an ARM executable"));
+        }
+        let entry = read_u32(data, 24)?;
+        let phoff = read_u32(data, 28)? as usize;
+        let shoff = read_u32(data, 32)? as usize;
+        let phentsize = read_u16(data, 42)? as usize;
+        let phnum = read_u16(data, 44)? as usize;
+        let shentsize = read_u16(data, 46)? as usize;
+        let shnum = read_u16(data, 48)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let program = header(data, phoff, i, phentsize)?;
+            if read_u32(program, 0)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(program, 4)? as usize;
+            let addr = read_u32(program, 12)?;
+            let file_size = read_u32(program, 16)?;
+            let mem_size = read_u32(program, 20)?;
+            if mem_size == 0 {
+                continue;
+            }
+            let size = mem_size.max(file_size);
+            check_segment(addr, size)?;
+            let mut contents = slice(data, offset, file_size as usize)?.to_vec();
+            contents.resize(size as usize, 0);
+            segments.push(Segment { addr, data: contents });
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let section = header(data, shoff, i, shentsize)?;
+            if read_u32(section, 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let table = slice(data,
+                read_u32(section, 16)? as usize,
+                read_u32(section, 20)? as usize)?;
+            // the linked section holds the symbol names
+            let strtab_header = header(data, shoff, read_u32(section, 24)? as usize, shentsize)?;
+            let strtab = slice(data,
+                read_u32(strtab_header, 16)? as usize,
+                read_u32(strtab_header, 20)? as usize)?;
+            for entry in table.chunks(16).filter(|entry| entry.len() == 16) {
+                let kind = entry[12] & 0xF;
+                let section = read_u16(entry, 14)?;
+                if (kind != STT_FUNC && kind != STT_OBJECT) || section == 0 {
+                    continue;
+                }
+                let name_start = read_u32(entry, 0)? as usize;
+                let name = match strtab.get(name_start..) {
+                    Some(rest) => {
+                        let len = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+                        String::from_utf8_lossy(&rest[..len]).into_owned()
+                    },
+                    None => return Err(ElfError::Truncated),
+                };
+                let mut addr = read_u32(entry, 4)?;
+                if kind == STT_FUNC {
+                    addr &= !1;
+                }
+                symbols.push(Symbol { name, addr, size: read_u32(entry, 8)? });
+            }
+        }
+
+        Ok(Elf { entry, segments, symbols })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn push_u16(out: &mut Vec<u8>, val: u16) {
+        out.extend_from_slice(&[val as u8, (val >> 8) as u8]);
+    }
+
+    fn push_u32(out: &mut Vec<u8>, val: u32) {
+        push_u16(out, val as u16);
+        push_u16(out, (val >> 16) as u16);
+    }
+
+    /// Build a minimal ELF file with a segment for each of the given
+    /// (address, contents, size in memory), and a FUNC symbol for each of the
+    /// given (name, address, size)
+    pub fn build_elf(entry: u32, segments: &[(u32, &[u8], u32)],
+                     symbols: &[(&str, u32, u32)]) -> Vec<u8> {
+        let phoff = 52;
+        let data_start = phoff + 32 * segments.len();
+        let data_len: usize = segments.iter().map(|seg| seg.1.len()).sum();
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for &(name, addr, size) in symbols.iter() {
+            push_u32(&mut symtab, strtab.len() as u32);
+            push_u32(&mut symtab, addr);
+            push_u32(&mut symtab, size);
+            symtab.extend_from_slice(&[0x10 | STT_FUNC, 0]);
+            push_u16(&mut symtab, 1);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let symtab_start = data_start + data_len;
+        let strtab_start = symtab_start + symtab.len();
+        let shoff = strtab_start + strtab.len();
+
+        let mut out = b"\x7FELF\x01\x01\x01".to_vec();
+        out.resize(16, 0);
+        push_u16(&mut out, 2); // executable
+        push_u16(&mut out, EM_ARM);
+        push_u32(&mut out, 1);
+        push_u32(&mut out, entry);
+        push_u32(&mut out, phoff as u32);
+        push_u32(&mut out, shoff as u32);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, 52);
+        push_u16(&mut out, 32);
+        push_u16(&mut out, segments.len() as u16);
+        push_u16(&mut out, 40);
+        push_u16(&mut out, 3);
+        push_u16(&mut out, 0);
+
+        let mut offset = data_start;
+        for &(addr, contents, mem_size) in segments.iter() {
+            for val in [PT_LOAD, offset as u32, addr, addr, contents.len() as u32,
+                        mem_size, 7, 4].iter() {
+                push_u32(&mut out, *val);
+            }
+            offset += contents.len();
+        }
+        for &(_, contents, _) in segments.iter() {
+            out.extend_from_slice(contents);
+        }
+        out.extend_from_slice(&symtab);
+        out.extend_from_slice(&strtab);
+
+        // a null section, the symbol table, and the string table
+        out.extend_from_slice(&[0; 40]);
+        for val in [0, SHT_SYMTAB, 0, 0, symtab_start as u32, symtab.len() as u32,
+                    2, 0, 4, 16].iter() {
+            push_u32(&mut out, *val);
+        }
+        for val in [0, 3, 0, 0, strtab_start as u32, strtab.len() as u32,
+                    0, 0, 1, 0].iter() {
+            push_u32(&mut out, *val);
+        }
+        out
+    }
+
+    #[test]
+    fn parse() {
+        let data = build_elf(0x8000000,
+            &[(0x8000000, &[1, 2, 3, 4], 4), (0x3000000, &[5], 8)],
+            &[("main", 0x8000101, 0x20), ("_start", 0x8000000, 0)]);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry, 0x8000000);
+        assert_eq!(elf.segments, vec![
+            Segment { addr: 0x8000000, data: vec![1, 2, 3, 4] },
+            Segment { addr: 0x3000000, data: vec![5, 0, 0, 0, 0, 0, 0, 0] },
+        ]);
+
+        let symbols = Symbols::from_vec(elf.symbols);
+        assert_eq!(symbols.find("main").unwrap().addr, 0x8000100);
+        assert_eq!(symbols.describe(0x8000000), Some("_start".to_string()));
+        assert_eq!(symbols.describe(0x80000F0), Some("_start+0xF0".to_string()));
+        assert_eq!(symbols.describe(0x800011A), Some("main+0x1A".to_string()));
+        assert_eq!(symbols.describe(0x8000120), None);
+        assert_eq!(symbols.describe(0x3000000), None);
+
+        assert_eq!(Elf::parse(b"MZ").err(), Some(ElfError::NotElf));
+        assert_eq!(Elf::parse(&data[..60]).err(), Some(ElfError::Truncated));
+
+        // segments have to fit in the region they're loaded into, however
+        // large they say they are
+        let data = build_elf(0x3000000, &[(0x3007FF0, &[1], 0x10)], &[]);
+        assert!(Elf::parse(&data).is_ok());
+        let data = build_elf(0x3000000, &[(0x3007FF0, &[1], 0x11)], &[]);
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::TooLarge(0x3007FF0)));
+        let data = build_elf(0x3000000, &[(0x3000000, &[1], 0xFFFFFFFF)], &[]);
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::TooLarge(0x3000000)));
+        let data = build_elf(0x3000000, &[(0x1000000, &[1], 1)], &[]);
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadAddress(0x1000000)));
+        // and offsets past the end of the file don't wrap around
+        let mut data = build_elf(0x8000000, &[(0x8000000, &[1], 1)], &[]);
+        data[56..60].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::Truncated));
+    }
+}
@@ -0,0 +1,118 @@
+//! Tools for homebrew developers. dev_boot() runs a freshly built ELF file
+//! directly, with the debug output channel and strict memory checks turned
+//! on, so that a program can be rebuilt and rerun in the browser without
+//! converting it to a ROM or needing a BIOS.
+
+pub mod elf;
+
+use cpu::CPUWrapper;
+use mem::addrs::{ROM_START, ROM_END};
+use self::elf::{Elf, ElfError, Symbols};
+
+impl CPUWrapper {
+    /// Load the given ELF file and reset the emulator to start at its entry
+    /// point, as if booted directly from the cartridge. Segments addressed to
+    /// the cartridge replace the loaded ROM, and the rest are copied into
+    /// memory
+    pub fn dev_boot(&mut self, data: &[u8]) -> Result<(), ElfError> {
+        let elf = Elf::parse(data)?;
+
+        let mut rom = Vec::new();
+        for segment in elf.segments.iter() {
+            let end = segment.addr as u64 + segment.data.len() as u64;
+            if segment.addr < ROM_START || end > ROM_END as u64 + 1 {
+                if !self.cpu.mem.is_mapped(segment.addr) || self.cpu.mem.is_rom(segment.addr) {
+                    return Err(ElfError::BadAddress(segment.addr));
+                }
+                continue;
+            }
+            let start = (segment.addr - ROM_START) as usize;
+            if rom.len() < start + segment.data.len() {
+                rom.resize(start + segment.data.len(), 0);
+            }
+            rom[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+
+        self.cpu.mem.clear();
+        if !rom.is_empty() {
+            self.cpu.mem.load_rom(rom);
+        }
+        for segment in elf.segments.iter() {
+            if self.cpu.mem.is_rom(segment.addr) {
+                continue;
+            }
+            for (i, byte) in segment.data.iter().enumerate() {
+                self.cpu.mem.set_byte(segment.addr + i as u32, *byte);
+            }
+        }
+
+        self.symbols = Symbols::from_vec(elf.symbols);
+        self.cpu.mem.debug.available = true;
+        self.cpu.mem.strict = true;
+        self.direct_boot_at(elf.entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::elf::test::build_elf;
+    use cpu::status_reg::InstructionSet;
+    use error::{AccessKind, Error};
+
+    #[test]
+    fn dev_boot() {
+        // prints "hi" through the debug channel, then writes to ROM
+        let code: [u32; 14] = [
+            0xE59F1024, // ldr r1, =0x4FFF600
+            0xE2813C01, // add r3, r1, #0x100
+            0xE59F2020, // ldr r2, =0xC0DE
+            0xE1C328B0, // strh r2, [r3, #0x80] (DEBUG_ENABLE)
+            0xE59F201C, // ldr r2, ="hi"
+            0xE5812000, // str r2, [r1]
+            0xE3A02F41, // mov r2, #0x104
+            0xE1C320B0, // strh r2, [r3] (DEBUG_FLAGS)
+            0xE3A01302, // mov r1, #0x8000000
+            0xE5811000, // str r1, [r1]
+            0xEAFFFFFE, // b .
+            0x4FFF600,
+            0xC0DE,
+            0x6968, // "hi"
+        ];
+        let mut bytes = Vec::new();
+        for ins in code.iter() {
+            for i in 0..4 {
+                bytes.push((ins >> (i * 8)) as u8);
+            }
+        }
+        let data = build_elf(0x8000000,
+            &[(0x8000000, &bytes, bytes.len() as u32), (0x3000000, &[7], 4)],
+            &[("_start", 0x8000000, 0)]);
+
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_word(0x2000000, 1);
+        gba.dev_boot(&data).unwrap();
+        assert_eq!(gba.cpu.r[15], 0x8000000);
+        assert_eq!(gba.cpu.cpsr.isa, InstructionSet::ARM);
+        assert_eq!(gba.cpu.mem.get_word(0x3000000), 7);
+        assert_eq!(gba.cpu.mem.get_word(0x2000000), 0);
+        assert_eq!(gba.symbols.describe(0x8000008), Some("_start+0x8".to_string()));
+
+        let mut result = Ok(false);
+        for _ in 0..20 {
+            result = gba.step();
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(Error::UnmappedAccess { addr: 0x8000000, kind: AccessKind::Write }));
+        let messages = gba.cpu.mem.debug.take_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "hi");
+
+        assert_eq!(gba.dev_boot(b"\x7FELF\x02").err(), Some(ElfError::Unsupported("not 32 bit")));
+        let data = build_elf(0x8000000, &[(0x1000000, &[0], 1)], &[]);
+        assert_eq!(gba.dev_boot(&data).err(), Some(ElfError::BadAddress(0x1000000)));
+    }
+}
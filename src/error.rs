@@ -0,0 +1,83 @@
+//! Errors caused by the emulated program doing something that we can't (or
+//! don't yet) emulate. Bugs in the emulator itself still panic, and are caught
+//! at the wasm boundary to produce a crash report.
+
+use std;
+use std::fmt;
+use cpu::unsupported::Feature;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// tried to execute an instruction that couldn't be decoded
+    InvalidOpcode { pc: u32, raw: u32 },
+    /// tried to access memory that isn't backed by anything
+    UnmappedAccess { addr: u32, kind: AccessKind },
+    /// the mode bits of the CPSR were set to a value that doesn't correspond
+    /// to any CPU mode
+    InvalidPsrMode { bits: u32 },
+    /// tried to run code from the cartridge before a ROM was uploaded
+    RomNotLoaded,
+    /// the instruction at pc needs something that isn't emulated, see
+    /// cpu::unsupported
+    Unsupported { pc: u32, feature: Feature },
+}
+
+impl Error {
+    /// Return the name of the variant, for callers that want to match on the
+    /// type of error without parsing the message
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::InvalidOpcode { .. } => "InvalidOpcode",
+            Error::UnmappedAccess { .. } => "UnmappedAccess",
+            Error::InvalidPsrMode { .. } => "InvalidPsrMode",
+            Error::RomNotLoaded => "RomNotLoaded",
+            Error::Unsupported { .. } => "Unsupported",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidOpcode { pc, raw } =>
+                write!(f, "invalid opcode {:#010X} at {:#010X}", raw, pc),
+            Error::UnmappedAccess { addr, kind } =>
+                write!(f, "{:?} from unmapped address {:#010X}", kind, addr),
+            Error::InvalidPsrMode { bits } =>
+                write!(f, "invalid CPSR mode {:#07b}", bits),
+            Error::RomNotLoaded => write!(f, "no ROM has been loaded"),
+            Error::Unsupported { pc, feature } => write!(f, "{} at {:#010X}", feature, pc),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Error::InvalidOpcode { pc: 0x8000000, raw: 0xE7F000F0 }.to_string(),
+            "invalid opcode 0xE7F000F0 at 0x08000000");
+        assert_eq!(
+            Error::UnmappedAccess { addr: 0x1000000, kind: AccessKind::Fetch }.to_string(),
+            "Fetch from unmapped address 0x01000000");
+        assert_eq!(Error::InvalidPsrMode { bits: 0 }.to_string(), "invalid CPSR mode 0b00000");
+        assert_eq!(Error::RomNotLoaded.kind(), "RomNotLoaded");
+        assert_eq!(
+            Error::Unsupported { pc: 0x8000000, feature: Feature::Exception("FIQ") }.to_string(),
+            "the FIQ exception isn't supported at 0x08000000");
+    }
+}
@@ -0,0 +1,86 @@
+//! Exports decoded graphics from memory in formats that can be opened outside
+//! of the emulator, e.g. for artists and modders who want to see a game's
+//! sprites without going through a tile viewer.
+
+pub mod png;
+pub mod zip;
+
+use mem::Memory;
+use mem::oam::{Sprite, SpriteType};
+
+impl Memory {
+    /// Return the sprite as rows of RGBA pixels, using its current palette and
+    /// flips. Transparent pixels have an alpha of 0. Affine sprites are
+    /// exported without their transformation
+    pub fn sprite_rgba(&self, sprite: &Sprite) -> Vec<u8> {
+        let (width, height) = (sprite.width as u32, sprite.height as u32);
+        let flip = sprite.mode == SpriteType::Normal;
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for dy in 0..height {
+            for dx in 0..width {
+                let x = if flip && sprite.hflip { width - 1 - dx } else { dx };
+                let y = if flip && sprite.vflip { height - 1 - dy } else { dy };
+                match self.sprite_texel(sprite, x, y) {
+                    Some(idx) => {
+                        let color = self.palette.sprite[idx % 256];
+                        pixels.extend_from_slice(&[
+                            (color >> 16) as u8,
+                            (color >> 8) as u8,
+                            color as u8,
+                            0xFF,
+                        ]);
+                    },
+                    None => pixels.extend_from_slice(&[0, 0, 0, 0]),
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Return a zip archive containing a PNG for each sprite in OAM. Hidden
+    /// sprites, and sprites that are entirely transparent, are skipped
+    pub fn export_sprites(&self) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new();
+        for (i, sprite) in self.sprites.sprites.iter().enumerate() {
+            if !sprite.mode.is_visible() {
+                continue;
+            }
+            let pixels = self.sprite_rgba(sprite);
+            if pixels.chunks(4).all(|pixel| pixel[3] == 0) {
+                continue;
+            }
+            let image = png::encode_rgba(sprite.width as u32, sprite.height as u32, &pixels);
+            zip.add_file(&format!("sprite_{:03}.png", i), &image);
+        }
+        zip.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sprites() {
+        let mut mem = Memory::new();
+        // sprite 1 is an 8x8 4bpp sprite using palette 1 and flipped
+        // horizontally, with a single opaque pixel in the top left of its tile
+        mem.set_halfword(0x7000008, 0);
+        mem.set_halfword(0x700000A, 1 << 12);
+        mem.set_halfword(0x700000C, (1 << 12) | 1);
+        mem.set_byte(0x6010020, 0x02);
+        mem.set_halfword(0x5000224, 0x001F);
+
+        let pixels = mem.sprite_rgba(&mem.sprites.sprites[1]);
+        assert_eq!(pixels.len(), 8 * 8 * 4);
+        assert_eq!(&pixels[28..32], &[0xF8, 0, 0, 0xFF]);
+        assert_eq!(&pixels[0..4], &[0, 0, 0, 0]);
+
+        mem.set_halfword(0x7000010, 0x0200); // sprite 2 is hidden
+        let zip = mem.export_sprites();
+        // sprites 0 and 2+ are transparent or hidden
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(&end[10..12], &[1, 0]);
+        assert_eq!(&zip[30..44], b"sprite_001.png");
+    }
+}
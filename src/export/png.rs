@@ -0,0 +1,93 @@
+//! A minimal PNG encoder for RGBA images. The image data is stored without
+//! compression, which keeps the encoder small at the cost of larger files.
+
+use util::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// the largest block that can be stored uncompressed in a zlib stream
+const MAX_BLOCK: usize = 0xFFFF;
+
+/// Encode an image given as rows of RGBA pixels (4 bytes per pixel)
+pub fn encode_rgba(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&be32(width));
+    header.extend_from_slice(&be32(height));
+    // 8 bit depth, RGBA, default compression/filter, no interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header);
+
+    // each row starts with the filter type, which is always 0 (none)
+    let stride = width as usize * 4;
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in pixels.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&be32(data.len() as u32));
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&be32(crc));
+}
+
+/// Wrap the data in a zlib stream made of uncompressed blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = if blocks.peek().is_none() { 1 } else { 0 };
+        let len = block.len() as u16;
+        out.push(last);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8]);
+        out.extend_from_slice(&[!len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&be32(adler32(data)));
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn be32(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        let png = encode_rgba(1, 1, &[0x12, 0x34, 0x56, 0xFF]);
+        assert_eq!(&png[..8], &SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        // IDAT contains the zlib header, a single stored block with the
+        // filter byte and the pixel, and the checksum
+        let idat = &png[33..];
+        assert_eq!(&idat[..4], &[0, 0, 0, 16]);
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..20], &[0x78, 0x01, 1, 5, 0, 0xFA, 0xFF, 0, 0x12, 0x34, 0x56, 0xFF]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
@@ -0,0 +1,120 @@
+//! Writes zip archives with uncompressed entries, so that multiple exported
+//! files can be handed to JS as a single download.
+
+use util::crc32;
+
+/// the DOS date for 1980-01-01, since zip can't represent earlier dates
+const DOS_DATE: u16 = 0x21;
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+pub struct ZipWriter {
+    out: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter {
+            out: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, data: &[u8]) {
+        let entry = Entry {
+            name: name.to_string(),
+            crc: crc32(data),
+            size: data.len() as u32,
+            offset: self.out.len() as u32,
+        };
+        put32(&mut self.out, 0x04034B50);
+        put16(&mut self.out, 20); // version needed to extract
+        write_common(&mut self.out, &entry);
+        put16(&mut self.out, 0); // extra field length
+        self.out.extend_from_slice(name.as_bytes());
+        self.out.extend_from_slice(data);
+        self.entries.push(entry);
+    }
+
+    /// Write the central directory and return the archive
+    pub fn finish(mut self) -> Vec<u8> {
+        let start = self.out.len() as u32;
+        for entry in self.entries.iter() {
+            put32(&mut self.out, 0x02014B50);
+            put16(&mut self.out, 20); // version made by
+            put16(&mut self.out, 20); // version needed to extract
+            write_common(&mut self.out, entry);
+            put16(&mut self.out, 0); // extra field length
+            put16(&mut self.out, 0); // comment length
+            put16(&mut self.out, 0); // disk number
+            put16(&mut self.out, 0); // internal attributes
+            put32(&mut self.out, 0); // external attributes
+            put32(&mut self.out, entry.offset);
+            self.out.extend_from_slice(entry.name.as_bytes());
+        }
+        let size = self.out.len() as u32 - start;
+        let count = self.entries.len() as u16;
+        put32(&mut self.out, 0x06054B50);
+        put16(&mut self.out, 0); // this disk
+        put16(&mut self.out, 0); // disk with the central directory
+        put16(&mut self.out, count);
+        put16(&mut self.out, count);
+        put32(&mut self.out, size);
+        put32(&mut self.out, start);
+        put16(&mut self.out, 0); // comment length
+        self.out
+    }
+}
+
+/// Write the fields shared by the local and central headers, from the flags
+/// up to the file name length
+fn write_common(out: &mut Vec<u8>, entry: &Entry) {
+    put16(out, 0); // flags
+    put16(out, 0); // stored (no compression)
+    put16(out, 0); // time
+    put16(out, DOS_DATE);
+    put32(out, entry.crc);
+    put32(out, entry.size); // compressed size
+    put32(out, entry.size); // uncompressed size
+    put16(out, entry.name.len() as u16);
+}
+
+fn put16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&[val as u8, (val >> 8) as u8]);
+}
+
+fn put32(out: &mut Vec<u8>, val: u32) {
+    put16(out, val as u16);
+    put16(out, (val >> 16) as u16);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn archive() {
+        let mut zip = ZipWriter::new();
+        zip.add_file("a.txt", b"hello");
+        zip.add_file("b.txt", b"");
+        let data = zip.finish();
+
+        assert_eq!(&data[..4], b"PK\x03\x04");
+        assert_eq!(&data[30..35], b"a.txt");
+        assert_eq!(&data[35..40], b"hello");
+        // the second local header starts right after the first file
+        assert_eq!(&data[40..44], b"PK\x03\x04");
+
+        let end = &data[data.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(&end[10..12], &[2, 0]);
+        let start = end[16] as usize | (end[17] as usize) << 8;
+        assert_eq!(&data[start..start + 4], b"PK\x01\x02");
+    }
+}
@@ -11,7 +11,19 @@ extern crate console_error_panic_hook;
 pub use wasm::*;
 pub use wasm::GBA;
 
+pub mod compat;
+pub mod config;
 pub mod cpu;
+pub mod dev;
+pub mod error;
+#[cfg(feature = "render")]
+pub mod export;
+pub mod link;
 pub mod mem;
+pub mod patch;
+pub mod savestate;
+pub mod script;
+pub mod self_test;
+pub mod time;
 pub mod util;
 pub mod wasm;
@@ -0,0 +1,90 @@
+//! Emulates a link cable between two GBAs running in the same module, using
+//! the serial port in multiplayer mode. The two cores are run in lockstep (the
+//! one that is behind in cycles is always stepped next) so that the child sees
+//! the parent's transfers at the right time. When the parent's transfer
+//! finishes, the values in each player's SIOMLT_SEND are exchanged.
+
+pub mod network;
+
+use cpu::CPUWrapper;
+use error::Result;
+
+/// Connect the two GBAs with a link cable. The parent is player 0, and is the
+/// only one that can start transfers
+pub fn connect(parent: &mut CPUWrapper, child: &mut CPUWrapper) {
+    parent.cpu.mem.set_link(true, 0);
+    child.cpu.mem.set_link(true, 1);
+}
+
+pub fn disconnect(parent: &mut CPUWrapper, child: &mut CPUWrapper) {
+    parent.cpu.mem.set_link(false, 0);
+    child.cpu.mem.set_link(false, 0);
+}
+
+/// Run both GBAs until the parent starts a new frame
+pub fn frame(parent: &mut CPUWrapper, child: &mut CPUWrapper) -> Result<()> {
+    loop {
+        if child.total_cycles < parent.total_cycles {
+            child.step()?;
+        } else if parent.step()? {
+            exchange(parent, child);
+            return Ok(());
+        }
+        exchange(parent, child);
+    }
+}
+
+/// Complete the parent's transfer if it has finished
+fn exchange(parent: &mut CPUWrapper, child: &mut CPUWrapper) {
+    if !parent.cpu.mem.serial.transfer_done {
+        return;
+    }
+    let data = [
+        parent.cpu.mem.multi_send(),
+        child.cpu.mem.multi_send(),
+        0xFFFF,
+        0xFFFF,
+    ];
+    parent.cpu.mem.finish_multiplayer_transfer(data);
+    child.cpu.mem.finish_multiplayer_transfer(data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Point both GBAs at an infinite loop in IWRAM, and put them in
+    /// multiplayer mode with serial interrupts enabled
+    fn setup(gba: &mut CPUWrapper, send: u32) {
+        gba.cpu.mem.set_word(0x3000000, 0xEAFFFFFE); // b 0x3000000
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.mem.set_halfword(0x4000128, 0x6003);
+        gba.cpu.mem.set_halfword(0x400012A, send);
+    }
+
+    #[test]
+    fn exchange_data() {
+        let mut parent = CPUWrapper::new();
+        let mut child = CPUWrapper::new();
+        setup(&mut parent, 0x1111);
+        setup(&mut child, 0x2222);
+        connect(&mut parent, &mut child);
+        assert_eq!(child.cpu.mem.get_halfword(0x4000128) & 0x3C, 0b01_1_1_00);
+
+        // only the parent can start a transfer
+        child.cpu.mem.set_halfword(0x4000128, 0x6083);
+        assert!(!child.cpu.mem.serial.busy);
+        parent.cpu.mem.set_halfword(0x4000128, 0x6083);
+        assert!(parent.cpu.mem.serial.busy);
+
+        frame(&mut parent, &mut child).unwrap();
+        for gba in [&parent, &child].iter() {
+            let mem = &gba.cpu.mem;
+            assert!(!mem.serial.busy);
+            assert_eq!(mem.get_halfword(0x4000120), 0x1111);
+            assert_eq!(mem.get_halfword(0x4000122), 0x2222);
+            assert_eq!(mem.get_halfword(0x4000124), 0xFFFF);
+            assert!(mem.int.triggered.serial);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! A link cable between two GBAs running on different machines. The emulator
+//! doesn't know how messages get delivered: the frontend provides a
+//! LinkTransport (e.g. backed by a WebRTC data channel or a WebSocket).
+//!
+//! Each multiplayer transfer is numbered, and both sides exchange their values
+//! tagged with the transfer number. To hide latency, the child sends its
+//! SIOMLT_SEND value ahead of time (whenever it changes), so that most of the
+//! time the parent already has it when its own transfer finishes. If it
+//! hasn't arrived yet, the parent stalls until it does. The child never
+//! stalls: it completes each transfer as soon as the parent's value arrives,
+//! which on hardware is also the point where it's too late for the child to
+//! change what it sends.
+
+use cpu::CPUWrapper;
+use error::Result;
+
+/// A value sent by one of the players for the given transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkMessage {
+    /// the number of transfers completed before this one
+    pub seq: u32,
+    pub data: u16,
+}
+
+pub trait LinkTransport {
+    /// Send a message to the other GBA. Messages must be delivered in order
+    fn send(&mut self, msg: LinkMessage);
+    /// Return the next message from the other GBA, if any have arrived
+    fn recv(&mut self) -> Option<LinkMessage>;
+}
+
+pub struct NetworkLink<T: LinkTransport> {
+    pub transport: T,
+    /// 0 for the parent, 1 for the child
+    pub player_id: u8,
+    /// number of transfers completed so far
+    pub seq: u32,
+    /// values received for the current transfer (or later ones) that haven't
+    /// been used yet
+    received: Vec<LinkMessage>,
+    /// the parent's value has been sent for the current transfer
+    announced: bool,
+    /// the value the child last sent for the current transfer
+    last_sent: Option<u16>,
+    /// number of times the parent had to wait for the child's data
+    pub stalls: u32,
+}
+
+impl<T: LinkTransport> NetworkLink<T> {
+    /// Create a link and plug the cable into the given GBA
+    pub fn new(transport: T, player_id: u8, gba: &mut CPUWrapper) -> NetworkLink<T> {
+        gba.cpu.mem.set_link(true, player_id);
+        NetworkLink {
+            transport,
+            player_id,
+            seq: 0,
+            received: Vec::new(),
+            announced: false,
+            last_sent: None,
+            stalls: 0,
+        }
+    }
+
+    /// Run until the next frame starts, and return true. If the parent is
+    /// waiting on data from the child this returns false early, and should
+    /// be called again once more messages have arrived
+    pub fn frame(&mut self, gba: &mut CPUWrapper) -> Result<bool> {
+        loop {
+            self.poll(gba);
+            if gba.cpu.mem.serial.transfer_done {
+                match self.take_remote() {
+                    Some(data) => {
+                        let own = gba.cpu.mem.multi_send();
+                        self.finish(gba, [own, data, 0xFFFF, 0xFFFF]);
+                    },
+                    None => {
+                        self.stalls += 1;
+                        return Ok(false);
+                    },
+                }
+            }
+            if gba.step()? {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn poll(&mut self, gba: &mut CPUWrapper) {
+        if self.player_id == 0 {
+            if gba.cpu.mem.serial.busy && !self.announced {
+                let data = gba.cpu.mem.multi_send();
+                self.transport.send(LinkMessage { seq: self.seq, data });
+                self.announced = true;
+            }
+            while let Some(msg) = self.transport.recv() {
+                self.received.push(msg);
+            }
+        } else {
+            self.send_ahead(gba);
+            while let Some(msg) = self.transport.recv() {
+                if msg.seq == self.seq {
+                    let own = gba.cpu.mem.multi_send();
+                    self.finish(gba, [msg.data, own, 0xFFFF, 0xFFFF]);
+                    self.send_ahead(gba);
+                }
+            }
+        }
+    }
+
+    /// Send the child's value for the current transfer if it has changed
+    fn send_ahead(&mut self, gba: &CPUWrapper) {
+        let data = gba.cpu.mem.multi_send();
+        if self.last_sent != Some(data) {
+            self.transport.send(LinkMessage { seq: self.seq, data });
+            self.last_sent = Some(data);
+        }
+    }
+
+    /// Return the most recent value the child sent for the current transfer
+    fn take_remote(&mut self) -> Option<u16> {
+        let seq = self.seq;
+        let data = self.received.iter().rev().find(|msg| msg.seq == seq).map(|msg| msg.data);
+        if data.is_some() {
+            self.received.retain(|msg| msg.seq != seq);
+        }
+        data
+    }
+
+    fn finish(&mut self, gba: &mut CPUWrapper, data: [u16; 4]) {
+        gba.cpu.mem.finish_multiplayer_transfer(data);
+        self.seq = self.seq.wrapping_add(1);
+        self.announced = false;
+        self.last_sent = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    type Queue = Rc<RefCell<VecDeque<LinkMessage>>>;
+
+    struct Loopback {
+        outgoing: Queue,
+        incoming: Queue,
+    }
+
+    impl LinkTransport for Loopback {
+        fn send(&mut self, msg: LinkMessage) {
+            self.outgoing.borrow_mut().push_back(msg);
+        }
+
+        fn recv(&mut self) -> Option<LinkMessage> {
+            self.incoming.borrow_mut().pop_front()
+        }
+    }
+
+    fn setup(gba: &mut CPUWrapper, send: u32) {
+        gba.cpu.mem.set_word(0x3000000, 0xEAFFFFFE); // b 0x3000000
+        gba.cpu.r[15] = 0x3000000;
+        gba.cpu.mem.set_halfword(0x4000128, 0x6003);
+        gba.cpu.mem.set_halfword(0x400012A, send);
+    }
+
+    #[test]
+    fn exchange() {
+        let a: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let b: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let mut parent = CPUWrapper::new();
+        let mut child = CPUWrapper::new();
+        setup(&mut parent, 0x1111);
+        setup(&mut child, 0x2222);
+        let mut parent_link = NetworkLink::new(
+            Loopback { outgoing: a.clone(), incoming: b.clone() }, 0, &mut parent);
+        let mut child_link = NetworkLink::new(
+            Loopback { outgoing: b.clone(), incoming: a.clone() }, 1, &mut child);
+
+        // the parent finishes its transfer before the child has run, so it
+        // has to wait for the child's value
+        parent.cpu.mem.set_halfword(0x4000128, 0x6083);
+        assert_eq!(parent_link.frame(&mut parent).unwrap(), false);
+        assert_eq!(parent_link.stalls, 1);
+        assert!(child_link.frame(&mut child).unwrap());
+        assert_eq!(child.cpu.mem.get_halfword(0x4000120), 0x1111);
+        assert_eq!(child.cpu.mem.get_halfword(0x4000122), 0x2222);
+        assert!(parent_link.frame(&mut parent).unwrap());
+        assert_eq!(parent.cpu.mem.get_halfword(0x4000120), 0x1111);
+        assert_eq!(parent.cpu.mem.get_halfword(0x4000122), 0x2222);
+
+        // the child already sent its value for the next transfer, so the
+        // parent doesn't have to wait this time
+        child.cpu.mem.set_halfword(0x400012A, 0x3333);
+        child_link.frame(&mut child).unwrap();
+        parent.cpu.mem.set_halfword(0x4000128, 0x6083);
+        assert!(parent_link.frame(&mut parent).unwrap());
+        assert_eq!(parent_link.stalls, 1);
+        assert_eq!(parent.cpu.mem.get_halfword(0x4000122), 0x3333);
+    }
+}
@@ -17,4 +17,8 @@ pub const ROM_END: u32 = 0x09FFFFFF;
 pub const ROM_MIRROR1_START: u32 = 0xA000000;
 pub const ROM_MIRROR1_END: u32 = 0xBFFFFFF;
 pub const ROM_MIRROR2_START: u32 = 0xC000000;
-pub const ROM_MIRROR2_END: u32 = 0xDFFFFFF;
\ No newline at end of file
+pub const ROM_MIRROR2_END: u32 = 0xDFFFFFF;
+pub const SRAM_START: u32 = 0xE000000;
+pub const SRAM_END: u32 = 0xE00FFFF;
+/// the size of each of the 3 cartridge ROM regions
+pub const MAX_ROM_SIZE: usize = 0x2000000;
@@ -0,0 +1,918 @@
+//! Battery backed SRAM in the game pak, where games keep their saves. SRAM
+//! appears at 0x0E000000 and is 32KB, mirrored across the 64KB region. Most
+//! carts either have exactly that or use a different kind of chip, but a few
+//! (mostly multi game compilations) have more SRAM than fits in the region
+//! and bank it instead: only the lower 32KB of the region maps SRAM, and
+//! writing a byte anywhere in the upper 32KB selects which bank appears there.
+//!
+//! Nintendo's save library leaves an ID string in the ROM that says which
+//! chip the cart has (see detect), and "SRAM_V" or "SRAM_F_V" gives it the
+//! usual single bank. Nothing says how many banks a banked cart has, so for
+//! those the number is set per game (see config::GameOverrides). Without
+//! either, the region reads as if there was no chip at all.
+//!
+//! Other carts save to a 64KB or 128KB Flash chip in the same region instead.
+//! Flash can be read like SRAM, but is only written through command
+//! sequences: writing 0xAA to 0x5555 and 0x55 to 0x2AAA unlocks the chip, and
+//! the next byte written to 0x5555 is the command. Commands can erase the
+//! whole chip or a 4KB sector back to 0xFF, program a single byte, switch to
+//! reading the chip's ID, or (on 128KB chips) select which 64KB bank appears
+//! in the region. Flash is found from the ID string when the ROM is loaded.
+//!
+//! The rest use a 512 byte or 8KB serial EEPROM, which isn't in the backup
+//! region at all but at the top of the last ROM mirror (0x0D000000, or
+//! 0x0DFFFF00 for 32MB ROMs). It's accessed one bit at a time, in bit 0 of
+//! each halfword, always with DMA3. A request is 2 bits for the command
+//! (0b11 to read, 0b10 to write), the address of an 8 byte block (6 bits on
+//! 512 byte chips and 14 on 8KB ones), the 64 bits to write for a write, and
+//! a final 0 bit. After a read request, the next 68 bits read are 4 junk bits
+//! and then the block, most significant bit first. The ROM says that a cart
+//! has EEPROM but not which size, so the size is taken from the length of the
+//! first request, which is the DMA's count.
+
+use std::cell::Cell;
+use mem::{Memory, RawMemory, canonicalize_addr};
+use mem::addrs::{SRAM_START, ROM_MIRROR2_END};
+
+/// the size of the SRAM chip in a normal cart, and of each bank in a banked
+/// one
+pub const BANK_SIZE: usize = 0x8000;
+/// the most banks a cart can have
+pub const MAX_BANKS: u8 = 8;
+
+pub struct Sram {
+    data: Vec<u8>,
+    /// the number of BANK_SIZE banks. 0 means there's no SRAM and 1 that it
+    /// isn't banked
+    banks: u8,
+    /// the bank currently mapped into the region
+    bank: u8,
+}
+
+impl Sram {
+    pub const fn new() -> Sram {
+        Sram {
+            data: Vec::new(),
+            banks: 0,
+            bank: 0,
+        }
+    }
+
+    pub fn banks(&self) -> u8 {
+        self.banks
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Change the number of banks (up to MAX_BANKS), keeping the contents of
+    /// the ones that are still there. New banks start out erased
+    pub fn set_banks(&mut self, banks: u8) {
+        let banks = banks.min(MAX_BANKS);
+        self.data.resize(banks as usize * BANK_SIZE, 0xFF);
+        self.banks = banks;
+        if self.bank >= banks {
+            self.bank = 0;
+        }
+    }
+
+    /// Map the first bank back in, as when the GBA is turned on
+    pub fn reset_bank(&mut self) {
+        self.bank = 0;
+    }
+
+    /// Return the index into the data of the given offset into the SRAM
+    /// region, or None if there's no SRAM there
+    fn index(&self, offset: u32) -> Option<usize> {
+        let offset = offset as usize;
+        match self.banks {
+            0 => None,
+            1 => Some(offset % BANK_SIZE),
+            _ if offset < BANK_SIZE => Some(self.bank as usize * BANK_SIZE + offset),
+            _ => None,
+        }
+    }
+
+    /// Return the data and the index into it of the given offset into the
+    /// SRAM region, for RawMemory::get_loc
+    pub fn loc(&self, offset: u32) -> Option<(&[u8], usize)> {
+        let idx = self.index(offset)?;
+        Some((&self.data, idx))
+    }
+
+    pub fn loc_mut(&mut self, offset: u32) -> Option<(&mut [u8], usize)> {
+        let idx = self.index(offset)?;
+        Some((&mut self.data, idx))
+    }
+
+    /// Return true if a write to the given offset into the SRAM region
+    /// selects a bank instead of writing to SRAM
+    pub fn is_bank_select(&self, offset: u32) -> bool {
+        self.banks > 1 && offset as usize >= BANK_SIZE
+    }
+
+    /// Map in the given bank. Bank numbers past the last bank wrap around, as
+    /// the unused address lines are ignored
+    pub fn select_bank(&mut self, bank: u8) {
+        if self.banks > 0 {
+            self.bank = bank % self.banks;
+        }
+    }
+
+    /// Return the contents of every bank in order, which is the format of the
+    /// .sav files used by other emulators and flash carts
+    pub fn export(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Load a save produced by export(). Anything past the end of the SRAM is
+    /// dropped, and a smaller save leaves the rest of the SRAM as it is
+    pub fn import(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+/// the size of a 512Kbit Flash chip, which is also the size of each bank of
+/// a 1Mbit one
+pub const FLASH_64K: usize = 0x10000;
+pub const FLASH_128K: usize = 0x20000;
+/// the size of the blocks erased by the sector erase command
+const SECTOR_SIZE: usize = 0x1000;
+
+/// the (manufacturer, device) IDs reported in ID mode, of the Panasonic 64KB
+/// and Sanyo 128KB chips, which the save libraries for each size accept
+const ID_64K: [u8; 2] = [0x32, 0x1B];
+const ID_128K: [u8; 2] = [0x62, 0x13];
+
+/// the addresses the unlock sequence and commands are written to
+const CMD_ADDR1: u32 = 0x5555;
+const CMD_ADDR2: u32 = 0x2AAA;
+
+/// A write that the last command said to expect next, instead of another
+/// command
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pending {
+    None,
+    /// program the next byte written
+    Write,
+    /// the next write to offset 0 selects the bank
+    Bank,
+}
+
+pub struct Flash {
+    data: Vec<u8>,
+    /// how far through the unlock sequence the last writes got (0 to 2)
+    unlock: u8,
+    /// set by the erase command, which needs another unlocked command to say
+    /// whether to erase the chip or a sector
+    erase: bool,
+    pending: Pending,
+    /// set while reads from the first two bytes return the chip's ID
+    id_mode: bool,
+    id: [u8; 2],
+    /// the 64KB bank currently mapped into the region
+    bank: u8,
+}
+
+impl Flash {
+    pub const fn new() -> Flash {
+        Flash {
+            data: Vec::new(),
+            unlock: 0,
+            erase: false,
+            pending: Pending::None,
+            id_mode: false,
+            id: [0; 2],
+            bank: 0,
+        }
+    }
+
+    /// the size of the chip in bytes, or 0 if there isn't one
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Replace the chip with an erased one of the given size (FLASH_64K or
+    /// FLASH_128K), or remove it with any other size
+    pub fn set_size(&mut self, size: usize) {
+        *self = Flash::new();
+        match size {
+            FLASH_64K => self.id = ID_64K,
+            FLASH_128K => self.id = ID_128K,
+            _ => return,
+        }
+        self.data = vec![0xFF; size];
+    }
+
+    /// Abandon any command in progress and map the first bank back in, as
+    /// when the GBA is turned on
+    pub fn reset(&mut self) {
+        self.unlock = 0;
+        self.erase = false;
+        self.pending = Pending::None;
+        self.id_mode = false;
+        self.bank = 0;
+    }
+
+    /// Return the state of the command sequence as (unlock, erase, pending,
+    /// id mode, bank), for save states
+    pub fn internal_state(&self) -> (u8, bool, u8, bool, u8) {
+        let pending = match self.pending {
+            Pending::None => 0,
+            Pending::Write => 1,
+            Pending::Bank => 2,
+        };
+        (self.unlock, self.erase, pending, self.id_mode, self.bank)
+    }
+
+    /// Restore the state from internal_state. Values that the chip can't be
+    /// in are replaced with the ones it has when turned on
+    pub fn restore_internal_state(&mut self, unlock: u8, erase: bool, pending: u8,
+                                  id_mode: bool, bank: u8) {
+        self.reset();
+        if self.data.is_empty() {
+            return;
+        }
+        self.unlock = if unlock <= 2 { unlock } else { 0 };
+        self.erase = erase;
+        self.pending = match pending {
+            1 => Pending::Write,
+            2 if self.data.len() == FLASH_128K => Pending::Bank,
+            _ => Pending::None,
+        };
+        self.id_mode = id_mode;
+        if (bank as usize) < self.data.len() / FLASH_64K {
+            self.bank = bank;
+        }
+    }
+
+    /// Return the data and the index into it of the given offset into the
+    /// backup region, for RawMemory::get_loc
+    pub fn loc(&self, offset: u32) -> Option<(&[u8], usize)> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let offset = offset as usize % FLASH_64K;
+        if self.id_mode && offset < 2 {
+            return Some((&self.id, offset));
+        }
+        Some((&self.data, self.bank as usize * FLASH_64K + offset))
+    }
+
+    /// Handle a byte written to the given offset into the backup region
+    pub fn write(&mut self, offset: u32, val: u8) {
+        let offset = offset % FLASH_64K as u32;
+        match self.pending {
+            Pending::Write => {
+                let idx = self.bank as usize * FLASH_64K + offset as usize;
+                self.data[idx] = val;
+                self.pending = Pending::None;
+                return;
+            },
+            Pending::Bank if offset == 0 => {
+                self.bank = val & 1;
+                self.pending = Pending::None;
+                return;
+            },
+            _ => (),
+        }
+        match (self.unlock, offset, val) {
+            (0, CMD_ADDR1, 0xAA) => self.unlock = 1,
+            (1, CMD_ADDR2, 0x55) => self.unlock = 2,
+            (2, CMD_ADDR1, cmd) => {
+                self.unlock = 0;
+                self.command(cmd);
+            },
+            (2, _, 0x30) if self.erase => {
+                let start = self.bank as usize * FLASH_64K +
+                    offset as usize / SECTOR_SIZE * SECTOR_SIZE;
+                for byte in self.data[start..start + SECTOR_SIZE].iter_mut() {
+                    *byte = 0xFF;
+                }
+                self.unlock = 0;
+                self.erase = false;
+            },
+            // some libraries leave ID mode without unlocking first
+            (_, _, 0xF0) => {
+                self.unlock = 0;
+                self.id_mode = false;
+            },
+            _ => self.unlock = 0,
+        }
+    }
+
+    fn command(&mut self, cmd: u8) {
+        let erase = self.erase;
+        self.erase = false;
+        match cmd {
+            0x90 => self.id_mode = true,
+            0xF0 => self.id_mode = false,
+            0x80 => self.erase = true,
+            0x10 if erase => {
+                for byte in self.data.iter_mut() {
+                    *byte = 0xFF;
+                }
+            },
+            0xA0 => self.pending = Pending::Write,
+            0xB0 if self.data.len() == FLASH_128K => self.pending = Pending::Bank,
+            _ => (),
+        }
+    }
+
+    /// Return the contents of the chip, in the same .sav format as SRAM
+    pub fn export(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Load a save produced by export(), in the same way as Sram::import
+    pub fn import(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+pub const EEPROM_512: usize = 0x200;
+pub const EEPROM_8K: usize = 0x2000;
+/// the number of bits read back for a block: 4 junk bits and then 64 of data
+const EEPROM_READ_BITS: u32 = 68;
+
+pub struct Eeprom {
+    /// set if the cart has EEPROM, even while its size is unknown
+    present: bool,
+    /// empty until the size is known
+    data: Vec<u8>,
+    /// the bits of the request being written, the latest in bit 0
+    request: u128,
+    request_len: u32,
+    /// the byte index of the block being read back, and how many of its bits
+    /// have been read. Reads happen behind a shared reference
+    read: Cell<Option<(usize, u32)>>,
+}
+
+impl Eeprom {
+    pub const fn new() -> Eeprom {
+        Eeprom {
+            present: false,
+            data: Vec::new(),
+            request: 0,
+            request_len: 0,
+            read: Cell::new(None),
+        }
+    }
+
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    /// the size of the chip in bytes, or 0 if there isn't one or its size
+    /// isn't known yet
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Give the cart EEPROM whose size will be found from its first request
+    pub fn enable(&mut self) {
+        *self = Eeprom::new();
+        self.present = true;
+    }
+
+    /// Replace the chip with an erased one of the given size (EEPROM_512 or
+    /// EEPROM_8K), or remove it with any other size
+    pub fn set_size(&mut self, size: usize) {
+        *self = Eeprom::new();
+        if size == EEPROM_512 || size == EEPROM_8K {
+            self.present = true;
+            self.data = vec![0xFF; size];
+        }
+    }
+
+    /// Abandon any request in progress, as when the GBA is turned on
+    pub fn reset(&mut self) {
+        self.request = 0;
+        self.request_len = 0;
+        self.read.set(None);
+    }
+
+    /// Return the request being written as (bits, number of bits), and the
+    /// (byte index, bits read) of the block being read back, for save states
+    pub fn internal_state(&self) -> (u128, u32, Option<(usize, u32)>) {
+        (self.request, self.request_len, self.read.get())
+    }
+
+    /// Restore the contents, size and state from export and internal_state.
+    /// A cart without EEPROM is left without it, and an empty save leaves
+    /// the size to be found from the next request. A request or read that
+    /// the chip can't be in is abandoned
+    pub fn restore_internal_state(&mut self, data: &[u8], request: u128, request_len: u32,
+                                  read: Option<(usize, u32)>) {
+        if !self.present {
+            return;
+        }
+        if data.is_empty() {
+            self.enable();
+            return;
+        }
+        self.set_size(data.len());
+        self.import(data);
+        if self.data.is_empty() {
+            // not a size the chip comes in
+            self.enable();
+            return;
+        }
+        if request_len <= 2 + self.addr_bits() + 64 {
+            self.request = request & ((1 << request_len) - 1);
+            self.request_len = request_len;
+        }
+        self.read.set(read.filter(|&(idx, n)| idx + 8 <= self.data.len() && n < EEPROM_READ_BITS));
+    }
+
+    fn addr_bits(&self) -> u32 {
+        if self.data.len() == EEPROM_8K { 14 } else { 6 }
+    }
+
+    /// Return the byte index of the block addressed by the given bits. 8KB
+    /// chips only use the low 10 bits of the address
+    fn block_index(&self, addr: u128) -> usize {
+        (addr as usize % (self.data.len() / 8)) * 8
+    }
+
+    /// Set the size from the number of bits in a DMA to the chip, if it isn't
+    /// known yet: a read request or a write request for each size
+    pub fn size_from_count(&mut self, count: u32) {
+        if !self.present || !self.data.is_empty() {
+            return;
+        }
+        match count {
+            9 | 73 => self.data = vec![0xFF; EEPROM_512],
+            17 | 81 => self.data = vec![0xFF; EEPROM_8K],
+            _ => (),
+        }
+    }
+
+    /// Handle a bit of a request. Requests to a chip of unknown size are
+    /// ignored
+    pub fn write_bit(&mut self, bit: u32) {
+        if self.data.is_empty() {
+            return;
+        }
+        self.request = self.request << 1 | (bit & 1) as u128;
+        self.request_len += 1;
+        if self.request_len < 2 {
+            return;
+        }
+        let addr_bits = self.addr_bits();
+        let addr_mask = (1u128 << addr_bits) - 1;
+        match self.request >> (self.request_len - 2) {
+            0b11 if self.request_len == 2 + addr_bits + 1 => {
+                let idx = self.block_index((self.request >> 1) & addr_mask);
+                self.read.set(Some((idx, 0)));
+            },
+            0b10 if self.request_len == 2 + addr_bits + 64 + 1 => {
+                let idx = self.block_index((self.request >> 65) & addr_mask);
+                let block = (self.request >> 1) as u64;
+                for i in 0..8 {
+                    self.data[idx + i] = (block >> (56 - 8 * i)) as u8;
+                }
+            },
+            // the request isn't finished yet
+            0b11 | 0b10 => return,
+            _ => (),
+        }
+        self.request = 0;
+        self.request_len = 0;
+    }
+
+    /// Return the next bit of the block being read, or 1 (ready) if there
+    /// isn't one. Writes finish instantly, so the chip is always ready
+    pub fn read_bit(&self) -> u16 {
+        let (idx, n) = match self.read.get() {
+            Some(read) => read,
+            None => return 1,
+        };
+        self.read.set(if n + 1 < EEPROM_READ_BITS { Some((idx, n + 1)) } else { None });
+        if n < 4 {
+            return 0;
+        }
+        let i = (n - 4) as usize;
+        (self.data[idx + i / 8] >> (7 - i % 8)) as u16 & 1
+    }
+
+    /// Return the contents of the chip, in the same .sav format as SRAM
+    pub fn export(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// Load a save produced by export(), in the same way as Sram::import. If
+    /// the size isn't known yet, it's taken from the save
+    pub fn import(&mut self, data: &[u8]) {
+        if self.present && self.data.is_empty() {
+            self.set_size(data.len());
+        }
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+/// The save chip that a ROM's save library ID string asks for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chip {
+    /// no string, which means no saves unless SRAM is set up per game (see
+    /// Sram)
+    Unknown,
+    /// a single bank of SRAM
+    Sram,
+    Eeprom,
+    /// a Flash chip of the given size
+    Flash(usize),
+}
+
+/// Return the chip that the save library ID string in the given ROM asks
+/// for. The strings are word aligned
+pub fn detect(rom: &[u8]) -> Chip {
+    for i in (0..rom.len()).step_by(4) {
+        let rest = &rom[i..];
+        if rest.starts_with(b"FLASH1M_V") {
+            return Chip::Flash(FLASH_128K);
+        } else if rest.starts_with(b"FLASH_V") || rest.starts_with(b"FLASH512_V") {
+            return Chip::Flash(FLASH_64K);
+        } else if rest.starts_with(b"EEPROM_V") {
+            return Chip::Eeprom;
+        } else if rest.starts_with(b"SRAM_V") || rest.starts_with(b"SRAM_F_V") {
+            return Chip::Sram;
+        }
+    }
+    Chip::Unknown
+}
+
+impl RawMemory {
+    /// Return the data and the index into it of the given offset into the
+    /// backup region, from Flash if the cart has any and otherwise from SRAM
+    pub fn backup_loc(&self, offset: u32) -> Option<(&[u8], usize)> {
+        if self.flash.size() > 0 {
+            self.flash.loc(offset)
+        } else {
+            self.sram.loc(offset)
+        }
+    }
+
+    /// Flash is only written through commands, so only SRAM is writable
+    pub fn backup_loc_mut(&mut self, offset: u32) -> Option<(&mut [u8], usize)> {
+        if self.flash.size() > 0 {
+            None
+        } else {
+            self.sram.loc_mut(offset)
+        }
+    }
+
+    pub fn has_backup(&self) -> bool {
+        self.flash.size() > 0 || self.sram.banks() > 0
+    }
+
+    /// Return true if the given (canonical) address is where the cart's
+    /// EEPROM is accessed. Carts with 32MB ROMs need all of the last mirror
+    /// for ROM but its last 256 bytes
+    pub fn is_eeprom(&self, addr: u32) -> bool {
+        if !self.eeprom.present() {
+            return false;
+        }
+        let large_rom = self.rom.as_ref().map_or(false, |rom| rom.len() > 0x1000000);
+        match addr {
+            0xD000000...ROM_MIRROR2_END => !large_rom || addr >= 0xDFFFF00,
+            _ => false,
+        }
+    }
+
+    /// Give the cart the save chip that the loaded ROM asks for, removing
+    /// any it had before
+    pub fn detect_backup(&mut self) {
+        self.sram = Sram::new();
+        self.flash = Flash::new();
+        self.eeprom = Eeprom::new();
+        let chip = self.rom.as_ref().map_or(Chip::Unknown, |rom| detect(rom));
+        match chip {
+            Chip::Flash(size) => self.flash.set_size(size),
+            Chip::Sram => self.sram.set_banks(1),
+            Chip::Eeprom => self.eeprom.enable(),
+            Chip::Unknown => (),
+        }
+    }
+
+    /// Return the contents of whichever backup chip the cart has, as a .sav
+    /// file
+    pub fn export_backup(&self) -> Vec<u8> {
+        if self.flash.size() > 0 {
+            self.flash.export()
+        } else if self.eeprom.present() {
+            self.eeprom.export()
+        } else {
+            self.sram.export()
+        }
+    }
+
+    pub fn import_backup(&mut self, data: &[u8]) {
+        if self.flash.size() > 0 {
+            self.flash.import(data);
+        } else if self.eeprom.present() {
+            self.eeprom.import(data);
+        } else {
+            self.sram.import(data);
+        }
+    }
+}
+
+impl Memory {
+    /// Writes to SRAM itself go straight to raw memory, so this only needs to
+    /// handle bank selects, Flash commands and the tilt sensor
+    pub fn update_sram_byte(&mut self, addr: u32, val: u8) {
+        let offset = addr - SRAM_START;
+        if self.raw.tilt.present() {
+            self.raw.tilt.write(offset, val);
+        } else if self.raw.flash.size() > 0 {
+            self.raw.flash.write(offset, val);
+        } else if self.raw.sram.is_bank_select(offset) {
+            self.raw.sram.select_bank(val);
+        }
+    }
+
+    /// Writes to the EEPROM's addresses send it the bit in bit 0
+    pub fn update_eeprom_hw(&mut self, addr: u32, val: u32) {
+        if self.raw.is_eeprom(addr) {
+            self.raw.eeprom.write_bit(val);
+        }
+    }
+
+    /// Called before DMA3 runs, so that the first transfer to EEPROM can set
+    /// its size
+    pub fn check_eeprom_dma(&mut self, dest: u32, count: u32) {
+        if self.raw.is_eeprom(canonicalize_addr(dest)) {
+            self.raw.eeprom.size_from_count(count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unbanked() {
+        let mut mem = Memory::new();
+        // without SRAM the region isn't driven
+        mem.set_byte(0xE000010, 0x12);
+        assert_eq!(mem.get_byte(0xE000010), 0xFF);
+
+        mem.raw.sram.set_banks(1);
+        mem.set_byte(0xE000010, 0x12);
+        assert_eq!(mem.get_byte(0xE000010), 0x12);
+        // the upper half mirrors the lower half
+        assert_eq!(mem.get_byte(0xE008010), 0x12);
+        assert_eq!(mem.raw.sram.export().len(), BANK_SIZE);
+    }
+
+    #[test]
+    fn banked() {
+        let mut mem = Memory::new();
+        mem.raw.sram.set_banks(4);
+        for bank in 0..4 {
+            mem.set_byte(0xE008000, bank);
+            mem.set_byte(0xE000000, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            mem.set_byte(0xE00FFFF, bank);
+            assert_eq!(mem.get_byte(0xE000000), 0x10 + bank);
+        }
+        // the bank select register can't be read back
+        assert_eq!(mem.get_byte(0xE008000), 0xFF);
+        // and only the low bits of the bank number are used
+        mem.set_byte(0xE008000, 5);
+        assert_eq!(mem.raw.sram.bank(), 1);
+
+        // saves hold every bank
+        let save = mem.raw.sram.export();
+        assert_eq!(save.len(), 4 * BANK_SIZE);
+        assert_eq!(save[3 * BANK_SIZE], 0x13);
+
+        let mut other = Memory::new();
+        other.raw.sram.set_banks(4);
+        other.raw.sram.import(&save);
+        other.set_byte(0xE008000, 2);
+        assert_eq!(other.get_byte(0xE000000), 0x12);
+
+        // shrinking keeps the remaining banks and switches back to the first
+        other.raw.sram.set_banks(2);
+        assert_eq!(other.raw.sram.bank(), 0);
+        assert_eq!(other.get_byte(0xE000000), 0x10);
+    }
+
+    /// Write the unlock sequence followed by the given command
+    fn flash_command(mem: &mut Memory, cmd: u8) {
+        mem.set_byte(0xE005555, 0xAA);
+        mem.set_byte(0xE002AAA, 0x55);
+        mem.set_byte(0xE005555, cmd);
+    }
+
+    #[test]
+    fn flash_id() {
+        let mut mem = Memory::new();
+        mem.raw.flash.set_size(FLASH_128K);
+        assert_eq!(mem.get_halfword(0xE000000), 0xFFFF);
+        flash_command(&mut mem, 0x90);
+        assert_eq!(mem.get_byte(0xE000000), 0x62);
+        assert_eq!(mem.get_byte(0xE000001), 0x13);
+        flash_command(&mut mem, 0xF0);
+        assert_eq!(mem.get_byte(0xE000000), 0xFF);
+
+        // a write without the unlock sequence also leaves ID mode
+        mem.raw.flash.set_size(FLASH_64K);
+        flash_command(&mut mem, 0x90);
+        assert_eq!(mem.get_byte(0xE000000), 0x32);
+        mem.set_byte(0xE005555, 0xF0);
+        assert_eq!(mem.get_byte(0xE000000), 0xFF);
+    }
+
+    #[test]
+    fn flash_write_erase() {
+        let mut mem = Memory::new();
+        mem.raw.flash.set_size(FLASH_64K);
+        // writes without a command are ignored
+        mem.set_byte(0xE001234, 0x12);
+        assert_eq!(mem.get_byte(0xE001234), 0xFF);
+
+        flash_command(&mut mem, 0xA0);
+        mem.set_byte(0xE001234, 0x12);
+        flash_command(&mut mem, 0xA0);
+        mem.set_byte(0xE002000, 0x34);
+        assert_eq!(mem.get_byte(0xE001234), 0x12);
+        assert_eq!(mem.get_byte(0xE002000), 0x34);
+
+        // erasing a sector leaves the others alone
+        flash_command(&mut mem, 0x80);
+        mem.set_byte(0xE005555, 0xAA);
+        mem.set_byte(0xE002AAA, 0x55);
+        mem.set_byte(0xE001000, 0x30);
+        assert_eq!(mem.get_byte(0xE001234), 0xFF);
+        assert_eq!(mem.get_byte(0xE002000), 0x34);
+
+        flash_command(&mut mem, 0x80);
+        flash_command(&mut mem, 0x10);
+        assert_eq!(mem.get_byte(0xE002000), 0xFF);
+
+        // the chip erase command does nothing without the erase command first
+        flash_command(&mut mem, 0xA0);
+        mem.set_byte(0xE000010, 0x56);
+        flash_command(&mut mem, 0x10);
+        assert_eq!(mem.get_byte(0xE000010), 0x56);
+    }
+
+    #[test]
+    fn flash_banks() {
+        let mut mem = Memory::new();
+        mem.raw.flash.set_size(FLASH_128K);
+        for bank in 0..2 {
+            flash_command(&mut mem, 0xB0);
+            mem.set_byte(0xE000000, bank);
+            flash_command(&mut mem, 0xA0);
+            mem.set_byte(0xE000100, 0x10 + bank);
+        }
+        flash_command(&mut mem, 0xB0);
+        mem.set_byte(0xE000000, 0);
+        assert_eq!(mem.get_byte(0xE000100), 0x10);
+        assert_eq!(mem.raw.export_backup()[FLASH_64K + 0x100], 0x11);
+
+        // 64KB chips have no banks
+        mem.raw.flash.set_size(FLASH_64K);
+        flash_command(&mut mem, 0xB0);
+        mem.set_byte(0xE000000, 1);
+        assert_eq!(mem.raw.flash.bank(), 0);
+    }
+
+    #[test]
+    fn flash_takes_over_region() {
+        let mut mem = Memory::new();
+        mem.raw.sram.set_banks(1);
+        mem.raw.flash.set_size(FLASH_64K);
+        mem.set_byte(0xE000000, 0x12);
+        assert_eq!(mem.get_byte(0xE000000), 0xFF);
+        assert_eq!(mem.raw.export_backup().len(), FLASH_64K);
+
+        let mut save = vec![0; FLASH_64K];
+        save[5] = 0x12;
+        mem.raw.import_backup(&save);
+        assert_eq!(mem.get_byte(0xE000005), 0x12);
+    }
+
+    #[test]
+    fn detect_chip() {
+        let mut rom = vec![0; 0x200];
+        assert_eq!(detect(&rom), Chip::Unknown);
+        rom[0x100..0x10A].copy_from_slice(b"FLASH_V124");
+        assert_eq!(detect(&rom), Chip::Flash(FLASH_64K));
+        rom[0x100..0x10C].copy_from_slice(b"FLASH1M_V103");
+        assert_eq!(detect(&rom), Chip::Flash(FLASH_128K));
+        rom[0x100..0x10D].copy_from_slice(b"FLASH512_V131");
+        assert_eq!(detect(&rom), Chip::Flash(FLASH_64K));
+        rom[0x100..0x10D].copy_from_slice(b"EEPROM_V124\0\0");
+        assert_eq!(detect(&rom), Chip::Eeprom);
+
+        let mut mem = Memory::new();
+        mem.load_rom(rom);
+        assert!(mem.raw.eeprom.present());
+        assert_eq!(mem.raw.flash.size(), 0);
+
+        let mut rom = vec![0; 0x200];
+        rom[0x100..0x10B].copy_from_slice(b"SRAM_F_V100");
+        assert_eq!(detect(&rom), Chip::Sram);
+        rom[0x100..0x10B].copy_from_slice(b"SRAM_V113\0\0");
+        assert_eq!(detect(&rom), Chip::Sram);
+        mem.load_rom(rom);
+        assert!(!mem.raw.eeprom.present());
+        assert_eq!(mem.raw.sram.banks(), 1);
+        mem.set_byte(0xE000005, 0x12);
+        assert_eq!(mem.get_byte(0xE000005), 0x12);
+    }
+
+    /// Send a request to the EEPROM with DMA3, as games do: copy it a bit per
+    /// halfword from EWRAM
+    fn eeprom_request(mem: &mut Memory, bits: &[u32]) {
+        for (i, bit) in bits.iter().enumerate() {
+            mem.raw.set_halfword(0x2000000 + 2 * i as u32, *bit);
+        }
+        mem.check_eeprom_dma(0xD000000, bits.len() as u32);
+        for i in 0..bits.len() as u32 {
+            let val = mem.get_halfword(0x2000000 + 2 * i) as u32;
+            mem.set_halfword(0xD000000, val);
+        }
+    }
+
+    /// Return the bits of the given value, most significant first
+    fn to_bits(val: u64, len: u32) -> Vec<u32> {
+        (0..len).rev().map(|i| (val >> i) as u32 & 1).collect()
+    }
+
+    fn eeprom_write(mem: &mut Memory, addr_bits: u32, block: u64, val: u64) {
+        let mut bits = vec![1, 0];
+        bits.extend(to_bits(block, addr_bits));
+        bits.extend(to_bits(val, 64));
+        bits.push(0);
+        eeprom_request(mem, &bits);
+    }
+
+    fn eeprom_read(mem: &mut Memory, addr_bits: u32, block: u64) -> u64 {
+        let mut bits = vec![1, 1];
+        bits.extend(to_bits(block, addr_bits));
+        bits.push(0);
+        eeprom_request(mem, &bits);
+        let read: Vec<u16> = (0..68).map(|_| mem.get_halfword(0xD000000) & 1).collect();
+        assert_eq!(&read[..4], &[0, 0, 0, 0]);
+        read[4..].iter().fold(0, |val, bit| val << 1 | *bit as u64)
+    }
+
+    #[test]
+    fn eeprom_512() {
+        let mut mem = Memory::new();
+        mem.raw.eeprom.enable();
+        // the size isn't known until the first request
+        assert_eq!(mem.raw.eeprom.size(), 0);
+        eeprom_write(&mut mem, 6, 0x3F, 0x0123456789ABCDEF);
+        assert_eq!(mem.raw.eeprom.size(), EEPROM_512);
+        assert_eq!(eeprom_read(&mut mem, 6, 0x3F), 0x0123456789ABCDEF);
+        assert_eq!(eeprom_read(&mut mem, 6, 0), 0xFFFFFFFFFFFFFFFF);
+        // ready once the block has been read
+        assert_eq!(mem.get_halfword(0xD000000), 1);
+
+        let save = mem.raw.export_backup();
+        assert_eq!(save.len(), EEPROM_512);
+        assert_eq!(&save[0x1F8..], &[0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+    }
+
+    #[test]
+    fn eeprom_8k() {
+        let mut mem = Memory::new();
+        mem.raw.eeprom.enable();
+        let mut save = vec![0xFF; EEPROM_8K];
+        save[8..16].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        mem.raw.import_backup(&save);
+        assert_eq!(mem.raw.eeprom.size(), EEPROM_8K);
+        assert_eq!(eeprom_read(&mut mem, 14, 1), 0x0102030405060708);
+        // only the low 10 bits of the address are used
+        eeprom_write(&mut mem, 14, 0x3FF | 0x400, 42);
+        assert_eq!(eeprom_read(&mut mem, 14, 0x3FF), 42);
+    }
+
+    #[test]
+    fn eeprom_large_rom() {
+        let mut mem = Memory::new();
+        mem.raw.rom = Some(vec![0; 0x1000004]);
+        mem.raw.eeprom.set_size(EEPROM_512);
+        assert!(!mem.raw.is_eeprom(0xD000000));
+        assert!(mem.raw.is_eeprom(0xDFFFF00));
+        mem.raw.rom = Some(vec![0; 0x1000000]);
+        assert!(mem.raw.is_eeprom(0xD000000));
+    }
+}
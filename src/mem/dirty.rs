@@ -0,0 +1,116 @@
+//! The palette and OAM are parsed into Palette and Sprites by the write
+//! handlers that Memory::set_byte etc. call, but some writes go straight to
+//! raw memory instead (e.g. the bulk writes of a DMA into OAM). So that the
+//! parsed versions can't go stale, RawMemory marks each halfword of the
+//! palette and OAM it writes, the write handlers unmark the halfwords they
+//! parse, and anything still marked is parsed again before a line is drawn.
+//! VRAM is read directly by the renderer, so it has nothing to refresh.
+
+use mem::Memory;
+use mem::addrs::{PAL_START, PAL_END, OAM_START, OAM_END};
+
+/// Both regions are 0x400 bytes
+const HALFWORDS: usize = 0x200;
+
+pub struct Dirty {
+    pal: [bool; HALFWORDS],
+    oam: [bool; HALFWORDS],
+    /// set if anything is marked, so that nothing needs to be checked in the
+    /// usual case where every write went through the handlers
+    any: bool,
+}
+
+impl Dirty {
+    pub const fn new() -> Dirty {
+        Dirty {
+            pal: [false; HALFWORDS],
+            oam: [false; HALFWORDS],
+            any: false,
+        }
+    }
+
+    /// Mark the halfword containing the given (canonical) address as written
+    /// but not parsed yet, if it's in the palette or OAM
+    pub fn mark(&mut self, addr: u32) {
+        let hw = ((addr & 0x3FF) / 2) as usize;
+        match addr {
+            PAL_START...PAL_END => self.pal[hw] = true,
+            OAM_START...OAM_END => self.oam[hw] = true,
+            _ => return,
+        }
+        self.any = true;
+    }
+
+    /// Unmark the halfword containing the given address once it's been parsed
+    pub fn unmark(&mut self, addr: u32) {
+        let hw = ((addr & 0x3FF) / 2) as usize;
+        match addr {
+            PAL_START...PAL_END => self.pal[hw] = false,
+            OAM_START...OAM_END => self.oam[hw] = false,
+            _ => (),
+        }
+    }
+}
+
+impl Memory {
+    /// Parse the halfwords of the palette and OAM that were written without
+    /// going through the write handlers
+    pub fn refresh_parsed_mirrors(&mut self) {
+        if !self.raw.dirty.any {
+            return;
+        }
+        for hw in 0..HALFWORDS {
+            if self.raw.dirty.pal[hw] {
+                let addr = PAL_START + hw as u32 * 2;
+                let val = self.raw.get_halfword(addr) as u32;
+                self.update_pal_hw(addr, val);
+            }
+        }
+        // the affine params were written whenever the raw write happened, not
+        // now, so this shouldn't count as an HBlank write
+        let hblank_affine_writes = self.sprites.hblank_affine_writes;
+        for hw in 0..HALFWORDS {
+            if self.raw.dirty.oam[hw] {
+                let addr = OAM_START + hw as u32 * 2;
+                let val = self.raw.get_halfword(addr) as u32;
+                self.update_oam_hw(addr, val);
+            }
+        }
+        self.sprites.hblank_affine_writes = hblank_affine_writes;
+        self.raw.dirty.any = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mem::palette::high_to_true;
+
+    #[test]
+    fn refresh() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x5000002, 0x001F);
+        assert!(!mem.raw.dirty.pal[1]);
+
+        mem.raw.set_halfword(0x5000002, 0x7C00);
+        mem.raw.set_halfword(0x7000000, 40);
+        mem.raw.set_halfword(0x7000006, 0x0100);
+        assert_eq!(mem.palette.bg[1], high_to_true(0x001F));
+
+        // during HBlank, to make sure the refresh isn't taken for a game's
+        // write to the affine params
+        mem.graphics.disp_stat.is_hblank = true;
+        mem.refresh_parsed_mirrors();
+        assert_eq!(mem.palette.bg[1], high_to_true(0x7C00));
+        assert_eq!(mem.sprites.sprites[0].y, 40);
+        assert_eq!(mem.sprites.affine_params[0].dx, 1.0);
+        assert!(!mem.sprites.hblank_affine_writes);
+        assert!(!mem.raw.dirty.any);
+
+        // writes through the handlers are parsed right away, so aren't left
+        // marked
+        mem.raw.set_halfword(0x5000004, 0x03E0);
+        mem.set_halfword(0x5000004, 0x001F);
+        assert!(!mem.raw.dirty.pal[2]);
+    }
+}
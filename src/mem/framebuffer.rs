@@ -1,55 +1,543 @@
 //! The logic of reading values from OAM/palette/VRAM etc. and determining
 //! what color each pixel on the screen is goes here. Each pixel has 5 bits each
 //! for RGB, and 1 pixel for alpha
+//! Without the render feature none of this is compiled in, and every line is
+//! left blank
 
+use std::fmt;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
 use mem::Memory;
-use mem::oam::Sprite;
+#[cfg(feature = "render")]
+use mem::addrs::VRAM_START;
+use mem::io::graphics::BlendType;
+#[cfg(feature = "render")]
+use mem::io::graphics::WindowSettings;
+use mem::postprocess::PostProcess;
+#[cfg(feature = "debugger")]
+use mem::layer_view::LayerView;
+#[cfg(all(feature = "render", feature = "debugger"))]
+use mem::layer_view::layer_color;
+#[cfg(feature = "render")]
+use mem::oam::{Sprite, GfxMode};
+#[cfg(feature = "render")]
+use mem::reference::RenderCheck;
 
 pub const WIDTH: usize = 240;
 pub const HEIGHT: usize = 160;
 
+const BLANK_FRAME: [[u16; WIDTH]; HEIGHT] = [[0; WIDTH]; HEIGHT];
+
+/// A frame sized buffer kept on the heap. The emulator's state is built in a
+/// const fn (for the static GBA), so it can't allocate up front, and with
+/// every frame inline it's too big to build on the stack. The buffer is
+/// allocated the first time it's written to, and reads as black until then
+pub struct HeapFrame(Option<Box<[[u16; WIDTH]; HEIGHT]>>);
+
+impl HeapFrame {
+    /// the bytes the frame takes up on the heap once it's allocated
+    pub const SIZE: usize = size_of::<[[u16; WIDTH]; HEIGHT]>();
+
+    pub const fn new() -> HeapFrame {
+        HeapFrame(None)
+    }
+}
+
+impl Deref for HeapFrame {
+    type Target = [[u16; WIDTH]; HEIGHT];
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_deref().unwrap_or(&BLANK_FRAME)
+    }
+}
+
+impl DerefMut for HeapFrame {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.get_or_insert_with(|| Box::new(BLANK_FRAME))
+    }
+}
+
+/// the size of the bitmap in mode 5
+pub const MODE5_WIDTH: u32 = 160;
+pub const MODE5_HEIGHT: u32 = 128;
+
+/// sprite tiles always start at this offset into VRAM
+pub const OBJ_TILE_BASE: usize = 0x10000;
+/// in the bitmap modes the bitmap extends into the first half of the sprite
+/// tiles, so only tiles starting from this number can be displayed
+pub const BITMAP_OBJ_MIN_TILE: u32 = 512;
+
+/// what's drawn at every pixel when none of the windows are enabled
+#[cfg(feature = "render")]
+const NO_WINDOW: WindowSettings = WindowSettings {
+    bg: [true; 4],
+    sprite: true,
+    blend: true,
+};
+
 pub struct FrameBuffer {
-    pixels: [[u16; WIDTH]; HEIGHT]
+    pub pixels: [[u16; WIDTH]; HEIGHT],
+    /// for the line currently being drawn, whether each pixel is covered by
+    /// a sprite in OBJ window mode
+    pub obj_window: [bool; WIDTH],
+    /// the OAM indices of the visible sprites on the line currently being
+    /// drawn, in OAM order
+    pub line_sprites: Vec<usize>,
+    /// the columns (start inclusive, end exclusive) covered by the bounds of
+    /// any of the line's sprites. outside of these, sprites aren't checked
+    pub line_sprite_span: (u32, u32),
+    /// the backgrounds that can be drawn on the current line: enabled, and
+    /// used by the current mode
+    pub line_bgs: Vec<usize>,
+    /// effects applied to each finished frame, which produce the frame that's
+    /// shown
+    pub post: PostProcess,
+    /// set while the shown frame is a preview from render_speculative, to the
+    /// first line that was rendered speculatively
+    pub speculative_from: Option<u32>,
+    pub stats: RenderStats,
+    /// which layer won each pixel, see layer_view
+    #[cfg(feature = "debugger")]
+    pub layer_view: LayerView,
+    /// the comparison of each line with the reference renderer, see reference
+    #[cfg(feature = "render")]
+    pub check: RenderCheck,
 }
 
 impl FrameBuffer {
+    /// the bytes of the frames kept on the heap (see HeapFrame), which
+    /// size_of doesn't count: post's previous and output frames, and the
+    /// layer view's
+    pub const HEAP_SIZE: usize =
+        if cfg!(feature = "debugger") { 3 } else { 2 } * HeapFrame::SIZE;
+
     pub const fn new() -> FrameBuffer {
         FrameBuffer {
             pixels: [[0; WIDTH]; HEIGHT],
+            obj_window: [false; WIDTH],
+            line_sprites: Vec::new(),
+            line_sprite_span: (0, 0),
+            line_bgs: Vec::new(),
+            post: PostProcess::new(),
+            speculative_from: None,
+            stats: RenderStats::new(),
+            #[cfg(feature = "debugger")]
+            layer_view: LayerView::new(),
+            #[cfg(feature = "render")]
+            check: RenderCheck::new(),
+        }
+    }
+}
+
+/// Counts of the lines given to the renderer since the last reset. Lines
+/// drawn during forced blank are just filled in, so the share of those is
+/// roughly the share of the renderer's time that was saved. Nothing is drawn
+/// during VBlank, so those lines aren't counted at all
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RenderStats {
+    pub lines_drawn: u64,
+    pub lines_blanked: u64,
+}
+
+impl RenderStats {
+    pub const fn new() -> RenderStats {
+        RenderStats { lines_drawn: 0, lines_blanked: 0 }
+    }
+}
+
+/// One of the layers that make up the screen
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Bg(usize),
+    /// a sprite, by its index in OAM
+    Obj(usize),
+    Backdrop,
+}
+
+impl Layer {
+    /// Return the bit for this layer in BLDCNT
+    #[cfg(feature = "render")]
+    fn blend_bit(&self) -> usize {
+        match *self {
+            Layer::Bg(bg) => bg,
+            Layer::Obj(_) => 4,
+            Layer::Backdrop => 5,
         }
     }
 }
 
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Layer::Bg(bg) => write!(f, "BG{}", bg),
+            Layer::Obj(i) => write!(f, "OBJ {}", i),
+            Layer::Backdrop => write!(f, "backdrop"),
+        }
+    }
+}
+
+/// Where the color of a pixel comes from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PixelSource {
+    pub layer: Layer,
+    /// the tile number, for tiled backgrounds and sprites
+    pub tile: Option<u32>,
+    /// the address in VRAM of the tile's graphics, or of the pixel itself in
+    /// the bitmap modes
+    pub addr: Option<u32>,
+    /// the 16 color palette line, for 4 bit tiles
+    pub palette_line: Option<u8>,
+    pub color: u16,
+}
+
+/// The result of explain_pixel
+#[derive(Debug)]
+pub struct PixelExplanation {
+    /// the layer that's drawn
+    pub top: PixelSource,
+    /// the next visible layer behind it, which is what it would be blended
+    /// with. None if the top layer is the backdrop
+    pub below: Option<PixelSource>,
+    pub blend_mode: BlendType,
+    /// true if the top layer is a first target of the blend, either from
+    /// BLDCNT or by being a semi transparent sprite
+    pub first_target: bool,
+    /// true if the layer below is a second target of the blend
+    pub second_target: bool,
+    /// the color that's drawn, once the effect that applies has been applied
+    pub blended: u16,
+}
+
+/// The result of render_bg_map
+#[cfg(all(feature = "render", feature = "debugger"))]
+pub struct BgMap {
+    /// 256 or 512
+    pub width: u32,
+    /// 256 or 512
+    pub height: u32,
+    /// the top left corner of the viewport in the map
+    pub scroll_x: u32,
+    pub scroll_y: u32,
+    /// width * height 15 bit colors, row by row
+    pub pixels: Vec<u16>,
+}
+
+#[cfg(feature = "render")]
 impl Memory {
+    /// Draw an entire line into the framebuffer. Sprites are evaluated in two
+    /// passes: first the sprites in OBJ window mode, which aren't drawn but
+    /// define the shape of the OBJ window for this line, and then the visible
+    /// sprites as each pixel is drawn
+    pub fn render_scanline(&mut self, row: u32) {
+        self.refresh_parsed_mirrors();
+        #[cfg(feature = "debugger")]
+        self.capture_scanline(row);
+        self.sprites.latch_line();
+        if self.graphics.disp_cnt.forced_blank {
+            self.framebuffer.stats.lines_blanked += 1;
+        } else {
+            self.framebuffer.stats.lines_drawn += 1;
+        }
+        self.draw_line(row);
+        if self.framebuffer.check.enabled {
+            self.check_line(row);
+        }
+    }
+
+    /// Draw a line with the current state. During forced blank the screen is
+    /// white, so there's no need to look at any of the layers
+    fn draw_line(&mut self, row: u32) {
+        if self.graphics.disp_cnt.forced_blank {
+            self.framebuffer.pixels[row as usize] = [0x7FFF; WIDTH];
+            #[cfg(feature = "debugger")]
+            {
+                if self.framebuffer.layer_view.enabled {
+                    self.framebuffer.layer_view.pixels[row as usize] = [0x7FFF; WIDTH];
+                }
+            }
+            return;
+        }
+        self.evaluate_line(row);
+        self.update_obj_window(row);
+        for col in 0..(WIDTH as u32) {
+            self.update_pixel(row, col);
+        }
+    }
+
+    /// Called once every line of a frame has been drawn, to produce the
+    /// frame that gets shown
+    pub fn finish_frame(&mut self) {
+        let framebuffer = &mut self.framebuffer;
+        framebuffer.post.finish_frame(&framebuffer.pixels);
+        framebuffer.speculative_from = None;
+    }
+
+    /// Show the frame as it would look if the lines from first_row on were
+    /// drawn now, for when the debugger pauses mid frame. The lines that have
+    /// already been drawn are kept, and nothing the game can see is changed.
+    /// The result is only a guess, since the game may still change registers
+    /// or VRAM before those lines are really drawn, so the shown frame is
+    /// marked as speculative until the next frame finishes
+    pub fn render_speculative(&mut self, first_row: u32) {
+        self.refresh_parsed_mirrors();
+        let drawn = self.framebuffer.pixels;
+        let line_affine_params = self.sprites.line_affine_params;
+        self.sprites.latch_line();
+        for row in first_row..(HEIGHT as u32) {
+            self.draw_line(row);
+        }
+        self.sprites.line_affine_params = line_affine_params;
+
+        let framebuffer = &mut self.framebuffer;
+        framebuffer.post.show_preview(&framebuffer.pixels);
+        framebuffer.pixels = drawn;
+        framebuffer.speculative_from = Some(first_row);
+    }
+
     /// Update the framebuffer at the given pixel. Will try to render sprites/
     /// backgrounds in order of priority; if there no objects at this pixel then
-    /// use the first background palette color as a fallback
+    /// use the first background palette color as a fallback. The layer below
+    /// is only looked up if the top one could be blended with it
     pub fn update_pixel(&mut self, row: u32, col: u32) {
-        // self.framebuffer.pixels[row as usize][col as usize] = (0..4)
-        //     .filter_map(|i| self.by_priority(i, row, col))
-        //     .next()
-        //     .unwrap_or(self.palette.bg[0])
+        let window = self.window_at(row, col);
+        let (priority, layer, color) = {
+            let mut layers = self.layers(row, col, window);
+            let (priority, top) = layers.next().unwrap();
+            let params = &self.graphics.blend_params;
+            if window.blend && (self.is_semi_transparent(&top) ||
+                    (params.mode != BlendType::Off && params.source[top.layer.blend_bit()])) {
+                let below = layer_below(&top, layers.map(|(_, source)| source));
+                let effect = self.effect(&top, below.as_ref(), window);
+                (priority, top.layer, self.apply_effect(effect, &top, below.as_ref()))
+            } else {
+                (priority, top.layer, top.color)
+            }
+        };
+        self.framebuffer.pixels[row as usize][col as usize] = color;
+        #[cfg(feature = "debugger")]
+        {
+            let view = &mut self.framebuffer.layer_view;
+            if view.enabled {
+                view.pixels[row as usize][col as usize] = layer_color(layer, priority);
+            }
+        }
+        #[cfg(not(feature = "debugger"))]
+        let _ = (priority, layer);
     }
 
-    fn by_priority(&self, priority: u8, row: u32, col: u32) -> Option<u32> {
-        self.render_sprites(priority, row, col)
-            .or_else(|| self.render_bgs(priority, row, col))
+    /// Describe what's drawn at the given pixel and why, using the same
+    /// lookups as the renderer. This uses the current registers and VRAM, so
+    /// while paused mid frame the lines that were already drawn may have used
+    /// different values. Blending is reported as configured, and off where the
+    /// pixel's window turns it off
+    #[cfg(feature = "debugger")]
+    pub fn explain_pixel(&mut self, x: u32, y: u32) -> Option<PixelExplanation> {
+        if x >= WIDTH as u32 || y >= HEIGHT as u32 {
+            return None;
+        }
+        self.evaluate_line(y);
+        self.update_obj_window(y);
+        let window = self.window_at(y, x);
+        let mut layers = self.layers(y, x, window).map(|(_, source)| source);
+        let top = layers.next().unwrap();
+        let below = layer_below(&top, layers);
+
+        let params = &self.graphics.blend_params;
+        let semi_transparent = self.is_semi_transparent(&top);
+        let blend_mode = if !window.blend {
+            BlendType::Off
+        } else if semi_transparent {
+            BlendType::AlphaBlend
+        } else {
+            params.mode
+        };
+        Some(PixelExplanation {
+            top,
+            below,
+            blend_mode,
+            first_target: params.source[top.layer.blend_bit()] || semi_transparent,
+            second_target: below.map_or(false, |below| params.target[below.layer.blend_bit()]),
+            blended: self.apply_effect(self.effect(&top, below.as_ref(), window), &top,
+                below.as_ref()),
+        })
     }
 
-    fn render_sprites(&self, priority: u8, row: u32, col: u32) -> Option<u32> {
-        self.sprites.sprites.iter()
-            .filter(|ref sprite| sprite.priority == priority)
-            .filter_map(|ref sprite| self.render_sprite_pixel(sprite, row, col))
-            .next()
+    /// Return the visible layers at the given pixel from front to back, with
+    /// the priority of each, ending with the backdrop
+    fn layers<'a>(&'a self, row: u32, col: u32, window: WindowSettings)
+            -> impl Iterator<Item = (u8, PixelSource)> + 'a {
+        (0..4).flat_map(move |i| {
+            self.render_sprites(i, row, col, window).into_iter()
+                .chain(self.bg_pixels(i, row, col, window))
+                .map(move |source| (i, source))
+        }).chain(Some((3, self.backdrop())))
     }
 
-    fn render_bgs(&self, priority: u8, row: u32, col: u32) -> Option<u32> {
-        self.graphics.bg_cnt.iter().enumerate()
-            .filter(|(_, bg)| bg.priority == priority)
-            .filter_map(|(i, _)| self.render_bg_pixel(i, row, col))
+    fn is_semi_transparent(&self, source: &PixelSource) -> bool {
+        match source.layer {
+            Layer::Obj(i) => self.sprites.sprites[i].gfx_mode == GfxMode::SemiTransparent,
+            _ => false,
+        }
+    }
+
+    /// Return the color effect applied to a pixel, given its top layer and
+    /// the visible layer below it. Semi transparent sprites are always blended
+    /// with a second target below them, whatever BLDCNT says. Otherwise the
+    /// effect in BLDCNT applies to its first targets, although alpha blending
+    /// needs a second target below too. Nothing applies where the window
+    /// turns effects off
+    fn effect(&self, top: &PixelSource, below: Option<&PixelSource>, window: WindowSettings)
+            -> BlendType {
+        let params = &self.graphics.blend_params;
+        let second_target = below.map_or(false, |below| params.target[below.layer.blend_bit()]);
+        if !window.blend {
+            BlendType::Off
+        } else if self.is_semi_transparent(top) && second_target {
+            BlendType::AlphaBlend
+        } else if !params.source[top.layer.blend_bit()] {
+            BlendType::Off
+        } else {
+            match params.mode {
+                BlendType::AlphaBlend if !second_target => BlendType::Off,
+                mode => mode,
+            }
+        }
+    }
+
+    /// Return the color of a pixel with the given effect applied. Each
+    /// channel is worked out separately, rounding down and saturating at 31
+    fn apply_effect(&self, effect: BlendType, top: &PixelSource, below: Option<&PixelSource>)
+            -> u16 {
+        let graphics = &self.graphics;
+        match (effect, below) {
+            (BlendType::AlphaBlend, Some(below)) => map_channels(top.color, below.color, |a, b| {
+                (a as f32 * graphics.alpha_a_coef + b as f32 * graphics.alpha_b_coef) as u16
+            }),
+            (BlendType::Lighten, _) => map_channels(top.color, 0, |a, _| {
+                a + ((31 - a) as f32 * graphics.brightness_coef) as u16
+            }),
+            (BlendType::Darken, _) => map_channels(top.color, 0, |a, _| {
+                a - (a as f32 * graphics.brightness_coef) as u16
+            }),
+            _ => top.color,
+        }
+    }
+
+    fn backdrop(&self) -> PixelSource {
+        PixelSource {
+            layer: Layer::Backdrop,
+            tile: None,
+            addr: None,
+            palette_line: None,
+            color: self.get_bg_color(0),
+        }
+    }
+
+    /// Return true if the given pixel on the current line is inside the OBJ
+    /// window
+    pub fn in_obj_window(&self, col: u32) -> bool {
+        self.framebuffer.obj_window[col as usize]
+    }
+
+    /// Return the layers that can be drawn at the given pixel on the current
+    /// line. Window 0 takes precedence over window 1, and both over the OBJ
+    /// window; pixels in none of the enabled windows use the settings for
+    /// outside. The backdrop is drawn everywhere regardless
+    pub fn window_at(&self, row: u32, col: u32) -> WindowSettings {
+        let disp_cnt = &self.graphics.disp_cnt;
+        if !disp_cnt.window_enabled[0] && !disp_cnt.window_enabled[1] &&
+                !disp_cnt.obj_win_enabled {
+            return NO_WINDOW;
+        }
+        let settings = &self.graphics.window_settings;
+        for win in 0..2 {
+            if disp_cnt.window_enabled[win] &&
+                    self.graphics.window_coords[win].contains(row, col) {
+                return settings[win];
+            }
+        }
+        if self.in_obj_window(col) {
+            settings[3]
+        } else {
+            settings[2]
+        }
+    }
+
+    /// Work out which sprites and backgrounds can appear on the given line, so
+    /// that the rest don't have to be checked for every pixel
+    fn evaluate_line(&mut self, row: u32) {
+        self.evaluate_sprites(row);
+        let mode = self.graphics.disp_cnt.bg_mode;
+        let mut line_bgs = Vec::new();
+        for bg in 0..4 {
+            if self.graphics.disp_cnt.bg_enabled[bg] && bg_in_mode(mode, bg) {
+                line_bgs.push(bg);
+            }
+        }
+        self.framebuffer.line_bgs = line_bgs;
+    }
+
+    /// Find the sprites that overlap the given line, skipping hidden sprites
+    /// and sprites that are entirely off screen
+    fn evaluate_sprites(&mut self, row: u32) {
+        let line_sprites: Vec<usize> = self.sprites.sprites.iter().enumerate()
+            .filter(|(_, sprite)| sprite.mode.is_visible())
+            .filter(|(_, sprite)| sprite.covers_line(row))
+            .filter(|(_, sprite)| sprite.right > 0 && sprite.left < WIDTH as i16)
+            .map(|(i, _)| i)
+            .collect();
+        let sprites = &self.sprites.sprites;
+        let start = line_sprites.iter().map(|&i| sprites[i].left.max(0) as u32).min();
+        let end = line_sprites.iter().map(|&i| sprites[i].right as u32).max();
+        self.framebuffer.line_sprite_span = (start.unwrap_or(0), end.unwrap_or(0));
+        self.framebuffer.line_sprites = line_sprites;
+    }
+
+    /// Return the sprites on the current line, in OAM order
+    fn line_sprites<'a>(&'a self) -> impl Iterator<Item = &'a Sprite> + 'a {
+        self.framebuffer.line_sprites.iter().map(move |i| &self.sprites.sprites[*i])
+    }
+
+    fn update_obj_window(&mut self, row: u32) {
+        let enabled = self.graphics.disp_cnt.obj_enabled &&
+            self.graphics.disp_cnt.obj_win_enabled;
+        for col in 0..WIDTH {
+            self.framebuffer.obj_window[col] = enabled &&
+                self.line_sprites()
+                    .filter(|sprite| sprite.gfx_mode == GfxMode::ObjWindow)
+                    .filter(|sprite| sprite.covers_column(col as u32))
+                    .any(|sprite| self.sprite_pixel_index(sprite, row, col as u32).is_some());
+        }
+    }
+
+    fn render_sprites(&self, priority: u8, row: u32, col: u32, window: WindowSettings)
+            -> Option<PixelSource> {
+        let (start, end) = self.framebuffer.line_sprite_span;
+        if !self.graphics.disp_cnt.obj_enabled || !window.sprite || col < start || col >= end {
+            return None;
+        }
+        // sprites in OBJ window mode only contribute to the window, so they
+        // should never hide a visible sprite underneath them
+        self.framebuffer.line_sprites.iter()
+            .map(|&i| (i, &self.sprites.sprites[i]))
+            .filter(|(_, sprite)| sprite.priority == priority)
+            .filter(|(_, sprite)| sprite.gfx_mode != GfxMode::ObjWindow)
+            .filter(|(_, sprite)| sprite.covers_column(col))
+            .filter_map(|(i, sprite)| self.render_sprite_pixel(i, sprite, row, col))
             .next()
     }
- 
+
+    /// Return the visible pixels of the backgrounds with the given priority,
+    /// from front to back. When backgrounds have the same priority the lower
+    /// numbered one is drawn on top, which falls out of checking them in order
+    fn bg_pixels<'a>(&'a self, priority: u8, row: u32, col: u32, window: WindowSettings)
+            -> impl Iterator<Item = PixelSource> + 'a {
+        self.framebuffer.line_bgs.iter()
+            .filter(move |&&bg| window.bg[bg])
+            .filter(move |&&bg| self.graphics.bg_cnt[bg].priority == priority)
+            .filter_map(move |&bg| self.render_bg_pixel(bg, row, col))
+    }
+
     // background modes:
     //     tile modes:
     // 0: 4 tile layers (bg0 - bg3)
@@ -60,7 +548,7 @@ impl Memory {
     // 4: 240x160 8 bit bitmap with page flip. the 8 bits here are an index into
     //    the background palette at 0x5000000
     // 5: 160x128 15 bit bitmap with page flip
-    fn render_bg_pixel(&self, bg: usize, row: u32, col: u32) -> Option<u32> {
+    fn render_bg_pixel(&self, bg: usize, row: u32, col: u32) -> Option<PixelSource> {
         match (self.graphics.disp_cnt.bg_mode, bg) {
             (0, _) => self.render_tile_bg(bg, row, col),
             (1, 0) => self.render_tile_bg(bg, row, col),
@@ -75,23 +563,841 @@ impl Memory {
         }
     }
 
-    fn render_sprite_pixel(
-        &self,
-        _sprite: &Sprite,
-        _row: u32,
-        _col: u32) -> Option<u32> {
-        None
+    fn render_sprite_pixel(&self, i: usize, sprite: &Sprite, row: u32, col: u32)
+            -> Option<PixelSource> {
+        self.sprite_pixel(sprite, row, col).map(|(tile, addr, idx)| PixelSource {
+            layer: Layer::Obj(i),
+            tile: Some(tile),
+            addr: Some(addr),
+            palette_line: if sprite.bit_depth == 8 { None } else { Some(sprite.palette_number) },
+            color: self.get_sprite_color(idx),
+        })
+    }
+
+    /// Return the index into the sprite palette of the sprite at the given
+    /// pixel, or None if the sprite doesn't cover the pixel or is transparent
+    /// there
+    fn sprite_pixel_index(&self, sprite: &Sprite, row: u32, col: u32) -> Option<usize> {
+        self.sprite_pixel(sprite, row, col).map(|(_, _, idx)| idx)
+    }
+
+    /// Return the (tile number, tile address, palette index) of the sprite
+    /// at the given pixel
+    fn sprite_pixel(&self, sprite: &Sprite, row: u32, col: u32) -> Option<(u32, u32, usize)> {
+        if !sprite.mode.is_visible() {
+            return None;
+        }
+        // y wraps around at 256 and x is a signed 9 bit value
+        let (bound_width, bound_height) = sprite.bounds();
+        let dx = col.wrapping_sub(sprite.x as u32) & 0x1FF;
+        let dy = row.wrapping_sub(sprite.y as u32) & 0xFF;
+        if dx >= bound_width || dy >= bound_height {
+            return None;
+        }
+        let width = sprite.width as u32;
+        let height = sprite.height as u32;
+        if !sprite.mode.is_affine() {
+            let x = if sprite.hflip { width - 1 - dx } else { dx };
+            let y = if sprite.vflip { height - 1 - dy } else { dy };
+            return self.sprite_tile_texel(sprite, x, y);
+        }
+
+        // affine sprites are transformed around the center of their bounds,
+        // and are cut off outside of the original sprite
+        let params = &self.sprites.line_affine_params[sprite.affine_group as usize];
+        let cx = dx as f32 - (bound_width / 2) as f32;
+        let cy = dy as f32 - (bound_height / 2) as f32;
+        let x = (params.dx * cx + params.dmx * cy).floor() as i32 + (width / 2) as i32;
+        let y = (params.dy * cx + params.dmy * cy).floor() as i32 + (height / 2) as i32;
+        if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+            return None;
+        }
+        self.sprite_tile_texel(sprite, x as u32, y as u32)
     }
 
-    fn render_tile_bg(&self, _bg: usize, _row: u32, _col: u32) -> Option<u32> {
-        None
+    /// Return the index into the sprite palette of the given pixel of the
+    /// sprite's graphics (before flipping), or None if it's transparent
+    pub fn sprite_texel(&self, sprite: &Sprite, x: u32, y: u32) -> Option<usize> {
+        self.sprite_tile_texel(sprite, x, y).map(|(_, _, idx)| idx)
     }
 
-    fn render_affine_bg(&self, _bg: usize, _row: u32, _col: u32) -> Option<u32> {
-        None
+    /// Like sprite_texel, but also return the tile number and address the
+    /// pixel is in
+    fn sprite_tile_texel(&self, sprite: &Sprite, x: u32, y: u32)
+            -> Option<(u32, u32, usize)> {
+        let width = sprite.width as u32;
+        // tiles are 8x8, and the tile number counts in units of 32 bytes (the
+        // size of a 4 bit tile) even when using 8 bit tiles. with 1D mapping
+        // the sprite's tiles are laid out one after the other, and with 2D
+        // mapping each row of tiles starts 32 tiles after the last
+        let tile_units = if sprite.bit_depth == 8 { 2 } else { 1 };
+        let row_stride = if self.graphics.disp_cnt.sprite_2d {
+            32
+        } else {
+            (width / 8) * tile_units
+        };
+        let tile = sprite.tile_number as u32 + (y / 8) * row_stride + (x / 8) * tile_units;
+        if self.graphics.disp_cnt.bg_mode >= 3 && tile % 1024 < BITMAP_OBJ_MIN_TILE {
+            return None;
+        }
+        let (px, py) = (x % 8, y % 8);
+        // an 8 bit tile starting at the last tile number runs off the end of
+        // VRAM, so reads have to be bounds checked
+        let tile_addr = VRAM_START + (OBJ_TILE_BASE as u32) + (tile % 1024) * 32;
+        let idx = if sprite.bit_depth == 8 {
+            self.vram_byte(tile_addr + py * 8 + px) as usize
+        } else {
+            let byte = self.vram_byte(tile_addr + py * 4 + px / 2);
+            let nibble = if px % 2 == 0 { byte & 0xF } else { byte >> 4 };
+            if nibble == 0 {
+                return None;
+            }
+            sprite.palette_number as usize * 16 + nibble as usize
+        };
+        if idx % 256 == 0 { None } else { Some((tile % 1024, tile_addr, idx)) }
+    }
+
+    /// Return the byte of VRAM at the given address, or 0 if it's past the
+    /// end of VRAM (which can happen with a badly configured background or
+    /// sprite). 0 is transparent in every palette, so these pixels aren't
+    /// drawn
+    fn vram_byte(&self, addr: u32) -> u8 {
+        let idx = addr.wrapping_sub(VRAM_START) as usize;
+        self.raw.vram.get(idx).cloned().unwrap_or(0)
+    }
+
+    /// Text backgrounds are made of 32x32 tile screenblocks, each of which
+    /// takes up 2KB of map data. Each map entry is 16 bits:
+    /// F E D C  B A 9 8  7 6 5 4  3 2 1 0
+    /// L L L L  V H T T  T T T T  T T T T
+    /// 0-9 (T) = tile number
+    /// A   (H) = flip horizontally
+    /// B   (V) = flip vertically
+    /// C-F (L) = palette bank (4bpp only)
+    fn render_tile_bg(&self, bg: usize, row: u32, col: u32) -> Option<PixelSource> {
+        let cnt = &self.graphics.bg_cnt[bg];
+        let x = (col + self.graphics.bg_offset_x[bg] as u32) % cnt.width as u32;
+        let y = (row + self.graphics.bg_offset_y[bg] as u32) % cnt.height as u32;
+        self.tile_bg_texel(bg, x, y)
+    }
+
+    /// Return the pixel at the given position in a text background's map,
+    /// ignoring scrolling
+    fn tile_bg_texel(&self, bg: usize, x: u32, y: u32) -> Option<PixelSource> {
+        let cnt = &self.graphics.bg_cnt[bg];
+        let (tile_x, tile_y) = (x / 8, y / 8);
+        let screenblock = (tile_x / 32) + (tile_y / 32) * (cnt.width as u32 / 256);
+        let entry_addr = cnt.map_addr + screenblock * 0x800 +
+            ((tile_y % 32) * 32 + tile_x % 32) * 2;
+        let entry = self.vram_byte(entry_addr) as u32 |
+            (self.vram_byte(entry_addr + 1) as u32) << 8;
+
+        let tile = entry & 0x3FF;
+        let px = if (entry >> 10) & 1 == 1 { 7 - x % 8 } else { x % 8 };
+        let py = if (entry >> 11) & 1 == 1 { 7 - y % 8 } else { y % 8 };
+        let (tile_addr, idx, palette_line) = if cnt.depth == 8 {
+            let tile_addr = cnt.tile_addr + tile * 64;
+            (tile_addr, self.vram_byte(tile_addr + py * 8 + px) as usize, None)
+        } else {
+            let tile_addr = cnt.tile_addr + tile * 32;
+            let byte = self.vram_byte(tile_addr + py * 4 + px / 2);
+            let nibble = if px % 2 == 0 { byte & 0xF } else { byte >> 4 };
+            if nibble == 0 {
+                return None;
+            }
+            let line = (entry >> 12) as u8;
+            (tile_addr, line as usize * 16 + nibble as usize, Some(line))
+        };
+        if idx == 0 {
+            return None;
+        }
+        Some(PixelSource {
+            layer: Layer::Bg(bg),
+            tile: Some(tile),
+            addr: Some(tile_addr),
+            palette_line,
+            color: self.get_bg_color(idx),
+        })
+    }
+
+    /// Rotational backgrounds map each screen pixel to a texture coordinate
+    /// using the affine matrix and reference point. Their map entries are a
+    /// single byte (just the tile number), and their tiles are always 8bpp no
+    /// matter what the depth bit of BGCNT says
+    fn render_affine_bg(&self, bg: usize, row: u32, col: u32) -> Option<PixelSource> {
+        let cnt = &self.graphics.bg_cnt[bg];
+        let params = &self.graphics.bg_affine[bg - 2];
+        let size = cnt.affine_size() as i32;
+        let (col, row) = (col as f32, row as f32);
+        let mut x = (params.ref_x + params.dx * col + params.dmx * row).floor() as i32;
+        let mut y = (params.ref_y + params.dy * col + params.dmy * row).floor() as i32;
+        if x < 0 || y < 0 || x >= size || y >= size {
+            if !cnt.overflow {
+                return None;
+            }
+            x = x.rem_euclid(size);
+            y = y.rem_euclid(size);
+        }
+        let (x, y) = (x as u32, y as u32);
+        let tiles_per_row = size as u32 / 8;
+        let tile = self.vram_byte(cnt.map_addr + (y / 8) * tiles_per_row + x / 8) as u32;
+        let tile_addr = cnt.tile_addr + tile * 64;
+        match self.vram_byte(tile_addr + (y % 8) * 8 + x % 8) {
+            0 => None,
+            idx => Some(PixelSource {
+                layer: Layer::Bg(bg),
+                tile: Some(tile),
+                addr: Some(tile_addr),
+                palette_line: None,
+                color: self.get_bg_color(idx as usize),
+            }),
+        }
     }
 
-    fn render_bitmap_bg(&self, _bg: usize, _row: u32, _col: u32) -> Option<u32> {
-        None
+    fn render_bitmap_bg(&self, bg: usize, row: u32, col: u32) -> Option<PixelSource> {
+        let pixel = row * WIDTH as u32 + col;
+        let (addr, color) = match self.graphics.disp_cnt.bg_mode {
+            3 => {
+                let addr = VRAM_START + pixel * 2;
+                (addr, self.vram_byte(addr) as u16 | (self.vram_byte(addr + 1) as u16) << 8)
+            },
+            4 => {
+                let addr = self.graphics.disp_cnt.frame_base + pixel;
+                match self.vram_byte(addr) {
+                    0 => return None,
+                    idx => (addr, self.get_bg_color(idx as usize)),
+                }
+            },
+            // a smaller bitmap, so that there's room for two pages of 15 bit
+            // colors. the rest of the screen is transparent
+            5 => {
+                if col >= MODE5_WIDTH || row >= MODE5_HEIGHT {
+                    return None;
+                }
+                let addr = self.graphics.disp_cnt.frame_base + (row * MODE5_WIDTH + col) * 2;
+                (addr, self.vram_byte(addr) as u16 | (self.vram_byte(addr + 1) as u16) << 8)
+            },
+            _ => return None,
+        };
+        Some(PixelSource { layer: Layer::Bg(bg), tile: None, addr: Some(addr), palette_line: None, color })
     }
-}
\ No newline at end of file
+}
+
+/// Return the layer that the given top layer would be blended with, from the
+/// visible layers behind it. Sprites are combined into a single layer before
+/// blending, so the layer below a sprite is never another sprite
+#[cfg(feature = "render")]
+fn layer_below<I: Iterator<Item = PixelSource>>(top: &PixelSource, mut layers: I)
+        -> Option<PixelSource> {
+    layers.find(|source| match (top.layer, source.layer) {
+        (Layer::Obj(_), Layer::Obj(_)) => false,
+        _ => true,
+    })
+}
+
+/// Combine the 5 bit channels of two colors one at a time, saturating at 31
+#[cfg(feature = "render")]
+fn map_channels<F: Fn(u16, u16) -> u16>(a: u16, b: u16, f: F) -> u16 {
+    (0..3).map(|i| {
+        let shift = i * 5;
+        f((a >> shift) & 0x1F, (b >> shift) & 0x1F).min(31) << shift
+    }).fold(0, |color, channel| color | channel)
+}
+
+/// The color drawn over the outline of the viewport in a BgMap is the
+/// inverse of the map's color there, so that it shows up on any background
+#[cfg(all(feature = "render", feature = "debugger"))]
+fn outline_color(color: u16) -> u16 {
+    !color & 0x7FFF
+}
+
+#[cfg(all(feature = "render", feature = "debugger"))]
+impl Memory {
+    /// Draw the whole map of a text background, with the part that's scrolled
+    /// onto the screen outlined. Transparent pixels are drawn with the
+    /// backdrop color. Returns None if the background isn't a text background
+    /// in the current mode, since affine and bitmap backgrounds aren't laid out
+    /// in screenblocks. Like explain_pixel this uses the current registers and
+    /// VRAM, so calling it once a frame shows the map as it changes
+    pub fn render_bg_map(&self, bg: usize) -> Option<BgMap> {
+        match (self.graphics.disp_cnt.bg_mode, bg) {
+            (0, 0...3) | (1, 0...1) => (),
+            _ => return None,
+        }
+        let cnt = &self.graphics.bg_cnt[bg];
+        let (width, height) = (cnt.width as u32, cnt.height as u32);
+        let backdrop = self.get_bg_color(0);
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(self.tile_bg_texel(bg, x, y).map_or(backdrop, |source| source.color));
+            }
+        }
+
+        // the viewport wraps around the edges of the map just like the
+        // screen does
+        let scroll_x = self.graphics.bg_offset_x[bg] as u32 % width;
+        let scroll_y = self.graphics.bg_offset_y[bg] as u32 % height;
+        let (w, h) = (WIDTH as u32, HEIGHT as u32);
+        let mut outline = |x: u32, y: u32| {
+            let i = (((scroll_y + y) % height) * width + (scroll_x + x) % width) as usize;
+            pixels[i] = outline_color(pixels[i]);
+        };
+        for x in 0..w {
+            outline(x, 0);
+            outline(x, h - 1);
+        }
+        for y in 1..(h - 1) {
+            outline(0, y);
+            outline(w - 1, y);
+        }
+        Some(BgMap { width, height, scroll_x, scroll_y, pixels })
+    }
+}
+
+/// Return true if the given background is drawn in the given mode
+#[cfg(feature = "render")]
+fn bg_in_mode(mode: u8, bg: usize) -> bool {
+    match mode {
+        0 => true,
+        1 => bg <= 2,
+        2 => bg >= 2,
+        3...5 => bg == 2,
+        _ => false,
+    }
+}
+
+/// The stand-ins used when the renderer is compiled out
+#[cfg(not(feature = "render"))]
+impl Memory {
+    /// Nothing is drawn, but the sprites' affine parameters are still latched
+    /// since they're part of the saved state
+    #[allow(unused_variables)]
+    pub fn render_scanline(&mut self, row: u32) {
+        self.refresh_parsed_mirrors();
+        #[cfg(feature = "debugger")]
+        self.capture_scanline(row);
+        self.sprites.latch_line();
+    }
+
+    pub fn finish_frame(&mut self) {}
+
+    pub fn render_speculative(&mut self, _first_row: u32) {}
+}
+
+#[cfg(all(test, feature = "render"))]
+mod test {
+    use super::*;
+
+    /// write an 8x8 4 bit sprite to OAM entry i, with every pixel of its tile
+    /// set to the given palette index
+    fn make_sprite(mem: &mut Memory, i: u32, x: u32, y: u32, gfx_mode: u32, tile: u32, color: u8) {
+        let oam = 0x7000000 + i * 8;
+        mem.set_halfword(oam, y | (gfx_mode << 10));
+        mem.set_halfword(oam + 2, x);
+        mem.set_halfword(oam + 4, tile);
+        for j in 0..32 {
+            mem.set_byte(0x6010000 + tile * 32 + j, color | (color << 4));
+        }
+    }
+
+    #[test]
+    fn render_speculative() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x5000000, 0x1111);
+        for row in 0..80 {
+            mem.render_scanline(row);
+        }
+        mem.set_halfword(0x5000000, 0x2222);
+        mem.render_speculative(80);
+
+        let output = &mem.framebuffer.post.output;
+        assert_eq!((output[79][0], output[80][0], output[159][239]), (0x1111, 0x2222, 0x2222));
+        // the real lines are still drawn later
+        assert_eq!(mem.framebuffer.pixels[80][0], 0);
+        assert_eq!(mem.framebuffer.speculative_from, Some(80));
+
+        for row in 80..160 {
+            mem.render_scanline(row);
+        }
+        mem.finish_frame();
+        assert_eq!(mem.framebuffer.speculative_from, None);
+    }
+
+    #[test]
+    fn sprite_tile_mapping() {
+        let mut mem = Memory::new();
+        // fill each tile with its own color, and make the palette index the
+        // color so the tile a pixel came from can be read back
+        for tile in 0..128 {
+            let color = (tile % 15 + 1) as u8;
+            for j in 0..32 {
+                mem.set_byte(0x6010000 + tile * 32 + j, color | (color << 4));
+            }
+        }
+        for color in 1..16 {
+            mem.set_halfword(0x5000200 + color * 2, color);
+        }
+        // a 32x32 sprite at the top left using tile 0
+        mem.set_halfword(0x7000000, 0);
+        mem.set_halfword(0x7000002, 0x8000);
+        mem.set_halfword(0x7000004, 0);
+
+        let color_of = |tile: u32| (tile % 15 + 1) as u16;
+        for &(dispcnt, stride) in [(0x1040, 4), (0x1000, 32)].iter() {
+            mem.set_halfword(0x4000000, dispcnt);
+            for &(x, y) in [(0, 0), (8, 0), (0, 8), (8, 16), (24, 24)].iter() {
+                mem.render_scanline(y);
+                assert_eq!(mem.framebuffer.pixels[y as usize][x as usize],
+                    color_of((y / 8) * stride + x / 8),
+                    "DISPCNT {:04X} at ({}, {})", dispcnt, x, y);
+            }
+        }
+
+        // 8 bit tiles take up two tile numbers each, but rows in 2D mapping
+        // are still 32 tile numbers apart
+        for color in 1..16 {
+            mem.set_halfword(0x5000200 + color * 17 * 2, 100 + color);
+        }
+        mem.set_halfword(0x7000000, 1 << 13);
+        mem.render_scanline(8);
+        assert_eq!(mem.framebuffer.pixels[8][8], 100 + color_of(32 + 2));
+    }
+
+    #[test]
+    fn sprite() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1000); // OBJ enabled
+        mem.set_halfword(0x5000000, 0x1111); // backdrop
+        mem.set_halfword(0x5000202, 0x7FFF);
+        make_sprite(&mut mem, 0, 4, 10, 0, 1, 1);
+
+        mem.render_scanline(12);
+        let pixels = &mem.framebuffer.pixels[12];
+        assert_eq!(pixels[3], 0x1111);
+        assert_eq!(pixels[4], 0x7FFF);
+        assert_eq!(pixels[11], 0x7FFF);
+        assert_eq!(pixels[12], 0x1111);
+
+        mem.render_scanline(18);
+        assert_eq!(mem.framebuffer.pixels[18][4], 0x1111);
+    }
+
+    #[test]
+    fn culling() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1401); // mode 1, BG2 and OBJ enabled
+        mem.set_halfword(0x5000202, 0x7FFF);
+        // partly off the left edge, entirely off the right edge, and a
+        // double size affine sprite with only its extra area on screen
+        make_sprite(&mut mem, 0, 508, 0, 0, 1, 1);
+        make_sprite(&mut mem, 1, 240, 0, 0, 1, 1);
+        make_sprite(&mut mem, 2, 500, 0, 0, 1, 1);
+        mem.set_halfword(0x7000010, 0x300);
+
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.line_sprites, vec![0, 2]);
+        assert_eq!(mem.framebuffer.line_sprite_span, (0, 4));
+        assert_eq!(mem.framebuffer.line_bgs, vec![2]);
+        let pixels = &mem.framebuffer.pixels[0];
+        assert_eq!(pixels[3], 0x7FFF);
+        assert_eq!(pixels[4], 0);
+
+        // BG3 isn't drawn in mode 1 even when enabled
+        mem.set_halfword(0x4000000, 0x1F01);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.line_bgs, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn hidden_sprite() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1000);
+        mem.set_halfword(0x5000202, 0x7FFF);
+        make_sprite(&mut mem, 0, 0, 0, 0, 1, 1);
+        mem.set_halfword(0x7000000, 0x200);
+
+        mem.render_scanline(0);
+        assert!(!mem.framebuffer.line_sprites.contains(&0));
+        assert_eq!(mem.framebuffer.pixels[0][0], 0);
+    }
+
+    #[test]
+    fn sprite_past_vram() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1000);
+        mem.set_halfword(0x500020A, 0x7FFF);
+        // an 8 bit sprite using the last tile, whose bottom half would be
+        // past the end of VRAM
+        mem.set_halfword(0x7000000, 0x2000);
+        mem.set_halfword(0x7000004, 1023);
+        mem.set_byte(0x6017FE0, 5);
+
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x7FFF);
+        assert_eq!(mem.framebuffer.pixels[0][1], 0);
+        mem.render_scanline(7);
+        assert_eq!(mem.framebuffer.pixels[7][0], 0);
+    }
+
+    #[test]
+    fn affine_sprite() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1000);
+        mem.set_halfword(0x5000202, 0x7FFF);
+        make_sprite(&mut mem, 0, 0, 0, 0, 1, 1);
+        // double size, with the identity matrix in group 0
+        mem.set_halfword(0x7000000, 0x300);
+        mem.set_halfword(0x7000006, 0x100);
+        mem.set_halfword(0x700001E, 0x100);
+        mem.on_vdraw_hook();
+
+        mem.render_scanline(8);
+        let pixels = &mem.framebuffer.pixels[8];
+        assert_eq!(pixels[3], 0);
+        assert_eq!(pixels[4], 0x7FFF);
+        assert_eq!(pixels[11], 0x7FFF);
+        assert_eq!(pixels[12], 0);
+
+        // scaled up 2x to fill the double size area
+        mem.set_halfword(0x7000006, 0x80);
+        mem.set_halfword(0x700001E, 0x80);
+        mem.on_vdraw_hook();
+        mem.render_scanline(0);
+        let pixels = &mem.framebuffer.pixels[0];
+        assert_eq!(pixels[0], 0x7FFF);
+        assert_eq!(pixels[15], 0x7FFF);
+        assert_eq!(pixels[16], 0);
+    }
+
+    #[test]
+    fn obj_window() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x9000); // OBJ and OBJ window enabled
+        mem.set_halfword(0x400004A, 0x1000); // sprites only in the OBJ window
+        mem.set_halfword(0x5000202, 0x7FFF);
+        mem.set_halfword(0x5000204, 0x001F);
+        // the OBJ window sprite is first in OAM, so it would be drawn over the
+        // normal sprite if it was treated as a regular sprite
+        make_sprite(&mut mem, 0, 0, 0, 2, 1, 2);
+        make_sprite(&mut mem, 1, 4, 0, 0, 2, 1);
+
+        mem.render_scanline(0);
+        for col in 0..8 {
+            assert!(mem.in_obj_window(col));
+        }
+        assert!(!mem.in_obj_window(8));
+        let pixels = &mem.framebuffer.pixels[0];
+        assert_eq!(pixels[0], 0);
+        assert_eq!(pixels[4], 0x7FFF);
+        // outside the OBJ window the sprite is hidden
+        assert_eq!(pixels[11], 0);
+    }
+
+    #[test]
+    fn blending() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1403); // mode 3, BG2 and OBJ enabled
+        mem.set_halfword(0x5000000, 0x7C00);
+        mem.set_halfword(0x5000202, 0x03E0);
+        for col in 0..WIDTH as u32 {
+            mem.set_halfword(0x6000000 + col * 2, 0x001F);
+        }
+        make_sprite(&mut mem, 0, 8, 0, 1, 512, 1); // semi transparent
+
+        // BG2 alpha blended with the backdrop, half and half
+        mem.set_halfword(0x4000050, 0x2044);
+        mem.set_halfword(0x4000052, 0x0808);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x3C0F);
+        // BG2 isn't a second target, so the sprite is drawn as is
+        assert_eq!(mem.framebuffer.pixels[0][8], 0x03E0);
+
+        // the sprite is blended with BG2 once it is, even without being a
+        // first target itself
+        mem.set_halfword(0x4000050, 0x2444);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][8], 0x01EF);
+        // channels saturate at 31
+        mem.set_halfword(0x4000052, 0x1010);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x7C1F);
+        assert_eq!(mem.framebuffer.pixels[0][8], 0x03FF);
+
+        // brightening moves each channel towards 31, and darkening towards 0
+        mem.set_halfword(0x4000050, 0x0084);
+        mem.set_halfword(0x4000054, 8);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x3DFF);
+        assert_eq!(mem.framebuffer.pixels[0][8], 0x03E0);
+        mem.set_halfword(0x4000050, 0x00C4);
+        mem.set_halfword(0x4000054, 0x1F);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0);
+
+        // and nothing is applied in a window with effects turned off
+        mem.set_halfword(0x4000000, 0x3403);
+        mem.set_halfword(0x4000040, 0 << 8 | 4);
+        mem.set_halfword(0x4000044, 0 << 8 | 1);
+        mem.set_halfword(0x4000048, 0x0014);
+        mem.set_halfword(0x400004A, 0x0034);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x001F);
+        assert_eq!(mem.framebuffer.pixels[0][4], 0);
+    }
+
+    #[test]
+    fn windows() {
+        let mut mem = Memory::new();
+        // mode 3 with BG2 and OBJ, and both windows
+        mem.set_halfword(0x4000000, 0x7403);
+        mem.set_halfword(0x5000202, 0x001F);
+        make_sprite(&mut mem, 0, 0, 0, 0, 512, 1);
+        for col in 0..WIDTH as u32 {
+            mem.set_halfword(0x6000000 + col * 2, 0x03E0);
+        }
+        // window 0 covers 4..12 and only shows BG2, window 1 covers 8..20 and
+        // only shows sprites, and outside them nothing but the backdrop
+        mem.set_halfword(0x4000040, 4 << 8 | 12);
+        mem.set_halfword(0x4000044, 0 << 8 | 1);
+        mem.set_halfword(0x4000042, 8 << 8 | 20);
+        mem.set_halfword(0x4000046, 0 << 8 | 1);
+        mem.set_halfword(0x4000048, 0x10_04);
+        mem.set_halfword(0x400004A, 0x00_00);
+        mem.render_scanline(0);
+        let pixels = mem.framebuffer.pixels[0];
+        assert_eq!(pixels[3], 0);
+        // the sprite would be in front of BG2, but window 0 hides it
+        assert_eq!(pixels[4], 0x03E0);
+        assert_eq!(pixels[7], 0x03E0);
+        // window 0 takes precedence where they overlap
+        assert_eq!(pixels[11], 0x03E0);
+        // the sprite ends at column 8, so window 1 shows the backdrop after
+        assert_eq!(pixels[12], 0);
+        assert_eq!(pixels[20], 0);
+
+        // the windows only cover the first line
+        mem.render_scanline(1);
+        assert!(mem.framebuffer.pixels[1].iter().all(|pixel| *pixel == 0));
+
+        // with the windows off everything is drawn again
+        mem.set_halfword(0x4000000, 0x1403);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x001F);
+        assert_eq!(mem.framebuffer.pixels[0][8], 0x03E0);
+    }
+
+    #[test]
+    fn mode3_priority() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1403); // mode 3, BG2 and OBJ enabled
+        mem.set_halfword(0x400000C, 1); // BG2 priority 1
+        mem.set_halfword(0x6000000, 0x1234);
+        mem.set_halfword(0x6000002, 0x4321);
+        mem.set_halfword(0x5000202, 0x7FFF);
+        // the first sprite is hidden since its tiles overlap with the bitmap
+        make_sprite(&mut mem, 0, 0, 0, 0, 1, 1);
+        make_sprite(&mut mem, 1, 1, 0, 0, 512, 1);
+
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x1234);
+        assert_eq!(mem.framebuffer.pixels[0][1], 0x7FFF);
+
+        // lower priority than BG2
+        mem.set_halfword(0x700000C, 512 | (2 << 10));
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][1], 0x4321);
+    }
+
+    #[test]
+    fn mode5() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x0405); // mode 5 page 0, BG2 enabled
+        mem.set_halfword(0x5000000, 0x0001);
+        mem.set_halfword(0x6000000, 0x1234);
+        mem.set_halfword(0x600013E, 0x2345);
+        mem.set_halfword(0x6000140, 0x3456);
+        mem.set_halfword(0x600A000, 0x4567);
+
+        mem.render_scanline(0);
+        {
+            let pixels = &mem.framebuffer.pixels[0];
+            assert_eq!(pixels[0], 0x1234);
+            assert_eq!(pixels[159], 0x2345);
+            // the rest of the line shows the backdrop
+            assert_eq!(pixels[160], 0x0001);
+        }
+        mem.render_scanline(1);
+        assert_eq!(mem.framebuffer.pixels[1][0], 0x3456);
+        mem.render_scanline(128);
+        assert_eq!(mem.framebuffer.pixels[128][0], 0x0001);
+
+        // page 1
+        mem.set_halfword(0x4000000, 0x0415);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x4567);
+    }
+
+    #[test]
+    fn mode4_priority() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1414); // mode 4 page 1, BG2 and OBJ enabled
+        mem.set_halfword(0x400000C, 1);
+        mem.set_halfword(0x5000000, 0x1111);
+        mem.set_halfword(0x5000006, 0x2222);
+        mem.set_halfword(0x5000202, 0x7FFF);
+        mem.set_halfword(0x600A000, 0x0303);
+        make_sprite(&mut mem, 0, 1, 0, 0, 512, 1);
+        mem.set_halfword(0x7000004, 512 | (2 << 10));
+
+        mem.render_scanline(0);
+        let pixels = &mem.framebuffer.pixels[0];
+        assert_eq!(pixels[0], 0x2222);
+        assert_eq!(pixels[1], 0x2222);
+        // index 0 is transparent so the lower priority sprite shows through
+        assert_eq!(pixels[2], 0x7FFF);
+        assert_eq!(pixels[9], 0x1111);
+    }
+
+    #[test]
+    fn bg_priority_ties() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x0300); // mode 0, BG0 and BG1 enabled
+        mem.set_halfword(0x4000008, 8 << 8); // BG0 map at 0x6004000
+        mem.set_halfword(0x400000A, 9 << 8); // BG1 map at 0x6004800
+        mem.set_halfword(0x6004000, 1);
+        mem.set_halfword(0x6004800, 2);
+        mem.set_halfword(0x6000020, 0x0001);
+        mem.set_halfword(0x6000040, 0x0002);
+        mem.set_halfword(0x5000002, 0x001F);
+        mem.set_halfword(0x5000004, 0x03E0);
+
+        // same priority, so the lower numbered BG is on top
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x001F);
+
+        mem.set_halfword(0x4000008, (8 << 8) | 1);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x03E0);
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn explain_pixel() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1300); // mode 0, BG0, BG1 and OBJ enabled
+        mem.set_halfword(0x4000008, (8 << 8) | 1); // BG0 map at 0x6004000
+        mem.set_halfword(0x400000A, (9 << 8) | 2); // BG1 map at 0x6004800
+        // tile 1 of BG0 with palette line 3, tile 2 of BG1
+        mem.set_halfword(0x6004000, 0x3001);
+        mem.set_halfword(0x6004800, 2);
+        mem.set_word(0x6000020, 0x11111111);
+        mem.set_word(0x6000040, 0x22222222);
+        mem.set_halfword(0x5000062, 0x001F);
+        make_sprite(&mut mem, 5, 4, 0, 1, 3, 1);
+        mem.set_halfword(0x700002C, 3 | (1 << 12)); // palette 1
+        mem.set_halfword(0x5000222, 0x7FFF);
+        // BG0 and OBJ blended on top of BG1
+        mem.set_halfword(0x4000050, 0x0211);
+
+        let info = mem.explain_pixel(0, 0).unwrap();
+        assert_eq!(info.top, PixelSource {
+            layer: Layer::Bg(0),
+            tile: Some(1),
+            addr: Some(0x6000020),
+            palette_line: Some(3),
+            color: 0x001F,
+        });
+        assert_eq!(info.below.unwrap().layer, Layer::Bg(1));
+        assert_eq!(info.below.unwrap().tile, Some(2));
+        assert_eq!(info.blend_mode, BlendType::Off);
+        assert!(info.first_target && info.second_target);
+        assert_eq!(info.blended, 0x001F);
+
+        // the semi transparent sprite has the highest priority
+        let info = mem.explain_pixel(4, 0).unwrap();
+        assert_eq!(info.top.layer, Layer::Obj(5));
+        assert_eq!(info.top.tile, Some(3));
+        assert_eq!(info.top.addr, Some(0x6010060));
+        assert_eq!(info.top.palette_line, Some(1));
+        assert_eq!(info.top.color, 0x7FFF);
+        assert_eq!(info.below.unwrap().layer, Layer::Bg(0));
+        assert_eq!(info.blend_mode, BlendType::AlphaBlend);
+        assert!(info.first_target && !info.second_target);
+        // BG0 isn't a second target, so it isn't blended after all
+        assert_eq!(info.blended, 0x7FFF);
+
+        // BG0 and BG1 are transparent past the first tile
+        let info = mem.explain_pixel(20, 0).unwrap();
+        assert_eq!(info.top.layer, Layer::Backdrop);
+        assert_eq!(info.below, None);
+        assert!(mem.explain_pixel(240, 0).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn render_bg_map() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x0100); // mode 0, BG0 enabled
+        mem.set_halfword(0x4000008, (1 << 14) | (8 << 8)); // 512x256, map at 0x6004000
+        // tile 1 at the top left of the second screenblock
+        mem.set_halfword(0x6004800, 1);
+        mem.set_word(0x6000020, 0x11111111);
+        mem.set_halfword(0x5000000, 0x1234);
+        mem.set_halfword(0x5000002, 0x001F);
+        mem.set_halfword(0x4000010, 300);
+        mem.set_halfword(0x4000012, 10);
+
+        let map = mem.render_bg_map(0).unwrap();
+        assert_eq!((map.width, map.height), (512, 256));
+        assert_eq!((map.scroll_x, map.scroll_y), (300, 10));
+        assert_eq!(map.pixels.len(), 512 * 256);
+        assert_eq!(map.pixels[256], 0x001F);
+        assert_eq!(map.pixels[263], 0x001F);
+        assert_eq!(map.pixels[264], 0x1234);
+        // the viewport's corners, the right side having wrapped around
+        let outline = !0x1234 & 0x7FFF;
+        assert_eq!(map.pixels[10 * 512 + 300], outline);
+        assert_eq!(map.pixels[169 * 512 + 300], outline);
+        assert_eq!(map.pixels[10 * 512 + 27], outline);
+        assert_eq!(map.pixels[169 * 512 + 27], outline);
+        assert_eq!(map.pixels[100 * 512 + 100], 0x1234);
+
+        // BG2 is affine in mode 1
+        assert!(mem.render_bg_map(2).is_some());
+        mem.set_halfword(0x4000000, 0x0101);
+        assert!(mem.render_bg_map(2).is_none());
+    }
+
+    #[test]
+    fn affine_bg_depth() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x0401); // mode 1, BG2 enabled
+        // 4bpp depth bit, which should be ignored
+        mem.set_halfword(0x400000C, 8 << 8);
+        mem.set_halfword(0x4000020, 0x0100);
+        mem.set_halfword(0x4000026, 0x0100);
+        mem.set_halfword(0x6004000, 1);
+        mem.set_halfword(0x6000040, 0x0021);
+        mem.set_halfword(0x5000042, 0x7C00);
+
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x7C00);
+        // outside the 128x128 background without wraparound
+        assert_eq!(mem.framebuffer.pixels[0][200], 0);
+    }
+
+    #[test]
+    fn forced_blank() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x5000000, 0x1234);
+        mem.set_halfword(0x4000000, 0x0080);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.pixels[0], [0x7FFF; WIDTH]);
+
+        mem.set_halfword(0x4000000, 0);
+        mem.render_scanline(1);
+        assert_eq!(mem.framebuffer.pixels[1][0], 0x1234);
+        assert_eq!(mem.framebuffer.stats, RenderStats { lines_drawn: 1, lines_blanked: 1 });
+    }
+}
@@ -24,6 +24,39 @@ pub const BLDALPHA_HI: u32 = 0x4000053;
 pub const BLDY: u32 = 0x4000054;
 pub const GRAPHICS_END: u32 = 0x4000055;
 
+// SOUND
+pub const SOUND_START: u32 = 0x4000060;
+pub const SOUND1CNT_L: u32 = 0x4000060;
+pub const SOUND1CNT_H: u32 = 0x4000062;
+pub const SOUND1CNT_H_HI: u32 = 0x4000063;
+pub const SOUND1CNT_X: u32 = 0x4000064;
+pub const SOUND1CNT_X_HI: u32 = 0x4000065;
+pub const SOUND2CNT_L: u32 = 0x4000068;
+pub const SOUND2CNT_L_HI: u32 = 0x4000069;
+pub const SOUND2CNT_H: u32 = 0x400006C;
+pub const SOUND2CNT_H_HI: u32 = 0x400006D;
+pub const SOUND3CNT_L: u32 = 0x4000070;
+pub const SOUND3CNT_H: u32 = 0x4000072;
+pub const SOUND3CNT_H_HI: u32 = 0x4000073;
+pub const SOUND3CNT_X: u32 = 0x4000074;
+pub const SOUND3CNT_X_HI: u32 = 0x4000075;
+pub const SOUND4CNT_L: u32 = 0x4000078;
+pub const SOUND4CNT_L_HI: u32 = 0x4000079;
+pub const SOUND4CNT_H: u32 = 0x400007C;
+pub const SOUND4CNT_H_HI: u32 = 0x400007D;
+pub const SOUNDCNT_L: u32 = 0x4000080;
+pub const SOUNDCNT_L_HI: u32 = 0x4000081;
+pub const SOUNDCNT_H: u32 = 0x4000082;
+pub const SOUNDCNT_H_HI: u32 = 0x4000083;
+pub const SOUNDCNT_X: u32 = 0x4000084;
+pub const SOUNDBIAS: u32 = 0x4000088;
+pub const SOUNDBIAS_HI: u32 = 0x4000089;
+pub const WAVE_RAM: u32 = 0x4000090;
+pub const WAVE_RAM_END: u32 = 0x400009F;
+pub const FIFO_A: u32 = 0x40000A0;
+pub const FIFO_B: u32 = 0x40000A4;
+pub const FIFO_B_END: u32 = 0x40000A7;
+
 // DMA
 pub const DMA_START: u32 = 0x40000B0;
 pub const DMA_END: u32 = 0x40000DF;
@@ -31,6 +64,28 @@ pub const DMA_SAD: [u32; 4] = [0x40000B0, 0x40000BC, 0x40000C8, 0x40000D4];
 pub const DMA_DAD: [u32; 4] = [0x40000B4, 0x40000C0, 0x40000CC, 0x40000D8];
 pub const DMA_CNT: [u32; 4] = [0x40000BA, 0x40000C6, 0x40000D2, 0x40000DE];
 
+// TIMERS
+pub const TIMER_START: u32 = 0x4000100;
+pub const TMCNT_L: [u32; 4] = [0x4000100, 0x4000104, 0x4000108, 0x400010C];
+pub const TMCNT_H: [u32; 4] = [0x4000102, 0x4000106, 0x400010A, 0x400010E];
+pub const TIMER_END: u32 = 0x400010F;
+
+// SERIAL
+pub const SERIAL_START: u32 = 0x4000120;
+pub const SIOMULTI: [u32; 4] = [0x4000120, 0x4000122, 0x4000124, 0x4000126];
+pub const SIODATA32: u32 = 0x4000120;
+pub const SIODATA8: u32 = 0x400012A;
+pub const SIOCNT: u32 = 0x4000128;
+pub const SIOMLT_SEND: u32 = 0x400012A;
+pub const SERIAL_END: u32 = 0x400012B;
+pub const RCNT: u32 = 0x4000134;
+pub const RCNT_HI: u32 = 0x4000135;
+
+// KEYPAD
+/// reads as the buttons that aren't pressed
+pub const KEYINPUT: u32 = 0x4000130;
+pub const KEYCNT: u32 = 0x4000132;
+
 // INTERRUPTS
 pub const INT_START: u32 = 0x4000200;
 pub const IE_LO: u32 = 0x4000200;
@@ -38,5 +93,14 @@ pub const IE_HI: u32 = 0x4000201;
 pub const IF_LO: u32 = 0x4000202;
 pub const IF_HI: u32 = 0x4000203;
 pub const IME: u32 = 0x4000208;
-pub const WSCNT_LO: u32 = 0x4000204;
-pub const INT_END: u32 = 0x4000208;
\ No newline at end of file
+pub const INT_END: u32 = 0x4000208;
+
+// WAITCNT
+pub const WAITCNT_LO: u32 = 0x4000204;
+pub const WAITCNT_HI: u32 = 0x4000205;
+// DEBUG OUTPUT (mGBA compatible, see debug)
+pub const DEBUG_START: u32 = 0x4FFF600;
+pub const DEBUG_STRING_END: u32 = 0x4FFF6FF;
+pub const DEBUG_FLAGS: u32 = 0x4FFF700;
+pub const DEBUG_ENABLE: u32 = 0x4FFF780;
+pub const DEBUG_END: u32 = 0x4FFF781;
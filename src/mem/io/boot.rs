@@ -0,0 +1,66 @@
+//! The values the BIOS leaves in the IO registers by the time it jumps to the
+//! cartridge. Every other register is still zero from power on. A direct boot
+//! skips the BIOS, so these are written in its place, and games that read a
+//! register before setting it (e.g. checking KEYINPUT for a held button, or
+//! setting one bit of SOUNDBIAS) see what they would on hardware.
+
+use mem::Memory;
+
+pub struct BootValue {
+    pub name: &'static str,
+    pub addr: u32,
+    pub value: u16,
+}
+
+const fn boot(name: &'static str, addr: u32, value: u16) -> BootValue {
+    BootValue { name, addr, value }
+}
+
+pub const POST_BOOT_IO: &[BootValue] = &[
+    // the screen is left blanked until the game has set it up
+    boot("DISPCNT", 0x4000000, 0x0080),
+    // the identity matrix for the affine backgrounds
+    boot("BG2PA", 0x4000020, 0x0100),
+    boot("BG2PD", 0x4000026, 0x0100),
+    boot("BG3PA", 0x4000030, 0x0100),
+    boot("BG3PD", 0x4000036, 0x0100),
+    // the bias level is halfway, at the resolution with the highest rate
+    boot("SOUNDBIAS", 0x4000088, 0x0200),
+    // no buttons are pressed
+    boot("KEYINPUT", 0x4000130, 0x03FF),
+    // the serial port is in general purpose mode
+    boot("RCNT", 0x4000134, 0x8000),
+    // the BIOS doesn't touch the waitstates, so the game pak is still at its
+    // slowest settings until the game sets its own
+    boot("WAITCNT", 0x4000204, 0x0000),
+    // set by the BIOS once it's booted, and read by games on soft reset
+    boot("POSTFLG", 0x4000300, 0x0001),
+];
+
+impl Memory {
+    /// Set the IO registers to the values the BIOS leaves behind. They're
+    /// written like any other write, so that the parsed registers agree
+    pub fn apply_post_boot_io(&mut self) {
+        for reg in POST_BOOT_IO {
+            self.set_halfword(reg.addr, reg.value as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn post_boot() {
+        let mut mem = Memory::new();
+        mem.apply_post_boot_io();
+        for reg in POST_BOOT_IO {
+            assert_eq!(mem.get_halfword(reg.addr), reg.value, "{}", reg.name);
+        }
+        assert!(mem.graphics.disp_cnt.forced_blank);
+        assert_eq!(mem.graphics.bg_affine[0].dx, 1.0);
+        assert_eq!(mem.graphics.bg_affine[1].dmy, 1.0);
+        assert!(mem.io_invariant_violations().is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+//! A debug output channel that homebrew can print to, using the same
+//! registers as mGBA so that existing logging code works unchanged. These
+//! don't exist on the GBA, so the channel is only available in dev mode.
+//! 0x4FFF600 - 0x4FFF6FF: the message, terminated by a 0 byte if shorter
+//! 0x4FFF700: flags, written to send the message
+//!   F E D C  B A 9 8  7 6 5 4  3 2 1 0
+//!   X X X X  X X X S  X X X X  X L L L
+//!   0-2 (L) = level (fatal, error, warn, info, debug)
+//!   8   (S) = send the message and clear it
+//! 0x4FFF780: write 0xC0DE to enable the channel, after which it reads as
+//!            0x1DEA
+
+use super::addrs::*;
+use mem::Memory;
+
+/// the value written to DEBUG_ENABLE to enable the channel
+const ENABLE_REQUEST: u16 = 0xC0DE;
+/// the value DEBUG_ENABLE reads as while enabled
+const ENABLE_RESPONSE: u16 = 0x1DEA;
+/// messages that haven't been taken by the frontend yet are dropped, oldest
+/// first, past this many
+pub const MAX_MESSAGES: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugLevel {
+    Fatal,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl DebugLevel {
+    fn from_flags(flags: u16) -> DebugLevel {
+        match flags & 0b111 {
+            0 => DebugLevel::Fatal,
+            1 => DebugLevel::Error,
+            2 => DebugLevel::Warn,
+            3 => DebugLevel::Info,
+            _ => DebugLevel::Debug,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DebugLevel::Fatal => "fatal",
+            DebugLevel::Error => "error",
+            DebugLevel::Warn => "warn",
+            DebugLevel::Info => "info",
+            DebugLevel::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugMessage {
+    pub level: DebugLevel,
+    pub text: String,
+}
+
+pub struct DebugOutput {
+    /// whether the registers exist at all. When false they read as 0 and
+    /// writes are ignored, like on hardware
+    pub available: bool,
+    /// set once the game has written ENABLE_REQUEST
+    pub enabled: bool,
+    enable_reg: u16,
+    flags: u16,
+    buffer: [u8; 0x100],
+    /// messages sent by the game, oldest first
+    pub messages: Vec<DebugMessage>,
+}
+
+impl DebugOutput {
+    pub const fn new() -> DebugOutput {
+        DebugOutput {
+            available: false,
+            enabled: false,
+            enable_reg: 0,
+            flags: 0,
+            buffer: [0; 0x100],
+            messages: Vec::new(),
+        }
+    }
+
+    /// Remove and return all messages sent so far
+    pub fn take_messages(&mut self) -> Vec<DebugMessage> {
+        std::mem::replace(&mut self.messages, Vec::new())
+    }
+
+    fn send(&mut self) {
+        let len = self.buffer.iter().position(|b| *b == 0).unwrap_or(self.buffer.len());
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(DebugMessage {
+            level: DebugLevel::from_flags(self.flags),
+            text: String::from_utf8_lossy(&self.buffer[..len]).into_owned(),
+        });
+        self.buffer = [0; 0x100];
+    }
+}
+
+/// Replace the given byte (0 or 1) of a 16 bit register
+fn set_reg_byte(reg: u16, byte: u32, val: u8) -> u16 {
+    let shift = 8 * (byte & 1);
+    (reg & !(0xFF << shift)) | ((val as u16) << shift)
+}
+
+impl Memory {
+    pub fn get_debug_byte(&self, addr: u32) -> u8 {
+        let debug = &self.debug;
+        if !debug.available {
+            return 0;
+        }
+        let shift = 8 * (addr & 1);
+        match addr {
+            DEBUG_START...DEBUG_STRING_END => debug.buffer[(addr - DEBUG_START) as usize],
+            DEBUG_FLAGS...0x4FFF701 => (debug.flags >> shift) as u8,
+            DEBUG_ENABLE...DEBUG_END if debug.enabled => (ENABLE_RESPONSE >> shift) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn update_debug_byte(&mut self, addr: u32, val: u8) {
+        let debug = &mut self.debug;
+        if !debug.available {
+            return;
+        }
+        match addr {
+            DEBUG_START...DEBUG_STRING_END if debug.enabled =>
+                debug.buffer[(addr - DEBUG_START) as usize] = val,
+            DEBUG_FLAGS...0x4FFF701 if debug.enabled => {
+                debug.flags = set_reg_byte(debug.flags, addr, val);
+                if addr == DEBUG_FLAGS + 1 && val & 1 == 1 {
+                    debug.send();
+                }
+            },
+            DEBUG_ENABLE...DEBUG_END => {
+                debug.enable_reg = set_reg_byte(debug.enable_reg, addr, val);
+                debug.enabled = debug.enable_reg == ENABLE_REQUEST;
+            },
+            _ => (),
+        }
+    }
+
+    pub fn update_debug_hw(&mut self, addr: u32, val: u32) {
+        self.update_debug_byte(addr, val as u8);
+        self.update_debug_byte(addr + 1, (val >> 8) as u8);
+    }
+
+    pub fn update_debug_word(&mut self, addr: u32, val: u32) {
+        self.update_debug_hw(addr, val & 0xFFFF);
+        self.update_debug_hw(addr + 2, val >> 16);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn print() {
+        let mut mem = Memory::new();
+        mem.set_halfword(DEBUG_ENABLE, ENABLE_REQUEST as u32);
+        assert_eq!(mem.get_halfword(DEBUG_ENABLE), 0);
+
+        mem.debug.available = true;
+        mem.set_halfword(DEBUG_ENABLE, ENABLE_REQUEST as u32);
+        assert_eq!(mem.get_halfword(DEBUG_ENABLE), ENABLE_RESPONSE);
+        for (i, b) in b"hi!".iter().enumerate() {
+            mem.set_byte(DEBUG_START + i as u32, *b);
+        }
+        mem.set_halfword(DEBUG_FLAGS, 0x103);
+        mem.set_word(DEBUG_START, 0x00006B6F); // "ok"
+        mem.set_halfword(DEBUG_FLAGS, 0x102);
+        assert_eq!(mem.debug.take_messages(), vec![
+            DebugMessage { level: DebugLevel::Info, text: "hi!".to_string() },
+            DebugMessage { level: DebugLevel::Warn, text: "ok".to_string() },
+        ]);
+        assert!(mem.debug.messages.is_empty());
+        assert_eq!(mem.get_byte(DEBUG_START), 0);
+    }
+}
@@ -8,14 +8,48 @@
 //! bits to certain locations which sets the parameters of DMA. When a transfer
 //! is requested, the DMA controller takes over the hardware and the CPU is halted
 //! until the transfer is complete.
+//!
+//! Transfers are started by edges rather than levels: an immediate transfer
+//! starts when its channel's enabled bit goes from 0 to 1, and a VBlank or
+//! HBlank transfer each time that period begins while the channel is enabled.
+//! A started transfer is kept pending until check_dma runs it, and each
+//! channel runs at most once per call, so transfers that keep enabling each
+//! other's channels can't keep the CPU halted forever. Nor can a transfer
+//! with the repeat bit set along with immediate timing, which the hardware
+//! runs just once.
 
 use num::FromPrimitive;
+use std::fmt;
 use super::addrs::*;
-use mem::Memory;
+use error::AccessKind;
+use mem::{Memory, canonicalize_addr};
+use mem::watch::Source;
+use mem::addrs::{IO_START, IO_END, PAL_START, PAL_END, OAM_START, OAM_END};
 use util;
 
+/// a transfer that starts in the IO registers and writes more than this many
+/// bytes covers the whole IO region, so it can't be what the game meant to do
+pub const RUNAWAY_BYTES: u32 = 0x400;
+
 pub struct DMA {
     pub channels: [DMAChannel; 4],
+    /// debug overrides of the enabled bit of each channel, which are checked
+    /// whenever a transfer would be triggered. these aren't visible to the game
+    pub overrides: [Option<bool>; 4],
+    /// cycles spent on transfers that the CPU hasn't been stalled for yet
+    pending_cycles: u32,
+    /// cycles taken so far by the transfer that's running
+    transfer_cycles: u32,
+    /// totals for each channel since the last reset, for profiling
+    pub stats: [DMAStats; 4],
+    /// the last runaway transfer reported for each channel, so that a repeating
+    /// transfer is only reported once
+    last_runaway: [Option<RunawayDma>; 4],
+    /// reports that haven't been taken by the frontend yet
+    runaways: Vec<RunawayDma>,
+    /// set while check_dma is running transfers, so that a transfer started
+    /// by another one is run by the same loop instead of a nested call
+    in_transfer: bool,
 }
 
 impl DMA {
@@ -26,34 +60,118 @@ impl DMA {
                 DMAChannel::new(),
                 DMAChannel::new(),
                 DMAChannel::new(),
-            ]
+            ],
+            overrides: [None; 4],
+            pending_cycles: 0,
+            transfer_cycles: 0,
+            stats: [DMAStats::new(); 4],
+            last_runaway: [None; 4],
+            runaways: Vec::new(),
+            in_transfer: false,
         }
     }
+
+    /// Return the number of cycles taken by transfers since the last call.
+    /// The CPU is halted during a transfer, so these should be added to the
+    /// time taken by the current instruction
+    pub fn take_cycles(&mut self) -> u32 {
+        std::mem::replace(&mut self.pending_cycles, 0)
+    }
+
+    /// Return the number of cycles the CPU will be stalled for by the
+    /// transfers since the last call to take_cycles, up to and including the
+    /// chunk being copied now
+    pub fn elapsed(&self) -> u32 {
+        self.pending_cycles + self.transfer_cycles
+    }
+
+    /// Remove and return the runaway transfers reported so far
+    pub fn take_runaways(&mut self) -> Vec<RunawayDma> {
+        std::mem::replace(&mut self.runaways, Vec::new())
+    }
+
+    /// Return the channels that have made a runaway transfer, including ones
+    /// whose reports have already been taken
+    pub fn runaway_channels(&self) -> Vec<usize> {
+        (0..4).filter(|i| self.last_runaway[*i].is_some()).collect()
+    }
+
+    fn report_runaway(&mut self, runaway: RunawayDma) {
+        if self.last_runaway[runaway.channel] != Some(runaway) {
+            self.last_runaway[runaway.channel] = Some(runaway);
+            self.runaways.push(runaway);
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = [DMAStats::new(); 4];
+    }
+
+    fn record(&mut self, channel: usize, chunks: u32, cycles: u32) {
+        self.pending_cycles += cycles;
+        let stats = &mut self.stats[channel];
+        stats.transfers += 1;
+        stats.chunks += chunks as u64;
+        stats.cycles += cycles as u64;
+    }
+
+    /// Force a channel to be enabled or disabled regardless of what the game
+    /// writes to its control register, or remove the override with None. Note
+    /// that forcing a channel on that hasn't been set up will keep copying
+    /// every VBlank or HBlank if that's its timing mode. Immediate transfers
+    /// still only start when the game enables the channel, and one started
+    /// while the channel is forced off waits for the override to be removed
+    pub fn set_enabled_override(&mut self, channel: usize, enabled: Option<bool>) {
+        self.overrides[channel] = enabled;
+    }
+
+    /// Return whether the given channel should run when its timing triggers
+    pub fn is_enabled(&self, channel: usize) -> bool {
+        self.overrides[channel].unwrap_or(self.channels[channel].enabled)
+    }
 }
 
 
 impl Memory {
-    // TODO: should this take val? does it make most sense to implement in
-    // terms of byte?
+    /// All DMA registers are 16 or 32 bits, so single byte writes update the
+    /// whole halfword they belong to
     pub fn update_dma_byte(&mut self, addr: u32, _val: u8) {
+        self.update_dma_reg(addr & !1);
+    }
+
+    pub fn update_dma_hw(&mut self, addr: u32, _val: u32) {
+        self.update_dma_reg(addr & !1);
+    }
+
+    /// A 32 bit write to the count register also writes the control register,
+    /// so the halfwords are processed in order to make sure that the count is
+    /// up to date when the channel is enabled
+    pub fn update_dma_word(&mut self, addr: u32, _val: u32) {
+        self.update_dma_reg(addr & !3);
+        self.update_dma_reg((addr & !3) + 2);
+    }
+
+    /// Update the parsed DMA state after a write to the halfword at addr, using
+    /// the value that was written to raw memory
+    fn update_dma_reg(&mut self, addr: u32) {
         let offset = addr - DMA_START;
         // each channel is 12 bytes: 4 src, 4 dest, 2 count, 2 cnt
         let channel_num = offset as usize / 12;
         match offset % 12 {
-            0...3 => { // src
-                let src = self.raw.get_word(addr & !3);
+            0 | 2 => { // src
+                let src = self.raw.get_word(DMA_SAD[channel_num]);
                 let mut channel = &mut self.dma.channels[channel_num];
                 let mask = if channel_num == 0 { 0x7FFFFFF } else { 0xFFFFFFF };
                 channel.src = src & mask;
             },
-            4...7 => { // dest
-                let dest = self.raw.get_word(addr & !3);
+            4 | 6 => { // dest
+                let dest = self.raw.get_word(DMA_DAD[channel_num]);
                 let mut channel = &mut self.dma.channels[channel_num];
                 let mask = if channel_num == 3 { 0xFFFFFFF } else { 0x7FFFFFF };
                 channel.dest = dest & mask;
             },
-            8...9 => { // chunk count
-                let count = self.raw.get_halfword(addr & !1);
+            8 => { // chunk count
+                let count = self.raw.get_halfword(addr);
                 let mut channel = &mut self.dma.channels[channel_num];
                 channel.count = count & 0x3FFF;
             },
@@ -66,9 +184,10 @@ impl Memory {
             // C-D (M) = timing mode
             // E   (I) = irq
             // F   (N) = enabled
-            10...11 => { // cnt register
-                let reg = self.raw.get_halfword(addr & !1);
+            10 => { // cnt register
+                let reg = self.raw.get_halfword(addr);
                 let mut channel = &mut self.dma.channels[channel_num];
+                let was_enabled = channel.enabled;
                 channel.dest_incr = IncrType::from_u16((reg >> 5) & 0b11).unwrap();
                 channel.src_incr = IncrType::from_u16((reg >> 7) & 0b11).unwrap();
                 channel.repeat = util::get_bit_hw(reg, 9);
@@ -77,75 +196,222 @@ impl Memory {
                 channel.irq = util::get_bit_hw(reg, 14);
                 channel.enabled = util::get_bit_hw(reg, 15);
 
-                if channel.count == 0 {
-                    channel.count = 0x4000;
+                // the addresses and count are copied into internal registers
+                // when the channel gets enabled, so that changing them
+                // afterwards has no effect on the transfer
+                if !was_enabled && channel.enabled {
+                    channel.latch();
+                    channel.pending = channel.timing == TimingMode::Now;
+                }
+                if !channel.enabled {
+                    channel.pending = false;
                 }
             },
             _ => panic!("should not get here")
         }
     }
 
-    pub fn update_dma_hw(&mut self, addr: u32, val: u32) {
-        self.update_dma_byte(addr, val as u8);
-        self.update_dma_byte(addr + 1, (val >> 8) as u8);
-    }
-
-    pub fn update_dma_word(&mut self, addr: u32, val: u32) {
-        self.update_dma_hw(addr, val);
-        self.update_dma_hw(addr + 2, val >> 16);
-    }
-
+    /// Start the transfers of the enabled channels with the given timing, and
+    /// then run every pending transfer in order of priority. Immediate
+    /// transfers are started by enabling their channel instead, so checking
+    /// for those only runs the ones that are pending. A channel started again
+    /// by a transfer in the same call is left pending for the next one
     pub fn check_dma(&mut self, timing: TimingMode) {
-        for i in 0..self.dma.channels.len() {
-            if self.dma.channels[i].enabled  && self.dma.channels[i].timing == timing {
-                self.run_dma(i)
+        if timing != TimingMode::Now {
+            for i in 0..self.dma.channels.len() {
+                if self.dma.is_enabled(i) && self.dma.channels[i].timing == timing {
+                    self.dma.channels[i].pending = true;
+                }
             }
         }
+        if self.dma.in_transfer {
+            return;
+        }
+        self.dma.in_transfer = true;
+        let mut ran = [false; 4];
+        let runnable = |dma: &DMA, i: usize| dma.channels[i].pending && dma.is_enabled(i);
+        while let Some(i) = (0..4).find(|&i| !ran[i] && runnable(&self.dma, i)) {
+            ran[i] = true;
+            self.dma.channels[i].pending = false;
+            self.run_dma(i);
+        }
+        self.dma.in_transfer = false;
     }
 
     fn run_dma(&mut self, channel_num: usize) {
-        { // scope with mutable borrow on self.dma.channels
-            let channel = &mut self.dma.channels[channel_num];
+        let (word, src_incr, dest_incr, count) = {
+            let channel = &self.dma.channels[channel_num];
+            (channel.word, channel.src_incr, channel.dest_incr, channel.internal_count)
+        };
+        // word or halfword align the src/dest addrs depending on chunk size
+        let chunk_size = if word { 4 } else { 2 };
+        let mut src = self.dma.channels[channel_num].internal_src & !(chunk_size - 1);
+        let mut dest = self.dma.channels[channel_num].internal_dest & !(chunk_size - 1);
+        let source = self.dma.channels[channel_num].source(channel_num);
+        if channel_num == 3 {
+            self.check_eeprom_dma(dest, count);
+        }
 
-            // word or halfword align the src/dest addrs depending on chunk size
-            let mask = if channel.word { !3 } else { !1 };
-            let src = channel.src & mask;
-            let dest = channel.dest & mask;
-
-            let chunk_size = if channel.word { 4 } else { 2 };
-            // TODO: is using copy_from_slice() faster?
-            // TODO: can avoid this loop if the dest is fixed
-            for _ in 0..(channel.count * chunk_size) {
-                // TODO: if update_x_hw or update_x_word get implemented separately
-                // from the byte version, should call that here instead
-                let val = self.raw.get_byte(src);
-                self.raw.set_byte(dest, val);
-
-                channel.src_incr.update_addr(src);
-                channel.dest_incr.update_addr(dest);
-            }
+        // a transfer into the IO registers bigger than the whole region would
+        // run the update handlers of every register thousands of times, so
+        // its writes are treated like those to OAM and the palette
+        let runaway = match canonicalize_addr(dest) {
+            IO_START...IO_END => count * chunk_size > RUNAWAY_BYTES,
+            _ => false,
+        };
+        if runaway {
+            self.dma.report_runaway(RunawayDma {
+                channel: channel_num,
+                src,
+                dest,
+                count,
+                word,
+            });
+        }
 
-            // update mapped/raw addrs
-            channel.src = src;
-            match channel.dest_incr {
-                IncrType::Reload => (),
-                _ => channel.dest = dest
+        let mut deferred_io = [false; 0x200];
+        let mut cycles = self.transfer_overhead(src, dest);
+        // TODO: can avoid this loop if the dest is fixed
+        for i in 0..count {
+            // only the first read and write are nonsequential, unless the
+            // address doesn't go up after each chunk
+            cycles += self.sized_access_time(src, chunk_size, i == 0 || !src_incr.is_sequential()) +
+                self.sized_access_time(dest, chunk_size, i == 0 || !dest_incr.is_sequential());
+            self.dma.transfer_cycles = cycles;
+            let val = if word {
+                self.get_word_by(src, source)
+            } else {
+                self.get_halfword_by(src, source) as u32
+            };
+            if !self.write_deferred(dest, val, word, runaway, &mut deferred_io, source) {
+                if word {
+                    self.set_word_by(dest, val, source);
+                } else {
+                    self.set_halfword_by(dest, val, source);
+                }
             }
-            self.raw.set_word(DMA_SAD[channel_num], channel.src);
-            self.raw.set_word(DMA_DAD[channel_num], channel.dest);
+            src = src_incr.update_addr(src, chunk_size);
+            dest = dest_incr.update_addr(dest, chunk_size);
+        }
+        self.apply_deferred(&deferred_io);
+        self.dma.transfer_cycles = 0;
 
-            // update mapped/raw cnt register
-            if !channel.repeat {
+        {
+            let channel = &mut self.dma.channels[channel_num];
+            channel.internal_src = src;
+            channel.internal_dest = dest;
+            // an immediate transfer only runs once, even with repeat set
+            if channel.repeat && channel.timing != TimingMode::Now {
+                channel.internal_count = channel.reload_count();
+                if channel.dest_incr == IncrType::Reload {
+                    channel.internal_dest = channel.dest;
+                }
+            } else {
                 channel.enabled = false;
-                let old_reg = self.raw.get_word(DMA_CNT[channel_num]);
-                self.raw.set_word(DMA_CNT[channel_num], old_reg & !0x8000);
             }
         }
+        if !self.dma.channels[channel_num].enabled {
+            let idx = (DMA_CNT[channel_num] + 1 - IO_START) as usize;
+            self.raw.io[idx] &= !0x80;
+        }
+
+        self.dma.record(channel_num, count, cycles);
+        self.on_dma_finish_hook(channel_num);
+    }
+
+    /// Write a chunk of a transfer straight to raw memory if it lands in OAM,
+    /// the palette, or (for runaway transfers) the IO registers, so that its
+    /// parsed state is only updated once the transfer is done. OAM and the
+    /// palette keep track of this themselves (see mem::dirty), and the IO
+    /// halfwords written are marked in deferred_io. Return false if the chunk
+    /// should be written normally instead
+    fn write_deferred(&mut self, dest: u32, val: u32, word: bool, io: bool,
+        deferred_io: &mut [bool; 0x200], source: Source) -> bool {
+        let addr = canonicalize_addr(dest);
+        match addr {
+            OAM_START...OAM_END | PAL_START...PAL_END => (),
+            IO_START...IO_END if io => {
+                let hw = ((addr - IO_START) / 2) as usize;
+                deferred_io[hw] = true;
+                if word {
+                    deferred_io[hw + 1] = true;
+                }
+            },
+            _ => return false,
+        }
+        self.watch.check(addr, if word { 4 } else { 2 }, AccessKind::Write, source);
+        if word {
+            self.raw.set_word(addr, val);
+        } else {
+            self.raw.set_halfword(addr, val);
+        }
+        true
+    }
+
+    /// Update the parsed state of each halfword written by write_deferred,
+    /// using its final value. The IO registers are done in address order, as
+    /// they would be by a normal transfer
+    fn apply_deferred(&mut self, deferred_io: &[bool; 0x200]) {
+        for (i, _) in deferred_io.iter().enumerate().filter(|&(_, &dirty)| dirty) {
+            let addr = IO_START + i as u32 * 2;
+            let val = self.raw.get_halfword(addr) as u32;
+            self.update_hw(addr, val);
+        }
+        self.refresh_parsed_mirrors();
+    }
+
+    /// Return the internal cycles taken to start a transfer, which is 2, or 4
+    /// if both the source and dest are in the game pak
+    fn transfer_overhead(&self, src: u32, dest: u32) -> u32 {
+        let internal = if self.is_rom(src) && self.is_rom(dest) { 4 } else { 2 };
+        self.internal_cycles(internal)
+    }
 
+    /// Sound DMA always copies 4 words to the FIFO, ignoring the count, size
+    /// and dest increment settings
+    pub fn run_fifo_dma(&mut self, channel_num: usize) {
+        let (src_incr, mut src, dest, source) = {
+            let channel = &self.dma.channels[channel_num];
+            (channel.src_incr, channel.internal_src & !3, channel.fifo_dest(),
+             channel.source(channel_num))
+        };
+        let mut cycles = self.transfer_overhead(src, dest);
+        for i in 0..4 {
+            // the FIFO is at a fixed address
+            cycles += self.sized_access_time(src, 4, i == 0 || !src_incr.is_sequential()) +
+                self.sized_access_time(dest, 4, true);
+            let val = self.get_word_by(src, source);
+            self.set_word_by(dest, val, source);
+            src = src_incr.update_addr(src, 4);
+        }
+        self.dma.channels[channel_num].internal_src = src;
+        if !self.dma.channels[channel_num].repeat {
+            self.dma.channels[channel_num].enabled = false;
+            let idx = (DMA_CNT[channel_num] + 1 - IO_START) as usize;
+            self.raw.io[idx] &= !0x80;
+        }
+        self.dma.record(channel_num, 4, cycles);
         self.on_dma_finish_hook(channel_num);
     }
 }
 
+/// Totals for a DMA channel, used to see how much time a game spends copying
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DMAStats {
+    /// the number of times the channel has run
+    pub transfers: u32,
+    /// the number of words/halfwords copied
+    pub chunks: u64,
+    /// the number of cycles the CPU was halted for
+    pub cycles: u64,
+}
+
+impl DMAStats {
+    pub const fn new() -> DMAStats {
+        DMAStats { transfers: 0, chunks: 0, cycles: 0 }
+    }
+}
+
 #[derive(Debug)] 
 pub struct DMAChannel {
     /// 27 bit for channel 0, 28 bit for 1 - 3
@@ -164,6 +430,14 @@ pub struct DMAChannel {
     /// if true, raise an interrupt when finished
     pub irq: bool,
     enabled: bool,
+    /// set when a transfer has been started but check_dma hasn't run it yet
+    pending: bool,
+
+    /// the internal copies of src, dest, and count which get updated as the
+    /// transfer progresses
+    internal_src: u32,
+    internal_dest: u32,
+    internal_count: u32,
 }
 
 impl DMAChannel {
@@ -178,13 +452,78 @@ impl DMAChannel {
             word: true,
             timing: TimingMode::Now,
             irq: false,
-            enabled: false
+            enabled: false,
+            pending: false,
+            internal_src: 0,
+            internal_dest: 0,
+            internal_count: 0,
         }
     }
+
+    /// Return the number of chunks to transfer, where a count of 0 means the
+    /// maximum
+    fn reload_count(&self) -> u32 {
+        if self.count == 0 { 0x4000 } else { self.count as u32 }
+    }
+
+    /// Return the internal (src, dest, count) of the current transfer
+    pub fn internal_regs(&self) -> (u32, u32, u32) {
+        (self.internal_src, self.internal_dest, self.internal_count)
+    }
+
+    pub fn restore_internal_regs(&mut self, src: u32, dest: u32, count: u32) {
+        self.internal_src = src;
+        self.internal_dest = dest;
+        self.internal_count = count;
+    }
+
+    /// Describe the channel (numbered channel_num) as the source of its
+    /// accesses, for the watchpoints
+    fn source(&self, channel_num: usize) -> Source {
+        Source::Dma { channel: channel_num, src: self.src, dest: self.dest }
+    }
+
+    /// The word aligned address written to by sound DMA
+    fn fifo_dest(&self) -> u32 {
+        self.internal_dest & !3
+    }
+
+    /// Return true if this is a sound DMA that refills the FIFO at the given
+    /// address
+    pub fn feeds(&self, fifo_addr: u32) -> bool {
+        self.timing == TimingMode::Special && self.fifo_dest() == fifo_addr
+    }
+
+    fn latch(&mut self) {
+        self.internal_src = self.src;
+        self.internal_dest = self.dest;
+        self.internal_count = self.reload_count();
+    }
+}
+/// The report for a transfer into the IO registers that's too big to be
+/// intentional, which usually means the game set up the channel wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunawayDma {
+    pub channel: usize,
+    pub src: u32,
+    pub dest: u32,
+    /// the number of words/halfwords copied
+    pub count: u32,
+    pub word: bool,
 }
+
+impl fmt::Display for RunawayDma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DMA {} copied {} {} from {:#010X} into the IO registers at {:#010X}; \
+            the game probably set up the wrong destination or count",
+            self.channel, self.count, if self.word { "words" } else { "halfwords" },
+            self.src, self.dest)
+    }
+}
+
 /// Specifies how to modify the src/dest of the channel
 enum_from_primitive! {
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum IncrType {
     /// increment after each transfer
@@ -200,14 +539,21 @@ pub enum IncrType {
 }
 
 impl IncrType {
-    pub fn update_addr(&self, addr: u32) -> u32 {
+    /// Return the address of the next chunk of a transfer
+    pub fn update_addr(&self, addr: u32, chunk_size: u32) -> u32 {
         match *self {
             IncrType::Inc |
-            IncrType::Reload => addr + 1,
-            IncrType::Dec => addr - 1,
+            IncrType::Reload => addr.wrapping_add(chunk_size),
+            IncrType::Dec => addr.wrapping_sub(chunk_size),
             IncrType::Fixed => addr
         }
     }
+
+    /// Return true if each chunk is at the address after the last one, so
+    /// that accessing it is sequential
+    pub fn is_sequential(&self) -> bool {
+        *self == IncrType::Inc || *self == IncrType::Reload
+    }
 }
 
 /// Enum specifying when the DMA transfer should start
@@ -221,8 +567,9 @@ pub enum TimingMode {
     VBlank,
     /// start at the next HBlank
     HBlank,
-    /// depends on the channel, but currently unimplemented
-    Refresh,
+    /// depends on the channel: channels 1 and 2 refill a sound FIFO when it
+    /// runs low, and the rest are currently unimplemented
+    Special,
 }
 }
 
@@ -230,6 +577,7 @@ pub enum TimingMode {
 #[cfg(test)]
 mod test {
     use super::*;
+    use mem::watch::WatchHit;
 
     #[test]
     fn write() {
@@ -273,4 +621,293 @@ mod test {
             assert_eq!(channel.dest_incr, IncrType::Fixed);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn enabled_override() {
+        let mut mem = Memory::new();
+        mem.set_word(0x3000000, 0x1234);
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x3000100);
+        mem.set_halfword(0x40000DC, 1);
+
+        mem.dma.set_enabled_override(3, Some(false));
+        mem.set_halfword(0x40000DE, 0x8000);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_halfword(0x3000100), 0);
+        // the game can still see the enabled bit
+        assert!(mem.dma.channels[3].enabled);
+
+        mem.dma.set_enabled_override(3, None);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_halfword(0x3000100), 0x1234);
+        assert!(!mem.dma.is_enabled(3));
+    }
+
+    #[test]
+    fn word_write_cnt() {
+        let mut mem = Memory::new();
+        for i in 0..4 {
+            mem.set_word(0x3000000 + i * 4, i + 1);
+        }
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x3000100);
+        // write count and control at once: copy 3 words, starting immediately
+        mem.set_word(0x40000DC, 0x8400_0003);
+        {
+            let channel = &mem.dma.channels[3];
+            assert!(channel.enabled);
+            assert_eq!(channel.internal_count, 3);
+        }
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 1);
+        assert_eq!(mem.get_word(0x3000108), 3);
+        assert_eq!(mem.get_word(0x300010C), 0);
+        assert!(!mem.dma.channels[3].enabled);
+        assert_eq!(mem.get_halfword(0x40000DE), 0x0400);
+        // the source and dest registers are left alone
+        assert_eq!(mem.get_word(0x40000D4), 0x3000000);
+    }
+
+    #[test]
+    fn timing() {
+        let mut mem = Memory::new();
+        // copy 4 words from IWRAM (1 cycle) to EWRAM, which takes 2 halfword
+        // writes of 3 cycles each
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x2000000);
+        mem.set_word(0x40000DC, 0x8400_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.take_cycles(), 2 + 4 * (1 + 6));
+        assert_eq!(mem.dma.take_cycles(), 0);
+
+        // from ROM, the first halfword read is nonsequential (5 cycles) and
+        // the rest sequential (3 cycles)
+        mem.load_rom(vec![0; 0x100]);
+        mem.set_word(0x40000D4, 0x8000000);
+        mem.set_word(0x40000D8, 0x3000000);
+        mem.set_word(0x40000DC, 0x8400_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.take_cycles(), 2 + 5 + 7 * 3 + 4);
+
+        let stats = mem.dma.stats[3];
+        assert_eq!(stats, DMAStats { transfers: 2, chunks: 8, cycles: 30 + 32 });
+
+        // going down through ROM makes every read nonsequential, but the
+        // second halfword of each word still follows the first
+        mem.set_word(0x40000D4, 0x8000010);
+        mem.set_word(0x40000DC, 0x8480_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.take_cycles(), 2 + 4 * (5 + 3 + 1));
+
+        // and so does a fixed source
+        mem.set_word(0x40000DC, 0x8100_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.take_cycles(), 2 + 4 * (5 + 1));
+        mem.dma.reset_stats();
+        assert_eq!(mem.dma.stats[3].transfers, 0);
+    }
+
+    #[test]
+    fn deferred_writes() {
+        // copy 0x180 words through OAM, so that the end of the transfer wraps
+        // around and overwrites the first sprites, and the same into the
+        // palette
+        let mut mem = Memory::new();
+        let mut expected = Memory::new();
+        for i in 0..0x180u32 {
+            // clear the shape bits, since a shape of 3 is invalid
+            let val = i.wrapping_mul(0x01030507) & 0x3FFF_3FFF;
+            mem.set_word(0x2000000 + i * 4, val);
+            expected.set_word(0x7000000 + i * 4, val);
+            expected.set_word(0x5000000 + i * 4, val);
+        }
+        for &dest in [0x7000000, 0x5000000].iter() {
+            mem.set_word(0x40000D4, 0x2000000);
+            mem.set_word(0x40000D8, dest);
+            mem.set_word(0x40000DC, 0x8400_0180);
+            mem.check_dma(TimingMode::Now);
+        }
+        assert_eq!(&mem.sprites.sprites[..], &expected.sprites.sprites[..]);
+        assert_eq!(&mem.palette.bg[..], &expected.palette.bg[..]);
+        assert_eq!(&mem.palette.sprite[..], &expected.palette.sprite[..]);
+        assert!(mem.dma.take_runaways().is_empty());
+    }
+
+    #[test]
+    fn runaway() {
+        let mut mem = Memory::new();
+        for i in 0..0x400 {
+            mem.set_word(0x3000000 + i * 4, i);
+        }
+        // copy 0x400 words to BG0HOFS without incrementing the dest
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x4000010);
+        mem.set_word(0x40000DC, 0x8440_0400);
+        mem.check_dma(TimingMode::Now);
+        // the registers still end up with the last value written
+        assert_eq!(mem.get_halfword(0x4000010), 0x3FF);
+        assert_eq!(mem.graphics.bg_offset_x[0], 0x1FF);
+        assert_eq!(mem.dma.stats[3].chunks, 0x400);
+
+        let runaways = mem.dma.take_runaways();
+        assert_eq!(runaways, vec![RunawayDma {
+            channel: 3,
+            src: 0x3000000,
+            dest: 0x4000010,
+            count: 0x400,
+            word: true,
+        }]);
+        assert_eq!(runaways[0].to_string(), "DMA 3 copied 1024 words from 0x03000000 \
+            into the IO registers at 0x04000010; the game probably set up the wrong \
+            destination or count");
+
+        // the same transfer isn't reported again
+        mem.set_word(0x40000DC, 0x8440_0400);
+        mem.check_dma(TimingMode::Now);
+        assert!(mem.dma.take_runaways().is_empty());
+
+        // but transfers that fit in the IO region aren't runaways
+        mem.set_word(0x40000DC, 0x8440_0100);
+        mem.check_dma(TimingMode::Now);
+        assert!(mem.dma.take_runaways().is_empty());
+    }
+
+    #[test]
+    fn immediate_repeat() {
+        let mut mem = Memory::new();
+        mem.set_word(0x3000000, 0x1234);
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x3000100);
+        // immediate with the repeat bit set
+        mem.set_word(0x40000DC, 0x8600_0001);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+        // it only runs once, and the enabled bit is cleared like any other
+        // immediate transfer
+        assert!(!mem.dma.is_enabled(3));
+        assert_eq!(mem.get_halfword(0x40000DE), 0x0600);
+        mem.set_word(0x3000000, 0x5678);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+        assert_eq!(mem.dma.stats[3].transfers, 1);
+
+        // writing the control register again without clearing the enabled bit
+        // in between doesn't start another transfer
+        mem.set_halfword(0x40000DE, 0x9000);
+        mem.set_halfword(0x40000DE, 0x8000);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+        // and disabling a transfer before it runs cancels it
+        mem.set_halfword(0x40000DE, 0);
+        mem.set_halfword(0x40000DE, 0x8400);
+        mem.set_halfword(0x40000DE, 0x0400);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+    }
+
+    #[test]
+    fn repeat_per_trigger() {
+        let mut mem = Memory::new();
+        mem.set_word(0x3000000, 0x1234);
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x3000100);
+        // VBlank, repeat, and reloading the dest each time
+        mem.set_word(0x40000DC, 0x9660_0001);
+        mem.check_dma(TimingMode::Now);
+        mem.check_dma(TimingMode::HBlank);
+        assert_eq!(mem.get_word(0x3000100), 0);
+
+        mem.check_dma(TimingMode::VBlank);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+        assert!(mem.dma.is_enabled(3));
+        // only the next VBlank runs it again
+        mem.set_word(0x3000004, 0x5678);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.get_word(0x3000100), 0x1234);
+        mem.check_dma(TimingMode::VBlank);
+        assert_eq!(mem.get_word(0x3000100), 0x5678);
+        assert_eq!(mem.dma.stats[3].transfers, 2);
+    }
+
+    #[test]
+    fn retrigger_guard() {
+        let mut mem = Memory::new();
+        // DMA 3 enables DMA 2, which enables DMA 3 again, which would go on
+        // forever if the transfers it starts were run straight away
+        mem.set_word(0x3000000, 0x8400_0001);
+        mem.set_word(0x3000004, 0x8400_0001);
+        mem.set_word(0x40000C8, 0x3000004);
+        mem.set_word(0x40000CC, 0x40000DC);
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x40000D0);
+        mem.set_word(0x40000DC, 0x8400_0001);
+        mem.check_dma(TimingMode::Now);
+        // DMA 2 is started by DMA 3 and still runs in the same call, but DMA 3
+        // waits for the next one
+        assert_eq!(mem.dma.stats[3].transfers, 1);
+        assert_eq!(mem.dma.stats[2].transfers, 1);
+        assert!(mem.dma.is_enabled(3));
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.stats[3].transfers, 2);
+        assert_eq!(mem.dma.stats[2].transfers, 2);
+
+        // a transfer started in the middle of check_dma by some other trigger
+        // is also left for the loop that's running
+        mem.set_word(0x40000DC, 0);
+        mem.set_word(0x40000D0, 0);
+        mem.dma.in_transfer = true;
+        mem.set_word(0x40000DC, 0x8400_0001);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.stats[3].transfers, 2);
+        mem.dma.in_transfer = false;
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.dma.stats[3].transfers, 3);
+    }
+
+    #[test]
+    fn latch_on_enable() {
+        let mut mem = Memory::new();
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, 0x3000100);
+        mem.set_halfword(0x40000DC, 2);
+        mem.set_halfword(0x40000DE, 0x9000); // enabled, wait for vblank
+        // writing the registers while the channel is enabled doesn't affect
+        // the current transfer
+        mem.set_word(0x40000D8, 0x3000200);
+        mem.set_halfword(0x40000DE, 0x9000);
+        assert_eq!(mem.dma.channels[3].internal_dest, 0x3000100);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut mem = Memory::new();
+        mem.watch.add(0x3000008, 4, false, true);
+        mem.watch.add(0x7000004, 2, true, true);
+        mem.set_word(0x40000D4, 0x2000000);
+        mem.set_word(0x40000D8, 0x3000000);
+        mem.set_word(0x40000DC, 0x8400_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.watch.take_hit(), Some(WatchHit {
+            addr: 0x3000008,
+            size: 4,
+            kind: AccessKind::Write,
+            source: Source::Dma { channel: 3, src: 0x2000000, dest: 0x3000000 },
+        }));
+
+        // writes to OAM bypass the usual write path, but are still caught
+        mem.set_word(0x40000B0, 0x2000000);
+        mem.set_word(0x40000B4, 0x7000000);
+        mem.set_word(0x40000B8, 0x8000_0004);
+        mem.check_dma(TimingMode::Now);
+        let hit = mem.watch.take_hit().unwrap();
+        assert_eq!(hit.to_string(),
+            "2 byte write at 0x07000004 by DMA channel 0 (0x02000000 to 0x07000000)");
+
+        // reading the watched address to copy it elsewhere is a read
+        mem.set_word(0x40000D4, 0x7000000);
+        mem.set_word(0x40000D8, 0x2000000);
+        mem.set_word(0x40000DC, 0x8000_0004);
+        mem.check_dma(TimingMode::Now);
+        assert_eq!(mem.watch.take_hit().unwrap().kind, AccessKind::Read);
+    }
+}
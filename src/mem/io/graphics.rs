@@ -1,5 +1,6 @@
 use super::addrs::*;
 use mem::Memory;
+use mem::addrs::IO_START;
 // use core::cmp::min;
 use std::cmp::min;
 use util;
@@ -18,7 +19,7 @@ pub struct LCD {
     pub bg_affine: [BgAffineParams; 2],
 
     pub window_coords: [WindowCoords; 2],
-    // win0 inside, win1 inside, win0 outside, win1 outside
+    // win0 inside, win1 inside, outside both windows, inside the OBJ window
     pub window_settings: [WindowSettings; 4],
 
     pub bg_mos_hsize: u8,
@@ -71,10 +72,49 @@ impl LCD {
         }
     }
 
-    pub fn update_vcount(&mut self, vcount: u8) {
+    /// Move on to the given line. Return true if the VCount IRQ should be
+    /// raised (see update_vcount_match)
+    pub fn update_vcount(&mut self, vcount: u8) -> bool {
         self.vcount = vcount;
-        self.disp_stat.vcount_triggered =
-            self.vcount == self.disp_stat.vcount_line_trigger;
+        self.disp_stat.vcount_irq_raised = false;
+        self.update_vcount_match()
+    }
+
+    /// Update the VCount match flag after VCOUNT or the trigger line changes.
+    /// The IRQ goes off when the flag is set, whether VCOUNT reached the
+    /// trigger line or the trigger line was moved to the current one, but at
+    /// most once per line: moving the trigger away and back again on the same
+    /// line doesn't raise it twice. Enabling the IRQ while the flag is already
+    /// set doesn't raise it either. Return true if the IRQ should be raised
+    pub fn update_vcount_match(&mut self) -> bool {
+        let disp_stat = &mut self.disp_stat;
+        let matched = self.vcount == disp_stat.vcount_line_trigger;
+        let rising = matched && !disp_stat.vcount_triggered;
+        disp_stat.vcount_triggered = matched;
+        if rising && disp_stat.vcount_irq_enabled && !disp_stat.vcount_irq_raised {
+            disp_stat.vcount_irq_raised = true;
+            return true;
+        }
+        false
+    }
+
+    /// Return the BGxHOFS/BGxVOFS register containing the given address
+    fn bg_offset(&self, addr: u32) -> u16 {
+        let bg = ((addr - BG_OFFSET_START) / 4) as usize;
+        if addr & 2 == 0 { self.bg_offset_x[bg] } else { self.bg_offset_y[bg] }
+    }
+
+    /// Set the BGxHOFS/BGxVOFS register containing the given address. Only
+    /// the low 9 bits are used, which covers the 512 pixels of the largest
+    /// text background
+    fn set_bg_offset(&mut self, addr: u32, val: u16) {
+        let bg = ((addr - BG_OFFSET_START) / 4) as usize;
+        let offset = if addr & 2 == 0 {
+            &mut self.bg_offset_x[bg]
+        } else {
+            &mut self.bg_offset_y[bg]
+        };
+        *offset = val & 0x1FF;
     }
 }
 
@@ -91,13 +131,16 @@ impl Memory {
                     graphics.disp_cnt.bg_mode = val & 0x7;
                 }
                 graphics.disp_cnt.frame_base =
-                    if (val & 0x10) > 0 { 0x600A000 } else { 0x60000000 };
+                    if (val & 0x10) > 0 { 0x600A000 } else { 0x6000000 };
                 graphics.disp_cnt.hblank_interval_free = (val & 0x20) == 0x20;
+                graphics.disp_cnt.sprite_2d = (val & 0x40) == 0;
+                graphics.disp_cnt.forced_blank = (val & 0x80) == 0x80;
             },
             DISPCNT_HI => {
                 for i in 0..4 {
                     graphics.disp_cnt.bg_enabled[i] = (val & (1 << i)) > 0;
                 }
+                graphics.disp_cnt.obj_enabled = (val & 0x10) == 0x10;
                 graphics.disp_cnt.window_enabled[0] = (val & 0x20) == 0x20;
                 graphics.disp_cnt.window_enabled[1] = (val & 0x40) == 0x40;
                 graphics.disp_cnt.obj_win_enabled = (val & 0x80) == 0x80;
@@ -106,9 +149,12 @@ impl Memory {
                 graphics.disp_stat.vblank_irq_enabled = (val & 0x8) == 0x8;
                 graphics.disp_stat.hblank_irq_enabled = (val & 0x10) == 0x10;
                 graphics.disp_stat.vcount_irq_enabled = (val & 0x20) == 0x20;
+                self.sync_vcount_match(false);
             },
             DISPSTAT_HI => {
-                graphics.disp_stat.vcount_line_trigger = val
+                graphics.disp_stat.vcount_line_trigger = val;
+                let raise = graphics.update_vcount_match();
+                self.sync_vcount_match(raise);
             },
             BGCNT_START...BGCNT_END => {
                 let bg = ((addr - BGCNT_START) / 2) as usize;
@@ -116,6 +162,7 @@ impl Memory {
                     graphics.bg_cnt[bg].map_addr =
                         0x6000000 + (val as u32 & 0x1F)*0x800;
                     graphics.bg_cnt[bg].overflow = (val & 0x20) == 0x20;
+                    graphics.bg_cnt[bg].size = val >> 6;
                     let (width, height) = match val >> 6 { // upper 2 bits
                         0 => (256, 256),
                         1 => (512, 256),
@@ -134,24 +181,10 @@ impl Memory {
                 }
             },
             BG_OFFSET_START...BG_OFFSET_END => {
-                let bg = ((addr - BG_OFFSET_START) / 4) as usize;
-                if (addr & 2) == 0 { // horizontal coord
-                    if (addr % 2) == 0 { // low byte
-                        graphics.bg_offset_x[bg] &= 0xFF00;
-                        graphics.bg_offset_x[bg] |= val as u16;
-                    } else { // high byte
-                        graphics.bg_offset_x[bg] &= 0x00FF;
-                        graphics.bg_offset_x[bg] |= (val as u16 & 3) << 8;
-                    }
-                } else { // vertical coord
-                    if (addr % 2) == 0 { // low byte
-                        graphics.bg_offset_y[bg] &= 0xFF00;
-                        graphics.bg_offset_y[bg] |= val as u16;
-                    } else { // high byte
-                        graphics.bg_offset_y[bg] &= 0x00FF;
-                        graphics.bg_offset_y[bg] |= (val as u16 & 3) << 8;
-                    }
-                }
+                let shift = 8 * (addr & 1);
+                let reg = (graphics.bg_offset(addr) & !(0xFF << shift)) |
+                    (val as u16) << shift;
+                graphics.set_bg_offset(addr, reg);
             },
             BG_AFFINE_START...BG_AFFINE_END => {
                 let bg = ((addr - BG_AFFINE_START) / 16) as usize;
@@ -243,9 +276,28 @@ impl Memory {
         }
     }
 
+    /// Copy the read only bits of DISPSTAT over whatever was written to them,
+    /// and raise the VCount IRQ if the match flag was just set
+    pub fn sync_vcount_match(&mut self, raise: bool) {
+        let idx = (DISPSTAT_LO - IO_START) as usize;
+        let status = self.graphics.disp_stat.as_u16() as u8 & 0b111;
+        self.raw.io[idx] = (self.raw.io[idx] & !0b111) | status;
+        if raise {
+            self.int.triggered.vcount = true;
+            self.raw.io[(IF_LO - IO_START) as usize] |= 0b100;
+        }
+    }
+
     pub fn update_graphics_hw(&mut self, addr: u32, val: u32) {
-        self.update_graphics_byte(addr, val as u8);
-        self.update_graphics_byte(addr + 1, (val >> 8) as u8);
+        match addr {
+            // games write the scroll registers every frame (often every line),
+            // so they skip the byte by byte update
+            BG_OFFSET_START...BG_OFFSET_END => self.graphics.set_bg_offset(addr, val as u16),
+            _ => {
+                self.update_graphics_byte(addr, val as u8);
+                self.update_graphics_byte(addr + 1, (val >> 8) as u8);
+            },
+        }
     }
 
     pub fn update_graphics_word(&mut self, addr: u32, val: u32) {
@@ -274,17 +326,17 @@ pub struct DispCnt {
     pub hblank_interval_free: bool,
     /// 6   (D) = Sets whether sprites stored in VRAM use 1 dimension or 2.
     ///           1 - 1d: tiles are are stored sequentially
-    ///           0 - 2d: each row of tiles is stored 32 x 64 bytes in from the start of the
-    ///           previous row.
-    // sprite_2d: bool,
+    ///           0 - 2d: each row of tiles is stored 32 x 32 bytes in from the start of the
+    ///           previous row, as if VRAM were a 32 tile wide sheet.
+    pub sprite_2d: bool,
     /// 7   (F) = Force the display to go blank when set. This can be used to save power
     ///           when the display isn't needed, or to blank the screen when it is being
     ///           built up
-    // force_blank: bool,
+    pub forced_blank: bool,
     /// 8-B (L) = enable the display of BGi
     pub bg_enabled: [bool; 4],
     /// C   (S) = If set, enable display of OAM (sprites).
-    // oam_enabled: bool,
+    pub obj_enabled: bool,
     /// D-E (U) = enable the display of window i
     pub window_enabled: [bool; 2],
     /// F   (W) = Enable Sprite Windows
@@ -295,9 +347,12 @@ impl DispCnt {
     pub const fn new() -> DispCnt {
         DispCnt {
             bg_mode: 0,
-            frame_base: 0,
+            frame_base: 0x6000000,
             hblank_interval_free: false,
+            sprite_2d: true,
+            forced_blank: false,
             bg_enabled: [false; 4],
+            obj_enabled: false,
             window_enabled: [false; 2],
             obj_win_enabled: false,
         }
@@ -308,7 +363,8 @@ impl DispCnt {
 ///                              R R R
 /// F E D C  B A 9 8  7 6 5 4  3 2 1 0
 /// T T T T  T T T T  X X Y H  V Z G W
-/// NOTE the read only bits are only kept updated in this struct, not in raw memory
+/// NOTE the read only bits are only kept up to date in this struct, so reads are
+/// computed from it instead of raw memory (see Memory::get_io_byte)
 pub struct DispStat {
     /// 0   (W) = V Refresh status. This will be 0 during VDraw, and 1 during VBlank.
     pub is_vblank: bool,
@@ -324,7 +380,10 @@ pub struct DispStat {
     pub vcount_irq_enabled: bool,
     /// 8-F (T) = Vcount line trigger. Set this to the VCount value you wish to trigger an
     ///           interrupt.
-    pub vcount_line_trigger: u8
+    pub vcount_line_trigger: u8,
+    /// set once the VCount IRQ has gone off on the current line. not part
+    /// of the register
+    pub vcount_irq_raised: bool,
 }
 
 impl DispStat {
@@ -336,9 +395,21 @@ impl DispStat {
             vblank_irq_enabled: false,
             hblank_irq_enabled: false,
             vcount_irq_enabled: false,
-            vcount_line_trigger: 0
+            vcount_line_trigger: 0,
+            vcount_irq_raised: false,
         }
     }
+
+    /// Return the register in the format read by the game
+    pub fn as_u16(&self) -> u16 {
+        self.is_vblank as u16 |
+            (self.is_hblank as u16) << 1 |
+            (self.vcount_triggered as u16) << 2 |
+            (self.vblank_irq_enabled as u16) << 3 |
+            (self.hblank_irq_enabled as u16) << 4 |
+            (self.vcount_irq_enabled as u16) << 5 |
+            (self.vcount_line_trigger as u16) << 8
+    }
 }
 
 /// Address: 0x400008 - 0x40001E: Background Registers
@@ -383,21 +454,30 @@ pub struct BgCnt {
     ///           11 : 1024x1024 (128x128 tiles)
     pub width: u16,
     pub height: u16,
+    /// the raw size bits, needed for rotational backgrounds
+    pub size: u8,
 }
 
 impl BgCnt {
     pub const fn new() -> BgCnt {
         BgCnt {
             priority: 0,
-            tile_addr: 0,
+            // as parsed from a register of 0
+            tile_addr: 0x6000000,
             mosaic_enabled: false,
-            depth: 8,
-            map_addr: 0,
+            depth: 4,
+            map_addr: 0x6000000,
             overflow: false,
-            width: 0,
-            height: 0,
+            width: 256,
+            height: 256,
+            size: 0,
         }
     }
+
+    /// Return the width (and height) of a rotational background in pixels
+    pub fn affine_size(&self) -> u32 {
+        128 << self.size
+    }
 }
 
 pub struct BgAffineParams {
@@ -440,8 +520,15 @@ impl WindowCoords {
             right: 0,
         }
     }
+
+    pub fn contains(&self, row: u32, col: u32) -> bool {
+        row >= self.top as u32 && row < self.bottom as u32 &&
+            col >= self.left as u32 && col < self.right as u32
+    }
 }
 
+/// The layers that are drawn in one of the window regions
+#[derive(Clone, Copy)]
 pub struct WindowSettings {
     pub bg: [bool; 4],
     pub sprite: bool,
@@ -476,7 +563,7 @@ impl BlendParams {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BlendType {
     Off,
     AlphaBlend,
@@ -484,9 +571,10 @@ pub enum BlendType {
     Darken,
 }
 
-/// takes a 5 bit value and parses it as an effect coefficent
+/// takes a 5 bit value and parses it as an effect coefficent. the rest of
+/// the byte is unused, and anything past 16 counts as 16
 fn to_coeff(raw: u8) -> f32 {
-    (min(raw, 16) as f32) / 16.0
+    (min(raw & 0x1F, 16) as f32) / 16.0
 }
 
 #[cfg(test)]
@@ -504,6 +592,7 @@ mod test {
             assert_eq!(disp_cnt.bg_mode, 2);
             assert_eq!(disp_cnt.frame_base, 0x600A000);
             assert_eq!(disp_cnt.hblank_interval_free, false);
+            assert_eq!(disp_cnt.sprite_2d, false);
             assert_eq!(disp_cnt.bg_enabled[0], true);
             assert_eq!(disp_cnt.bg_enabled[1], false);
             assert_eq!(disp_cnt.bg_enabled[2], false);
@@ -555,9 +644,9 @@ mod test {
         }
 
         mem.set_halfword(0x4000010, 0x03AB);
-        assert_eq!(mem.graphics.bg_offset_x[0], 0x03AB);
+        assert_eq!(mem.graphics.bg_offset_x[0], 0x01AB);
         mem.set_halfword(0x4000016, 0xFFFF);
-        assert_eq!(mem.graphics.bg_offset_y[1], 0x03FF);
+        assert_eq!(mem.graphics.bg_offset_y[1], 0x01FF);
         mem.set_halfword(0x4000018, 0x0123);
         assert_eq!(mem.graphics.bg_offset_x[2], 0x0123);
         mem.set_halfword(0x400001E, 0x0010);
@@ -666,6 +755,75 @@ mod test {
         assert_eq!(mem.graphics.brightness_coef, 1.0);
     }
 
+    #[test]
+    fn bg_offset() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000010, 300);
+        assert_eq!(mem.graphics.bg_offset_x[0], 300);
+        // the upper bits are dropped
+        mem.set_halfword(0x4000012, 0xFE00 | 511);
+        assert_eq!(mem.graphics.bg_offset_y[0], 511);
+
+        // byte writes only change their half of the register
+        mem.set_byte(0x4000014, 0x2C);
+        mem.set_byte(0x4000015, 0x03);
+        assert_eq!(mem.graphics.bg_offset_x[1], 0x12C);
+        mem.set_byte(0x4000014, 0x05);
+        assert_eq!(mem.graphics.bg_offset_x[1], 0x105);
+
+        mem.set_word(0x4000018, 0x0100_00F0);
+        assert_eq!(mem.graphics.bg_offset_x[2], 0xF0);
+        assert_eq!(mem.graphics.bg_offset_y[2], 0x100);
+    }
+
+    #[test]
+    fn vcount_match() {
+        let mut mem = Memory::new();
+        let raised = |mem: &mut Memory| {
+            let raised = mem.int.triggered.vcount;
+            mem.set_halfword(0x4000202, 0b100);
+            raised
+        };
+        // line 5 with the IRQ enabled
+        mem.set_halfword(0x4000004, 0x0520);
+        for line in 0..5 {
+            mem.on_vcount_hook(line);
+            assert!(!raised(&mut mem));
+        }
+        mem.on_vcount_hook(5);
+        assert!(raised(&mut mem));
+        assert_eq!(mem.get_halfword(0x4000004), 0x0524);
+
+        // moving the trigger away and back on the same line sets the flag
+        // again, but only raises the IRQ once per line
+        mem.set_halfword(0x4000004, 0x0620);
+        assert_eq!(mem.get_halfword(0x4000004), 0x0620);
+        mem.set_halfword(0x4000004, 0x0520);
+        assert_eq!(mem.get_halfword(0x4000004), 0x0524);
+        assert!(!raised(&mut mem));
+
+        // moving it onto the current line raises it
+        mem.on_vcount_hook(6);
+        assert!(!raised(&mut mem));
+        mem.set_byte(0x4000005, 6);
+        assert!(raised(&mut mem));
+        assert_eq!(mem.raw.get_byte(0x4000004) & 0b111, 0b100);
+
+        // enabling the IRQ after the flag is set doesn't
+        mem.set_halfword(0x4000004, 0x0700);
+        mem.on_vcount_hook(7);
+        mem.set_halfword(0x4000004, 0x0720);
+        assert!(!raised(&mut mem));
+
+        // lines in VBlank count too
+        mem.set_halfword(0x4000004, 0xC820);
+        for line in 8..228 {
+            mem.on_vcount_hook(line);
+            assert_eq!(raised(&mut mem), line == 200);
+        }
+        assert_eq!(mem.get_halfword(0x4000006), 227);
+    }
+
     #[test]
     fn parse_coeff() {
         assert_eq!(to_coeff(8), 0.5);
@@ -23,6 +23,7 @@
 
 use super::addrs::*;
 use mem::Memory;
+use mem::addrs::IO_START;
 
 #[derive(Debug)]
 pub struct Interrupt {
@@ -101,18 +102,16 @@ impl Memory {
                 triggered.keypad &= !get_bit(val, 4);
                 triggered.gamepak &= !get_bit(val, 5);
             },
-            WSCNT_LO => {
-                self.rom_n_cycle = match (val >> 2) & 0b11 {
-                    0 => 4,
-                    1 => 3,
-                    2 => 2,
-                    3 => 8,
-                    _ => panic!("should not get here")
-                };
-                self.rom_s_cycle_fast = (val >> 4) & 1 == 1;
-            }
             _ => ()
         }
+
+        // the write has already been copied into raw memory, but IF should
+        // read back as the interrupts that are still waiting
+        if addr == IF_LO || addr == IF_HI {
+            let bits = self.int.triggered.as_u16();
+            self.raw.io[(IF_LO - IO_START) as usize] = bits as u8;
+            self.raw.io[(IF_HI - IO_START) as usize] = (bits >> 8) as u8;
+        }
     }
 
     pub fn update_int_hw(&mut self, addr: u32, val: u32) {
@@ -170,6 +169,27 @@ impl InterruptBitmap {
             self.gamepak,
         ]
     }
+
+    /// Return the bitmap in the format of IE and IF
+    pub fn as_u16(&self) -> u16 {
+        self.as_array().iter().enumerate()
+            .fold(0, |bits, (i, &set)| bits | ((set as u16) << i))
+    }
+
+    /// Parse a bitmap in the format of IE and IF
+    pub fn from_u16(bits: u16) -> InterruptBitmap {
+        let bit = |i: u16| (bits >> i) & 1 == 1;
+        InterruptBitmap {
+            vblank: bit(0),
+            hblank: bit(1),
+            vcount: bit(2),
+            timer: [bit(3), bit(4), bit(5), bit(6)],
+            serial: bit(7),
+            dma: [bit(8), bit(9), bit(10), bit(11)],
+            keypad: bit(12),
+            gamepak: bit(13),
+        }
+    }
 }
 
 fn get_bit(val: u8, i: u8) -> bool {
@@ -206,8 +226,8 @@ mod test {
         }
 
         mem.set_byte(0x4000204, 0b1011_0100);
-        assert_eq!(mem.rom_n_cycle, 3);
-        assert_eq!(mem.rom_s_cycle_fast, true);
+        assert_eq!(mem.waitcnt.rom_n[0], 3);
+        assert_eq!(mem.waitcnt.rom_s[0], 1);
     }
 
     #[test]
@@ -240,5 +260,13 @@ mod test {
             assert_eq!(triggered.keypad, false);
             assert_eq!(triggered.gamepak, false);
         }
+        assert_eq!(mem.get_halfword(0x4000202), 0b0000_1100_0000_0000);
+
+        // writing IE and IF together leaves bits that weren't acknowledged set
+        mem.set_word(0x4000200, (0b0000_0100_0000_0000 << 16) | 0x0F00);
+        assert_eq!(mem.int.enabled.dma[3], true);
+        assert_eq!(mem.int.triggered.dma[2], false);
+        assert_eq!(mem.int.triggered.dma[3], true);
+        assert_eq!(mem.get_word(0x4000200), (0b0000_1000_0000_0000 << 16) | 0x0F00);
     }
 }
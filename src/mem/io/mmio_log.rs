@@ -0,0 +1,377 @@
+//! Records the writes a game makes to the IO registers so that they can be
+//! saved as a fixture and replayed later without the ROM. Replaying checks
+//! after every write that the parsed state still agrees with the raw
+//! registers, so a session with a real game becomes a regression test for
+//! the register handlers. The fixtures in assets/mmio are replayed by the
+//! tests. init_and_vblank.mmio is the typical setup of the display, sound,
+//! timers and interrupts followed by 8 frames of scrolling and OAM DMA,
+//! recorded from writes made through Memory rather than from a game.
+//!
+//! A fixture is "MMIO", a version byte, then one entry per write:
+//!   - the cycles since the previous write, as a LEB128 varint
+//!   - a little endian halfword: the offset into the IO region in the low 10
+//!     bits and the size of the write (0: byte, 1: halfword, 2: word) in the
+//!     top 2 bits
+//!   - the value written, little endian, in as many bytes as the write
+//!
+//! Replaying only applies the writes in order. The cycles are kept so that
+//! a session can be lined up with what the game did, but nothing else (the
+//! LCD, DMA timing) is run between writes.
+
+use std::fmt;
+use mem::Memory;
+use mem::addrs::{IO_START, IO_END};
+use super::addrs::*;
+
+const MAGIC: &[u8] = b"MMIO";
+const VERSION: u8 = 1;
+
+/// A single write to the IO region
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioWrite {
+    /// cycles since power on when the instruction making the write started
+    pub cycle: u64,
+    pub addr: u32,
+    /// 1, 2 or 4 bytes
+    pub size: u8,
+    pub value: u32,
+}
+
+pub struct MmioLog {
+    pub recording: bool,
+    /// the cycle the current instruction started on, kept up to date by the
+    /// CPU
+    pub now: u64,
+    writes: Vec<MmioWrite>,
+}
+
+impl MmioLog {
+    pub const fn new() -> MmioLog {
+        MmioLog {
+            recording: false,
+            now: 0,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Start recording, dropping anything recorded before
+    pub fn start(&mut self) {
+        self.writes.clear();
+        self.recording = true;
+    }
+
+    /// Stop recording and return the fixture of everything recorded since the
+    /// last start
+    pub fn stop(&mut self) -> Vec<u8> {
+        self.recording = false;
+        encode(&std::mem::replace(&mut self.writes, Vec::new()))
+    }
+
+    pub fn writes(&self) -> &[MmioWrite] {
+        &self.writes
+    }
+
+    /// Record a write to the given (canonical) address, if it's in the IO
+    /// region
+    pub fn record(&mut self, addr: u32, size: u8, value: u32) {
+        if !self.recording {
+            return;
+        }
+        match addr {
+            IO_START...IO_END => self.writes.push(MmioWrite {
+                cycle: self.now,
+                addr,
+                size,
+                value,
+            }),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FixtureError {
+    /// the data doesn't start with the fixture header, or is from a newer
+    /// version
+    BadHeader,
+    /// the data ends in the middle of an entry
+    Truncated,
+    /// the entry at the given index has a size code that isn't 0, 1 or 2
+    BadSize(usize),
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FixtureError::BadHeader => write!(f, "not an MMIO fixture"),
+            FixtureError::Truncated => write!(f, "MMIO fixture is truncated"),
+            FixtureError::BadSize(i) => write!(f, "write {} has an invalid size", i),
+        }
+    }
+}
+
+/// A write during a replay after which the parsed state no longer matched
+/// the raw registers
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayError {
+    /// the index of the write in the fixture
+    pub index: usize,
+    pub write: MmioWrite,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "after write {} ({} byte(s) of {:#X} to {:#010X} at cycle {}): {}",
+            self.index, self.write.size, self.write.value, self.write.addr,
+            self.write.cycle, self.problems.join("; "))
+    }
+}
+
+pub fn encode(writes: &[MmioWrite]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    let mut last_cycle = writes.first().map_or(0, |w| w.cycle);
+    for write in writes {
+        let mut delta = write.cycle - last_cycle;
+        last_cycle = write.cycle;
+        loop {
+            let byte = (delta & 0x7F) as u8;
+            delta >>= 7;
+            if delta == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+        let size_code = match write.size { 1 => 0, 2 => 1, _ => 2 };
+        let header = (write.addr - IO_START) as u16 | size_code << 14;
+        out.extend_from_slice(&[header as u8, (header >> 8) as u8]);
+        for i in 0..write.size {
+            out.push((write.value >> (8 * i)) as u8);
+        }
+    }
+    out
+}
+
+/// Parse a fixture produced by encode. The first write is taken to be at
+/// cycle 0
+pub fn decode(data: &[u8]) -> Result<Vec<MmioWrite>, FixtureError> {
+    if !data.starts_with(MAGIC) || data.get(MAGIC.len()) != Some(&VERSION) {
+        return Err(FixtureError::BadHeader);
+    }
+    let mut bytes = data[MAGIC.len() + 1..].iter().cloned();
+    let mut next = || bytes.next().ok_or(FixtureError::Truncated);
+    let mut writes = Vec::new();
+    let mut cycle = 0;
+    loop {
+        let mut byte = match next() {
+            Ok(byte) => byte,
+            Err(_) => return Ok(writes),
+        };
+        let mut delta = 0u64;
+        let mut shift = 0;
+        while byte & 0x80 != 0 {
+            delta |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+            byte = next()?;
+        }
+        cycle += delta | (byte as u64) << shift;
+
+        let header = next()? as u32 | (next()? as u32) << 8;
+        let size = match header >> 14 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            _ => return Err(FixtureError::BadSize(writes.len())),
+        };
+        let mut value = 0;
+        for i in 0..size {
+            value |= (next()? as u32) << (8 * i);
+        }
+        writes.push(MmioWrite {
+            cycle,
+            addr: IO_START + (header & 0x3FF),
+            size,
+            value,
+        });
+    }
+}
+
+impl Memory {
+    /// Apply each write in order, checking io_invariant_violations after
+    /// each one. Stops at the first write that breaks an invariant
+    pub fn replay_mmio(&mut self, writes: &[MmioWrite]) -> Result<(), ReplayError> {
+        for (index, write) in writes.iter().enumerate() {
+            match write.size {
+                1 => self.set_byte(write.addr, write.value as u8),
+                2 => self.set_halfword(write.addr, write.value),
+                _ => self.set_word(write.addr, write.value),
+            }
+            let problems = self.io_invariant_violations();
+            if !problems.is_empty() {
+                return Err(ReplayError { index, write: *write, problems });
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a description of each parsed register that doesn't match its
+    /// raw value. Only the settings the game controls are compared, since
+    /// status bits are updated in both places by the hardware hooks
+    pub fn io_invariant_violations(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, name: &str| if !ok {
+            problems.push(format!("{} doesn't match the raw register", name));
+        };
+        let raw = |addr: u32| self.raw.get_halfword(addr);
+        let bit = |reg: u16, i: u16| (reg >> i) & 1 == 1;
+        let graphics = &self.graphics;
+
+        let disp_cnt = raw(DISPCNT_LO);
+        // invalid modes are ignored, leaving the last valid one
+        check(disp_cnt & 7 > 5 || graphics.disp_cnt.bg_mode as u16 == disp_cnt & 7,
+            "DISPCNT mode");
+        check(graphics.disp_cnt.hblank_interval_free == bit(disp_cnt, 5) &&
+            graphics.disp_cnt.sprite_2d == !bit(disp_cnt, 6) &&
+            graphics.disp_cnt.forced_blank == bit(disp_cnt, 7) &&
+            (0..4).all(|i| graphics.disp_cnt.bg_enabled[i] == bit(disp_cnt, 8 + i as u16)) &&
+            graphics.disp_cnt.obj_enabled == bit(disp_cnt, 12) &&
+            graphics.disp_cnt.window_enabled[0] == bit(disp_cnt, 13) &&
+            graphics.disp_cnt.window_enabled[1] == bit(disp_cnt, 14) &&
+            graphics.disp_cnt.obj_win_enabled == bit(disp_cnt, 15),
+            "DISPCNT");
+        check(graphics.disp_stat.as_u16() & 0xFF38 == raw(DISPSTAT_LO) & 0xFF38,
+            "DISPSTAT");
+        for bg in 0..4 {
+            let bg_cnt = raw(BGCNT_START + bg as u32 * 2);
+            let parsed = &graphics.bg_cnt[bg];
+            check(parsed.priority as u16 == bg_cnt & 3 &&
+                parsed.tile_addr == 0x6000000 + ((bg_cnt >> 2) as u32 & 3) * 0x4000 &&
+                parsed.mosaic_enabled == bit(bg_cnt, 6) &&
+                parsed.depth == if bit(bg_cnt, 7) { 8 } else { 4 } &&
+                parsed.map_addr == 0x6000000 + ((bg_cnt >> 8) as u32 & 0x1F) * 0x800 &&
+                parsed.size as u16 == bg_cnt >> 14,
+                &format!("BG{}CNT", bg));
+            let offset = BG_OFFSET_START + bg as u32 * 4;
+            check(graphics.bg_offset_x[bg] == raw(offset) & 0x1FF,
+                &format!("BG{}HOFS", bg));
+            check(graphics.bg_offset_y[bg] == raw(offset + 2) & 0x1FF,
+                &format!("BG{}VOFS", bg));
+        }
+
+        check(self.int.enabled.as_u16() == raw(IE_LO) & 0x3FFF, "IE");
+        check(self.int.triggered.as_u16() == raw(IF_LO), "IF");
+        check(self.int.master_enabled == bit(raw(IME), 0), "IME");
+
+        let sound_cnt_l = raw(SOUNDCNT_L);
+        check(self.apu.master_enabled == bit(raw(SOUNDCNT_X), 7), "SOUNDCNT_X master enable");
+        check(self.apu.volumes == ((sound_cnt_l & 7) as u8, ((sound_cnt_l >> 4) & 7) as u8) &&
+            (0..4).all(|i| self.apu.outputs[i] ==
+                (bit(sound_cnt_l, 8 + i as u16), bit(sound_cnt_l, 12 + i as u16))),
+            "SOUNDCNT_L");
+
+        for channel in 0..4 {
+            // with an override, whether the channel runs has nothing to do
+            // with the register
+            if self.dma.overrides[channel].is_none() {
+                check(self.dma.is_enabled(channel) == bit(raw(DMA_CNT[channel]), 15),
+                    &format!("DMA{}CNT_H enable bit", channel));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use cpu::CPUWrapper;
+
+    #[test]
+    fn round_trip() {
+        let writes = vec![
+            MmioWrite { cycle: 0, addr: 0x4000000, size: 2, value: 0x1403 },
+            MmioWrite { cycle: 300, addr: 0x4000208, size: 1, value: 1 },
+            MmioWrite { cycle: 300, addr: 0x40000D4, size: 4, value: 0x8000000 },
+            MmioWrite { cycle: 280896, addr: 0x4000202, size: 2, value: 0xFFFF },
+        ];
+        let fixture = encode(&writes);
+        // 4 header bytes and a version, then 1+2+2, 2+2+1, 1+2+4, 3+2+2
+        assert_eq!(fixture.len(), 5 + 5 + 5 + 7 + 7);
+        assert_eq!(decode(&fixture), Ok(writes));
+
+        assert_eq!(decode(b"MMIO\x02"), Err(FixtureError::BadHeader));
+        assert_eq!(decode(&fixture[..fixture.len() - 1]), Err(FixtureError::Truncated));
+        assert_eq!(decode(b"MMIO\x01\x00\x00\xC0"), Err(FixtureError::BadSize(0)));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut gba = CPUWrapper::new();
+        let code: [u32; 5] = [
+            0xE3A00301, // mov r0, #0x4000000
+            0xE3A01B05, // mov r1, #0x1400
+            0xE2811003, // add r1, r1, #3
+            0xE1C010B0, // strh r1, [r0]
+            0xE5C01208, // strb r1, [r0, #0x208]
+        ];
+        for (i, ins) in code.iter().enumerate() {
+            gba.cpu.mem.set_word(0x3000000 + i as u32 * 4, *ins);
+        }
+        gba.direct_boot_at(0x3000000);
+        gba.cpu.mem.mmio_log.start();
+        // the 5 instructions after filling the pipeline
+        for _ in 0..7 {
+            gba.step().unwrap();
+        }
+        let writes = gba.cpu.mem.mmio_log.writes().to_vec();
+        assert_eq!(writes.len(), 2);
+        assert_eq!((writes[0].addr, writes[0].size, writes[0].value), (0x4000000, 2, 0x1403));
+        assert_eq!((writes[1].addr, writes[1].size, writes[1].value), (0x4000208, 1, 3));
+        assert!(writes[1].cycle > writes[0].cycle);
+
+        let fixture = gba.cpu.mem.mmio_log.stop();
+        assert!(!gba.cpu.mem.mmio_log.recording);
+        let mut mem = Memory::new();
+        mem.replay_mmio(&decode(&fixture).unwrap()).unwrap();
+        assert_eq!(mem.graphics.disp_cnt.bg_mode, 3);
+        assert!(mem.int.master_enabled);
+    }
+
+    #[test]
+    fn replay_catches_stale_state() {
+        let mut mem = Memory::new();
+        // IE changed behind the handlers' backs
+        mem.raw.set_halfword(0x4000200, 1);
+        let write = MmioWrite { cycle: 0, addr: 0x4000010, size: 2, value: 4 };
+        let err = mem.replay_mmio(&[write]).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert_eq!(err.problems, vec!["IE doesn't match the raw register".to_string()]);
+    }
+
+    /// Replay every recorded fixture. There's always at least one, so a
+    /// missing directory fails instead of passing without checking anything
+    #[test]
+    fn fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/mmio");
+        let entries = fs::read_dir(&dir)
+            .unwrap_or_else(|err| panic!("{}: {}", dir.display(), err));
+        let mut replayed = 0;
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "mmio") {
+                continue;
+            }
+            replayed += 1;
+            let writes = decode(&fs::read(&path).unwrap())
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            if let Err(err) = Memory::new().replay_mmio(&writes) {
+                panic!("{}: {}", path.display(), err);
+            }
+        }
+        assert!(replayed > 0, "no fixtures in {}", dir.display());
+    }
+}
@@ -14,6 +14,17 @@
 //! to Memory but are implemented in the submodules here
 
 pub mod addrs;
+pub mod boot;
+pub mod debug;
 pub mod graphics;
 pub mod dma;
-pub mod interrupt;
\ No newline at end of file
+pub mod interrupt;
+pub mod mmio_log;
+pub mod psg;
+pub mod serial;
+pub mod sound;
+pub mod timers;
+pub mod unsupported;
+pub mod waitcnt;
+#[cfg(feature = "debugger")]
+pub mod registers;
@@ -0,0 +1,510 @@
+//! The four PSG channels inherited from the Game Boy: two square waves (the
+//! first with a frequency sweep), a channel that plays 4 bit samples from wave
+//! RAM, and a noise channel. Each channel steps through its waveform at a
+//! rate set by its frequency register, and outputs a level from 0 to 15
+//! which is centered on 0 here so that a channel at rest is silent.
+//!
+//! The frame sequencer clocks the slower parts of the channels 512 times a
+//! second, in a cycle of 8 steps: the length counters on every other step
+//! (256 Hz), the sweep on steps 2 and 6 (128 Hz), and the envelopes on step 7
+//! (64 Hz).
+//!
+//! The registers are parsed by the sound module. This only has the state of
+//! each channel and the stepping logic
+
+/// the frame sequencer steps every 32768 cycles, 512 times a second
+pub const CYCLES_PER_SEQUENCER_STEP: u32 = 0x8000;
+
+/// the 8 steps of each duty cycle (12.5%, 25%, 50% and 75%)
+const DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false],
+];
+
+/// Stops a channel once it has played for its length, if enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Length {
+    pub enabled: bool,
+    /// steps left before the channel stops
+    pub counter: u16,
+    /// 64, or 256 for the wave channel
+    max: u16,
+}
+
+impl Length {
+    pub const fn new(max: u16) -> Length {
+        Length { enabled: false, counter: 0, max }
+    }
+
+    /// Set the length from a register, which holds the number of steps
+    /// already taken
+    pub fn load(&mut self, taken: u16) {
+        self.counter = self.max - taken;
+    }
+
+    /// A restart plays for the full length if the last length ran out
+    fn restart(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Count down, and return true if the channel should stop
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// Fades the volume of a square or noise channel up or down over time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub initial: u8,
+    pub increase: bool,
+    /// in 64ths of a second, 0 to keep the volume constant
+    pub step_time: u8,
+    pub volume: u8,
+    pub timer: u8,
+}
+
+impl Envelope {
+    pub const fn new() -> Envelope {
+        Envelope { initial: 0, increase: false, step_time: 0, volume: 0, timer: 0 }
+    }
+
+    /// Parse the upper byte of the channel's envelope register
+    ///   F E D C  B A 9 8
+    ///   V V V V  D T T T
+    ///   8-A (T) = step time, B (D) = increase, C-F (V) = initial volume
+    pub fn parse(&mut self, val: u8) {
+        self.step_time = val & 7;
+        self.increase = val & 8 != 0;
+        self.initial = val >> 4;
+    }
+
+    /// The channel can only play while it has an initial volume or is set to
+    /// fade in. Otherwise it's turned off, like on the Game Boy
+    pub fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn restart(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.step_time;
+    }
+
+    fn clock(&mut self) {
+        if self.step_time == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.step_time;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+/// Moves the frequency of channel 1 up or down over time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sweep {
+    pub shift: u8,
+    pub decrease: bool,
+    /// in 128ths of a second, 0 to leave the frequency alone
+    pub step_time: u8,
+    pub timer: u8,
+    /// the frequency the sweep works from, copied when the channel restarts
+    pub shadow: u16,
+    pub enabled: bool,
+}
+
+impl Sweep {
+    pub const fn new() -> Sweep {
+        Sweep { shift: 0, decrease: false, step_time: 0, timer: 0, shadow: 0, enabled: false }
+    }
+
+    /// Return the next frequency, which is over 2047 if the sweep overflowed
+    fn next(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.decrease { self.shadow - delta } else { self.shadow + delta }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Square {
+    /// whether the channel is playing
+    pub enabled: bool,
+    /// 0-3, see DUTY_PATTERNS
+    pub duty: u8,
+    /// 11 bits, the tone is 131072 / (2048 - freq) Hz
+    pub freq: u16,
+    pub length: Length,
+    pub envelope: Envelope,
+    /// only used by channel 1
+    pub sweep: Sweep,
+    /// cycles until the next step of the duty cycle
+    pub countdown: u32,
+    /// 0-7
+    pub position: u8,
+}
+
+impl Square {
+    pub const fn new() -> Square {
+        Square {
+            enabled: false,
+            duty: 0,
+            freq: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: Sweep::new(),
+            countdown: 0,
+            position: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 16
+    }
+
+    pub fn restart(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.restart();
+        self.envelope.restart();
+        self.countdown = self.period();
+        let sweep = &mut self.sweep;
+        sweep.shadow = self.freq;
+        sweep.timer = if sweep.step_time == 0 { 8 } else { sweep.step_time };
+        sweep.enabled = sweep.step_time != 0 || sweep.shift != 0;
+        if sweep.shift != 0 && sweep.next() > 2047 {
+            self.enabled = false;
+        }
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.countdown {
+            cycles -= self.countdown;
+            self.countdown = self.period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.countdown -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = &mut self.sweep;
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = if sweep.step_time == 0 { 8 } else { sweep.step_time };
+        if !sweep.enabled || sweep.step_time == 0 {
+            return;
+        }
+        let freq = sweep.next();
+        if freq > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = freq;
+            self.freq = freq;
+            // the next step is checked straight away too
+            if sweep.next() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Return the level the channel is outputting, from -15 to 15
+    pub fn output(&self) -> i32 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i32;
+        if DUTY_PATTERNS[self.duty as usize][self.position as usize] { volume } else { -volume }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Wave {
+    pub enabled: bool,
+    /// bit 7 of SOUND3CNT_L. when clear the channel is off
+    pub dac_enabled: bool,
+    /// play both banks as one 64 sample wave instead of one 32 sample bank
+    pub two_banks: bool,
+    /// the bank played (or played first, with two_banks)
+    pub bank: usize,
+    /// 11 bits, each sample is played for (2048 - freq) * 8 cycles
+    pub freq: u16,
+    pub length: Length,
+    /// the volume as a fraction: 0, 1/4, 1/2, 3/4 or 1, in quarters
+    pub volume_quarters: i32,
+    pub countdown: u32,
+    /// the sample being played, counting from the start of the first bank
+    pub position: u8,
+}
+
+impl Wave {
+    pub const fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            two_banks: false,
+            bank: 0,
+            freq: 0,
+            length: Length::new(256),
+            volume_quarters: 0,
+            countdown: 0,
+            position: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 8
+    }
+
+    pub fn restart(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.restart();
+        self.countdown = self.period();
+        self.position = 0;
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        let samples = if self.two_banks { 64 } else { 32 };
+        let mut cycles = cycles;
+        while cycles >= self.countdown {
+            cycles -= self.countdown;
+            self.countdown = self.period();
+            self.position = (self.position + 1) % samples;
+        }
+        self.countdown -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Return the level the channel is outputting, from -15 to 15, given
+    /// both banks of wave RAM. Each byte holds two samples, the upper nibble
+    /// first
+    pub fn output(&self, banks: &[[u8; 16]; 2]) -> i32 {
+        if !self.enabled {
+            return 0;
+        }
+        let i = self.position as usize;
+        let byte = banks[(self.bank + i / 32) % 2][(i % 32) / 2];
+        let sample = if i % 2 == 0 { byte >> 4 } else { byte & 0xF };
+        (2 * sample as i32 - 15) * self.volume_quarters / 4
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Noise {
+    pub enabled: bool,
+    /// the divider, where 0 counts as 0.5
+    pub ratio: u8,
+    pub shift: u8,
+    /// use a 7 bit shift register instead of 15 bits, which repeats sooner
+    /// and sounds more metallic
+    pub short: bool,
+    pub length: Length,
+    pub envelope: Envelope,
+    pub countdown: u32,
+    pub lfsr: u16,
+}
+
+impl Noise {
+    pub const fn new() -> Noise {
+        Noise {
+            enabled: false,
+            ratio: 0,
+            shift: 0,
+            short: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            countdown: 0,
+            lfsr: 0,
+        }
+    }
+
+    /// The register is shifted at 524288 / ratio / 2^(shift + 1) Hz
+    fn period(&self) -> u32 {
+        let ratio = if self.ratio == 0 { 16 } else { 32 * self.ratio as u32 };
+        ratio << (self.shift + 1)
+    }
+
+    pub fn restart(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.restart();
+        self.envelope.restart();
+        self.countdown = self.period();
+        self.lfsr = if self.short { 0x40 } else { 0x4000 };
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.countdown {
+            cycles -= self.countdown;
+            self.countdown = self.period();
+            // the output is the bit that's shifted out
+            let carry = self.lfsr & 1;
+            self.lfsr >>= 1;
+            if carry == 1 {
+                self.lfsr ^= if self.short { 0x60 } else { 0x6000 };
+            }
+        }
+        self.countdown -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Return the level the channel is outputting, from -15 to 15
+    pub fn output(&self) -> i32 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i32;
+        // the bit last shifted out set the top bit of the feedback
+        let high = self.lfsr & if self.short { 0x40 } else { 0x4000 } != 0;
+        if high { volume } else { -volume }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn square() {
+        let mut square = Square::new();
+        square.duty = 2;
+        square.freq = 2047;
+        square.envelope.parse(0xF0);
+        square.restart();
+        assert!(square.enabled);
+        assert_eq!(square.output(), 15);
+        // each step is 16 cycles at the highest frequency
+        square.advance(16 * 4);
+        assert_eq!(square.position, 4);
+        assert_eq!(square.output(), -15);
+        square.advance(16 * 5 + 3);
+        assert_eq!((square.position, square.countdown), (1, 13));
+
+        // an envelope with no volume that doesn't fade in is off
+        square.envelope.parse(0x00);
+        square.restart();
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn length_and_envelope() {
+        let mut square = Square::new();
+        square.envelope.parse(0x29);
+        square.length.load(62);
+        square.length.enabled = true;
+        square.restart();
+        assert_eq!(square.envelope.volume, 2);
+        square.clock_envelope();
+        assert_eq!(square.envelope.volume, 3);
+        square.clock_length();
+        assert!(square.enabled);
+        square.clock_length();
+        assert!(!square.enabled);
+
+        // restarting after the length ran out plays the full length
+        square.restart();
+        assert_eq!(square.length.counter, 64);
+    }
+
+    #[test]
+    fn sweep() {
+        let mut square = Square::new();
+        square.envelope.parse(0xF0);
+        square.freq = 0x300;
+        // shift 1, increasing, every step
+        square.sweep.shift = 1;
+        square.sweep.step_time = 1;
+        square.restart();
+        square.clock_sweep();
+        assert_eq!(square.freq, 0x480);
+        assert!(square.enabled);
+        // the step after 0x6C0 would overflow, which stops the channel as
+        // soon as it's reached
+        square.clock_sweep();
+        assert_eq!(square.freq, 0x6C0);
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn wave() {
+        let mut banks = [[0; 16]; 2];
+        banks[0][0] = 0xF0;
+        banks[1][0] = 0x8F;
+        let mut wave = Wave::new();
+        wave.dac_enabled = true;
+        wave.volume_quarters = 4;
+        wave.freq = 2047;
+        wave.restart();
+        assert_eq!(wave.output(&banks), 15);
+        wave.advance(8);
+        assert_eq!(wave.output(&banks), -15);
+        // one bank wraps around to itself
+        wave.advance(8 * 31);
+        assert_eq!(wave.output(&banks), 15);
+        wave.two_banks = true;
+        wave.advance(8 * 32);
+        assert_eq!(wave.output(&banks), 1);
+        wave.volume_quarters = 2;
+        wave.advance(8);
+        assert_eq!(wave.output(&banks), 7);
+    }
+
+    #[test]
+    fn noise() {
+        let mut noise = Noise::new();
+        noise.envelope.parse(0xF0);
+        noise.short = true;
+        noise.restart();
+        assert_eq!(noise.period(), 32);
+        // the 7 bit register repeats every 127 steps
+        let start = noise.lfsr;
+        let mut steps = 0;
+        loop {
+            noise.advance(32);
+            steps += 1;
+            if noise.lfsr == start {
+                break;
+            }
+        }
+        assert_eq!(steps, 127);
+    }
+}
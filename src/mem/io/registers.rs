@@ -0,0 +1,469 @@
+//! A table describing the layout of each known IO register, used to produce
+//! a human readable dump of the IO map for debugging and bug reports. This is
+//! only used for display: the parsed state used by the emulator is still
+//! updated by the handlers in the other io modules.
+
+use std::fmt::Write;
+use mem::Memory;
+use mem::io::addrs::DMA_CNT;
+
+/// A single field within a register
+pub enum Field {
+    /// an unsigned number: name, lowest bit, number of bits
+    Value(&'static str, u8, u8),
+    /// a bit that is only shown when set: name, bit
+    Flag(&'static str, u8),
+    /// a bit that picks between two settings: bit, text when clear, text when set
+    Choice(u8, &'static str, &'static str),
+    /// a signed fixed point number with 8 fractional bits starting from bit 0:
+    /// name, number of bits
+    Fixed(&'static str, u8),
+}
+
+pub struct IoRegister {
+    pub name: &'static str,
+    pub addr: u32,
+    /// size in bytes (2 or 4)
+    pub size: u8,
+    pub fields: &'static [Field],
+}
+
+impl Field {
+    /// Return the decoded text for this field, or None if there's nothing to
+    /// show (i.e. a flag that isn't set)
+    fn decode(&self, raw: u32) -> Option<String> {
+        match *self {
+            Field::Value(name, lo, len) =>
+                Some(format!("{}={}", name, (raw >> lo) & ((1 << len) - 1))),
+            Field::Flag(name, bit) =>
+                if (raw >> bit) & 1 == 1 { Some(format!("{} on", name)) } else { None },
+            Field::Choice(bit, off, on) =>
+                Some(if (raw >> bit) & 1 == 1 { on } else { off }.to_string()),
+            Field::Fixed(name, len) => {
+                let shift = 32 - len as u32;
+                let val = ((raw << shift) as i32 >> shift) as f32 / 256.0;
+                Some(format!("{}={}", name, val))
+            },
+        }
+    }
+}
+
+impl IoRegister {
+    /// Return the decoded text of each field that has something to show
+    pub fn decode_fields(&self, raw: u32) -> Vec<String> {
+        self.fields.iter()
+            .filter_map(|field| field.decode(raw))
+            .collect()
+    }
+
+    /// Format the register as e.g. "BLDY=0x0010: EVY=16"
+    pub fn decode(&self, raw: u32) -> String {
+        let fields = self.decode_fields(raw);
+        let mut out = if self.size == 4 {
+            format!("{}={:#010X}", self.name, raw)
+        } else {
+            format!("{}={:#06X}", self.name, raw)
+        };
+        if !fields.is_empty() {
+            out.push_str(": ");
+            out.push_str(&fields.join(", "));
+        }
+        out
+    }
+}
+
+const DISPCNT: &[Field] = &[
+    Field::Value("mode", 0, 3),
+    Field::Value("frame", 4, 1),
+    Field::Flag("HBlank free", 5),
+    Field::Choice(6, "2D mapping", "1D mapping"),
+    Field::Flag("forced blank", 7),
+    Field::Flag("BG0", 8),
+    Field::Flag("BG1", 9),
+    Field::Flag("BG2", 10),
+    Field::Flag("BG3", 11),
+    Field::Flag("OBJ", 12),
+    Field::Flag("WIN0", 13),
+    Field::Flag("WIN1", 14),
+    Field::Flag("OBJWIN", 15),
+];
+
+const DISPSTAT: &[Field] = &[
+    Field::Flag("VBlank", 0),
+    Field::Flag("HBlank", 1),
+    Field::Flag("VCount", 2),
+    Field::Flag("VBlank IRQ", 3),
+    Field::Flag("HBlank IRQ", 4),
+    Field::Flag("VCount IRQ", 5),
+    Field::Value("trigger", 8, 8),
+];
+
+const VCOUNT: &[Field] = &[Field::Value("line", 0, 8)];
+
+const BGCNT: &[Field] = &[
+    Field::Value("priority", 0, 2),
+    Field::Value("tile base", 2, 2),
+    Field::Flag("mosaic", 6),
+    Field::Choice(7, "4bpp", "8bpp"),
+    Field::Value("map base", 8, 5),
+    Field::Flag("wraparound", 13),
+    Field::Value("size", 14, 2),
+];
+
+const BG_OFFSET: &[Field] = &[Field::Value("offset", 0, 9)];
+const BG_AFFINE_PARAM: &[Field] = &[Field::Fixed("value", 16)];
+const BG_REF_POINT: &[Field] = &[Field::Fixed("value", 28)];
+
+const WIN_H: &[Field] = &[Field::Value("left", 8, 8), Field::Value("right", 0, 8)];
+const WIN_V: &[Field] = &[Field::Value("top", 8, 8), Field::Value("bottom", 0, 8)];
+
+const WININ: &[Field] = &[
+    Field::Flag("WIN0 BG0", 0),
+    Field::Flag("WIN0 BG1", 1),
+    Field::Flag("WIN0 BG2", 2),
+    Field::Flag("WIN0 BG3", 3),
+    Field::Flag("WIN0 OBJ", 4),
+    Field::Flag("WIN0 blend", 5),
+    Field::Flag("WIN1 BG0", 8),
+    Field::Flag("WIN1 BG1", 9),
+    Field::Flag("WIN1 BG2", 10),
+    Field::Flag("WIN1 BG3", 11),
+    Field::Flag("WIN1 OBJ", 12),
+    Field::Flag("WIN1 blend", 13),
+];
+
+const WINOUT: &[Field] = &[
+    Field::Flag("outside BG0", 0),
+    Field::Flag("outside BG1", 1),
+    Field::Flag("outside BG2", 2),
+    Field::Flag("outside BG3", 3),
+    Field::Flag("outside OBJ", 4),
+    Field::Flag("outside blend", 5),
+    Field::Flag("OBJWIN BG0", 8),
+    Field::Flag("OBJWIN BG1", 9),
+    Field::Flag("OBJWIN BG2", 10),
+    Field::Flag("OBJWIN BG3", 11),
+    Field::Flag("OBJWIN OBJ", 12),
+    Field::Flag("OBJWIN blend", 13),
+];
+
+const MOSAIC: &[Field] = &[
+    Field::Value("BG h", 0, 4),
+    Field::Value("BG v", 4, 4),
+    Field::Value("OBJ h", 8, 4),
+    Field::Value("OBJ v", 12, 4),
+];
+
+const BLDCNT: &[Field] = &[
+    Field::Flag("1st BG0", 0),
+    Field::Flag("1st BG1", 1),
+    Field::Flag("1st BG2", 2),
+    Field::Flag("1st BG3", 3),
+    Field::Flag("1st OBJ", 4),
+    Field::Flag("1st backdrop", 5),
+    Field::Value("mode", 6, 2),
+    Field::Flag("2nd BG0", 8),
+    Field::Flag("2nd BG1", 9),
+    Field::Flag("2nd BG2", 10),
+    Field::Flag("2nd BG3", 11),
+    Field::Flag("2nd OBJ", 12),
+    Field::Flag("2nd backdrop", 13),
+];
+
+const BLDALPHA: &[Field] = &[Field::Value("EVA", 0, 5), Field::Value("EVB", 8, 5)];
+const BLDY: &[Field] = &[Field::Value("EVY", 0, 5)];
+
+const SWEEP: &[Field] = &[
+    Field::Value("shift", 0, 3),
+    Field::Choice(3, "up", "down"),
+    Field::Value("time", 4, 3),
+];
+
+const DUTY_ENVELOPE: &[Field] = &[
+    Field::Value("length", 0, 6),
+    Field::Value("duty", 6, 2),
+    Field::Value("step time", 8, 3),
+    Field::Choice(11, "fade out", "fade in"),
+    Field::Value("volume", 12, 4),
+];
+
+const FREQUENCY: &[Field] = &[
+    Field::Value("freq", 0, 11),
+    Field::Flag("timed", 14),
+];
+
+const SOUND3CNT_L: &[Field] = &[
+    Field::Choice(5, "one bank", "two banks"),
+    Field::Value("bank", 6, 1),
+    Field::Flag("playing", 7),
+];
+
+const SOUND3CNT_H: &[Field] = &[
+    Field::Value("length", 0, 8),
+    Field::Value("volume", 13, 2),
+    Field::Flag("75%", 15),
+];
+
+const SOUND4CNT_L: &[Field] = &[
+    Field::Value("length", 0, 6),
+    Field::Value("step time", 8, 3),
+    Field::Choice(11, "fade out", "fade in"),
+    Field::Value("volume", 12, 4),
+];
+
+const SOUND4CNT_H: &[Field] = &[
+    Field::Value("ratio", 0, 3),
+    Field::Choice(3, "15 bit", "7 bit"),
+    Field::Value("shift", 4, 4),
+    Field::Flag("timed", 14),
+];
+
+const SOUNDCNT_L: &[Field] = &[
+    Field::Value("right volume", 0, 3),
+    Field::Value("left volume", 4, 3),
+    Field::Flag("1 right", 8),
+    Field::Flag("2 right", 9),
+    Field::Flag("3 right", 10),
+    Field::Flag("4 right", 11),
+    Field::Flag("1 left", 12),
+    Field::Flag("2 left", 13),
+    Field::Flag("3 left", 14),
+    Field::Flag("4 left", 15),
+];
+
+const SOUNDCNT_H: &[Field] = &[
+    Field::Value("PSG volume", 0, 2),
+    Field::Choice(2, "A 50%", "A 100%"),
+    Field::Choice(3, "B 50%", "B 100%"),
+    Field::Flag("A right", 8),
+    Field::Flag("A left", 9),
+    Field::Value("A timer", 10, 1),
+    Field::Flag("B right", 12),
+    Field::Flag("B left", 13),
+    Field::Value("B timer", 14, 1),
+];
+
+const SOUNDCNT_X: &[Field] = &[
+    Field::Flag("1 playing", 0),
+    Field::Flag("2 playing", 1),
+    Field::Flag("3 playing", 2),
+    Field::Flag("4 playing", 3),
+    Field::Flag("enabled", 7),
+];
+
+const SOUNDBIAS: &[Field] = &[
+    Field::Value("bias", 0, 10),
+    Field::Value("resolution", 14, 2),
+];
+
+const DMA_COUNT: &[Field] = &[Field::Value("count", 0, 16)];
+const DMA_CONTROL: &[Field] = &[
+    Field::Value("dest incr", 5, 2),
+    Field::Value("src incr", 7, 2),
+    Field::Flag("repeat", 9),
+    Field::Choice(10, "16 bit", "32 bit"),
+    Field::Value("timing", 12, 2),
+    Field::Flag("IRQ", 14),
+    Field::Flag("enabled", 15),
+];
+
+/// the raw TMxCNT_L is the reload value, not the current count
+const TIMER_RELOAD: &[Field] = &[Field::Value("reload", 0, 16)];
+const TIMER_CONTROL: &[Field] = &[
+    Field::Value("prescaler", 0, 2),
+    Field::Flag("count up", 2),
+    Field::Flag("IRQ", 6),
+    Field::Flag("enabled", 7),
+];
+
+const SIOCNT: &[Field] = &[
+    Field::Value("baud", 0, 2),
+    Field::Flag("SI", 2),
+    Field::Flag("SD", 3),
+    Field::Value("ID", 4, 2),
+    Field::Flag("error", 6),
+    Field::Flag("busy", 7),
+    Field::Value("mode", 12, 2),
+    Field::Flag("IRQ", 14),
+];
+
+const RCNT: &[Field] = &[Field::Value("mode", 14, 2)];
+
+const INTERRUPTS: &[Field] = &[
+    Field::Flag("VBlank", 0),
+    Field::Flag("HBlank", 1),
+    Field::Flag("VCount", 2),
+    Field::Flag("timer 0", 3),
+    Field::Flag("timer 1", 4),
+    Field::Flag("timer 2", 5),
+    Field::Flag("timer 3", 6),
+    Field::Flag("serial", 7),
+    Field::Flag("DMA0", 8),
+    Field::Flag("DMA1", 9),
+    Field::Flag("DMA2", 10),
+    Field::Flag("DMA3", 11),
+    Field::Flag("keypad", 12),
+    Field::Flag("game pak", 13),
+];
+
+const WAITCNT: &[Field] = &[
+    Field::Value("SRAM", 0, 2),
+    Field::Value("WS0 N", 2, 2),
+    Field::Value("WS0 S", 4, 1),
+    Field::Value("WS1 N", 5, 2),
+    Field::Value("WS1 S", 7, 1),
+    Field::Value("WS2 N", 8, 2),
+    Field::Value("WS2 S", 10, 1),
+    Field::Flag("prefetch", 14),
+];
+
+const IME: &[Field] = &[Field::Flag("master", 0)];
+
+macro_rules! reg {
+    ($name:expr, $addr:expr, $size:expr, $fields:expr) => {
+        IoRegister { name: $name, addr: $addr, size: $size, fields: $fields }
+    }
+}
+
+/// All registers that the emulator knows about, in address order
+pub const IO_REGISTERS: &[IoRegister] = &[
+    reg!("DISPCNT", 0x4000000, 2, DISPCNT),
+    reg!("DISPSTAT", 0x4000004, 2, DISPSTAT),
+    reg!("VCOUNT", 0x4000006, 2, VCOUNT),
+    reg!("BG0CNT", 0x4000008, 2, BGCNT),
+    reg!("BG1CNT", 0x400000A, 2, BGCNT),
+    reg!("BG2CNT", 0x400000C, 2, BGCNT),
+    reg!("BG3CNT", 0x400000E, 2, BGCNT),
+    reg!("BG0HOFS", 0x4000010, 2, BG_OFFSET),
+    reg!("BG0VOFS", 0x4000012, 2, BG_OFFSET),
+    reg!("BG1HOFS", 0x4000014, 2, BG_OFFSET),
+    reg!("BG1VOFS", 0x4000016, 2, BG_OFFSET),
+    reg!("BG2HOFS", 0x4000018, 2, BG_OFFSET),
+    reg!("BG2VOFS", 0x400001A, 2, BG_OFFSET),
+    reg!("BG3HOFS", 0x400001C, 2, BG_OFFSET),
+    reg!("BG3VOFS", 0x400001E, 2, BG_OFFSET),
+    reg!("BG2PA", 0x4000020, 2, BG_AFFINE_PARAM),
+    reg!("BG2PB", 0x4000022, 2, BG_AFFINE_PARAM),
+    reg!("BG2PC", 0x4000024, 2, BG_AFFINE_PARAM),
+    reg!("BG2PD", 0x4000026, 2, BG_AFFINE_PARAM),
+    reg!("BG2X", 0x4000028, 4, BG_REF_POINT),
+    reg!("BG2Y", 0x400002C, 4, BG_REF_POINT),
+    reg!("BG3PA", 0x4000030, 2, BG_AFFINE_PARAM),
+    reg!("BG3PB", 0x4000032, 2, BG_AFFINE_PARAM),
+    reg!("BG3PC", 0x4000034, 2, BG_AFFINE_PARAM),
+    reg!("BG3PD", 0x4000036, 2, BG_AFFINE_PARAM),
+    reg!("BG3X", 0x4000038, 4, BG_REF_POINT),
+    reg!("BG3Y", 0x400003C, 4, BG_REF_POINT),
+    reg!("WIN0H", 0x4000040, 2, WIN_H),
+    reg!("WIN1H", 0x4000042, 2, WIN_H),
+    reg!("WIN0V", 0x4000044, 2, WIN_V),
+    reg!("WIN1V", 0x4000046, 2, WIN_V),
+    reg!("WININ", 0x4000048, 2, WININ),
+    reg!("WINOUT", 0x400004A, 2, WINOUT),
+    reg!("MOSAIC", 0x400004C, 2, MOSAIC),
+    reg!("BLDCNT", 0x4000050, 2, BLDCNT),
+    reg!("BLDALPHA", 0x4000052, 2, BLDALPHA),
+    reg!("BLDY", 0x4000054, 2, BLDY),
+    reg!("SOUND1CNT_L", 0x4000060, 2, SWEEP),
+    reg!("SOUND1CNT_H", 0x4000062, 2, DUTY_ENVELOPE),
+    reg!("SOUND1CNT_X", 0x4000064, 2, FREQUENCY),
+    reg!("SOUND2CNT_L", 0x4000068, 2, DUTY_ENVELOPE),
+    reg!("SOUND2CNT_H", 0x400006C, 2, FREQUENCY),
+    reg!("SOUND3CNT_L", 0x4000070, 2, SOUND3CNT_L),
+    reg!("SOUND3CNT_H", 0x4000072, 2, SOUND3CNT_H),
+    reg!("SOUND3CNT_X", 0x4000074, 2, FREQUENCY),
+    reg!("SOUND4CNT_L", 0x4000078, 2, SOUND4CNT_L),
+    reg!("SOUND4CNT_H", 0x400007C, 2, SOUND4CNT_H),
+    reg!("SOUNDCNT_L", 0x4000080, 2, SOUNDCNT_L),
+    reg!("SOUNDCNT_H", 0x4000082, 2, SOUNDCNT_H),
+    reg!("SOUNDCNT_X", 0x4000084, 2, SOUNDCNT_X),
+    reg!("SOUNDBIAS", 0x4000088, 2, SOUNDBIAS),
+    reg!("DMA0SAD", 0x40000B0, 4, &[]),
+    reg!("DMA0DAD", 0x40000B4, 4, &[]),
+    reg!("DMA0CNT_L", 0x40000B8, 2, DMA_COUNT),
+    reg!("DMA0CNT_H", 0x40000BA, 2, DMA_CONTROL),
+    reg!("DMA1SAD", 0x40000BC, 4, &[]),
+    reg!("DMA1DAD", 0x40000C0, 4, &[]),
+    reg!("DMA1CNT_L", 0x40000C4, 2, DMA_COUNT),
+    reg!("DMA1CNT_H", 0x40000C6, 2, DMA_CONTROL),
+    reg!("DMA2SAD", 0x40000C8, 4, &[]),
+    reg!("DMA2DAD", 0x40000CC, 4, &[]),
+    reg!("DMA2CNT_L", 0x40000D0, 2, DMA_COUNT),
+    reg!("DMA2CNT_H", 0x40000D2, 2, DMA_CONTROL),
+    reg!("DMA3SAD", 0x40000D4, 4, &[]),
+    reg!("DMA3DAD", 0x40000D8, 4, &[]),
+    reg!("DMA3CNT_L", 0x40000DC, 2, DMA_COUNT),
+    reg!("DMA3CNT_H", 0x40000DE, 2, DMA_CONTROL),
+    reg!("TM0CNT_L", 0x4000100, 2, TIMER_RELOAD),
+    reg!("TM0CNT_H", 0x4000102, 2, TIMER_CONTROL),
+    reg!("TM1CNT_L", 0x4000104, 2, TIMER_RELOAD),
+    reg!("TM1CNT_H", 0x4000106, 2, TIMER_CONTROL),
+    reg!("TM2CNT_L", 0x4000108, 2, TIMER_RELOAD),
+    reg!("TM2CNT_H", 0x400010A, 2, TIMER_CONTROL),
+    reg!("TM3CNT_L", 0x400010C, 2, TIMER_RELOAD),
+    reg!("TM3CNT_H", 0x400010E, 2, TIMER_CONTROL),
+    reg!("SIOMULTI0", 0x4000120, 2, &[]),
+    reg!("SIOMULTI1", 0x4000122, 2, &[]),
+    reg!("SIOMULTI2", 0x4000124, 2, &[]),
+    reg!("SIOMULTI3", 0x4000126, 2, &[]),
+    reg!("SIOCNT", 0x4000128, 2, SIOCNT),
+    reg!("SIOMLT_SEND", 0x400012A, 2, &[]),
+    reg!("RCNT", 0x4000134, 2, RCNT),
+    reg!("IE", 0x4000200, 2, INTERRUPTS),
+    reg!("IF", 0x4000202, 2, INTERRUPTS),
+    reg!("WAITCNT", 0x4000204, 2, WAITCNT),
+    reg!("IME", 0x4000208, 2, IME),
+];
+
+impl Memory {
+    /// Return the raw value of the register, without any side effects
+    pub fn io_register_value(&self, reg: &IoRegister) -> u32 {
+        if reg.size == 4 {
+            self.raw.get_word(reg.addr)
+        } else {
+            self.raw.get_halfword(reg.addr) as u32
+        }
+    }
+
+    /// Return every known IO register with its raw value and decoded fields,
+    /// one per line. Debug overrides that change how a register behaves are
+    /// noted after its value
+    pub fn dump_io_decoded(&self) -> String {
+        let mut out = String::new();
+        for reg in IO_REGISTERS.iter() {
+            let _ = write!(out, "{}", reg.decode(self.io_register_value(reg)));
+            if let Some(channel) = DMA_CNT.iter().position(|&addr| addr == reg.addr) {
+                if let Some(enabled) = self.dma.overrides[channel] {
+                    let _ = write!(out, " (forced {})", if enabled { "on" } else { "off" });
+                }
+            }
+            let _ = writeln!(out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000000, 0x1140);
+        mem.set_word(0x4000028, 0x0FFFFE80);
+        mem.set_halfword(0x40000C6, 0x8400);
+        mem.set_word(0x4000104, 0x00C4_FF00);
+        mem.dma.set_enabled_override(1, Some(false));
+
+        let dump = mem.dump_io_decoded();
+        assert!(dump.contains("DISPCNT=0x1140: mode=0, frame=0, 1D mapping, BG0 on, OBJ on\n"));
+        assert!(dump.contains("BG2X=0x0FFFFE80: value=-1.5\n"));
+        assert!(dump.contains("BG0CNT=0x0000: priority=0, tile base=0, 4bpp, map base=0, size=0\n"));
+        assert!(dump.contains("DMA1CNT_H=0x8400: dest incr=0, src incr=0, 32 bit, timing=0, enabled on (forced off)\n"));
+        assert!(dump.contains("DMA0SAD=0x00000000\n"));
+        assert!(dump.contains("TM1CNT_L=0xFF00: reload=65280\n"));
+        assert!(dump.contains("TM1CNT_H=0x00C4: prescaler=0, count up on, IRQ on, enabled on\n"));
+        assert_eq!(dump.lines().count(), IO_REGISTERS.len());
+    }
+}
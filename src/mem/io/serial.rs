@@ -0,0 +1,303 @@
+//! The serial port is used for link cable communication. The mode is selected
+//! by RCNT bit F and SIOCNT bits C-D:
+//!   RCNT F = 0: SIOCNT 00 = normal 8 bit, 01 = normal 32 bit,
+//!               10 = multiplayer, 11 = UART
+//!   RCNT F = 1: RCNT E = 0 general purpose, 1 = JOY bus
+//! Only multiplayer mode can be linked to another GBA. Normal mode transfers
+//! always run as if nothing is connected: they complete with all 1s received,
+//! since the SI line is pulled high. With an external clock nothing would
+//! ever drive the transfer, so it completes after a timeout instead of
+//! leaving the game waiting forever. In multiplayer mode up to 4
+//! GBAs are chained together: the parent (player 0) starts a transfer, and
+//! each player's SIOMLT_SEND value is then copied to SIOMULTI0-3 on every GBA.
+//! SIOCNT in multiplayer mode has the following format:
+//!                              R R R R  R R
+//! F E D C  B A 9 8  7 6 5 4  3 2 1 0
+//! X I M M  X X X X  S E D D  A C B B
+//! 0-1 (B) = baud rate (9600, 38400, 57600, 115200 bps)
+//! 2   (C) = SI terminal (0 for the parent, 1 for children)
+//! 3   (A) = SD terminal (1 when all GBAs are connected)
+//! 4-5 (D) = multiplayer ID
+//! 6   (E) = error
+//! 7   (S) = start/busy. written by the parent to start a transfer, and
+//!           cleared when the transfer completes
+//! C-D (M) = mode
+//! E   (I) = raise an interrupt when a transfer completes
+//! In normal mode, bit 0 selects the internal (1) or external (0) clock, and
+//! bit 1 selects 256KHz (0) or 2MHz (1) for the internal clock
+
+use super::addrs::*;
+use cpu::pacing::CLOCK_HZ;
+use mem::Memory;
+use mem::addrs::IO_START;
+
+const BAUD_RATES: [u32; 4] = [9600, 38400, 57600, 115200];
+/// each player sends a start bit, 16 data bits, and a stop bit
+const BITS_PER_PLAYER: u32 = 18;
+/// how long a normal mode transfer waits for an external clock before giving
+/// up (one frame)
+pub const NO_PARTNER_TIMEOUT: u32 = 280896;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialMode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+pub struct Serial {
+    pub mode: SerialMode,
+    /// index into BAUD_RATES
+    pub baud: u8,
+    pub irq: bool,
+    /// set while a transfer is in progress
+    pub busy: bool,
+    /// set when the last multiplayer transfer failed
+    pub error: bool,
+    /// number of cycles left in the current transfer
+    pub cycles_left: u32,
+    /// set when connected to another GBA. when linked, finished transfers are
+    /// left for the link to exchange the data, otherwise they complete
+    /// immediately as if nothing is connected
+    pub linked: bool,
+    /// set once the current transfer has finished, but before its data has
+    /// been exchanged with the other players
+    pub transfer_done: bool,
+    /// position in the multiplayer chain, where 0 is the parent
+    pub player_id: u8,
+}
+
+impl Serial {
+    pub const fn new() -> Serial {
+        Serial {
+            mode: SerialMode::Normal8,
+            baud: 0,
+            irq: false,
+            busy: false,
+            error: false,
+            cycles_left: 0,
+            linked: false,
+            transfer_done: false,
+            player_id: 0,
+        }
+    }
+
+    /// Return the number of cycles a transfer takes at the current baud rate
+    /// (or clock, in normal mode)
+    pub fn transfer_cycles(&self) -> u32 {
+        match self.mode {
+            SerialMode::Normal8 | SerialMode::Normal32 => {
+                if self.baud & 1 == 0 {
+                    return NO_PARTNER_TIMEOUT;
+                }
+                let bits = if self.mode == SerialMode::Normal8 { 8 } else { 32 };
+                let cycles_per_bit = if self.baud & 2 == 0 { 64 } else { 8 };
+                bits * cycles_per_bit
+            },
+            _ => {
+                let players = if self.linked { 2 } else { 1 };
+                CLOCK_HZ as u32 / BAUD_RATES[self.baud as usize] * BITS_PER_PLAYER * players
+            },
+        }
+    }
+
+    fn is_normal(&self) -> bool {
+        self.mode == SerialMode::Normal8 || self.mode == SerialMode::Normal32
+    }
+
+    /// Return the read only bits of SIOCNT, and a mask of the bits that are
+    /// left as written
+    fn status_bits(&self) -> (u8, u8) {
+        if self.is_normal() {
+            // TODO: SI should read as high when nothing is connected
+            return (0, 0b1111);
+        }
+        let si = if self.player_id == 0 { 0 } else { 0b100 };
+        let sd = if self.linked { 0b1000 } else { 0 };
+        let error = if self.error { 0b100_0000 } else { 0 };
+        (si | sd | (self.player_id << 4) | error, 0b11)
+    }
+}
+
+impl Memory {
+    /// All serial registers are 16 bits, so single byte writes update the
+    /// whole halfword they belong to
+    pub fn update_serial_byte(&mut self, addr: u32, _val: u8) {
+        self.update_serial_reg(addr & !1);
+    }
+
+    pub fn update_serial_hw(&mut self, addr: u32, _val: u32) {
+        self.update_serial_reg(addr & !1);
+    }
+
+    pub fn update_serial_word(&mut self, addr: u32, _val: u32) {
+        self.update_serial_reg(addr & !3);
+        self.update_serial_reg((addr & !3) + 2);
+    }
+
+    fn update_serial_reg(&mut self, addr: u32) {
+        match addr {
+            SIOCNT | RCNT => {
+                let siocnt = self.raw.get_halfword(SIOCNT);
+                let rcnt = self.raw.get_halfword(RCNT);
+                let serial = &mut self.serial;
+                serial.mode = match ((rcnt >> 14) & 0b11, (siocnt >> 12) & 0b11) {
+                    (0...1, 0) => SerialMode::Normal8,
+                    (0...1, 1) => SerialMode::Normal32,
+                    (0...1, 2) => SerialMode::Multiplayer,
+                    (0...1, _) => SerialMode::Uart,
+                    (2, _) => SerialMode::GeneralPurpose,
+                    _ => SerialMode::JoyBus,
+                };
+                serial.baud = (siocnt & 0b11) as u8;
+                serial.irq = (siocnt >> 14) & 1 == 1;
+
+                // TODO: UART mode transfers
+                let start = (siocnt >> 7) & 1 == 1;
+                let can_start = serial.is_normal() ||
+                    (serial.mode == SerialMode::Multiplayer && serial.player_id == 0);
+                if can_start && start && !serial.busy {
+                    serial.busy = true;
+                    serial.cycles_left = serial.transfer_cycles();
+                }
+                self.update_serial_status();
+            },
+            _ => (),
+        }
+    }
+
+    /// Plug in or unplug the link cable, and set this GBA's position in the
+    /// multiplayer chain
+    pub fn set_link(&mut self, linked: bool, player_id: u8) {
+        self.serial.linked = linked;
+        self.serial.player_id = player_id;
+        self.update_serial_status();
+    }
+
+    /// Copy the read only bits of the parsed state into SIOCNT
+    pub fn update_serial_status(&mut self) {
+        let idx = (SIOCNT - IO_START) as usize;
+        let busy = if self.serial.busy { 0x80 } else { 0 };
+        let (status, mask) = self.serial.status_bits();
+        self.raw.io[idx] = (self.raw.io[idx] & mask) | status | busy;
+    }
+
+    /// Advance the current transfer by the given number of cycles
+    pub fn tick_serial(&mut self, cycles: u32) {
+        let serial = &mut self.serial;
+        if !serial.busy || serial.transfer_done {
+            return;
+        }
+        serial.cycles_left = serial.cycles_left.saturating_sub(cycles);
+        if serial.cycles_left == 0 && serial.is_normal() {
+            self.finish_normal_transfer();
+        } else if serial.cycles_left == 0 {
+            serial.transfer_done = true;
+            if !serial.linked {
+                // with nothing connected, the other players read as 0xFFFF
+                let data = [self.multi_send(), 0xFFFF, 0xFFFF, 0xFFFF];
+                self.finish_multiplayer_transfer(data);
+            }
+        }
+    }
+
+    /// The value this GBA sends in the next multiplayer transfer
+    pub fn multi_send(&self) -> u16 {
+        self.raw.get_halfword(SIOMLT_SEND)
+    }
+
+    /// Complete a normal mode transfer, receiving all 1s
+    fn finish_normal_transfer(&mut self) {
+        if self.serial.mode == SerialMode::Normal8 {
+            self.raw.set_byte(SIODATA8, 0xFF);
+        } else {
+            self.raw.set_word(SIODATA32, 0xFFFFFFFF);
+        }
+        self.serial.busy = false;
+        self.update_serial_status();
+        self.raise_serial_irq();
+    }
+
+    /// Complete a multiplayer transfer, where data contains the value sent by
+    /// each player. Without a link, the error bit is set so that games can
+    /// tell that nobody answered
+    pub fn finish_multiplayer_transfer(&mut self, data: [u16; 4]) {
+        for (i, val) in data.iter().enumerate() {
+            self.raw.set_halfword(SIOMULTI[i], *val as u32);
+        }
+        self.serial.busy = false;
+        self.serial.transfer_done = false;
+        self.serial.error = !self.serial.linked;
+        self.update_serial_status();
+        self.raise_serial_irq();
+    }
+
+    fn raise_serial_irq(&mut self) {
+        if self.serial.irq {
+            self.int.triggered.serial = true;
+            self.raw.io[(IF_LO - IO_START) as usize] |= 0x80;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mode() {
+        let mut mem = Memory::new();
+        assert_eq!(mem.serial.mode, SerialMode::Normal8);
+        mem.set_halfword(0x4000128, 0x6003);
+        assert_eq!(mem.serial.mode, SerialMode::Multiplayer);
+        assert_eq!(mem.serial.baud, 3);
+        assert_eq!(mem.serial.irq, true);
+        mem.set_halfword(0x4000134, 0x8000);
+        assert_eq!(mem.serial.mode, SerialMode::GeneralPurpose);
+    }
+
+    #[test]
+    fn unconnected_transfer() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x400012A, 0x1234);
+        mem.set_halfword(0x4000128, 0x6083);
+        assert!(mem.serial.busy);
+        assert_eq!(mem.get_halfword(0x4000128) & 0x80, 0x80);
+
+        let cycles = mem.serial.transfer_cycles();
+        mem.tick_serial(cycles - 1);
+        assert!(mem.serial.busy);
+        mem.tick_serial(1);
+        assert!(!mem.serial.busy);
+        assert_eq!(mem.get_halfword(0x4000128) & 0x80, 0);
+        assert_eq!(mem.get_halfword(0x4000120), 0x1234);
+        assert_eq!(mem.get_halfword(0x4000122), 0xFFFF);
+        assert_eq!(mem.get_halfword(0x4000128) & 0x40, 0x40);
+        assert!(mem.int.triggered.serial);
+    }
+
+    #[test]
+    fn normal_transfer() {
+        let mut mem = Memory::new();
+        // 8 bit, internal 2MHz clock
+        mem.set_byte(0x400012A, 0x12);
+        mem.set_halfword(0x4000128, 0x4083);
+        assert!(mem.serial.busy);
+        mem.tick_serial(8 * 8);
+        assert!(!mem.serial.busy);
+        assert_eq!(mem.get_byte(0x400012A), 0xFF);
+        assert_eq!(mem.get_halfword(0x4000128) & 0x8F, 0x03);
+        assert!(mem.int.triggered.serial);
+
+        // 32 bit, external clock: times out since nothing drives the clock
+        mem.set_halfword(0x4000128, 0x1080);
+        mem.tick_serial(NO_PARTNER_TIMEOUT - 1);
+        assert!(mem.serial.busy);
+        mem.tick_serial(1);
+        assert!(!mem.serial.busy);
+        assert_eq!(mem.get_word(0x4000120), 0xFFFFFFFF);
+    }
+}
@@ -0,0 +1,941 @@
+//! The APU steps the four PSG channels (see psg) along with the CPU, and
+//! mixes their output with the two DirectSound channels into stereo samples
+//! at SAMPLE_RATE for the frontend to play. The PSG's registers are:
+//! 0x4000060: SOUND1CNT_L, the sweep of channel 1: shift (0-2), decrease (3)
+//!   and step time (4-6)
+//! 0x4000062: SOUND1CNT_H, 0x4000068: SOUND2CNT_L, 0x4000078: SOUND4CNT_L
+//!   length (0-5), duty (6-7, squares only) and envelope (8-F)
+//! 0x4000064: SOUND1CNT_X, 0x400006C: SOUND2CNT_H, 0x4000074: SOUND3CNT_X
+//!   frequency (0-A), stop at the end of the length (E) and restart (F,
+//!   write only)
+//! 0x4000070: SOUND3CNT_L, play both wave RAM banks (5), the bank to play
+//!   (6) and channel 3 on (7)
+//! 0x4000072: SOUND3CNT_H, length (0-7), volume (D-E) and force 75% (F)
+//! 0x400007C: SOUND4CNT_H, the noise divider (0-2), 7 bit noise (3), shift
+//!   (4-7), and bits E-F as above
+//! 0x4000080: SOUNDCNT_L, the PSG volume on the right (0-2) and left (4-6),
+//!   and the channels played on the right (8-B) and left (C-F)
+//! 0x4000084: SOUNDCNT_X, whether each PSG channel is playing (0-3, read only)
+//!   and the master enable (7). Turning it off zeroes the PSG registers and
+//!   stops every channel, and while it's off the registers ignore writes, the
+//!   frame sequencer is stopped and DirectSound doesn't take samples from its
+//!   FIFOs. Turning it back on starts the frame sequencer from its first
+//!   step, with every channel still stopped until the game restarts it
+//! 0x4000088: SOUNDBIAS, the level (0-9) the mixed output is centered on
+//! 0x4000090: WAVE_RAM, 16 bytes of 4 bit samples for channel 3. There are
+//!   two banks, and the CPU sees the one that isn't being played
+//!
+//! The two DirectSound channels (A and B) play 8 bit signed samples that the
+//! game streams into a FIFO for each channel, usually with DMA 1 and 2. Each
+//! time the timer selected for a channel overflows, the next sample is taken
+//! out of its FIFO, and once 16 or fewer bytes are left the DMA channel that
+//! feeds the FIFO is asked for 4 more words. If the DMA falls behind and the
+//! FIFO runs dry, the channel keeps outputting the last sample instead of
+//! dropping to 0, which would be heard as a click. At full volume a sample
+//! spans the whole 10 bit output range, so DirectSound is much louder than
+//! the PSG.
+//! 0x4000082: SOUNDCNT_H
+//!   F E D C  B A 9 8  7 6 5 4  3 2 1 0
+//!   S T L R  S T L R  X X X X  B A V V
+//!   0-1 (V) = PSG volume, 2 (A) and 3 (B) = DirectSound volume (50%/100%)
+//!   8-9 (R, L) = channel A enabled right/left
+//!   A   (T) = channel A timer (0 or 1)
+//!   B   (S) = reset channel A's FIFO. always reads as 0
+//!   C-F = the same for channel B
+//! 0x40000A0: FIFO_A, 0x40000A4: FIFO_B (write only)
+
+use std::cmp;
+
+use super::addrs::*;
+use super::psg::{CYCLES_PER_SEQUENCER_STEP, Noise, Square, Wave};
+use mem::Memory;
+use mem::addrs::IO_START;
+use util;
+
+/// the number of bytes each FIFO can hold
+pub const FIFO_SIZE: usize = 32;
+/// a DMA refill is requested once this many or fewer bytes are left
+pub const FIFO_REFILL_LEVEL: usize = 16;
+
+/// A DirectSound sample queue. Without the audio feature only the number of
+/// queued samples is kept, so DMA refills still happen when they would on
+/// hardware, and every sample reads as 0
+pub struct Fifo {
+    #[cfg(feature = "audio")]
+    samples: [i8; FIFO_SIZE],
+    /// index of the oldest sample
+    read: usize,
+    len: usize,
+    /// the sample being played, which is held when the FIFO underruns
+    pub output: i8,
+    /// the number of samples that were needed while the FIFO was empty
+    pub underruns: u32,
+}
+
+impl Fifo {
+    pub const fn new() -> Fifo {
+        Fifo {
+            #[cfg(feature = "audio")]
+            samples: [0; FIFO_SIZE],
+            read: 0,
+            len: 0,
+            output: 0,
+            underruns: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return the queued samples, oldest first
+    pub fn samples(&self) -> Vec<i8> {
+        (0..self.len).map(|i| self.sample(i)).collect()
+    }
+
+    /// Return the sample i places after the oldest one
+    #[cfg(feature = "audio")]
+    fn sample(&self, i: usize) -> i8 {
+        self.samples[(self.read + i) % FIFO_SIZE]
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn sample(&self, _i: usize) -> i8 {
+        0
+    }
+
+    /// Store a sample i places after the oldest one
+    #[cfg(feature = "audio")]
+    fn store(&mut self, i: usize, sample: i8) {
+        self.samples[(self.read + i) % FIFO_SIZE] = sample;
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn store(&mut self, _i: usize, _sample: i8) {}
+
+    /// Add a sample written by the game. Writes to a full FIFO are dropped
+    pub fn push(&mut self, sample: i8) {
+        if self.len == FIFO_SIZE {
+            return;
+        }
+        let len = self.len;
+        self.store(len, sample);
+        self.len += 1;
+    }
+
+    /// Move the next sample to the output, or keep the current output if
+    /// the FIFO is empty. Returns the new output
+    pub fn pop(&mut self) -> i8 {
+        if self.len == 0 {
+            self.underruns += 1;
+            return self.output;
+        }
+        self.output = self.sample(0);
+        self.read = (self.read + 1) % FIFO_SIZE;
+        self.len -= 1;
+        self.output
+    }
+
+    /// Return true if the FIFO is low enough to request more data
+    pub fn needs_refill(&self) -> bool {
+        self.len <= FIFO_REFILL_LEVEL
+    }
+
+    /// Empty the FIFO, as done by the reset bit in SOUNDCNT_H. The output is
+    /// left alone
+    pub fn reset(&mut self) {
+        self.read = 0;
+        self.len = 0;
+    }
+}
+
+pub struct DirectSound {
+    pub fifos: [Fifo; 2],
+    /// the timer (0 or 1) that drives each FIFO
+    pub timers: [usize; 2],
+    /// whether each channel is played on the (right, left) speakers
+    pub enabled: [(bool, bool); 2],
+    /// true if the channel is at full volume, otherwise it's at 50%
+    pub full_volume: [bool; 2],
+}
+
+impl DirectSound {
+    pub const fn new() -> DirectSound {
+        DirectSound {
+            fifos: [Fifo::new(), Fifo::new()],
+            timers: [0; 2],
+            enabled: [(false, false); 2],
+            full_volume: [false; 2],
+        }
+    }
+
+    /// Return the (right, left) output of both channels around 0
+    #[cfg(feature = "audio")]
+    fn mix(&self) -> (i32, i32) {
+        let (mut right, mut left) = (0, 0);
+        for i in 0..2 {
+            let scale = if self.full_volume[i] { 4 } else { 2 };
+            let level = self.fifos[i].output as i32 * scale;
+            let (on_right, on_left) = self.enabled[i];
+            if on_right {
+                right += level;
+            }
+            if on_left {
+                left += level;
+            }
+        }
+        (right, left)
+    }
+}
+
+/// the PSG is mixed once every 512 cycles
+pub const SAMPLE_RATE: u32 = 32768;
+const CYCLES_PER_SAMPLE: u32 = 512;
+/// a quarter second of samples, counting left and right separately. If the
+/// frontend falls this far behind, the oldest half is dropped
+#[cfg(feature = "audio")]
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 2;
+
+pub struct APU {
+    pub squares: [Square; 2],
+    pub wave: Wave,
+    pub noise: Noise,
+    /// both banks of wave RAM. Raw memory holds the bank the CPU can see
+    pub wave_banks: [[u8; 16]; 2],
+    /// bit 7 of SOUNDCNT_X. While clear the PSG is off
+    pub master_enabled: bool,
+    /// 0-7 on the (right, left) speakers
+    pub volumes: (u8, u8),
+    /// whether each channel is played on the (right, left) speakers
+    pub outputs: [(bool, bool); 4],
+    /// 25%, 50% or 100% (0-2) from SOUNDCNT_H. 3 is the same as 2
+    pub psg_volume: u8,
+    /// the level the output is centered on, from SOUNDBIAS
+    pub bias: u16,
+    /// 0-7, see psg
+    pub sequencer_step: u8,
+    pub sequencer_countdown: u32,
+    pub sample_countdown: u32,
+    /// cycles the PSG has already been run past the start of the current
+    /// step, to catch up with a register write made part way through it. See
+    /// catch_up_sound
+    pub ahead: u32,
+    /// interleaved left and right samples that haven't been taken yet
+    #[cfg(feature = "audio")]
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub const fn new() -> APU {
+        APU {
+            squares: [Square::new(), Square::new()],
+            wave: Wave::new(),
+            noise: Noise::new(),
+            wave_banks: [[0; 16]; 2],
+            master_enabled: false,
+            volumes: (0, 0),
+            outputs: [(false, false); 4],
+            psg_volume: 0,
+            bias: 0,
+            sequencer_step: 0,
+            sequencer_countdown: CYCLES_PER_SEQUENCER_STEP,
+            sample_countdown: CYCLES_PER_SAMPLE,
+            ahead: 0,
+            #[cfg(feature = "audio")]
+            samples: Vec::new(),
+        }
+    }
+
+    /// Reset everything that's cleared when the PSG is turned off, which is
+    /// all but wave RAM and the registers outside of it
+    fn power_off(&mut self) {
+        self.squares = [Square::new(), Square::new()];
+        self.wave = Wave::new();
+        self.noise = Noise::new();
+        self.volumes = (0, 0);
+        self.outputs = [(false, false); 4];
+        self.sequencer_step = 0;
+    }
+
+    /// Start the frame sequencer again from its first step, with a whole
+    /// step to go until it's clocked
+    fn power_on(&mut self) {
+        self.sequencer_step = 0;
+        self.sequencer_countdown = CYCLES_PER_SEQUENCER_STEP;
+    }
+
+    /// Return the bits of SOUNDCNT_X that show which channels are playing
+    pub fn status(&self) -> u8 {
+        let playing = [
+            self.squares[0].enabled,
+            self.squares[1].enabled,
+            self.wave.enabled,
+            self.noise.enabled,
+        ];
+        playing.iter().enumerate().fold(0, |status, (i, &on)| status | (on as u8) << i)
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        for square in self.squares.iter_mut().filter(|square| square.enabled) {
+            square.advance(cycles);
+        }
+        if self.wave.enabled {
+            self.wave.advance(cycles);
+        }
+        if self.noise.enabled {
+            self.noise.advance(cycles);
+        }
+    }
+
+    fn clock_sequencer(&mut self) {
+        let step = self.sequencer_step;
+        if step % 2 == 0 {
+            self.squares[0].clock_length();
+            self.squares[1].clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.squares[0].clock_sweep();
+        }
+        if step == 7 {
+            self.squares[0].clock_envelope();
+            self.squares[1].clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.sequencer_step = (step + 1) % 8;
+    }
+
+    /// Return the (right, left) output of the PSG around 0, before the bias
+    /// is added
+    #[cfg(feature = "audio")]
+    fn mix(&self) -> (i32, i32) {
+        let levels = [
+            self.squares[0].output(),
+            self.squares[1].output(),
+            self.wave.output(&self.wave_banks),
+            self.noise.output(),
+        ];
+        let (mut right, mut left) = (0, 0);
+        for (level, &(on_right, on_left)) in levels.iter().zip(self.outputs.iter()) {
+            if on_right {
+                right += level;
+            }
+            if on_left {
+                left += level;
+            }
+        }
+        let shift = 2 - cmp::min(self.psg_volume, 2);
+        let scale = |sum: i32, volume: u8| (sum * (volume as i32 + 1)) >> shift;
+        (scale(right, self.volumes.0), scale(left, self.volumes.1))
+    }
+
+    /// Add the current output of the PSG and DirectSound to the queued
+    /// samples. The hardware adds the bias and clips to 10 bits, so a game
+    /// with the wrong bias is clipped here too. Everything is silent while the
+    /// master enable is off
+    #[cfg(feature = "audio")]
+    fn push_sample(&mut self, sound: &DirectSound) {
+        let (right, left) = if self.master_enabled {
+            let (psg, direct) = (self.mix(), sound.mix());
+            (psg.0 + direct.0, psg.1 + direct.1)
+        } else {
+            (0, 0)
+        };
+        let bias = self.bias as i32;
+        let level = |mix: i32| (cmp::max(0, cmp::min(0x3FF, mix + bias)) - bias) as f32 / 512.0;
+        if self.samples.len() >= MAX_QUEUED_SAMPLES {
+            self.samples.drain(..MAX_QUEUED_SAMPLES / 2);
+        }
+        self.samples.push(level(left));
+        self.samples.push(level(right));
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn push_sample(&mut self, _sound: &DirectSound) {}
+
+    /// Return the samples made since the last call, as interleaved left and
+    /// right pairs from -1 to 1 at SAMPLE_RATE
+    #[cfg(feature = "audio")]
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::replace(&mut self.samples, Vec::new())
+    }
+
+    /// Drop the queued samples, e.g. after the emulator was paused
+    #[cfg(feature = "audio")]
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl Memory {
+    pub fn update_sound_byte(&mut self, addr: u32, val: u8) {
+        if addr >= SOUND_START && addr <= WAVE_RAM_END {
+            self.catch_up_sound();
+        }
+        if addr >= SOUND_START && addr <= SOUNDCNT_L_HI && !self.apu.master_enabled {
+            // the PSG's registers can't be written while it's off
+            self.raw.io[(addr - IO_START) as usize] = 0;
+            return;
+        }
+        match addr {
+            SOUND1CNT_L => {
+                let sweep = &mut self.apu.squares[0].sweep;
+                sweep.shift = val & 7;
+                sweep.decrease = val & 8 != 0;
+                sweep.step_time = (val >> 4) & 7;
+            },
+            SOUND1CNT_H | SOUND2CNT_L => {
+                let square = &mut self.apu.squares[if addr == SOUND1CNT_H { 0 } else { 1 }];
+                square.length.load((val & 0x3F) as u16);
+                square.duty = val >> 6;
+            },
+            SOUND1CNT_H_HI | SOUND2CNT_L_HI => {
+                let square = &mut self.apu.squares[if addr == SOUND1CNT_H_HI { 0 } else { 1 }];
+                square.envelope.parse(val);
+                square.enabled &= square.envelope.dac_enabled();
+            },
+            SOUND1CNT_X...SOUND1CNT_X_HI => self.update_square_freq(0, SOUND1CNT_X),
+            SOUND2CNT_H...SOUND2CNT_H_HI => self.update_square_freq(1, SOUND2CNT_H),
+            SOUND3CNT_L => {
+                let wave = &mut self.apu.wave;
+                wave.two_banks = val & 0x20 != 0;
+                wave.dac_enabled = val & 0x80 != 0;
+                wave.enabled &= wave.dac_enabled;
+                let bank = ((val >> 6) & 1) as usize;
+                if bank != wave.bank {
+                    wave.bank = bank;
+                    self.load_wave_ram();
+                }
+            },
+            SOUND3CNT_H => self.apu.wave.length.load(val as u16),
+            SOUND3CNT_H_HI => {
+                // bit 7 forces 75%, and otherwise 0 is mute, 1 is full volume,
+                // 2 is 50% and 3 is 25%
+                self.apu.wave.volume_quarters = if val & 0x80 != 0 {
+                    3
+                } else {
+                    [0, 4, 2, 1][((val >> 5) & 3) as usize]
+                };
+            },
+            SOUND3CNT_X...SOUND3CNT_X_HI => {
+                let reg = self.raw.get_halfword(SOUND3CNT_X);
+                let wave = &mut self.apu.wave;
+                wave.freq = reg & 0x7FF;
+                wave.length.enabled = util::get_bit_hw(reg, 14);
+                if util::get_bit_hw(reg, 15) {
+                    wave.restart();
+                    self.clear_restart_bit(SOUND3CNT_X_HI);
+                }
+            },
+            SOUND4CNT_L => self.apu.noise.length.load((val & 0x3F) as u16),
+            SOUND4CNT_L_HI => {
+                let noise = &mut self.apu.noise;
+                noise.envelope.parse(val);
+                noise.enabled &= noise.envelope.dac_enabled();
+            },
+            SOUND4CNT_H => {
+                let noise = &mut self.apu.noise;
+                noise.ratio = val & 7;
+                noise.short = val & 8 != 0;
+                noise.shift = val >> 4;
+            },
+            SOUND4CNT_H_HI => {
+                let noise = &mut self.apu.noise;
+                noise.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    noise.restart();
+                    self.clear_restart_bit(SOUND4CNT_H_HI);
+                }
+            },
+            SOUNDCNT_L...SOUNDCNT_L_HI => self.update_soundcnt_l(),
+            SOUNDCNT_H...SOUNDCNT_H_HI => self.update_soundcnt_h(),
+            SOUNDCNT_X => self.update_soundcnt_x(val),
+            SOUNDBIAS...SOUNDBIAS_HI =>
+                self.apu.bias = self.raw.get_halfword(SOUNDBIAS) & 0x3FF,
+            WAVE_RAM...WAVE_RAM_END => {
+                let bank = 1 - self.apu.wave.bank;
+                self.apu.wave_banks[bank][(addr - WAVE_RAM) as usize] = val;
+            },
+            FIFO_A...FIFO_B_END => {
+                let fifo = ((addr - FIFO_A) / 4) as usize;
+                self.sound.fifos[fifo].push(val as i8);
+            },
+            _ => (),
+        }
+        self.update_channel_status();
+    }
+
+    pub fn update_sound_hw(&mut self, addr: u32, val: u32) {
+        for i in 0..2 {
+            self.update_sound_byte(addr + i, (val >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn update_sound_word(&mut self, addr: u32, val: u32) {
+        for i in 0..4 {
+            self.update_sound_byte(addr + i, (val >> (8 * i)) as u8);
+        }
+    }
+
+    fn update_square_freq(&mut self, i: usize, addr: u32) {
+        let reg = self.raw.get_halfword(addr);
+        let square = &mut self.apu.squares[i];
+        square.freq = reg & 0x7FF;
+        square.length.enabled = util::get_bit_hw(reg, 14);
+        if util::get_bit_hw(reg, 15) {
+            square.restart();
+            self.clear_restart_bit(addr + 1);
+        }
+    }
+
+    /// The restart bits are write only
+    fn clear_restart_bit(&mut self, addr: u32) {
+        self.raw.io[(addr - IO_START) as usize] &= 0x7F;
+    }
+
+    /// Copy the bank of wave RAM that isn't being played to raw memory, after
+    /// switching banks
+    fn load_wave_ram(&mut self) {
+        let start = (WAVE_RAM - IO_START) as usize;
+        let bank = self.apu.wave_banks[1 - self.apu.wave.bank];
+        self.raw.io[start..start + 16].copy_from_slice(&bank);
+    }
+
+    /// Show which channels are playing in SOUNDCNT_X
+    pub fn update_channel_status(&mut self) {
+        let status = self.apu.status();
+        let reg = &mut self.raw.io[(SOUNDCNT_X - IO_START) as usize];
+        *reg = (*reg & 0xF0) | status;
+    }
+
+    fn update_soundcnt_l(&mut self) {
+        let reg = self.raw.get_halfword(SOUNDCNT_L);
+        let apu = &mut self.apu;
+        apu.volumes = ((reg & 7) as u8, ((reg >> 4) & 7) as u8);
+        for i in 0..4 {
+            apu.outputs[i] = (util::get_bit_hw(reg, 8 + i as u8), util::get_bit_hw(reg, 12 + i as u8));
+        }
+    }
+
+    fn update_soundcnt_x(&mut self, val: u8) {
+        let enabled = val & 0x80 != 0;
+        if self.apu.master_enabled && !enabled {
+            // turning the PSG off clears its registers, but keeps wave RAM
+            let start = (SOUND_START - IO_START) as usize;
+            let end = (SOUNDCNT_L_HI - IO_START) as usize;
+            for byte in self.raw.io[start..=end].iter_mut() {
+                *byte = 0;
+            }
+            self.apu.power_off();
+            self.load_wave_ram();
+            // and DirectSound stops, dropping what was queued
+            for fifo in self.sound.fifos.iter_mut() {
+                fifo.reset();
+                fifo.output = 0;
+            }
+        } else if !self.apu.master_enabled && enabled {
+            self.apu.power_on();
+        }
+        self.apu.master_enabled = enabled;
+    }
+
+    /// Rebuild the DirectSound and PSG state from the raw registers, for
+    /// rebuild_parsed_state. Which channels are playing, and how far along
+    /// they are, can't be told from the registers, so every channel starts
+    /// off stopped. Raw memory only has the bank of wave RAM that the CPU
+    /// sees, so it's copied to that bank
+    pub fn rebuild_sound(&mut self) {
+        let wave_start = (WAVE_RAM - IO_START) as usize;
+        let mut wave_ram = [0; 16];
+        wave_ram.copy_from_slice(&self.raw.io[wave_start..wave_start + 16]);
+        self.sound = DirectSound::new();
+        self.apu = APU::new();
+        // the PSG has to be on for its registers to be written
+        let val = self.raw.get_byte(SOUNDCNT_X);
+        self.update_sound_byte(SOUNDCNT_X, val);
+        for addr in SOUND_START..SOUNDBIAS_HI + 1 {
+            let val = self.raw.get_byte(addr);
+            self.update_sound_byte(addr, val);
+        }
+        let bank = 1 - self.apu.wave.bank;
+        self.apu.wave_banks[bank] = wave_ram;
+        self.load_wave_ram();
+    }
+
+    /// Run the PSG for the given number of cycles, clocking the frame
+    /// sequencer and mixing a sample whenever they come due. DirectSound only
+    /// changes when a timer overflows, so its output is the same throughout.
+    /// Cycles it was already run for by catch_up_sound are skipped
+    pub fn tick_sound(&mut self, cycles: u32) {
+        let ahead = std::mem::replace(&mut self.apu.ahead, 0);
+        self.run_psg(cycles.saturating_sub(ahead));
+    }
+
+    /// Run the PSG up to the point in the current step that a write to its
+    /// registers is being made at, so that the write takes effect from the
+    /// sample it was made on rather than from the start of the step. The CPU's
+    /// own cycles are only known once the instruction ends, so only writes
+    /// made by DMA are moved: each lands after the transfers (and the part of
+    /// its own transfer) that came before it
+    fn catch_up_sound(&mut self) {
+        let elapsed = self.dma.elapsed();
+        if elapsed > self.apu.ahead {
+            let ahead = self.apu.ahead;
+            self.run_psg(elapsed - ahead);
+            self.apu.ahead = elapsed;
+        }
+    }
+
+    fn run_psg(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        let sound = &self.sound;
+        let apu = &mut self.apu;
+        while cycles > 0 {
+            let step = cmp::min(cycles, cmp::min(apu.sequencer_countdown, apu.sample_countdown));
+            apu.advance(step);
+            cycles -= step;
+            apu.sequencer_countdown -= step;
+            apu.sample_countdown -= step;
+            if apu.sequencer_countdown == 0 {
+                apu.sequencer_countdown = CYCLES_PER_SEQUENCER_STEP;
+                if apu.master_enabled {
+                    apu.clock_sequencer();
+                }
+            }
+            if apu.sample_countdown == 0 {
+                apu.sample_countdown = CYCLES_PER_SAMPLE;
+                apu.push_sample(sound);
+            }
+        }
+        self.update_channel_status();
+    }
+
+    fn update_soundcnt_h(&mut self) {
+        let reg = self.raw.get_halfword(SOUNDCNT_H);
+        self.apu.psg_volume = (reg & 3) as u8;
+        for i in 0..2 {
+            let shift = 8 + 4 * i as u8;
+            let sound = &mut self.sound;
+            sound.full_volume[i] = util::get_bit_hw(reg, 2 + i as u8);
+            sound.enabled[i] = (util::get_bit_hw(reg, shift), util::get_bit_hw(reg, shift + 1));
+            sound.timers[i] = util::get_bit_hw(reg, shift + 2) as usize;
+            if util::get_bit_hw(reg, shift + 3) {
+                sound.fifos[i].reset();
+            }
+        }
+        // the reset bits are write only
+        self.raw.io[(SOUNDCNT_H_HI - IO_START) as usize] &= 0x77;
+    }
+
+    /// Called when a timer overflows, to move the next sample of each FIFO
+    /// driven by it to the output and refill the FIFO if it's running low.
+    /// Nothing happens while the master enable is off
+    pub fn on_timer_overflow(&mut self, timer: usize) {
+        if !self.apu.master_enabled {
+            return;
+        }
+        for i in 0..2 {
+            if self.sound.timers[i] != timer {
+                continue;
+            }
+            self.sound.fifos[i].pop();
+            if self.sound.fifos[i].needs_refill() {
+                self.request_fifo_dma(FIFO_A + 4 * i as u32);
+            }
+        }
+    }
+
+    /// Run the sound DMA (channel 1 or 2 with special timing) that writes to
+    /// the FIFO at the given address, if there is one
+    fn request_fifo_dma(&mut self, fifo_addr: u32) {
+        for channel in 1..3 {
+            if self.dma.is_enabled(channel) && self.dma.channels[channel].feeds(fifo_addr) {
+                self.run_fifo_dma(channel);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mem::io::dma::TimingMode;
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn fifo() {
+        let mut fifo = Fifo::new();
+        for i in 0..40 {
+            fifo.push(i);
+        }
+        assert_eq!(fifo.len(), FIFO_SIZE);
+        assert!(!fifo.needs_refill());
+        for i in 0..16 {
+            assert_eq!(fifo.pop(), i);
+        }
+        assert!(fifo.needs_refill());
+        for i in 16..32 {
+            assert_eq!(fifo.pop(), i);
+        }
+        // an empty FIFO holds the last sample
+        assert_eq!(fifo.pop(), 31);
+        assert_eq!(fifo.pop(), 31);
+        assert_eq!(fifo.underruns, 2);
+        fifo.push(-1);
+        assert_eq!(fifo.pop(), -1);
+    }
+
+    #[test]
+    fn soundcnt_h() {
+        let mut mem = Memory::new();
+        mem.set_word(FIFO_B, 0x04030201);
+        assert_eq!(mem.sound.fifos[1].len(), 4);
+        mem.set_halfword(SOUNDCNT_H, 0b1101_0110_0000_0100);
+        assert_eq!(mem.sound.enabled, [(false, true), (true, false)]);
+        assert_eq!(mem.sound.timers, [1, 1]);
+        assert_eq!(mem.sound.full_volume, [true, false]);
+        assert_eq!(mem.sound.fifos[1].len(), 0);
+        assert_eq!(mem.get_halfword(SOUNDCNT_H), 0b0101_0110_0000_0100);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn dma_refill() {
+        let mut mem = Memory::new();
+        mem.set_byte(SOUNDCNT_X, 0x80);
+        for i in 0..16 {
+            mem.set_word(0x2000000 + i * 4, 0x01010101 * (i + 1));
+        }
+        // channel A on timer 0, fed by DMA 1 from EWRAM
+        mem.set_halfword(SOUNDCNT_H, 0x0300);
+        mem.set_word(0x40000BC, 0x2000000);
+        mem.set_word(0x40000C0, FIFO_A);
+        mem.set_word(0x40000C4, 0xF640_0000);
+
+        // the FIFO starts empty, so the first overflow holds 0 but requests
+        // 4 words
+        mem.on_timer_overflow(1);
+        assert_eq!(mem.sound.fifos[0].len(), 0);
+        mem.on_timer_overflow(0);
+        assert_eq!(mem.sound.fifos[0].output, 0);
+        assert_eq!(mem.sound.fifos[0].len(), 16);
+        for _ in 0..4 {
+            assert_eq!(mem.sound.fifos[0].pop(), 1);
+        }
+        // 12 bytes left, which isn't enough, so another 16 come in
+        mem.on_timer_overflow(0);
+        assert_eq!(mem.sound.fifos[0].output, 2);
+        assert_eq!(mem.sound.fifos[0].len(), 27);
+        assert!(mem.dma.is_enabled(1));
+        assert!(mem.int.triggered.dma[1]);
+    }
+
+    /// Turn the PSG on, playing every channel on both sides at full volume
+    fn psg_on() -> Memory {
+        let mut mem = Memory::new();
+        mem.set_byte(SOUNDCNT_X, 0x80);
+        mem.set_halfword(SOUNDCNT_L, 0xFF77);
+        mem.set_halfword(SOUNDCNT_H, 0x0002);
+        mem.set_halfword(SOUNDBIAS, 0x0200);
+        mem
+    }
+
+    #[test]
+    fn dma_write_timing() {
+        let mut mem = psg_on();
+        // 0x100 halfwords from IWRAM into SOUNDCNT_L, without moving the dest
+        for i in 0..0x100 {
+            mem.set_halfword(0x3000000 + i * 2, 0xFF77);
+        }
+        mem.set_word(0x40000D4, 0x3000000);
+        mem.set_word(0x40000D8, SOUNDCNT_L);
+        mem.set_word(0x40000DC, 0x8040_0100);
+        mem.check_dma(TimingMode::Now);
+        let cycles = mem.dma.take_cycles();
+        // each write ran the PSG up to the point it was made at, which for the
+        // last is the end of the transfer
+        assert_eq!(mem.apu.ahead, cycles);
+        assert_eq!(mem.apu.sequencer_countdown, CYCLES_PER_SEQUENCER_STEP - cycles);
+
+        // and the rest of the step makes up the difference
+        mem.tick_sound(cycles + 10);
+        assert_eq!(mem.apu.ahead, 0);
+        assert_eq!(mem.apu.sequencer_countdown, CYCLES_PER_SEQUENCER_STEP - cycles - 10);
+    }
+
+    #[test]
+    fn psg_registers() {
+        // writes are ignored while the PSG is off
+        let mut mem = Memory::new();
+        mem.set_halfword(SOUND1CNT_H, 0xF0BF);
+        assert_eq!(mem.get_halfword(SOUND1CNT_H), 0);
+        assert_eq!(mem.apu.squares[0].envelope.initial, 0);
+
+        let mut mem = psg_on();
+        assert_eq!(mem.apu.volumes, (7, 7));
+        assert_eq!(mem.apu.outputs, [(true, true); 4]);
+        assert_eq!(mem.apu.psg_volume, 2);
+        assert_eq!(mem.apu.bias, 0x200);
+        mem.set_halfword(SOUND1CNT_L, 0x0071);
+        mem.set_halfword(SOUND1CNT_H, 0xA7BE);
+        mem.set_halfword(SOUND1CNT_X, 0xC400);
+        {
+            let square = &mem.apu.squares[0];
+            assert!(square.enabled);
+            assert_eq!((square.duty, square.freq, square.length.counter), (2, 0x400, 2));
+            assert_eq!((square.envelope.volume, square.envelope.step_time), (10, 7));
+            assert_eq!((square.sweep.shift, square.sweep.step_time), (1, 7));
+        }
+        // the restart bit reads as 0, and the channel shows as playing
+        assert_eq!(mem.get_halfword(SOUND1CNT_X), 0x4400);
+        assert_eq!(mem.get_byte(SOUNDCNT_X), 0x81);
+
+        // the length runs out on the second length step
+        mem.tick_sound(CYCLES_PER_SEQUENCER_STEP);
+        assert_eq!(mem.apu.squares[0].length.counter, 1);
+        mem.tick_sound(2 * CYCLES_PER_SEQUENCER_STEP);
+        assert!(!mem.apu.squares[0].enabled);
+        assert_eq!(mem.get_byte(SOUNDCNT_X), 0x80);
+
+        mem.set_halfword(SOUND4CNT_L, 0xF000);
+        mem.set_halfword(SOUND4CNT_H, 0x805B);
+        {
+            let noise = &mem.apu.noise;
+            assert!(noise.enabled);
+            assert_eq!((noise.ratio, noise.short, noise.shift), (3, true, 5));
+            assert_eq!(noise.length.counter, 64);
+        }
+
+        // turning the PSG off clears the registers and stops every channel
+        mem.set_byte(SOUNDCNT_X, 0);
+        assert!(!mem.apu.noise.enabled);
+        assert_eq!(mem.get_halfword(SOUND1CNT_H), 0);
+        assert_eq!(mem.get_halfword(SOUNDCNT_L), 0);
+        assert_eq!(mem.get_byte(SOUNDCNT_X), 0);
+        assert_eq!(mem.apu.volumes, (0, 0));
+        assert_eq!(mem.get_halfword(SOUNDBIAS), 0x200);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn master_enable() {
+        let mut mem = psg_on();
+        mem.set_halfword(SOUNDCNT_H, 0x0300);
+        mem.set_word(FIFO_A, 0x04030201);
+        mem.on_timer_overflow(0);
+        assert_eq!(mem.sound.fifos[0].output, 1);
+        mem.tick_sound(3 * CYCLES_PER_SEQUENCER_STEP);
+        assert_eq!(mem.apu.sequencer_step, 3);
+
+        // turning it off drops the queued samples and silences DirectSound
+        mem.set_byte(SOUNDCNT_X, 0);
+        assert_eq!(mem.sound.fifos[0].len(), 0);
+        assert_eq!(mem.sound.fifos[0].output, 0);
+        // SOUNDCNT_H isn't one of the PSG's registers, so it's kept
+        assert_eq!(mem.sound.enabled[0], (true, true));
+
+        // while it's off the timers don't take samples and the frame sequencer
+        // doesn't step
+        mem.set_word(FIFO_A, 0x04030201);
+        mem.on_timer_overflow(0);
+        assert_eq!(mem.sound.fifos[0].len(), 4);
+        assert_eq!(mem.sound.fifos[0].output, 0);
+        mem.tick_sound(CYCLES_PER_SEQUENCER_STEP / 2);
+        mem.tick_sound(3 * CYCLES_PER_SEQUENCER_STEP);
+        assert_eq!(mem.apu.sequencer_step, 0);
+
+        // turning it on starts the sequencer from the beginning of a step
+        mem.set_byte(SOUNDCNT_X, 0x80);
+        mem.tick_sound(CYCLES_PER_SEQUENCER_STEP - 1);
+        assert_eq!(mem.apu.sequencer_step, 0);
+        mem.tick_sound(1);
+        assert_eq!(mem.apu.sequencer_step, 1);
+        assert_eq!(mem.get_halfword(SOUND1CNT_H), 0);
+        mem.on_timer_overflow(0);
+        assert_eq!(mem.sound.fifos[0].output, 1);
+    }
+
+    #[test]
+    fn wave_ram() {
+        let mut mem = psg_on();
+        // the CPU writes to bank 1 while bank 0 is selected
+        mem.set_word(WAVE_RAM, 0x12345678);
+        assert_eq!(mem.apu.wave_banks[1][0..4], [0x78, 0x56, 0x34, 0x12]);
+        mem.set_byte(SOUND3CNT_L, 0xC0);
+        assert_eq!(mem.get_word(WAVE_RAM), 0);
+        mem.set_byte(WAVE_RAM, 0xAB);
+        assert_eq!(mem.apu.wave_banks[0][0], 0xAB);
+
+        mem.set_halfword(SOUND3CNT_H, 0x4000);
+        assert_eq!(mem.apu.wave.volume_quarters, 2);
+        mem.set_halfword(SOUND3CNT_H, 0xE000);
+        assert_eq!(mem.apu.wave.volume_quarters, 3);
+        mem.set_halfword(SOUND3CNT_X, 0x87FF);
+        assert!(mem.apu.wave.enabled);
+        assert_eq!(mem.get_byte(SOUNDCNT_X), 0x84);
+
+        // the rebuilt state has the CPU's bank, but not the one being played
+        mem.rebuild_parsed_state();
+        assert_eq!(mem.apu.wave.bank, 1);
+        assert_eq!(mem.apu.wave_banks[0][0], 0xAB);
+        assert_eq!(mem.apu.wave_banks[1][0], 0);
+        assert!(!mem.apu.wave.enabled);
+
+        // turning the PSG off keeps wave RAM, but selects bank 0 again
+        mem.set_byte(SOUNDCNT_X, 0);
+        assert_eq!(mem.get_byte(WAVE_RAM), 0);
+        assert_eq!(mem.apu.wave_banks[0][0], 0xAB);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn samples() {
+        let mut mem = psg_on();
+        // channel 2 on the left only, at full volume with a 50% duty cycle,
+        // stepping once a sample
+        mem.set_halfword(SOUNDCNT_L, 0x2077);
+        mem.set_halfword(SOUND2CNT_L, 0xF080);
+        mem.set_halfword(SOUND2CNT_H, 0x8000 | 2016);
+        mem.tick_sound(8 * CYCLES_PER_SAMPLE);
+        let samples = mem.apu.take_samples();
+        let level = 15.0 * 8.0 / 512.0;
+        let left: Vec<f32> = samples.iter().step_by(2).cloned().collect();
+        assert_eq!(left, vec![-level, -level, -level, -level, level, level, level, level]);
+        assert!(samples.iter().skip(1).step_by(2).all(|&sample| sample == 0.0));
+        assert!(mem.apu.take_samples().is_empty());
+
+        // without a bias the low half of the wave is clipped
+        mem.set_halfword(SOUNDBIAS, 0);
+        mem.tick_sound(CYCLES_PER_SAMPLE);
+        assert_eq!(mem.apu.take_samples(), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn direct_sound_samples() {
+        let mut mem = psg_on();
+        mem.set_halfword(SOUNDCNT_L, 0);
+        // channel A at full volume on the right, and B at 50% on both sides
+        mem.set_halfword(SOUNDCNT_H, 0x3104);
+        mem.set_word(FIFO_A, 0x00_00_80_40);
+        mem.set_word(FIFO_B, 0x00_00_00_20);
+        mem.on_timer_overflow(0);
+        mem.tick_sound(CYCLES_PER_SAMPLE);
+        // (left, right)
+        assert_eq!(mem.apu.take_samples(), vec![64.0 / 512.0, 320.0 / 512.0]);
+
+        // with a lower bias, channel A's next sample is clipped
+        mem.set_halfword(SOUNDCNT_H, 0x0104);
+        mem.set_halfword(SOUNDBIAS, 0x100);
+        mem.on_timer_overflow(0);
+        mem.tick_sound(CYCLES_PER_SAMPLE);
+        assert_eq!(mem.apu.take_samples(), vec![0.0, -0.5]);
+
+        // nothing is heard while the master enable is off
+        mem.set_byte(SOUNDCNT_X, 0);
+        mem.tick_sound(CYCLES_PER_SAMPLE);
+        assert_eq!(mem.apu.take_samples(), vec![0.0, 0.0]);
+    }
+}
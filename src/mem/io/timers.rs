@@ -0,0 +1,281 @@
+//! There are four 16 bit timers, each with two registers:
+//! TMxCNT_L (0x4000100 + 4x) reads as the current count, and writes set the
+//! reload value. The counter is loaded with it when the timer is started, and
+//! again each time the counter overflows.
+//! TMxCNT_H has the following format:
+//! F E D C  B A 9 8  7 6 5 4  3 2 1 0
+//! X X X X  X X X X  E I X X  X C P P
+//! 0-1 (P) = prescaler: count every 1, 64, 256 or 1024 cycles
+//! 2   (C) = count up: count the overflows of the previous timer instead of
+//!           cycles. ignored for timer 0
+//! 6   (I) = raise an interrupt on overflow
+//! 7   (E) = enabled
+//! Timers 0 and 1 also drive the DirectSound FIFOs (see sound.rs)
+
+use super::addrs::*;
+use mem::Memory;
+use mem::addrs::IO_START;
+
+/// the number of cycles per count for each prescaler setting
+pub const PRESCALERS: [u32; 4] = [1, 64, 256, 1024];
+
+pub struct Timer {
+    /// loaded into the counter when the timer starts and when it overflows
+    pub reload: u16,
+    pub counter: u16,
+    /// index into PRESCALERS
+    pub prescaler: u8,
+    /// set when counting the previous timer's overflows instead of cycles
+    pub cascade: bool,
+    pub irq: bool,
+    pub enabled: bool,
+    /// cycles counted since the counter was last incremented
+    pub ticks: u32,
+}
+
+impl Timer {
+    pub const fn new() -> Timer {
+        Timer {
+            reload: 0,
+            counter: 0,
+            prescaler: 0,
+            cascade: false,
+            irq: false,
+            enabled: false,
+            ticks: 0,
+        }
+    }
+
+    /// Add to the counter, returning the number of times it overflowed
+    fn count(&mut self, increments: u32) -> u32 {
+        let until_overflow = 0x10000 - self.counter as u32;
+        if increments < until_overflow {
+            self.counter += increments as u16;
+            return 0;
+        }
+        let period = 0x10000 - self.reload as u32;
+        let rest = increments - until_overflow;
+        self.counter = self.reload + (rest % period) as u16;
+        1 + rest / period
+    }
+
+    /// Count the given number of cycles, returning the number of overflows
+    fn tick(&mut self, cycles: u32) -> u32 {
+        let prescaler = PRESCALERS[self.prescaler as usize];
+        self.ticks += cycles;
+        let increments = self.ticks / prescaler;
+        self.ticks %= prescaler;
+        self.count(increments)
+    }
+
+    /// Return the number of cycles until the counter next overflows, or None
+    /// if it's counting up instead of counting cycles. Whether the timer is
+    /// enabled is up to the caller (see Timers::is_enabled)
+    pub fn cycles_to_overflow(&self) -> Option<u32> {
+        if self.cascade {
+            return None;
+        }
+        let increments = 0x10000 - self.counter as u32;
+        Some(increments * PRESCALERS[self.prescaler as usize] - self.ticks)
+    }
+}
+
+pub struct Timers {
+    pub timers: [Timer; 4],
+    /// debug overrides of the enabled bit of each timer, which are checked
+    /// instead of the bit when set
+    pub overrides: [Option<bool>; 4],
+}
+
+impl Timers {
+    pub const fn new() -> Timers {
+        Timers {
+            timers: [Timer::new(), Timer::new(), Timer::new(), Timer::new()],
+            overrides: [None; 4],
+        }
+    }
+
+    /// Force a timer to run or stop regardless of what the game writes to its
+    /// control register, or remove the override with None. A timer forced on
+    /// counts from wherever its counter is, since the reload value is only
+    /// loaded when the game starts it
+    pub fn set_enabled_override(&mut self, timer: usize, enabled: Option<bool>) {
+        self.overrides[timer] = enabled;
+    }
+
+    /// Return whether the given timer should count
+    pub fn is_enabled(&self, timer: usize) -> bool {
+        self.overrides[timer].unwrap_or(self.timers[timer].enabled)
+    }
+
+    /// Return the number of cycles until any timer overflows. Timers that
+    /// count up only overflow when the one before them does, so they're left
+    /// out
+    pub fn cycles_to_overflow(&self) -> Option<u32> {
+        self.timers.iter().enumerate()
+            .filter(|&(i, _)| self.is_enabled(i))
+            .filter_map(|(_, timer)| timer.cycles_to_overflow())
+            .min()
+    }
+}
+
+impl Memory {
+    /// All timer registers are 16 bits, so single byte writes update the
+    /// whole halfword they belong to
+    pub fn update_timer_byte(&mut self, addr: u32, _val: u8) {
+        self.update_timer_reg(addr & !1);
+    }
+
+    pub fn update_timer_hw(&mut self, addr: u32, _val: u32) {
+        self.update_timer_reg(addr & !1);
+    }
+
+    /// A word write sets the reload value before starting the timer, so that
+    /// the counter starts from the new value
+    pub fn update_timer_word(&mut self, addr: u32, _val: u32) {
+        self.update_timer_reg(addr & !3);
+        self.update_timer_reg((addr & !3) + 2);
+    }
+
+    fn update_timer_reg(&mut self, addr: u32) {
+        let i = ((addr - TIMER_START) / 4) as usize;
+        let val = self.raw.get_halfword(addr);
+        let timer = &mut self.timers.timers[i];
+        if addr & 2 == 0 {
+            timer.reload = val;
+            return;
+        }
+        timer.prescaler = (val & 0b11) as u8;
+        timer.cascade = i > 0 && val & 0b100 != 0;
+        timer.irq = val & 0x40 != 0;
+        let enabled = val & 0x80 != 0;
+        if enabled && !timer.enabled {
+            timer.counter = timer.reload;
+            timer.ticks = 0;
+        }
+        timer.enabled = enabled;
+    }
+
+    /// Return the current count of the timer whose TMxCNT_L is at addr
+    pub fn timer_counter(&self, addr: u32) -> u16 {
+        self.timers.timers[((addr - TIMER_START) / 4) as usize].counter
+    }
+
+    /// Advance the timers by the given number of cycles, raising interrupts
+    /// and feeding the sound FIFOs for each overflow
+    pub fn tick_timers(&mut self, cycles: u32) {
+        // the overflows of the previous timer, for the next one to count
+        let mut overflows = 0;
+        for i in 0..4 {
+            let enabled = self.timers.is_enabled(i);
+            let timer = &mut self.timers.timers[i];
+            overflows = if !enabled {
+                0
+            } else if timer.cascade {
+                timer.count(overflows)
+            } else {
+                timer.tick(cycles)
+            };
+            if overflows == 0 {
+                continue;
+            }
+            if timer.irq {
+                self.int.triggered.timer[i] = true;
+                self.raw.io[(IF_LO - IO_START) as usize] |= 1 << (3 + i);
+            }
+            if i < 2 {
+                for _ in 0..overflows {
+                    self.on_timer_overflow(i);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count() {
+        let mut mem = Memory::new();
+        // reload and start timer 0 at 1/64 in one write
+        mem.set_word(0x4000100, 0x0081_FFF0);
+        assert_eq!(mem.timers.timers[0].counter, 0xFFF0);
+        mem.tick_timers(63);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF0);
+        mem.tick_timers(1 + 64 * 4);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF5);
+        // the reload value is only loaded on overflow
+        mem.set_halfword(0x4000100, 0xFF00);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF5);
+        mem.tick_timers(64 * 11);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFF00);
+        assert!(!mem.int.triggered.timer[0]);
+
+        // rewriting the control register while running doesn't restart it
+        mem.set_halfword(0x4000102, 0x00C1);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFF00);
+        assert_eq!(mem.timers.cycles_to_overflow(), Some(64 * 0x100));
+        mem.tick_timers(64 * 0x100 * 3 + 64 * 2);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFF02);
+        assert!(mem.int.triggered.timer[0]);
+        assert_eq!(mem.get_halfword(0x4000202), 0b1000);
+
+        mem.set_halfword(0x4000102, 0);
+        mem.tick_timers(0x10000);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFF02);
+        assert_eq!(mem.timers.cycles_to_overflow(), None);
+    }
+
+    #[test]
+    fn cascade() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000100, 0xFFFE);
+        mem.set_halfword(0x4000108, 0xFFFD);
+        // timer 2 counts up, but timer 1 isn't running so it never counts
+        mem.set_halfword(0x400010A, 0x00C4);
+        mem.set_halfword(0x4000102, 0x0080);
+        mem.tick_timers(100);
+        assert_eq!(mem.get_halfword(0x4000108), 0xFFFD);
+
+        // timer 1 counts timer 0's overflows, and timer 2 counts timer 1's
+        mem.set_halfword(0x4000104, 0xFFFF);
+        mem.set_halfword(0x4000106, 0x0084);
+        // count up is ignored for timer 0
+        mem.set_halfword(0x4000102, 0x0084);
+        mem.tick_timers(2 * 2);
+        assert_eq!(mem.get_halfword(0x4000108), 0xFFFF);
+        assert!(!mem.int.triggered.timer[2]);
+        mem.tick_timers(2);
+        assert_eq!(mem.get_halfword(0x4000108), 0xFFFD);
+        assert!(mem.int.triggered.timer[2]);
+        assert!(!mem.int.triggered.timer[0] && !mem.int.triggered.timer[1]);
+        assert_eq!(mem.timers.cycles_to_overflow(), Some(2));
+    }
+
+    #[test]
+    fn enabled_override() {
+        let mut mem = Memory::new();
+        mem.set_word(0x4000100, 0x0080_FFF0);
+        mem.timers.set_enabled_override(0, Some(false));
+        mem.tick_timers(100);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF0);
+        assert_eq!(mem.timers.cycles_to_overflow(), None);
+        // the game can still see the enabled bit
+        assert_eq!(mem.get_halfword(0x4000102), 0x0080);
+
+        mem.timers.set_enabled_override(0, None);
+        mem.tick_timers(4);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF4);
+
+        // a timer the game hasn't started runs when forced on, from its
+        // counter rather than the reload value
+        mem.set_halfword(0x4000104, 0xFFFE);
+        mem.timers.set_enabled_override(1, Some(true));
+        assert_eq!(mem.timers.cycles_to_overflow(), Some(12));
+        mem.tick_timers(12);
+        assert_eq!(mem.get_halfword(0x4000100), 0xFFF0);
+        assert_eq!(mem.get_halfword(0x4000104), 12);
+    }
+}
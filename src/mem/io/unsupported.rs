@@ -0,0 +1,182 @@
+//! Some hardware isn't emulated yet: the keypad and the JOY bus.
+//! Their registers read back whatever was last written to them (or 0), and
+//! never change on their own, so a game waiting for one of them to change
+//! (e.g. for a key press, or a transfer to finish) hangs without any
+//! sign of why. To point at the missing hardware
+//! instead, reads of these registers are counted each frame, and a subsystem
+//! whose registers are read thousands of times in a single frame is reported
+//! once as the likely cause.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+/// a game reading a subsystem's registers this many times (counting each
+/// byte) in a frame is assumed to be stuck polling them. A tight loop on one
+/// register reads it tens of thousands of times a frame, while games that
+/// just check it every frame read it a handful of times
+pub const POLL_LIMIT: u32 = 2000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Keypad,
+    Serial,
+}
+
+const SUBSYSTEMS: usize = 2;
+const ALL_SUBSYSTEMS: [Subsystem; SUBSYSTEMS] = [Subsystem::Keypad, Subsystem::Serial];
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Subsystem::Keypad => "keypad",
+            Subsystem::Serial => "serial (JOY bus)",
+        }
+    }
+}
+
+/// An IO register belonging to hardware that isn't emulated
+pub struct UnsupportedRegister {
+    pub name: &'static str,
+    pub addr: u32,
+    /// in bytes
+    pub size: u32,
+    pub subsystem: Subsystem,
+}
+
+const fn reg(name: &'static str, addr: u32, size: u32, subsystem: Subsystem)
+    -> UnsupportedRegister {
+    UnsupportedRegister { name, addr, size, subsystem }
+}
+
+pub const UNSUPPORTED_REGISTERS: &[UnsupportedRegister] = &[
+    reg("KEYINPUT", 0x4000130, 2, Subsystem::Keypad),
+    reg("KEYCNT", 0x4000132, 2, Subsystem::Keypad),
+    reg("JOYCNT", 0x4000140, 2, Subsystem::Serial),
+    reg("JOY_RECV", 0x4000150, 4, Subsystem::Serial),
+    reg("JOY_TRANS", 0x4000154, 4, Subsystem::Serial),
+    reg("JOYSTAT", 0x4000158, 2, Subsystem::Serial),
+];
+
+/// Return the unsupported register at the given address, if any
+pub fn unsupported_register(addr: u32) -> Option<&'static UnsupportedRegister> {
+    UNSUPPORTED_REGISTERS.iter().find(|reg| addr >= reg.addr && addr < reg.addr + reg.size)
+}
+
+/// The report for a game that looks stuck waiting on missing hardware
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StuckPoll {
+    pub subsystem: Subsystem,
+    /// the register that was being read when the limit was reached
+    pub register: &'static str,
+    pub addr: u32,
+}
+
+impl fmt::Display for StuckPoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the game keeps reading {} ({:#010X}) and may be stuck; it needs {} support",
+            self.register, self.addr, self.subsystem.name())
+    }
+}
+
+/// Reads are made through &Memory, so the counts are kept in cells
+pub struct PollWatch {
+    /// reads of each subsystem's registers since the frame started
+    reads: Cell<[u32; SUBSYSTEMS]>,
+    /// subsystems are only reported once
+    reported: Cell<[bool; SUBSYSTEMS]>,
+    /// reports that haven't been taken by the frontend yet
+    pending: RefCell<Vec<StuckPoll>>,
+}
+
+impl PollWatch {
+    pub const fn new() -> PollWatch {
+        PollWatch {
+            reads: Cell::new([0; SUBSYSTEMS]),
+            reported: Cell::new([false; SUBSYSTEMS]),
+            pending: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Count a read of an IO register, reporting its subsystem if it's
+    /// unsupported and this read reaches the limit
+    pub fn record_read(&self, addr: u32) {
+        let reg = match unsupported_register(addr) {
+            Some(reg) => reg,
+            None => return,
+        };
+        let i = reg.subsystem as usize;
+        let mut reads = self.reads.get();
+        reads[i] += 1;
+        self.reads.set(reads);
+
+        let mut reported = self.reported.get();
+        if reads[i] >= POLL_LIMIT && !reported[i] {
+            reported[i] = true;
+            self.reported.set(reported);
+            self.pending.borrow_mut().push(StuckPoll {
+                subsystem: reg.subsystem,
+                register: reg.name,
+                addr: reg.addr,
+            });
+        }
+    }
+
+    /// Called at the start of each frame
+    pub fn start_frame(&self) {
+        self.reads.set([0; SUBSYSTEMS]);
+    }
+
+    /// Remove and return the reports made so far
+    pub fn take_reports(&self) -> Vec<StuckPoll> {
+        self.pending.replace(Vec::new())
+    }
+
+    /// Return every subsystem that's been reported, including reports that
+    /// have already been taken
+    pub fn stuck_subsystems(&self) -> Vec<Subsystem> {
+        let reported = self.reported.get();
+        ALL_SUBSYSTEMS.iter().cloned().filter(|s| reported[*s as usize]).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mem::Memory;
+
+    #[test]
+    fn stuck_poll() {
+        let mut mem = Memory::new();
+        // checking the keypad once a frame is fine
+        for _ in 0..10 {
+            mem.get_halfword(0x4000130);
+            mem.on_vdraw_hook();
+        }
+        // as are supported registers, like a timer's count
+        for _ in 0..POLL_LIMIT {
+            mem.get_halfword(0x4000006);
+            mem.get_halfword(0x4000104);
+        }
+        assert!(mem.poll_watch.take_reports().is_empty());
+
+        // waiting for a key press
+        for _ in 0..POLL_LIMIT {
+            mem.get_halfword(0x4000130);
+        }
+        let reports = mem.poll_watch.take_reports();
+        assert_eq!(reports, vec![StuckPoll {
+            subsystem: Subsystem::Keypad,
+            register: "KEYINPUT",
+            addr: 0x4000130,
+        }]);
+        assert_eq!(reports[0].to_string(), "the game keeps reading KEYINPUT (0x04000130) \
+            and may be stuck; it needs keypad support");
+
+        // each subsystem is only reported once
+        mem.on_vdraw_hook();
+        for _ in 0..POLL_LIMIT {
+            mem.get_word(0x4000130);
+        }
+        assert!(mem.poll_watch.take_reports().is_empty());
+    }
+}
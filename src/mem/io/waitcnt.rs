@@ -0,0 +1,210 @@
+//! WAITCNT controls the number of waitstates used when accessing the game pak.
+//! The game pak ROM is mirrored in 3 regions (0x8000000, 0xA000000, 0xC000000)
+//! which each have their own configurable waitstates, so that a game can use
+//! the region whose timing suits the chip being accessed. The format is:
+//! F E D C  B A 9 8  7 6 5 4  3 2 1 0
+//! G P X H  H Z Y Y  V U U T  S S R R
+//! 0-1 (R) = SRAM waitstates (4, 3, 2, 8)
+//! 2-3 (S) = WS0 non sequential waitstates (4, 3, 2, 8)
+//! 4   (T) = WS0 sequential waitstates (2, 1)
+//! 5-6 (U) = WS1 non sequential waitstates (4, 3, 2, 8)
+//! 7   (V) = WS1 sequential waitstates (4, 1)
+//! 8-9 (Y) = WS2 non sequential waitstates (4, 3, 2, 8)
+//! A   (Z) = WS2 sequential waitstates (8, 1)
+//! B-C (H) = PHI terminal output (unused)
+//! E   (P) = game pak prefetch buffer enabled
+//! F   (G) = game pak type (read only, always 0 for GBA carts)
+
+use std::cell::Cell;
+use super::addrs::*;
+use mem::Memory;
+use mem::addrs::IO_START;
+
+/// non sequential waitstates, indexed by the 2 bit value in WAITCNT
+const N_CYCLES: [u8; 4] = [4, 3, 2, 8];
+
+pub struct WaitCnt {
+    pub sram: u8,
+    /// non sequential waitstates for each of the 3 ROM regions
+    pub rom_n: [u8; 3],
+    /// sequential waitstates for each of the 3 ROM regions
+    pub rom_s: [u8; 3],
+    /// when the prefetch buffer is enabled, sequential reads from ROM are
+    /// hidden behind the CPU executing other instructions. we approximate this
+    /// by treating them as if they had no waitstates
+    pub prefetch: bool,
+    /// set when the CPU spends internal cycles without using the bus. when
+    /// prefetch is disabled, this causes the following access to ROM to be
+    /// non sequential, even if it is at the next address (for example the
+    /// opcode fetch after an LDR from ROM)
+    pub after_internal_cycle: Cell<bool>,
+}
+
+impl WaitCnt {
+    pub const fn new() -> WaitCnt {
+        WaitCnt {
+            sram: 4,
+            rom_n: [4, 4, 4],
+            rom_s: [2, 4, 8],
+            prefetch: false,
+            after_internal_cycle: Cell::new(false),
+        }
+    }
+
+    /// Return the waitstates for an access to the given ROM region (0-2).
+    /// The game pak is divided into 128KB pages, and the first access to
+    /// each page is always non sequential
+    pub fn rom_waitstates(&self, region: usize, addr: u32, first_access: bool) -> u8 {
+        if first_access || (addr & 0x1FFFF) == 0 {
+            self.rom_n[region]
+        } else if self.prefetch {
+            0
+        } else {
+            self.rom_s[region]
+        }
+    }
+}
+
+/// Counters used to find out how much time is spent waiting on memory. These
+/// are updated from access_time(), which only has an immutable borrow
+pub struct AccessStats {
+    pub accesses: Cell<u64>,
+    pub cycles: Cell<u64>,
+}
+
+impl AccessStats {
+    pub const fn new() -> AccessStats {
+        AccessStats {
+            accesses: Cell::new(0),
+            cycles: Cell::new(0),
+        }
+    }
+
+    pub fn record(&self, cycles: u32) {
+        self.accesses.set(self.accesses.get() + 1);
+        self.cycles.set(self.cycles.get() + cycles as u64);
+    }
+
+    /// Return the average number of cycles taken per memory access since the
+    /// last reset
+    pub fn average_latency(&self) -> f64 {
+        match self.accesses.get() {
+            0 => 0.0,
+            n => self.cycles.get() as f64 / n as f64,
+        }
+    }
+
+    pub fn reset(&self) {
+        self.accesses.set(0);
+        self.cycles.set(0);
+    }
+}
+
+impl Memory {
+    /// Record that the CPU spent the given number of internal cycles without
+    /// accessing memory, and return the number of cycles taken
+    pub fn internal_cycles(&self, cycles: u32) -> u32 {
+        if cycles > 0 {
+            self.waitcnt.after_internal_cycle.set(true);
+        }
+        cycles
+    }
+
+    pub fn update_waitcnt_byte(&mut self, addr: u32, val: u8) {
+        let waitcnt = &mut self.waitcnt;
+        match addr {
+            WAITCNT_LO => {
+                waitcnt.sram = N_CYCLES[(val & 0b11) as usize];
+                waitcnt.rom_n[0] = N_CYCLES[((val >> 2) & 0b11) as usize];
+                waitcnt.rom_s[0] = if (val & 0x10) == 0x10 { 1 } else { 2 };
+                waitcnt.rom_n[1] = N_CYCLES[((val >> 5) & 0b11) as usize];
+                waitcnt.rom_s[1] = if (val & 0x80) == 0x80 { 1 } else { 4 };
+            },
+            WAITCNT_HI => {
+                waitcnt.rom_n[2] = N_CYCLES[(val & 0b11) as usize];
+                waitcnt.rom_s[2] = if (val & 0x4) == 0x4 { 1 } else { 8 };
+                waitcnt.prefetch = (val & 0x40) == 0x40;
+                // the game pak type bit can't be written to
+                self.raw.io[(WAITCNT_HI - IO_START) as usize] &= 0x7F;
+            },
+            _ => ()
+        }
+    }
+
+    pub fn update_waitcnt_hw(&mut self, addr: u32, val: u32) {
+        self.update_waitcnt_byte(addr, val as u8);
+        self.update_waitcnt_byte(addr + 1, (val >> 8) as u8);
+    }
+
+    pub fn update_waitcnt_word(&mut self, addr: u32, val: u32) {
+        self.update_waitcnt_hw(addr, val);
+        self.update_waitcnt_hw(addr + 2, val >> 16);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000204, 0b1100_0101_1011_0110);
+        assert_eq!(mem.waitcnt.sram, 2);
+        assert_eq!(mem.waitcnt.rom_n, [3, 3, 3]);
+        assert_eq!(mem.waitcnt.rom_s, [1, 1, 1]);
+        assert_eq!(mem.waitcnt.prefetch, true);
+        // the game pak type bit is read only
+        assert_eq!(mem.get_halfword(0x4000204), 0b0100_0101_1011_0110);
+    }
+
+    #[test]
+    fn reconfigure() {
+        let mut mem = Memory::new();
+        assert_eq!(mem.access_time(0x8000000, true), 5);
+        assert_eq!(mem.access_time(0xC000004, false), 9);
+
+        mem.set_halfword(0x4000204, 0x0014);
+        assert_eq!(mem.access_time(0x8000000, true), 4);
+        assert_eq!(mem.access_time(0x8000004, false), 2);
+        assert_eq!(mem.access_time(0xC000004, false), 9);
+
+        mem.set_halfword(0x4000204, 0x4014);
+        assert_eq!(mem.access_time(0x8000000, true), 4);
+        assert_eq!(mem.access_time(0xC000004, false), 1);
+    }
+
+    #[test]
+    fn prefetch_disabled() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x4000204, 0x0014);
+        assert_eq!(mem.access_time(0x8000004, false), 2);
+        // crossing into the next 128KB page
+        assert_eq!(mem.access_time(0x8020000, false), 4);
+
+        // a sequential fetch right after an internal cycle is non sequential
+        mem.internal_cycles(1);
+        assert_eq!(mem.access_time(0x8000008, false), 4);
+        assert_eq!(mem.access_time(0x800000C, false), 2);
+        // any other access in between resets it
+        mem.internal_cycles(1);
+        mem.access_time(0x3000000, true);
+        assert_eq!(mem.access_time(0x8000010, false), 2);
+
+        // the prefetch buffer hides the penalty
+        mem.set_halfword(0x4000204, 0x4014);
+        mem.internal_cycles(1);
+        assert_eq!(mem.access_time(0x8000008, false), 1);
+    }
+
+    #[test]
+    fn stats() {
+        let mem = Memory::new();
+        assert_eq!(mem.access_stats.average_latency(), 0.0);
+        mem.access_time(0x3000000, true);
+        mem.access_time(0x8000000, true);
+        assert_eq!(mem.access_stats.average_latency(), 3.0);
+        mem.access_stats.reset();
+        assert_eq!(mem.access_stats.accesses.get(), 0);
+    }
+}
@@ -0,0 +1,67 @@
+//! A false color copy of the screen for debugging priority bugs, where each
+//! pixel is colored by the layer that won it instead of by its real color:
+//! BG0 red, BG1 yellow, BG2 blue, BG3 cyan, sprites green and the backdrop
+//! grey. The higher the priority number the darker the color, so e.g. a
+//! sprite drawn in front of a background because of its priority is easy to
+//! tell from one drawn in front because of a tie. It's drawn alongside the
+//! real frame while enabled, so it's left as is while disabled.
+
+use mem::framebuffer::{HeapFrame, Layer};
+
+pub struct LayerView {
+    pub enabled: bool,
+    pub pixels: HeapFrame,
+}
+
+impl LayerView {
+    pub const fn new() -> LayerView {
+        LayerView {
+            enabled: false,
+            pixels: HeapFrame::new(),
+        }
+    }
+}
+
+/// Return the false color for a pixel from the given layer, drawn with the
+/// given priority (0-3). The backdrop has no priority of its own
+pub fn layer_color(layer: Layer, priority: u8) -> u16 {
+    let level = 31 - 6 * priority as u16;
+    // 5 bits each of red, green and blue from the lowest bit up
+    let (r, g, b) = match layer {
+        Layer::Bg(0) => (level, 0, 0),
+        Layer::Bg(1) => (level, level, 0),
+        Layer::Bg(2) => (0, 0, level),
+        Layer::Bg(_) => (0, level, level),
+        Layer::Obj(_) => (0, level, 0),
+        Layer::Backdrop => return 0x2108,
+    };
+    r | g << 5 | b << 10
+}
+
+#[cfg(all(test, feature = "render"))]
+mod test {
+    use super::*;
+    use mem::Memory;
+
+    #[test]
+    fn layer_view() {
+        let mut mem = Memory::new();
+        mem.framebuffer.layer_view.enabled = true;
+        // mode 3, with BG2 at priority 2
+        mem.set_halfword(0x4000000, 0x0403);
+        mem.set_halfword(0x400000C, 0x0002);
+        mem.set_halfword(0x6000000, 0x001F);
+        mem.render_scanline(0);
+
+        assert_eq!(mem.framebuffer.pixels[0][0], 0x001F);
+        assert_eq!(mem.framebuffer.layer_view.pixels[0][0], 19 << 10);
+        assert_eq!(layer_color(Layer::Obj(3), 0), 31 << 5);
+
+        // the real frame is still drawn, but the view is left alone while
+        // it's disabled
+        mem.framebuffer.layer_view.enabled = false;
+        mem.set_halfword(0x400000C, 0x0000);
+        mem.render_scanline(0);
+        assert_eq!(mem.framebuffer.layer_view.pixels[0][0], 19 << 10);
+    }
+}
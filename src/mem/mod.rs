@@ -1,14 +1,33 @@
-mod addrs;
-mod framebuffer;
+pub mod addrs;
+pub mod backup;
+pub mod dirty;
+pub mod framebuffer;
 mod palette;
+pub mod postprocess;
 pub mod io;
+#[cfg(feature = "debugger")]
+pub mod layer_view;
 pub mod oam;
+#[cfg(feature = "debugger")]
+pub mod oam_log;
+pub mod overlay;
+#[cfg(feature = "render")]
+pub mod reference;
+#[cfg(feature = "debugger")]
+pub mod scanline_log;
+pub mod tilt;
+pub mod watch;
 
-use std;
+use std::cell::Cell;
+use std::cmp;
+use std::convert::TryInto;
+use std::ops::{Deref, DerefMut};
+use error::{AccessKind, Error};
 use util;
 use mem::io::addrs::*;
 use mem::io::dma::TimingMode;
 use self::addrs::*;
+use self::watch::Source;
 
 pub struct Memory {
     pub raw: RawMemory,
@@ -17,18 +36,41 @@ pub struct Memory {
     pub graphics: io::graphics::LCD,
     pub dma: io::dma::DMA,
     pub int: io::interrupt::Interrupt,
+    pub serial: io::serial::Serial,
+    pub timers: io::timers::Timers,
+    pub sound: io::sound::DirectSound,
+    pub apu: io::sound::APU,
+    pub debug: io::debug::DebugOutput,
     pub sprites: oam::Sprites,
     pub palette: palette::Palette,
 
-    // waitstates for reading from ROM, can be configured by writing to REG_WSCNT
-    /// waitstates for a non sequential read from ROM
-    rom_n_cycle: u8,
-    /// if true, sequential reads from ROM are fast and otherwise they are slow.
-    /// fast will always be 1 cycle but the number of cycles for a slow sequential
-    /// read depends on which mirror data is being read from
-    rom_s_cycle_fast: bool,
+    /// waitstates for reading from the game pak, configured by writing to
+    /// WAITCNT
+    pub waitcnt: io::waitcnt::WaitCnt,
+    pub access_stats: io::waitcnt::AccessStats,
+
+    /// set once a BIOS has been uploaded. without one, BIOS calls must be
+    /// emulated
+    pub bios_loaded: bool,
+
+    /// when set, accesses to memory that isn't backed by anything (including
+    /// writes to ROM) are reported as errors instead of being ignored. This
+    /// catches bugs in homebrew that would go unnoticed on hardware
+    pub strict: bool,
+    /// the first bad access since the last call to take_violation, in strict
+    /// mode
+    violation: Cell<Option<Error>>,
+    /// watches for games stuck polling hardware that isn't emulated
+    pub poll_watch: io::unsupported::PollWatch,
+    /// the game's writes to the IO registers, when recording a fixture
+    pub mmio_log: io::mmio_log::MmioLog,
+    pub watch: watch::Watchpoints,
 
     pub framebuffer: framebuffer::FrameBuffer,
+    #[cfg(feature = "debugger")]
+    pub scanline_log: scanline_log::ScanlineLog,
+    #[cfg(feature = "debugger")]
+    pub oam_log: oam_log::OamLog,
 }
 
 impl Memory {
@@ -38,44 +80,204 @@ impl Memory {
             graphics: io::graphics::LCD::new(),
             dma: io::dma::DMA::new(),
             int: io::interrupt::Interrupt::new(),
+            serial: io::serial::Serial::new(),
+            timers: io::timers::Timers::new(),
+            sound: io::sound::DirectSound::new(),
+            apu: io::sound::APU::new(),
+            debug: io::debug::DebugOutput::new(),
             sprites: oam::Sprites::new(),
             palette: palette::Palette::new(),
-            rom_n_cycle: 4,
-            rom_s_cycle_fast: false,
+            waitcnt: io::waitcnt::WaitCnt::new(),
+            access_stats: io::waitcnt::AccessStats::new(),
+            bios_loaded: false,
+            strict: false,
+            violation: Cell::new(None),
+            poll_watch: io::unsupported::PollWatch::new(),
+            mmio_log: io::mmio_log::MmioLog::new(),
+            watch: watch::Watchpoints::new(),
             framebuffer: framebuffer::FrameBuffer::new(),
+            #[cfg(feature = "debugger")]
+            scanline_log: scanline_log::ScanlineLog::new(),
+            #[cfg(feature = "debugger")]
+            oam_log: oam_log::OamLog::new(),
         }
     }
 
     pub fn get_byte(&self, addr: u32) -> u8 {
+        self.get_byte_by(addr, Source::Cpu)
+    }
+
+    /// Read a byte on behalf of the given source, e.g. the frame script
+    pub fn get_byte_by(&self, addr: u32, source: Source) -> u8 {
         let addr = canonicalize_addr(addr);
-        self.raw.get_byte(addr)
+        self.watch.check(addr, 1, AccessKind::Read, source);
+        self.load_byte(addr)
+    }
+
+    /// Read a byte from a canonical address, without checking the watchpoints
+    fn load_byte(&self, addr: u32) -> u8 {
+        self.check_access(addr, AccessKind::Read);
+        match addr {
+            IO_START...IO_END => self.get_io_byte(addr),
+            DEBUG_START...DEBUG_END => self.get_debug_byte(addr),
+            SRAM_START...SRAM_END if self.raw.tilt.present() =>
+                self.raw.tilt.read(addr - SRAM_START),
+            _ => self.raw.get_byte(addr),
+        }
+    }
+
+    /// Registers with bits that change without being written (e.g. the
+    /// VBlank flag in DISPSTAT) are read from the parsed structs, so reads
+    /// wider than a register are put together from each register's value
+    fn get_io_byte(&self, addr: u32) -> u8 {
+        self.poll_watch.record_read(addr);
+        let reg = match addr & !1 {
+            DISPSTAT_LO => self.graphics.disp_stat.as_u16(),
+            VCOUNT_LO => self.graphics.vcount as u16,
+            IE_LO => self.int.enabled.as_u16(),
+            IF_LO => self.int.triggered.as_u16(),
+            TIMER_START...TIMER_END if addr & 2 == 0 => self.timer_counter(addr & !3),
+            _ => return self.raw.get_byte(addr),
+        };
+        (reg >> (8 * (addr & 1))) as u8
     }
 
     pub fn get_halfword(&self, addr: u32) -> u16 {
+        self.get_halfword_by(addr, Source::Cpu)
+    }
+
+    /// Read a halfword on behalf of the given source, e.g. a DMA channel
+    pub fn get_halfword_by(&self, addr: u32, source: Source) -> u16 {
         let addr = canonicalize_addr(addr);
-        self.raw.get_halfword(addr)
+        self.watch.check(addr, 2, AccessKind::Read, source);
+        self.load_halfword(addr)
+    }
+
+    fn load_halfword(&self, addr: u32) -> u16 {
+        match addr {
+            IO_START...IO_END | DEBUG_START...DEBUG_END =>
+                self.load_byte(addr) as u16 | (self.load_byte(addr + 1) as u16) << 8,
+            ROM_MIRROR2_START...ROM_MIRROR2_END if self.raw.is_eeprom(addr) =>
+                self.raw.eeprom.read_bit(),
+            _ => {
+                self.check_access(addr, AccessKind::Read);
+                self.raw.get_halfword(addr)
+            },
+        }
     }
 
     pub fn get_word(&self, addr: u32) -> u32 {
+        self.get_word_by(addr, Source::Cpu)
+    }
+
+    /// Read a word on behalf of the given source, e.g. a DMA channel
+    pub fn get_word_by(&self, addr: u32, source: Source) -> u32 {
         let addr = canonicalize_addr(addr);
-        self.raw.get_word(addr)
+        self.watch.check(addr, 4, AccessKind::Read, source);
+        self.load_word(addr)
+    }
+
+    fn load_word(&self, addr: u32) -> u32 {
+        match addr {
+            IO_START...IO_END | DEBUG_START...DEBUG_END =>
+                self.load_halfword(addr) as u32 | (self.load_halfword(addr + 2) as u32) << 16,
+            _ => {
+                self.check_access(addr, AccessKind::Read);
+                self.raw.get_word(addr)
+            },
+        }
+    }
+
+    /// Fetch the THUMB instruction at the given address. Fetches aren't
+    /// reads as far as the watchpoints are concerned
+    pub fn fetch_halfword(&self, addr: u32) -> u16 {
+        self.load_halfword(canonicalize_addr(addr))
+    }
+
+    /// Fetch the ARM instruction at the given address
+    pub fn fetch_word(&self, addr: u32) -> u32 {
+        self.load_word(canonicalize_addr(addr))
+    }
+
+    /// In strict mode, remember the access if there's nothing at the given
+    /// address to read from or write to
+    fn check_access(&self, addr: u32, kind: AccessKind) {
+        if !self.strict {
+            return;
+        }
+        let valid = match addr {
+            DEBUG_START...DEBUG_END => self.debug.available,
+            SRAM_START...SRAM_END if self.raw.tilt.present() => true,
+            SRAM_START...SRAM_END if kind == AccessKind::Write =>
+                self.raw.has_backup(),
+            ROM_MIRROR2_START...ROM_MIRROR2_END if self.raw.is_eeprom(addr) => true,
+            _ if kind == AccessKind::Write => self.is_mapped(addr) && !self.is_rom(addr),
+            _ => self.is_mapped(addr),
+        };
+        if !valid {
+            let first = self.violation.take().unwrap_or(Error::UnmappedAccess { addr, kind });
+            self.violation.set(Some(first));
+        }
+    }
+
+    /// Return the first bad access made in strict mode since the last call,
+    /// if any
+    pub fn take_violation(&self) -> Option<Error> {
+        self.violation.take()
+    }
+
+    /// Return true if the given address is backed by memory (as opposed to
+    /// returning a value from the bus)
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        match self.raw.get_loc(canonicalize_addr(addr)) {
+            Some((segment, idx)) => idx < segment.len(),
+            None => false,
+        }
+    }
+
+    /// Return true if the given address is in one of the cartridge ROM regions
+    pub fn is_rom(&self, addr: u32) -> bool {
+        match addr {
+            ROM_START...ROM_MIRROR2_END => true,
+            _ => false,
+        }
     }
 
     pub fn set_byte(&mut self, addr: u32, val: u8) {
+        self.set_byte_by(addr, val, Source::Cpu);
+    }
+
+    /// Write a byte on behalf of the given source, e.g. the frame script
+    pub fn set_byte_by(&mut self, addr: u32, val: u8, source: Source) {
         let addr = canonicalize_addr(addr);
+        self.watch.check(addr, 1, AccessKind::Write, source);
+        self.check_access(addr, AccessKind::Write);
+        self.mmio_log.record(addr, 1, val as u32);
         self.raw.set_byte(addr, val);
 
         match addr {
             GRAPHICS_START...GRAPHICS_END =>
                 self.update_graphics_byte(addr, val),
+            SOUND_START...FIFO_B_END =>
+                self.update_sound_byte(addr, val),
             DMA_START...DMA_END =>
                 self.update_dma_byte(addr, val),
+            TIMER_START...TIMER_END =>
+                self.update_timer_byte(addr, val),
+            SERIAL_START...SERIAL_END | RCNT...RCNT_HI =>
+                self.update_serial_byte(addr, val),
+            WAITCNT_LO...WAITCNT_HI =>
+                self.update_waitcnt_byte(addr, val),
             INT_START...INT_END =>
                 self.update_int_byte(addr, val),
             OAM_START...OAM_END =>
                 self.update_oam_byte(addr, val),
             PAL_START...PAL_END =>
                 self.update_pal_byte(addr, val),
+            DEBUG_START...DEBUG_END =>
+                self.update_debug_byte(addr, val),
+            SRAM_START...SRAM_END =>
+                self.update_sram_byte(addr, val),
             _ => ()
         }
     }
@@ -85,45 +287,162 @@ impl Memory {
     // mapped segment?
 
     pub fn set_halfword(&mut self, addr: u32, val: u32) {
+        self.set_halfword_by(addr, val, Source::Cpu);
+    }
+
+    /// Write a halfword on behalf of the given source, e.g. a DMA channel
+    pub fn set_halfword_by(&mut self, addr: u32, val: u32, source: Source) {
         let addr = canonicalize_addr(addr);
+        self.watch.check(addr, 2, AccessKind::Write, source);
+        self.check_access(addr, AccessKind::Write);
+        self.mmio_log.record(addr, 2, val & 0xFFFF);
         self.raw.set_halfword(addr, val);
+        self.update_hw(addr, val);
+    }
 
+    /// Update the parsed state that depends on the halfword at the given
+    /// (canonical) address after it has been written to raw memory
+    fn update_hw(&mut self, addr: u32, val: u32) {
         match addr {
             GRAPHICS_START...GRAPHICS_END =>
                 self.update_graphics_hw(addr, val),
+            SOUND_START...FIFO_B_END =>
+                self.update_sound_hw(addr, val),
             DMA_START...DMA_END =>
                 self.update_dma_hw(addr, val),
+            TIMER_START...TIMER_END =>
+                self.update_timer_hw(addr, val),
+            SERIAL_START...SERIAL_END | RCNT...RCNT_HI =>
+                self.update_serial_hw(addr, val),
+            WAITCNT_LO...WAITCNT_HI =>
+                self.update_waitcnt_hw(addr, val),
             INT_START...INT_END =>
                 self.update_int_hw(addr, val),
             OAM_START...OAM_END =>
                 self.update_oam_hw(addr, val),
             PAL_START...PAL_END =>
                 self.update_pal_hw(addr, val),
+            DEBUG_START...DEBUG_END =>
+                self.update_debug_hw(addr, val),
+            SRAM_START...SRAM_END =>
+                self.update_sram_byte(addr, val as u8),
+            ROM_MIRROR2_START...ROM_MIRROR2_END =>
+                self.update_eeprom_hw(addr, val),
             _ => ()
         }
     }
 
     pub fn set_word(&mut self, addr: u32, val: u32) {
+        self.set_word_by(addr, val, Source::Cpu);
+    }
+
+    /// Write a word on behalf of the given source, e.g. a DMA channel
+    pub fn set_word_by(&mut self, addr: u32, val: u32, source: Source) {
         let addr = canonicalize_addr(addr);
+        self.watch.check(addr, 4, AccessKind::Write, source);
+        self.check_access(addr, AccessKind::Write);
+        self.mmio_log.record(addr, 4, val);
         self.raw.set_word(addr, val);
 
         match addr {
             GRAPHICS_START...GRAPHICS_END =>
                 self.update_graphics_word(addr, val),
+            SOUND_START...FIFO_B_END =>
+                self.update_sound_word(addr, val),
             DMA_START...DMA_END =>
                 self.update_dma_word(addr, val),
+            TIMER_START...TIMER_END =>
+                self.update_timer_word(addr, val),
+            SERIAL_START...SERIAL_END | RCNT...RCNT_HI =>
+                self.update_serial_word(addr, val),
+            WAITCNT_LO...WAITCNT_HI =>
+                self.update_waitcnt_word(addr, val),
             INT_START...INT_END =>
                 self.update_int_word(addr, val),
             OAM_START...OAM_END =>
                 self.update_oam_word(addr, val),
             PAL_START...PAL_END =>
                 self.update_pal_word(addr, val),
+            DEBUG_START...DEBUG_END =>
+                self.update_debug_word(addr, val),
+            SRAM_START...SRAM_END =>
+                self.update_sram_byte(addr, val as u8),
             _ => ()
         }
     }
 
+    /// Copy count words from src to dest in one go, or fill them with the
+    /// first word of src. This is for the BIOS's copies and for copy loops
+    /// (see cpu::fast_copy), and is only done when both ranges are aligned
+    /// and lie in memory that has no side effects on write: EWRAM, IWRAM and
+    /// VRAM, or ROM for the source. Returns false without copying anything
+    /// otherwise, and the caller should copy each word through set_word.
+    /// Nothing is copied in one go while there are watchpoints, so that the
+    /// copy stops at the word that hits one
+    pub fn copy_words(&mut self, src: u32, dest: u32, count: u32, fill: bool) -> bool {
+        if count == 0 {
+            return true;
+        }
+        if !self.watch.points().is_empty() {
+            return false;
+        }
+        let len = count * 4;
+        let src_len = if fill { 4 } else { len };
+        let (src, dest) = match (self.plain_range(src, src_len, false),
+                                 self.plain_range(dest, len, true)) {
+            (Some(src), Some(dest)) => (src, dest),
+            _ => return false,
+        };
+        // copying forwards onto a later part of the source repeats the start
+        // of the source, which a single slice copy wouldn't do
+        if !fill && dest > src && dest < src + len {
+            return false;
+        }
+        let data = match self.raw.get_loc(src) {
+            Some((segment, idx)) => segment[idx..idx + src_len as usize].to_vec(),
+            None => return false,
+        };
+        if let Some((segment, idx)) = self.raw.get_loc_mut(dest) {
+            let dest = &mut segment[idx..idx + len as usize];
+            if fill {
+                for word in dest.chunks_mut(4) {
+                    word.copy_from_slice(&data);
+                }
+            } else {
+                dest.copy_from_slice(&data);
+            }
+        }
+        true
+    }
+
+    /// Return the canonical address of the given range if it's aligned and
+    /// entirely in one of the regions copy_words can use
+    fn plain_range(&self, addr: u32, len: u32, write: bool) -> Option<u32> {
+        if addr % 4 != 0 {
+            return None;
+        }
+        let start = canonicalize_addr(addr);
+        // ranges that cross into a mirror aren't contiguous in the raw memory
+        if canonicalize_addr(addr.checked_add(len - 1)?) != start + len - 1 {
+            return None;
+        }
+        match start {
+            EWRAM_START...EWRAM_END |
+            IWRAM_START...IWRAM_END |
+            VRAM_START...VRAM_END => (),
+            ROM_START...ROM_MIRROR2_END if !write => (),
+            _ => return None,
+        }
+        match self.raw.get_loc(start) {
+            Some((segment, idx)) if idx + len as usize <= segment.len() => Some(start),
+            _ => None,
+        }
+    }
+
     pub fn on_vdraw_hook(&mut self) {
         self.graphics.disp_stat.is_vblank = false;
+        self.sprites.latch_frame();
+        self.poll_watch.start_frame();
         self.raw.io[(DISPSTAT_LO - IO_START) as usize] &= !1;
     }
 
@@ -136,6 +455,8 @@ impl Memory {
             self.int.triggered.vblank = true;
             self.raw.io[(IF_LO - IO_START) as usize] |= 1;
         }
+        #[cfg(feature = "debugger")]
+        self.capture_oam_changes();
         self.check_dma(TimingMode::VBlank);
     }
 
@@ -155,13 +476,9 @@ impl Memory {
     }
 
     pub fn on_vcount_hook(&mut self, vcount: u8) {
-        self.graphics.update_vcount(vcount);
+        let raise = self.graphics.update_vcount(vcount);
         self.raw.io[(VCOUNT_LO - IO_START) as usize] = vcount;
-        if self.graphics.disp_stat.vcount_triggered &&
-            self.graphics.disp_stat.vcount_irq_enabled {
-            self.int.triggered.vcount = true;
-            self.raw.io[(IF_LO  - IO_START) as usize] |= 0b100;
-        }
+        self.sync_vcount_match(raise);
     }
 
     pub fn on_dma_finish_hook(&mut self, channel: usize) {
@@ -175,6 +492,11 @@ impl Memory {
     /// addr. If first access is true, assumes a non sequential access (N cycle),
     /// otherwise assumes a sequential access (S cycle).
     pub fn access_time(&self, addr: u32, first_access: bool) -> u32 {
+        // an internal cycle only affects the access right after it, and only
+        // when the prefetch buffer is disabled
+        let after_internal_cycle = self.waitcnt.after_internal_cycle.replace(false);
+        let rom_first_access = first_access ||
+            (after_internal_cycle && !self.waitcnt.prefetch);
         let waitstates = match addr {
             EWRAM_START...EWRAM_END => 2,
             VRAM_START...VRAM_END |
@@ -184,52 +506,242 @@ impl Memory {
                 if drawing { 1 } else { 0 }
             }
             ROM_START...ROM_END =>
-                if first_access {
-                    self.rom_n_cycle
-                } else {
-                    if self.rom_s_cycle_fast { 1 } else { 2 }
-                },
+                self.waitcnt.rom_waitstates(0, addr, rom_first_access),
             ROM_MIRROR1_START...ROM_MIRROR1_END =>
-                if first_access {
-                    self.rom_n_cycle
-                } else {
-                    if self.rom_s_cycle_fast { 1 } else { 4 }
-                },
+                self.waitcnt.rom_waitstates(1, addr, rom_first_access),
             ROM_MIRROR2_START...ROM_MIRROR2_END =>
-                if first_access {
-                    self.rom_n_cycle
-                } else {
-                    if self.rom_s_cycle_fast { 1 } else { 8 }
-                },
+                self.waitcnt.rom_waitstates(2, addr, rom_first_access),
+            SRAM_START...SRAM_END => self.waitcnt.sram,
             _ => 0,
         };
-        (1 + waitstates).into()
+        let cycles = 1 + waitstates as u32;
+        self.access_stats.record(cycles);
+        cycles
+    }
+
+    /// Return the number of cycles taken by an access of size bytes, which
+    /// is split up into one access per bus width (see bus_width). Only the
+    /// first of them can be non sequential, since the rest follow on from it
+    pub fn sized_access_time(&self, addr: u32, size: u32, first_access: bool) -> u32 {
+        let width = cmp::min(bus_width(addr), size);
+        (0..size / width)
+            .map(|i| self.access_time(addr + i * width, first_access && i == 0))
+            .sum()
     }
 
     pub fn load_bios(&mut self, data: &[u8]) {
-        for i in 0..self.raw.sysrom.len() {
-            self.raw.sysrom[i] = data[i];
+        // incomplete dumps are allowed, since the missing calls can be emulated
+        for (dest, src) in self.raw.sysrom.iter_mut().zip(data.iter()) {
+            *dest = *src;
         }
+        self.bios_loaded = true;
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        unsafe {
-            self.raw.rom = Some(std::slice::from_raw_parts(
-                data as *const [u8] as *const u8,
-                data.len()));
+    /// Load a cartridge ROM. The cartridge address space is 32MB, so anything
+    /// past that is cut off. Each of the 3 ROM regions maps the same data.
+    /// The new cartridge starts without SRAM until it's configured, but gets
+    /// Flash or EEPROM if its save library asks for it (see mem::backup), and
+    /// a tilt sensor if it's one of the games that has one (see mem::tilt)
+    pub fn load_rom(&mut self, mut data: Vec<u8>) {
+        data.truncate(MAX_ROM_SIZE);
+        self.raw.rom = Some(data);
+        self.raw.detect_backup();
+        let game_code = self.game_code();
+        self.raw.detect_tilt(game_code.as_ref().map(|code| code.as_str()));
+    }
+
+    /// Zero RAM, VRAM and the IO registers as if the GBA had just been turned
+    /// on, keeping the BIOS, ROM, saves and link cable
+    pub fn clear(&mut self) {
+        for byte in self.raw.ewram.iter_mut()
+            .chain(self.raw.iwram.iter_mut())
+            .chain(self.raw.io.iter_mut())
+            .chain(self.raw.pal.iter_mut())
+            .chain(self.raw.vram.iter_mut())
+            .chain(self.raw.oam.iter_mut()) {
+            *byte = 0;
+        }
+        self.graphics = io::graphics::LCD::new();
+        self.dma = io::dma::DMA::new();
+        self.int = io::interrupt::Interrupt::new();
+        self.sprites = oam::Sprites::new();
+        self.palette = palette::Palette::new();
+        self.waitcnt = io::waitcnt::WaitCnt::new();
+        let (linked, player_id) = (self.serial.linked, self.serial.player_id);
+        self.serial = io::serial::Serial::new();
+        self.timers = io::timers::Timers::new();
+        self.sound = io::sound::DirectSound::new();
+        self.apu = io::sound::APU::new();
+        self.set_link(linked, player_id);
+        let available = self.debug.available;
+        self.debug = io::debug::DebugOutput::new();
+        self.debug.available = available;
+        self.violation.set(None);
+        self.poll_watch = io::unsupported::PollWatch::new();
+        self.raw.sram.reset_bank();
+        self.raw.flash.reset();
+        self.raw.eeprom.reset();
+        self.raw.tilt.reset();
+    }
+
+    /// Rebuild the parsed state (LCD, DMA, interrupts, sprites, palette, ...)
+    /// after the raw IO registers, palette or OAM have been overwritten
+    /// directly instead of through the write handlers. The registers are run
+    /// back through the same handlers as a game's writes. State that can't be
+    /// derived from them, like the progress of a DMA or serial transfer, the
+    /// timers' counts or the queued sound samples, is reset
+    pub fn rebuild_parsed_state(&mut self) {
+        let raw_hw = |mem: &Memory, addr: u32| mem.raw.get_halfword(addr) as u32;
+
+        // putting back the trigger line mustn't look like it being moved onto
+        // the current line, so the VCount IRQ is held off, and the status
+        // bits are restored as they were afterwards
+        self.graphics = io::graphics::LCD::new();
+        let disp_stat = self.raw.get_byte(DISPSTAT_LO);
+        self.graphics.vcount = self.raw.get_byte(VCOUNT_LO);
+        self.graphics.disp_stat.vcount_irq_raised = true;
+        for addr in (GRAPHICS_START..GRAPHICS_END).step_by(2) {
+            let val = raw_hw(self, addr);
+            self.update_graphics_hw(addr, val);
+        }
+        self.graphics.disp_stat.is_vblank = disp_stat & 1 == 1;
+        self.graphics.disp_stat.is_hblank = disp_stat & 2 == 2;
+        self.graphics.disp_stat.vcount_triggered = disp_stat & 4 == 4;
+        self.graphics.disp_stat.vcount_irq_raised = disp_stat & 4 == 4;
+        self.sync_vcount_match(false);
+
+        // the debug overrides and stats aren't part of the game's state, so
+        // only the channels are reset
+        for channel in self.dma.channels.iter_mut() {
+            *channel = io::dma::DMAChannel::new();
+        }
+        for addr in (DMA_START..DMA_END).step_by(2) {
+            let val = raw_hw(self, addr);
+            self.update_dma_hw(addr, val);
+        }
+
+        self.int = io::interrupt::Interrupt::new();
+        for &addr in [IE_LO, IME].iter() {
+            let val = raw_hw(self, addr);
+            self.update_int_hw(addr, val);
+        }
+        self.int.triggered = io::interrupt::InterruptBitmap::from_u16(
+            self.raw.get_halfword(IF_LO));
+
+        self.waitcnt = io::waitcnt::WaitCnt::new();
+        let val = raw_hw(self, WAITCNT_LO);
+        self.update_waitcnt_hw(WAITCNT_LO, val);
+
+        let (linked, player_id) = (self.serial.linked, self.serial.player_id);
+        self.serial = io::serial::Serial::new();
+        self.serial.linked = linked;
+        self.serial.player_id = player_id;
+        self.serial.error = self.raw.get_byte(SIOCNT) & 0x40 != 0;
+        for &addr in [SIOCNT, RCNT].iter() {
+            let val = raw_hw(self, addr);
+            self.update_serial_hw(addr, val);
+        }
+
+        // keeping the debug overrides, as for DMA
+        for timer in self.timers.timers.iter_mut() {
+            *timer = io::timers::Timer::new();
+        }
+        for (&cnt_l, &cnt_h) in TMCNT_L.iter().zip(TMCNT_H.iter()) {
+            let (reload, control) = (raw_hw(self, cnt_l), raw_hw(self, cnt_h));
+            self.update_timer_hw(cnt_l, reload);
+            self.update_timer_hw(cnt_h, control);
+        }
+
+        self.rebuild_sound();
+
+        for addr in (PAL_START..PAL_END).step_by(2) {
+            let val = raw_hw(self, addr);
+            self.update_pal_hw(addr, val);
+        }
+        let affine_snapshot = self.sprites.affine_snapshot;
+        self.sprites = oam::Sprites::new();
+        self.sprites.affine_snapshot = affine_snapshot;
+        for addr in (OAM_START..OAM_END).step_by(2) {
+            let val = raw_hw(self, addr);
+            self.update_oam_hw(addr, val);
+        }
+        self.sprites.latch_frame();
+    }
+
+    /// Copy data straight into memory starting at the given address (e.g. to
+    /// restore a dump of VRAM and OAM), rebuilding the parsed state if it
+    /// touches the IO registers, palette or OAM. Bytes that land in ROM or
+    /// unmapped memory are dropped
+    pub fn load_region(&mut self, addr: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.raw.set_byte(addr.wrapping_add(i as u32), *byte);
+        }
+        let end = addr.saturating_add(data.len() as u32);
+        if addr <= OAM_END && end > IO_START {
+            self.rebuild_parsed_state();
+        }
+    }
+
+    /// Return the bytes of RAM and frames kept on the heap, which size_of
+    /// doesn't count. The ROM, saves and scaled output aren't included
+    pub fn heap_size(&self) -> usize {
+        self.raw.heap_size() + framebuffer::FrameBuffer::HEAP_SIZE
+    }
+
+    /// Return the 4 character game code from the cartridge header, which is
+    /// used to identify the game
+    pub fn game_code(&self) -> Option<String> {
+        match self.raw.rom {
+            Some(ref rom) if rom.len() >= 0xB0 =>
+                Some(String::from_utf8_lossy(&rom[0xAC..0xB0]).into_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// what the RAM regions read as before they're written to, which is as big
+/// as the largest of them
+static ZEROS: [u8; 0x40000] = [0; 0x40000];
+
+/// One of the larger regions of memory, kept on the heap. Like HeapFrame,
+/// this is because the memory is built in a const fn but would be too big to
+/// build on the stack. It's allocated the first time it's written to, and
+/// reads as zeros until then
+pub struct HeapRam<const N: usize>(Option<Box<[u8; N]>>);
+
+impl<const N: usize> HeapRam<N> {
+    pub const fn new() -> HeapRam<N> {
+        HeapRam(None)
+    }
+}
+
+impl<const N: usize> Deref for HeapRam<N> {
+    type Target = [u8; N];
+
+    fn deref(&self) -> &[u8; N] {
+        match self.0 {
+            Some(ref ram) => ram,
+            None => ZEROS[..N].try_into().unwrap(),
         }
     }
 }
 
+impl<const N: usize> DerefMut for HeapRam<N> {
+    fn deref_mut(&mut self) -> &mut [u8; N] {
+        // allocated as a Vec, since Box::new would build it on the stack first
+        self.0.get_or_insert_with(|| vec![0; N].into_boxed_slice().try_into().unwrap())
+    }
+}
+
 pub struct RawMemory {
     /// contains the BIOS
-    pub sysrom: [u8; 0x4000],
+    pub sysrom: HeapRam<0x4000>,
     /// space for game data/code; largest area of RAM but memory transfers are
     /// 16 bit wide which makes it slower than iwram
-    pub ewram: [u8; 0x40000],
+    pub ewram: HeapRam<0x40000>,
     /// fastest RAM segment which is internally embedded in the CPU chip package
     /// with a 32 bit bus
-    pub iwram: [u8; 0x8000],
+    pub iwram: HeapRam<0x8000>,
     /// a mirror of the memory mapped ASIC registers on the GBA used to control
     /// graphics, sound, DMA, timers, etc.
     pub io: [u8; 0x400],
@@ -238,51 +750,71 @@ pub struct RawMemory {
     pub pal: [u8; 0x400],
     /// stores the frame buffer in bitmapped modes or the tile data/tile maps
     /// in text, rotate/scale modes
-    pub vram: [u8; 0x18000],
+    pub vram: HeapRam<0x18000>,
     /// stores 128 entries of 8 bytes, containing information for each sprite
     pub oam: [u8; 0x400],
     // ROM in the game cartridge appears in this area. This ROM gets uploaded
-    // on the javascript side and then a reference to it is set here
-    pub rom: Option<&'static [u8]>,
-    // either SRAM or flash ROM used for saving game data
-    // TODO: allocate on the javascript side?
-    // cart: Vec<u8>,
+    // on the javascript side and copied here
+    pub rom: Option<Vec<u8>>,
+    /// battery backed SRAM in the game pak used for saving game data, if the
+    /// cartridge has any
+    pub sram: backup::Sram,
+    /// the Flash chip that some cartridges save to instead of SRAM. When
+    /// there is one, it takes over the backup region
+    pub flash: backup::Flash,
+    /// the serial EEPROM that the rest of the cartridges save to, accessed
+    /// through the end of the last ROM mirror
+    pub eeprom: backup::Eeprom,
+    /// the tilt sensor that a couple of cartridges have in the backup region
+    pub tilt: tilt::TiltSensor,
+    /// the parts of the palette and OAM written since they were last parsed
+    pub dirty: dirty::Dirty,
 }
 
 impl RawMemory {
     pub const fn new() -> RawMemory {
         RawMemory {
-            sysrom: [0; 0x4000],
-            ewram: [0; 0x40000],
-            iwram: [0; 0x8000],
+            sysrom: HeapRam::new(),
+            ewram: HeapRam::new(),
+            iwram: HeapRam::new(),
             io: [0; 0x400],
             pal: [0; 0x400],
-            vram: [0; 0x18000],
+            vram: HeapRam::new(),
             oam: [0; 0x400],
             rom: None,
-            // pak: Vec::new(),
-            // cart: Vec::new(),
+            sram: backup::Sram::new(),
+            flash: backup::Flash::new(),
+            eeprom: backup::Eeprom::new(),
+            tilt: tilt::TiltSensor::new(),
+            dirty: dirty::Dirty::new(),
         }
     }
 
+    /// Return the bytes of RAM kept on the heap (see HeapRam), which size_of
+    /// doesn't count, whether or not it has been allocated yet
+    pub fn heap_size(&self) -> usize {
+        self.sysrom.len() + self.ewram.len() + self.iwram.len() + self.vram.len()
+    }
+
     /// given an absolute address into memory, convert it to a reference to
-    /// one of the memory segments and an index into that segment
+    /// one of the memory segments and an index into that segment. returns None
+    /// if there is no memory backing the given address
     pub fn get_loc(&self, addr: u32) -> Option<(&[u8], usize)> {
         // TODO: use addr / 0x01000000 instead of a match statement?
         let result: (&[u8], u32) = match addr {
-            SYSROM_START...SYSROM_END => (&self.sysrom, addr),
-            EWRAM_START...EWRAM_END => (&self.ewram, addr - EWRAM_START),
-            IWRAM_START...IWRAM_END => (&self.iwram, addr - IWRAM_START),
+            SYSROM_START...SYSROM_END => (&self.sysrom[..], addr),
+            EWRAM_START...EWRAM_END => (&self.ewram[..], addr - EWRAM_START),
+            IWRAM_START...IWRAM_END => (&self.iwram[..], addr - IWRAM_START),
             IO_START...IO_END => (&self.io, addr - IO_START),
             PAL_START...PAL_END => (&self.pal, addr - PAL_START),
-            VRAM_START...VRAM_END => (&self.vram, addr - VRAM_START),
+            VRAM_START...VRAM_END => (&self.vram[..], addr - VRAM_START),
             OAM_START...OAM_END => (&self.oam, addr - OAM_START),
-            ROM_START...ROM_END => (self.rom.unwrap(), addr - ROM_START),
+            ROM_START...ROM_END => (&self.rom.as_ref()?[..], addr - ROM_START),
             ROM_MIRROR1_START...ROM_MIRROR1_END =>
-                (self.rom.unwrap(), addr - ROM_MIRROR1_START),
+                (&self.rom.as_ref()?[..], addr - ROM_MIRROR1_START),
             ROM_MIRROR2_START...ROM_MIRROR2_END =>
-                (self.rom.unwrap(), addr - ROM_MIRROR2_START),
-            0x0E000000...0x0E00FFFF => unimplemented!(),
+                (&self.rom.as_ref()?[..], addr - ROM_MIRROR2_START),
+            SRAM_START...SRAM_END => return self.backup_loc(addr - SRAM_START),
             _ => { return None; }
         };
         Some((result.0, result.1 as usize))
@@ -291,23 +823,25 @@ impl RawMemory {
     pub fn get_loc_mut(&mut self, addr: u32) -> Option<(&mut [u8], usize)> {
         // TODO: use addr / 0x01000000 instead of a match statement?
         let result: (&mut [u8], u32) = match addr {
-            SYSROM_START...SYSROM_END => (&mut self.sysrom, addr),
-            EWRAM_START...EWRAM_END => (&mut self.ewram, addr - EWRAM_START),
-            IWRAM_START...IWRAM_END => (&mut self.iwram, addr - IWRAM_START),
+            SYSROM_START...SYSROM_END => (&mut self.sysrom[..], addr),
+            EWRAM_START...EWRAM_END => (&mut self.ewram[..], addr - EWRAM_START),
+            IWRAM_START...IWRAM_END => (&mut self.iwram[..], addr - IWRAM_START),
             IO_START...IO_END => (&mut self.io, addr - IO_START),
             PAL_START...PAL_END => (&mut self.pal, addr - PAL_START),
-            VRAM_START...VRAM_END => (&mut self.vram, addr - VRAM_START),
+            VRAM_START...VRAM_END => (&mut self.vram[..], addr - VRAM_START),
             OAM_START...OAM_END => (&mut self.oam, addr - OAM_START),
-            ROM_START...ROM_MIRROR2_END => panic!("trying to write to ROM"),
-            0x0E000000...0x0E00FFFF => unimplemented!(),
+            SRAM_START...SRAM_END => return self.backup_loc_mut(addr - SRAM_START),
+            // writes to ROM and to unmapped memory are ignored
             _ => { return None; }
         };
         Some((result.0, result.1 as usize))
     }
 
     pub fn get_byte(&self, addr: u32) -> u8 {
-        let (segment, idx) = self.get_loc(addr).unwrap_or((&[], 1));
-        if idx >= segment.len() { 0 } else { segment[idx] }
+        match self.get_loc(addr) {
+            Some((segment, idx)) if idx < segment.len() => segment[idx],
+            _ => unmapped_byte(addr),
+        }
     }
 
     pub fn get_halfword(&self, addr: u32) -> u16 {
@@ -325,6 +859,7 @@ impl RawMemory {
         self.get_loc_mut(addr).map(|(segment, idx)| {
             segment[idx] = val;
         });
+        self.dirty.mark(addr);
     }
 
     pub fn set_halfword(&mut self, addr: u32, val: u32) {
@@ -340,8 +875,35 @@ impl RawMemory {
     }
 }
 
+/// Return the value read from an address that isn't backed by any memory
+fn unmapped_byte(addr: u32) -> u8 {
+    match addr {
+        // the cartridge bus is shared between address and data, so reading
+        // past the end of the ROM (or without a ROM) returns the lower 16 bits
+        // of the address in halfwords
+        ROM_START...ROM_MIRROR2_END => ((addr >> 1) >> (8 * (addr & 1))) as u8,
+        // the backup region reads as all 1s when there is no chip to drive
+        // the data lines
+        SRAM_START...0x0FFFFFFF => 0xFF,
+        // TODO: the rest of the unused regions should return the last value
+        // prefetched by the CPU (open bus)
+        _ => 0,
+    }
+}
+
+/// Return the width in bytes of the data bus to the memory at addr, in any
+/// mirror. A word read from the game pak takes two halfword reads, and the
+/// backup chip can only be read a byte at a time
+pub fn bus_width(addr: u32) -> u32 {
+    match addr >> 24 {
+        0x2 | 0x5 | 0x6 | 0x8...0xD => 2,
+        0xE...0xF => 1,
+        _ => 4,
+    }
+}
+
 /// map any addresses of mirrored segments of memory to the actual segment
-fn canonicalize_addr(addr: u32) -> u32 {
+pub fn canonicalize_addr(addr: u32) -> u32 {
     match addr {
         0x0000000...0x0FFFFFF => addr,
         0x2000000...0x2FFFFFF => EWRAM_START + (addr % 0x40000),
@@ -405,6 +967,99 @@ mod test {
         assert_eq!(mem.get_word(0x3007FFC), 0x300);
     }
 
+    #[test]
+    fn unmapped() {
+        let mut mem = RawMemory::new();
+        assert_eq!(mem.get_halfword(0x8000000), 0);
+        assert_eq!(mem.get_halfword(0x80001FE), 0xFF);
+        assert_eq!(mem.get_word(0xC123454), 0x1A2B1A2A);
+        assert_eq!(mem.get_word(0xE000000), 0xFFFFFFFF);
+        assert_eq!(mem.get_word(0xF123456), 0xFFFFFFFF);
+        assert_eq!(mem.get_word(0x1000000), 0);
+        assert_eq!(mem.get_word(0xFFFFFFF0), 0);
+
+        mem.rom = Some(vec![1, 2, 3, 4]);
+        assert_eq!(mem.get_word(0xA000000), 0x04030201);
+        assert_eq!(mem.get_halfword(0x8000004), 2);
+        mem.set_word(0x8000000, 0);
+        mem.set_word(0xE000000, 0);
+        assert_eq!(mem.get_word(0x8000000), 0x04030201);
+        assert_eq!(mem.get_word(0xE000000), 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn large_rom() {
+        let mut mem = Memory::new();
+        let mut rom = vec![0; MAX_ROM_SIZE + 4];
+        rom[0xC0] = 0x12;
+        rom[MAX_ROM_SIZE - 1] = 0x34;
+        rom[MAX_ROM_SIZE] = 0x56;
+        mem.load_rom(rom);
+        assert_eq!(mem.raw.rom.as_ref().unwrap().len(), MAX_ROM_SIZE);
+        // the 3 regions map the same data
+        for base in [ROM_START, ROM_MIRROR1_START, ROM_MIRROR2_START].iter() {
+            assert_eq!(mem.get_byte(base + 0xC0), 0x12);
+            assert_eq!(mem.get_byte(base + MAX_ROM_SIZE as u32 - 1), 0x34);
+        }
+
+        // reads past the end of a smaller ROM return the address
+        let mut rom = vec![0xFF; 0x1000000];
+        rom[0xFFFFFF] = 0x78;
+        mem.load_rom(rom);
+        assert_eq!(mem.get_byte(0x8FFFFFF), 0x78);
+        assert_eq!(mem.get_halfword(0x9000000), 0);
+        assert_eq!(mem.get_halfword(0xB000002), 1);
+        assert_eq!(mem.get_word(0xDFFFFFC), 0xFFFFFFFE);
+        assert!(mem.is_mapped(0xCFFFFFF));
+        assert!(!mem.is_mapped(0xD000000));
+    }
+
+    #[test]
+    fn io_reads() {
+        let mut mem = Memory::new();
+        mem.set_halfword(DISPSTAT_LO, 0x5038);
+        mem.on_vblank_hook();
+        mem.on_vcount_hook(0x50);
+        assert_eq!(mem.get_halfword(DISPSTAT_LO), 0x503D);
+        assert_eq!(mem.get_byte(DISPSTAT_HI), 0x50);
+        // DISPSTAT and VCOUNT together
+        assert_eq!(mem.get_word(DISPSTAT_LO), 0x0050_503D);
+        assert_eq!(mem.get_halfword(DISPSTAT_HI), 0x5050);
+        // writing DISPSTAT doesn't change the status bits
+        mem.set_word(DISPSTAT_LO, 0xFFFF_5038);
+        assert_eq!(mem.get_word(DISPSTAT_LO), 0x0050_503D);
+
+        // IE and IF together
+        mem.set_halfword(IE_LO, 0x0105);
+        mem.dma.channels[0].irq = true;
+        mem.on_dma_finish_hook(0);
+        assert_eq!(mem.get_word(IE_LO), 0x0105_0105);
+        mem.set_word(IE_LO, 0x0001_0003);
+        assert_eq!(mem.get_word(IE_LO), 0x0104_0003);
+        assert_eq!(mem.get_byte(IF_HI), 1);
+    }
+
+    #[test]
+    fn load_region() {
+        let mut mem = Memory::new();
+        // DISPCNT = mode 3, and a 16x16 sprite at (10, 20) in OAM
+        mem.load_region(0x4000000, &[0x03, 0x04]);
+        mem.load_region(0x7000000, &[20, 0, 10, 0x40]);
+        mem.load_region(0x5000200, &[0xFF, 0x7F]);
+        assert_eq!(mem.graphics.disp_cnt.bg_mode, 3);
+        assert!(mem.graphics.disp_cnt.bg_enabled[2]);
+        assert_eq!((mem.sprites.sprites[0].x, mem.sprites.sprites[0].y), (10, 20));
+        assert_eq!(mem.sprites.sprites[0].width, 16);
+        assert_eq!(mem.palette.sprite[0], palette::high_to_true(0x7FFF));
+
+        // the parsed state is only rebuilt from the raw memory
+        mem.raw.set_halfword(0x4000200, 0x0001);
+        assert!(!mem.int.enabled.vblank);
+        mem.rebuild_parsed_state();
+        assert!(mem.int.enabled.vblank);
+        assert_eq!(mem.graphics.disp_cnt.bg_mode, 3);
+    }
+
     #[test]
     fn canonicalize() {
         assert_eq!(canonicalize_addr(0x0123456), 0x0123456);
@@ -429,4 +1084,17 @@ mod test {
 
         assert_eq!(canonicalize_addr(0x70034AA), 0x70000AA);
     }
+
+    #[test]
+    fn sized_access_time() {
+        let mem = Memory::new();
+        assert_eq!(mem.sized_access_time(0x3000000, 4, true), 1);
+        assert_eq!(mem.sized_access_time(0x2000000, 4, true), 6);
+        assert_eq!(mem.sized_access_time(0x2000000, 1, true), 3);
+        // a word read from the game pak is two halfword reads, and only the
+        // first can be nonsequential
+        assert_eq!(mem.sized_access_time(0x8000000, 4, true), 5 + 3);
+        assert_eq!(mem.sized_access_time(0x8000004, 4, false), 3 + 3);
+        assert_eq!(mem.sized_access_time(0xE000000, 2, true), 10);
+    }
 }
@@ -19,10 +19,23 @@ pub const NUM_AFFFINE_SPRITES: usize = 32;
 pub struct Sprites {
     pub sprites: [Sprite; NUM_SPRITES],
     pub affine_params: [SpriteAffineParams; NUM_AFFFINE_SPRITES],
+    /// the affine params used to draw the current line. normally these are
+    /// the same as affine_params, but see affine_snapshot
+    pub line_affine_params: [SpriteAffineParams; NUM_AFFFINE_SPRITES],
+    /// when set, the affine params are only picked up at the start of each
+    /// frame, so that games which update the matrices from their main loop
+    /// (and expect the changes to take effect next frame) don't tear. games
+    /// that update them from HBlank are detected and fall back to latching
+    /// the params on every line for the rest of the frame
+    pub affine_snapshot: bool,
+    /// set when affine params are written during HBlank, cleared at the
+    /// start of each frame
+    pub hblank_affine_writes: bool,
 }
 
 impl Memory {
     pub fn update_oam_byte(&mut self, addr: u32, val: u8) {
+        self.raw.dirty.unmark(addr);
         let sprite_num = (addr - OAM_START) / BYTES_PER_OAM_ENTRY;
         let sprite = &mut self.sprites.sprites[sprite_num as usize];
         match addr % BYTES_PER_OAM_ENTRY {
@@ -41,6 +54,7 @@ impl Memory {
             // E-F (S) = shape
             1 => {
                 sprite.mode = SpriteType::from_u8(val & 0b11).unwrap();
+                sprite.gfx_mode = GfxMode::from_u8((val >> 2) & 0b11).unwrap();
                 sprite.bit_depth = if (val & 0x20) == 0x20 { 8 } else { 4 };
                 sprite.shape = (val >> 6) & 0b11;
                 sprite.update_boundaries();
@@ -78,6 +92,10 @@ impl Memory {
                 sprite.palette_number = ((attr2 >> 12) & 0xF) as u8;
             },
             6...7 => {
+                let disp_stat = &self.graphics.disp_stat;
+                if disp_stat.is_hblank && !disp_stat.is_vblank {
+                    self.sprites.hblank_affine_writes = true;
+                }
                 let attr3 = self.raw.get_halfword(addr & !1);
                 let affine_group = (addr - OAM_START) / BYTES_PER_AFFINE_GROUP;
                 let params = &mut self.sprites.affine_params[affine_group as usize];
@@ -109,6 +127,23 @@ impl Sprites {
         Sprites {
             sprites: [Sprite::new(); 128],
             affine_params: [SpriteAffineParams::new(); 32],
+            line_affine_params: [SpriteAffineParams::new(); 32],
+            affine_snapshot: false,
+            hblank_affine_writes: false,
+        }
+    }
+
+    /// Called at the start of each frame, once the game has had all of
+    /// VBlank to update OAM
+    pub fn latch_frame(&mut self) {
+        self.line_affine_params = self.affine_params;
+        self.hblank_affine_writes = false;
+    }
+
+    /// Called before drawing each line
+    pub fn latch_line(&mut self) {
+        if !self.affine_snapshot || self.hblank_affine_writes {
+            self.line_affine_params = self.affine_params;
         }
     }
 }
@@ -150,11 +185,13 @@ pub struct Sprite {
     /// base tile index of the sprite
     pub tile_number: u16,
 
+    /// whether this sprite is blended, or used as part of the OBJ window
+    pub gfx_mode: GfxMode,
     // TODO: implement effects
-    // gfx_mode: GfxMode,
     // mosaic_enabled: bool,
 
-    // derived attributes:
+    // derived attributes: the screen area the sprite is drawn in (see
+    // update_boundaries), with right and bottom exclusive
     pub left: i16,
     pub right: i16,
     pub top: i16,
@@ -173,6 +210,7 @@ impl Sprite {
             bit_depth: 0,
             palette_number: 0,
             mode: SpriteType::Normal,
+            gfx_mode: GfxMode::Normal,
             affine_group: 0,
             vflip: false,
             hflip: false,
@@ -206,16 +244,43 @@ impl Sprite {
         self.width = width;
         self.height = height;
 
-        // TODO: figure out sprite boundaries
-        self.top = 0;
-        self.bottom = 0;
+        // x is a signed 9 bit value. y wraps around at 256, so a sprite that
+        // runs past the bottom of that range is treated as starting above
+        // the screen
+        let (bound_width, bound_height) = self.bounds();
+        self.left = if self.x >= 256 { self.x as i16 - 512 } else { self.x as i16 };
+        self.right = self.left + bound_width as i16;
+        self.top = if self.y as u32 + bound_height > 256 {
+            self.y as i16 - 256
+        } else {
+            self.y as i16
+        };
+        self.bottom = self.top + bound_height as i16;
+    }
+
+    /// Return true if the sprite is drawn on any part of the given line. A
+    /// tall sprite starting low enough on the screen can wrap around to the
+    /// top as well, so this doesn't just check top and bottom
+    pub fn covers_line(&self, row: u32) -> bool {
+        let (_, height) = self.bounds();
+        (row.wrapping_sub(self.y as u32) & 0xFF) < height
+    }
+
+    /// Return true if the given column is within the sprite's bounds
+    pub fn covers_column(&self, col: u32) -> bool {
+        let col = col as i16;
+        col >= self.left && col < self.right
+    }
 
-        self.left = 0;
-        self.right = 0;
+    /// Return the size of the area the sprite is drawn in, which is twice the
+    /// size of the sprite for double size affine sprites
+    pub fn bounds(&self) -> (u32, u32) {
+        let scale = if self.mode == SpriteType::DoubleAffine { 2 } else { 1 };
+        (self.width as u32 * scale, self.height as u32 * scale)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SpriteAffineParams {
     pub dx: f32,
     pub dmx: f32,
@@ -245,14 +310,33 @@ pub enum SpriteType {
 }
 }
 
+enum_from_primitive! {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GfxMode {
+    Normal = 0,
+    /// the sprite is used as the first target for alpha blending
+    SemiTransparent,
+    /// the sprite isn't drawn, but its opaque pixels define the OBJ window
+    ObjWindow,
+    Prohibited
+}
+}
+
 impl SpriteType {
     pub fn is_affine(&self) -> bool {
         match *self {
             SpriteType::Affine |
-            SpriteType::DoubleAffine => false,
-            _ => true
+            SpriteType::DoubleAffine => true,
+            _ => false
         }
     }
+
+    /// Bit 9 of attribute 0 hides a normal sprite, but doubles the size of
+    /// the area an affine sprite is drawn in
+    pub fn is_visible(&self) -> bool {
+        *self != SpriteType::Disabled
+    }
 }
 
 #[cfg(test)]
@@ -271,6 +355,7 @@ mod test {
             assert_eq!(sprite.y, 0x08);
             assert_eq!(sprite.x, 0b0_1100_1010);
             assert_eq!(sprite.mode, SpriteType::Disabled);
+            assert_eq!(sprite.gfx_mode, GfxMode::Normal);
             assert_eq!(sprite.shape, 2);
             assert_eq!(sprite.hflip, true);
             assert_eq!(sprite.vflip, true);
@@ -290,6 +375,7 @@ mod test {
             assert_eq!(sprite.y, 0b1000_1001);
             assert_eq!(sprite.x, 0b1_1101_1000);
             assert_eq!(sprite.mode, SpriteType::Affine);
+            assert_eq!(sprite.gfx_mode, GfxMode::Normal);
             assert_eq!(sprite.shape, 0);
             assert_eq!(sprite.hflip, false);
             assert_eq!(sprite.vflip, false);
@@ -313,4 +399,78 @@ mod test {
             assert_eq!(params.dmy, 1.0);
         }
     }
+
+    #[test]
+    fn affine_snapshot() {
+        let mut mem = Memory::new();
+        mem.render_scanline(0);
+        mem.set_halfword(0x7000006, 0x0100);
+        mem.render_scanline(1);
+        assert_eq!(mem.sprites.line_affine_params[0].dx, 1.0);
+
+        mem.sprites.affine_snapshot = true;
+        mem.on_vblank_hook();
+        mem.set_halfword(0x7000006, 0x0200);
+        mem.on_vdraw_hook();
+        assert_eq!(mem.sprites.line_affine_params[0].dx, 2.0);
+
+        // writes while drawing are held until the next frame
+        mem.set_halfword(0x7000006, 0x0300);
+        mem.render_scanline(0);
+        assert_eq!(mem.sprites.line_affine_params[0].dx, 2.0);
+
+        // but writes during HBlank take effect on the next line
+        mem.on_hblank_hook();
+        mem.set_halfword(0x7000006, 0x0400);
+        mem.on_hdraw_hook();
+        mem.render_scanline(1);
+        assert_eq!(mem.sprites.line_affine_params[0].dx, 4.0);
+
+        mem.on_vdraw_hook();
+        assert_eq!(mem.sprites.hblank_affine_writes, false);
+    }
+
+    #[test]
+    fn sprite_type() {
+        assert!(!SpriteType::Normal.is_affine());
+        assert!(SpriteType::Affine.is_affine());
+        assert!(!SpriteType::Disabled.is_affine());
+        assert!(SpriteType::DoubleAffine.is_affine());
+
+        assert!(SpriteType::Normal.is_visible());
+        assert!(SpriteType::Affine.is_visible());
+        assert!(!SpriteType::Disabled.is_visible());
+        assert!(SpriteType::DoubleAffine.is_visible());
+
+        let mut sprite = Sprite::new();
+        sprite.shape = 1;
+        sprite.update_boundaries();
+        assert_eq!(sprite.bounds(), (16, 8));
+        sprite.mode = SpriteType::DoubleAffine;
+        assert_eq!(sprite.bounds(), (32, 16));
+    }
+
+    #[test]
+    fn boundaries() {
+        let mut mem = Memory::new();
+        // 16x16 at (-12, -8)
+        mem.set_halfword(0x7000000, 248);
+        mem.set_halfword(0x7000002, 500 | (1 << 14));
+        {
+            let sprite = &mem.sprites.sprites[0];
+            assert_eq!((sprite.left, sprite.right), (-12, 4));
+            assert_eq!((sprite.top, sprite.bottom), (-8, 8));
+            assert!(sprite.covers_column(0) && sprite.covers_column(3));
+            assert!(!sprite.covers_column(4));
+            assert!(sprite.covers_line(7) && !sprite.covers_line(8));
+        }
+
+        // double size affine sprites cover twice the area
+        mem.set_halfword(0x7000000, 100 | (3 << 8));
+        {
+            let sprite = &mem.sprites.sprites[0];
+            assert_eq!((sprite.left, sprite.right), (-12, 20));
+            assert_eq!((sprite.top, sprite.bottom), (100, 132));
+        }
+    }
 }
@@ -0,0 +1,214 @@
+//! Optionally records which OAM entries and affine parameters changed in each
+//! frame, with their decoded values before and after. This makes it possible
+//! to find the frame in which a sprite's tile, palette or position went wrong
+//! in an animation glitch. OAM is compared with how it was at the start of
+//! the previous VBlank, so several writes to an entry in one frame show up as
+//! a single change.
+
+use std::fmt::Write;
+use mem::Memory;
+use mem::oam::{Sprite, SpriteAffineParams, NUM_SPRITES, NUM_AFFFINE_SPRITES};
+
+/// the most frames with changes that are kept. older ones are dropped
+const MAX_FRAMES: usize = 600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OamChange {
+    Sprite { index: usize, old: Sprite, new: Sprite },
+    Affine { group: usize, old: SpriteAffineParams, new: SpriteAffineParams },
+}
+
+/// The changes made during a single frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameChanges {
+    /// the number of frames since the log was enabled
+    pub frame: u32,
+    pub changes: Vec<OamChange>,
+}
+
+pub struct OamLog {
+    pub enabled: bool,
+    frame: u32,
+    /// the sprites and affine params at the end of the last frame
+    sprites: Vec<Sprite>,
+    affine_params: Vec<SpriteAffineParams>,
+    /// the frames with changes, oldest first
+    pub frames: Vec<FrameChanges>,
+}
+
+impl OamLog {
+    pub const fn new() -> OamLog {
+        OamLog {
+            enabled: false,
+            frame: 0,
+            sprites: Vec::new(),
+            affine_params: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Start or stop recording. Changes are counted from the state of OAM
+    /// when recording starts, so the game's setup isn't reported
+    pub fn set_enabled(&mut self, enabled: bool, sprites: &[Sprite],
+        affine_params: &[SpriteAffineParams]) {
+        if enabled && !self.enabled {
+            self.frame = 0;
+            self.sprites = sprites.to_vec();
+            self.affine_params = affine_params.to_vec();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Compare the given sprites and affine params with those at the end of
+    /// the last frame, and record the changes
+    fn capture(&mut self, sprites: &[Sprite; NUM_SPRITES],
+        affine_params: &[SpriteAffineParams; NUM_AFFFINE_SPRITES]) {
+        let mut changes = Vec::new();
+        for (index, (old, new)) in self.sprites.iter().zip(sprites.iter()).enumerate() {
+            if old != new {
+                changes.push(OamChange::Sprite { index, old: *old, new: *new });
+            }
+        }
+        for (group, (old, new)) in self.affine_params.iter().zip(affine_params.iter()).enumerate() {
+            if old != new {
+                changes.push(OamChange::Affine { group, old: *old, new: *new });
+            }
+        }
+        if !changes.is_empty() {
+            if self.frames.len() == MAX_FRAMES {
+                self.frames.remove(0);
+            }
+            self.frames.push(FrameChanges { frame: self.frame, changes });
+            self.sprites.copy_from_slice(sprites);
+            self.affine_params.copy_from_slice(affine_params);
+        }
+        self.frame += 1;
+    }
+
+    /// Return the log with one line per change, listing only the attributes
+    /// that changed
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        for frame in self.frames.iter() {
+            for change in frame.changes.iter() {
+                let _ = write!(out, "frame {}: ", frame.frame);
+                match *change {
+                    OamChange::Sprite { index, ref old, ref new } => {
+                        let _ = write!(out, "sprite {}:", index);
+                        write_sprite_diff(&mut out, old, new);
+                    },
+                    OamChange::Affine { group, ref old, ref new } => {
+                        let _ = write!(out, "affine {}:", group);
+                        let fields = [
+                            ("dx", old.dx, new.dx),
+                            ("dmx", old.dmx, new.dmx),
+                            ("dy", old.dy, new.dy),
+                            ("dmy", old.dmy, new.dmy),
+                        ];
+                        for &(name, old, new) in fields.iter().filter(|field| field.1 != field.2) {
+                            let _ = write!(out, " {} {} -> {}", name, old, new);
+                        }
+                    },
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+/// Write each attribute of a sprite that differs between old and new
+fn write_sprite_diff(out: &mut String, old: &Sprite, new: &Sprite) {
+    macro_rules! diff {
+        ($($field:ident),*) => {
+            $(
+                if old.$field != new.$field {
+                    let _ = write!(out, " {} {:?} -> {:?}", stringify!($field),
+                        old.$field, new.$field);
+                }
+            )*
+        }
+    }
+    diff!(x, y, shape, size, bit_depth, palette_number, affine_group, mode, vflip,
+        hflip, priority, tile_number, gfx_mode);
+}
+
+impl Memory {
+    /// Record the changes made to OAM during the frame that just ended, if
+    /// logging is enabled. Called at the start of VBlank
+    pub fn capture_oam_changes(&mut self) {
+        if !self.oam_log.enabled {
+            return;
+        }
+        // pick up anything that DMA wrote after the last line was drawn
+        self.refresh_parsed_mirrors();
+        self.oam_log.capture(&self.sprites.sprites, &self.sprites.affine_params);
+    }
+
+    pub fn set_oam_log_enabled(&mut self, enabled: bool) {
+        self.oam_log.set_enabled(enabled, &self.sprites.sprites, &self.sprites.affine_params);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mem::oam::SpriteType;
+
+    #[test]
+    fn capture() {
+        let mut mem = Memory::new();
+        mem.set_halfword(0x7000000, 10);
+        mem.on_vblank_hook();
+        assert!(mem.oam_log.frames.is_empty());
+
+        // the state when recording starts isn't a change
+        mem.set_oam_log_enabled(true);
+        mem.on_vblank_hook();
+        assert!(mem.oam_log.frames.is_empty());
+
+        // several writes in a frame are one change
+        mem.set_halfword(0x7000000, 11);
+        mem.set_halfword(0x7000000, 12 | 0x200);
+        mem.set_halfword(0x700000C, 5);
+        mem.set_halfword(0x7000006, 0x0100);
+        mem.on_vblank_hook();
+        mem.on_vblank_hook();
+        assert_eq!(mem.oam_log.frames.len(), 1);
+        let frame = &mem.oam_log.frames[0];
+        assert_eq!(frame.frame, 1);
+        assert_eq!(frame.changes.len(), 3);
+        match frame.changes[0] {
+            OamChange::Sprite { index, old, new } => {
+                assert_eq!(index, 0);
+                assert_eq!((old.y, new.y), (10, 12));
+                assert_eq!(new.mode, SpriteType::Disabled);
+            },
+            _ => panic!("expected a sprite change"),
+        }
+        let log = mem.oam_log.format();
+        assert!(log.contains("frame 1: sprite 0: y 10 -> 12 mode Normal -> Disabled\n"));
+        assert!(log.contains("frame 1: sprite 1: tile_number 0 -> 5\n"));
+        assert!(log.contains("frame 1: affine 0: dx 0 -> 1\n"));
+
+        mem.oam_log.clear();
+        assert_eq!(mem.oam_log.format(), "");
+    }
+
+    #[test]
+    fn bounded() {
+        let mut mem = Memory::new();
+        mem.set_oam_log_enabled(true);
+        for frame in 0..MAX_FRAMES + 2 {
+            mem.set_halfword(0x7000004, frame as u32);
+            mem.on_vblank_hook();
+        }
+        assert_eq!(mem.oam_log.frames.len(), MAX_FRAMES);
+        // the first frame doesn't change anything, so only frame 1 is dropped
+        assert_eq!(mem.oam_log.frames[0].frame, 2);
+    }
+}
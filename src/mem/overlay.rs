@@ -0,0 +1,219 @@
+//! Information drawn over the shown frame by the emulator itself, so that
+//! every frontend shows it the same way: the buttons being held, the number
+//! of the frame, and the emulation speed. They're drawn with a tiny built in
+//! font in the corners of the output, after any other effects, and never
+//! touch the frame the game drew.
+//!
+//! The buttons are shown in the usual order for GBA input logs, UDLRsSBALR
+//! (s is Select and S is Start, and the last L and R are the shoulder
+//! buttons), with a dot in place of each button that isn't held.
+
+use cpu::{CPUWrapper, REFRESH};
+use mem::framebuffer::{WIDTH, HEIGHT};
+use mem::io::addrs::KEYINPUT;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverlayKind {
+    /// the buttons held, in the bottom left corner
+    Input,
+    /// the number of frames since the GBA was turned on, in the top left
+    FrameCount,
+    /// the emulation speed as a percentage, in the top right
+    Speed,
+}
+
+pub const OVERLAY_KINDS: [OverlayKind; 3] =
+    [OverlayKind::Input, OverlayKind::FrameCount, OverlayKind::Speed];
+
+impl OverlayKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OverlayKind::Input => "input",
+            OverlayKind::FrameCount => "frame_count",
+            OverlayKind::Speed => "speed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OverlayKind> {
+        OVERLAY_KINDS.iter().cloned().find(|kind| kind.name() == name)
+    }
+}
+
+/// the buttons in the order they're shown, with their bits in KEYINPUT
+const INPUT_ORDER: [(char, u32); 10] = [
+    ('U', 6), ('D', 7), ('L', 5), ('R', 4), ('s', 2),
+    ('S', 3), ('B', 1), ('A', 0), ('L', 9), ('R', 8),
+];
+
+const TEXT_COLOR: u16 = 0x7FFF;
+const BACKGROUND_COLOR: u16 = 0;
+
+pub struct Overlay {
+    /// whether each of OVERLAY_KINDS is shown
+    enabled: [bool; 3],
+    /// the values to show, as of the last call to update
+    buttons: u16,
+    frame: u64,
+    speed_percent: u32,
+}
+
+impl Overlay {
+    pub const fn new() -> Overlay {
+        Overlay {
+            enabled: [false; 3],
+            buttons: 0,
+            frame: 0,
+            speed_percent: 100,
+        }
+    }
+
+    pub fn is_enabled(&self, kind: OverlayKind) -> bool {
+        self.enabled[kind as usize]
+    }
+
+    pub fn set_enabled(&mut self, kind: OverlayKind, enabled: bool) {
+        self.enabled[kind as usize] = enabled;
+    }
+
+    /// Set the values shown on the next frame: the buttons held (a bit for
+    /// each, in the order of KEYINPUT), the frame's number, and the speed
+    pub fn update(&mut self, buttons: u16, frame: u64, speed_percent: u32) {
+        self.buttons = buttons;
+        self.frame = frame;
+        self.speed_percent = speed_percent;
+    }
+
+    /// Draw the enabled overlays onto the given frame
+    pub fn draw(&self, frame: &mut [[u16; WIDTH]; HEIGHT]) {
+        if self.is_enabled(OverlayKind::FrameCount) {
+            draw_text(frame, 1, 1, &self.frame.to_string());
+        }
+        if self.is_enabled(OverlayKind::Speed) {
+            let text = format!("{}%", self.speed_percent);
+            draw_text(frame, WIDTH - text_width(&text) - 1, 1, &text);
+        }
+        if self.is_enabled(OverlayKind::Input) {
+            let text: String = INPUT_ORDER.iter()
+                .map(|&(name, bit)| if self.buttons & (1 << bit) != 0 { name } else { '.' })
+                .collect();
+            draw_text(frame, 1, HEIGHT - GLYPH_HEIGHT - 1, &text);
+        }
+    }
+}
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+/// Return the given character's pixels, a row of 3 bits at a time starting
+/// from the top row in the highest bits. Characters without a glyph are blank
+fn glyph(c: char) -> u16 {
+    match c {
+        '0' => 0b111_101_101_101_111,
+        '1' => 0b010_110_010_010_111,
+        '2' => 0b111_001_111_100_111,
+        '3' => 0b111_001_111_001_111,
+        '4' => 0b101_101_111_001_001,
+        '5' => 0b111_100_111_001_111,
+        '6' => 0b111_100_111_101_111,
+        '7' => 0b111_001_001_001_001,
+        '8' => 0b111_101_111_101_111,
+        '9' => 0b111_101_111_001_111,
+        '%' => 0b101_001_010_100_101,
+        '.' => 0b000_000_000_000_010,
+        'A' => 0b010_101_111_101_101,
+        'B' => 0b110_101_110_101_110,
+        'D' => 0b110_101_101_101_110,
+        'L' => 0b100_100_100_100_111,
+        'R' => 0b110_101_110_101_101,
+        'S' => 0b011_100_010_001_110,
+        's' => 0b000_011_100_001_110,
+        'U' => 0b101_101_101_101_111,
+        _ => 0,
+    }
+}
+
+/// the width in pixels of the given text, with a column between characters
+fn text_width(text: &str) -> usize {
+    (text.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1)
+}
+
+/// Draw the given text with its top left corner at the given position, on a
+/// background that extends a pixel past it on each side so that it can be
+/// read over anything. Anything off the edge of the frame is left out
+fn draw_text(frame: &mut [[u16; WIDTH]; HEIGHT], x: usize, y: usize, text: &str) {
+    let right = (x + text_width(text) + 1).min(WIDTH);
+    let bottom = (y + GLYPH_HEIGHT + 1).min(HEIGHT);
+    for row in frame[y.saturating_sub(1)..bottom].iter_mut() {
+        for pixel in row[x.saturating_sub(1)..right].iter_mut() {
+            *pixel = BACKGROUND_COLOR;
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        let bits = glyph(c);
+        for row in 0..GLYPH_HEIGHT {
+            for col in 0..GLYPH_WIDTH {
+                let shift = (GLYPH_HEIGHT - 1 - row) * GLYPH_WIDTH + (GLYPH_WIDTH - 1 - col);
+                let (px, py) = (x + i * (GLYPH_WIDTH + 1) + col, y + row);
+                if bits & (1 << shift) != 0 && px < WIDTH && py < HEIGHT {
+                    frame[py][px] = TEXT_COLOR;
+                }
+            }
+        }
+    }
+}
+
+impl CPUWrapper {
+    /// Give the overlays the values for the frame that's about to be shown
+    pub fn update_overlay(&mut self) {
+        let buttons = !self.cpu.mem.raw.get_halfword(KEYINPUT) & 0x3FF;
+        let frame = self.total_cycles / REFRESH as u64;
+        let speed = self.pacing.speed_percent().round() as u32;
+        self.cpu.mem.framebuffer.post.overlay.update(buttons, frame, speed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Return the given row of the frame as a string, with # for the text
+    /// color, . for the background and a space for anything else
+    fn row_text(frame: &[[u16; WIDTH]; HEIGHT], row: usize, cols: ::std::ops::Range<usize>) -> String {
+        frame[row][cols].iter().map(|pixel| match *pixel {
+            TEXT_COLOR => '#',
+            BACKGROUND_COLOR => '.',
+            _ => ' ',
+        }).collect()
+    }
+
+    #[test]
+    fn names() {
+        for kind in OVERLAY_KINDS.iter() {
+            assert_eq!(OverlayKind::from_name(kind.name()), Some(*kind));
+        }
+        assert_eq!(OverlayKind::from_name("fps"), None);
+    }
+
+    #[test]
+    fn draw() {
+        let mut overlay = Overlay::new();
+        let mut frame = [[0x1234; WIDTH]; HEIGHT];
+        overlay.update(0b10_0000_0001, 17, 100);
+        overlay.draw(&mut frame);
+        assert!(frame.iter().all(|row| row.iter().all(|pixel| *pixel == 0x1234)));
+
+        for kind in OVERLAY_KINDS.iter() {
+            overlay.set_enabled(*kind, true);
+        }
+        overlay.draw(&mut frame);
+        // "17" in the top left
+        assert_eq!(row_text(&frame, 0, 0..9), ".........");
+        assert_eq!(row_text(&frame, 1, 0..9), "..#..###.");
+        assert_eq!(row_text(&frame, 5, 0..9), ".###...#.");
+        // "100%" in the top right
+        assert_eq!(row_text(&frame, 1, WIDTH - 17..WIDTH), "..#..###.###.#.#.");
+        // only A and the L shoulder button are held
+        assert_eq!(row_text(&frame, HEIGHT - 2, 0..8), "..#...#.");
+        assert_eq!(row_text(&frame, HEIGHT - 2, 28..40), ".#.#.###..#.");
+    }
+}
@@ -30,6 +30,7 @@ impl Memory {
         let offset = addr - PAL_START;
         let idx = (offset / 2) % 256;
         arr[idx as usize] = high_to_true(high_color);
+        self.raw.dirty.unmark(addr);
     }
 
     pub fn update_pal_hw(&mut self, addr: u32, val: u32) {
@@ -41,10 +42,25 @@ impl Memory {
         self.update_pal_hw(addr, val);
         self.update_pal_hw(addr + 2, val >> 16);
     }
+
+    /// Return the 15 bit color at the given index of the background palette.
+    /// Indices wrap around at 256, so a bad index can't read past the palette
+    pub fn get_bg_color(&self, idx: usize) -> u16 {
+        self.get_pal_color(idx % 256)
+    }
+
+    /// Return the 15 bit color at the given index of the sprite palette
+    pub fn get_sprite_color(&self, idx: usize) -> u16 {
+        self.get_pal_color(256 + idx % 256)
+    }
+
+    fn get_pal_color(&self, idx: usize) -> u16 {
+        self.raw.pal[idx * 2] as u16 | (self.raw.pal[idx * 2 + 1] as u16) << 8
+    }
 }
 
 /// convert 15 bit RGB to 32 bit RGBA
-fn high_to_true(color: u16) -> u32 {
+pub fn high_to_true(color: u16) -> u32 {
     let color = color as u32;
     let red = color & 0x1F;
     let green = (color >> 5) & 0x1F;
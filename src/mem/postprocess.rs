@@ -0,0 +1,330 @@
+//! Optional effects applied to each finished frame before it's shown. The
+//! frame the game draws is left alone, so the effects don't change what the
+//! emulator sees (e.g. for savestates or tests).
+//!
+//! The output can also be upscaled into an RGBA buffer, for frontends that
+//! just want to copy it to a canvas with putImageData.
+//!
+//! The unscaled output is normally left in the GBA's own 15 bit colors, but
+//! it can be converted to RGB565 or RGBA instead, so that WebGL frontends can
+//! upload it as a texture without converting each pixel themselves.
+//!
+//! Any overlays (see overlay) are drawn last, so they aren't blurred by
+//! ghosting.
+
+use mem::framebuffer::{HeapFrame, WIDTH, HEIGHT};
+use mem::overlay::Overlay;
+use mem::palette::high_to_true;
+
+/// the largest supported scale factor
+pub const MAX_SCALE: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleFilter {
+    /// each pixel becomes a solid block
+    Nearest,
+    /// the bottom row of each block is darkened, like a CRT
+    Scanlines,
+    /// the bottom row and right column of each block are darkened, like the
+    /// gaps between the pixels of the GBA's LCD
+    LcdGrid,
+}
+
+/// The pixel format of the unscaled output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// the GBA's 15 bit colors as is: red in bits 0-4, green in 5-9 and blue
+    /// in 10-14
+    Bgr555,
+    /// red in bits 11-15, green in 5-10 and blue in 0-4, for textures of type
+    /// UNSIGNED_SHORT_5_6_5
+    Rgb565,
+    /// 4 bytes per pixel in that order, with opaque alpha
+    Rgba8888,
+}
+
+impl OutputFormat {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OutputFormat::Bgr555 => "bgr555",
+            OutputFormat::Rgb565 => "rgb565",
+            OutputFormat::Rgba8888 => "rgba8888",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "bgr555" => Some(OutputFormat::Bgr555),
+            "rgb565" => Some(OutputFormat::Rgb565),
+            "rgba8888" => Some(OutputFormat::Rgba8888),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            OutputFormat::Bgr555 | OutputFormat::Rgb565 => 2,
+            OutputFormat::Rgba8888 => 4,
+        }
+    }
+
+    /// the number of bytes from the start of one row of the unscaled output
+    /// to the next. Rows are packed
+    pub fn stride(&self) -> usize {
+        WIDTH * self.bytes_per_pixel()
+    }
+}
+
+pub struct PostProcess {
+    /// how much of the previous frame is blended into the current one, from
+    /// 0 (off) to 1. The GBA's LCD is slow to change, so games that flicker
+    /// sprites on alternate frames (to make them look transparent) rely on
+    /// this blurring
+    pub ghosting: f32,
+    /// the last frame drawn by the game, before any effects
+    previous: HeapFrame,
+    /// the last frame after effects, which is what gets shown
+    pub output: HeapFrame,
+    /// the factor to upscale the output by, or 1 to not produce a scaled
+    /// output
+    pub scale: u32,
+    pub filter: ScaleFilter,
+    /// the output scaled up by scale, as RGBA bytes
+    pub scaled: Vec<u8>,
+    pub format: OutputFormat,
+    /// the output converted to format, unless that's Bgr555, in which case
+    /// output is used as is
+    pub converted: Vec<u8>,
+    pub overlay: Overlay,
+}
+
+impl PostProcess {
+    pub const fn new() -> PostProcess {
+        PostProcess {
+            ghosting: 0.0,
+            previous: HeapFrame::new(),
+            output: HeapFrame::new(),
+            scale: 1,
+            filter: ScaleFilter::Nearest,
+            scaled: Vec::new(),
+            format: OutputFormat::Bgr555,
+            converted: Vec::new(),
+            overlay: Overlay::new(),
+        }
+    }
+
+    /// Change the format of the unscaled output. The converted buffer is
+    /// allocated here, so it doesn't move until the format changes again
+    pub fn set_format(&mut self, format: OutputFormat) {
+        self.format = format;
+        self.converted = match format {
+            OutputFormat::Bgr555 => Vec::new(),
+            _ => vec![0; format.stride() * HEIGHT],
+        };
+        self.convert_output();
+    }
+
+    /// Set the scale factor (between 1 and MAX_SCALE) and filter for the
+    /// scaled output
+    pub fn set_scale(&mut self, scale: u32, filter: ScaleFilter) {
+        self.scale = scale.max(1).min(MAX_SCALE);
+        self.filter = filter;
+        if self.scale == 1 {
+            self.scaled = Vec::new();
+        }
+    }
+
+    pub fn set_ghosting(&mut self, weight: f32) {
+        self.ghosting = weight.max(0.0).min(1.0);
+    }
+
+    /// Called with each frame once the game has finished drawing it
+    pub fn finish_frame(&mut self, pixels: &[[u16; WIDTH]; HEIGHT]) {
+        if self.ghosting <= 0.0 {
+            *self.output = *pixels;
+        } else {
+            for row in 0..HEIGHT {
+                for col in 0..WIDTH {
+                    self.output[row][col] =
+                        blend(pixels[row][col], self.previous[row][col], self.ghosting);
+                }
+            }
+        }
+        *self.previous = *pixels;
+        self.overlay.draw(&mut self.output);
+        self.convert_output();
+        if self.scale > 1 {
+            self.scale_output();
+        }
+    }
+
+    /// Show the given frame as is, e.g. a preview of a frame that hasn't
+    /// finished. It doesn't count as the previous frame for ghosting
+    pub fn show_preview(&mut self, pixels: &[[u16; WIDTH]; HEIGHT]) {
+        *self.output = *pixels;
+        self.overlay.draw(&mut self.output);
+        self.convert_output();
+        if self.scale > 1 {
+            self.scale_output();
+        }
+    }
+
+    fn convert_output(&mut self) {
+        if self.format == OutputFormat::Bgr555 {
+            return;
+        }
+        let bytes = self.format.bytes_per_pixel();
+        for (i, color) in self.output.iter().flat_map(|row| row.iter()).enumerate() {
+            let pixel = &mut self.converted[i * bytes..(i + 1) * bytes];
+            match self.format {
+                OutputFormat::Bgr555 => (),
+                OutputFormat::Rgb565 => {
+                    let [lo, hi] = to_rgb565(*color).to_le_bytes();
+                    pixel[0] = lo;
+                    pixel[1] = hi;
+                },
+                OutputFormat::Rgba8888 => pixel.copy_from_slice(&to_rgba(*color)),
+            }
+        }
+    }
+
+    fn scale_output(&mut self) {
+        let scale = self.scale as usize;
+        let width = WIDTH * scale;
+        self.scaled.resize(width * HEIGHT * scale * 4, 0);
+        for row in 0..HEIGHT * scale {
+            for col in 0..width {
+                let color = to_rgba(self.output[row / scale][col / scale]);
+                let last_row = row % scale == scale - 1;
+                let last_col = col % scale == scale - 1;
+                let darken = match self.filter {
+                    ScaleFilter::Nearest => false,
+                    ScaleFilter::Scanlines => last_row,
+                    ScaleFilter::LcdGrid => last_row || last_col,
+                };
+                let idx = (row * width + col) * 4;
+                let pixel = &mut self.scaled[idx..idx + 4];
+                pixel.copy_from_slice(&color);
+                if darken {
+                    for channel in pixel[..3].iter_mut() {
+                        *channel /= 2;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Convert a 15 bit color to RGBA bytes
+fn to_rgba(color: u16) -> [u8; 4] {
+    let color = high_to_true(color);
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8, 0xFF]
+}
+
+/// Convert a 15 bit color to RGB565. Green's extra bit is filled from its top
+/// bit, so that white stays white
+fn to_rgb565(color: u16) -> u16 {
+    let red = color & 0x1F;
+    let green = (color >> 5) & 0x1F;
+    let blue = (color >> 10) & 0x1F;
+    red << 11 | (green << 1 | green >> 4) << 5 | blue
+}
+
+/// Mix two 15 bit colors, where weight is the amount of the second color
+fn blend(a: u16, b: u16, weight: f32) -> u16 {
+    let mut out = 0;
+    for shift in [0, 5, 10].iter() {
+        let a = ((a >> shift) & 0x1F) as f32;
+        let b = ((b >> shift) & 0x1F) as f32;
+        let mixed = (a + (b - a) * weight).round() as u16;
+        out |= mixed.min(0x1F) << shift;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ghosting() {
+        let mut post = PostProcess::new();
+        let mut frame = [[0; WIDTH]; HEIGHT];
+        frame[0][0] = 0x7FFF;
+        post.finish_frame(&frame);
+        assert_eq!(post.output[0][0], 0x7FFF);
+
+        // a sprite flickering on alternate frames shows at half brightness
+        post.set_ghosting(0.5);
+        frame[0][0] = 0;
+        post.finish_frame(&frame);
+        assert_eq!(post.output[0][0], 0x4210);
+        frame[0][0] = 0x7FFF;
+        post.finish_frame(&frame);
+        assert_eq!(post.output[0][0], 0x4210);
+
+        assert_eq!(blend(0x001F, 0x7C00, 0.25), 0x2017);
+    }
+
+    #[test]
+    fn scale() {
+        let mut post = PostProcess::new();
+        let mut frame = [[0; WIDTH]; HEIGHT];
+        frame[0][0] = 0x001F;
+        post.finish_frame(&frame);
+        assert!(post.scaled.is_empty());
+
+        post.set_scale(2, ScaleFilter::Nearest);
+        post.finish_frame(&frame);
+        let width = WIDTH * 2;
+        assert_eq!(post.scaled.len(), width * HEIGHT * 2 * 4);
+        for &(row, col) in [(0, 0), (0, 1), (1, 0), (1, 1)].iter() {
+            let idx = (row * width + col) * 4;
+            assert_eq!(&post.scaled[idx..idx + 4], &[0xF8, 0, 0, 0xFF]);
+        }
+        assert_eq!(&post.scaled[8..12], &[0, 0, 0, 0xFF]);
+
+        post.set_scale(3, ScaleFilter::LcdGrid);
+        post.finish_frame(&frame);
+        let width = WIDTH * 3;
+        assert_eq!(&post.scaled[0..4], &[0xF8, 0, 0, 0xFF]);
+        assert_eq!(&post.scaled[8..12], &[0x7C, 0, 0, 0xFF]);
+        let idx = (2 * width) * 4;
+        assert_eq!(&post.scaled[idx..idx + 4], &[0x7C, 0, 0, 0xFF]);
+
+        post.set_scale(5, ScaleFilter::Scanlines);
+        assert_eq!(post.scale, MAX_SCALE);
+    }
+
+    #[test]
+    fn format() {
+        let mut post = PostProcess::new();
+        let mut frame = [[0; WIDTH]; HEIGHT];
+        // red, green, blue and white
+        frame[0][0..4].copy_from_slice(&[0x001F, 0x03E0, 0x7C00, 0x7FFF]);
+        frame[1][0] = 0x0210;
+        post.finish_frame(&frame);
+        assert!(post.converted.is_empty());
+
+        post.set_format(OutputFormat::Rgb565);
+        assert_eq!(post.converted.len(), WIDTH * HEIGHT * 2);
+        // the frame that's already showing is converted straight away
+        let pixels: Vec<u16> = post.converted.chunks(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+            .collect();
+        assert_eq!(&pixels[0..4], &[0xF800, 0x07E0, 0x001F, 0xFFFF]);
+        assert_eq!(pixels[WIDTH], 0x8420);
+
+        post.set_format(OutputFormat::Rgba8888);
+        assert_eq!(OutputFormat::Rgba8888.stride(), WIDTH * 4);
+        frame[0][0] = 0x7C00;
+        post.finish_frame(&frame);
+        assert_eq!(&post.converted[0..4], &[0, 0, 0xF8, 0xFF]);
+        assert_eq!(&post.converted[12..16], &[0xF8, 0xF8, 0xF8, 0xFF]);
+
+        assert_eq!(OutputFormat::from_name("rgb565"), Some(OutputFormat::Rgb565));
+        assert_eq!(OutputFormat::from_name(OutputFormat::Bgr555.name()),
+                   Some(OutputFormat::Bgr555));
+        assert_eq!(OutputFormat::from_name("rgb888"), None);
+    }
+}
@@ -0,0 +1,488 @@
+//! A second renderer, for differential testing of the one in framebuffer.
+//! Each pixel is worked out on its own, straight from raw memory: the
+//! registers and OAM are decoded again for every pixel, with integer math,
+//! and nothing is evaluated per line or carried over between pixels. It's
+//! far too slow to use for playing, but short enough to check against
+//! GBATEK by reading, so a difference between the two points at the caching
+//! and fast paths of the real renderer (the sprites and backgrounds picked
+//! for each line, the parsed registers and OAM, forced blank).
+//!
+//! It draws the same things the real renderer does: mosaic isn't drawn by
+//! either, so it isn't compared.
+//!
+//! While the check is enabled, every line drawn is also drawn by the
+//! reference renderer, and the pixels that differ are kept for the frontend
+//! or a test to take.
+
+use mem::Memory;
+use mem::addrs::{OAM_START, VRAM_START};
+use mem::framebuffer::{WIDTH, HEIGHT, OBJ_TILE_BASE, BITMAP_OBJ_MIN_TILE};
+
+/// the most differences kept between calls to take_diffs, so that a frame
+/// that's wrong everywhere doesn't take up much memory
+pub const MAX_DIFFS: usize = 256;
+
+const DISPCNT: u32 = 0x4000000;
+const BGCNT: u32 = 0x4000008;
+const BGHOFS: u32 = 0x4000010;
+const BG2PA: u32 = 0x4000020;
+const WIN0H: u32 = 0x4000040;
+const WIN0V: u32 = 0x4000044;
+const WININ: u32 = 0x4000048;
+const WINOUT: u32 = 0x400004A;
+const BLDCNT: u32 = 0x4000050;
+const BLDALPHA: u32 = 0x4000052;
+const BLDY: u32 = 0x4000054;
+/// the position of the backdrop in reference_layer's drawing order
+const BACKDROP: u32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelDiff {
+    pub row: u32,
+    pub col: u32,
+    /// the color drawn by the real renderer
+    pub drawn: u16,
+    /// the color drawn by the reference renderer
+    pub expected: u16,
+}
+
+pub struct RenderCheck {
+    pub enabled: bool,
+    /// lines compared since the last call to take_diffs
+    pub lines_checked: u64,
+    /// how many pixels differed, including ones that weren't kept
+    pub pixels_differing: u64,
+    diffs: Vec<PixelDiff>,
+}
+
+impl RenderCheck {
+    pub const fn new() -> RenderCheck {
+        RenderCheck {
+            enabled: false,
+            lines_checked: 0,
+            pixels_differing: 0,
+            diffs: Vec::new(),
+        }
+    }
+
+    /// Remove and return the differences found so far, and reset the counts
+    pub fn take_diffs(&mut self) -> Vec<PixelDiff> {
+        self.lines_checked = 0;
+        self.pixels_differing = 0;
+        std::mem::take(&mut self.diffs)
+    }
+}
+
+impl Memory {
+    /// Compare the given line of the framebuffer with what the reference
+    /// renderer draws for it now
+    pub fn check_line(&mut self, row: u32) {
+        for col in 0..WIDTH as u32 {
+            let drawn = self.framebuffer.pixels[row as usize][col as usize];
+            let expected = self.reference_pixel(row, col);
+            if drawn != expected {
+                let check = &mut self.framebuffer.check;
+                check.pixels_differing += 1;
+                if check.diffs.len() < MAX_DIFFS {
+                    check.diffs.push(PixelDiff { row, col, drawn, expected });
+                }
+            }
+        }
+        self.framebuffer.check.lines_checked += 1;
+    }
+
+    /// Return the color of the given pixel, worked out from scratch
+    pub fn reference_pixel(&self, row: u32, col: u32) -> u16 {
+        let dispcnt = self.raw.get_halfword(DISPCNT) as u32;
+        if dispcnt & 0x80 != 0 {
+            return 0x7FFF;
+        }
+        let window = self.reference_window(dispcnt, row, col);
+        let (pos, bit, color, semi_transparent) =
+            self.reference_layer(dispcnt, window, row, col, 0, true);
+        let bldcnt = self.raw.get_halfword(BLDCNT) as u32;
+        let first_target = bldcnt & (1 << bit) != 0;
+        if window & 0x20 == 0 || !(first_target || semi_transparent) {
+            return color;
+        }
+        // nothing is below the backdrop, and the layer below a sprite is
+        // never another sprite
+        let (below, second_target) = if pos == BACKDROP {
+            (0, false)
+        } else {
+            let (_, bit, below, _) =
+                self.reference_layer(dispcnt, window, row, col, pos + 1, bit != 4);
+            (below, bldcnt & (0x100 << bit) != 0)
+        };
+
+        let coeff = |addr: u32, shift: u32| {
+            ((self.raw.get_halfword(addr) as u32 >> shift) & 0x1F).min(16)
+        };
+        let channels = |f: &dyn Fn(u32, u32) -> u32| -> u16 {
+            (0..3).map(|i| {
+                let (a, b) = ((color as u32 >> (i * 5)) & 0x1F, (below as u32 >> (i * 5)) & 0x1F);
+                (f(a, b).min(31) << (i * 5)) as u16
+            }).sum()
+        };
+        let alpha = |a, b| (a * coeff(BLDALPHA, 0) + b * coeff(BLDALPHA, 8)) >> 4;
+        if semi_transparent && second_target {
+            return channels(&alpha);
+        }
+        if !first_target {
+            return color;
+        }
+        match bldcnt >> 6 & 3 {
+            1 if second_target => channels(&alpha),
+            2 => channels(&|a, _| a + (((31 - a) * coeff(BLDY, 0)) >> 4)),
+            3 => channels(&|a, _| a - ((a * coeff(BLDY, 0)) >> 4)),
+            _ => color,
+        }
+    }
+
+    /// Find the first visible layer at the given pixel, starting from the
+    /// given position in the order they're drawn in: for each priority the
+    /// sprites and then the backgrounds, and the backdrop last. Return its
+    /// position, its bit in BLDCNT, its color, and whether it's a semi
+    /// transparent sprite
+    fn reference_layer(&self, dispcnt: u32, window: u32, row: u32, col: u32, from: u32,
+            sprites: bool) -> (u32, u32, u16, bool) {
+        for pos in from..BACKDROP {
+            let (priority, slot) = (pos / 5, pos % 5);
+            if slot == 0 {
+                if !sprites || dispcnt & 0x1000 == 0 || window & 0x10 == 0 {
+                    continue;
+                }
+                // lower OAM indices are in front
+                for i in 0..128 {
+                    let sprite = self.reference_sprite(dispcnt, i, priority, row, col);
+                    if let Some((color, semi)) = sprite {
+                        return (pos, 4, color, semi);
+                    }
+                }
+            } else {
+                // and so are lower numbered backgrounds
+                let bg = slot - 1;
+                let bgcnt = self.raw.get_halfword(BGCNT + bg * 2) as u32;
+                if dispcnt & (0x100 << bg) == 0 || window & (1 << bg) == 0 ||
+                        bgcnt & 3 != priority {
+                    continue;
+                }
+                if let Some(color) = self.reference_bg(dispcnt, bg, bgcnt, row, col) {
+                    return (pos, bg, color, false);
+                }
+            }
+        }
+        (BACKDROP, 5, self.get_bg_color(0), false)
+    }
+
+    /// Return the WININ or WINOUT byte for the window the given pixel is in,
+    /// or all layers if no window is enabled
+    fn reference_window(&self, dispcnt: u32, row: u32, col: u32) -> u32 {
+        if dispcnt & 0xE000 == 0 {
+            return 0x3F;
+        }
+        for win in 0..2 {
+            if dispcnt & (0x2000 << win) == 0 {
+                continue;
+            }
+            // a right or bottom edge past the screen or before the other edge
+            // is treated as the edge of the screen
+            let h = self.raw.get_halfword(WIN0H + win * 2) as u32;
+            let (left, right) = (h >> 8, (h & 0xFF).min(WIDTH as u32));
+            let right = if left > right { WIDTH as u32 } else { right };
+            let v = self.raw.get_halfword(WIN0V + win * 2) as u32;
+            let (top, bottom) = (v >> 8, (v & 0xFF).min(HEIGHT as u32));
+            let bottom = if top > bottom { HEIGHT as u32 } else { bottom };
+            if col >= left && col < right && row >= top && row < bottom {
+                return self.raw.get_byte(WININ + win) as u32;
+            }
+        }
+        let in_obj_window = dispcnt & 0x9000 == 0x9000 && (0..128)
+            .any(|i| self.reference_sprite_texel(dispcnt, i, row, col, true).is_some());
+        self.raw.get_byte(WINOUT + in_obj_window as u32) as u32
+    }
+
+    fn reference_bg(&self, dispcnt: u32, bg: u32, bgcnt: u32, row: u32, col: u32)
+            -> Option<u16> {
+        match (dispcnt & 7, bg) {
+            (0, _) | (1, 0) | (1, 1) => self.reference_text_bg(bg, bgcnt, row, col),
+            (1, 2) | (2, 2) | (2, 3) => self.reference_affine_bg(bg, bgcnt, row, col),
+            (3, 2) => {
+                let addr = VRAM_START + (row * WIDTH as u32 + col) * 2;
+                Some(self.raw.get_halfword(addr))
+            },
+            (4, 2) => {
+                let frame = if dispcnt & 0x10 != 0 { 0xA000 } else { 0 };
+                match self.raw.get_byte(VRAM_START + frame + row * WIDTH as u32 + col) {
+                    0 => None,
+                    idx => Some(self.get_bg_color(idx as usize)),
+                }
+            },
+            (5, 2) if col < 160 && row < 128 => {
+                let frame = if dispcnt & 0x10 != 0 { 0xA000 } else { 0 };
+                Some(self.raw.get_halfword(VRAM_START + frame + (row * 160 + col) * 2))
+            },
+            _ => None,
+        }
+    }
+
+    fn reference_text_bg(&self, bg: u32, bgcnt: u32, row: u32, col: u32) -> Option<u16> {
+        let (width, height) = match bgcnt >> 14 {
+            0 => (256, 256),
+            1 => (512, 256),
+            2 => (256, 512),
+            _ => (512, 512),
+        };
+        let x = (col + (self.raw.get_halfword(BGHOFS + bg * 4) as u32 & 0x1FF)) % width;
+        let y = (row + (self.raw.get_halfword(BGHOFS + bg * 4 + 2) as u32 & 0x1FF)) % height;
+
+        // 32x32 tile screenblocks, going across and then down
+        let screenblock = x / 256 + (y / 256) * (width / 256);
+        let map = VRAM_START + ((bgcnt >> 8) & 0x1F) * 0x800 + screenblock * 0x800;
+        let entry = self.reference_vram_halfword(map + ((y / 8 % 32) * 32 + x / 8 % 32) * 2);
+        let tile = entry & 0x3FF;
+        let px = if entry & 0x400 != 0 { 7 - x % 8 } else { x % 8 };
+        let py = if entry & 0x800 != 0 { 7 - y % 8 } else { y % 8 };
+        let tiles = VRAM_START + ((bgcnt >> 2) & 3) * 0x4000;
+        if bgcnt & 0x80 != 0 {
+            match self.reference_vram_byte(tiles + tile * 64 + py * 8 + px) {
+                0 => None,
+                idx => Some(self.get_bg_color(idx as usize)),
+            }
+        } else {
+            let byte = self.reference_vram_byte(tiles + tile * 32 + py * 4 + px / 2);
+            match (byte >> (4 * (px % 2))) & 0xF {
+                0 => None,
+                idx => Some(self.get_bg_color((entry >> 12) as usize * 16 + idx as usize)),
+            }
+        }
+    }
+
+    fn reference_affine_bg(&self, bg: u32, bgcnt: u32, row: u32, col: u32) -> Option<u16> {
+        let regs = BG2PA + (bg - 2) * 0x10;
+        let param = |offset| self.raw.get_halfword(regs + offset) as i16 as i32;
+        // the reference point is a signed 28 bit value
+        let point = |offset| ((self.raw.get_word(regs + offset) << 4) as i32) >> 4;
+        let (col, row) = (col as i32, row as i32);
+        // all 8 bits of fraction, so shifting rounds down
+        let mut x = (point(8) + param(0) * col + param(2) * row) >> 8;
+        let mut y = (point(12) + param(4) * col + param(6) * row) >> 8;
+        let size = 128 << (bgcnt >> 14);
+        if x < 0 || y < 0 || x >= size || y >= size {
+            if bgcnt & 0x2000 == 0 {
+                return None;
+            }
+            x = x.rem_euclid(size);
+            y = y.rem_euclid(size);
+        }
+        let (x, y, size) = (x as u32, y as u32, size as u32);
+        let map = VRAM_START + ((bgcnt >> 8) & 0x1F) * 0x800;
+        let tile = self.reference_vram_byte(map + (y / 8) * (size / 8) + x / 8) as u32;
+        let tiles = VRAM_START + ((bgcnt >> 2) & 3) * 0x4000;
+        match self.reference_vram_byte(tiles + tile * 64 + (y % 8) * 8 + x % 8) {
+            0 => None,
+            idx => Some(self.get_bg_color(idx as usize)),
+        }
+    }
+
+    /// Return the sprite's color at the given pixel, and whether it's semi
+    /// transparent
+    fn reference_sprite(&self, dispcnt: u32, i: u32, priority: u32, row: u32, col: u32)
+            -> Option<(u16, bool)> {
+        let attr0 = self.raw.get_halfword(OAM_START + i * 8) as u32;
+        let attr2 = self.raw.get_halfword(OAM_START + i * 8 + 4) as u32;
+        if (attr2 >> 10) & 3 != priority {
+            return None;
+        }
+        self.reference_sprite_texel(dispcnt, i, row, col, false)
+            .map(|idx| (self.get_sprite_color(idx as usize), (attr0 >> 10) & 3 == 1))
+    }
+
+    /// Return the palette index of the sprite's pixel, if it's opaque. Only
+    /// sprites that are part of the OBJ window are looked at if obj_window is
+    /// set, and only the others if it isn't
+    fn reference_sprite_texel(&self, dispcnt: u32, i: u32, row: u32, col: u32,
+            obj_window: bool) -> Option<u32> {
+        let attr = |n: u32| self.raw.get_halfword(OAM_START + i * 8 + n * 2) as u32;
+        let (attr0, attr1, attr2) = (attr(0), attr(1), attr(2));
+        let affine = attr0 & 0x100 != 0;
+        if (!affine && attr0 & 0x200 != 0) || ((attr0 >> 10) & 3 == 2) != obj_window {
+            return None;
+        }
+        let (width, height) = match (attr0 >> 14, attr1 >> 14) {
+            (0, size) => (8 << size, 8 << size),
+            (1, 0) => (16, 8),
+            (1, 1) => (32, 8),
+            (1, 2) => (32, 16),
+            (1, _) => (64, 32),
+            (2, 0) => (8, 16),
+            (2, 1) => (8, 32),
+            (2, 2) => (16, 32),
+            (2, _) => (32, 64),
+            _ => return None,
+        };
+        let scale = if affine && attr0 & 0x200 != 0 { 2 } else { 1 };
+        let (bound_width, bound_height) = (width * scale, height * scale);
+        // x is a signed 9 bit value, and y wraps around at 256
+        let dx = col.wrapping_sub(attr1 & 0x1FF) & 0x1FF;
+        let dy = row.wrapping_sub(attr0 & 0xFF) & 0xFF;
+        if dx >= bound_width || dy >= bound_height {
+            return None;
+        }
+
+        let (x, y) = if affine {
+            let group = OAM_START + ((attr1 >> 9) & 0x1F) * 32;
+            let param = |n: u32| self.raw.get_halfword(group + 6 + n * 8) as i16 as i32;
+            let cx = dx as i32 - (bound_width / 2) as i32;
+            let cy = dy as i32 - (bound_height / 2) as i32;
+            let x = ((param(0) * cx + param(1) * cy) >> 8) + (width / 2) as i32;
+            let y = ((param(2) * cx + param(3) * cy) >> 8) + (height / 2) as i32;
+            if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                return None;
+            }
+            (x as u32, y as u32)
+        } else {
+            let x = if attr1 & 0x1000 != 0 { width - 1 - dx } else { dx };
+            let y = if attr1 & 0x2000 != 0 { height - 1 - dy } else { dy };
+            (x, y)
+        };
+
+        let depth8 = attr0 & 0x2000 != 0;
+        // tile numbers count 32 bytes, so 8 bit tiles take up two
+        let units = if depth8 { 2 } else { 1 };
+        let stride = if dispcnt & 0x40 != 0 { width / 8 * units } else { 32 };
+        let tile = ((attr2 & 0x3FF) + (y / 8) * stride + (x / 8) * units) % 1024;
+        if dispcnt & 7 >= 3 && tile < BITMAP_OBJ_MIN_TILE {
+            return None;
+        }
+        let tile_addr = VRAM_START + OBJ_TILE_BASE as u32 + tile * 32;
+        let idx = if depth8 {
+            self.reference_vram_byte(tile_addr + (y % 8) * 8 + x % 8) as u32
+        } else {
+            let byte = self.reference_vram_byte(tile_addr + (y % 8) * 4 + (x % 8) / 2);
+            match (byte >> (4 * (x % 2))) & 0xF {
+                0 => 0,
+                nibble => (attr2 >> 12) * 16 + nibble as u32,
+            }
+        };
+        if idx % 256 == 0 { None } else { Some(idx) }
+    }
+
+    /// Reads past the end of VRAM are transparent
+    fn reference_vram_byte(&self, addr: u32) -> u8 {
+        self.raw.vram.get((addr - VRAM_START) as usize).cloned().unwrap_or(0)
+    }
+
+    fn reference_vram_halfword(&self, addr: u32) -> u32 {
+        self.reference_vram_byte(addr) as u32 | (self.reference_vram_byte(addr + 1) as u32) << 8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Fill VRAM, OAM and the palette with a repeating pattern, so that every
+    /// layer has something to draw
+    fn scramble(mem: &mut Memory) {
+        let mut seed = 0x12345678u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for addr in (0x5000000..0x5000400).step_by(2) {
+            let color = next() & 0x7FFF;
+            mem.set_halfword(addr, color);
+        }
+        for addr in (0x6000000..0x6018000).step_by(4) {
+            let word = next();
+            mem.set_word(addr, word);
+        }
+        for sprite in 0..128 {
+            let addr = 0x7000000 + sprite * 8;
+            // keep clear of the prohibited shape
+            let attr0 = next() & !0xC000 | (sprite % 3) << 14;
+            mem.set_halfword(addr, attr0);
+            let attr1 = next();
+            mem.set_halfword(addr + 2, attr1);
+            let attr2 = next();
+            mem.set_halfword(addr + 4, attr2);
+            let param = next() & 0x1FF;
+            mem.set_halfword(addr + 6, param);
+        }
+        for bg in 0..4 {
+            let bgcnt = next() & !0x40;
+            mem.set_halfword(0x4000008 + bg * 2, bgcnt);
+            let hofs = next();
+            mem.set_word(0x4000010 + bg * 4, hofs);
+        }
+    }
+
+    #[test]
+    fn matches_renderer() {
+        let mut mem = Memory::new();
+        scramble(&mut mem);
+        // small affine steps, and reference points near the origin, which
+        // both renderers can represent exactly
+        for &bg in [0x4000020u32, 0x4000030].iter() {
+            mem.set_halfword(bg, 0x0110);
+            mem.set_halfword(bg + 2, 0xFFC0);
+            mem.set_halfword(bg + 4, 0x0040);
+            mem.set_halfword(bg + 6, 0x00F0);
+            mem.set_word(bg + 8, 0x1280);
+            mem.set_word(bg + 12, 0xFFFFF000);
+        }
+        // overlapping windows, each showing a different set of layers
+        mem.set_halfword(0x4000040, 20 << 8 | 150);
+        mem.set_halfword(0x4000044, 10 << 8 | 90);
+        mem.set_halfword(0x4000042, 100 << 8 | 230);
+        mem.set_halfword(0x4000046, 60 << 8 | 200);
+        mem.set_halfword(0x4000048, 0x1D_35);
+        mem.set_halfword(0x400004A, 0x1A_17);
+        mem.set_halfword(0x4000052, 0x0A07);
+        mem.set_halfword(0x4000054, 0x0009);
+        mem.framebuffer.check.enabled = true;
+        for mode in 0..6 {
+            // each of the effects, with most layers as first and second
+            // targets, including some as both
+            mem.set_halfword(0x4000050, 0x2E00 | (mode % 4) << 6 | 0x35);
+            // everything enabled, in both sprite mappings, with and without
+            // the windows
+            for &windows in [0, 0xE000].iter() {
+                let dispcnt = 0x1F00 | windows | mode | (mode % 2) << 6 | (mode / 4) << 4;
+                mem.set_halfword(0x4000000, dispcnt);
+                for row in 0..HEIGHT as u32 {
+                    mem.render_scanline(row);
+                }
+                assert!(mem.framebuffer.check.lines_checked > 0);
+                assert_eq!(mem.framebuffer.check.take_diffs(), vec![], "dispcnt {:x}", dispcnt);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_diffs() {
+        let mut mem = Memory::new();
+        scramble(&mut mem);
+        mem.set_halfword(0x4000000, 0x1F00);
+        mem.framebuffer.check.enabled = true;
+        mem.render_scanline(10);
+        assert!(mem.framebuffer.check.take_diffs().is_empty());
+
+        // as if the renderer had missed a write
+        mem.render_scanline(11);
+        mem.framebuffer.pixels[11][3] ^= 1;
+        let expected = mem.framebuffer.pixels[11][3] ^ 1;
+        mem.check_line(11);
+        assert_eq!(mem.framebuffer.check.pixels_differing, 1);
+        assert_eq!(mem.framebuffer.check.take_diffs(), vec![PixelDiff {
+            row: 11,
+            col: 3,
+            drawn: expected ^ 1,
+            expected,
+        }]);
+        assert_eq!(mem.framebuffer.check.lines_checked, 0);
+    }
+}
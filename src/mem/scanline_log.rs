@@ -0,0 +1,108 @@
+//! Optionally records the value of the registers most commonly changed mid
+//! frame (usually from an HBlank interrupt or DMA) for each line that gets
+//! drawn. This makes it possible to debug raster effects like wobbling
+//! backgrounds or gradient fades without having to instrument the ROM itself.
+
+use std::fmt::Write;
+use mem::Memory;
+use mem::framebuffer::HEIGHT;
+use mem::io::addrs::{DISPCNT_LO, BLDY};
+
+/// The registers that were used to draw a single line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScanlineRegs {
+    pub disp_cnt: u16,
+    pub bg_offset_x: [u16; 4],
+    pub bg_offset_y: [u16; 4],
+    /// reference points for BG2 and BG3
+    pub bg_ref_x: [f32; 2],
+    pub bg_ref_y: [f32; 2],
+    pub bldy: u8,
+}
+
+pub struct ScanlineLog {
+    pub enabled: bool,
+    /// one entry per line of the last frame. this is only allocated once the
+    /// log is enabled
+    pub lines: Vec<ScanlineRegs>,
+}
+
+impl ScanlineLog {
+    pub const fn new() -> ScanlineLog {
+        ScanlineLog {
+            enabled: false,
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if enabled && self.lines.is_empty() {
+            self.lines = vec![ScanlineRegs::default(); HEIGHT];
+        }
+    }
+
+    /// Return the log as a table with one line per scanline
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        for (row, regs) in self.lines.iter().enumerate() {
+            let _ = write!(out, "{:3}: DISPCNT={:04X}", row, regs.disp_cnt);
+            for bg in 0..4 {
+                let _ = write!(out, " BG{}=({},{})",
+                    bg, regs.bg_offset_x[bg], regs.bg_offset_y[bg]);
+            }
+            for bg in 0..2 {
+                let _ = write!(out, " BG{}REF=({},{})",
+                    bg + 2, regs.bg_ref_x[bg], regs.bg_ref_y[bg]);
+            }
+            let _ = writeln!(out, " BLDY={}", regs.bldy);
+        }
+        out
+    }
+}
+
+impl Memory {
+    /// Record the registers used to draw the given line, if logging is enabled
+    pub fn capture_scanline(&mut self, row: u32) {
+        if !self.scanline_log.enabled {
+            return;
+        }
+        let graphics = &self.graphics;
+        let regs = ScanlineRegs {
+            disp_cnt: self.get_halfword(DISPCNT_LO),
+            bg_offset_x: graphics.bg_offset_x,
+            bg_offset_y: graphics.bg_offset_y,
+            bg_ref_x: [graphics.bg_affine[0].ref_x, graphics.bg_affine[1].ref_x],
+            bg_ref_y: [graphics.bg_affine[0].ref_y, graphics.bg_affine[1].ref_y],
+            bldy: self.get_byte(BLDY) & 0x1F,
+        };
+        if let Some(line) = self.scanline_log.lines.get_mut(row as usize) {
+            *line = regs;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture() {
+        let mut mem = Memory::new();
+        mem.capture_scanline(0);
+        assert!(mem.scanline_log.lines.is_empty());
+
+        mem.scanline_log.set_enabled(true);
+        for row in 0..3 {
+            mem.set_halfword(0x4000010, row * 2);
+            mem.set_byte(0x4000054, row as u8);
+            mem.render_scanline(row);
+        }
+        let lines = &mem.scanline_log.lines;
+        assert_eq!(lines.len(), HEIGHT);
+        assert_eq!(lines[1].bg_offset_x[0], 2);
+        assert_eq!(lines[2].bg_offset_x[0], 4);
+        assert_eq!(lines[2].bldy, 2);
+        assert!(mem.scanline_log.format().contains("  2: DISPCNT=0000 BG0=(4,0)"));
+    }
+}
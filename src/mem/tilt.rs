@@ -0,0 +1,179 @@
+//! The tilt sensor in Yoshi Topsy-Turvy and Koro Koro Puzzle, which sits in
+//! the backup region in place of SRAM (they save to EEPROM). Writing 0x55 to
+//! 0x0E008000 and then 0xAA to 0x0E008100 starts a sample, and bit 7 of
+//! 0x0E008300 is set once it's ready. The sample is two 12 bit readings, one
+//! for each axis, read a byte at a time:
+//!
+//!   0x0E008200: X bits 0-7
+//!   0x0E008300: X bits 8-11, and the ready flag in bit 7
+//!   0x0E008400: Y bits 0-7
+//!   0x0E008500: Y bits 8-11
+//!
+//! The real sensor takes a while to sample, but games just poll the ready
+//! flag, so here a sample is ready as soon as it's started. The tilt comes
+//! from the frontend (e.g. from the browser's deviceorientation events).
+
+use mem::RawMemory;
+
+const START: u32 = 0x8000;
+const SAMPLE: u32 = 0x8100;
+const X_LO: u32 = 0x8200;
+const X_HI: u32 = 0x8300;
+const Y_LO: u32 = 0x8400;
+const Y_HI: u32 = 0x8500;
+
+/// the readings when the GBA is held flat
+const X_CENTER: f32 = 0x392 as f32;
+const Y_CENTER: f32 = 0x3A0 as f32;
+/// how far the readings go either side of the center when it's tilted as
+/// far as the games expect
+const RANGE: f32 = 0xE0 as f32;
+
+/// Return true if the game with the given code has a tilt sensor. Only the
+/// first 3 characters are checked, since the last is the region
+pub fn has_tilt_sensor(game_code: &str) -> bool {
+    game_code.starts_with("KYG") || game_code.starts_with("KHP")
+}
+
+pub struct TiltSensor {
+    present: bool,
+    /// the current tilt on each axis, from -1.0 to 1.0
+    x: f32,
+    y: f32,
+    /// the readings taken by the last sample
+    sample: (u16, u16),
+    /// set by the first write of the start sequence
+    started: bool,
+    ready: bool,
+}
+
+impl TiltSensor {
+    pub const fn new() -> TiltSensor {
+        TiltSensor {
+            present: false,
+            x: 0.0,
+            y: 0.0,
+            sample: (0, 0),
+            started: false,
+            ready: false,
+        }
+    }
+
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    pub fn set_present(&mut self, present: bool) {
+        self.present = present;
+        self.reset();
+    }
+
+    /// Set how far the GBA is tilted on each axis, from -1.0 to 1.0. The
+    /// lowest readings are at -1.0 and the highest at 1.0, and values outside
+    /// that are clamped, with NaN counting as -1.0. The games only see it at
+    /// the next sample
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        let clamp = |val: f32| if val.is_nan() { -1.0 } else { val.clamp(-1.0, 1.0) };
+        self.x = clamp(x);
+        self.y = clamp(y);
+    }
+
+    /// Forget any sample in progress, as when the GBA is turned on
+    pub fn reset(&mut self) {
+        self.sample = (0, 0);
+        self.started = false;
+        self.ready = false;
+    }
+
+    pub fn write(&mut self, offset: u32, val: u8) {
+        match (offset, val) {
+            (START, 0x55) => {
+                self.started = true;
+                self.ready = false;
+            },
+            (SAMPLE, 0xAA) if self.started => {
+                self.sample = (
+                    (X_CENTER + self.x * RANGE) as u16,
+                    (Y_CENTER + self.y * RANGE) as u16,
+                );
+                self.started = false;
+                self.ready = true;
+            },
+            _ => (),
+        }
+    }
+
+    /// Read the given offset into the backup region. Anything other than the
+    /// sensor's registers isn't driven
+    pub fn read(&self, offset: u32) -> u8 {
+        let (x, y) = self.sample;
+        match offset {
+            X_LO => x as u8,
+            X_HI => (x >> 8) as u8 & 0xF | (self.ready as u8) << 7,
+            Y_LO => y as u8,
+            Y_HI => (y >> 8) as u8 & 0xF,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl RawMemory {
+    /// Give the cart a tilt sensor if the loaded game has one, removing any
+    /// it had before. The current tilt is kept
+    pub fn detect_tilt(&mut self, game_code: Option<&str>) {
+        self.tilt.set_present(game_code.is_some_and(has_tilt_sensor));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mem::Memory;
+
+    fn tilt_cart() -> Memory {
+        let mut mem = Memory::new();
+        let mut rom = vec![0; 0x200];
+        rom[0xAC..0xB0].copy_from_slice(b"KYGE");
+        mem.load_rom(rom);
+        mem
+    }
+
+    fn sample(mem: &mut Memory) -> (u16, u16) {
+        mem.set_byte(0xE008000, 0x55);
+        mem.set_byte(0xE008100, 0xAA);
+        assert_eq!(mem.get_byte(0xE008300) & 0x80, 0x80);
+        let x = mem.get_byte(0xE008200) as u16 | (mem.get_byte(0xE008300) as u16 & 0xF) << 8;
+        let y = mem.get_byte(0xE008400) as u16 | (mem.get_byte(0xE008500) as u16) << 8;
+        (x, y)
+    }
+
+    #[test]
+    fn detect() {
+        let mut mem = tilt_cart();
+        assert!(mem.raw.tilt.present());
+        mem.load_rom(vec![0; 0x200]);
+        assert!(!mem.raw.tilt.present());
+        // without a sensor the region isn't driven
+        mem.set_byte(0xE008000, 0x55);
+        mem.set_byte(0xE008100, 0xAA);
+        assert_eq!(mem.get_byte(0xE008300), 0xFF);
+    }
+
+    #[test]
+    fn handshake() {
+        let mut mem = tilt_cart();
+        assert_eq!(mem.get_byte(0xE008300) & 0x80, 0);
+        assert_eq!(sample(&mut mem), (0x392, 0x3A0));
+
+        mem.raw.tilt.set_tilt(1.0, -2.0);
+        // the readings don't change until the next sample
+        assert_eq!(mem.get_byte(0xE008200), 0x92);
+        assert_eq!(sample(&mut mem), (0x472, 0x2C0));
+
+        // starting a sample clears the ready flag, and the second write has
+        // to follow the first
+        mem.set_byte(0xE008000, 0x55);
+        assert_eq!(mem.get_byte(0xE008300) & 0x80, 0);
+        mem.set_byte(0xE008100, 0x12);
+        assert_eq!(mem.get_byte(0xE008300) & 0x80, 0);
+    }
+}
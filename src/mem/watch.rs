@@ -0,0 +1,186 @@
+//! Watchpoints stop emulation when a watched range of memory is read or
+//! written, for tracking down what overwrites a variable. The CPU isn't the
+//! only thing that touches memory: DMA copies behind its back, and a
+//! watchpoint hit by a transfer that the game started long before would
+//! otherwise point at whatever instruction the CPU happened to be on. So
+//! every access through Memory says where it came from, and a hit by DMA
+//! names the channel along with the source and dest it was programmed with.
+//! Instruction fetches don't count as reads, and neither do a frame script's
+//! accesses, which happen between frames rather than on behalf of the game.
+
+use std::cell::Cell;
+use std::fmt;
+use error::AccessKind;
+use mem::canonicalize_addr;
+
+/// What made a memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// the CPU, including the BIOS calls that are emulated
+    Cpu,
+    /// a DMA channel, with the src and dest from its registers rather than
+    /// how far the transfer has got
+    Dma { channel: usize, src: u32, dest: u32 },
+    /// the frame script, whose accesses are never hits
+    Script,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Cpu => write!(f, "the CPU"),
+            Source::Dma { channel, src, dest } =>
+                write!(f, "DMA channel {} ({:#010X} to {:#010X})", channel, src, dest),
+            Source::Script => write!(f, "the frame script"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// a canonical address (see canonicalize_addr)
+    pub start: u32,
+    /// in bytes
+    pub len: u32,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    /// Return true if an access of size bytes at addr touches the range
+    fn overlaps(&self, addr: u32, size: u32) -> bool {
+        let (addr, start) = (addr as u64, self.start as u64);
+        addr < start + self.len as u64 && start < addr + size as u64
+    }
+}
+
+/// An access that touched a watched range
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// the start of the access, which can be before the watched range for a
+    /// halfword or word
+    pub addr: u32,
+    /// in bytes
+    pub size: u32,
+    pub kind: AccessKind,
+    pub source: Source,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.kind == AccessKind::Write { "write" } else { "read" };
+        write!(f, "{} byte {} at {:#010X} by {}", self.size, kind, self.addr, self.source)
+    }
+}
+
+/// Reads are made through &Memory, so the hit is kept in a cell
+pub struct Watchpoints {
+    points: Vec<Watchpoint>,
+    /// the first hit since the last call to take_hit
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub const fn new() -> Watchpoints {
+        Watchpoints {
+            points: Vec::new(),
+            hit: Cell::new(None),
+        }
+    }
+
+    /// Watch len bytes from start, which may be any mirror of the address
+    pub fn add(&mut self, start: u32, len: u32, read: bool, write: bool) {
+        self.points.push(Watchpoint { start: canonicalize_addr(start), len, read, write });
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.hit.set(None);
+    }
+
+    pub fn points(&self) -> &[Watchpoint] {
+        &self.points
+    }
+
+    /// Record the access if it touches a watched range. The address must be
+    /// canonical
+    pub fn check(&self, addr: u32, size: u32, kind: AccessKind, source: Source) {
+        if self.points.is_empty() || self.hit.get().is_some() || source == Source::Script {
+            return;
+        }
+        let watched = self.points.iter().any(|point| {
+            let kind_watched = if kind == AccessKind::Write { point.write } else { point.read };
+            kind_watched && point.overlaps(addr, size)
+        });
+        if watched {
+            self.hit.set(Some(WatchHit { addr, size, kind, source }));
+        }
+    }
+
+    /// Return true if a watchpoint has been hit and not taken yet
+    pub fn is_hit(&self) -> bool {
+        self.hit.get().is_some()
+    }
+
+    /// Remove and return the first hit since the last call, if any
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::CPU;
+    use cpu::bios::CPU_FAST_SET;
+    use mem::Memory;
+
+    #[test]
+    fn cpu_accesses() {
+        let mut mem = Memory::new();
+        // a mirror of 0x3000012
+        mem.watch.add(0x3008012, 2, false, true);
+        mem.set_byte(0x3000014, 1);
+        assert_eq!(mem.watch.take_hit(), None);
+        mem.get_word(0x3000010);
+        assert_eq!(mem.watch.take_hit(), None);
+        // a word write that covers the range, and only the first hit is kept
+        mem.set_word(0x3000010, 5);
+        mem.set_byte(0x3000013, 1);
+        assert_eq!(mem.watch.take_hit().unwrap().to_string(),
+            "4 byte write at 0x03000010 by the CPU");
+        assert_eq!(mem.watch.take_hit(), None);
+
+        mem.watch.add(0x8000000, 4, true, false);
+        mem.fetch_word(0x8000000);
+        mem.fetch_halfword(0x8000002);
+        assert!(!mem.watch.is_hit());
+        mem.get_halfword(0x8000002);
+        assert_eq!(mem.watch.take_hit(), Some(WatchHit {
+            addr: 0x8000002,
+            size: 2,
+            kind: AccessKind::Read,
+            source: Source::Cpu,
+        }));
+
+        mem.watch.clear();
+        mem.set_word(0x3000010, 0);
+        assert!(!mem.watch.is_hit());
+    }
+
+    #[test]
+    fn bios_copies() {
+        let mut cpu = CPU::new();
+        cpu.mem.watch.add(0x3000108, 4, false, true);
+        cpu.r[0] = 0x3000000;
+        cpu.r[1] = 0x3000100;
+        cpu.r[2] = 8;
+        cpu.run_swi(CPU_FAST_SET, 0);
+        assert_eq!(cpu.mem.watch.take_hit(), Some(WatchHit {
+            addr: 0x3000108,
+            size: 4,
+            kind: AccessKind::Write,
+            source: Source::Cpu,
+        }));
+    }
+}
@@ -0,0 +1,356 @@
+//! Romhacks and translations are distributed as patches to the original ROM
+//! rather than as ROMs, in one of two formats:
+//!   - IPS: a list of records that each overwrite a run of bytes (or fill it
+//!     with one value). There are no checksums, so applying one to the wrong
+//!     ROM can't be detected, and offsets are 24 bits so only the first 16MB
+//!     of a ROM can be patched
+//!   - UPS: the XOR of the original and patched ROMs, skipping unchanged
+//!     bytes. It ends with CRC-32s of the original ROM, the patched ROM and
+//!     the patch itself, so mismatched ROMs and corrupt patches are caught
+//!
+//! The format is detected from the patch's header.
+
+use std::fmt;
+use mem::Memory;
+use mem::addrs::MAX_ROM_SIZE;
+use util::crc32;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_HEADER: &[u8] = b"UPS1";
+/// the CRC-32s of the source, target and patch at the end of a UPS patch
+const UPS_FOOTER_LEN: usize = 12;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// the patch doesn't start with an IPS or UPS header
+    UnknownFormat,
+    /// the patch ends in the middle of a record
+    Truncated,
+    /// the patch's checksum of itself doesn't match, so it's been damaged
+    CorruptPatch,
+    /// the ROM isn't the one the patch was made for, going by the CRC-32s of
+    /// the expected ROM and the loaded one
+    WrongBaseRom { expected_crc: u32, actual_crc: u32 },
+    /// applying the patch didn't produce the ROM it was made to produce
+    BadOutput,
+    /// the patched ROM would have the given size, which doesn't fit in the
+    /// cartridge space
+    TooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::UnknownFormat => write!(f, "not an IPS or UPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::CorruptPatch => write!(f, "patch is corrupt (checksum mismatch)"),
+            PatchError::WrongBaseRom { expected_crc, actual_crc } =>
+                write!(f, "patch is for a different ROM (expected CRC32 {:08X}, \
+                    but the ROM's is {:08X})", expected_crc, actual_crc),
+            PatchError::BadOutput =>
+                write!(f, "patched ROM doesn't match the patch's checksum"),
+            PatchError::TooLarge(size) =>
+                write!(f, "patched ROM is too large ({} bytes)", size),
+        }
+    }
+}
+
+/// Return the result of applying an IPS or UPS patch to the ROM
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_HEADER) {
+        apply_ips(rom, &patch[IPS_HEADER.len()..])
+    } else if patch.starts_with(UPS_HEADER) {
+        apply_ups(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Reads the numbers of a patch, reporting an error if the patch ends early
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() - self.pos < len {
+            return Err(PatchError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// A big endian number of the given size, as used by IPS
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |n, byte| (n << 8) | *byte as usize))
+    }
+
+    /// A variable length number, as used by UPS: 7 bits at a time starting
+    /// from the lowest, with the top bit set on the last byte. Each
+    /// continuation also adds one, so that every number has one encoding
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = value.checked_add((byte & 0x7F) as usize * shift)
+                .ok_or(PatchError::CorruptPatch)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::CorruptPatch)?;
+            value = value.checked_add(shift).ok_or(PatchError::CorruptPatch)?;
+        }
+    }
+}
+
+/// Apply the records of an IPS patch (after the header). Each record is a
+/// 24 bit offset, a 16 bit length and that many bytes, or if the length is 0,
+/// a 16 bit count and a byte to repeat. Records can extend the ROM, and the
+/// 24 bit size after the end marker (if any) truncates it
+fn apply_ips(rom: &[u8], records: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(records);
+    loop {
+        if reader.bytes(3)? == IPS_EOF {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.be(3)?;
+        let len = reader.be(2)?;
+        let (len, fill) = if len == 0 {
+            (reader.be(2)?, Some(reader.u8()?))
+        } else {
+            (len, None)
+        };
+        if offset + len > out.len() {
+            if offset + len > MAX_ROM_SIZE {
+                return Err(PatchError::TooLarge(offset + len));
+            }
+            out.resize(offset + len, 0);
+        }
+        let dest = &mut out[offset..offset + len];
+        match fill {
+            Some(byte) => {
+                for dest in dest.iter_mut() {
+                    *dest = byte;
+                }
+            },
+            None => dest.copy_from_slice(reader.bytes(len)?),
+        }
+    }
+    if let Ok(size) = reader.be(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+/// Apply a UPS patch: the sizes of the source and target ROMs, then hunks that
+/// each skip a number of unchanged bytes and XOR the ROM with the bytes up to
+/// a 0 byte (which stands for the unchanged byte ending the hunk), then the
+/// checksums
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < UPS_HEADER.len() + UPS_FOOTER_LEN {
+        return Err(PatchError::Truncated);
+    }
+    let body_end = patch.len() - UPS_FOOTER_LEN;
+    let mut footer = Reader::new(&patch[body_end..]);
+    let le = |bytes: &[u8]| bytes.iter().rev().fold(0, |n, byte| (n << 8) | *byte as u32);
+    let source_crc = le(footer.bytes(4)?);
+    let target_crc = le(footer.bytes(4)?);
+    let patch_crc = le(footer.bytes(4)?);
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(PatchError::CorruptPatch);
+    }
+
+    let mut reader = Reader::new(&patch[..body_end]);
+    reader.pos = UPS_HEADER.len();
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let rom_crc = crc32(rom);
+    if rom.len() != source_size || rom_crc != source_crc {
+        return Err(PatchError::WrongBaseRom { expected_crc: source_crc, actual_crc: rom_crc });
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while reader.pos < body_end {
+        // a skip can be any size, so a corrupt patch could send pos past
+        // usize::MAX
+        pos = pos.checked_add(reader.varint()?).ok_or(PatchError::CorruptPatch)?;
+        loop {
+            let byte = reader.u8()?;
+            if let Some(dest) = out.get_mut(pos) {
+                *dest ^= byte;
+            }
+            pos = pos.checked_add(1).ok_or(PatchError::CorruptPatch)?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    if crc32(&out) != target_crc {
+        return Err(PatchError::BadOutput);
+    }
+    Ok(out)
+}
+
+impl Memory {
+    /// Load a cartridge ROM with an IPS or UPS patch applied. Nothing is
+    /// loaded if the patch can't be applied
+    pub fn load_rom_with_patch(&mut self, rom: Vec<u8>, patch: &[u8])
+        -> Result<(), PatchError> {
+        let rom = apply(&rom, patch)?;
+        self.load_rom(rom);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    /// Return a UPS patch from source to target, with a hunk for each run of
+    /// changed bytes
+    fn make_ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_HEADER.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        let byte = |data: &[u8], i: usize| data.get(i).cloned().unwrap_or(0);
+        let mut skipped = 0;
+        let mut i = 0;
+        while i < target.len() {
+            if byte(source, i) == target[i] {
+                skipped += 1;
+                i += 1;
+                continue;
+            }
+            varint(&mut patch, skipped);
+            while i < target.len() && byte(source, i) != target[i] {
+                patch.push(byte(source, i) ^ target[i]);
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            skipped = 0;
+        }
+        finish_ups(&mut patch, source, target);
+        patch
+    }
+
+    /// Append the checksums that end a UPS patch
+    fn finish_ups(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        for crc in [crc32(source), crc32(target)].iter() {
+            patch.extend_from_slice(&[*crc as u8, (*crc >> 8) as u8, (*crc >> 16) as u8,
+                (*crc >> 24) as u8]);
+        }
+        let crc = crc32(patch);
+        patch.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8,
+            (crc >> 24) as u8]);
+    }
+
+    #[test]
+    fn ips() {
+        let rom = vec![0; 0x10];
+        let mut patch = IPS_HEADER.to_vec();
+        // write 2 bytes at 0x4
+        patch.extend_from_slice(&[0, 0, 4, 0, 2, 0xAB, 0xCD]);
+        // fill 3 bytes at 0xE with 0x11, extending the ROM by 1
+        patch.extend_from_slice(&[0, 0, 0xE, 0, 0, 0, 3, 0x11]);
+        patch.extend_from_slice(IPS_EOF);
+        let out = apply(&rom, &patch).unwrap();
+        assert_eq!(out.len(), 0x11);
+        assert_eq!(&out[3..7], &[0, 0xAB, 0xCD, 0]);
+        assert_eq!(&out[0xD..], &[0, 0x11, 0x11, 0x11]);
+
+        // truncate to 8 bytes
+        patch.extend_from_slice(&[0, 0, 8]);
+        assert_eq!(apply(&rom, &patch).unwrap().len(), 8);
+
+        let truncated = &patch[..patch.len() - 8];
+        assert_eq!(apply(&rom, truncated), Err(PatchError::Truncated));
+        assert_eq!(apply(&rom, b"PACTH"), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn ups() {
+        let source: Vec<u8> = (0..0x300).map(|i| i as u8).collect();
+        let mut target = source.clone();
+        target[0x10] = 0xFF;
+        target[0x11] = 0xEE;
+        target[0x200] = 0;
+        target.extend_from_slice(&[1, 2, 3]);
+        let patch = make_ups(&source, &target);
+        assert_eq!(apply(&source, &patch), Ok(target.clone()));
+
+        let mut mem = Memory::new();
+        mem.load_rom_with_patch(source.clone(), &patch).unwrap();
+        assert_eq!(mem.get_byte(0x8000010), 0xFF);
+
+        // the wrong ROM is caught and nothing is loaded
+        let mut other = source.clone();
+        other[0] = 1;
+        let err = mem.load_rom_with_patch(other.clone(), &patch).unwrap_err();
+        assert_eq!(err, PatchError::WrongBaseRom {
+            expected_crc: crc32(&source),
+            actual_crc: crc32(&other),
+        });
+        assert_eq!(err.to_string(), format!("patch is for a different ROM (expected \
+            CRC32 {:08X}, but the ROM's is {:08X})", crc32(&source), crc32(&other)));
+        assert_eq!(mem.get_byte(0x8000010), 0xFF);
+
+        // as is a damaged patch
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert_eq!(apply(&source, &corrupt), Err(PatchError::CorruptPatch));
+
+        // skips that add up past usize::MAX are rejected
+        let mut patch = UPS_HEADER.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, source.len());
+        for _ in 0..2 {
+            varint(&mut patch, usize::MAX / 2 + 1);
+            patch.push(0);
+        }
+        finish_ups(&mut patch, &source, &source);
+        assert_eq!(apply(&source, &patch), Err(PatchError::CorruptPatch));
+    }
+
+    #[test]
+    fn ups_varint() {
+        for &value in [0, 0x7F, 0x80, 0x407F, 0x4080, 0x123456].iter() {
+            let mut data = Vec::new();
+            varint(&mut data, value);
+            assert_eq!(Reader::new(&data).varint(), Ok(value));
+        }
+        assert_eq!(Reader::new(&[0x00]).varint(), Err(PatchError::Truncated));
+    }
+}
@@ -0,0 +1,664 @@
+//! The contents of each chunk. Loading is split into load(), which only
+//! decodes the data, and apply(), which can't fail, so that a bad state is
+//! caught before anything is overwritten. load() is given the chunk's
+//! version, which has already been checked to be one this build can read;
+//! every chunk is still at version 1, so none of them branch on it yet.
+//!
+//! The parsed state is rebuilt from the raw registers, palette and OAM with
+//! Memory::rebuild_parsed_state once all of them have been applied. Only the
+//! state that can't be derived from the registers (e.g. the progress of a DMA
+//! transfer) is saved separately, and restored on top afterwards.
+
+use num::FromPrimitive;
+use cpu::{CPUWrapper, REFRESH};
+use cpu::status_reg::{CPUMode, PSR};
+use mem::Memory;
+use mem::addrs::IO_START;
+use mem::io::addrs::TMCNT_H;
+use mem::io::sound::APU;
+use mem::io::timers::PRESCALERS;
+use super::{Reader, StateError, Writer};
+
+pub const CPU_TAG: &[u8; 4] = b"CPU ";
+pub const CPU_VERSION: u16 = 1;
+pub const RAM_TAG: &[u8; 4] = b"RAM ";
+pub const RAM_VERSION: u16 = 1;
+pub const IO_TAG: &[u8; 4] = b"IO  ";
+pub const IO_VERSION: u16 = 1;
+pub const PPU_TAG: &[u8; 4] = b"PPU ";
+pub const PPU_VERSION: u16 = 1;
+pub const APU_TAG: &[u8; 4] = b"APU ";
+pub const APU_VERSION: u16 = 1;
+pub const CART_TAG: &[u8; 4] = b"CART";
+pub const CART_VERSION: u16 = 1;
+pub const BACKUP_TAG: &[u8; 4] = b"BKUP";
+pub const BACKUP_VERSION: u16 = 1;
+
+fn write_regs(out: &mut Writer, regs: &[u32]) {
+    for reg in regs.iter() {
+        out.u32(*reg);
+    }
+}
+
+fn read_regs(reader: &mut Reader, regs: &mut [u32]) -> Result<(), StateError> {
+    for reg in regs.iter_mut() {
+        *reg = reader.u32()?;
+    }
+    Ok(())
+}
+
+fn read_psr(reader: &mut Reader) -> Result<PSR, StateError> {
+    let val = reader.u32()?;
+    if CPUMode::from_u32(val & 0b11111).is_none() {
+        return Err(StateError::InvalidValue("CPU mode"));
+    }
+    let mut psr = PSR::new();
+    psr.from_u32(val, false);
+    Ok(psr)
+}
+
+/// Registers, the pipeline, and the position in the frame
+pub struct CpuChunk {
+    r: [u32; 16],
+    r_fiq: [u32; 7],
+    /// r13 and r14 for IRQ, UND, ABT and SVC
+    r_banked: [u32; 8],
+    cpsr: PSR,
+    /// SVC, ABT, UND, IRQ and FIQ
+    spsr: [PSR; 5],
+    should_flush: bool,
+    pipeline_depth: u32,
+    cycles: u32,
+    total_cycles: u64,
+    /// the IntrWait being waited in (see Bios::waiting)
+    waiting: Option<u32>,
+}
+
+impl CpuChunk {
+    pub fn save(gba: &CPUWrapper) -> Writer {
+        let cpu = &gba.cpu;
+        let mut out = Writer::new();
+        write_regs(&mut out, &cpu.r);
+        write_regs(&mut out, &cpu.r_fiq);
+        for regs in [cpu.r_irq, cpu.r_und, cpu.r_abt, cpu.r_svc].iter() {
+            write_regs(&mut out, regs);
+        }
+        for psr in [cpu.cpsr, cpu.spsr_svc, cpu.spsr_abt, cpu.spsr_und,
+                    cpu.spsr_irq, cpu.spsr_fiq].iter() {
+            out.u32(psr.to_u32());
+        }
+        out.bool(cpu.should_flush);
+        out.u32(gba.pipeline_depth());
+        out.u32(gba.cycles);
+        out.u64(gba.total_cycles);
+        out.bool(cpu.bios.waiting.is_some());
+        out.u32(cpu.bios.waiting.unwrap_or(0));
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<CpuChunk, StateError> {
+        let mut chunk = CpuChunk {
+            r: [0; 16],
+            r_fiq: [0; 7],
+            r_banked: [0; 8],
+            cpsr: PSR::new(),
+            spsr: [PSR::new(); 5],
+            should_flush: false,
+            pipeline_depth: 0,
+            cycles: 0,
+            total_cycles: 0,
+            waiting: None,
+        };
+        read_regs(reader, &mut chunk.r)?;
+        read_regs(reader, &mut chunk.r_fiq)?;
+        read_regs(reader, &mut chunk.r_banked)?;
+        chunk.cpsr = read_psr(reader)?;
+        for psr in chunk.spsr.iter_mut() {
+            *psr = read_psr(reader)?;
+        }
+        chunk.should_flush = reader.bool()?;
+        chunk.pipeline_depth = reader.u32()?;
+        // there are at most 2 instructions fetched ahead of the one executing
+        if chunk.pipeline_depth > 2 {
+            return Err(StateError::InvalidValue("pipeline depth"));
+        }
+        chunk.cycles = reader.u32()?;
+        if chunk.cycles >= REFRESH {
+            return Err(StateError::InvalidValue("frame cycle count"));
+        }
+        chunk.total_cycles = reader.u64()?;
+        if !reader.is_empty() {
+            let waiting = reader.bool()?;
+            let pc = reader.u32()?;
+            chunk.waiting = if waiting { Some(pc) } else { None };
+        }
+        Ok(chunk)
+    }
+
+    pub fn apply(self, gba: &mut CPUWrapper) {
+        {
+            let cpu = &mut gba.cpu;
+            cpu.r = self.r;
+            cpu.r_fiq = self.r_fiq;
+            let banked = &self.r_banked;
+            cpu.r_irq.copy_from_slice(&banked[0..2]);
+            cpu.r_und.copy_from_slice(&banked[2..4]);
+            cpu.r_abt.copy_from_slice(&banked[4..6]);
+            cpu.r_svc.copy_from_slice(&banked[6..8]);
+            cpu.cpsr = self.cpsr;
+            cpu.spsr_svc = self.spsr[0];
+            cpu.spsr_abt = self.spsr[1];
+            cpu.spsr_und = self.spsr[2];
+            cpu.spsr_irq = self.spsr[3];
+            cpu.spsr_fiq = self.spsr[4];
+            cpu.bios.waiting = self.waiting;
+        }
+        gba.cycles = self.cycles;
+        gba.total_cycles = self.total_cycles;
+        gba.last_instruction = None;
+        gba.fast_copy.forget();
+        if self.pipeline_depth > 0 {
+            gba.restore_pipeline(self.pipeline_depth);
+        } else {
+            gba.flush_pipeline();
+        }
+        gba.cpu.should_flush = self.should_flush;
+    }
+}
+
+pub struct RamChunk {
+    ewram: Vec<u8>,
+    iwram: Vec<u8>,
+}
+
+impl RamChunk {
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        out.bytes(&mem.raw.ewram[..]);
+        out.bytes(&mem.raw.iwram[..]);
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<RamChunk, StateError> {
+        let mut chunk = RamChunk { ewram: vec![0; 0x40000], iwram: vec![0; 0x8000] };
+        reader.fill(&mut chunk.ewram)?;
+        reader.fill(&mut chunk.iwram)?;
+        Ok(chunk)
+    }
+
+    pub fn apply(self, mem: &mut Memory) {
+        mem.raw.ewram.copy_from_slice(&self.ewram);
+        mem.raw.iwram.copy_from_slice(&self.iwram);
+    }
+}
+
+/// The raw IO registers, plus state that isn't visible in them
+pub struct IoChunk {
+    io: Vec<u8>,
+    /// the internal (src, dest, count) of each DMA channel. None if the
+    /// state is from before these were saved, in which case they're reloaded
+    /// from the registers
+    dma: Option<[(u32, u32, u32); 4]>,
+    /// (busy, cycles left, transfer done) for the serial port
+    serial: Option<(bool, u32, bool)>,
+    /// (counter, ticks) for each timer. None if the state is from before the
+    /// timers were emulated, in which case they start from their reload value
+    timers: Option<[(u16, u32); 4]>,
+}
+
+impl IoChunk {
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        out.bytes(&mem.raw.io);
+        for channel in mem.dma.channels.iter() {
+            let (src, dest, count) = channel.internal_regs();
+            write_regs(&mut out, &[src, dest, count]);
+        }
+        out.bool(mem.serial.busy);
+        out.u32(mem.serial.cycles_left);
+        out.bool(mem.serial.transfer_done);
+        for timer in mem.timers.timers.iter() {
+            out.u16(timer.counter);
+            out.u32(timer.ticks);
+        }
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<IoChunk, StateError> {
+        let mut chunk = IoChunk { io: vec![0; 0x400], dma: None, serial: None, timers: None };
+        reader.fill(&mut chunk.io)?;
+        if !reader.is_empty() {
+            let mut dma = [(0, 0, 0); 4];
+            for channel in dma.iter_mut() {
+                *channel = (reader.u32()?, reader.u32()?, reader.u32()?);
+            }
+            chunk.dma = Some(dma);
+            chunk.serial = Some((reader.bool()?, reader.u32()?, reader.bool()?));
+        }
+        if !reader.is_empty() {
+            let mut timers = [(0, 0); 4];
+            for (timer, cnt) in timers.iter_mut().zip(TMCNT_H.iter()) {
+                *timer = (reader.u16()?, reader.u32()?);
+                // the cycles towards the next count are always fewer than a
+                // count takes
+                let prescaler = chunk.io[(cnt - IO_START) as usize] & 0b11;
+                if timer.1 >= PRESCALERS[prescaler as usize] {
+                    return Err(StateError::InvalidValue("timer tick count"));
+                }
+            }
+            chunk.timers = Some(timers);
+        }
+        Ok(chunk)
+    }
+
+    pub fn apply(&self, mem: &mut Memory) {
+        mem.raw.io.copy_from_slice(&self.io);
+    }
+
+    /// Restore the state that isn't visible in the registers, after the parsed
+    /// state has been rebuilt from them
+    pub fn restore_internal(self, mem: &mut Memory) {
+        if let Some(dma) = self.dma {
+            for (channel, &(src, dest, count)) in mem.dma.channels.iter_mut().zip(dma.iter()) {
+                channel.restore_internal_regs(src, dest, count);
+            }
+        }
+        let (busy, cycles_left, transfer_done) = self.serial.unwrap_or((false, 0, false));
+        mem.serial.busy = busy;
+        mem.serial.cycles_left = cycles_left;
+        mem.serial.transfer_done = transfer_done;
+        mem.update_serial_status();
+        if let Some(timers) = self.timers {
+            for (timer, &(counter, ticks)) in mem.timers.timers.iter_mut().zip(timers.iter()) {
+                timer.counter = counter;
+                timer.ticks = ticks;
+            }
+        }
+    }
+}
+
+/// Palette, VRAM and OAM
+pub struct PpuChunk {
+    pal: Vec<u8>,
+    vram: Vec<u8>,
+    oam: Vec<u8>,
+}
+
+impl PpuChunk {
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        out.bytes(&mem.raw.pal);
+        out.bytes(&mem.raw.vram[..]);
+        out.bytes(&mem.raw.oam);
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<PpuChunk, StateError> {
+        let mut chunk = PpuChunk {
+            pal: vec![0; 0x400],
+            vram: vec![0; 0x18000],
+            oam: vec![0; 0x400],
+        };
+        reader.fill(&mut chunk.pal)?;
+        reader.fill(&mut chunk.vram)?;
+        reader.fill(&mut chunk.oam)?;
+        Ok(chunk)
+    }
+
+    pub fn apply(self, mem: &mut Memory) {
+        mem.raw.pal.copy_from_slice(&self.pal);
+        mem.raw.vram.copy_from_slice(&self.vram);
+        mem.raw.oam.copy_from_slice(&self.oam);
+    }
+}
+
+/// The parts of a PSG channel that change as it plays
+#[derive(Clone, Copy)]
+struct ChannelState {
+    enabled: bool,
+    length: u16,
+    /// the envelope's volume and timer, which are 0 for the wave channel
+    volume: u8,
+    envelope_timer: u8,
+    countdown: u32,
+    /// the position in the duty cycle or wave, or the noise shift register
+    position: u16,
+}
+
+impl ChannelState {
+    fn write(&self, out: &mut Writer) {
+        out.bool(self.enabled);
+        out.u16(self.length);
+        out.u8(self.volume);
+        out.u8(self.envelope_timer);
+        out.u32(self.countdown);
+        out.u16(self.position);
+    }
+
+    fn read(reader: &mut Reader) -> Result<ChannelState, StateError> {
+        Ok(ChannelState {
+            enabled: reader.bool()?,
+            length: reader.u16()?,
+            volume: reader.u8()?,
+            envelope_timer: reader.u8()?,
+            countdown: reader.u32()?,
+            position: reader.u16()?,
+        })
+    }
+}
+
+/// The state of the PSG that isn't visible in its registers
+struct PsgState {
+    /// the two squares, the wave channel and the noise channel
+    channels: [ChannelState; 4],
+    /// (shadow frequency, timer, enabled) for channel 1's sweep, and the
+    /// frequency it has swept to
+    sweep: (u16, u8, bool),
+    freq: u16,
+    /// the bank of wave RAM being played, which raw memory doesn't have
+    wave_bank: [u8; 16],
+    /// (step, countdown) for the frame sequencer
+    sequencer: (u8, u32),
+    sample_countdown: u32,
+}
+
+impl PsgState {
+    fn save(apu: &APU) -> PsgState {
+        let mut channels = [ChannelState {
+            enabled: false, length: 0, volume: 0, envelope_timer: 0, countdown: 0, position: 0,
+        }; 4];
+        for (state, square) in channels.iter_mut().zip(apu.squares.iter()) {
+            *state = ChannelState {
+                enabled: square.enabled,
+                length: square.length.counter,
+                volume: square.envelope.volume,
+                envelope_timer: square.envelope.timer,
+                countdown: square.countdown,
+                position: square.position as u16,
+            };
+        }
+        let wave = &apu.wave;
+        channels[2] = ChannelState {
+            enabled: wave.enabled,
+            length: wave.length.counter,
+            volume: 0,
+            envelope_timer: 0,
+            countdown: wave.countdown,
+            position: wave.position as u16,
+        };
+        let noise = &apu.noise;
+        channels[3] = ChannelState {
+            enabled: noise.enabled,
+            length: noise.length.counter,
+            volume: noise.envelope.volume,
+            envelope_timer: noise.envelope.timer,
+            countdown: noise.countdown,
+            position: noise.lfsr,
+        };
+        let sweep = &apu.squares[0].sweep;
+        PsgState {
+            channels,
+            sweep: (sweep.shadow, sweep.timer, sweep.enabled),
+            freq: apu.squares[0].freq,
+            wave_bank: apu.wave_banks[wave.bank],
+            sequencer: (apu.sequencer_step, apu.sequencer_countdown),
+            sample_countdown: apu.sample_countdown,
+        }
+    }
+
+    fn write(&self, out: &mut Writer) {
+        for channel in self.channels.iter() {
+            channel.write(out);
+        }
+        out.u16(self.sweep.0);
+        out.u8(self.sweep.1);
+        out.bool(self.sweep.2);
+        out.u16(self.freq);
+        out.bytes(&self.wave_bank);
+        out.u8(self.sequencer.0);
+        out.u32(self.sequencer.1);
+        out.u32(self.sample_countdown);
+    }
+
+    fn read(reader: &mut Reader) -> Result<PsgState, StateError> {
+        let channels = [
+            ChannelState::read(reader)?,
+            ChannelState::read(reader)?,
+            ChannelState::read(reader)?,
+            ChannelState::read(reader)?,
+        ];
+        // the positions index the duty patterns and wave RAM, and the square
+        // and noise volumes only fade between 0 and 15
+        let positions = [8, 8, 64];
+        if channels.iter().zip(positions.iter()).any(|(state, len)| state.position >= *len) {
+            return Err(StateError::InvalidValue("PSG channel position"));
+        }
+        if channels.iter().any(|state| state.volume > 15) {
+            return Err(StateError::InvalidValue("PSG envelope volume"));
+        }
+        let sweep = (reader.u16()?, reader.u8()?, reader.bool()?);
+        let freq = reader.u16()?;
+        let mut wave_bank = [0; 16];
+        reader.fill(&mut wave_bank)?;
+        let sequencer = (reader.u8()?, reader.u32()?);
+        if sequencer.0 >= 8 {
+            return Err(StateError::InvalidValue("frame sequencer step"));
+        }
+        Ok(PsgState {
+            channels,
+            sweep,
+            freq,
+            wave_bank,
+            sequencer,
+            sample_countdown: reader.u32()?,
+        })
+    }
+
+    fn apply(&self, apu: &mut APU) {
+        for (square, state) in apu.squares.iter_mut().zip(self.channels.iter()) {
+            square.enabled = state.enabled;
+            square.length.counter = state.length;
+            square.envelope.volume = state.volume;
+            square.envelope.timer = state.envelope_timer;
+            square.countdown = state.countdown;
+            square.position = state.position as u8;
+        }
+        let state = &self.channels[2];
+        let wave = &mut apu.wave;
+        wave.enabled = state.enabled;
+        wave.length.counter = state.length;
+        wave.countdown = state.countdown;
+        wave.position = state.position as u8;
+        let state = &self.channels[3];
+        let noise = &mut apu.noise;
+        noise.enabled = state.enabled;
+        noise.length.counter = state.length;
+        noise.envelope.volume = state.volume;
+        noise.envelope.timer = state.envelope_timer;
+        noise.countdown = state.countdown;
+        noise.lfsr = state.position;
+
+        let sweep = &mut apu.squares[0].sweep;
+        sweep.shadow = self.sweep.0;
+        sweep.timer = self.sweep.1;
+        sweep.enabled = self.sweep.2;
+        apu.squares[0].freq = self.freq;
+        apu.wave_banks[apu.wave.bank] = self.wave_bank;
+        apu.sequencer_step = self.sequencer.0;
+        apu.sequencer_countdown = self.sequencer.1;
+        apu.sample_countdown = self.sample_countdown;
+    }
+}
+
+/// The contents of the sound FIFOs, and the state of the PSG channels
+pub struct ApuChunk {
+    /// (queued samples, output) for FIFO A and B
+    fifos: [(Vec<i8>, i8); 2],
+    /// None if the state is from before the PSG was emulated, in which case
+    /// every channel is stopped
+    psg: Option<PsgState>,
+}
+
+impl ApuChunk {
+    /// The state of a GBA that has just been turned on, used for states from
+    /// before sound was emulated
+    pub fn default() -> ApuChunk {
+        ApuChunk { fifos: [(Vec::new(), 0), (Vec::new(), 0)], psg: None }
+    }
+
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        for fifo in mem.sound.fifos.iter() {
+            let samples = fifo.samples();
+            out.u8(samples.len() as u8);
+            for sample in samples {
+                out.u8(sample as u8);
+            }
+            out.u8(fifo.output as u8);
+        }
+        PsgState::save(&mem.apu).write(&mut out);
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<ApuChunk, StateError> {
+        let mut chunk = ApuChunk::default();
+        for fifo in chunk.fifos.iter_mut() {
+            let len = reader.u8()? as usize;
+            fifo.0 = reader.bytes(len)?.iter().map(|b| *b as i8).collect();
+            fifo.1 = reader.u8()? as i8;
+        }
+        if !reader.is_empty() {
+            chunk.psg = Some(PsgState::read(reader)?);
+        }
+        Ok(chunk)
+    }
+
+    pub fn apply(self, mem: &mut Memory) {
+        for (fifo, (samples, output)) in mem.sound.fifos.iter_mut().zip(self.fifos.iter()) {
+            fifo.reset();
+            for sample in samples.iter() {
+                fifo.push(*sample);
+            }
+            fifo.output = *output;
+        }
+        if let Some(psg) = self.psg {
+            psg.apply(&mut mem.apu);
+        }
+        mem.update_channel_status();
+    }
+}
+
+/// Identifies the game the state was saved from
+pub struct CartChunk {
+    /// empty if no ROM was loaded
+    game_code: String,
+}
+
+impl CartChunk {
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        let code = mem.game_code().unwrap_or_default();
+        out.u8(code.len() as u8);
+        out.bytes(code.as_bytes());
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<CartChunk, StateError> {
+        let len = reader.u8()? as usize;
+        let game_code = String::from_utf8_lossy(reader.bytes(len)?).into_owned();
+        Ok(CartChunk { game_code })
+    }
+
+    /// Make sure the loaded game is the one the state was saved from
+    pub fn check(&self, mem: &Memory) -> Result<(), StateError> {
+        if self.game_code.is_empty() ||
+           mem.game_code().as_ref() == Some(&self.game_code) {
+            Ok(())
+        } else {
+            Err(StateError::WrongGame(self.game_code.clone()))
+        }
+    }
+}
+
+/// The contents of the EEPROM and the request it's in the middle of, see
+/// Eeprom::internal_state
+struct EepromState {
+    data: Vec<u8>,
+    request: u128,
+    request_len: u32,
+    read: Option<(usize, u32)>,
+}
+
+/// The state of the cart's save chips: the SRAM bank, how far through a
+/// command the Flash chip is, and the EEPROM with the request it's in the
+/// middle of
+pub struct BackupChunk {
+    sram_bank: u8,
+    /// (unlock, erase, pending, id mode, bank), see Flash::internal_state
+    flash: (u8, bool, u8, bool, u8),
+    /// None if the state is from before EEPROM was saved, in which case its
+    /// contents are left as they are and any request is abandoned
+    eeprom: Option<EepromState>,
+}
+
+impl BackupChunk {
+    /// The state of the chips when the GBA is turned on, used for states from
+    /// before this was saved
+    pub fn default() -> BackupChunk {
+        BackupChunk { sram_bank: 0, flash: (0, false, 0, false, 0), eeprom: None }
+    }
+
+    pub fn save(mem: &Memory) -> Writer {
+        let mut out = Writer::new();
+        out.u8(mem.raw.sram.bank());
+        let (unlock, erase, pending, id_mode, bank) = mem.raw.flash.internal_state();
+        out.u8(unlock);
+        out.bool(erase);
+        out.u8(pending);
+        out.bool(id_mode);
+        out.u8(bank);
+        let eeprom = mem.raw.eeprom.export();
+        out.u32(eeprom.len() as u32);
+        out.bytes(&eeprom);
+        let (request, request_len, read) = mem.raw.eeprom.internal_state();
+        out.u64(request as u64);
+        out.u64((request >> 64) as u64);
+        out.u32(request_len);
+        let (idx, n) = read.unwrap_or((0, 0));
+        out.bool(read.is_some());
+        out.u32(idx as u32);
+        out.u32(n);
+        out
+    }
+
+    pub fn load(_version: u16, reader: &mut Reader) -> Result<BackupChunk, StateError> {
+        let mut chunk = BackupChunk {
+            sram_bank: reader.u8()?,
+            flash: (reader.u8()?, reader.bool()?, reader.u8()?, reader.bool()?, reader.u8()?),
+            eeprom: None,
+        };
+        if !reader.is_empty() {
+            let len = reader.u32()? as usize;
+            let data = reader.bytes(len)?.to_vec();
+            let request = reader.u64()? as u128 | (reader.u64()? as u128) << 64;
+            let request_len = reader.u32()?;
+            let reading = reader.bool()?;
+            let read = (reader.u32()? as usize, reader.u32()?);
+            let read = if reading { Some(read) } else { None };
+            chunk.eeprom = Some(EepromState { data, request, request_len, read });
+        }
+        Ok(chunk)
+    }
+
+    pub fn apply(self, mem: &mut Memory) {
+        mem.raw.sram.reset_bank();
+        mem.raw.sram.select_bank(self.sram_bank);
+        let (unlock, erase, pending, id_mode, bank) = self.flash;
+        mem.raw.flash.restore_internal_state(unlock, erase, pending, id_mode, bank);
+        match self.eeprom {
+            Some(state) => mem.raw.eeprom.restore_internal_state(&state.data, state.request,
+                                                                 state.request_len, state.read),
+            None => mem.raw.eeprom.reset(),
+        }
+    }
+}
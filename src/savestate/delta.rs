@@ -0,0 +1,186 @@
+//! Deltas between two save states, so that a state can be sent (e.g. to
+//! resync a netplay peer) or kept (e.g. for rewind) as just the changes from
+//! one the other side already has. Most of a state is RAM and VRAM, and only
+//! a small part of that changes from one frame to the next.
+//!
+//! A delta is the XOR of the two states, with the runs of unchanged bytes
+//! left out:
+//!
+//!     "GBSD" <base length: u32> <target length: u32>
+//!     <base CRC-32: u32> <target CRC-32: u32>
+//!     <unchanged bytes skipped: u32> <length: u32> <XORed bytes>
+//!     ...
+//!
+//! The base is treated as zero past its end, and is cut to the target's
+//! length. The CRC-32s catch a delta being applied to the wrong base, and
+//! one that doesn't produce the state it was made from.
+
+use savestate::{Reader, StateError, Writer};
+use util::crc32;
+
+const MAGIC: &[u8; 4] = b"GBSD";
+/// runs of unchanged bytes shorter than this are kept in the changed run
+/// around them, since starting a new run takes up as much space
+const MIN_SKIP: usize = 8;
+/// the longest target a delta can have. States are well under this, so a
+/// longer one means the delta is corrupt, and isn't worth allocating for
+const MAX_TARGET_LEN: usize = 0x200000;
+
+/// Return the delta that turns the base state into the target
+pub fn diff_states(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Writer::new();
+    out.bytes(MAGIC);
+    out.u32(base.len() as u32);
+    out.u32(target.len() as u32);
+    out.u32(crc32(base));
+    out.u32(crc32(target));
+
+    let change = |i: usize| target[i] ^ base.get(i).cloned().unwrap_or(0);
+    let mut pos = 0;
+    while let Some(start) = (pos..target.len()).find(|&i| change(i) != 0) {
+        // the run ends at the last change before MIN_SKIP unchanged bytes
+        let mut end = start + 1;
+        let mut i = end;
+        while i < target.len() && i - end < MIN_SKIP {
+            if change(i) != 0 {
+                end = i + 1;
+            }
+            i += 1;
+        }
+        out.u32((start - pos) as u32);
+        out.u32((end - start) as u32);
+        for i in start..end {
+            out.u8(change(i));
+        }
+        pos = end;
+    }
+    out.data
+}
+
+/// The header of a delta: the lengths and CRC-32s of its base and target
+struct Header {
+    base_len: usize,
+    target_len: usize,
+    base_crc: u32,
+    target_crc: u32,
+}
+
+fn read_header(reader: &mut Reader) -> Result<Header, StateError> {
+    if reader.bytes(4).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::NotDelta);
+    }
+    Ok(Header {
+        base_len: reader.u32()? as usize,
+        target_len: reader.u32()? as usize,
+        base_crc: reader.u32()?,
+        target_crc: reader.u32()?,
+    })
+}
+
+/// Return an error if the delta wasn't made from the given base, without
+/// applying it
+pub fn check_base(base: &[u8], delta: &[u8]) -> Result<(), StateError> {
+    let header = read_header(&mut Reader::new(delta))?;
+    if base.len() != header.base_len || crc32(base) != header.base_crc {
+        return Err(StateError::WrongBase);
+    }
+    Ok(())
+}
+
+/// Return the state that the delta was made from, given its base
+pub fn apply_diff(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, StateError> {
+    check_base(base, delta)?;
+    let mut reader = Reader::new(delta);
+    let header = read_header(&mut reader)?;
+    if header.target_len > MAX_TARGET_LEN {
+        return Err(StateError::CorruptDelta);
+    }
+    let mut out = base.to_vec();
+    out.resize(header.target_len, 0);
+    let mut pos: usize = 0;
+    while !reader.is_empty() {
+        let start = pos.checked_add(reader.u32()? as usize).ok_or(StateError::CorruptDelta)?;
+        let changes = reader.u32()? as usize;
+        let changes = reader.bytes(changes)?;
+        let end = start.checked_add(changes.len()).ok_or(StateError::CorruptDelta)?;
+        let run = out.get_mut(start..end).ok_or(StateError::CorruptDelta)?;
+        for (byte, change) in run.iter_mut().zip(changes.iter()) {
+            *byte ^= *change;
+        }
+        pos = end;
+    }
+    if crc32(&out) != header.target_crc {
+        return Err(StateError::CorruptDelta);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use savestate::test::running_gba;
+
+    #[test]
+    fn round_trip() {
+        let mut gba = running_gba();
+        let mut base = gba.save_state();
+        let mut seed = 0x2468ACEFu32;
+        for _ in 0..20 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            for _ in 0..(seed % 3000) {
+                gba.step().unwrap();
+            }
+            let target = gba.save_state();
+            let delta = diff_states(&base, &target);
+            assert!(delta.len() < target.len() / 4);
+            assert_eq!(apply_diff(&base, &delta), Ok(target.clone()));
+
+            // the rebuilt state loads like the original
+            let mut restored = running_gba();
+            restored.load_state(&apply_diff(&base, &delta).unwrap()).unwrap();
+            assert_eq!(restored.cpu.r, gba.cpu.r);
+            assert_eq!(restored.save_state(), target);
+            base = target;
+        }
+
+        // an unchanged state is just the header
+        assert_eq!(diff_states(&base, &base).len(), 20);
+    }
+
+    #[test]
+    fn lengths() {
+        let base = vec![1, 2, 3, 4, 5, 6];
+        let longer = vec![1, 2, 9, 4, 5, 6, 0, 7];
+        let delta = diff_states(&base, &longer);
+        assert_eq!(apply_diff(&base, &delta), Ok(longer.clone()));
+        let delta = diff_states(&longer, &base);
+        assert_eq!(apply_diff(&longer, &delta), Ok(base.clone()));
+    }
+
+    #[test]
+    fn errors() {
+        let base = vec![0; 100];
+        let mut target = base.clone();
+        target[50] = 1;
+        let mut delta = diff_states(&base, &target);
+        assert_eq!(apply_diff(&target, &delta), Err(StateError::WrongBase));
+        assert_eq!(check_base(&base[..99], &delta), Err(StateError::WrongBase));
+        assert_eq!(check_base(&base, &delta), Ok(()));
+        assert_eq!(apply_diff(&base, b"GBAS"), Err(StateError::NotDelta));
+        assert_eq!(apply_diff(&base, &delta[..delta.len() - 1]), Err(StateError::Truncated));
+
+        *delta.last_mut().unwrap() ^= 2;
+        assert_eq!(apply_diff(&base, &delta), Err(StateError::CorruptDelta));
+
+        // a huge target length is rejected before anything is allocated for it
+        let mut delta = diff_states(&base, &target);
+        delta[8..12].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(apply_diff(&base, &delta), Err(StateError::CorruptDelta));
+        // as is a run that's longer than the delta
+        let mut delta = diff_states(&base, &target);
+        delta[24..28].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(apply_diff(&base, &delta), Err(StateError::Truncated));
+    }
+}
@@ -0,0 +1,538 @@
+//! Save states capture everything needed to resume emulation later. A state
+//! is a header followed by a list of tagged chunks, one per subsystem:
+//!
+//!     "GBAS" <format version: u32>
+//!     <tag: 4 bytes> <chunk version: u16> <length: u32> <data>
+//!     ...
+//!
+//! All numbers are little endian. Each chunk is versioned separately, so
+//! changing one subsystem doesn't invalidate the rest of the state:
+//!   - new fields are only ever appended to a chunk. Older builds ignore the
+//!     data they don't know about, and newer builds use a default when a
+//!     chunk from an older build ends early
+//!   - when the meaning of existing data changes, the chunk's version is
+//!     bumped and the loader keeps a branch for each older version that
+//!     converts it
+//!   - a subsystem that's added later gets its own chunk. States from before
+//!     it existed don't have the chunk, and load with the subsystem reset
+//!     (e.g. states without an APU chunk start with empty sound FIFOs)
+//!   - chunks with an unknown tag are skipped
+//!
+//! A state is fully decoded before anything is applied, so a state that fails
+//! to load leaves the emulator untouched.
+//!
+//! States can also be sent as the changes from an earlier state, see delta.
+
+mod chunks;
+pub mod delta;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str;
+use cpu::CPUWrapper;
+use self::chunks::*;
+
+const MAGIC: &[u8; 4] = b"GBAS";
+/// the version of the container, which only changes if the chunk layout
+/// itself does
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// the data doesn't start with the save state header
+    NotSaveState,
+    /// the state was saved by a newer build with an incompatible format
+    UnsupportedVersion(u32),
+    /// a chunk was saved by a newer build that changed its meaning
+    UnsupportedChunkVersion(String, u16),
+    /// a chunk that every state must have is missing
+    MissingChunk(&'static str),
+    /// the state ends in the middle of a chunk
+    Truncated,
+    /// the state was saved while a different game (with the given game code)
+    /// was loaded
+    WrongGame(String),
+    /// the data doesn't start with the delta header
+    NotDelta,
+    /// a delta was applied to a different state than the one it was made from
+    WrongBase,
+    /// applying a delta didn't produce the state it was made from
+    CorruptDelta,
+    /// a chunk holds something the emulator can't be in, e.g. an unknown CPU
+    /// mode
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotSaveState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "unsupported save state version {}", version),
+            StateError::UnsupportedChunkVersion(ref tag, version) =>
+                write!(f, "unsupported version {} of the {} chunk", version, tag),
+            StateError::MissingChunk(tag) => write!(f, "save state has no {} chunk", tag),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::WrongGame(ref code) =>
+                write!(f, "save state is for a different game ({})", code),
+            StateError::NotDelta => write!(f, "not a save state delta"),
+            StateError::WrongBase =>
+                write!(f, "save state delta is for a different base state"),
+            StateError::CorruptDelta => write!(f, "save state delta is corrupt"),
+            StateError::InvalidValue(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+/// Builds the data of a single chunk
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&[val as u8, (val >> 8) as u8]);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.u16(val as u16);
+        self.u16((val >> 16) as u16);
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.u32(val as u32);
+        self.u32((val >> 32) as u32);
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+}
+
+/// Reads the data of a single chunk
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    /// Return true if there's no more data, i.e. the chunk was saved before
+    /// any fields that come after this point were added
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    /// Fill the given buffer, e.g. a RAM array
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.bytes(out.len())?);
+        Ok(())
+    }
+}
+
+/// The chunks of a state by tag, as (version, data)
+struct Chunks<'a> {
+    chunks: HashMap<[u8; 4], (u16, &'a [u8])>,
+}
+
+impl<'a> Chunks<'a> {
+    fn parse(data: &'a [u8]) -> Result<Chunks<'a>, StateError> {
+        let mut reader = Reader::new(data);
+        if reader.bytes(4).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::NotSaveState);
+        }
+        let version = reader.u32()?;
+        // versions start at 1, so 0 is as unreadable as a newer one
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let mut chunks = HashMap::new();
+        while !reader.is_empty() {
+            let mut tag = [0; 4];
+            reader.fill(&mut tag)?;
+            let version = reader.u16()?;
+            let len = reader.u32()? as usize;
+            chunks.insert(tag, (version, reader.bytes(len)?));
+        }
+        Ok(Chunks { chunks })
+    }
+
+    /// Return a reader for the given chunk, if it exists and its version is
+    /// between 1 and the newest one this build understands
+    fn get(&self, tag: &'static [u8; 4], newest: u16)
+            -> Result<Option<(u16, Reader<'a>)>, StateError> {
+        match self.chunks.get(tag) {
+            Some(&(version, _)) if version == 0 || version > newest =>
+                Err(StateError::UnsupportedChunkVersion(chunk_name(tag).to_string(), version)),
+            Some(&(version, data)) => Ok(Some((version, Reader::new(data)))),
+            None => Ok(None),
+        }
+    }
+
+    fn require(&self, tag: &'static [u8; 4], newest: u16)
+            -> Result<(u16, Reader<'a>), StateError> {
+        self.get(tag, newest)?.ok_or(StateError::MissingChunk(chunk_name(tag)))
+    }
+}
+
+/// The name of a chunk for error messages, i.e. its tag without the padding
+fn chunk_name(tag: &'static [u8; 4]) -> &'static str {
+    str::from_utf8(tag).expect("chunk tags are ASCII").trim()
+}
+
+fn write_chunk(out: &mut Vec<u8>, tag: &[u8; 4], version: u16, chunk: Writer) {
+    out.extend_from_slice(tag);
+    let mut header = Writer::new();
+    header.u16(version);
+    header.u32(chunk.data.len() as u32);
+    out.extend_from_slice(&header.data);
+    out.extend_from_slice(&chunk.data);
+}
+
+impl CPUWrapper {
+    /// Save the state of the emulator. The BIOS and ROM aren't included, so
+    /// the same game needs to be loaded to restore it
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&[FORMAT_VERSION as u8, 0, 0, 0]);
+        write_chunk(&mut out, CPU_TAG, CPU_VERSION, CpuChunk::save(self));
+        write_chunk(&mut out, RAM_TAG, RAM_VERSION, RamChunk::save(&self.cpu.mem));
+        write_chunk(&mut out, IO_TAG, IO_VERSION, IoChunk::save(&self.cpu.mem));
+        write_chunk(&mut out, PPU_TAG, PPU_VERSION, PpuChunk::save(&self.cpu.mem));
+        write_chunk(&mut out, APU_TAG, APU_VERSION, ApuChunk::save(&self.cpu.mem));
+        write_chunk(&mut out, CART_TAG, CART_VERSION, CartChunk::save(&self.cpu.mem));
+        write_chunk(&mut out, BACKUP_TAG, BACKUP_VERSION, BackupChunk::save(&self.cpu.mem));
+        out
+    }
+
+    /// Restore a state produced by save_state(), possibly by an older build.
+    /// Debug settings (overrides, breakpoints etc.) are left as they are
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let chunks = Chunks::parse(data)?;
+        let (version, mut reader) = chunks.require(CPU_TAG, CPU_VERSION)?;
+        let cpu = CpuChunk::load(version, &mut reader)?;
+        let (version, mut reader) = chunks.require(RAM_TAG, RAM_VERSION)?;
+        let ram = RamChunk::load(version, &mut reader)?;
+        let (version, mut reader) = chunks.require(IO_TAG, IO_VERSION)?;
+        let io = IoChunk::load(version, &mut reader)?;
+        let (version, mut reader) = chunks.require(PPU_TAG, PPU_VERSION)?;
+        let ppu = PpuChunk::load(version, &mut reader)?;
+        let apu = match chunks.get(APU_TAG, APU_VERSION)? {
+            Some((version, mut reader)) => ApuChunk::load(version, &mut reader)?,
+            None => ApuChunk::default(),
+        };
+        if let Some((version, mut reader)) = chunks.get(CART_TAG, CART_VERSION)? {
+            CartChunk::load(version, &mut reader)?.check(&self.cpu.mem)?;
+        }
+        let backup = match chunks.get(BACKUP_TAG, BACKUP_VERSION)? {
+            Some((version, mut reader)) => BackupChunk::load(version, &mut reader)?,
+            None => BackupChunk::default(),
+        };
+
+        ram.apply(&mut self.cpu.mem);
+        ppu.apply(&mut self.cpu.mem);
+        io.apply(&mut self.cpu.mem);
+        self.cpu.mem.rebuild_parsed_state();
+        io.restore_internal(&mut self.cpu.mem);
+        apu.apply(&mut self.cpu.mem);
+        backup.apply(&mut self.cpu.mem);
+        // the pipeline is refilled from memory, so this has to come last
+        cpu.apply(self);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::REFRESH;
+    use cpu::status_reg::{CPUMode, InstructionSet};
+    use mem::backup::{EEPROM_512, FLASH_128K};
+
+    /// A THUMB loop in IWRAM that counts up r0 and stores it to EWRAM
+    pub fn running_gba() -> CPUWrapper {
+        let mut gba = CPUWrapper::new();
+        let code: [u16; 4] = [
+            0x3001, // add r0, #1
+            0x6008, // str r0, [r1]
+            0xE7FC, // b .-4
+            0x0000,
+        ];
+        for (i, ins) in code.iter().enumerate() {
+            gba.cpu.mem.set_halfword(0x3000000 + i as u32 * 2, *ins as u32);
+        }
+        gba.direct_boot_at(0x3000001);
+        gba.cpu.set_reg(1, 0x2000000);
+        gba.cpu.mem.set_halfword(0x4000000, 0x0403);
+        gba.cpu.mem.set_halfword(0x4000200, 0x0001);
+        gba.cpu.mem.set_word(0x40000A0, 0x04030201);
+        // channel 2 playing a tone
+        gba.cpu.mem.set_byte(0x4000084, 0x80);
+        gba.cpu.mem.set_halfword(0x4000068, 0xF080);
+        gba.cpu.mem.set_halfword(0x400006C, 0x8700);
+        // DMA 3 waiting for vblank
+        gba.cpu.mem.set_word(0x40000D4, 0x3000000);
+        gba.cpu.mem.set_word(0x40000D8, 0x2000100);
+        gba.cpu.mem.set_word(0x40000DC, 0x9000_0004);
+        // timer 2 counting every 64 cycles
+        gba.cpu.mem.set_word(0x4000108, 0x0081_1234);
+        for _ in 0..1001 {
+            gba.step().unwrap();
+        }
+        gba
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut gba = running_gba();
+        let state = gba.save_state();
+        assert_eq!(&state[..4], b"GBAS");
+
+        let mut restored = CPUWrapper::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.r, gba.cpu.r);
+        assert_eq!(restored.cpu.cpsr.isa, InstructionSet::THUMB);
+        assert_eq!(restored.cycles, gba.cycles);
+        assert_eq!(restored.cpu.mem.graphics.disp_cnt.bg_mode, 3);
+        assert!(restored.cpu.mem.int.enabled.vblank);
+        assert_eq!(restored.cpu.mem.sound.fifos[0].len(), 4);
+        #[cfg(feature = "audio")]
+        assert_eq!(restored.cpu.mem.sound.fifos[0].samples(), vec![1, 2, 3, 4]);
+        assert_eq!(restored.pipeline_depth(), gba.pipeline_depth());
+        assert!(restored.cpu.mem.apu.squares[1].enabled);
+        assert_eq!(restored.cpu.mem.apu.squares[1].position, gba.cpu.mem.apu.squares[1].position);
+        assert!(restored.cpu.mem.dma.is_enabled(3));
+        assert_eq!(restored.cpu.mem.dma.channels[3].internal_regs(),
+                   gba.cpu.mem.dma.channels[3].internal_regs());
+        assert_ne!(gba.cpu.mem.get_halfword(0x4000108), 0x1234);
+        assert_eq!(restored.cpu.mem.get_halfword(0x4000108), gba.cpu.mem.get_halfword(0x4000108));
+        assert_eq!(restored.cpu.mem.timers.timers[2].ticks, gba.cpu.mem.timers.timers[2].ticks);
+
+        // both carry on exactly the same
+        for _ in 0..500 {
+            gba.step().unwrap();
+            restored.step().unwrap();
+        }
+        assert_eq!(restored.cpu.r, gba.cpu.r);
+        assert_eq!(restored.cpu.mem.get_word(0x2000000), gba.cpu.mem.get_word(0x2000000));
+        assert_eq!(restored.save_state(), gba.save_state());
+    }
+
+    #[test]
+    fn backup() {
+        let mut gba = running_gba();
+        gba.cpu.mem.raw.sram.set_banks(4);
+        gba.cpu.mem.raw.sram.select_bank(2);
+        let flash = &mut gba.cpu.mem.raw.flash;
+        flash.set_size(FLASH_128K);
+        // switch to bank 1, enter ID mode, and start unlocking the chip
+        for &(offset, val) in [(0x5555, 0xAA), (0x2AAA, 0x55), (0x5555, 0xB0), (0, 1),
+                               (0x5555, 0xAA), (0x2AAA, 0x55), (0x5555, 0x90),
+                               (0x5555, 0xAA)].iter() {
+            flash.write(offset, val);
+        }
+        assert_eq!(flash.internal_state(), (1, false, 0, true, 1));
+        let state = gba.save_state();
+
+        let mut restored = CPUWrapper::new();
+        restored.cpu.mem.raw.sram.set_banks(4);
+        restored.cpu.mem.raw.flash.set_size(FLASH_128K);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.mem.raw.sram.bank(), 2);
+        assert_eq!(restored.cpu.mem.raw.flash.internal_state(), (1, false, 0, true, 1));
+        assert_eq!(restored.save_state(), state);
+
+        // EEPROM partway through reading back a block, which was written
+        let mut gba = running_gba();
+        let eeprom = &mut gba.cpu.mem.raw.eeprom;
+        eeprom.set_size(EEPROM_512);
+        let block = 0xDEADBEEF_01234567u64;
+        let mut write = vec![1, 0, 0, 0, 0, 0, 1, 1];
+        write.extend((0..64).rev().map(|i| (block >> i) as u32 & 1));
+        write.push(0);
+        for bit in write.iter().chain([1, 1, 0, 0, 0, 0, 1, 1, 0].iter()) {
+            eeprom.write_bit(*bit);
+        }
+        for _ in 0..20 {
+            eeprom.read_bit();
+        }
+        let state = gba.save_state();
+        let mut restored = CPUWrapper::new();
+        restored.cpu.mem.raw.eeprom.enable();
+        restored.load_state(&state).unwrap();
+        let restored_eeprom = &restored.cpu.mem.raw.eeprom;
+        assert_eq!(restored_eeprom.size(), EEPROM_512);
+        assert_eq!(restored_eeprom.export(), gba.cpu.mem.raw.eeprom.export());
+        assert_eq!(restored_eeprom.internal_state(), gba.cpu.mem.raw.eeprom.internal_state());
+        let rest: Vec<u16> = (0..48).map(|_| restored_eeprom.read_bit()).collect();
+        let expected: Vec<u16> = (0..48).rev().map(|i| (block >> i) as u16 & 1).collect();
+        assert_eq!(rest, expected);
+
+        // states from before the chunk existed load with the chips reset
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&[1, 0, 0, 0]);
+        write_chunk(&mut state, CPU_TAG, 1, CpuChunk::save(&gba));
+        write_chunk(&mut state, RAM_TAG, 1, RamChunk::save(&gba.cpu.mem));
+        write_chunk(&mut state, IO_TAG, 1, IoChunk::save(&gba.cpu.mem));
+        write_chunk(&mut state, PPU_TAG, 1, PpuChunk::save(&gba.cpu.mem));
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.mem.raw.sram.bank(), 0);
+        assert_eq!(restored.cpu.mem.raw.flash.internal_state(), (0, false, 0, false, 0));
+    }
+
+    #[test]
+    fn older_states() {
+        // a state from before the APU chunk existed, with an IO chunk that
+        // ends before fields added later
+        let gba = running_gba();
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&[1, 0, 0, 0]);
+        write_chunk(&mut state, CPU_TAG, 1, CpuChunk::save(&gba));
+        write_chunk(&mut state, RAM_TAG, 1, RamChunk::save(&gba.cpu.mem));
+        let mut io = Writer::new();
+        io.bytes(&gba.cpu.mem.raw.io);
+        write_chunk(&mut state, IO_TAG, 1, io);
+        write_chunk(&mut state, PPU_TAG, 1, PpuChunk::save(&gba.cpu.mem));
+
+        let mut restored = CPUWrapper::new();
+        restored.cpu.mem.sound.fifos[0].push(9);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.r, gba.cpu.r);
+        assert_eq!(restored.cpu.mem.sound.fifos[0].len(), 0);
+        assert_eq!(restored.cpu.mem.graphics.disp_cnt.bg_mode, 3);
+    }
+
+    #[test]
+    fn newer_states() {
+        let gba = running_gba();
+        let mut state = gba.save_state();
+        // chunks added by newer builds are skipped
+        write_chunk(&mut state, b"TIMR", 1, Writer::new());
+        let mut restored = CPUWrapper::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.r, gba.cpu.r);
+
+        // but a known chunk whose meaning changed can't be read
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&[1, 0, 0, 0]);
+        write_chunk(&mut state, CPU_TAG, CPU_VERSION + 1, CpuChunk::save(&gba));
+        assert_eq!(restored.load_state(&state),
+            Err(StateError::UnsupportedChunkVersion("CPU".to_string(), CPU_VERSION + 1)));
+        let mut state = gba.save_state();
+        state[4] = 2;
+        assert_eq!(restored.load_state(&state), Err(StateError::UnsupportedVersion(2)));
+
+        // as can version 0 of either, which no build has written
+        state[4] = 0;
+        assert_eq!(restored.load_state(&state), Err(StateError::UnsupportedVersion(0)));
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&[1, 0, 0, 0]);
+        write_chunk(&mut state, CPU_TAG, 0, CpuChunk::save(&gba));
+        assert_eq!(restored.load_state(&state),
+            Err(StateError::UnsupportedChunkVersion("CPU".to_string(), 0)));
+    }
+
+    #[test]
+    fn errors() {
+        let mut gba = running_gba();
+        let r = gba.cpu.r;
+        let state = gba.save_state();
+        assert_eq!(gba.load_state(b"MZ"), Err(StateError::NotSaveState));
+        assert_eq!(gba.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(gba.load_state(&state[..8]), Err(StateError::MissingChunk("CPU")));
+        let mut no_ppu = state[..8].to_vec();
+        write_chunk(&mut no_ppu, CPU_TAG, CPU_VERSION, CpuChunk::save(&gba));
+        write_chunk(&mut no_ppu, RAM_TAG, RAM_VERSION, RamChunk::save(&gba.cpu.mem));
+        write_chunk(&mut no_ppu, IO_TAG, IO_VERSION, IoChunk::save(&gba.cpu.mem));
+        assert_eq!(gba.load_state(&no_ppu), Err(StateError::MissingChunk("PPU")));
+        // nothing is changed by a failed load
+        assert_eq!(gba.cpu.r, r);
+
+        let mut rom = vec![0; 0xC0];
+        rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+        gba.cpu.mem.load_rom(rom.clone());
+        let state = gba.save_state();
+        rom[0xAC..0xB0].copy_from_slice(b"BPEE");
+        gba.cpu.mem.load_rom(rom);
+        assert_eq!(gba.load_state(&state), Err(StateError::WrongGame("AXVE".to_string())));
+    }
+
+    /// Save the state of a running GBA after breaking it with f, and check
+    /// that loading it fails with the given error and changes nothing
+    fn check_invalid<F: FnOnce(&mut CPUWrapper)>(f: F, what: &'static str) {
+        let mut gba = running_gba();
+        let r = gba.cpu.r;
+        let mut broken = running_gba();
+        f(&mut broken);
+        assert_eq!(gba.load_state(&broken.save_state()), Err(StateError::InvalidValue(what)));
+        assert_eq!(gba.cpu.r, r);
+    }
+
+    #[test]
+    fn invalid_values() {
+        check_invalid(|gba| gba.cpu.cpsr.mode = CPUMode::INVALID, "CPU mode");
+        check_invalid(|gba| gba.cpu.spsr_irq.mode = CPUMode::INVALID, "CPU mode");
+        check_invalid(|gba| gba.cycles = REFRESH, "frame cycle count");
+        check_invalid(|gba| gba.cpu.mem.apu.squares[1].position = 8, "PSG channel position");
+        check_invalid(|gba| gba.cpu.mem.apu.wave.position = 64, "PSG channel position");
+        check_invalid(|gba| gba.cpu.mem.apu.noise.envelope.volume = 16, "PSG envelope volume");
+        check_invalid(|gba| gba.cpu.mem.apu.sequencer_step = 8, "frame sequencer step");
+        check_invalid(|gba| {
+            gba.cpu.mem.set_halfword(0x4000106, 0x0081);
+            gba.cpu.mem.timers.timers[1].ticks = 64;
+        }, "timer tick count");
+
+        let gba = running_gba();
+        let mut cpu = CpuChunk::save(&gba).data;
+        // after the 31 registers, 6 PSRs and should_flush
+        cpu[31 * 4 + 6 * 4 + 1] = 3;
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&[1, 0, 0, 0]);
+        write_chunk(&mut state, CPU_TAG, CPU_VERSION, Writer { data: cpu });
+        assert_eq!(CPUWrapper::new().load_state(&state),
+            Err(StateError::InvalidValue("pipeline depth")));
+    }
+}
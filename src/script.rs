@@ -0,0 +1,195 @@
+//! Frame scripts: a callback run after every frame, for automating a game
+//! without changing the emulator (e.g. bots, randomizer logic, or
+//! accessibility aids that read the game's state and press buttons). A
+//! script only gets a ScriptHandle, which can read any memory but only write
+//! to the work RAM, and can hold buttons down. Everything else (the IO
+//! registers, VRAM, the CPU) is off limits, so a script can't leave the
+//! emulator in a state the game couldn't have put it in.
+//!
+//! The keypad isn't emulated, so the buttons a script holds are the only
+//! ones the game sees: they're written straight to KEYINPUT.
+//!
+//! A script's reads and writes never hit watchpoints, since they aren't made
+//! by the game.
+
+use std::fmt;
+use cpu::CPUWrapper;
+use mem::canonicalize_addr;
+use mem::addrs::{EWRAM_START, EWRAM_END, IWRAM_START, IWRAM_END, IO_START};
+use mem::io::addrs::KEYINPUT;
+use mem::watch::Source;
+/// one bit for each of config::BUTTONS
+pub const ALL_BUTTONS: u16 = 0x3FF;
+
+pub type FrameScript = Box<dyn FnMut(&mut ScriptHandle)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    /// scripts can only write to EWRAM and IWRAM
+    NotWritable(u32),
+    /// halfword and word writes must be aligned
+    Misaligned(u32),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScriptError::NotWritable(addr) =>
+                write!(f, "scripts can't write to {:#010X}, only to work RAM", addr),
+            ScriptError::Misaligned(addr) =>
+                write!(f, "script write to {:#010X} isn't aligned", addr),
+        }
+    }
+}
+
+pub struct ScriptHandle<'a> {
+    gba: &'a mut CPUWrapper,
+}
+
+impl<'a> ScriptHandle<'a> {
+    pub fn new(gba: &'a mut CPUWrapper) -> ScriptHandle<'a> {
+        ScriptHandle { gba }
+    }
+
+    pub fn read_byte(&self, addr: u32) -> u8 {
+        self.gba.cpu.mem.get_byte_by(addr, Source::Script)
+    }
+
+    pub fn read_halfword(&self, addr: u32) -> u16 {
+        self.gba.cpu.mem.get_halfword_by(addr, Source::Script)
+    }
+
+    pub fn read_word(&self, addr: u32) -> u32 {
+        self.gba.cpu.mem.get_word_by(addr, Source::Script)
+    }
+
+    pub fn write_byte(&mut self, addr: u32, val: u8) -> Result<(), ScriptError> {
+        check_write(addr, 1)?;
+        self.gba.cpu.mem.set_byte_by(addr, val, Source::Script);
+        Ok(())
+    }
+
+    pub fn write_halfword(&mut self, addr: u32, val: u16) -> Result<(), ScriptError> {
+        check_write(addr, 2)?;
+        self.gba.cpu.mem.set_halfword_by(addr, val as u32, Source::Script);
+        Ok(())
+    }
+
+    pub fn write_word(&mut self, addr: u32, val: u32) -> Result<(), ScriptError> {
+        check_write(addr, 4)?;
+        self.gba.cpu.mem.set_word_by(addr, val, Source::Script);
+        Ok(())
+    }
+
+    /// Return the buttons being held, with a bit set for each pressed button
+    /// in the order of config::BUTTONS
+    pub fn buttons(&self) -> u16 {
+        !self.gba.cpu.mem.raw.get_halfword(KEYINPUT) & ALL_BUTTONS
+    }
+
+    /// Hold down exactly the given buttons until this is next called
+    pub fn set_buttons(&mut self, pressed: u16) {
+        let idx = (KEYINPUT - IO_START) as usize;
+        let keyinput = !pressed & ALL_BUTTONS;
+        self.gba.cpu.mem.raw.io[idx] = keyinput as u8;
+        self.gba.cpu.mem.raw.io[idx + 1] = (keyinput >> 8) as u8;
+    }
+}
+
+/// Check that a script can write size bytes at the given address
+fn check_write(addr: u32, size: u32) -> Result<(), ScriptError> {
+    if addr % size != 0 {
+        return Err(ScriptError::Misaligned(addr));
+    }
+    match canonicalize_addr(addr) {
+        EWRAM_START...EWRAM_END | IWRAM_START...IWRAM_END => Ok(()),
+        _ => Err(ScriptError::NotWritable(addr)),
+    }
+}
+
+impl CPUWrapper {
+    /// Set the script to run after each frame, or remove it with None
+    pub fn set_frame_script(&mut self, script: Option<FrameScript>) {
+        self.frame_script = script;
+    }
+
+    /// Run the frame script, if there is one. Called by step at the end of
+    /// each frame, between instructions
+    pub fn run_frame_script(&mut self) {
+        let mut script = match self.frame_script.take() {
+            Some(script) => script,
+            None => return,
+        };
+        script(&mut ScriptHandle::new(self));
+        // the script may have been replaced while it ran
+        if self.frame_script.is_none() {
+            self.frame_script = Some(script);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::REFRESH;
+
+    #[test]
+    fn bounds() {
+        let mut gba = CPUWrapper::new();
+        let mut handle = ScriptHandle::new(&mut gba);
+        assert_eq!(handle.write_word(0x2000000, 0x12345678), Ok(()));
+        // mirrors of work RAM are fine
+        assert_eq!(handle.write_byte(0x3008001, 0x9A), Ok(()));
+        assert_eq!(handle.read_byte(0x3000001), 0x9A);
+        assert_eq!(handle.read_word(0x2000000), 0x12345678);
+
+        assert_eq!(handle.write_halfword(0x4000000, 3), Err(ScriptError::NotWritable(0x4000000)));
+        assert_eq!(handle.write_byte(0x6000000, 3), Err(ScriptError::NotWritable(0x6000000)));
+        assert_eq!(handle.write_word(0x2000002, 3), Err(ScriptError::Misaligned(0x2000002)));
+        assert_eq!(handle.read_halfword(0x4000000), 0);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.watch.add(0x2000000, 4, true, true);
+        {
+            let mut handle = ScriptHandle::new(&mut gba);
+            handle.write_word(0x2000000, 1).unwrap();
+            handle.read_byte(0x2000001);
+            handle.read_halfword(0x2000002);
+        }
+        assert!(!gba.cpu.mem.watch.is_hit());
+        gba.cpu.mem.get_byte(0x2000000);
+        assert!(gba.cpu.mem.watch.is_hit());
+    }
+
+    #[test]
+    fn frame_script() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_word(0x3000000, 0xEAFFFFFE); // b 0x3000000
+        gba.direct_boot_at(0x3000000);
+        gba.set_frame_script(Some(Box::new(|handle: &mut ScriptHandle| {
+            let frames = handle.read_byte(0x2000000);
+            handle.write_byte(0x2000000, frames + 1).unwrap();
+            // hold A and start
+            handle.set_buttons(0b1001);
+        })));
+
+        gba.cycles = REFRESH - 1;
+        while !gba.step().unwrap() {}
+        assert_eq!(gba.cpu.mem.get_byte(0x2000000), 1);
+        // what the game reads
+        assert_eq!(gba.cpu.mem.get_halfword(0x4000130), 0x3F6);
+        assert_eq!(ScriptHandle::new(&mut gba).buttons(), 0b1001);
+
+        // the script is kept for the next frame, and only runs once per frame
+        gba.frame().unwrap();
+        gba.step().unwrap();
+        assert_eq!(gba.cpu.mem.get_byte(0x2000000), 2);
+
+        gba.set_frame_script(None);
+        gba.frame().unwrap();
+        assert_eq!(gba.cpu.mem.get_byte(0x2000000), 2);
+    }
+}
@@ -0,0 +1,393 @@
+//! A quick check that the emulator works in the browser it's running in, for
+//! players to run when a game misbehaves and attach to their bug report. Each
+//! check runs a small synthetic program or setup that exercises one part of
+//! the hardware, using nothing but IWRAM so that it works without a ROM or
+//! BIOS, and compares the result with what the hardware would produce.
+//!
+//! The checks run on the emulator itself, since a second one wouldn't fit in
+//! the stack, so its state is saved first and restored afterwards. Only the
+//! state kept in a save state is restored: the debugging statistics and logs
+//! include whatever the checks did.
+
+use std::fmt;
+use cpu::CPUWrapper;
+use cpu::bios::IrqDispatch;
+use cpu::status_reg::InstructionSet;
+use mem::backup::{Eeprom, Flash, Sram};
+use mem::tilt::TiltSensor;
+use mem::watch::Watchpoints;
+use savestate::StateError;
+#[cfg(feature = "render")]
+use mem::framebuffer::{HEIGHT, WIDTH};
+#[cfg(feature = "render")]
+use util::crc32;
+
+/// how many instructions a check's program can run for before it's
+/// considered stuck
+const MAX_STEPS: u32 = 1000;
+
+/// Sets up and runs a check on an emulator with cleared memory
+type Check = fn(&mut CPUWrapper) -> Outcome;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// with a description of what was wrong
+    Failed(String),
+    /// the check can't be run in this build, for the given reason
+    Skipped(&'static str),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    /// Return true if none of the checks failed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| match result.outcome {
+            Outcome::Failed(_) => false,
+            _ => true,
+        })
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut features = Vec::new();
+        if cfg!(feature = "render") {
+            features.push("render");
+        }
+        if cfg!(feature = "audio") {
+            features.push("audio");
+        }
+        if cfg!(feature = "debugger") {
+            features.push("debugger");
+        }
+        writeln!(f, "self test {}", if self.passed() { "passed" } else { "FAILED" })?;
+        writeln!(f, "build: {} ({})", env!("CARGO_PKG_VERSION"), features.join(", "))?;
+        for result in self.results.iter() {
+            match result.outcome {
+                Outcome::Passed => writeln!(f, "  {}: ok", result.name)?,
+                Outcome::Failed(ref why) => writeln!(f, "  {}: FAILED ({})", result.name, why)?,
+                Outcome::Skipped(why) => writeln!(f, "  {}: skipped ({})", result.name, why)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CPUWrapper {
+    /// Run every check and return the results. Whatever was running is
+    /// saved and restored around the checks, which fails if the restored
+    /// state doesn't load
+    pub fn self_test(&mut self) -> Result<SelfTestReport, StateError> {
+        let state = self.save_state();
+        let irq_dispatch = self.cpu.bios.irq_dispatch;
+        // the checks shouldn't set off the game's watchpoints
+        let watch = std::mem::replace(&mut self.cpu.mem.watch, Watchpoints::new());
+        // clearing memory resets the cart's save chips and tilt sensor, which
+        // are set aside instead so that they come back exactly as they were
+        let raw = &mut self.cpu.mem.raw;
+        let sram = std::mem::replace(&mut raw.sram, Sram::new());
+        let flash = std::mem::replace(&mut raw.flash, Flash::new());
+        let eeprom = std::mem::replace(&mut raw.eeprom, Eeprom::new());
+        let tilt = std::mem::replace(&mut raw.tilt, TiltSensor::new());
+        let checks: [(&'static str, Check); 5] = [
+            ("cpu", check_cpu),
+            ("dma", check_dma),
+            ("timers", check_timers),
+            ("irq", check_irq),
+            ("render", check_render),
+        ];
+        let results = checks.iter().map(|&(name, check)| {
+            self.cpu.mem.clear();
+            CheckResult { name, outcome: check(self) }
+        }).collect();
+        self.cpu.bios.irq_dispatch = irq_dispatch;
+        self.cpu.mem.watch = watch;
+        self.cpu.mem.clear();
+        let raw = &mut self.cpu.mem.raw;
+        raw.sram = sram;
+        raw.flash = flash;
+        raw.eeprom = eeprom;
+        raw.tilt = tilt;
+        self.load_state(&state)?;
+        Ok(SelfTestReport { results })
+    }
+}
+
+/// Copy the given ARM code to the start of IWRAM and start running it
+fn load_program(gba: &mut CPUWrapper, code: &[u32]) {
+    for (i, ins) in code.iter().enumerate() {
+        gba.cpu.mem.set_word(0x3000000 + i as u32 * 4, *ins);
+    }
+    gba.direct_boot_at(0x3000000);
+}
+
+/// Run until the given address is reached
+fn run_until(gba: &mut CPUWrapper, addr: u32) -> Result<(), String> {
+    for _ in 0..MAX_STEPS {
+        let size = gba.cpu.instruction_size();
+        if gba.cpu.r[15].wrapping_sub(2 * size) == addr {
+            return Ok(());
+        }
+        gba.step().map_err(|err| err.to_string())?;
+    }
+    Err(format!("never reached {:#010X}", addr))
+}
+
+/// Compare the words starting at addr with the expected ones
+fn expect_words(gba: &CPUWrapper, addr: u32, expected: &[u32]) -> Outcome {
+    for (i, &want) in expected.iter().enumerate() {
+        let at = addr + i as u32 * 4;
+        let got = gba.cpu.mem.get_word(at);
+        if got != want {
+            return Outcome::Failed(format!("{:#010X} is {:#010X} instead of {:#010X}", at, got, want));
+        }
+    }
+    Outcome::Passed
+}
+
+/// Arithmetic, flags and conditions, multiplies, loads and stores of each
+/// size, a call and return, and switching to THUMB. Results are stored
+/// from 0x3000400
+fn check_cpu(gba: &mut CPUWrapper) -> Outcome {
+    let code: [u32; 26] = [
+        0xE3A00005, // mov r0, #5
+        0xE3A01007, // mov r1, #7
+        0xE0020190, // mul r2, r0, r1
+        0xE0822201, // add r2, r2, r1, lsl #4
+        0xE3E03000, // mvn r3, #0
+        0xE2933001, // adds r3, r3, #1
+        0x23A04001, // movcs r4, #1
+        0x03A05002, // moveq r5, #2
+        0xE3E08000, // mvn r8, #0
+        0xE0876898, // umull r6, r7, r8, r8
+        0xE3A09403, // mov r9, #0x3000000
+        0xE2899B01, // add r9, r9, #0x400
+        0xE8A900F4, // stmia r9!, {r2, r4-r7}
+        0xE5C98000, // strb r8, [r9]
+        0xE1D9A0D0, // ldrsb r10, [r9]
+        0xE1C920B2, // strh r2, [r9, #2]
+        0xE599B000, // ldr r11, [r9]
+        0xEB000005, // bl sub
+        0xE8A90C01, // stmia r9!, {r0, r10, r11}
+        0xE28FC001, // add r12, pc, #1
+        0xE12FFF1C, // bx r12
+        0x008020C8, // movs r0, #200; lsls r0, r0, #2
+        0x46493801, // subs r0, #1; mov r1, r9
+        0xE7FE6008, // str r0, [r1]; b .
+        // sub:
+        0xE2800001, // add r0, r0, #1
+        0xE12FFF1E, // bx lr
+    ];
+    load_program(gba, &code);
+    if let Err(why) = run_until(gba, 0x300005E) {
+        return Outcome::Failed(why);
+    }
+    if gba.cpu.cpsr.isa != InstructionSet::THUMB {
+        return Outcome::Failed("didn't switch to THUMB".to_string());
+    }
+    expect_words(gba, 0x3000400, &[147, 1, 2, 1, 0xFFFFFFFE, 6, 0xFFFFFFFF, 0x009300FF, 799])
+}
+
+/// Copies with DMA 3 that go up, go down, and fill from a fixed source
+fn check_dma(gba: &mut CPUWrapper) -> Outcome {
+    load_program(gba, &[0xEAFFFFFE]); // b .
+    for i in 0..8 {
+        gba.cpu.mem.set_word(0x3001000 + i * 4, 0x01010101 * (i + 1));
+    }
+    let transfers = [
+        // 8 words up into EWRAM
+        (0x3001000, 0x2000000, 0x8400_0008),
+        // 8 words down, which keeps them in the same order
+        (0x300101C, 0x200003C, 0x84A0_0008),
+        // the first halfword 16 times
+        (0x3001000, 0x2000040, 0x8100_0010),
+    ];
+    for &(src, dest, cnt) in transfers.iter() {
+        gba.cpu.mem.set_word(0x40000D4, src);
+        gba.cpu.mem.set_word(0x40000D8, dest);
+        gba.cpu.mem.set_word(0x40000DC, cnt);
+        if let Err(err) = gba.step() {
+            return Outcome::Failed(err.to_string());
+        }
+    }
+    let mut expected: Vec<u32> = (1..9).chain(1..9).map(|i| 0x01010101 * i).collect();
+    expected.extend_from_slice(&[0x01010101; 8]);
+    expect_words(gba, 0x2000000, &expected)
+}
+
+/// Timer 1 counting up from timer 0's overflows, with a program that waits
+/// for timer 1's overflow interrupt flag. Timer 0 overflows every 64 cycles
+/// and timer 1 on the third of those
+fn check_timers(gba: &mut CPUWrapper) -> Outcome {
+    load_program(gba, &[
+        0xE3A01301, // mov r1, #0x4000000
+        0xE2811C02, // add r1, r1, #0x200
+        // wait:
+        0xE1D100B2, // ldrh r0, [r1, #2]
+        0xE3100010, // tst r0, #0x10
+        0x0AFFFFFC, // beq wait
+        0xEAFFFFFE, // b .
+    ]);
+    let mem = &mut gba.cpu.mem;
+    mem.set_word(0x4000104, 0x00C4_FFFD); // timer 1 counts up, with an IRQ
+    mem.set_word(0x4000100, 0x0080_FFC0); // timer 0 counts every cycle
+    let start = gba.total_cycles;
+    if let Err(why) = run_until(gba, 0x3000014) {
+        return Outcome::Failed(why);
+    }
+    let elapsed = gba.total_cycles - start;
+    if elapsed < 3 * 64 || elapsed > 3 * 64 + 32 {
+        return Outcome::Failed(format!("timer 1 overflowed after {} cycles instead of 192", elapsed));
+    }
+    let counter = gba.cpu.mem.get_halfword(0x4000104);
+    if counter != 0xFFFD {
+        return Outcome::Failed(format!("timer 1 is {:#06X} instead of 0xFFFD", counter));
+    }
+    Outcome::Passed
+}
+
+/// Run until the given number of frames have started
+fn run_frames(gba: &mut CPUWrapper, frames: u32) -> Result<(), String> {
+    for _ in 0..frames {
+        while !gba.step().map_err(|err| err.to_string())? {}
+    }
+    Ok(())
+}
+
+/// VBlank interrupts, dispatched through the emulated BIOS handler to a
+/// handler that counts them at 0x3000200 and acknowledges them
+fn check_irq(gba: &mut CPUWrapper) -> Outcome {
+    load_program(gba, &[
+        0xEAFFFFFE, // b .
+        // handler:
+        0xE3A03301, // mov r3, #0x4000000
+        0xE2833C02, // add r3, r3, #0x200
+        0xE3A02001, // mov r2, #1
+        0xE1C320B2, // strh r2, [r3, #2]
+        0xE3A00403, // mov r0, #0x3000000
+        0xE2800C02, // add r0, r0, #0x200
+        0xE5902000, // ldr r2, [r0]
+        0xE2822001, // add r2, r2, #1
+        0xE5802000, // str r2, [r0]
+        0xE12FFF1E, // bx lr
+    ]);
+    gba.cpu.bios.irq_dispatch = IrqDispatch::Hle;
+    gba.cpu.mem.set_word(0x3007FFC, 0x3000004);
+    gba.cpu.mem.set_halfword(0x4000004, 0x8); // VBlank IRQ
+    gba.cpu.mem.set_halfword(0x4000200, 0x1); // IE = VBlank
+    gba.cpu.mem.set_halfword(0x4000208, 0x1); // IME
+    if let Err(why) = run_frames(gba, 2) {
+        return Outcome::Failed(why);
+    }
+    if gba.cpu.mem.get_halfword(0x4000202) != 0 {
+        return Outcome::Failed("IF wasn't acknowledged".to_string());
+    }
+    if gba.cpu.cpsr.irq || gba.cpu.r[15] > 0x3000008 {
+        return Outcome::Failed("didn't return from the handler".to_string());
+    }
+    expect_words(gba, 0x3000200, &[2])
+}
+
+/// A BG made of a diagonal stripe pattern of the first 4 colors, which is
+/// compared with the expected image by hash
+#[cfg(feature = "render")]
+fn check_render(gba: &mut CPUWrapper) -> Outcome {
+    load_program(gba, &[0xEAFFFFFE]); // b .
+    let colors = [0x7C00, 0x03E0, 0x001F, 0x7FFF];
+    let mem = &mut gba.cpu.mem;
+    for (i, &color) in colors.iter().enumerate() {
+        mem.set_halfword(0x5000000 + i as u32 * 2, color);
+    }
+    // tile 1 (4 bit), where each pixel is color (x + y) % 4
+    for y in 0..8 {
+        let row = (0..8).fold(0, |row, x| row | ((x + y) % 4) << (4 * x));
+        mem.set_word(0x6000020 + y * 4, row);
+    }
+    // a map of tile 1 in screenblock 31
+    for i in 0..0x400 {
+        mem.set_halfword(0x600F800 + i * 2, 1);
+    }
+    mem.set_halfword(0x4000008, 0x1F00); // BG0CNT
+    mem.set_halfword(0x4000000, 0x0100); // mode 0, BG0 on
+    if let Err(why) = run_frames(gba, 1) {
+        return Outcome::Failed(why);
+    }
+
+    let hash = |pixels: Vec<u16>| {
+        let bytes: Vec<u8> = pixels.iter().flat_map(|&pixel| vec![pixel as u8, (pixel >> 8) as u8]).collect();
+        crc32(&bytes)
+    };
+    let expected = hash((0..HEIGHT * WIDTH)
+        .map(|i| colors[(i / WIDTH + i % WIDTH) % 4] as u16)
+        .collect());
+    let got = hash(gba.cpu.mem.framebuffer.pixels.iter()
+        .flat_map(|row| row.iter().cloned())
+        .collect());
+    if got != expected {
+        return Outcome::Failed(format!("frame hash is {:08X} instead of {:08X}", got, expected));
+    }
+    Outcome::Passed
+}
+#[cfg(not(feature = "render"))]
+fn check_render(_gba: &mut CPUWrapper) -> Outcome {
+    Outcome::Skipped("built without the render feature")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mem::backup::EEPROM_512;
+
+    #[test]
+    fn self_test() {
+        let mut gba = CPUWrapper::new_direct_boot();
+        gba.cpu.mem.set_word(0x3000000, 0xEAFFFFFE); // b .
+        gba.cpu.mem.set_word(0x2000000, 1234);
+        gba.cpu.mem.raw.sram.set_banks(2);
+        gba.cpu.mem.raw.sram.select_bank(1);
+        gba.cpu.mem.raw.eeprom.set_size(EEPROM_512);
+        gba.cpu.mem.raw.eeprom.write_bit(1);
+        gba.direct_boot_at(0x3000000);
+        gba.step().unwrap();
+        let state = gba.save_state();
+
+        let report = gba.self_test().unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.results.len(), 5);
+        assert!(report.to_string().starts_with("self test passed\nbuild: "));
+        assert!(report.to_string().contains("\n  cpu: ok\n"));
+        // and the emulator is back where it was
+        assert_eq!(gba.save_state(), state);
+        assert_eq!(gba.cpu.mem.get_word(0x2000000), 1234);
+        // including the parts of the save chips that aren't in the state
+        assert_eq!(gba.cpu.mem.raw.sram.bank(), 1);
+        for bit in [1, 0, 0, 0, 0, 0, 0, 0].iter() {
+            gba.cpu.mem.raw.eeprom.write_bit(*bit);
+        }
+        assert_eq!(gba.cpu.mem.raw.eeprom.read_bit(), 0);
+    }
+
+    #[test]
+    fn failure() {
+        let report = SelfTestReport {
+            results: vec![
+                CheckResult { name: "cpu", outcome: Outcome::Passed },
+                CheckResult { name: "dma", outcome: Outcome::Failed("wrong".to_string()) },
+            ],
+        };
+        assert!(!report.passed());
+        assert!(report.to_string().starts_with("self test FAILED\n"));
+        assert!(report.to_string().ends_with("\n  cpu: ok\n  dma: FAILED (wrong)\n"));
+    }
+}
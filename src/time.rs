@@ -0,0 +1,69 @@
+//! All of the emulator's knowledge of the host's clocks comes from a
+//! TimeSource. In the browser this is performance.now() and Date.now(), but
+//! tests and deterministic runs (e.g. comparing two runs of the same input)
+//! can use a FixedTime instead, so that anything derived from the time is the
+//! same on every run.
+
+pub trait TimeSource {
+    /// Milliseconds from a monotonic clock with an arbitrary starting point,
+    /// used for frame pacing and measuring how long frames take
+    fn now_ms(&mut self) -> f64;
+    /// Milliseconds since the Unix epoch, which is what a cartridge real time
+    /// clock would be set from. There is no RTC emulation yet
+    fn date_ms(&mut self) -> f64;
+}
+
+/// A clock that only moves forward by a fixed step each time it's read, or
+/// when advanced explicitly
+pub struct FixedTime {
+    now_ms: f64,
+    /// the date when now_ms was 0
+    start_date_ms: f64,
+    step_ms: f64,
+}
+
+impl FixedTime {
+    pub fn new(start_date_ms: f64, step_ms: f64) -> FixedTime {
+        FixedTime { now_ms: 0.0, start_date_ms, step_ms }
+    }
+
+    pub fn advance(&mut self, ms: f64) {
+        self.now_ms += ms;
+    }
+}
+
+impl TimeSource for FixedTime {
+    fn now_ms(&mut self) -> f64 {
+        let now = self.now_ms;
+        self.now_ms += self.step_ms;
+        now
+    }
+
+    fn date_ms(&mut self) -> f64 {
+        self.start_date_ms + self.now_ms
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::pacing::{Pacing, FRAME_BUDGET_MS};
+
+    #[test]
+    fn fixed_time() {
+        let mut time = FixedTime::new(1_500_000_000_000.0, 10.0);
+        assert_eq!(time.now_ms(), 0.0);
+        assert_eq!(time.now_ms(), 10.0);
+        time.advance(100.0);
+        assert_eq!(time.date_ms(), 1_500_000_000_120.0);
+
+        // pacing driven by a fixed clock always makes the same decisions
+        let run = || {
+            let mut time = FixedTime::new(0.0, FRAME_BUDGET_MS / 2.0);
+            let mut pacing = Pacing::new();
+            (0..10).map(|_| pacing.frames_due(time.now_ms())).collect::<Vec<_>>()
+        };
+        assert_eq!(run(), vec![1, 0, 1, 0, 1, 0, 1, 0, 1, 0]);
+        assert_eq!(run(), run());
+    }
+}
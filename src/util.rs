@@ -45,6 +45,18 @@ pub fn to_float_word(raw: u32) -> f32 {
     (int as i32 as f32) + frac
 }
 
+/// CRC-32 as used by PNG, zip and UPS patches
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -59,4 +71,9 @@ mod test {
         assert_eq!(to_float_word(0xFF_FFFF_00), -1.0);
         assert_eq!(to_float_word(0x00_0002_80), 2.5);
     }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
 }
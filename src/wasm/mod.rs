@@ -0,0 +1,1519 @@
+// TODO: can we only compile this file when we build for wasm?
+pub mod types;
+
+use compat::{Cause, Fingerprint};
+use config::{Settings, is_valid_speed};
+use cpu::CPUWrapper;
+use cpu::bios::IrqDispatch;
+use cpu::invalid_opcode::OpcodePolicy;
+use cpu::pacing::REFRESH_HZ;
+use cpu::soft_reset::ComboAction;
+use cpu::unsupported::Policy;
+use error::{self, Error};
+use link;
+use link::network::{LinkMessage, LinkTransport, NetworkLink};
+#[cfg(feature = "debugger")]
+use mem::io::registers::IO_REGISTERS;
+use mem::RawMemory;
+use mem::backup::{BANK_SIZE, MAX_BANKS};
+use mem::framebuffer::{HEIGHT, RenderStats, WIDTH};
+use mem::overlay::OverlayKind;
+use mem::postprocess::{MAX_SCALE, OutputFormat, ScaleFilter};
+#[cfg(feature = "audio")]
+use mem::io::sound::SAMPLE_RATE;
+use savestate::delta;
+use script::{self, ScriptHandle};
+use time::{FixedTime, TimeSource};
+use wasm_bindgen::prelude::*;
+use console_error_panic_hook;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::panic;
+use self::types::{BranchEvent, CpuState, DebugEvent, DmaChannelStats, OpcodeCount, RenderLineStats,
+                  RunawayDmaEvent, SpriteState, StuckPollEvent, SwiEvent,
+                  WatchHitEvent};
+#[cfg(feature = "debugger")]
+use self::types::IoRegisterState;
+#[cfg(feature = "render")]
+use self::types::RenderDiff;
+#[cfg(all(feature = "render", feature = "debugger"))]
+use self::types::{BgMapImage, PixelInfo};
+
+pub static mut GBA: CPUWrapper = CPUWrapper::new();
+
+/// Settings that the frontend saves between sessions, see config
+pub static mut SETTINGS: Settings = Settings::new();
+
+/// The clock used for everything that depends on the host's time. None means
+/// the browser's clocks
+static mut TIME: Option<Box<dyn TimeSource>> = None;
+
+/// The browser's clocks
+struct JsTime;
+
+impl TimeSource for JsTime {
+    fn now_ms(&mut self) -> f64 {
+        now()
+    }
+
+    fn date_ms(&mut self) -> f64 {
+        date_now()
+    }
+}
+
+fn time() -> &'static mut dyn TimeSource {
+    unsafe {
+        if TIME.is_none() {
+            TIME = Some(Box::new(JsTime));
+        }
+        TIME.as_mut().unwrap().as_mut()
+    }
+}
+
+/// Replace the host's clocks with one that starts at the given date (in
+/// milliseconds since the Unix epoch) and moves forward by step_ms each time
+/// it's read, so that runs with the same input are reproducible
+#[wasm_bindgen]
+pub fn set_fixed_time(start_date_ms: f64, step_ms: f64) {
+    unsafe { TIME = Some(Box::new(FixedTime::new(start_date_ms, step_ms))) }
+}
+
+/// Go back to using the browser's clocks after set_fixed_time
+#[wasm_bindgen]
+pub fn use_host_time() {
+    unsafe { TIME = Some(Box::new(JsTime)) }
+}
+
+/// Set once the emulator has panicked. The CPU state can't be trusted after
+/// that point, so any further calls into the emulator return this report
+/// instead of running
+static mut CRASH_REPORT: Option<String> = None;
+/// Where the last panic happened, as file:line, which is recorded by the
+/// panic hook for the crash report's fingerprint
+static mut PANIC_SITE: Option<String> = None;
+/// The fingerprint of the panic that made CRASH_REPORT
+static mut CRASH_FINGERPRINT: Option<Fingerprint> = None;
+
+#[wasm_bindgen]
+extern {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(msg: &str);
+
+    #[wasm_bindgen(js_namespace = console)]
+    fn error(msg: &str);
+
+    #[wasm_bindgen(js_namespace = performance)]
+    fn now() -> f64;
+
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn date_now() -> f64;
+
+    /// Provided by the frontend to deliver a message to the other GBA over
+    /// the network
+    #[wasm_bindgen(js_name = gbaLinkSend)]
+    fn gba_link_send(seq: u32, data: u16);
+
+    /// Provided by the frontend to run after each frame while the frame
+    /// script is enabled (see set_frame_script_enabled)
+    #[wasm_bindgen(js_name = gbaFrameScript, catch)]
+    fn gba_frame_script(handle: FrameScriptHandle) -> Result<(), JsValue>;
+}
+
+// A macro to provide `println!(..)`-style syntax for `console.log` logging.
+macro_rules! log {
+    ($($t:tt)*) => (log(&format!($($t)*)))
+}
+
+macro_rules! error {
+    ($($t:tt)*) => (error(&format!($($t)*)))
+}
+
+/// should be called once to initialize panic hook. wasm builds abort on
+/// panic, so the hook is the only place the crash report can be made: the
+/// call into the emulator then traps, and the frontend reads the report
+/// with get_crash_report
+#[wasm_bindgen]
+pub fn set_panic_hook() {
+    panic::set_hook(Box::new(|inf| {
+        console_error_panic_hook::hook(inf);
+        if let Some(loc) = inf.location() {
+            unsafe { PANIC_SITE = Some(format!("{}:{}", loc.file(), loc.line())); }
+        }
+        error!("CPU dump:");
+        unsafe {
+            error!("Failed instruction: {:#?}", GBA.last_instruction.clone());
+            error!("CPSR: {:#?}", GBA.cpu.cpsr);
+            error!("User registers: {:#X?}", GBA.cpu.r);
+        }
+        record_crash(&panic_message(inf.payload()));
+    }));
+}
+
+/// Return the message a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Make the crash report for a panic with the given message, at the site
+/// recorded by the panic hook, and keep it and its fingerprint for
+/// get_crash_report and get_crash_fingerprint
+fn record_crash(msg: &str) -> String {
+    let mut report = unsafe { GBA.crash_report(msg) };
+    let site = unsafe { PANIC_SITE.take() }.unwrap_or_else(|| "unknown".to_string());
+    let fingerprint = unsafe { GBA.fingerprint(Some(&Cause::Panic(site))) };
+    report.push_str(&format!("fingerprint: {} ({})\n", fingerprint, fingerprint.key()));
+    if let Some(note) = fingerprint.note() {
+        report.push_str(&format!("known issue: {}\n", note));
+    }
+    error!("{}", report);
+    unsafe {
+        CRASH_REPORT = Some(report.clone());
+        CRASH_FINGERPRINT = Some(fingerprint);
+    }
+    report
+}
+
+/// The JS facing version of an emulator Error, which gets thrown as an
+/// exception. Fields that don't apply to this kind of error are undefined
+#[wasm_bindgen]
+pub struct EmulatorError {
+    err: Error,
+    fingerprint: Fingerprint,
+}
+
+#[wasm_bindgen]
+impl EmulatorError {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.err.kind().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.err.to_string()
+    }
+
+    /// Identifies the problem for bug reports, see compat
+    #[wasm_bindgen(getter)]
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint.to_string()
+    }
+
+    /// A note for the player if this is a known problem
+    #[wasm_bindgen(getter)]
+    pub fn note(&self) -> Option<String> {
+        self.fingerprint.note().map(|note| note.to_string())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> Option<u32> {
+        match self.err {
+            Error::InvalidOpcode { pc, .. } | Error::Unsupported { pc, .. } => Some(pc),
+            _ => None,
+        }
+    }
+
+    /// The kind of feature an Unsupported error needed (e.g. "Exception")
+    #[wasm_bindgen(getter)]
+    pub fn feature(&self) -> Option<String> {
+        match self.err {
+            Error::Unsupported { feature, .. } => Some(feature.kind().to_string()),
+            _ => None,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn raw(&self) -> Option<u32> {
+        match self.err {
+            Error::InvalidOpcode { raw, .. } => Some(raw),
+            Error::InvalidPsrMode { bits } => Some(bits),
+            _ => None,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn addr(&self) -> Option<u32> {
+        match self.err {
+            Error::UnmappedAccess { addr, .. } => Some(addr),
+            _ => None,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn access(&self) -> Option<String> {
+        match self.err {
+            Error::UnmappedAccess { kind, .. } => Some(format!("{:?}", kind)),
+            _ => None,
+        }
+    }
+}
+
+/// Run the given closure, converting any emulator errors into exceptions.
+/// Once the emulator has panicked, the crash report is returned to JS as an
+/// error instead. On wasm a panic aborts, so this only catches it where
+/// panics unwind (e.g. in tests), and otherwise the report is made by the
+/// panic hook
+fn guard<T, F: FnOnce() -> error::Result<T>>(f: F) -> Result<T, JsValue> {
+    unsafe {
+        if let Some(ref report) = CRASH_REPORT {
+            return Err(JsValue::from_str(report));
+        }
+    }
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f)).map_err(|payload| {
+        // the panic hook has already made the report if it's installed
+        let report = match unsafe { CRASH_REPORT.clone() } {
+            Some(report) => report,
+            None => record_crash(&panic_message(&*payload)),
+        };
+        JsValue::from_str(&report)
+    })?;
+    result.map_err(|err| {
+        error!("{}", err);
+        let fingerprint = unsafe { GBA.fingerprint(Some(&Cause::Error(err.clone()))) };
+        JsValue::from(EmulatorError { err, fingerprint })
+    })
+}
+
+/// Return the crash report if the emulator has crashed. After a call into the
+/// emulator traps, this is where the frontend finds out why
+#[wasm_bindgen]
+pub fn get_crash_report() -> Option<String> {
+    unsafe { CRASH_REPORT.clone() }
+}
+
+/// Return the fingerprint of the panic if the emulator has crashed, to show
+/// the player alongside the crash report
+#[wasm_bindgen]
+pub fn get_crash_fingerprint() -> Option<String> {
+    unsafe { CRASH_FINGERPRINT.as_ref().map(|fingerprint| fingerprint.to_string()) }
+}
+
+/// Return the note for the player if the emulator has crashed with a known
+/// problem
+#[wasm_bindgen]
+pub fn get_crash_note() -> Option<String> {
+    unsafe { CRASH_FINGERPRINT.as_ref()?.note().map(|note| note.to_string()) }
+}
+
+/// Return the fingerprint of a game that's misbehaving without having
+/// stopped (e.g. one that hangs), based on the diagnostics raised so far
+#[wasm_bindgen]
+pub fn get_fingerprint() -> String {
+    unsafe { GBA.fingerprint(None).to_string() }
+}
+
+/// Run the built in checks (see self_test) and return the report, which can
+/// be attached to a bug report. The running game carries on afterwards, or
+/// an error is returned if it couldn't be restored
+#[wasm_bindgen]
+pub fn run_self_test() -> Result<String, JsValue> {
+    unsafe {
+        GBA.self_test()
+            .map(|report| report.to_string())
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+}
+
+#[wasm_bindgen]
+pub fn upload_bios(data: &[u8]) {
+    unsafe { GBA.cpu.mem.load_bios(data) }
+}
+
+/// Copy data straight into memory at the given address, e.g. to restore a
+/// VRAM or OAM dump while debugging
+#[wasm_bindgen]
+pub fn load_memory_region(addr: u32, data: &[u8]) {
+    unsafe { GBA.cpu.mem.load_region(addr, data) }
+}
+
+#[wasm_bindgen]
+pub fn upload_rom(data: Vec<u8>) {
+    log!("rom size: {:X}", data.len());
+    unsafe {
+        GBA.cpu.mem.load_rom(data);
+        SETTINGS.apply(&mut GBA);
+    }
+}
+
+/// The size of a page of wasm memory, which is what memory.grow counts in
+pub const WASM_PAGE_SIZE: usize = 0x10000;
+
+/// Return roughly how much heap a session with a ROM of the given size (and
+/// a patch of the given size, or 0) needs at most, erring high: the RAM and
+/// frames of both GBAs, the ROM, the patched copy being built next to it,
+/// the largest SRAM, the scaled output at the largest scale, the output
+/// converted to RGBA, and a savestate being saved or loaded
+fn heap_requirements(rom_size: usize, patch_size: usize) -> usize {
+    // the GBA and the link peer are the same size, and their RAM and frames
+    // are allocated on first use even though they're statics
+    let (gbas, ram) = unsafe { (2 * GBA.cpu.mem.heap_size(), GBA.cpu.mem.raw.heap_size()) };
+    // the patch itself, and the patched ROM which is about the size of both
+    let patched = if patch_size > 0 { patch_size + rom_size + patch_size } else { 0 };
+    let scale = MAX_SCALE as usize;
+    let scaled = WIDTH * HEIGHT * scale * scale * 4;
+    let converted = OutputFormat::Rgba8888.stride() * HEIGHT;
+    // the state's raw memory dominates, and it's copied once on the way out
+    // of (or into) the module and once more while it's being assembled
+    let state = 2 * (size_of::<RawMemory>() + ram);
+    gbas + rom_size + patched + MAX_BANKS as usize * BANK_SIZE + scaled + converted + state
+}
+
+/// Return the number of bytes of wasm memory a session with a ROM of the
+/// given size (and a patch of the given size, or 0) needs at most, for
+/// loaders that set the memory's initial or maximum size themselves
+#[wasm_bindgen]
+pub fn get_memory_requirements(rom_size: usize, patch_size: usize) -> usize {
+    // the two GBAs are statics, apart from the RAM and frames that
+    // heap_requirements counts
+    let statics = 2 * size_of::<CPUWrapper>() + size_of::<Settings>();
+    let total = statics + heap_requirements(rom_size, patch_size);
+    (total + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE * WASM_PAGE_SIZE
+}
+
+/// Grow the wasm memory up front to fit a ROM of the given size (and a patch
+/// of the given size, or 0), so that it doesn't grow part way through
+/// loading. Growing detaches memory.buffer, so any views of it (e.g. of the
+/// framebuffer) have to be recreated after this and after any other call
+/// that allocates. The pointers returned by get_framebuffer() etc. are to
+/// statics, so they stay the same
+#[wasm_bindgen]
+pub fn reserve_memory(rom_size: usize, patch_size: usize) {
+    // the allocator keeps memory it's grown into, so the ROM will fit in the
+    // space this frees
+    let reserved: Vec<u8> = Vec::with_capacity(heap_requirements(rom_size, patch_size));
+    drop(reserved);
+}
+
+/// Load a ROM with an IPS or UPS patch (e.g. a romhack or translation)
+/// applied. Fails without loading anything if the patch is damaged or, for UPS
+/// patches, was made for a different ROM
+#[wasm_bindgen]
+pub fn upload_rom_with_patch(data: Vec<u8>, patch: &[u8]) -> Result<(), JsValue> {
+    unsafe {
+        GBA.cpu.mem.load_rom_with_patch(data, patch)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        SETTINGS.apply(&mut GBA);
+    }
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn get_register(i: usize) -> u32 {
+    unsafe { GBA.cpu.get_reg(i) }
+}
+
+#[wasm_bindgen]
+pub fn get_bios() -> *const u8 {
+    unsafe { GBA.cpu.mem.raw.sysrom.as_mut_ptr() as *const u8 }
+}
+
+#[wasm_bindgen]
+pub fn get_bg_palette() -> *const u8 {
+    unsafe { &GBA.cpu.mem.palette.bg as *const u32 as *const u8 }
+}
+
+#[wasm_bindgen]
+pub fn get_sprite_palette() -> *const u8 {
+    unsafe { &GBA.cpu.mem.palette.sprite as *const u32 as *const u8 }
+}
+
+#[wasm_bindgen]
+pub fn get_vram() -> *const u8 {
+    unsafe { GBA.cpu.mem.raw.vram.as_mut_ptr() as *const u8 }
+}
+
+#[wasm_bindgen]
+pub fn step() -> Result<bool, JsValue> {
+    guard(|| unsafe { GBA.step().map(|_| GBA.cpu.should_flush) })
+}
+
+#[wasm_bindgen]
+pub fn frame() -> Result<(), JsValue> {
+    let start = time().now_ms();
+    let result = guard(|| unsafe { GBA.frame() });
+    unsafe { GBA.pacing.record_frame_time(time().now_ms() - start) };
+    result
+}
+
+#[wasm_bindgen]
+pub fn get_cpsr() -> u32 {
+    unsafe { GBA.cpu.cpsr.to_u32() }
+}
+
+/// Return the registers and decoded CPSR of the current mode
+#[wasm_bindgen]
+pub fn get_cpu_state() -> CpuState {
+    unsafe { CpuState::capture(&GBA) }
+}
+
+/// Return the average number of cycles per memory access since the last call
+/// to reset_mem_stats, which is useful for tracking down slowdowns caused by
+/// the game's WAITCNT settings
+#[wasm_bindgen]
+pub fn get_mem_latency() -> f64 {
+    unsafe { GBA.cpu.mem.access_stats.average_latency() }
+}
+
+#[wasm_bindgen]
+pub fn reset_mem_stats() {
+    unsafe { GBA.cpu.mem.access_stats.reset() }
+}
+
+/// Start or stop counting how many times each kind of instruction runs, for
+/// profiling the interpreter. This slows down emulation a little, so it's off
+/// by default
+#[wasm_bindgen]
+pub fn set_opcode_stats_enabled(enabled: bool) {
+    unsafe { GBA.opcode_stats.enabled = enabled }
+}
+
+/// Return the number of times each kind of instruction has run since the last
+/// call to reset_opcode_stats, most frequent first
+#[wasm_bindgen]
+pub fn get_opcode_stats() -> Vec<OpcodeCount> {
+    let counts = unsafe { GBA.opcode_stats.counts() };
+    counts.iter().map(|&(name, count)| OpcodeCount::from_count(name, count)).collect()
+}
+
+#[wasm_bindgen]
+pub fn reset_opcode_stats() {
+    unsafe { GBA.opcode_stats.reset() }
+}
+
+/// Start or stop recording the branches the CPU takes, for finding a game's
+/// main loop and interrupt handler. Off by default
+#[wasm_bindgen]
+pub fn set_branch_log_enabled(enabled: bool) {
+    unsafe { GBA.branch_log.enabled = enabled }
+}
+
+/// Return the branches taken recently, most often taken first
+#[wasm_bindgen]
+pub fn get_branch_log() -> Vec<BranchEvent> {
+    unsafe {
+        GBA.branch_log.hottest().iter().map(|edge| BranchEvent::from_edge(&GBA, edge)).collect()
+    }
+}
+
+#[wasm_bindgen]
+pub fn clear_branch_log() {
+    unsafe { GBA.branch_log.clear() }
+}
+
+/// Start or stop recording the graphics registers used for each scanline
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn set_scanline_log_enabled(enabled: bool) {
+    unsafe { GBA.cpu.mem.scanline_log.set_enabled(enabled) }
+}
+
+/// Return the registers recorded for each line of the last frame
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn get_scanline_log() -> String {
+    unsafe { GBA.cpu.mem.scanline_log.format() }
+}
+
+/// Start or stop recording the sprites and affine parameters that change in
+/// each frame
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn set_oam_log_enabled(enabled: bool) {
+    unsafe { GBA.cpu.mem.set_oam_log_enabled(enabled) }
+}
+
+/// Return the recorded changes, one per line with the frame they were made
+/// in and the attributes that changed
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn get_oam_log() -> String {
+    unsafe { GBA.cpu.mem.oam_log.format() }
+}
+
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn clear_oam_log() {
+    unsafe { GBA.cpu.mem.oam_log.clear() }
+}
+
+/// Force a BIOS call to be emulated even when a BIOS is loaded, which can
+/// work around incomplete BIOS dumps
+#[wasm_bindgen]
+pub fn set_swi_hle(num: usize, force: bool) {
+    unsafe {
+        if num < GBA.cpu.bios.force_hle.len() {
+            GBA.cpu.bios.force_hle[num] = force;
+        }
+    }
+}
+
+/// Force every BIOS call that can be emulated to be, even when a BIOS is
+/// loaded, or go back to running them all in the loaded BIOS. Without a BIOS
+/// they're always emulated
+#[wasm_bindgen]
+pub fn set_bios_hle(force: bool) {
+    unsafe {
+        for forced in GBA.cpu.bios.force_hle.iter_mut() {
+            *forced = force;
+        }
+    }
+}
+
+/// Turn skipping through memory copy loops on or off. It's on by default,
+/// and only needs turning off to step through a copy loop in the debugger
+#[wasm_bindgen]
+pub fn set_fast_copy_enabled(enabled: bool) {
+    unsafe {
+        GBA.fast_copy.enabled = enabled;
+        GBA.fast_copy.forget();
+    }
+}
+
+/// Choose how interrupts reach the game's handler: "bios" to run the loaded
+/// BIOS's handler, "hle" to emulate it, or "vector" to jump straight to the
+/// handler at 0x03007FFC. Returns false for anything else
+#[wasm_bindgen]
+pub fn set_irq_dispatch(dispatch: &str) -> bool {
+    match IrqDispatch::from_name(dispatch) {
+        Some(dispatch) => {
+            unsafe { GBA.cpu.bios.irq_dispatch = dispatch }
+            true
+        },
+        None => false,
+    }
+}
+
+/// Return the most recent BIOS calls, and whether each one ran in the BIOS
+/// or was emulated
+#[wasm_bindgen]
+pub fn get_swi_log() -> String {
+    unsafe { GBA.cpu.bios.format_log() }
+}
+
+/// Return the most recent BIOS calls, oldest first
+#[wasm_bindgen]
+pub fn get_swi_events() -> Vec<SwiEvent> {
+    unsafe { GBA.cpu.bios.log.iter().map(SwiEvent::from_entry).collect() }
+}
+
+/// Force a DMA channel on or off (or clear the override with undefined), to
+/// see how the game behaves without it
+#[wasm_bindgen]
+pub fn set_dma_channel_enabled_override(channel: usize, enabled: Option<bool>) {
+    unsafe {
+        if channel < GBA.cpu.mem.dma.overrides.len() {
+            GBA.cpu.mem.dma.set_enabled_override(channel, enabled);
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_dma_channel_enabled_override(channel: usize) -> Option<bool> {
+    unsafe { GBA.cpu.mem.dma.overrides.get(channel).cloned().unwrap_or(None) }
+}
+
+/// Force a timer on or off (or clear the override with undefined), like
+/// set_dma_channel_enabled_override
+#[wasm_bindgen]
+pub fn set_timer_enabled_override(timer: usize, enabled: Option<bool>) {
+    unsafe {
+        if timer < GBA.cpu.mem.timers.overrides.len() {
+            GBA.cpu.mem.timers.set_enabled_override(timer, enabled);
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_timer_enabled_override(timer: usize) -> Option<bool> {
+    unsafe { GBA.cpu.mem.timers.overrides.get(timer).cloned().unwrap_or(None) }
+}
+
+/// Return the number of transfers made by a DMA channel and the time they
+/// took since the last call to reset_dma_stats
+#[wasm_bindgen]
+pub fn get_dma_stats(channel: usize) -> Option<DmaChannelStats> {
+    unsafe { GBA.cpu.mem.dma.stats.get(channel).map(DmaChannelStats::from_stats) }
+}
+
+#[wasm_bindgen]
+pub fn reset_dma_stats() {
+    unsafe { GBA.cpu.mem.dma.reset_stats() }
+}
+
+/// Return the number of lines drawn and the number skipped because of forced
+/// blank since the last call to reset_render_stats
+#[wasm_bindgen]
+pub fn get_render_stats() -> RenderLineStats {
+    unsafe { RenderLineStats::from_stats(&GBA.cpu.mem.framebuffer.stats) }
+}
+
+#[wasm_bindgen]
+pub fn reset_render_stats() {
+    unsafe { GBA.cpu.mem.framebuffer.stats = RenderStats::new() }
+}
+
+/// Start or stop comparing every line drawn with the reference renderer (see
+/// mem::reference). This makes rendering several times slower, so it's meant
+/// for tests and for tracking down rendering bugs
+#[cfg(feature = "render")]
+#[wasm_bindgen]
+pub fn set_render_check(enabled: bool) {
+    unsafe { GBA.cpu.mem.framebuffer.check.enabled = enabled }
+}
+
+/// Return the pixels that differed from the reference renderer since the
+/// last call, up to mem::reference::MAX_DIFFS of them
+#[cfg(feature = "render")]
+#[wasm_bindgen]
+pub fn take_render_diffs() -> Vec<RenderDiff> {
+    unsafe { GBA.cpu.mem.framebuffer.check.take_diffs().iter().map(RenderDiff::from_diff).collect() }
+}
+
+/// Set the most number of frames in a row that will be skipped when the
+/// host can't keep up. 0 disables frame skipping
+#[wasm_bindgen]
+pub fn set_max_frame_skip(max_skip: u32) {
+    unsafe { GBA.pacing.set_max_skip(max_skip) }
+}
+
+/// Return the number of frames currently being skipped after each drawn frame
+#[wasm_bindgen]
+pub fn get_frame_skip() -> u32 {
+    unsafe { GBA.pacing.skip }
+}
+
+/// Return the moving average of the time taken by frame(), in milliseconds
+#[wasm_bindgen]
+pub fn get_avg_frame_time() -> f64 {
+    unsafe { GBA.pacing.avg_frame_ms }
+}
+
+/// Return a summary of the pacing stats for display, e.g.
+/// "FPS: 60 (skipping 1/2)"
+#[wasm_bindgen]
+pub fn get_pacing_stats() -> String {
+    unsafe { GBA.pacing.summary() }
+}
+
+/// Set the sample rate of the host's audio output, and start measuring A/V
+/// drift from zero. Should be called when the audio output starts
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn start_av_sync(sample_rate: f64) {
+    unsafe {
+        GBA.pacing.av_sync.sample_rate = sample_rate;
+        GBA.pacing.av_sync.reset();
+    }
+}
+
+/// Report the number of samples the audio output has played since the last
+/// call
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn record_audio_samples(count: u32) {
+    unsafe { GBA.pacing.av_sync.record_samples(count) }
+}
+
+/// Return how far the audio is ahead of the video, in milliseconds
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn get_av_drift() -> f64 {
+    unsafe { GBA.pacing.av_sync.drift_ms }
+}
+
+/// Return the ratio the emulator's audio should be resampled by to correct
+/// the A/V drift, which is always within 0.5% of 1
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn get_resample_ratio() -> f64 {
+    unsafe { GBA.pacing.av_sync.resample_ratio }
+}
+
+/// Return the sample rate of the emulator's audio, before resampling
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn get_audio_sample_rate() -> u32 {
+    SAMPLE_RATE
+}
+
+/// Return the audio made since the last call, as interleaved left and right
+/// samples from -1 to 1
+#[cfg(feature = "audio")]
+#[wasm_bindgen]
+pub fn take_audio_samples() -> Vec<f32> {
+    unsafe { GBA.cpu.mem.apu.take_samples() }
+}
+
+/// Stop running frames, e.g. while the page is hidden. frame() does nothing
+/// until resume() is called
+#[wasm_bindgen]
+pub fn pause() {
+    unsafe { GBA.pacing.pause() }
+}
+
+/// Start running frames again after pause(). The time spent paused isn't
+/// made up for, so the emulator carries on from where it stopped
+#[wasm_bindgen]
+pub fn resume() {
+    // the audio made before the pause is stale by now
+    #[cfg(feature = "audio")]
+    unsafe { GBA.cpu.mem.apu.clear_samples() }
+    unsafe { GBA.pacing.resume() }
+}
+
+/// Return the number of frames that should be run to keep up with the wall
+/// clock. At most a couple of frames are run to catch up, so this is safe to
+/// call from requestAnimationFrame regardless of how long it has been since
+/// the last call
+#[wasm_bindgen]
+pub fn frames_due() -> u32 {
+    unsafe { GBA.pacing.frames_due(time().now_ms()) }
+}
+
+/// While paused mid frame, fill in the rest of the shown frame by drawing the
+/// lines that haven't been drawn yet with the current state. This is only a
+/// preview: the game may change things before those lines are really drawn.
+/// Returns the first speculative line (160 if there are none), and the frame
+/// stays marked as speculative until the next one finishes
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn render_to_current_line() -> u32 {
+    unsafe { GBA.render_to_current_line() }
+}
+
+/// Return the first line of the shown frame that was drawn speculatively by
+/// render_to_current_line, or nothing if the whole frame is real
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn get_speculative_line() -> Option<u32> {
+    unsafe { GBA.cpu.mem.framebuffer.speculative_from }
+}
+
+/// Return the number of frames the GBA draws per second, about 59.7275
+#[wasm_bindgen]
+pub fn get_refresh_rate() -> f64 {
+    REFRESH_HZ
+}
+
+/// Return the number of cycles to run given the milliseconds since the last
+/// call, for hosts that keep their own clock. Dividing by the cycles in a
+/// frame (280896) and rounding up gives the number of calls to frame()
+#[wasm_bindgen]
+pub fn cycles_until_next_frame(host_time_delta: f64) -> u32 {
+    unsafe { GBA.cycles_until_next_frame(host_time_delta) }
+}
+
+/// Return the settings in a versioned format that can be passed back to
+/// import_settings, e.g. in a later session
+#[wasm_bindgen]
+pub fn export_settings() -> Vec<u8> {
+    unsafe { SETTINGS.serialize() }
+}
+
+/// Replace the settings with ones from export_settings, and apply them
+#[wasm_bindgen]
+pub fn import_settings(data: &[u8]) -> Result<(), JsValue> {
+    let settings = Settings::parse(data)
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    unsafe {
+        SETTINGS = settings;
+        SETTINGS.apply(&mut GBA);
+    }
+    Ok(())
+}
+
+/// Return a save state of the current emulation, which can be restored with
+/// load_state in this or a later build (see savestate)
+#[wasm_bindgen]
+pub fn save_state() -> Vec<u8> {
+    unsafe { GBA.save_state() }
+}
+
+/// Restore a state from save_state. The same game has to be loaded first
+#[wasm_bindgen]
+pub fn load_state(data: &[u8]) -> Result<(), JsValue> {
+    unsafe {
+        GBA.load_state(data).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Return the changes from one save state to another, which apply_diff
+/// turns back into the second state given the first (see savestate::delta)
+#[wasm_bindgen]
+pub fn diff_states(base: &[u8], target: &[u8]) -> Vec<u8> {
+    delta::diff_states(base, target)
+}
+
+/// Rebuild the state that a delta from diff_states was made from, given the
+/// state it was made against. Fails if that's a different state
+#[wasm_bindgen]
+pub fn apply_diff(base: &[u8], diff: &[u8]) -> Result<Vec<u8>, JsValue> {
+    delta::apply_diff(base, diff).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Bind a button ("a", "b", "select", "start", "right", "left", "up", "down",
+/// "r" or "l") to a KeyboardEvent.code. Returns false for unknown buttons
+#[wasm_bindgen]
+pub fn set_key_binding(button: &str, key: &str) -> bool {
+    unsafe { SETTINGS.set_binding(button, key) }
+}
+
+#[wasm_bindgen]
+pub fn get_key_binding(button: &str) -> Option<String> {
+    unsafe { SETTINGS.binding(button).map(|key| key.to_string()) }
+}
+
+/// Set the emulation speed relative to the GBA, e.g. 2.0 to fast forward.
+/// Speeds that aren't positive, finite and at most 16 are ignored
+#[wasm_bindgen]
+pub fn set_speed(speed: f64) {
+    if is_valid_speed(speed) {
+        unsafe {
+            SETTINGS.speed = speed;
+            GBA.pacing.speed = speed;
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_speed() -> f64 {
+    unsafe { SETTINGS.speed }
+}
+
+/// Set how many times faster than normal the emulated CPU runs, from 1 to 4.
+/// This removes slowdown in games that can't keep up, while the frame rate,
+/// sound and everything else on the GBA keep their normal timing
+#[wasm_bindgen]
+pub fn set_overclock(multiplier: f64) {
+    unsafe {
+        GBA.overclock.set_multiplier(multiplier);
+        SETTINGS.overclock = GBA.overclock.multiplier();
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_overclock() -> f64 {
+    unsafe { SETTINGS.overclock }
+}
+
+/// Set the audio volume, from 0 to 100
+#[wasm_bindgen]
+pub fn set_volume(volume: u8) {
+    unsafe { SETTINGS.volume = volume.min(100) }
+}
+
+#[wasm_bindgen]
+pub fn get_volume() -> u8 {
+    unsafe { SETTINGS.volume }
+}
+
+/// Choose what holding A+B+Select+Start does: "off" to leave it to the game,
+/// "auto" to reset unless the game has a keypad interrupt for the combo, or
+/// "always" to reset. Returns false for anything else
+#[wasm_bindgen]
+pub fn set_reset_combo(action: &str) -> bool {
+    match ComboAction::from_name(action) {
+        Some(action) => {
+            unsafe {
+                SETTINGS.reset_combo = action;
+                GBA.reset_combo.action = action;
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_reset_combo() -> String {
+    unsafe { SETTINGS.reset_combo.name().to_string() }
+}
+
+/// Show or hide one of the overlays drawn over the screen: "input" for the
+/// buttons held, "frame_count" or "speed". Returns false for anything else
+#[wasm_bindgen]
+pub fn set_overlay_enabled(name: &str, enabled: bool) -> bool {
+    let kind = match OverlayKind::from_name(name) {
+        Some(kind) => kind,
+        None => return false,
+    };
+    unsafe {
+        SETTINGS.overlays.retain(|other| *other != kind);
+        if enabled {
+            SETTINGS.overlays.push(kind);
+        }
+        GBA.cpu.mem.framebuffer.post.overlay.set_enabled(kind, enabled);
+    }
+    true
+}
+
+#[wasm_bindgen]
+pub fn get_overlay_enabled(name: &str) -> bool {
+    match OverlayKind::from_name(name) {
+        Some(kind) => unsafe { SETTINGS.overlays.contains(&kind) },
+        None => false,
+    }
+}
+
+/// Remember the current frame skip, affine snapshot, forced HLE and SRAM
+/// settings for the loaded game, so they're applied whenever it's loaded again.
+/// Returns false if no game is loaded
+#[wasm_bindgen]
+pub fn save_game_settings() -> bool {
+    unsafe { SETTINGS.save_game(&GBA) }
+}
+
+/// Give the cartridge SRAM made of the given number of 32KB banks, where 0
+/// removes it and more than 1 is for carts that bank SRAM through writes to
+/// 0x0E008000 - 0x0E00FFFF. The contents of the remaining banks are kept
+#[wasm_bindgen]
+pub fn set_sram_banks(banks: u8) {
+    unsafe { GBA.cpu.mem.raw.sram.set_banks(banks) }
+}
+
+#[wasm_bindgen]
+pub fn get_sram_banks() -> u8 {
+    unsafe { GBA.cpu.mem.raw.sram.banks() }
+}
+
+/// Give the cartridge a Flash chip of the given size in KB (64 or 128), or
+/// remove it with any other size. This is only needed for carts whose ROM
+/// doesn't say they have one, since Flash is found when the ROM is loaded.
+/// The new chip starts out erased
+#[wasm_bindgen]
+pub fn set_flash_size(kb: u32) {
+    unsafe { GBA.cpu.mem.raw.flash.set_size(kb as usize * 1024) }
+}
+
+#[wasm_bindgen]
+pub fn get_flash_size() -> u32 {
+    unsafe { (GBA.cpu.mem.raw.flash.size() / 1024) as u32 }
+}
+
+/// Give the cartridge an EEPROM of the given size in bytes (512 or 8192), or
+/// remove it with any other size. As with Flash, this is only needed for carts
+/// whose ROM doesn't say they have one. The new chip starts out erased
+#[wasm_bindgen]
+pub fn set_eeprom_size(size: u32) {
+    unsafe { GBA.cpu.mem.raw.eeprom.set_size(size as usize) }
+}
+
+/// Return the size of the cartridge's EEPROM in bytes, or 0 if it doesn't
+/// have one or the game hasn't accessed it yet
+#[wasm_bindgen]
+pub fn get_eeprom_size() -> u32 {
+    unsafe { GBA.cpu.mem.raw.eeprom.size() as u32 }
+}
+
+/// Return true if the cartridge has a tilt sensor, so that the frontend knows
+/// to listen for the device's orientation
+#[wasm_bindgen]
+pub fn has_tilt_sensor() -> bool {
+    unsafe { GBA.cpu.mem.raw.tilt.present() }
+}
+
+/// Give the cartridge a tilt sensor or take it away, e.g. for homebrew that
+/// uses one. The games that have one get it when they're loaded
+#[wasm_bindgen]
+pub fn set_tilt_sensor_present(present: bool) {
+    unsafe { GBA.cpu.mem.raw.tilt.set_present(present) }
+}
+
+/// Set how far the GBA is tilted left/right (x) and forwards/backwards (y),
+/// from -1.0 to 1.0 on each axis, e.g. from deviceorientation events
+#[wasm_bindgen]
+pub fn set_tilt(x: f64, y: f64) {
+    unsafe { GBA.cpu.mem.raw.tilt.set_tilt(x as f32, y as f32) }
+}
+
+/// Return the contents of the cartridge's Flash or EEPROM, or of its SRAM
+/// with every bank in order, to be saved as a .sav file (e.g. in localStorage)
+#[wasm_bindgen]
+pub fn export_backup() -> Vec<u8> {
+    unsafe { GBA.cpu.mem.raw.export_backup() }
+}
+
+/// Load a .sav file produced by export_backup(). SRAM needs to be set up
+/// first (see set_sram_banks), but EEPROM takes its size from the save
+#[wasm_bindgen]
+pub fn import_backup(data: &[u8]) {
+    unsafe { GBA.cpu.mem.raw.import_backup(data) }
+}
+
+/// Only pick up changes to the sprite affine matrices at the start of each
+/// frame (unless the game changes them during HBlank), which stops sprites
+/// from tearing in games that update OAM outside of VBlank
+#[wasm_bindgen]
+pub fn set_affine_snapshot(enabled: bool) {
+    unsafe { GBA.cpu.mem.sprites.affine_snapshot = enabled }
+}
+
+/// Return every known IO register with its value decoded into fields, one
+/// per line, for debugging and bug reports
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn dump_io_decoded() -> String {
+    unsafe { GBA.cpu.mem.dump_io_decoded() }
+}
+
+/// Return every known IO register with its value and decoded fields
+#[cfg(feature = "debugger")]
+#[wasm_bindgen]
+pub fn get_io_registers() -> Vec<IoRegisterState> {
+    unsafe {
+        IO_REGISTERS.iter()
+            .map(|reg| IoRegisterState::capture(&GBA.cpu.mem, reg))
+            .collect()
+    }
+}
+
+/// Blend each frame with the previous one to mimic the GBA's slow LCD, where
+/// weight is the amount of the previous frame from 0 (off) to 1
+#[wasm_bindgen]
+pub fn set_ghosting(weight: f32) {
+    unsafe { GBA.cpu.mem.framebuffer.post.set_ghosting(weight) }
+}
+
+/// Upscale each finished frame by 2 or 3 (or 1 to turn scaling off) into an
+/// RGBA buffer that can be copied straight to a canvas. The filter is
+/// "nearest", "scanlines" or "lcd-grid"
+#[wasm_bindgen]
+pub fn set_output_scale(scale: u32, filter: &str) {
+    let filter = match filter {
+        "scanlines" => ScaleFilter::Scanlines,
+        "lcd-grid" => ScaleFilter::LcdGrid,
+        _ => ScaleFilter::Nearest,
+    };
+    unsafe { GBA.cpu.mem.framebuffer.post.set_scale(scale, filter) }
+}
+
+/// Return a pointer to the scaled output, which is
+/// (240 * scale) x (160 * scale) RGBA pixels. The buffer moves when the
+/// scale changes, so this should be called again after each frame
+#[wasm_bindgen]
+pub fn get_scaled_framebuffer() -> *const u8 {
+    unsafe { GBA.cpu.mem.framebuffer.post.scaled.as_ptr() }
+}
+
+/// Set the pixel format of the buffer returned by get_framebuffer():
+/// "bgr555" (the default, the GBA's own 15 bit colors), "rgb565" or
+/// "rgba8888". This is meant to be set once at startup, since the buffer
+/// moves when it changes. Returns false if the format isn't known
+#[wasm_bindgen]
+pub fn set_output_format(format: &str) -> bool {
+    match OutputFormat::from_name(format) {
+        Some(format) => {
+            unsafe { GBA.cpu.mem.framebuffer.post.set_format(format) };
+            true
+        },
+        None => false,
+    }
+}
+
+#[wasm_bindgen]
+pub fn get_output_format() -> String {
+    unsafe { GBA.cpu.mem.framebuffer.post.format.name().to_string() }
+}
+
+/// Return the number of bytes per row of the buffer returned by
+/// get_framebuffer(), which has 160 rows with nothing in between. Each pixel
+/// is 2 bytes (little endian) in the 16 bit formats, or 4 in RGBA
+#[wasm_bindgen]
+pub fn get_framebuffer_stride() -> usize {
+    unsafe { GBA.cpu.mem.framebuffer.post.format.stride() }
+}
+
+/// Return a pointer to the last finished frame, as 240x160 pixels in the
+/// format set by set_output_format()
+#[wasm_bindgen]
+pub fn get_framebuffer() -> *const u8 {
+    unsafe {
+        let post = &mut GBA.cpu.mem.framebuffer.post;
+        match post.format {
+            // through as_mut_ptr, which allocates the output if the game
+            // hasn't drawn anything yet, so that the pointer stays valid
+            OutputFormat::Bgr555 => post.output.as_mut_ptr() as *const u8,
+            _ => post.converted.as_ptr(),
+        }
+    }
+}
+
+/// A second GBA which can be connected to the main one with a link cable
+pub static mut PEER: CPUWrapper = CPUWrapper::new();
+static mut LINKED: bool = false;
+
+#[wasm_bindgen]
+pub fn upload_peer_bios(data: &[u8]) {
+    unsafe { PEER.cpu.mem.load_bios(data) }
+}
+
+#[wasm_bindgen]
+pub fn upload_peer_rom(data: Vec<u8>) {
+    unsafe { PEER.cpu.mem.load_rom(data) }
+}
+
+#[wasm_bindgen]
+pub fn get_peer_framebuffer() -> *const u8 {
+    unsafe { PEER.cpu.mem.framebuffer.post.output.as_mut_ptr() as *const u8 }
+}
+
+/// Connect or disconnect the link cable between the main GBA (player 1) and
+/// the peer (player 2)
+#[wasm_bindgen]
+pub fn set_link_connected(connected: bool) {
+    unsafe {
+        if connected {
+            link::connect(&mut GBA, &mut PEER);
+        } else {
+            link::disconnect(&mut GBA, &mut PEER);
+        }
+        LINKED = connected;
+    }
+}
+
+/// Run a frame on both GBAs. If they aren't linked, only the main GBA runs
+#[wasm_bindgen]
+pub fn frame_linked() -> Result<(), JsValue> {
+    guard(|| unsafe {
+        if LINKED {
+            link::frame(&mut GBA, &mut PEER)
+        } else {
+            GBA.frame()
+        }
+    })
+}
+
+/// Sends messages through the frontend's gbaLinkSend callback, and receives
+/// the messages passed in to network_link_receive
+pub struct JsTransport {
+    incoming: VecDeque<LinkMessage>,
+}
+
+impl LinkTransport for JsTransport {
+    fn send(&mut self, msg: LinkMessage) {
+        gba_link_send(msg.seq, msg.data);
+    }
+
+    fn recv(&mut self) -> Option<LinkMessage> {
+        self.incoming.pop_front()
+    }
+}
+
+static mut NETWORK_LINK: Option<NetworkLink<JsTransport>> = None;
+
+/// Connect the main GBA to a GBA on another machine as the given player (0
+/// for the parent, 1 for the child). Messages for the other machine are
+/// passed to the global gbaLinkSend(seq, data) function
+#[wasm_bindgen]
+pub fn network_link_start(player_id: u8) {
+    let transport = JsTransport { incoming: VecDeque::new() };
+    unsafe { NETWORK_LINK = Some(NetworkLink::new(transport, player_id, &mut GBA)) }
+}
+
+#[wasm_bindgen]
+pub fn network_link_stop() {
+    unsafe {
+        NETWORK_LINK = None;
+        GBA.cpu.mem.set_link(false, 0);
+    }
+}
+
+/// Should be called by the frontend for each message from the other machine
+#[wasm_bindgen]
+pub fn network_link_receive(seq: u32, data: u16) {
+    unsafe {
+        if let Some(ref mut link) = NETWORK_LINK {
+            link.transport.incoming.push_back(LinkMessage { seq, data });
+        }
+    }
+}
+
+/// Run a frame while connected over the network. Returns false if the frame
+/// stalled waiting for the other machine, in which case it should be called
+/// again once more messages arrive
+#[wasm_bindgen]
+pub fn frame_network() -> Result<bool, JsValue> {
+    guard(|| unsafe {
+        match NETWORK_LINK {
+            Some(ref mut link) => link.frame(&mut GBA),
+            None => GBA.frame().map(|_| true),
+        }
+    })
+}
+
+/// Return the number of times the parent has had to wait for the child
+#[wasm_bindgen]
+pub fn get_network_link_stalls() -> u32 {
+    unsafe { NETWORK_LINK.as_ref().map(|link| link.stalls).unwrap_or(0) }
+}
+
+/// Return a zip archive with a PNG of each visible sprite
+#[cfg(feature = "render")]
+#[wasm_bindgen]
+pub fn export_sprites() -> Vec<u8> {
+    unsafe { GBA.cpu.mem.export_sprites() }
+}
+
+/// Return the attributes of all 128 sprites in OAM, including hidden ones
+#[wasm_bindgen]
+pub fn get_sprites() -> Vec<SpriteState> {
+    unsafe {
+        GBA.cpu.mem.sprites.sprites.iter().enumerate()
+            .map(|(i, sprite)| SpriteState::capture(i, sprite))
+            .collect()
+    }
+}
+
+/// Describe where the color of the given screen pixel comes from, or return
+/// undefined if it's off screen
+#[cfg(all(feature = "render", feature = "debugger"))]
+#[wasm_bindgen]
+pub fn explain_pixel(x: u32, y: u32) -> Option<PixelInfo> {
+    unsafe { GBA.cpu.mem.explain_pixel(x, y).map(|info| PixelInfo::from_explanation(&info)) }
+}
+
+/// Draw the whole tilemap of the given background for the map viewer, or
+/// return undefined if it isn't a text background in the current mode
+#[cfg(all(feature = "render", feature = "debugger"))]
+#[wasm_bindgen]
+pub fn render_bg_map(bg: usize) -> Option<BgMapImage> {
+    unsafe { GBA.cpu.mem.render_bg_map(bg).map(BgMapImage::from_map) }
+}
+
+/// Start or stop drawing the false color layer view alongside each frame,
+/// see mem::layer_view
+#[cfg(all(feature = "render", feature = "debugger"))]
+#[wasm_bindgen]
+pub fn set_layer_view(enabled: bool) {
+    unsafe { GBA.cpu.mem.framebuffer.layer_view.enabled = enabled }
+}
+
+/// Return a pointer to the layer view, which is in the same format as the
+/// framebuffer. Lines drawn while it was disabled are left as they were
+#[cfg(all(feature = "render", feature = "debugger"))]
+#[wasm_bindgen]
+pub fn get_layer_view() -> *const u8 {
+    unsafe { GBA.cpu.mem.framebuffer.layer_view.pixels.as_mut_ptr() as *const u8 }
+}
+
+/// Run a homebrew ELF file directly, with debug output and strict memory
+/// checks enabled (see dev)
+#[wasm_bindgen]
+pub fn dev_boot(data: &[u8]) -> Result<(), JsValue> {
+    unsafe {
+        GBA.dev_boot(data).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        log!("loaded ELF with {} symbols", GBA.symbols.len());
+    }
+    Ok(())
+}
+
+/// Return the messages printed through the debug output channel since the
+/// last call, oldest first
+#[wasm_bindgen]
+pub fn take_debug_messages() -> Vec<DebugEvent> {
+    let messages = unsafe { GBA.cpu.mem.debug.take_messages() };
+    messages.iter().map(DebugEvent::from_message).collect()
+}
+
+/// Return the hardware the game has been found polling without it ever
+/// changing since the last call, because it isn't emulated yet. Each kind of
+/// hardware is only reported once
+#[wasm_bindgen]
+pub fn take_stuck_polls() -> Vec<StuckPollEvent> {
+    let reports = unsafe { GBA.cpu.mem.poll_watch.take_reports() };
+    reports.iter().map(StuckPollEvent::from_report).collect()
+}
+
+/// Return the DMA transfers into the IO registers that were too big to be
+/// intentional since the last call. These are still run, but the registers
+/// are only updated once with their final values. A repeating transfer is
+/// only reported once
+#[wasm_bindgen]
+pub fn take_runaway_dmas() -> Vec<RunawayDmaEvent> {
+    let reports = unsafe { GBA.cpu.mem.dma.take_runaways() };
+    reports.iter().map(RunawayDmaEvent::from_report).collect()
+}
+
+/// Stop frame() when len bytes from start are read (if read is set) or
+/// written (if write is set), whether by the CPU or by DMA. Instruction
+/// fetches don't count as reads
+#[wasm_bindgen]
+pub fn add_watchpoint(start: u32, len: u32, read: bool, write: bool) {
+    unsafe { GBA.cpu.mem.watch.add(start, len, read, write) }
+}
+
+#[wasm_bindgen]
+pub fn clear_watchpoints() {
+    unsafe { GBA.cpu.mem.watch.clear() }
+}
+
+/// Return the access that stopped the last frame early, if any. The frame
+/// stops once the instruction or DMA transfer that made it has finished
+#[wasm_bindgen]
+pub fn take_watch_hit() -> Option<WatchHitEvent> {
+    unsafe { GBA.cpu.mem.watch.take_hit().as_ref().map(WatchHitEvent::from_hit) }
+}
+
+/// What the frontend's gbaFrameScript is given to read memory and press
+/// buttons with (see script). It can only be used while the script it was
+/// made for runs
+#[wasm_bindgen]
+pub struct FrameScriptHandle {
+    /// the value of SCRIPT_GENERATION when the handle was made
+    generation: u32,
+}
+
+/// The handle of the frame script that's running, which only lives for the
+/// duration of run_js_frame_script
+static mut CURRENT_SCRIPT: Option<*mut ScriptHandle<'static>> = None;
+/// Bumped for each run of the frame script, so that a FrameScriptHandle the
+/// frontend kept from an earlier frame can't reach the current one
+static mut SCRIPT_GENERATION: u32 = 0;
+
+#[wasm_bindgen]
+impl FrameScriptHandle {
+    /// Call f with the script handle, if this handle's script is still running
+    fn with_handle<T, F>(&self, f: F) -> Result<T, JsValue>
+            where F: FnOnce(&mut ScriptHandle) -> T {
+        unsafe {
+            match CURRENT_SCRIPT {
+                Some(handle) if SCRIPT_GENERATION == self.generation => Ok(f(&mut *handle)),
+                _ => Err(JsValue::from_str("the frame script's handle was used after it returned")),
+            }
+        }
+    }
+
+    pub fn read_byte(&self, addr: u32) -> Result<u8, JsValue> {
+        self.with_handle(|handle| handle.read_byte(addr))
+    }
+
+    pub fn read_halfword(&self, addr: u32) -> Result<u16, JsValue> {
+        self.with_handle(|handle| handle.read_halfword(addr))
+    }
+
+    pub fn read_word(&self, addr: u32) -> Result<u32, JsValue> {
+        self.with_handle(|handle| handle.read_word(addr))
+    }
+
+    /// Writes are only allowed to EWRAM and IWRAM
+    pub fn write_byte(&self, addr: u32, val: u8) -> Result<(), JsValue> {
+        self.with_handle(|handle| handle.write_byte(addr, val))?
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn write_halfword(&self, addr: u32, val: u16) -> Result<(), JsValue> {
+        self.with_handle(|handle| handle.write_halfword(addr, val))?
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn write_word(&self, addr: u32, val: u32) -> Result<(), JsValue> {
+        self.with_handle(|handle| handle.write_word(addr, val))?
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// The buttons being held, a bit for each in the order of KEYINPUT
+    pub fn buttons(&self) -> Result<u16, JsValue> {
+        self.with_handle(|handle| handle.buttons())
+    }
+
+    pub fn set_buttons(&self, pressed: u16) -> Result<(), JsValue> {
+        self.with_handle(|handle| handle.set_buttons(pressed))
+    }
+}
+
+/// Pass a handle on to the frontend's gbaFrameScript. An exception thrown
+/// by the script is logged, and doesn't stop the emulator
+fn run_js_frame_script(handle: &mut ScriptHandle) {
+    let handle: *mut ScriptHandle = handle;
+    let generation = unsafe {
+        SCRIPT_GENERATION = SCRIPT_GENERATION.wrapping_add(1);
+        CURRENT_SCRIPT = Some(handle.cast::<ScriptHandle<'static>>());
+        SCRIPT_GENERATION
+    };
+    let result = gba_frame_script(FrameScriptHandle { generation });
+    unsafe { CURRENT_SCRIPT = None; }
+    if let Err(err) = result {
+        error!("frame script failed: {:?}", err);
+    }
+}
+
+/// Start or stop calling the global gbaFrameScript(handle) function after
+/// each frame, to automate the game (see script)
+#[wasm_bindgen]
+pub fn set_frame_script_enabled(enabled: bool) {
+    let script: Option<script::FrameScript> = if enabled {
+        Some(Box::new(run_js_frame_script))
+    } else {
+        None
+    };
+    unsafe { GBA.set_frame_script(script) }
+}
+
+/// Start recording the game's writes to the IO registers, to be saved as a
+/// test fixture (see mem::io::mmio_log)
+#[wasm_bindgen]
+pub fn start_mmio_recording() {
+    unsafe { GBA.cpu.mem.mmio_log.start() }
+}
+
+/// Stop recording and return the fixture of the writes recorded since
+/// start_mmio_recording()
+#[wasm_bindgen]
+pub fn stop_mmio_recording() -> Vec<u8> {
+    unsafe { GBA.cpu.mem.mmio_log.stop() }
+}
+
+/// Describe an address using the symbols of the loaded ELF file, if any
+#[wasm_bindgen]
+pub fn lookup_symbol(addr: u32) -> Option<String> {
+    unsafe { GBA.symbols.describe(addr) }
+}
+
+#[wasm_bindgen]
+pub fn set_strict_mode(enabled: bool) {
+    unsafe { GBA.cpu.mem.strict = enabled }
+}
+
+/// Panic (producing a crash report) as soon as the game needs something that
+/// isn't emulated, instead of stopping with an Unsupported error. Meant for
+/// working on the emulator, see cpu::unsupported
+#[wasm_bindgen]
+pub fn set_panic_on_unsupported(enabled: bool) {
+    let policy = if enabled { Policy::Panic } else { Policy::Halt };
+    unsafe { GBA.cpu.unsupported.policy = policy }
+}
+
+/// Choose what happens when the game runs an instruction that can't be
+/// decoded: "strict" to stop with an InvalidOpcode error, or "permissive" to
+/// skip it and carry on, see cpu::invalid_opcode. Returns false for anything
+/// else
+#[wasm_bindgen]
+pub fn set_opcode_policy(policy: &str) -> bool {
+    match OpcodePolicy::from_name(policy) {
+        Some(policy) => {
+            unsafe { GBA.invalid_opcodes.policy = policy }
+            true
+        },
+        None => false,
+    }
+}
+
+/// Return the instructions most recently skipped in permissive mode, one per
+/// line
+#[wasm_bindgen]
+pub fn get_skipped_opcodes() -> String {
+    unsafe { GBA.invalid_opcodes.format_log() }
+}
+
+#[wasm_bindgen]
+pub fn clear_skipped_opcodes() {
+    unsafe { GBA.invalid_opcodes.clear() }
+}
@@ -0,0 +1,503 @@
+//! Typed snapshots of the emulator state for the frontend and other tools.
+//! Each one is a wasm_bindgen class with a read only property for each
+//! field, so the generated TypeScript definitions describe exactly what's
+//! returned instead of leaving callers to parse numbers and strings.
+
+use cpu::CPUWrapper;
+use cpu::bios::SwiLogEntry;
+use cpu::branch_log::BranchEdge;
+use cpu::status_reg::InstructionSet;
+use error::AccessKind;
+#[cfg(feature = "debugger")]
+use mem::Memory;
+use mem::framebuffer::{PixelExplanation, RenderStats};
+#[cfg(all(feature = "render", feature = "debugger"))]
+use mem::framebuffer::BgMap;
+use mem::io::debug::DebugMessage;
+use mem::io::dma::{DMAStats, RunawayDma};
+use mem::io::unsupported::StuckPoll;
+#[cfg(feature = "debugger")]
+use mem::io::registers::IoRegister;
+use mem::oam::Sprite;
+use mem::watch::{Source, WatchHit};
+#[cfg(feature = "render")]
+use mem::reference::PixelDiff;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct CpuState {
+    /// r0 - r15 as seen by the current mode
+    #[wasm_bindgen(readonly)]
+    pub registers: Vec<u32>,
+    #[wasm_bindgen(readonly)]
+    pub cpsr: u32,
+    /// e.g. "USR" or "IRQ"
+    #[wasm_bindgen(readonly)]
+    pub mode: String,
+    #[wasm_bindgen(readonly)]
+    pub thumb: bool,
+    #[wasm_bindgen(readonly)]
+    pub negative: bool,
+    #[wasm_bindgen(readonly)]
+    pub zero: bool,
+    #[wasm_bindgen(readonly)]
+    pub carry: bool,
+    #[wasm_bindgen(readonly)]
+    pub overflow: bool,
+    /// true if IRQs are disabled
+    #[wasm_bindgen(readonly)]
+    pub irq_disabled: bool,
+}
+
+impl CpuState {
+    pub fn capture(gba: &CPUWrapper) -> CpuState {
+        let cpsr = gba.cpu.cpsr;
+        CpuState {
+            registers: (0..16).map(|i| gba.cpu.get_reg(i)).collect(),
+            cpsr: cpsr.to_u32(),
+            mode: format!("{:?}", cpsr.mode),
+            thumb: cpsr.isa == InstructionSet::THUMB,
+            negative: cpsr.neg,
+            zero: cpsr.zero,
+            carry: cpsr.carry,
+            overflow: cpsr.overflow,
+            irq_disabled: cpsr.irq,
+        }
+    }
+}
+
+#[cfg(feature = "debugger")]
+#[wasm_bindgen(getter_with_clone)]
+pub struct IoRegisterState {
+    #[wasm_bindgen(readonly)]
+    pub name: String,
+    #[wasm_bindgen(readonly)]
+    pub addr: u32,
+    /// in bytes
+    #[wasm_bindgen(readonly)]
+    pub size: u8,
+    #[wasm_bindgen(readonly)]
+    pub value: u32,
+    /// the decoded fields, e.g. "mode=3" or "BG2 on"
+    #[wasm_bindgen(readonly)]
+    pub fields: Vec<String>,
+}
+
+#[cfg(feature = "debugger")]
+impl IoRegisterState {
+    pub fn capture(mem: &Memory, reg: &IoRegister) -> IoRegisterState {
+        let value = mem.io_register_value(reg);
+        IoRegisterState {
+            name: reg.name.to_string(),
+            addr: reg.addr,
+            size: reg.size,
+            value,
+            fields: reg.decode_fields(value),
+        }
+    }
+}
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct SpriteState {
+    /// the sprite's position in OAM
+    #[wasm_bindgen(readonly)]
+    pub index: u8,
+    /// screen coordinates of the top left corner, which can be negative
+    #[wasm_bindgen(readonly)]
+    pub x: i16,
+    #[wasm_bindgen(readonly)]
+    pub y: i16,
+    #[wasm_bindgen(readonly)]
+    pub width: u8,
+    #[wasm_bindgen(readonly)]
+    pub height: u8,
+    /// "Normal", "Affine", "Disabled" or "DoubleAffine"
+    #[wasm_bindgen(readonly)]
+    pub mode: String,
+    #[wasm_bindgen(readonly)]
+    pub tile: u16,
+    #[wasm_bindgen(readonly)]
+    pub palette: u8,
+    /// 4 or 8 bits per pixel
+    #[wasm_bindgen(readonly)]
+    pub bpp: u8,
+    #[wasm_bindgen(readonly)]
+    pub priority: u8,
+    #[wasm_bindgen(readonly)]
+    pub hflip: bool,
+    #[wasm_bindgen(readonly)]
+    pub vflip: bool,
+}
+
+impl SpriteState {
+    pub fn capture(index: usize, sprite: &Sprite) -> SpriteState {
+        SpriteState {
+            index: index as u8,
+            x: sprite.left,
+            y: sprite.top,
+            width: sprite.width,
+            height: sprite.height,
+            mode: format!("{:?}", sprite.mode),
+            tile: sprite.tile_number,
+            palette: sprite.palette_number,
+            bpp: sprite.bit_depth,
+            priority: sprite.priority,
+            hflip: sprite.hflip,
+            vflip: sprite.vflip,
+        }
+    }
+}
+
+/// What's drawn at a pixel, see Memory::explain_pixel
+#[wasm_bindgen(getter_with_clone)]
+pub struct PixelInfo {
+    /// e.g. "BG2", "OBJ 5" or "backdrop"
+    #[wasm_bindgen(readonly)]
+    pub layer: String,
+    #[wasm_bindgen(readonly)]
+    pub tile: Option<u32>,
+    /// the tile's graphics, or the pixel itself in the bitmap modes
+    #[wasm_bindgen(readonly)]
+    pub tile_addr: Option<u32>,
+    /// only set for 4 bit tiles
+    #[wasm_bindgen(readonly)]
+    pub palette_line: Option<u8>,
+    #[wasm_bindgen(readonly)]
+    pub color: u16,
+    /// the next layer down, or undefined if the pixel is the backdrop
+    #[wasm_bindgen(readonly)]
+    pub below: Option<String>,
+    /// "Off", "AlphaBlend", "Lighten" or "Darken"
+    #[wasm_bindgen(readonly)]
+    pub blend_mode: String,
+    #[wasm_bindgen(readonly)]
+    pub first_target: bool,
+    #[wasm_bindgen(readonly)]
+    pub second_target: bool,
+    /// the color that's drawn, after blending
+    #[wasm_bindgen(readonly)]
+    pub blended: u16,
+}
+
+impl PixelInfo {
+    pub fn from_explanation(info: &PixelExplanation) -> PixelInfo {
+        PixelInfo {
+            layer: info.top.layer.to_string(),
+            tile: info.top.tile,
+            tile_addr: info.top.addr,
+            palette_line: info.top.palette_line,
+            color: info.top.color,
+            below: info.below.map(|below| below.layer.to_string()),
+            blend_mode: format!("{:?}", info.blend_mode),
+            first_target: info.first_target,
+            second_target: info.second_target,
+            blended: info.blended,
+        }
+    }
+}
+
+/// A background's whole tilemap, see Memory::render_bg_map
+#[cfg(all(feature = "render", feature = "debugger"))]
+#[wasm_bindgen(getter_with_clone)]
+pub struct BgMapImage {
+    #[wasm_bindgen(readonly)]
+    pub width: u32,
+    #[wasm_bindgen(readonly)]
+    pub height: u32,
+    /// the top left corner of the screen's 240x160 viewport, which is
+    /// outlined in the pixels and wraps around the edges of the map
+    #[wasm_bindgen(readonly)]
+    pub scroll_x: u32,
+    #[wasm_bindgen(readonly)]
+    pub scroll_y: u32,
+    /// width * height 15 bit colors, row by row
+    #[wasm_bindgen(readonly)]
+    pub pixels: Vec<u16>,
+}
+
+#[cfg(all(feature = "render", feature = "debugger"))]
+impl BgMapImage {
+    pub fn from_map(map: BgMap) -> BgMapImage {
+        BgMapImage {
+            width: map.width,
+            height: map.height,
+            scroll_x: map.scroll_x,
+            scroll_y: map.scroll_y,
+            pixels: map.pixels,
+        }
+    }
+}
+
+/// Totals for a DMA channel since the stats were last reset
+#[wasm_bindgen]
+pub struct DmaChannelStats {
+    #[wasm_bindgen(readonly)]
+    pub transfers: u32,
+    /// the number of words/halfwords copied
+    #[wasm_bindgen(readonly)]
+    pub chunks: f64,
+    /// the number of cycles the CPU was halted for
+    #[wasm_bindgen(readonly)]
+    pub cycles: f64,
+}
+
+impl DmaChannelStats {
+    pub fn from_stats(stats: &DMAStats) -> DmaChannelStats {
+        DmaChannelStats {
+            transfers: stats.transfers,
+            chunks: stats.chunks as f64,
+            cycles: stats.cycles as f64,
+        }
+    }
+}
+
+/// The lines given to the renderer since the stats were last reset
+#[wasm_bindgen]
+pub struct RenderLineStats {
+    #[wasm_bindgen(readonly)]
+    pub lines_drawn: f64,
+    /// lines during forced blank, which are filled in white without running
+    /// the renderer
+    #[wasm_bindgen(readonly)]
+    pub lines_blanked: f64,
+}
+
+impl RenderLineStats {
+    pub fn from_stats(stats: &RenderStats) -> RenderLineStats {
+        RenderLineStats {
+            lines_drawn: stats.lines_drawn as f64,
+            lines_blanked: stats.lines_blanked as f64,
+        }
+    }
+}
+
+/// A pixel drawn differently by the renderer and the reference renderer
+#[cfg(feature = "render")]
+#[wasm_bindgen]
+pub struct RenderDiff {
+    #[wasm_bindgen(readonly)]
+    pub x: u32,
+    #[wasm_bindgen(readonly)]
+    pub y: u32,
+    /// the color in the framebuffer
+    #[wasm_bindgen(readonly)]
+    pub drawn: u16,
+    /// the color the reference renderer drew
+    #[wasm_bindgen(readonly)]
+    pub expected: u16,
+}
+
+#[cfg(feature = "render")]
+impl RenderDiff {
+    pub fn from_diff(diff: &PixelDiff) -> RenderDiff {
+        RenderDiff {
+            x: diff.col,
+            y: diff.row,
+            drawn: diff.drawn,
+            expected: diff.expected,
+        }
+    }
+}
+
+/// How many times one kind of instruction has run
+#[wasm_bindgen(getter_with_clone)]
+pub struct OpcodeCount {
+    /// e.g. "ARM data processing" or "THUMB 4: ALU operations"
+    #[wasm_bindgen(readonly)]
+    pub name: String,
+    #[wasm_bindgen(readonly)]
+    pub count: f64,
+}
+
+impl OpcodeCount {
+    pub fn from_count(name: &str, count: u64) -> OpcodeCount {
+        OpcodeCount { name: name.to_string(), count: count as f64 }
+    }
+}
+
+/// A message printed through the debug output channel
+#[wasm_bindgen(getter_with_clone)]
+pub struct DebugEvent {
+    /// "fatal", "error", "warn", "info" or "debug"
+    #[wasm_bindgen(readonly)]
+    pub level: String,
+    #[wasm_bindgen(readonly)]
+    pub text: String,
+}
+
+impl DebugEvent {
+    pub fn from_message(msg: &DebugMessage) -> DebugEvent {
+        DebugEvent { level: msg.level.name().to_string(), text: msg.text.clone() }
+    }
+}
+
+/// A game that looks stuck waiting on hardware that isn't emulated
+#[wasm_bindgen(getter_with_clone)]
+pub struct StuckPollEvent {
+    /// "keypad" or "serial (JOY bus)"
+    #[wasm_bindgen(readonly)]
+    pub subsystem: String,
+    /// the register being polled, e.g. "KEYINPUT"
+    #[wasm_bindgen(readonly)]
+    pub register: String,
+    #[wasm_bindgen(readonly)]
+    pub addr: u32,
+    /// a description that can be shown to the user as is
+    #[wasm_bindgen(readonly)]
+    pub message: String,
+}
+
+impl StuckPollEvent {
+    pub fn from_report(report: &StuckPoll) -> StuckPollEvent {
+        StuckPollEvent {
+            subsystem: report.subsystem.name().to_string(),
+            register: report.register.to_string(),
+            addr: report.addr,
+            message: report.to_string(),
+        }
+    }
+}
+
+/// A DMA transfer into the IO registers that's too big to be intentional
+#[wasm_bindgen(getter_with_clone)]
+pub struct RunawayDmaEvent {
+    #[wasm_bindgen(readonly)]
+    pub channel: u8,
+    #[wasm_bindgen(readonly)]
+    pub src: u32,
+    #[wasm_bindgen(readonly)]
+    pub dest: u32,
+    /// the number of words/halfwords copied
+    #[wasm_bindgen(readonly)]
+    pub count: u32,
+    #[wasm_bindgen(readonly)]
+    pub word: bool,
+    /// a description that can be shown to the user as is
+    #[wasm_bindgen(readonly)]
+    pub message: String,
+}
+
+impl RunawayDmaEvent {
+    pub fn from_report(report: &RunawayDma) -> RunawayDmaEvent {
+        RunawayDmaEvent {
+            channel: report.channel as u8,
+            src: report.src,
+            dest: report.dest,
+            count: report.count,
+            word: report.word,
+            message: report.to_string(),
+        }
+    }
+}
+
+/// An access to a watched range of memory
+#[wasm_bindgen(getter_with_clone)]
+pub struct WatchHitEvent {
+    #[wasm_bindgen(readonly)]
+    pub addr: u32,
+    /// in bytes
+    #[wasm_bindgen(readonly)]
+    pub size: u32,
+    #[wasm_bindgen(readonly)]
+    pub write: bool,
+    /// the DMA channel that made the access, or -1 for the CPU
+    #[wasm_bindgen(readonly)]
+    pub dma_channel: i32,
+    /// a description that can be shown to the user as is
+    #[wasm_bindgen(readonly)]
+    pub message: String,
+}
+
+impl WatchHitEvent {
+    pub fn from_hit(hit: &WatchHit) -> WatchHitEvent {
+        WatchHitEvent {
+            addr: hit.addr,
+            size: hit.size,
+            write: hit.kind == AccessKind::Write,
+            dma_channel: match hit.source {
+                Source::Cpu | Source::Script => -1,
+                Source::Dma { channel, .. } => channel as i32,
+            },
+            message: hit.to_string(),
+        }
+    }
+}
+
+/// A BIOS call made by the game
+#[wasm_bindgen(getter_with_clone)]
+pub struct SwiEvent {
+    #[wasm_bindgen(readonly)]
+    pub num: u8,
+    /// address of the SWI instruction
+    #[wasm_bindgen(readonly)]
+    pub pc: u32,
+    /// "Bios", "Hle" or "Skipped"
+    #[wasm_bindgen(readonly)]
+    pub path: String,
+}
+
+impl SwiEvent {
+    pub fn from_entry(entry: &SwiLogEntry) -> SwiEvent {
+        SwiEvent { num: entry.num, pc: entry.pc, path: format!("{:?}", entry.path) }
+    }
+}
+
+/// A branch the CPU has taken recently, see cpu::branch_log
+#[wasm_bindgen(getter_with_clone)]
+pub struct BranchEvent {
+    /// address of the branch instruction
+    #[wasm_bindgen(readonly)]
+    pub from: u32,
+    /// address that was branched to
+    #[wasm_bindgen(readonly)]
+    pub to: u32,
+    #[wasm_bindgen(readonly)]
+    pub count: u32,
+    /// the symbol branched to (e.g. "main+0x1C") if the program was loaded
+    /// from an ELF file, otherwise empty
+    #[wasm_bindgen(readonly)]
+    pub target: String,
+}
+
+impl BranchEvent {
+    pub fn from_edge(gba: &CPUWrapper, edge: &BranchEdge) -> BranchEvent {
+        BranchEvent {
+            from: edge.from,
+            to: edge.to,
+            count: edge.count,
+            target: gba.symbols.describe(edge.to).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.set_reg(3, 0x1234);
+        gba.cpu.cpsr.isa = InstructionSet::THUMB;
+        gba.cpu.cpsr.carry = true;
+        let state = CpuState::capture(&gba);
+        assert_eq!(state.registers.len(), 16);
+        assert_eq!(state.registers[3], 0x1234);
+        assert_eq!(state.mode, "SVC");
+        assert!(state.thumb && state.carry && !state.zero);
+        assert_eq!(state.cpsr, gba.cpu.cpsr.to_u32());
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn capture_io_register() {
+        let mut gba = CPUWrapper::new();
+        gba.cpu.mem.set_halfword(0x4000000, 0x0403);
+        let dispcnt = IoRegisterState::capture(&gba.cpu.mem,
+            &::mem::io::registers::IO_REGISTERS[0]);
+        assert_eq!(dispcnt.name, "DISPCNT");
+        assert_eq!(dispcnt.value, 0x0403);
+        assert_eq!(dispcnt.fields[0], "mode=3");
+        assert!(dispcnt.fields.contains(&"BG2 on".to_string()));
+    }
+}
@@ -1,10 +1,25 @@
-const rust = import('./gba');
-const wasm = import('./gba_bg');
+// the bindings are loaded by index.html, and instantiate the module they're
+// given with their imports
+const VM = wasm_bindgen;
+
+// compile the module while it downloads, or download it and then compile it
+// in browsers without streaming compilation, or if the server doesn't send
+// it as application/wasm (which streaming compilation requires)
+const compileWasm = async (url) => {
+    if (typeof WebAssembly.compileStreaming === 'function') {
+        try {
+            return await WebAssembly.compileStreaming(fetch(url));
+        } catch (err) {
+            console.warn(`falling back to compiling ${url} after downloading it: ${err}`);
+        }
+    }
+    const bytes = await fetch(url).then(resp => resp.arrayBuffer());
+    return WebAssembly.compile(bytes);
+}
 
 const run = async () => {
 
-const VM = await rust;
-const { memory } = await wasm;
+const { memory } = await VM({ module_or_path: await compileWasm('gba_bg.wasm') });
 
 const armd = new cs.Capstone(cs.ARCH_ARM, cs.MODE_ARM);
 const thumbd = new cs.Capstone(cs.ARCH_ARM, cs.MODE_THUMB);
@@ -18,35 +33,21 @@ let tile_ptr = VM.get_vram();
 let rom;
 let buf8 = new Uint8Array(memory.buffer);
 
-const parseCpsr = (raw) => ({
-    neg: (raw >> 31) & 1 == 1,
-    zero: (raw >> 30) & 1 == 1,
-    carry: (raw >> 29) & 1 == 1,
-    overflow: (raw >> 28) & 1 == 1,
-    irq: (raw >> 7) & 1 == 1,
-    thumb: (raw >> 5) & 1 == 1,
-    mode: getMode(raw & 0b11111),
-})
-
-const getMode = (raw) => {
-    switch(raw) {
-        case 0b10000:
-            return "USR"
-        case 0b10001:
-            return "FIQ"
-        case 0b10010:
-            return "IRQ"
-        case 0b10011:
-            return "SVC"
-        case 0b10111:
-            return "ABT"
-        case 0b11011:
-            return "UND"
-        case 0b11111:
-            return "SYS"
-        default:
-            return raw.toString(2)
+// the wasm memory can grow during any call that allocates, which detaches the
+// old memory.buffer, so views of it have to be checked before each use
+const heap8 = () => {
+    if (buf8.buffer !== memory.buffer) {
+        buf8 = new Uint8Array(memory.buffer);
     }
+    return buf8;
+}
+
+// returns true if the CPU is in THUMB state
+const isThumb = () => {
+    let state = VM.get_cpu_state();
+    let thumb = state.thumb;
+    state.free();
+    return thumb;
 }
 
 const getFlag = (on, char) => on ? char : '-'
@@ -56,7 +57,25 @@ const updateSharedMem = () => {
     bg_palette_ptr = VM.get_bg_palette();
     sprite_palette_ptr = VM.get_sprite_palette();
     tile_ptr = VM.get_vram();
-    buf8 = new Uint8Array(memory.buffer);
+}
+
+const SETTINGS_KEY = "gba-settings";
+
+const loadSettings = () => {
+    let saved = localStorage.getItem(SETTINGS_KEY);
+    if (saved === null) {
+        return;
+    }
+    try {
+        VM.import_settings(new TextEncoder().encode(saved));
+    } catch (err) {
+        console.error(`ignoring saved settings: ${err}`);
+    }
+}
+
+const saveSettings = () => {
+    let settings = new TextDecoder().decode(VM.export_settings());
+    localStorage.setItem(SETTINGS_KEY, settings);
 }
 
 const addUploadListener = (id, callback) => {
@@ -77,6 +96,14 @@ const addDebugListener = () => {
     stepButton.addEventListener('click', event => step());
     const frameButton = document.getElementById('frame');
     frameButton.addEventListener('click', event => frame());
+    const selfTestButton = document.getElementById('self-test');
+    selfTestButton.addEventListener('click', event => {
+        try {
+            $("#self-test-report").text(VM.run_self_test());
+        } catch (err) {
+            $("#self-test-report").text(`the game couldn't be restored after the self test: ${err}`);
+        }
+    });
     const runButton = document.getElementById('bpsubmit')
     runButton.addEventListener("click", event => {
         let bp = parseInt(document.getElementById('bpinput').value, 16);
@@ -92,32 +119,32 @@ const addDebugListener = () => {
 const dumpState = () => {
     $("#count").text(instruction_count);
     $("#regs").empty();
-    for (let i = 0; i < 16; i++) {
+    let state = VM.get_cpu_state();
+    state.registers.forEach((reg, i) => {
         $("#regs").append(
-            // >>> "coerces" to an unsigned integer
-            `<div class="col-md-3">R${i}: ${(VM.get_register(i) >>> 0).toString(16)}</div>`);
-    }
+            `<div class="col-md-3">R${i}: ${reg.toString(16)}</div>`);
+    });
 
     $("#pipeline").empty();
-    let cpsr = parseCpsr(VM.get_cpsr());
     $("#pipeline").append(
         `<div class="col-md-4 col-md-offset-4">
-            ${getFlag(cpsr.neg, 'N')}
-            ${getFlag(cpsr.zero, 'Z')}
-            ${getFlag(cpsr.carry, 'C')}
-            ${getFlag(cpsr.overflow, 'V')}
-            ${getFlag(cpsr.irq, 'I')}
-            ${getFlag(cpsr.thumb, 'T')}
-            ${cpsr.mode}
+            ${getFlag(state.negative, 'N')}
+            ${getFlag(state.zero, 'Z')}
+            ${getFlag(state.carry, 'C')}
+            ${getFlag(state.overflow, 'V')}
+            ${getFlag(state.irq_disabled, 'I')}
+            ${getFlag(state.thumb, 'T')}
+            ${state.mode}
         </div>`);
 
-    let pc = VM.get_register(15);
+    let pc = state.registers[15];
+    state.free();
     let instr_size = dis === armd ? 4 : 2;
     let start = Math.max(0, pc - 2*instr_size);
     let end = pc + instr_size;
     try {
         let pipeline = dis.disasm(
-            buf8.slice(bios_ptr + start, bios_ptr + end),
+            heap8().slice(bios_ptr + start, bios_ptr + end),
             start);
         pipeline.forEach((instr) => {
             $("#pipeline").append(
@@ -157,6 +184,7 @@ const showTiles = () => {
     canvas.height = HEIGHT;
     let ctx = canvas.getContext('2d');
     let pixels = ctx.getImageData(0, 0, WIDTH, HEIGHT);
+    let heap = heap8();
     let current = tile_ptr;
     let tile_idx = 0;
     for (let i = 0; i < 6; i++) { // charblock
@@ -165,7 +193,7 @@ const showTiles = () => {
             tile_row = Math.floor(tile_idx / TILES_PER_ROW);
             tile_col = tile_idx % TILES_PER_ROW;
             for (let k = 0; k < 64; k++) {
-                let colorOffset = buf8[current];
+                let colorOffset = heap[current];
                 let palettePtr = i < 4 ? bg_palette_ptr : sprite_palette_ptr;
                 let { blue, green, red } = readColor(palettePtr + colorOffset*4);
 
@@ -186,24 +214,118 @@ const showTiles = () => {
     ctx.putImageData(pixels, 0, 0);
 }
 
-const readColor = (ptr) => ({
-    blue: buf8[ptr],
-    green: buf8[ptr + 1],
-    red: buf8[ptr + 2],
-})
+const readColor = (ptr) => {
+    let heap = heap8();
+    return { blue: heap[ptr], green: heap[ptr + 1], red: heap[ptr + 2] };
+}
+
+// the emulator returns a crash report instead of running once it has panicked,
+// or throws an EmulatorError if the game did something that can't be emulated.
+// the panic itself aborts, which traps out of the call, and the report made by
+// the panic hook has to be asked for
+const showCrash = (err) => {
+    if (err instanceof WebAssembly.RuntimeError) {
+        let text = VM.get_crash_report() || `emulator crashed: ${err}`;
+        const note = VM.get_crash_note();
+        if (note) {
+            text = `${note}\n${text}`;
+        }
+        const fingerprint = VM.get_crash_fingerprint();
+        $("#crash").text(fingerprint ? `fingerprint: ${fingerprint}\n${text}` : text);
+        return;
+    }
+    if (typeof err === 'string') {
+        $("#crash").text(err);
+        return;
+    }
+    let text = err.kind === 'Unsupported'
+        ? `This game needs something the emulator doesn't support yet (${err.message})`
+        : `${err.kind}: ${err.message}`;
+    if (err.note) {
+        text += `\n${err.note}`;
+    }
+    // players include this when reporting the problem
+    $("#crash").text(`${text}\nfingerprint: ${err.fingerprint}`);
+}
 
 const step = () => {
-    if (VM.step()) {
-        pipelineFill();
+    try {
+        if (VM.step()) {
+            pipelineFill();
+        }
+    } catch (report) {
+        showCrash(report);
     }
     instruction_count += 1;
-    dis = parseCpsr(VM.get_cpsr()).thumb ? thumbd : armd;
+    dis = isThumb() ? thumbd : armd;
     dumpState();
 }
 
+const printDebugOutput = () => {
+    for (let msg of VM.take_debug_messages()) {
+        console.log(`[${msg.level}] ${msg.text}`);
+        msg.free();
+    }
+}
+
+// created on the first frame, since browsers only let audio start once the
+// page has been interacted with
+let audio = null;
+// when the last queued buffer finishes playing, in the context's time
+let audioEnd = 0;
+
+const playAudio = () => {
+    if (audio === null) {
+        audio = new AudioContext();
+        // the drift is measured against the samples the context has played
+        VM.start_av_sync(audio.sampleRate);
+    }
+    const samples = VM.take_audio_samples();
+    const frames = samples.length / 2;
+    if (frames === 0) {
+        return;
+    }
+    const buffer = audio.createBuffer(2, frames, VM.get_audio_sample_rate());
+    const left = buffer.getChannelData(0);
+    const right = buffer.getChannelData(1);
+    for (let i = 0; i < frames; i++) {
+        left[i] = samples[2 * i];
+        right[i] = samples[2 * i + 1];
+    }
+    const source = audio.createBufferSource();
+    source.buffer = buffer;
+    source.connect(audio.destination);
+    // correct the drift by stretching the buffer: a ratio above 1 asks for
+    // more samples, which is the same as playing these ones slower
+    const rate = 1 / VM.get_resample_ratio();
+    source.playbackRate.value = rate;
+    const duration = buffer.duration / rate;
+    // report the buffer once it's been played, in the context's samples
+    source.onended = () => {
+        VM.record_audio_samples(Math.round(duration * audio.sampleRate));
+    };
+    // play straight after the last buffer, or now if it's already finished
+    audioEnd = Math.max(audioEnd, audio.currentTime);
+    source.start(audioEnd);
+    audioEnd += duration;
+}
+
 const frame = () => {
-    VM.frame();
-    dis = parseCpsr(VM.get_cpsr()).thumb ? thumbd : armd;
+    try {
+        VM.frame();
+    } catch (report) {
+        showCrash(report);
+    }
+    let hit = VM.take_watch_hit();
+    if (hit) {
+        console.log(`watchpoint: ${hit.message}`);
+        hit.free();
+        VM.pause();
+    }
+    playAudio();
+    $("#pacing").text(VM.get_pacing_stats());
+    printDebugOutput();
+    dis = isThumb() ? thumbd : armd;
     dumpState();
 }
 
@@ -221,7 +343,7 @@ const run_until_break = (breakpoint) => {
         steps += 1;
     }
     instruction_count += steps;
-    dis = parseCpsr(VM.get_cpsr()).thumb ? thumbd : armd;
+    dis = isThumb() ? thumbd : armd;
     dumpState();
 }
 
@@ -232,7 +354,7 @@ const trace = (n) => {
         }
     }
     instruction_count += n;
-    dis = parseCpsr(VM.get_cpsr()).thumb ? thumbd : armd;
+    dis = isThumb() ? thumbd : armd;
     dumpState();
 }
 
@@ -247,6 +369,7 @@ const init = async () => {
     VM.upload_bios(bios);
     let rom = new Uint8Array(
         await fetch (`data/sapphire.gba`).then(resp => resp.arrayBuffer()));
+    VM.reserve_memory(rom.length, 0);
     VM.upload_rom(rom);
     updateSharedMem();
     dumpState();
@@ -254,6 +377,8 @@ const init = async () => {
 }
 
 VM.set_panic_hook();
+loadSettings();
+window.addEventListener("beforeunload", saveSettings);
 addUploadListener("bios", (data) => {
     VM.upload_bios(data);
     updateSharedMem();
@@ -262,11 +387,29 @@ addUploadListener("bios", (data) => {
     pipelineFill();
 });
 addUploadListener("rom", (data) => {
+    VM.reserve_memory(data.length, 0);
     VM.upload_rom(data);
     updateSharedMem();
     rom = data;
 });
+addUploadListener("elf", (data) => {
+    try {
+        VM.dev_boot(data);
+    } catch (err) {
+        showCrash(err);
+        return;
+    }
+    updateSharedMem();
+    dumpState();
+});
 addDebugListener();
+document.addEventListener("visibilitychange", () => {
+    if (document.hidden) {
+        VM.pause();
+    } else {
+        VM.resume();
+    }
+});
 await init();
 }
 